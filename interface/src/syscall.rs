@@ -15,25 +15,450 @@ enum64! {
         13  => ReadStream,
         14  => WriteStream,
         15  => OpenFile,
+        16  => SetPriority,
+        17  => GetPriority,
+        18  => Kill,
+        19  => Sleep,
+        20  => Fork,
+        21  => Exec,
+        22  => Wait,
+        23  => CreateThread,
+        24  => SetAffinity,
+        25  => GetAffinity,
+        26  => Yield,
+        27  => Mmap,
+        28  => Munmap,
+        29  => Brk,
+        30  => ShmCreate,
+        31  => ShmMap,
+        32  => Mprotect,
+        33  => Sigaction,
+        34  => SendSignal,
+        35  => Sigreturn,
+        36  => Close,
+        37  => Dup,
+        38  => Dup2,
+        39  => Pipe,
+        40  => ChannelListen,
+        41  => ChannelConnect,
+        42  => ChannelAccept,
+        43  => ChannelSend,
+        44  => ChannelRecv,
+        45  => FutexWait,
+        46  => FutexWake,
+        47  => Poll,
+        48  => EvqCreate,
+        49  => EvqAdd,
+        50  => EvqRemove,
+        51  => EvqWait,
+        52  => EndpointCreate,
+        53  => Call,
+        54  => Receive,
+        55  => Reply,
+        56  => SetProcessGroup,
+        57  => GetProcessGroup,
+        58  => SendGroupSignal,
+        59  => SetForeground,
+        60  => GetForeground,
+        61  => TimerCreate,
+        62  => TimerSet,
+        63  => TimerRead,
+        64  => GetProcessId,
+        65  => GetParentProcessId,
+        66  => GetTaskId,
+        67  => Uname,
+        68  => Ioctl,
+        69  => Seek,
+        70  => Stat,
+        71  => Fstat,
+        72  => Getdents,
+        73  => Mount,
+        74  => Umount,
+        75  => Chdir,
+        76  => Getcwd,
+        77  => Chroot,
+        78  => ClockGettime,
+        79  => Reboot,
+        80  => SetAslr,
+        81  => GetRandom,
+        82  => SetWriteExecute,
+        83  => HandleRestrict,
+        84  => SyscallFilter,
+        85  => SetUid,
+        86  => SetGid,
+        87  => GetUid,
+        88  => GetGid,
+        89  => Unlink,
+        90  => Rename,
+        91  => SetSchedClass,
     }
 }
 
+// errors are returned from syscalls as negative errno values, the same ones
+// Linux uses, so that they're easy to tell apart from successful results and
+// to map onto C's errno:
 enum64! {
     enum SysError {
-        0xffff_ffff_0000_0001 => BadSyscall,
-        0xffff_ffff_0000_0002 => BadPointer,
-        0xffff_ffff_0000_0003 => AlreadyMapped,
-        0xffff_ffff_0000_0004 => MemoryExhausted,
-        0xffff_ffff_0000_0005 => IllegalValue,
-        0xffff_ffff_0000_0006 => WrongObjectKind,
-        0xffff_ffff_0000_0007 => BadHandle,
-        0xffff_ffff_0000_0008 => IoError,
-        0xffff_ffff_0000_0009 => NoFile,
-        0xffff_ffff_0000_0010 => InvalidOperation,
+        0xffff_ffff_ffff_ffda => BadSyscall, // -ENOSYS
+        0xffff_ffff_ffff_fff2 => BadPointer, // -EFAULT
+        0xffff_ffff_ffff_ffef => AlreadyMapped, // -EEXIST
+        0xffff_ffff_ffff_fff4 => MemoryExhausted, // -ENOMEM
+        0xffff_ffff_ffff_ffea => IllegalValue, // -EINVAL
+        0xffff_ffff_ffff_ffb3 => WrongObjectKind, // -EBADFD
+        0xffff_ffff_ffff_fff7 => BadHandle, // -EBADF
+        0xffff_ffff_ffff_fffb => IoError, // -EIO
+        0xffff_ffff_ffff_fffe => NoFile, // -ENOENT
+        0xffff_ffff_ffff_ffa1 => InvalidOperation, // -EOPNOTSUPP
+        0xffff_ffff_ffff_fffd => NoTask, // -ESRCH
+        0xffff_ffff_ffff_fff8 => BadExecutable, // -ENOEXEC
+        0xffff_ffff_ffff_fff5 => WouldBlock, // -EAGAIN
+        0xffff_ffff_ffff_ffe0 => BrokenPipe, // -EPIPE
+        0xffff_ffff_ffff_fff9 => ArgumentsTooLong, // -E2BIG
+        0xffff_ffff_ffff_ffd9 => NotEmpty, // -ENOTEMPTY
+        0xffff_ffff_ffff_ffee => CrossDevice, // -EXDEV
+        0xffff_ffff_ffff_fff0 => Busy, // -EBUSY
+        0xffff_ffff_ffff_ffe4 => NoSpace, // -ENOSPC
+        0xffff_ffff_ffff_fff3 => PermissionDenied, // -EACCES
+    }
+}
+
+impl SysError {
+    /// Returns the positive errno value for the error.
+    pub fn errno(self) -> u64 {
+        (self as u64).wrapping_neg()
     }
 }
 
 pub const OK: u64 = 0;
+
+/// Exit status reported for tasks terminated by the Kill syscall.
+pub const EXIT_KILLED: u64 = 0xffff_ffff_ffff_fffe;
+/// Exit status reported for tasks killed by a page fault they caused, such as
+/// an access to unmapped memory.
+pub const EXIT_FAULT: u64 = 0xffff_ffff_ffff_fffd;
+/// Set in every error returned from a syscall, and never in a successful
+/// result.
 pub const ERR_FLAG: u64 = 0x8000_0000_0000_0000;
 
+/// Protection flags for the Mmap and Mprotect syscalls. Mapped memory is
+/// always readable. Memory can't be both writable and executable unless the
+/// process has allowed it with the SetWriteExecute syscall.
+pub const PROT_READ: u64 = 0x01;
+pub const PROT_WRITE: u64 = 0x02;
+pub const PROT_EXEC: u64 = 0x04;
+
+/// Rights a handle carries. A new handle has them all, and the HandleRestrict
+/// syscall drops them. READ covers reading and receiving, WRITE writing,
+/// sending and changing settings, MAP mapping memory and running in a page
+/// context, DUP making more handles including by sending one, and SIGNAL
+/// waiting on events with Poll or an event queue.
+pub const RIGHT_READ: u64 = 0x01;
+pub const RIGHT_WRITE: u64 = 0x02;
+pub const RIGHT_MAP: u64 = 0x04;
+pub const RIGHT_DUP: u64 = 0x08;
+pub const RIGHT_SIGNAL: u64 = 0x10;
+pub const RIGHTS_ALL: u64 = 0x1f;
+
+/// Modes for the SyscallFilter syscall, which takes a bitmap of syscall
+/// numbers SYSCALL_FILTER_WORDS u64s long, lowest number first. FILTER_ALLOW
+/// allows only the syscalls in it, and FILTER_DENY all but those. Either way,
+/// whatever the task's filter already denied stays denied.
+pub const FILTER_ALLOW: u64 = 0;
+pub const FILTER_DENY: u64 = 1;
+pub const SYSCALL_FILTER_WORDS: u64 = 2;
+
+/// Flags for the Mmap syscall. MAP_FIXED places the mapping at exactly the
+/// given address, replacing anything already there, rather than treating the
+/// address as a hint. Only MAP_ANONYMOUS mappings are supported for now.
+pub const MAP_FIXED: u64 = 0x10;
+pub const MAP_ANONYMOUS: u64 = 0x20;
+
+/// Signals, numbered as on Linux. Signals go from 1 up to but not including
+/// NSIG.
+pub const NSIG: u64 = 64;
+pub const SIGINT: u64 = 2;
+pub const SIGKILL: u64 = 9;
+pub const SIGSEGV: u64 = 11;
+pub const SIGALRM: u64 = 14;
+pub const SIGTERM: u64 = 15;
+pub const SIGCHLD: u64 = 17;
+
+/// Handlers for the Sigaction syscall other than the address of a function.
+/// SIG_DFL terminates the task for most signals, and ignores SIGCHLD.
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+
+/// Scheduling classes for the SetSchedClass syscall. SCHED_NORMAL tasks are
+/// scheduled by priority. SCHED_FIFO and SCHED_RR tasks have a real-time
+/// priority from 0 to 7 inclusive, and always run before normal tasks.
+pub const SCHED_NORMAL: u64 = 0;
+pub const SCHED_FIFO: u64 = 1;
+pub const SCHED_RR: u64 = 2;
+
+/// Limits on channels: the length of the name a listener goes by, and the
+/// number of bytes and handles in a message.
+pub const CHANNEL_NAME_MAX: u64 = 32;
+pub const CHANNEL_MESSAGE_MAX: u64 = 4096;
+pub const CHANNEL_HANDLES_MAX: u64 = 8;
+
+/// Describes a message for the ChannelSend and ChannelRecv syscalls, which
+/// point at it. `data` and `handles` point at `data_len` bytes and
+/// `handles_len` handles. ChannelRecv takes the lengths as the sizes of the
+/// buffers and sets them to the size of the message received.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ChannelMessage {
+    pub data: u64,
+    pub data_len: u64,
+    pub handles: u64,
+    pub handles_len: u64,
+}
+
+/// Events for the Poll syscall. POLLIN and POLLOUT are asked for, the rest
+/// are always reported: POLLERR for a write end whose reader has gone,
+/// POLLHUP for a read end whose writer has gone, and POLLNVAL for a handle
+/// that isn't open.
+pub const POLLIN: u64 = 0x01;
+pub const POLLOUT: u64 = 0x04;
+pub const POLLERR: u64 = 0x08;
+pub const POLLHUP: u64 = 0x10;
+pub const POLLNVAL: u64 = 0x20;
+
+/// The most handles a single Poll syscall can wait on.
+pub const POLL_MAX: u64 = 64;
+
+/// Waits forever, as the timeout of the Poll syscall.
+pub const POLL_INFINITE: u64 = u64::max_value();
+
+/// One handle for the Poll syscall to wait on, which sets `revents` to the
+/// events that happened. A handle of 0 is skipped.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PollFd {
+    pub handle: u64,
+    pub events: u64,
+    pub revents: u64,
+}
+
+/// Set along with the POLL* events for the EvqAdd syscall to report them only
+/// as they happen, rather than for as long as the object stays ready.
+pub const EVQ_EDGE: u64 = 1 << 32;
+
+/// The most events a single EvqWait syscall returns.
+pub const EVQ_MAX_EVENTS: u64 = 64;
+
+/// An event returned by the EvqWait syscall: the data the handle was added
+/// with, and the POLL* events it's ready for.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EvqEvent {
+    pub data: u64,
+    pub events: u64,
+}
+
+/// Clocks for the ClockGettime syscall: the date and time, in time since the
+/// start of 1970 UTC, and time since boot, which never jumps.
+pub const CLOCK_REALTIME: u64 = 0;
+pub const CLOCK_MONOTONIC: u64 = 1;
+
+/// What the Reboot syscall does once everything is written back to disk:
+/// turn the machine off, or restart it.
+pub const REBOOT_POWER_OFF: u64 = 0;
+pub const REBOOT_RESTART: u64 = 1;
+
+/// A point in time read from a clock by the ClockGettime syscall, as whole
+/// seconds and the nanoseconds after.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timespec {
+    pub sec: u64,
+    pub nsec: u64,
+}
+
+/// Length of each field of Utsname, including its NUL padding.
+pub const UTSNAME_LEN: usize = 65;
+
+/// Identifies the kernel, as filled in by the Uname syscall. Each field is a
+/// NUL padded string.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Utsname {
+    pub sysname: [u8; UTSNAME_LEN],
+    pub nodename: [u8; UTSNAME_LEN],
+    pub release: [u8; UTSNAME_LEN],
+    pub version: [u8; UTSNAME_LEN],
+    pub machine: [u8; UTSNAME_LEN],
+}
+
+impl Default for Utsname {
+    fn default() -> Self {
+        Utsname {
+            sysname: [0; UTSNAME_LEN],
+            nodename: [0; UTSNAME_LEN],
+            release: [0; UTSNAME_LEN],
+            version: [0; UTSNAME_LEN],
+            machine: [0; UTSNAME_LEN],
+        }
+    }
+}
+
+/// Flags for the OpenFile syscall. Files are always readable, and writable
+/// if opened with OPEN_WRITE. OPEN_CREATE creates the file if it's missing,
+/// and with OPEN_EXCLUSIVE fails if it isn't. OPEN_DIRECTORY fails unless the
+/// path is a directory, which can only be opened to list. OPEN_APPEND makes
+/// every write go to the end of the file.
+pub const OPEN_WRITE: u64 = 0x01;
+pub const OPEN_CREATE: u64 = 0x02;
+pub const OPEN_EXCLUSIVE: u64 = 0x04;
+pub const OPEN_DIRECTORY: u64 = 0x08;
+pub const OPEN_APPEND: u64 = 0x10;
+
+/// Where the offset of the Seek syscall is from: the start of the file, the
+/// current position, or the end of the file.
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+/// Kinds of file, as reported by the Stat and Fstat syscalls and in Getdents
+/// records.
+pub const FILE_KIND_FILE: u32 = 1;
+pub const FILE_KIND_DIRECTORY: u32 = 2;
+pub const FILE_KIND_PIPE: u32 = 3;
+pub const FILE_KIND_CONSOLE: u32 = 4;
+
+/// Describes a file, as filled in by the Stat and Fstat syscalls. The layout
+/// doesn't change: fields added later take the place of `reserved`, which is
+/// zeroed until then.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stat {
+    pub kind: u32,
+    /// The permission bits, as in `chmod`.
+    pub mode: u32,
+    pub size: u64,
+    pub uid: u32,
+    pub gid: u32,
+    pub reserved: [u64; 5],
+}
+
+/// The start of each record the Getdents syscall fills its buffer with. The
+/// entry's name follows it, NUL terminated and padded so that the next
+/// record starts `record_len` bytes after this one, at a multiple of 8.
+/// `next` is the position to seek the directory to to carry on listing from
+/// the entry after this one.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Dirent {
+    pub next: u64,
+    pub record_len: u16,
+    pub name_len: u16,
+    pub kind: u32,
+}
+
+/// Describes a filesystem for the Mount syscall to mount, which points at
+/// it. Each pointer goes with the length of the string it points at.
+/// `fstype` is one of "tmpfs", "devfs", "procfs", "fat16", "fat32" or
+/// "ext2", and `source` the block device those last three are on, like
+/// "hda2", which the others ignore. `target` is the path to mount it at.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MountRequest {
+    pub fstype: u64,
+    pub fstype_len: u64,
+    pub source: u64,
+    pub source_len: u64,
+    pub target: u64,
+    pub target_len: u64,
+}
+
+/// A key going down or coming up, as read from /dev/kbd, which only reads
+/// whole events. `keysym` is the character the key types with the modifiers
+/// in `modifiers` held, or one of the KEYSYM_* values for keys that don't
+/// type one. Holding Ctrl doesn't change the character.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KeyEvent {
+    pub keysym: u32,
+    pub modifiers: u16,
+    pub pressed: u16,
+}
+
+/// Modifiers of a KeyEvent: the modifier keys held and the locks on when
+/// the key went down or came up.
+pub const KEY_MOD_SHIFT: u16 = 0x01;
+pub const KEY_MOD_CTRL: u16 = 0x02;
+pub const KEY_MOD_ALT: u16 = 0x04;
+pub const KEY_MOD_CAPS_LOCK: u16 = 0x08;
+
+/// Keysyms of keys that don't type a character, from the private use area
+/// so as not to clash with the characters of keys that do. The function
+/// keys are KEYSYM_F1 onwards, in order.
+pub const KEYSYM_SHIFT: u32 = 0xe000;
+pub const KEYSYM_CTRL: u32 = 0xe001;
+pub const KEYSYM_ALT: u32 = 0xe002;
+pub const KEYSYM_CAPS_LOCK: u32 = 0xe003;
+pub const KEYSYM_NUM_LOCK: u32 = 0xe004;
+pub const KEYSYM_SCROLL_LOCK: u32 = 0xe005;
+pub const KEYSYM_UP: u32 = 0xe010;
+pub const KEYSYM_DOWN: u32 = 0xe011;
+pub const KEYSYM_LEFT: u32 = 0xe012;
+pub const KEYSYM_RIGHT: u32 = 0xe013;
+pub const KEYSYM_HOME: u32 = 0xe014;
+pub const KEYSYM_END: u32 = 0xe015;
+pub const KEYSYM_PAGE_UP: u32 = 0xe016;
+pub const KEYSYM_PAGE_DOWN: u32 = 0xe017;
+pub const KEYSYM_INSERT: u32 = 0xe018;
+pub const KEYSYM_DELETE: u32 = 0xe019;
+pub const KEYSYM_F1: u32 = 0xe020;
+
+/// Ioctl requests of the console: TTY_GET_MODE returns its TTY_* modes, and
+/// TTY_SET_MODE sets them to `arg`.
+pub const TTY_GET_MODE: u64 = 0x5401;
+pub const TTY_SET_MODE: u64 = 0x5402;
+
+/// Modes of the console, all on to start with. In TTY_CANONICAL mode input
+/// is edited a line at a time, with Backspace and Ctrl-U, and read once
+/// Enter is pressed, no more than a line at once. Ctrl-D hands over the line
+/// without a newline, or makes the next read return nothing if the line is
+/// empty. TTY_ECHO shows what's typed on the screen, and TTY_SIGNALS sends
+/// SIGINT to the foreground group for Ctrl-C. With none of them, the console
+/// is raw, and reads get keys as they're typed.
+pub const TTY_CANONICAL: u64 = 0x01;
+pub const TTY_ECHO: u64 = 0x02;
+pub const TTY_SIGNALS: u64 = 0x04;
+
+/// An event read from an input device, /dev/event0 and so on, which only
+/// read whole events. Events come in groups, each ended by an EV_SYN event
+/// with code SYN_REPORT, that happened together, like the movement and
+/// buttons of one mouse packet. `time_ns` is the monotonic clock when the
+/// device reported it.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InputEvent {
+    pub time_ns: u64,
+    pub kind: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+/// Kinds of InputEvent: the end of a group, a button going down (value 1)
+/// or up (value 0), or relative movement along an axis by `value`.
+pub const EV_SYN: u16 = 0x00;
+pub const EV_KEY: u16 = 0x01;
+pub const EV_REL: u16 = 0x02;
+
+/// Code of the EV_SYN event that ends a group.
+pub const SYN_REPORT: u16 = 0x00;
+
+/// Codes of EV_REL events. Positive X is right, positive Y down and
+/// positive wheel movement away from the user.
+pub const REL_X: u16 = 0x00;
+pub const REL_Y: u16 = 0x01;
+pub const REL_WHEEL: u16 = 0x08;
+
+/// Codes of EV_KEY events for mouse buttons.
+pub const BTN_LEFT: u16 = 0x110;
+pub const BTN_RIGHT: u16 = 0x111;
+pub const BTN_MIDDLE: u16 = 0x112;
+
 pub type SysResult<T> = Result<T, SysError>;
@@ -2,6 +2,7 @@
 
 %define SECTOR_SIZE 512
 %define ENTRY_FIRST_CLUSTER 0x1a
+%define ENTRY_FILE_SIZE 0x1c
 
 fat_init:
     ; save boot device
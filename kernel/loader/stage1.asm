@@ -49,6 +49,7 @@ use16
     push es
     pop ds
 
+%if VBE_MODE
     ; query vbe info for the mode we want
     mov ax, 0x4f01
     mov cx, VBE_MODE
@@ -59,12 +60,35 @@ use16
     mov ax, 0x4f02
     mov bx, VBE_MODE | (1 << 14) ; linear frame buffer
     int 0x10
+%else
+    ; stay in text mode, which the kernel knows from the mode info being
+    ; all zero
+    xor eax, eax
+    mov di, EARLY_VBE_MODE_INFO
+    mov cx, 64
+    rep stosd
+%endif
 
     ; search for KERNEL.2 in root dir on disk
     mov ax, kernel2_filename
     call fat_find_file
+    call read_file
 
-cluster_read_loop:
+    ; the initrd is optional, and goes straight after the kernel. start.asm
+    ; moves it out of the way of the kernel's bss
+    mov eax, [kernel_read_ptr]
+    mov [EARLY_INITRD_BASE], eax
+    mov [EARLY_INITRD_LEN], dword 0
+    call find_initrd
+    jc loaded
+    mov [EARLY_INITRD_LEN], eax
+    call read_file
+    jmp loaded
+
+; reads the cluster chain starting at fatctx_current_cluster to
+; kernel_read_ptr, advancing it past the last cluster
+read_file:
+.cluster_read_loop:
     ; set up bufseg
     mov [lbapkt.bufseg], word read_buffer >> 4
 
@@ -116,7 +140,8 @@ cluster_read_loop:
 
     ; loop around
     cmp [fatctx_current_cluster], word 0xfff7
-    jb cluster_read_loop
+    jb .cluster_read_loop
+    ret
 
 loaded:
     ; print starting message
@@ -161,11 +186,37 @@ error_bios: ; pass msg in SI
 %define FATCTX_PTR fatctx
 %include "kernel/loader/fat.asm"
 
+; finds INITRD in the root dir, setting fatctx_current_cluster to its first
+; cluster and EAX to its size. sets CF if there isn't one, or it's empty
+find_initrd:
+    mov bx, fatctx_root_directory
+.search_file:
+    mov cx, 11
+    mov si, bx
+    mov di, initrd_filename
+    repe cmpsb
+    je .found_file
+    add bx, 0x20
+    cmp bx, fatctx_root_directory + 0x200
+    jb .search_file
+.none:
+    stc
+    ret
+.found_file:
+    mov eax, [bx + ENTRY_FILE_SIZE]
+    test eax, eax
+    jz .none
+    mov dx, [bx + ENTRY_FIRST_CLUSTER]
+    mov [fatctx_current_cluster], dx
+    clc
+    ret
+
 could_not_enable_a20 db "Could not enable A20 line", 0
 could_not_read_memory_map db "Could not read memory map from BIOS", 0
 
 starting db "Starting...", 0
 kernel2_filename db "KERNEL  2  "
+initrd_filename db "INITRD     "
 
 read_buffer equ 0x2000
 
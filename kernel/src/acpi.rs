@@ -0,0 +1,338 @@
+use core::mem;
+use core::ptr::{self, NonNull};
+use core::slice;
+
+use arrayvec::ArrayVec;
+
+use crate::mem::kvirt;
+use crate::mem::page::PAGE_SIZE;
+use crate::mem::phys::RawPhys;
+use crate::mem::MemoryExhausted;
+use crate::util::EarlyInit;
+
+pub mod fadt;
+pub mod madt;
+
+// the RSDP is somewhere on a 16 byte boundary in the first KiB of the EBDA,
+// whose segment the BIOS data area keeps at 0x40e, or else in the BIOS ROM:
+const EBDA_SEGMENT: u64 = 0x40e;
+const EBDA_SEARCH_SIZE: usize = 1024;
+const BIOS_ROM: u64 = 0xe0000;
+const BIOS_ROM_SIZE: usize = 0x20000;
+
+const RSDP_SIGNATURE: [u8; 8] = *b"RSD PTR ";
+// the part of the RSDP that the first checksum covers, all there is of it
+// before revision 2:
+const RSDP_V1_SIZE: usize = 20;
+
+const MAX_TABLES: usize = 32;
+
+// the AML that defines a sleep state's object is `Name (_Sx_, Package () {
+// SLP_TYPa, SLP_TYPb, ... })`, with the numbers as byte constants or, for 0
+// and 1, the Zero and One opcodes, which are 0 and 1 themselves:
+const AML_NAME_OP: u8 = 0x08;
+const AML_ROOT_PREFIX: u8 = b'\\';
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_BYTE_PREFIX: u8 = 0x0a;
+const AML_ONE_OP: u8 = 0x01;
+
+#[repr(C, packed)]
+#[allow(unused)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt: u32,
+    // from revision 2 on:
+    length: u32,
+    xsdt: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// The header every table but the RSDP starts with.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// Where a register is, in a table. Only the system memory and I/O port
+/// address spaces are of any use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+pub const ADDRESS_SPACE_MEMORY: u8 = 0;
+pub const ADDRESS_SPACE_IO: u8 = 1;
+
+/// Where a PCI segment group's configuration space is, according to MCFG.
+#[derive(Debug, Clone, Copy)]
+pub struct EcamRegion {
+    pub base: RawPhys,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+// MCFG has a region for each segment group after its header and 8 reserved
+// bytes:
+const MCFG_REGIONS: usize = 44;
+const MCFG_REGION_SIZE: usize = 16;
+
+#[derive(Debug)]
+pub enum AcpiError {
+    NoRsdp,
+    BadChecksum,
+    MemoryExhausted,
+}
+
+impl From<MemoryExhausted> for AcpiError {
+    fn from(_: MemoryExhausted) -> Self {
+        AcpiError::MemoryExhausted
+    }
+}
+
+// every table the RSDT or XSDT points to, mapped for good:
+static TABLES: EarlyInit<ArrayVec<[&'static [u8]; MAX_TABLES]>> = EarlyInit::new();
+
+/// Finds and maps the firmware's ACPI tables. If there aren't any, `find`
+/// finds nothing.
+// Safety: must not be called more than once
+pub unsafe fn init() -> Result<(), AcpiError> {
+    let mut tables = ArrayVec::new();
+    let result = load(&mut tables);
+
+    EarlyInit::set(&TABLES, tables);
+
+    result
+}
+
+/// Returns the table with the given signature, header and all. Tables with a
+/// bad checksum are left out.
+pub fn find(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    TABLES.iter()
+        .find(|table| &table[..4] == signature)
+        .cloned()
+}
+
+/// Returns the header of a table returned by `find`.
+pub fn header(table: &[u8]) -> SdtHeader {
+    unsafe { ptr::read_unaligned(table.as_ptr() as *const SdtHeader) }
+}
+
+/// Returns SLP_TYPa and SLP_TYPb for sleep state `state`, from the DSDT's
+/// `\_Sx_` object. This only recognises the plain way of defining it that
+/// firmware uses in practice, rather than running the AML.
+pub fn sleep_types(state: u8) -> Option<(u8, u8)> {
+    let dsdt = find(b"DSDT")?;
+    let name = [b'_', b'S', b'0' + state, b'_'];
+
+    (1..dsdt.len().saturating_sub(4))
+        .filter(|offset| dsdt[*offset..*offset + 4] == name)
+        .filter(|offset| {
+            dsdt[offset - 1] == AML_NAME_OP
+                || (*offset >= 2 && dsdt[offset - 1] == AML_ROOT_PREFIX && dsdt[offset - 2] == AML_NAME_OP)
+        })
+        .filter_map(|offset| sleep_package(&dsdt[offset + 4..]))
+        .next()
+}
+
+// parses the package that follows a sleep state's name:
+fn sleep_package(aml: &[u8]) -> Option<(u8, u8)> {
+    if *aml.first()? != AML_PACKAGE_OP {
+        return None;
+    }
+
+    // the package length's top two bits say how many more bytes it has, and
+    // the number of elements follows it:
+    let length_bytes = 1 + (*aml.get(1)? >> 6) as usize;
+    let elements = aml.get(1 + length_bytes + 1..)?;
+
+    let (a, elements) = aml_byte(elements)?;
+    let (b, _) = aml_byte(elements)?;
+
+    Some((a, b))
+}
+
+fn aml_byte(aml: &[u8]) -> Option<(u8, &[u8])> {
+    match *aml.first()? {
+        AML_BYTE_PREFIX => Some((*aml.get(1)?, &aml[2..])),
+        op if op <= AML_ONE_OP => Some((op, &aml[1..])),
+        _ => None,
+    }
+}
+
+/// Returns the regions of configuration space MCFG lists.
+pub fn ecam_regions() -> impl Iterator<Item = EcamRegion> {
+    let regions = find(b"MCFG")
+        .and_then(|table| table.get(MCFG_REGIONS..))
+        .unwrap_or(&[]);
+
+    regions.chunks_exact(MCFG_REGION_SIZE)
+        .map(|region| EcamRegion {
+            base: RawPhys(read_le(region, 0, 8)),
+            segment: read_le(region, 8, 2) as u16,
+            start_bus: region[10],
+            end_bus: region[11],
+        })
+}
+
+// reads the `len` byte little endian integer at `offset` in `bytes`, or 0 if
+// it isn't all there. tables grow new fields with each revision, and older
+// ones just end sooner:
+fn read_le(bytes: &[u8], offset: usize, len: usize) -> u64 {
+    match bytes.get(offset..offset + len) {
+        Some(field) => field.iter().rev().fold(0, |value, byte| value << 8 | *byte as u64),
+        None => 0,
+    }
+}
+
+/// Reads the generic address structure at `offset` in a table, if it's all
+/// there.
+pub fn generic_address(table: &[u8], offset: usize) -> Option<GenericAddress> {
+    let gas = table.get(offset..offset + 12)?;
+
+    Some(GenericAddress {
+        space: gas[0],
+        bit_width: gas[1],
+        bit_offset: gas[2],
+        access_size: gas[3],
+        address: read_le(gas, 4, 8),
+    })
+}
+
+unsafe fn load(tables: &mut ArrayVec<[&'static [u8]; MAX_TABLES]>) -> Result<(), AcpiError> {
+    let rsdp = find_rsdp()?.ok_or(AcpiError::NoRsdp)?;
+
+    // the XSDT has 64 bit pointers and the RSDT 32 bit ones, to the same
+    // tables:
+    let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt != 0 {
+        (map_table(rsdp.xsdt)?, 8)
+    } else {
+        (map_table(rsdp.rsdt as u64)?, 4)
+    };
+
+    let root = root.ok_or(AcpiError::BadChecksum)?;
+
+    for entry in root[mem::size_of::<SdtHeader>()..].chunks_exact(entry_size) {
+        if let Some(table) = map_table(read_le(entry, 0, entry_size))? {
+            if tables.try_push(table).is_err() {
+                crate::println!("acpi: more than {} tables, ignoring the rest", MAX_TABLES);
+                break;
+            }
+        }
+    }
+
+    // the DSDT isn't listed with the rest, the FADT points to it instead:
+    let dsdt = tables.iter()
+        .find(|table| &table[..4] == b"FACP")
+        .map(|fadt| fadt::dsdt_address(fadt));
+
+    if let Some(dsdt) = dsdt {
+        if let Some(table) = map_table(dsdt)? {
+            if tables.try_push(table).is_err() {
+                crate::println!("acpi: more than {} tables, ignoring the DSDT", MAX_TABLES);
+            }
+        }
+    }
+
+    crate::print!("acpi: rev {} tables:", rsdp.revision);
+
+    for table in tables.iter() {
+        crate::print!(" {}", core::str::from_utf8(&table[..4]).unwrap_or("????"));
+    }
+
+    crate::println!();
+
+    Ok(())
+}
+
+fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+// maps `len` bytes of physical memory from `phys`:
+unsafe fn map(phys: u64, len: usize) -> Result<(NonNull<u8>, usize, &'static [u8]), MemoryExhausted> {
+    let offset = phys as usize % PAGE_SIZE;
+    let pages = (offset + len + PAGE_SIZE - 1) / PAGE_SIZE;
+    let mapped = kvirt::map_phys(RawPhys(phys - offset as u64), pages)?;
+
+    Ok((mapped, pages, slice::from_raw_parts(mapped.as_ptr().add(offset), len)))
+}
+
+unsafe fn find_rsdp() -> Result<Option<Rsdp>, MemoryExhausted> {
+    let ebda = {
+        let (mapped, pages, bda) = map(EBDA_SEGMENT, 2)?;
+        let segment = u16::from_le_bytes([bda[0], bda[1]]);
+        kvirt::free_pages(mapped, pages);
+        (segment as u64) << 4
+    };
+
+    let mut areas = ArrayVec::<[(u64, usize); 2]>::new();
+
+    if ebda != 0 {
+        areas.push((ebda, EBDA_SEARCH_SIZE));
+    }
+
+    areas.push((BIOS_ROM, BIOS_ROM_SIZE));
+
+    for (base, len) in areas {
+        let (mapped, pages, area) = map(base, len)?;
+
+        let rsdp = (0..len).step_by(16)
+            .map(|offset| &area[offset..])
+            .filter(|rsdp| rsdp.len() >= mem::size_of::<Rsdp>() && rsdp[..8] == RSDP_SIGNATURE)
+            .map(|rsdp| (rsdp, ptr::read_unaligned(rsdp.as_ptr() as *const Rsdp)))
+            .find(|(bytes, rsdp)| {
+                checksum(&bytes[..RSDP_V1_SIZE])
+                    && (rsdp.revision < 2 || checksum(&bytes[..mem::size_of::<Rsdp>()]))
+            })
+            .map(|(_, rsdp)| rsdp);
+
+        kvirt::free_pages(mapped, pages);
+
+        if rsdp.is_some() {
+            return Ok(rsdp);
+        }
+    }
+
+    Ok(None)
+}
+
+// maps the whole of the table at `phys`, if its checksum is right:
+unsafe fn map_table(phys: u64) -> Result<Option<&'static [u8]>, MemoryExhausted> {
+    let length = {
+        let (mapped, pages, bytes) = map(phys, mem::size_of::<SdtHeader>())?;
+        let length = header(bytes).length as usize;
+        kvirt::free_pages(mapped, pages);
+        length
+    };
+
+    if length < mem::size_of::<SdtHeader>() {
+        return Ok(None);
+    }
+
+    let (mapped, pages, table) = map(phys, length)?;
+
+    if !checksum(table) {
+        kvirt::free_pages(mapped, pages);
+        return Ok(None);
+    }
+
+    Ok(Some(table))
+}
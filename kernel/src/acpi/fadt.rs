@@ -0,0 +1,73 @@
+use super::{generic_address, read_le, GenericAddress};
+
+// offsets of the fields used, from the start of the table. anything past the
+// end of an older, shorter FADT reads as 0:
+const FADT_DSDT: usize = 40;
+const FADT_SCI_INTERRUPT: usize = 46;
+const FADT_SMI_COMMAND: usize = 48;
+const FADT_ACPI_ENABLE: usize = 52;
+const FADT_ACPI_DISABLE: usize = 53;
+const FADT_PM1A_CONTROL: usize = 64;
+const FADT_PM1B_CONTROL: usize = 68;
+const FADT_CENTURY: usize = 108;
+const FADT_FLAGS: usize = 112;
+const FADT_RESET_REGISTER: usize = 116;
+const FADT_RESET_VALUE: usize = 128;
+const FADT_X_DSDT: usize = 140;
+
+/// Whether `reset_register` can be used.
+pub const FLAG_RESET_REGISTER: u32 = 1 << 10;
+
+/// The parts of the FADT the kernel has a use for, mostly where the power
+/// management registers are.
+#[derive(Debug, Clone, Copy)]
+#[allow(unused)]
+pub struct Fadt {
+    /// Where the DSDT is, which holds the AML for sleeping and such.
+    pub dsdt: u64,
+    /// The ISA IRQ that ACPI's system control interrupt comes in on.
+    pub sci_interrupt: u16,
+    /// The I/O port that hands ACPI over from SMM to the OS when
+    /// `acpi_enable` is written to it. 0 if ACPI is always in OS control.
+    pub smi_command: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    /// I/O ports of the power management control register blocks. The B
+    /// block is optional and 0 when there isn't one.
+    pub pm1a_control: u32,
+    pub pm1b_control: u32,
+    /// The CMOS register holding the century, or 0 if there isn't one.
+    pub century: u8,
+    pub flags: u32,
+    /// Writing `reset_value` here resets the machine, if `flags` says so.
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+/// Reads the FADT, if there is one.
+pub fn fadt() -> Option<Fadt> {
+    let table = super::find(b"FACP")?;
+    let field = |offset, len| read_le(table, offset, len);
+
+    Some(Fadt {
+        dsdt: dsdt_address(table),
+        sci_interrupt: field(FADT_SCI_INTERRUPT, 2) as u16,
+        smi_command: field(FADT_SMI_COMMAND, 4) as u32,
+        acpi_enable: field(FADT_ACPI_ENABLE, 1) as u8,
+        acpi_disable: field(FADT_ACPI_DISABLE, 1) as u8,
+        pm1a_control: field(FADT_PM1A_CONTROL, 4) as u32,
+        pm1b_control: field(FADT_PM1B_CONTROL, 4) as u32,
+        century: field(FADT_CENTURY, 1) as u8,
+        flags: field(FADT_FLAGS, 4) as u32,
+        reset_register: generic_address(table, FADT_RESET_REGISTER),
+        reset_value: field(FADT_RESET_VALUE, 1) as u8,
+    })
+}
+
+// the 64 bit DSDT pointer takes precedence where there is one:
+pub(super) fn dsdt_address(table: &[u8]) -> u64 {
+    match read_le(table, FADT_X_DSDT, 8) {
+        0 => read_le(table, FADT_DSDT, 4),
+        dsdt => dsdt,
+    }
+}
@@ -0,0 +1,146 @@
+use core::iter;
+
+use super::read_le;
+
+// the local APIC address and flags come after the header, then the entries:
+const MADT_FLAGS: usize = 40;
+const MADT_ENTRIES: usize = 44;
+
+// the machine has a pair of 8259 PICs as well as APICs:
+const FLAG_PCAT_COMPAT: u64 = 1 << 0;
+
+const ENTRY_LAPIC: u8 = 0;
+const ENTRY_IOAPIC: u8 = 1;
+const ENTRY_OVERRIDE: u8 = 2;
+const ENTRY_LAPIC_NMI: u8 = 4;
+const ENTRY_X2APIC: u8 = 9;
+
+const LAPIC_ENABLED: u64 = 1 << 0;
+// a disabled CPU can be brought online later if this is set, otherwise it
+// can't be used at all:
+const LAPIC_ONLINE_CAPABLE: u64 = 1 << 1;
+
+// polarity is in the bottom 2 bits of an override or NMI's flags, and trigger
+// mode in the 2 above. 0 in either means whatever the bus normally does:
+const POLARITY_HIGH: u64 = 0b01;
+const POLARITY_LOW: u64 = 0b11;
+const TRIGGER_EDGE: u64 = 0b01 << 2;
+const TRIGGER_LEVEL: u64 = 0b11 << 2;
+
+/// Whether an interrupt line is active high or low.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    High,
+    Low,
+}
+
+/// Whether an interrupt is signalled by an edge or a level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+/// An entry in the MADT. Entries of kinds not listed are left out.
+#[derive(Debug, Clone, Copy)]
+pub enum Entry {
+    /// A CPU and its local APIC.
+    Lapic { processor: u32, apic_id: u32, usable: bool },
+    /// An I/O APIC, handling global system interrupts from `gsi_base` on.
+    IoApic { id: u8, address: u32, gsi_base: u32 },
+    /// An ISA IRQ that isn't wired to the GSI of the same number, or doesn't
+    /// have ISA's polarity and trigger mode. `None` means the bus's usual.
+    Override { irq: u8, gsi: u32, polarity: Option<Polarity>, trigger: Option<Trigger> },
+    /// A local APIC's LINT pin wired to NMI. A processor of 0xff means every
+    /// processor's.
+    LapicNmi { processor: u8, lint: u8 },
+}
+
+/// Returns every entry in the MADT, in the order it lists them.
+pub fn entries() -> impl Iterator<Item = Entry> {
+    raw_entries().filter_map(parse)
+}
+
+// every entry, of whatever kind, type and length bytes first:
+fn raw_entries() -> impl Iterator<Item = &'static [u8]> {
+    let mut rest = super::find(b"APIC")
+        .and_then(|table| table.get(MADT_ENTRIES..))
+        .unwrap_or(&[]);
+
+    iter::from_fn(move || {
+        if rest.len() < 2 || (rest[1] as usize) < 2 || rest.len() < rest[1] as usize {
+            return None;
+        }
+
+        let (entry, next) = rest.split_at(rest[1] as usize);
+        rest = next;
+
+        Some(entry)
+    })
+}
+
+fn parse(entry: &[u8]) -> Option<Entry> {
+    let field = |offset, len| read_le(entry, offset, len);
+
+    match entry[0] {
+        ENTRY_LAPIC => Some(Entry::Lapic {
+            processor: field(2, 1) as u32,
+            apic_id: field(3, 1) as u32,
+            usable: field(4, 4) & (LAPIC_ENABLED | LAPIC_ONLINE_CAPABLE) != 0,
+        }),
+        ENTRY_X2APIC => Some(Entry::Lapic {
+            processor: field(12, 4) as u32,
+            apic_id: field(4, 4) as u32,
+            usable: field(8, 4) & (LAPIC_ENABLED | LAPIC_ONLINE_CAPABLE) != 0,
+        }),
+        ENTRY_IOAPIC => Some(Entry::IoApic {
+            id: field(2, 1) as u8,
+            address: field(4, 4) as u32,
+            gsi_base: field(8, 4) as u32,
+        }),
+        ENTRY_OVERRIDE => {
+            let flags = field(8, 2);
+
+            Some(Entry::Override {
+                irq: field(3, 1) as u8,
+                gsi: field(4, 4) as u32,
+                polarity: match flags & 0b11 {
+                    POLARITY_HIGH => Some(Polarity::High),
+                    POLARITY_LOW => Some(Polarity::Low),
+                    _ => None,
+                },
+                trigger: match flags & 0b1100 {
+                    TRIGGER_EDGE => Some(Trigger::Edge),
+                    TRIGGER_LEVEL => Some(Trigger::Level),
+                    _ => None,
+                },
+            })
+        }
+        ENTRY_LAPIC_NMI => Some(Entry::LapicNmi {
+            processor: field(2, 1) as u8,
+            lint: field(5, 1) as u8,
+        }),
+        _ => None,
+    }
+}
+
+/// Whether there are 8259 PICs to mask, besides the APICs. Without an MADT
+/// to say, there are.
+pub fn has_pics() -> bool {
+    match super::find(b"APIC") {
+        Some(table) => read_le(table, MADT_FLAGS, 4) & FLAG_PCAT_COMPAT != 0,
+        None => true,
+    }
+}
+
+/// The number of CPUs the MADT lists that can be used, if there's an MADT.
+pub fn usable_cpus() -> Option<usize> {
+    super::find(b"APIC")?;
+
+    Some(entries()
+        .filter(|entry| match entry {
+            Entry::Lapic { usable, .. } => *usable,
+            _ => false,
+        })
+        .count())
+}
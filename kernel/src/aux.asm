@@ -45,3 +45,73 @@ bits 64
 ;     mov esp, ebp
 ;     pop ebp
 ;     ret
+
+; Calls f(arg) with the stack pointer set to stack_top, then switches back to
+; the original stack and returns.
+;
+; extern "C" {
+;     fn call_on_stack(arg: *mut u8, f: extern "C" fn(*mut u8), stack_top: u64);
+; }
+global call_on_stack
+call_on_stack:
+    ; set up frame pointer, this also realigns the stack to 16 bytes
+    push rbp
+    mov rbp, rsp
+
+    ; switch stacks, arg is already in rdi
+    mov rsp, rdx
+    call rsi
+
+    ; switch back and return
+    mov rsp, rbp
+    pop rbp
+    ret
+
+; Copies len bytes from src to dst, where either may be a user pointer that
+; hasn't been fully validated. Returns 0, or -1 if the copy faulted part way.
+;
+; extern "C" {
+;     fn user_copy(dst: *mut u8, src: *const u8, len: usize) -> u64;
+; }
+;
+; Copies a NUL terminated string of at most len bytes from src to dst, with
+; the same caveats as user_copy. Returns the length of the string, len if it
+; wasn't terminated within len bytes, or -1 if the copy faulted part way.
+;
+; extern "C" {
+;     fn user_strncpy(dst: *mut u8, src: *const u8, len: usize) -> u64;
+; }
+;
+; The page fault handler resumes faults on user addresses between
+; user_copy_begin and user_copy_end at user_access_fault, see
+; mem/user.rs. Nothing in between may touch the stack.
+global user_copy
+global user_strncpy
+global user_copy_begin
+global user_copy_end
+global user_access_fault
+user_copy_begin:
+user_copy:
+    mov rcx, rdx
+    rep movsb
+    xor eax, eax
+    ret
+
+user_strncpy:
+    xor eax, eax
+.next:
+    cmp rax, rdx
+    je .done
+    mov cl, [rsi + rax]
+    mov [rdi + rax], cl
+    test cl, cl
+    jz .done
+    inc rax
+    jmp .next
+.done:
+    ret
+user_copy_end:
+
+user_access_fault:
+    mov rax, -1
+    ret
@@ -0,0 +1,88 @@
+//! Compile time kernel configuration.
+
+use crate::task::SchedPolicy;
+
+/// Frequency of the scheduler tick, in Hz. The timer only interrupts this
+/// often while there's a task running that might need preempting.
+pub const TIMER_HZ: usize = 20;
+
+/// Length of a task's time slice, in timer ticks. A task still running user
+/// code once it's up is preempted in favour of the next runnable task.
+pub const TIME_SLICE_TICKS: u64 = 2;
+
+/// How the scheduler picks the next task to run. RoundRobin always runs the
+/// highest priority runnable task, Fair shares the CPU between all runnable
+/// tasks in proportion to their priority.
+pub const SCHED_POLICY: SchedPolicy = SchedPolicy::RoundRobin;
+
+/// Number of lines that have scrolled off the top of the text console it
+/// keeps, to be scrolled back to with Shift+PageUp.
+pub const CONSOLE_SCROLLBACK: usize = 512;
+
+/// Whether kernel output goes to the first serial port instead of the
+/// screen, once the port is set up. For machines with no screen to look at.
+pub const SERIAL_CONSOLE: bool = false;
+
+/// Number of pages in each task's kernel stack, not counting the unmapped
+/// guard page below it.
+pub const KERNEL_STACK_PAGES: usize = 8;
+
+/// Whether the kernel heap checks itself for corruption. Every allocation is
+/// surrounded by redzones which are checked on free, and freed memory is
+/// poisoned and checked for writes before it's handed out again. Costs memory
+/// and time, so it's only meant for debugging.
+pub const HEAP_CHECKS: bool = false;
+
+/// Size in bytes of the buffer between the two ends of a pipe. Writers block
+/// once it's full.
+pub const PIPE_BUFFER_SIZE: usize = 4096;
+
+/// Number of messages that can be queued towards either end of a channel
+/// before senders block.
+pub const CHANNEL_QUEUE_LEN: usize = 16;
+
+/// Number of connections to a listener that can wait to be accepted before
+/// further attempts to connect fail with WouldBlock.
+pub const CHANNEL_BACKLOG: usize = 8;
+
+/// Number of calls that can be waiting to be received on an endpoint before
+/// further callers block.
+pub const ENDPOINT_QUEUE_LEN: usize = 16;
+
+/// Number of pages at the top of a new program's stack that its arguments
+/// and environment may take up. Must be a power of two, and no more than
+/// USER_STACK_PAGES in exec.rs.
+pub const EXEC_ARGS_PAGES: usize = 8;
+
+/// How many pages below USER_STACK_TOP in exec.rs ASLR may move the top of a
+/// new program's stack.
+pub const ASLR_STACK_PAGES: usize = 0x1_0000;
+
+/// How many pages past the end of its executable ASLR may move the start of a
+/// new program's heap.
+pub const ASLR_HEAP_PAGES: usize = 0x1_0000;
+
+/// How many pages above MMAP_BASE in vma.rs ASLR may move where the kernel
+/// starts placing a new program's mappings.
+pub const ASLR_MMAP_PAGES: usize = 0x100_0000;
+
+/// Longest name, in bytes, a tmpfs directory entry may have.
+pub const TMPFS_NAME_MAX: usize = 64;
+
+/// Longest name, in bytes, a device node in /dev may have.
+pub const DEVFS_NAME_MAX: usize = 32;
+
+/// Longest name, in bytes, a block device may be registered under.
+pub const BLOCK_NAME_MAX: usize = 16;
+
+/// How often, in milliseconds, the writeback task writes blocks written to
+/// the block cache back to their disks.
+pub const WRITEBACK_INTERVAL_MS: u64 = 5000;
+
+/// Maximum number of CPUs the kernel will bring up. Must match MAX_CPUS in
+/// consts.asm.
+pub const MAX_CPUS: usize = 8;
+
+/// Maximum number of NUMA nodes physical memory is split between. Memory on
+/// any further nodes is treated as belonging to the last one.
+pub const MAX_NODES: usize = 4;
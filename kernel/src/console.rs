@@ -1,15 +1,34 @@
+use core::cmp;
 use core::fmt::{self, Write};
 
+use crate::config;
 use crate::critical::Critical;
+use crate::device::serial;
+use crate::mem::page::{self, PageFlags, PAGE_SIZE};
+use crate::mem::phys::{Phys, RawPhys};
 use crate::sync::{Mutex, MutexGuard};
 
+use self::term::Term;
+
+mod fb;
+mod fbcon;
+mod term;
+pub mod tty;
 mod vga;
 
+const VRAM_SIZE: usize = 8 * 1024 * 1024;
+
+// where the screen's memory is mapped, text buffer or framebuffer:
+#[link_section=".unalloc"]
+static mut VRAM: [u8; VRAM_SIZE] = [0; VRAM_SIZE];
+
 static CONSOLE: Mutex<Console> = Mutex::new(Console::PortE9(PortE9));
 
 pub(self) enum Console {
     PortE9(PortE9),
-    VgaText(vga::VgaText),
+    VgaText(Term<vga::VgaText>),
+    Framebuffer(Term<fbcon::FbText>),
+    Serial(SerialConsole),
 }
 
 impl Write for Console {
@@ -17,6 +36,18 @@ impl Write for Console {
         match self {
             Console::PortE9(con) => con.write_str(s),
             Console::VgaText(con) => con.write_str(s),
+            Console::Framebuffer(con) => con.write_str(s),
+            Console::Serial(con) => con.write_str(s),
+        }
+    }
+}
+
+impl Console {
+    fn scroll_view(&mut self, half_screens: isize) {
+        match self {
+            Console::VgaText(term) => term.scroll_view(half_screens * (term.rows() / 2) as isize),
+            Console::Framebuffer(term) => term.scroll_view(half_screens * (term.rows() / 2) as isize),
+            Console::PortE9(_) | Console::Serial(_) => {}
         }
     }
 }
@@ -29,6 +60,59 @@ pub(self) fn set(console: Console) {
     *CONSOLE.lock() = console;
 }
 
+// called from start.asm, with the screen as the loader left it. text mode
+// if it's there, otherwise text drawn on whatever framebuffer the loader
+// found, otherwise port 0xe9 is all there is:
+#[no_mangle]
+pub unsafe extern "C" fn console_init(vbe_mode_info: *const fb::VbeModeInfo, bios_font: *const u8) {
+    let vbe_mode_info = &*vbe_mode_info;
+
+    if vbe_mode_info.text_mode() {
+        let vram = map_vram(RawPhys(vga::TEXT_BUFFER), PAGE_SIZE);
+        set(Console::VgaText(Term::new(vga::VgaText::new(vram))));
+    } else if let Some(mut info) = vbe_mode_info.framebuffer() {
+        // only so much of a huge screen fits where it's mapped:
+        info.height = cmp::min(info.height, VRAM_SIZE / info.pitch);
+
+        let vram = map_vram(info.base, info.pitch * info.height);
+        let fb = fb::Framebuffer::new(info, vram);
+        set(Console::Framebuffer(Term::new(fbcon::FbText::new(fb, bios_font))));
+    }
+}
+
+unsafe fn map_vram(base: RawPhys, len: usize) -> *mut u8 {
+    let virt = &mut VRAM as *mut [u8; VRAM_SIZE] as *mut u8;
+
+    for off in (0..len).step_by(PAGE_SIZE) {
+        let phys = Phys::new(RawPhys(base.0 + off as u64));
+        let virt = virt.add(off);
+
+        page::map(phys, virt, PageFlags::PRESENT | PageFlags::WRITE | PageFlags::NO_EXECUTE)
+            .expect("page::map in console_init");
+    }
+
+    virt
+}
+
+/// Scrolls the console back by half a screen through what went off the top
+/// of it, if it keeps that.
+pub fn page_up() {
+    CONSOLE.lock().scroll_view(1);
+}
+
+/// Scrolls the console forward by half a screen, towards what's on it now.
+pub fn page_down() {
+    CONSOLE.lock().scroll_view(-1);
+}
+
+/// Moves the console to COM1 if the kernel is configured to have it there.
+/// The serial port must have been set up first.
+pub fn init_serial() {
+    if config::SERIAL_CONSOLE {
+        set(Console::Serial(SerialConsole));
+    }
+}
+
 pub fn failsafe<'a>(_crit: &'a Critical) -> impl Write + 'a {
     PortE9
 }
@@ -62,3 +146,12 @@ impl Write for PortE9 {
         Ok(())
     }
 }
+
+pub(self) struct SerialConsole;
+
+impl Write for SerialConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        serial::write_console(s.as_bytes());
+        Ok(())
+    }
+}
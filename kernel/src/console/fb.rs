@@ -0,0 +1,185 @@
+use core::ptr;
+
+use crate::mem::phys::RawPhys;
+
+// VBE memory models, the framebuffer must be this one to draw in RGB:
+const MEMORY_MODEL_DIRECT: u8 = 6;
+
+#[repr(packed)]
+#[allow(unused)]
+pub struct VbeModeInfo {
+    attrs: u16,
+    win_a: u8,
+    win_b: u8,
+    granularity: u16,
+    winsize: u16,
+    seg_a: u16,
+    seg_b: u16,
+    real_fct_ptr: u32,
+    pitch: u16, // bytes per scanline
+    x_res: u16,
+    y_res: u16,
+    w_char: u8,
+    y_char: u8,
+    planes: u8,
+    bpp: u8,
+    banks: u8,
+    memory_model: u8,
+    bank_size: u8,
+    image_pages: u8,
+    reserved0: u8,
+    red_mask: u8,
+    red_pos: u8,
+    green_mask: u8,
+    green_pos: u8,
+    blue_mask: u8,
+    blue_pos: u8,
+    rsv_mask: u8,
+    rsv_pos: u8,
+    directcolor_attrs: u8,
+    phys_base: u32,
+    reserved1: u32,
+    reserved2: u32,
+}
+
+impl VbeModeInfo {
+    /// Whether the loader left the screen in text mode, which it says by
+    /// leaving the mode info all zero.
+    pub fn text_mode(&self) -> bool {
+        self.phys_base == 0
+    }
+
+    /// The linear framebuffer the loader switched to, if it's one that can
+    /// be drawn on.
+    pub fn framebuffer(&self) -> Option<FramebufferInfo> {
+        if self.text_mode() || self.memory_model != MEMORY_MODEL_DIRECT {
+            return None;
+        }
+
+        if self.bpp != 24 && self.bpp != 32 {
+            return None;
+        }
+
+        Some(FramebufferInfo {
+            base: RawPhys(self.phys_base as u64),
+            width: self.x_res as usize,
+            height: self.y_res as usize,
+            pitch: self.pitch as usize,
+            bytes_per_pixel: self.bpp as usize / 8,
+            red_pos: self.red_pos,
+            green_pos: self.green_pos,
+            blue_pos: self.blue_pos,
+        })
+    }
+}
+
+/// A linear framebuffer as a loader hands it over, whether found through
+/// VBE or UEFI's GOP: rows of pixels one after the other, each pixel 8 bits
+/// of red, green and blue somewhere in 3 or 4 bytes.
+#[derive(Debug, Clone, Copy)]
+pub struct FramebufferInfo {
+    pub base: RawPhys,
+    pub width: usize,
+    pub height: usize,
+    /// Bytes from the start of one row to the next.
+    pub pitch: usize,
+    pub bytes_per_pixel: usize,
+    /// Where each colour's byte is in a pixel, in bits from the bottom.
+    pub red_pos: u8,
+    pub green_pos: u8,
+    pub blue_pos: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rgb(pub u8, pub u8, pub u8);
+
+/// Pixels on a linear framebuffer, drawn in rectangles.
+pub struct Framebuffer {
+    vram: *mut u8,
+    info: FramebufferInfo,
+}
+
+impl Framebuffer {
+    /// Takes the framebuffer described by `info`, mapped at `vram`.
+    pub unsafe fn new(info: FramebufferInfo, vram: *mut u8) -> Framebuffer {
+        Framebuffer { vram, info }
+    }
+
+    pub fn width(&self) -> usize {
+        self.info.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.info.height
+    }
+
+    fn encode(&self, Rgb(red, green, blue): Rgb) -> u32 {
+        (red as u32) << self.info.red_pos
+            | (green as u32) << self.info.green_pos
+            | (blue as u32) << self.info.blue_pos
+    }
+
+    fn pixel(&self, x: usize, y: usize) -> *mut u8 {
+        assert!(x < self.info.width && y < self.info.height, "Framebuffer::pixel: out of bounds");
+
+        unsafe { self.vram.add(y * self.info.pitch + x * self.info.bytes_per_pixel) }
+    }
+
+    fn write(&mut self, x: usize, y: usize, value: u32) {
+        let pixel = self.pixel(x, y);
+
+        unsafe {
+            if self.info.bytes_per_pixel == 4 {
+                ptr::write_volatile(pixel as *mut u32, value);
+            } else {
+                ptr::write_volatile(pixel, value as u8);
+                ptr::write_volatile(pixel.add(1), (value >> 8) as u8);
+                ptr::write_volatile(pixel.add(2), (value >> 16) as u8);
+            }
+        }
+    }
+
+    pub fn fill(&mut self, x: usize, y: usize, width: usize, height: usize, colour: Rgb) {
+        let value = self.encode(colour);
+
+        for y in y..y + height {
+            for x in x..x + width {
+                self.write(x, y, value);
+            }
+        }
+    }
+
+    /// Draws a bitmap of one bit per pixel, a byte for each row with the
+    /// leftmost pixel in the top bit, in `fg` where bits are set and `bg`
+    /// where they aren't.
+    pub fn draw_mono(&mut self, x: usize, y: usize, rows: &[u8], fg: Rgb, bg: Rgb) {
+        let (fg, bg) = (self.encode(fg), self.encode(bg));
+
+        for (dy, &bits) in rows.iter().enumerate() {
+            for dx in 0..8 {
+                self.write(x + dx, y + dy, if bits & (0x80 >> dx) != 0 { fg } else { bg });
+            }
+        }
+    }
+
+    /// Draws `width` by `height` pixels, the colour of each given by `image`
+    /// from its position in the rectangle.
+    pub fn draw_image(&mut self, x: usize, y: usize, width: usize, height: usize, image: impl Fn(usize, usize) -> Rgb) {
+        for dy in 0..height {
+            for dx in 0..width {
+                let value = self.encode(image(dx, dy));
+                self.write(x + dx, y + dy, value);
+            }
+        }
+    }
+
+    /// Moves the pixels `width` wide from `x`, in the rows from `top` to
+    /// `bottom`, up by `by` rows. The bottom `by` rows are left as they were.
+    pub fn scroll_up(&mut self, x: usize, width: usize, top: usize, bottom: usize, by: usize) {
+        let len = width * self.info.bytes_per_pixel;
+
+        for y in top..bottom - by {
+            unsafe { ptr::copy(self.pixel(x, y + by), self.pixel(x, y), len); }
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use core::cmp;
+use core::ptr;
+
+use crate::console::fb::{Framebuffer, Rgb};
+use crate::console::term::{Cell, Display, MAX_COLS, MAX_ROWS};
+
+type Font = [u8; 4096];
+static mut FONT: Font = [0u8; 4096];
+
+const CHAR_HEIGHT: usize = 16;
+const CHAR_WIDTH: usize = 8;
+
+// the cursor is an underline this many pixels high:
+const CURSOR_HEIGHT: usize = 2;
+
+// the 16 CGA colours cell attributes index:
+const PALETTE: [Rgb; 16] = [
+    Rgb(0x00, 0x00, 0x00), Rgb(0x00, 0x00, 0xaa), Rgb(0x00, 0xaa, 0x00), Rgb(0x00, 0xaa, 0xaa),
+    Rgb(0xaa, 0x00, 0x00), Rgb(0xaa, 0x00, 0xaa), Rgb(0xaa, 0x55, 0x00), Rgb(0xaa, 0xaa, 0xaa),
+    Rgb(0x55, 0x55, 0x55), Rgb(0x55, 0x55, 0xff), Rgb(0x55, 0xff, 0x55), Rgb(0x55, 0xff, 0xff),
+    Rgb(0xff, 0x55, 0x55), Rgb(0xff, 0x55, 0xff), Rgb(0xff, 0xff, 0x55), Rgb(0xff, 0xff, 0xff),
+];
+
+const CURSOR: Rgb = PALETTE[7];
+
+// the crab sits in the bottom right corner when there's room for it beside
+// 80 columns of text:
+const CRAB_SIZE: usize = 256;
+const CRAB_HEADER: usize = 0x36;
+
+/// Text drawn on a framebuffer in the BIOS font, for when the screen isn't
+/// in text mode.
+pub struct FbText {
+    fb: Framebuffer,
+    font: &'static Font,
+    cols: usize,
+    rows: usize,
+}
+
+impl FbText {
+    /// Takes over `fb`, copying the font from where the loader put it.
+    pub unsafe fn new(mut fb: Framebuffer, font: *const u8) -> FbText {
+        ptr::copy(font, &mut FONT as *mut Font as *mut u8, 4096);
+
+        let crab = fb.width() >= CRAB_SIZE + 80 * CHAR_WIDTH && fb.height() >= CRAB_SIZE;
+        let text_width = if crab { fb.width() - CRAB_SIZE } else { fb.width() };
+
+        fb.fill(0, 0, fb.width(), fb.height(), PALETTE[0]);
+
+        if crab {
+            let image = include_bytes!("../../../crab.bmp");
+            let (x, y) = (fb.width() - CRAB_SIZE, fb.height() - CRAB_SIZE);
+
+            // blue, green, red:
+            fb.draw_image(x, y, CRAB_SIZE, CRAB_SIZE, |x, y| {
+                let pixel = CRAB_HEADER + (y * CRAB_SIZE + x) * 3;
+                Rgb(image[pixel + 2], image[pixel + 1], image[pixel])
+            });
+        }
+
+        FbText {
+            cols: cmp::min(text_width / CHAR_WIDTH, MAX_COLS),
+            rows: cmp::min(fb.height() / CHAR_HEIGHT, MAX_ROWS),
+            fb,
+            font: &FONT,
+        }
+    }
+}
+
+impl Display for FbText {
+    fn size(&self) -> (usize, usize) {
+        (self.cols, self.rows)
+    }
+
+    fn draw(&mut self, col: usize, row: usize, cell: Cell) {
+        let glyph = &self.font[cell.ch as usize * CHAR_HEIGHT..][..CHAR_HEIGHT];
+
+        self.fb.draw_mono(col * CHAR_WIDTH, row * CHAR_HEIGHT, glyph,
+            PALETTE[cell.fg() as usize], PALETTE[cell.bg() as usize]);
+    }
+
+    fn scroll_up(&mut self) {
+        self.fb.scroll_up(0, self.cols * CHAR_WIDTH, 0, self.rows * CHAR_HEIGHT, CHAR_HEIGHT);
+    }
+
+    fn set_cursor(&mut self, at: Option<(usize, usize)>) {
+        if let Some((col, row)) = at {
+            let y = (row + 1) * CHAR_HEIGHT - CURSOR_HEIGHT;
+            self.fb.fill(col * CHAR_WIDTH, y, CHAR_WIDTH, CURSOR_HEIGHT, CURSOR);
+        }
+    }
+}
@@ -0,0 +1,446 @@
+use core::cmp;
+use core::fmt::{self, Write};
+use core::ops::Range;
+
+use crate::config::CONSOLE_SCROLLBACK;
+
+/// Largest screen a terminal can fill, in characters.
+pub const MAX_COLS: usize = 160;
+pub const MAX_ROWS: usize = 64;
+
+// the screen and the scrollback above it, as one ring of lines:
+const LINES: usize = MAX_ROWS + CONSOLE_SCROLLBACK;
+
+// a CSI sequence with more parameters than this has the rest ignored:
+const MAX_PARAMS: usize = 4;
+
+const TAB_WIDTH: usize = 8;
+
+const ESC: u8 = 0x1b;
+
+// CGA colours, what the attribute byte indexes:
+const LIGHT_GREY: u8 = 7;
+const BLACK: u8 = 0;
+const BRIGHT: u8 = 8;
+
+// ANSI numbers its colours red, green, blue where CGA has blue, green, red:
+const ANSI_TO_CGA: [u8; 8] = [0, 4, 2, 6, 1, 5, 3, 7];
+
+static mut RING: [[Cell; MAX_COLS]; LINES] = [[Cell { ch: 0, attr: 0 }; MAX_COLS]; LINES];
+
+/// One character on the screen and its colours, laid out as in VGA text
+/// mode: a code page 437 character, and an attribute with the foreground
+/// colour in its low 4 bits and the background in its high 4, each an index
+/// into the 16 CGA colours.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cell {
+    pub ch: u8,
+    pub attr: u8,
+}
+
+impl Cell {
+    pub fn fg(&self) -> u8 {
+        self.attr & 0x0f
+    }
+
+    pub fn bg(&self) -> u8 {
+        self.attr >> 4
+    }
+}
+
+/// Something a terminal shows its screen on, as a grid of cells.
+pub trait Display {
+    /// Columns and rows in the grid, no more than MAX_COLS and MAX_ROWS.
+    fn size(&self) -> (usize, usize);
+
+    fn draw(&mut self, col: usize, row: usize, cell: Cell);
+
+    /// Moves every row up by one, losing the top one. The bottom row is
+    /// drawn again straight after.
+    fn scroll_up(&mut self);
+
+    /// Shows the cursor at a cell, or hides it. The cell under the cursor's
+    /// old position has been drawn again before this is called.
+    fn set_cursor(&mut self, at: Option<(usize, usize)>);
+}
+
+enum State {
+    Normal,
+    // after ESC:
+    Escape,
+    // after ESC [, collecting parameters:
+    Csi,
+}
+
+/// A terminal in the manner of a VT100: text written to it is put on its
+/// display, with escape sequences moving the cursor, erasing and setting
+/// colours. Lines scrolled off the top are kept to scroll back to.
+pub struct Term<D> {
+    display: D,
+    cols: usize,
+    rows: usize,
+    ring: &'static mut [[Cell; MAX_COLS]; LINES],
+    // index in the ring of the top row of the screen:
+    top: usize,
+    // how many lines above the screen there are to scroll back to:
+    history: usize,
+    // how many lines back the view is scrolled, zero when showing the
+    // screen:
+    view: usize,
+    // the column is cols after writing to the last one, the next character
+    // goes on a new line:
+    col: usize,
+    row: usize,
+    saved: (usize, usize),
+    // where the display's cursor is, if it's showing:
+    shown_cursor: Option<(usize, usize)>,
+    cursor_visible: bool,
+    fg: u8,
+    bg: u8,
+    bold: bool,
+    reverse: bool,
+    state: State,
+    params: [u16; MAX_PARAMS],
+    param_count: usize,
+    // whether the sequence started with '?', as the DEC private ones do:
+    private: bool,
+}
+
+impl<D: Display> Term<D> {
+    /// Takes over `display`, clearing it.
+    // Safety: must not be called more than once, there's only the one ring
+    // of lines
+    pub unsafe fn new(display: D) -> Term<D> {
+        let (cols, rows) = display.size();
+
+        assert!(cols <= MAX_COLS && rows <= MAX_ROWS, "Term::new: display too big");
+
+        let mut term = Term {
+            display,
+            cols,
+            rows,
+            ring: &mut RING,
+            top: 0,
+            history: 0,
+            view: 0,
+            col: 0,
+            row: 0,
+            saved: (0, 0),
+            shown_cursor: None,
+            cursor_visible: true,
+            fg: LIGHT_GREY,
+            bg: BLACK,
+            bold: false,
+            reverse: false,
+            state: State::Normal,
+            params: [0; MAX_PARAMS],
+            param_count: 0,
+            private: false,
+        };
+
+        term.erase_rows(0..rows);
+        term.update_cursor();
+        term
+    }
+
+    /// Scrolls the view back through the lines that went off the top of the
+    /// screen, or forward towards the screen with a negative count. Writing
+    /// anything scrolls it back to the screen.
+    pub fn scroll_view(&mut self, lines: isize) {
+        let view = cmp::min(cmp::max(self.view as isize + lines, 0) as usize, self.history);
+
+        if view != self.view {
+            self.view = view;
+            self.redraw();
+            self.update_cursor();
+        }
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    fn attr(&self) -> u8 {
+        let fg = if self.bold { self.fg | BRIGHT } else { self.fg };
+        let (fg, bg) = if self.reverse { (self.bg, fg) } else { (fg, self.bg) };
+
+        bg << 4 | fg
+    }
+
+    fn line(&mut self, row: usize) -> &mut [Cell; MAX_COLS] {
+        &mut self.ring[(self.top + row) % LINES]
+    }
+
+    fn put(&mut self, col: usize, row: usize, cell: Cell) {
+        self.line(row)[col] = cell;
+        self.display.draw(col, row, cell);
+    }
+
+    fn erase(&mut self, row: usize, cols: Range<usize>) {
+        let blank = Cell { ch: b' ', attr: self.attr() };
+
+        for col in cols {
+            self.put(col, row, blank);
+        }
+    }
+
+    fn erase_rows(&mut self, rows: Range<usize>) {
+        for row in rows {
+            self.erase(row, 0..self.cols);
+        }
+    }
+
+    fn redraw(&mut self) {
+        for row in 0..self.rows {
+            let line = (self.top + LINES - self.view + row) % LINES;
+
+            for col in 0..self.cols {
+                self.display.draw(col, row, self.ring[line][col]);
+            }
+        }
+    }
+
+    // shows the cursor where it is now, which is nowhere when scrolled back:
+    fn update_cursor(&mut self) {
+        let cursor = if self.cursor_visible && self.view == 0 {
+            Some((cmp::min(self.col, self.cols - 1), self.row))
+        } else {
+            None
+        };
+
+        if cursor == self.shown_cursor {
+            return;
+        }
+
+        if let Some((col, row)) = self.shown_cursor {
+            if self.view == 0 {
+                let cell = self.line(row)[col];
+                self.display.draw(col, row, cell);
+            }
+        }
+
+        self.display.set_cursor(cursor);
+        self.shown_cursor = cursor;
+    }
+
+    fn scroll(&mut self) {
+        self.top = (self.top + 1) % LINES;
+        self.history = cmp::min(self.history + 1, LINES - self.rows);
+
+        self.display.scroll_up();
+
+        // the display's cursor moved up with everything else:
+        if let Some((col, row)) = self.shown_cursor {
+            self.shown_cursor = if row > 0 { Some((col, row - 1)) } else { None };
+        }
+
+        let row = self.rows - 1;
+        self.erase(row, 0..self.cols);
+    }
+
+    fn newline(&mut self) {
+        self.col = 0;
+
+        if self.row + 1 < self.rows {
+            self.row += 1;
+        } else {
+            self.scroll();
+        }
+    }
+
+    fn print(&mut self, ch: u8) {
+        if self.col == self.cols {
+            self.newline();
+        }
+
+        let cell = Cell { ch, attr: self.attr() };
+        self.put(self.col, self.row, cell);
+        self.col += 1;
+    }
+
+    fn feed(&mut self, byte: u8) {
+        match self.state {
+            State::Normal => match byte {
+                b'\n' => self.newline(),
+                b'\r' => self.col = 0,
+                b'\t' => self.col = cmp::min((self.col / TAB_WIDTH + 1) * TAB_WIDTH, self.cols - 1),
+                // backspace only moves the cursor, the character stays:
+                0x08 => self.col = cmp::min(self.col, self.cols - 1).saturating_sub(1),
+                ESC => self.state = State::Escape,
+                // bell and the rest of the control characters do nothing:
+                0x00..=0x1f | 0x7f => {}
+                _ => self.print(byte),
+            },
+            State::Escape => {
+                self.state = State::Normal;
+
+                match byte {
+                    b'[' => {
+                        self.params = [0; MAX_PARAMS];
+                        self.param_count = 0;
+                        self.private = false;
+                        self.state = State::Csi;
+                    }
+                    b'7' => self.saved = (self.col, self.row),
+                    b'8' => self.restore(),
+                    b'c' => self.reset(),
+                    _ => {}
+                }
+            }
+            State::Csi => match byte {
+                b'0'..=b'9' => {
+                    if self.param_count == 0 {
+                        self.param_count = 1;
+                    }
+
+                    if let Some(param) = self.params.get_mut(self.param_count - 1) {
+                        *param = param.saturating_mul(10).saturating_add((byte - b'0') as u16);
+                    }
+                }
+                b';' => {
+                    // an empty first parameter still counts:
+                    self.param_count = cmp::max(self.param_count, 1) + 1;
+                }
+                b'?' => self.private = true,
+                0x40..=0x7e => {
+                    self.state = State::Normal;
+                    self.csi(byte);
+                }
+                // anything else can't be in a sequence, give up on it:
+                _ => self.state = State::Normal,
+            },
+        }
+    }
+
+    // parameter n, with 0 and missing meaning `default`:
+    fn param(&self, n: usize, default: usize) -> usize {
+        match self.params.get(n) {
+            Some(&param) if n < self.param_count && param != 0 => param as usize,
+            _ => default,
+        }
+    }
+
+    fn csi(&mut self, command: u8) {
+        let col = cmp::min(self.col, self.cols - 1);
+
+        if self.private {
+            // only showing and hiding the cursor is understood:
+            if self.param(0, 0) == 25 {
+                match command {
+                    b'h' => self.cursor_visible = true,
+                    b'l' => self.cursor_visible = false,
+                    _ => {}
+                }
+            }
+
+            return;
+        }
+
+        match command {
+            b'A' => self.row = self.row.saturating_sub(self.param(0, 1)),
+            b'B' => self.row = cmp::min(self.row + self.param(0, 1), self.rows - 1),
+            b'C' => self.col = cmp::min(col + self.param(0, 1), self.cols - 1),
+            b'D' => self.col = col.saturating_sub(self.param(0, 1)),
+            b'G' => self.col = cmp::min(self.param(0, 1), self.cols) - 1,
+            b'H' | b'f' => {
+                self.row = cmp::min(self.param(0, 1), self.rows) - 1;
+                self.col = cmp::min(self.param(1, 1), self.cols) - 1;
+            }
+            b'J' => {
+                let row = self.row;
+
+                match self.param(0, 0) {
+                    0 => {
+                        self.erase(row, col..self.cols);
+                        self.erase_rows(row + 1..self.rows);
+                    }
+                    1 => {
+                        self.erase_rows(0..row);
+                        self.erase(row, 0..col + 1);
+                    }
+                    2 => self.erase_rows(0..self.rows),
+                    // the scrollback too:
+                    3 => {
+                        self.erase_rows(0..self.rows);
+                        self.history = 0;
+                    }
+                    _ => {}
+                }
+            }
+            b'K' => {
+                let row = self.row;
+
+                match self.param(0, 0) {
+                    0 => self.erase(row, col..self.cols),
+                    1 => self.erase(row, 0..col + 1),
+                    2 => self.erase(row, 0..self.cols),
+                    _ => {}
+                }
+            }
+            b'm' => self.select_graphic_rendition(),
+            b's' => self.saved = (self.col, self.row),
+            b'u' => self.restore(),
+            _ => {}
+        }
+    }
+
+    fn select_graphic_rendition(&mut self) {
+        // no parameters at all is a reset:
+        for n in 0..cmp::max(self.param_count, 1) {
+            match self.param(n, 0) {
+                0 => {
+                    self.fg = LIGHT_GREY;
+                    self.bg = BLACK;
+                    self.bold = false;
+                    self.reverse = false;
+                }
+                1 => self.bold = true,
+                22 => self.bold = false,
+                7 => self.reverse = true,
+                27 => self.reverse = false,
+                param @ 30..=37 => self.fg = ANSI_TO_CGA[param - 30],
+                39 => self.fg = LIGHT_GREY,
+                param @ 40..=47 => self.bg = ANSI_TO_CGA[param - 40],
+                49 => self.bg = BLACK,
+                param @ 90..=97 => self.fg = ANSI_TO_CGA[param - 90] | BRIGHT,
+                param @ 100..=107 => self.bg = ANSI_TO_CGA[param - 100] | BRIGHT,
+                _ => {}
+            }
+        }
+    }
+
+    fn restore(&mut self) {
+        let (col, row) = self.saved;
+        self.col = cmp::min(col, self.cols);
+        self.row = cmp::min(row, self.rows - 1);
+    }
+
+    fn reset(&mut self) {
+        self.fg = LIGHT_GREY;
+        self.bg = BLACK;
+        self.bold = false;
+        self.reverse = false;
+        self.cursor_visible = true;
+        self.col = 0;
+        self.row = 0;
+
+        self.erase_rows(0..self.rows);
+    }
+}
+
+impl<D: Display> Write for Term<D> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.view != 0 {
+            self.view = 0;
+            self.redraw();
+        }
+
+        for c in s.chars() {
+            self.feed(if c.is_ascii() { c as u8 } else { b'?' });
+        }
+
+        self.update_cursor();
+
+        Ok(())
+    }
+}
@@ -0,0 +1,345 @@
+use core::fmt::Write;
+use core::task::{Poll, Waker};
+
+use arraydeque::{ArrayDeque, Saturating};
+use arrayvec::ArrayVec;
+use futures::future;
+use interface::{KeyEvent, SysError, SysResult, KEY_MOD_CTRL, KEY_MOD_SHIFT, SIGINT};
+use interface::{KEYSYM_DELETE, KEYSYM_DOWN, KEYSYM_END, KEYSYM_HOME, KEYSYM_LEFT, KEYSYM_PAGE_DOWN, KEYSYM_PAGE_UP};
+use interface::{KEYSYM_RIGHT, KEYSYM_UP};
+use interface::{TTY_CANONICAL, TTY_ECHO, TTY_GET_MODE, TTY_SET_MODE, TTY_SIGNALS};
+
+use crate::console;
+use crate::mem::MemoryExhausted;
+use crate::sync::Mutex;
+use crate::task::ProcessGroupId;
+use crate::task::signal::{self, Signal};
+use crate::util::AtomicList;
+use crate::work;
+
+// input waiting to be read, and the line being edited in canonical mode.
+// anything typed beyond these is dropped:
+const INPUT_BUFFER: usize = 256;
+const LINE_MAX: usize = 256;
+
+const CTRL_C: u8 = 0x03;
+const CTRL_D: u8 = 0x04;
+const BACKSPACE: u8 = 0x08;
+const CTRL_U: u8 = 0x15;
+const DELETE: u8 = 0x7f;
+
+const MODES: u64 = TTY_CANONICAL | TTY_ECHO | TTY_SIGNALS;
+
+// the console is the only terminal, and the controlling terminal of every
+// process. its foreground group gets the signals typed at the keyboard:
+static FOREGROUND: Mutex<Option<ProcessGroupId>> = Mutex::new(None);
+
+pub fn foreground() -> Option<ProcessGroupId> {
+    *FOREGROUND.lock()
+}
+
+/// Sets the group that gets SIGINT on Ctrl-C.
+pub fn set_foreground(group: ProcessGroupId) {
+    *FOREGROUND.lock() = Some(group);
+}
+
+/// Looks at each key pressed at the keyboard before the console gets it.
+/// Returns false for keys that scroll the console, which readers of the
+/// console don't see.
+pub fn filter_key(event: &KeyEvent) -> bool {
+    if event.modifiers & KEY_MOD_SHIFT != 0 {
+        match event.keysym {
+            KEYSYM_PAGE_UP => { console::page_up(); return false; }
+            KEYSYM_PAGE_DOWN => { console::page_down(); return false; }
+            _ => {}
+        }
+    }
+
+    true
+}
+
+/// What pressing a key sends to readers of the console: its character, the
+/// control character for Ctrl and a letter, or the escape sequence a VT100
+/// sends for keys without a character. Nothing for keys with neither.
+pub fn key_input(event: &KeyEvent) -> ArrayVec<[u8; 4]> {
+    let mut input = ArrayVec::new();
+
+    let sequence: &[u8] = match event.keysym {
+        KEYSYM_UP => b"\x1b[A",
+        KEYSYM_DOWN => b"\x1b[B",
+        KEYSYM_RIGHT => b"\x1b[C",
+        KEYSYM_LEFT => b"\x1b[D",
+        KEYSYM_HOME => b"\x1b[H",
+        KEYSYM_END => b"\x1b[F",
+        KEYSYM_DELETE => b"\x1b[3~",
+        keysym if keysym < 0x80 => {
+            let byte = keysym as u8;
+
+            if event.modifiers & KEY_MOD_CTRL != 0 && byte.is_ascii_alphabetic() {
+                input.push(byte & 0x1f);
+            } else {
+                input.push(byte);
+            }
+
+            return input;
+        }
+        _ => b"",
+    };
+
+    input.extend(sequence.iter().cloned());
+    input
+}
+
+fn signal_foreground(signal: u64) {
+    let signal = Signal::new(signal)
+        .expect("tty::signal_foreground: bad signal");
+
+    if let Some(group) = foreground() {
+        // the group may have no processes left in it:
+        let _ = signal::send_group(group, signal);
+    }
+}
+
+// the line discipline between the keyboard and readers of the console:
+struct Tty {
+    mode: u64,
+    // what readers get, which in canonical mode is only finished lines:
+    input: ArrayDeque<[u8; INPUT_BUFFER], Saturating>,
+    line: ArrayVec<[u8; LINE_MAX]>,
+    // set by Ctrl-D on an empty line, the next read once the input is
+    // empty returns nothing:
+    eof: bool,
+}
+
+static TTY: Mutex<Option<Tty>> = Mutex::new(None);
+
+static READ_WAKERS: AtomicList<Waker> = AtomicList::new();
+
+/// Sets up the console in canonical mode, with echo and signals.
+// Safety: must not be called more than once
+pub unsafe fn init() {
+    *TTY.lock() = Some(Tty {
+        mode: MODES,
+        input: ArrayDeque::new(),
+        line: ArrayVec::new(),
+        eof: false,
+    });
+}
+
+/// Takes bytes typed at the console, editing the line with them in
+/// canonical mode, echoing them and raising signals for them as the mode
+/// says.
+pub fn input(bytes: &[u8]) {
+    let readable = {
+        let mut tty = TTY.lock();
+
+        let tty = match tty.as_mut() {
+            Some(tty) => tty,
+            None => return,
+        };
+
+        bytes.iter().fold(false, |readable, &byte| tty.input(byte) | readable)
+    };
+
+    if readable {
+        wake_readers();
+    }
+}
+
+/// Reads from the console, waiting until there's something. In canonical
+/// mode that's a line, and no more than a line is read at once.
+pub async fn read(buf: &mut [u8]) -> Result<usize, MemoryExhausted> {
+    future::poll_fn(|ctx| {
+        // register waker before checking the input so that we can't miss
+        // a key arriving in between:
+        match READ_WAKERS.push_front(ctx.waker().clone()) {
+            Ok(()) => {}
+            Err(e) => { return Poll::Ready(Err(e)); }
+        }
+
+        let mut tty = TTY.lock();
+
+        let tty = tty.as_mut()
+            .expect("tty to be initialized");
+
+        if tty.input.is_empty() {
+            if tty.eof {
+                tty.eof = false;
+                return Poll::Ready(Ok(0));
+            }
+
+            return Poll::Pending;
+        }
+
+        let canonical = tty.mode & TTY_CANONICAL != 0;
+        let mut count = 0;
+
+        while count < buf.len() {
+            let byte = match tty.input.pop_front() {
+                Some(byte) => byte,
+                None => break,
+            };
+
+            buf[count] = byte;
+            count += 1;
+
+            if canonical && byte == b'\n' {
+                break;
+            }
+        }
+
+        Poll::Ready(Ok(count))
+    }).await
+}
+
+/// Whether there's console input to read, registering `waker` to be woken
+/// when some arrives.
+pub fn poll_readable(waker: &Waker) -> Result<bool, MemoryExhausted> {
+    READ_WAKERS.push_front(waker.clone())?;
+
+    let tty = TTY.lock();
+
+    let tty = tty.as_ref()
+        .expect("tty to be initialized");
+
+    Ok(!tty.input.is_empty() || tty.eof)
+}
+
+/// Gets or sets the console's TTY_* modes.
+pub fn ioctl(request: u64, arg: u64) -> SysResult<u64> {
+    let readable = {
+        let mut tty = TTY.lock();
+
+        let tty = tty.as_mut()
+            .expect("tty to be initialized");
+
+        match request {
+            TTY_GET_MODE => return Ok(tty.mode),
+            TTY_SET_MODE => {
+                if arg & !MODES != 0 {
+                    return Err(SysError::IllegalValue);
+                }
+
+                // leaving canonical mode hands over the line typed so far:
+                let readable = tty.mode & TTY_CANONICAL != 0 && arg & TTY_CANONICAL == 0
+                    && tty.finish_line();
+
+                tty.mode = arg;
+                readable
+            }
+            _ => return Err(SysError::InvalidOperation),
+        }
+    };
+
+    if readable {
+        wake_readers();
+    }
+
+    Ok(0)
+}
+
+fn wake_readers() {
+    for waker in READ_WAKERS.take_iter() {
+        waker.wake();
+    }
+}
+
+impl Tty {
+    // whether it made something new to read:
+    fn input(&mut self, byte: u8) -> bool {
+        if self.mode & TTY_SIGNALS != 0 && byte == CTRL_C {
+            // sending signals takes scheduler locks, leave that until the
+            // deferred work runs:
+            if work::defer(signal_foreground, SIGINT).is_err() {
+                crate::println!("tty: work queue full, dropping Ctrl-C");
+            }
+
+            self.line.clear();
+            self.echo_byte(byte);
+            self.echo("\n");
+
+            return false;
+        }
+
+        if self.mode & TTY_CANONICAL == 0 {
+            self.echo_byte(byte);
+            return self.input.push_back(byte).is_ok();
+        }
+
+        match byte {
+            BACKSPACE | DELETE => {
+                if let Some(erased) = self.line.pop() {
+                    self.echo_erase(erased);
+                }
+
+                false
+            }
+            CTRL_U => {
+                while let Some(erased) = self.line.pop() {
+                    self.echo_erase(erased);
+                }
+
+                false
+            }
+            CTRL_D if self.line.is_empty() => {
+                self.eof = true;
+                true
+            }
+            // what's typed so far goes to readers without a newline:
+            CTRL_D => self.finish_line(),
+            b'\n' => {
+                self.echo("\n");
+                self.line.push(byte);
+                self.finish_line()
+            }
+            // leaving room for the newline:
+            _ if self.line.len() < LINE_MAX - 1 => {
+                self.echo_byte(byte);
+                self.line.push(byte);
+                false
+            }
+            _ => false,
+        }
+    }
+
+    // moves the line to the input, whether there was anything to move:
+    fn finish_line(&mut self) -> bool {
+        let finished = !self.line.is_empty();
+
+        for byte in self.line.drain(..) {
+            let _ = self.input.push_back(byte);
+        }
+
+        finished
+    }
+
+    fn echo(&self, s: &str) {
+        if self.mode & TTY_ECHO != 0 {
+            let _ = console::get().write_str(s);
+        }
+    }
+
+    // control characters other than tab and newline are shown as a caret
+    // and a letter, Ctrl-C as ^C:
+    fn echo_byte(&self, byte: u8) {
+        let mut caret = [b'^', byte ^ 0x40];
+
+        let shown: &[u8] = match byte {
+            b'\t' | b'\n' | 0x20..=0x7e => { caret[0] = byte; &caret[..1] }
+            _ => &caret,
+        };
+
+        self.echo(core::str::from_utf8(shown).unwrap_or("?"));
+    }
+
+    fn echo_erase(&self, byte: u8) {
+        let width = match byte {
+            0x20..=0x7e | b'\t' => 1,
+            _ => 2,
+        };
+
+        for _ in 0..width {
+            self.echo("\x08 \x08");
+        }
+    }
+}
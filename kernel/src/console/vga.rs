@@ -1,232 +1,96 @@
-use core::fmt::{self, Write};
 use core::ptr;
 
-use crate::console::{self, Console};
-use crate::mem::page::{self, PageFlags, PAGE_SIZE};
-use crate::mem::phys::{Phys, RawPhys};
-
-type BiosFont = [u8; 4096];
-static mut BIOS_FONT: BiosFont = [0u8; 4096];
-
-const VRAM_SIZE: usize = 8 * 1024 * 1024;
-
-#[link_section=".unalloc"]
-static mut VRAM: [u8; VRAM_SIZE] = [0; VRAM_SIZE];
-
-#[repr(packed)]
-#[allow(unused)]
-pub struct VbeModeInfo {
-    attrs: u16,
-    win_a: u8,
-    win_b: u8,
-    granularity: u16,
-    winsize: u16,
-    seg_a: u16,
-    seg_b: u16,
-    real_fct_ptr: u32,
-    pitch: u16, // bytes per scanline
-    x_res: u16,
-    y_res: u16,
-    w_char: u8,
-    y_char: u8,
-    planes: u8,
-    bpp: u8,
-    banks: u8,
-    memory_model: u8,
-    bank_size: u8,
-    image_pages: u8,
-    reserved0: u8,
-    red_mask: u8,
-    red_pos: u8,
-    green_mask: u8,
-    green_pos: u8,
-    blue_mask: u8,
-    blue_pos: u8,
-    rsv_mask: u8,
-    rsv_pos: u8,
-    directcolor_attrs: u8,
-    phys_base: u32,
-    reserved1: u32,
-    reserved2: u32,
-}
-
-#[no_mangle]
-pub unsafe extern "C" fn console_init(vbe_mode_info: *const VbeModeInfo, bios_font: *const u8) {
-    // copy bios font from low memory
-    ptr::copy(bios_font, &mut BIOS_FONT as *mut BiosFont as *mut u8, 4096);
-
-    let vbe_mode_info = &*vbe_mode_info;
+use x86_64::instructions::port::Port;
 
-    let virt = &mut VRAM as *mut [u8; VRAM_SIZE] as *mut u8;
+use crate::console::term::{Cell, Display};
 
-    for off in (0..VRAM_SIZE).step_by(PAGE_SIZE) {
-        let phys = Phys::new(RawPhys(vbe_mode_info.phys_base as u64 + off as u64));
-        let virt = virt.add(off);
+/// Where the text buffer is in physical memory.
+pub const TEXT_BUFFER: u64 = 0xb8000;
 
-        page::map(phys, virt, PageFlags::PRESENT | PageFlags::WRITE)
-            .expect("page::map in console_init");
-    }
-
-    let mut vga = VgaText {
-        vram: virt,
-        bios_font: &BIOS_FONT,
-        width: vbe_mode_info.x_res as usize,
-        height: vbe_mode_info.y_res as usize,
-        pitch: vbe_mode_info.pitch as usize,
-        col: 0,
-        row: 0,
-    };
+const COLS: usize = 80;
+const ROWS: usize = 25;
 
-    vga.blank();
+// CRT controller registers, selected through the index port:
+const CRTC_INDEX: u16 = 0x3d4;
+const CRTC_DATA: u16 = 0x3d5;
+const CRTC_CURSOR_START: u8 = 0x0a;
+const CRTC_CURSOR_HIGH: u8 = 0x0e;
+const CRTC_CURSOR_LOW: u8 = 0x0f;
+const CURSOR_DISABLE: u8 = 1 << 5;
 
-    console::set(Console::VgaText(vga));
-}
+// the attribute controller takes an index and then data on the same port,
+// which of the two comes next is reset by reading the input status port:
+const ATTR_INDEX: u16 = 0x3c0;
+const ATTR_DATA_READ: u16 = 0x3c1;
+const INPUT_STATUS: u16 = 0x3da;
+const ATTR_MODE_CONTROL: u8 = 0x10;
+const ATTR_BLINK: u8 = 1 << 3;
+// must be set in the index, or the screen goes blank:
+const ATTR_PALETTE_ENABLE: u8 = 0x20;
 
+/// The 80x25 text mode the BIOS starts in, with the character and colours
+/// of each cell in a buffer the VGA card draws from.
 pub struct VgaText {
-    vram: *mut u8,
-    bios_font: &'static BiosFont,
-    width: usize,
-    height: usize,
-    pitch: usize,
-    col: usize,
-    row: usize,
+    cells: *mut u16,
 }
 
-const RED: u8 = 0xed;
-const GREEN: u8 = 0xbd;
-const BLUE: u8 = 0xa6;
-
-const CHAR_HEIGHT: usize = 16;
-const CHAR_WIDTH: usize = 8;
-const STRIDE: usize = 3;
-
 impl VgaText {
-    fn blank(&mut self) {
-        unsafe {
-            for y in 0..self.height {
-                let line = self.vram.add(y * self.pitch);
-
-                for x in 0..self.width {
-                    ptr::write(line.add(x * 3 + 0), BLUE);
-                    ptr::write(line.add(x * 3 + 1), GREEN);
-                    ptr::write(line.add(x * 3 + 2), RED);
-                }
-            }
-        }
-
-        // draw the crab
-        let crab = include_bytes!("../../../crab.bmp").as_ptr();
-        let crab_header = 0x36;
-        let crab_width = 256;
-        let crab_height = 256;
+    /// Takes the text buffer, mapped at `vram`.
+    pub unsafe fn new(vram: *mut u8) -> VgaText {
+        // the top bit of an attribute makes the character blink by default,
+        // rather than giving it one of the bright background colours:
+        Port::<u8>::new(INPUT_STATUS).read();
+        Port::<u8>::new(ATTR_INDEX).write(ATTR_MODE_CONTROL | ATTR_PALETTE_ENABLE);
+        let mode = Port::<u8>::new(ATTR_DATA_READ).read();
+        Port::<u8>::new(ATTR_INDEX).write(mode & !ATTR_BLINK);
+
+        VgaText { cells: vram as *mut u16 }
+    }
 
+    fn crtc_write(&mut self, register: u8, value: u8) {
         unsafe {
-            for y in 0..crab_height {
-                let line = self.vram
-                    .add((self.height - crab_height + y) * self.pitch)
-                    .add((self.width - crab_width) * STRIDE);
-                let crab_idx = y * crab_width * STRIDE + crab_header;
-                ptr::copy(crab.add(crab_idx), line, crab_width * STRIDE);
-            }
+            Port::<u8>::new(CRTC_INDEX).write(register);
+            Port::<u8>::new(CRTC_DATA).write(value);
         }
     }
 
-    fn rows(&self) -> usize {
-        self.height / CHAR_HEIGHT
+    fn crtc_read(&mut self, register: u8) -> u8 {
+        unsafe {
+            Port::<u8>::new(CRTC_INDEX).write(register);
+            Port::<u8>::new(CRTC_DATA).read()
+        }
     }
+}
 
-    fn cols(&self) -> usize {
-        (self.width - 256) / CHAR_WIDTH
+impl Display for VgaText {
+    fn size(&self) -> (usize, usize) {
+        (COLS, ROWS)
     }
 
-    fn newline(&mut self) {
-        self.row += 1;
-        self.col = 0;
-
-        if self.row == self.rows() {
-            let line0 = self.vram;
-            let line1 = unsafe { self.vram.add(self.pitch * CHAR_HEIGHT) };
-
-            let count = self.pitch * (self.rows() - 1) * CHAR_HEIGHT;
-            unsafe { ptr::copy(line1, line0, count); }
-
-            // blank last line
-            let last_line = unsafe { self.vram.add(count) };
-
-            unsafe {
-                for y in 0..CHAR_HEIGHT {
-                    let line = last_line.add(self.pitch * y);
+    fn draw(&mut self, col: usize, row: usize, cell: Cell) {
+        let value = (cell.attr as u16) << 8 | cell.ch as u16;
 
-                    for x in 0..self.width {
-                        ptr::write(line.add(x * 3 + 0), BLUE);
-                        ptr::write(line.add(x * 3 + 1), GREEN);
-                        ptr::write(line.add(x * 3 + 2), RED);
-                    }
-                }
-            }
-
-            self.row -= 1;
-        }
+        unsafe { ptr::write_volatile(self.cells.add(row * COLS + col), value); }
     }
 
-    fn write_cp437(&mut self, b: u8) {
-        let pos = self.row * CHAR_HEIGHT * self.pitch
-                + self.col * CHAR_WIDTH * STRIDE;
-
-        // write directly to VRAM:
-        unsafe {
-            for glyph_y in 0..CHAR_HEIGHT {
-                let glyph = self.bios_font[b as usize * 16 + glyph_y];
-
-                let pos = pos + glyph_y * self.pitch;
-
-                for glyph_x in 0..CHAR_WIDTH {
-                    let pos = pos + glyph_x * STRIDE;
-
-                    if (glyph & (0x80 >> glyph_x)) != 0 {
-                        ptr::write_volatile(self.vram.add(pos + 0), 0);
-                        ptr::write_volatile(self.vram.add(pos + 1), 0);
-                        ptr::write_volatile(self.vram.add(pos + 2), 0);
-                    }
-                }
-            }
-        }
-
-        self.col += 1;
-
-        if self.col == self.cols() {
-            self.newline();
-        }
+    fn scroll_up(&mut self) {
+        unsafe { ptr::copy(self.cells.add(COLS), self.cells, COLS * (ROWS - 1)); }
     }
 
-    fn update_cursor(&self) {
-        // let mut reg = Port::<u8>::new(0x3d4);
-        // let mut val = Port::<u8>::new(0x3d5);
-
-        // let pos = self.row * self.width + self.col;
-
-        // unsafe {
-        //     reg.write(0x0f);
-        //     val.write((pos & 0xff) as u8);
+    fn set_cursor(&mut self, at: Option<(usize, usize)>) {
+        let start = self.crtc_read(CRTC_CURSOR_START);
 
-        //     reg.write(0x0e);
-        //     val.write(((pos >> 8) & 0xff) as u8);
-        // }
-    }
-}
+        match at {
+            Some((col, row)) => {
+                let pos = row * COLS + col;
 
-impl Write for VgaText {
-    fn write_str(&mut self, s: &str) -> fmt::Result {
-        for c in s.chars() {
-            match c {
-                '\n' => self.newline(),
-                c if c.is_ascii() => self.write_cp437(c as u8),
-                _ => self.write_cp437(b'?'),
+                self.crtc_write(CRTC_CURSOR_LOW, pos as u8);
+                self.crtc_write(CRTC_CURSOR_HIGH, (pos >> 8) as u8);
+                self.crtc_write(CRTC_CURSOR_START, start & !CURSOR_DISABLE);
+            }
+            None => {
+                self.crtc_write(CRTC_CURSOR_START, start | CURSOR_DISABLE);
             }
         }
-
-        self.update_cursor();
-
-        Ok(())
     }
 }
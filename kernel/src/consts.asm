@@ -12,14 +12,20 @@
 %define EARLY_MEMORY_MAP        0x00004000
 %define EARLY_MEMORY_MAP_END    0x00004ff0
 %define EARLY_MEMORY_MAP_LEN    0x00004ff0
+; physical address and length of the initrd, with a length of 0 if there's
+; none:
+%define EARLY_INITRD_BASE       0x00004ff8
+%define EARLY_INITRD_LEN        0x00004ffc
 
 %define EARLY_BIOS_FONT         0x00005000
 %define EARLY_VBE_MODE_INFO     0x00006000
 
 %define SEG_KCODE               0x08
 %define SEG_KDATA               0x10
-%define SEG_UCODE               0x1b
-%define SEG_UDATA               0x23
+; user data comes before user code, as sysret expects. see
+; interrupt::init_syscall:
+%define SEG_UDATA               0x1b
+%define SEG_UCODE               0x23
 %define SEG_TSS                 0x28
 
 %define TSS_SIZE                0x68
@@ -32,4 +38,13 @@
 %define GDT64_64BIT             (1 << 53)
 %define GDT64_USER              (3 << 45)
 
-%define VBE_MODE                0x0118 ; TODO don't hardcode this
+%define MAX_CPUS                8 ; must match config::MAX_CPUS
+
+%define SYSCALL_VECTOR          0x80 ; must match Interrupt::FastSyscall
+%define PERCPU_USER_RSP         16   ; must match percpu::Header
+%define PERCPU_KERNEL_STACK     24   ; must match percpu::Header
+%define AP_TRAMPOLINE_BASE      0x00007000
+
+; the VBE mode the loader switches to, or 0 to stay in VGA text mode.
+; 0x0118 is 1024x768 in 24 bit colour
+%define VBE_MODE                0 ; TODO don't hardcode this
@@ -0,0 +1,264 @@
+use core::arch::x86_64::{CpuidResult, __cpuid_count, _rdrand64_step, _rdseed64_step};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use bitflags::bitflags;
+use x86_64::registers::model_specific::Msr;
+
+use crate::util::EarlyInit;
+
+pub mod fpu;
+
+const CPUID_MAX_BASIC: u32 = 0x0000_0000;
+const CPUID_FEATURES: u32 = 0x0000_0001;
+const CPUID_EXTENDED_FEATURES: u32 = 0x0000_0007;
+const CPUID_MAX_EXTENDED: u32 = 0x8000_0000;
+const CPUID_EXTENDED_PROCESSOR: u32 = 0x8000_0001;
+const CPUID_POWER_MANAGEMENT: u32 = 0x8000_0007;
+
+const FEATURES_EDX_APIC: u32 = 1 << 9;
+const FEATURES_ECX_X2APIC: u32 = 1 << 21;
+const FEATURES_ECX_XSAVE: u32 = 1 << 26;
+const FEATURES_ECX_AVX: u32 = 1 << 28;
+const FEATURES_ECX_RDRAND: u32 = 1 << 30;
+const EXTENDED_FEATURES_EBX_SMEP: u32 = 1 << 7;
+const EXTENDED_FEATURES_EBX_RDSEED: u32 = 1 << 18;
+const EXTENDED_FEATURES_EBX_SMAP: u32 = 1 << 20;
+const EXTENDED_PROCESSOR_EDX_NX: u32 = 1 << 20;
+const EXTENDED_PROCESSOR_EDX_PAGE_1G: u32 = 1 << 26;
+const POWER_MANAGEMENT_EDX_INVARIANT_TSC: u32 = 1 << 8;
+
+const MSR_EFER: u32 = 0xc000_0080;
+const EFER_LONG_MODE: u64 = 1 << 8;
+const EFER_NO_EXECUTE: u64 = 1 << 11;
+
+const CR4_SMEP: u64 = 1 << 20;
+const CR4_SMAP: u64 = 1 << 21;
+
+const RDRAND_RETRIES: usize = 10;
+
+bitflags! {
+    /// CPU features the kernel cares about, as CPUID reports them. Whether
+    /// the kernel has turned them on is another matter.
+    pub struct Features: u32 {
+        /// A local APIC, which keeps time and starts the other CPUs.
+        const APIC          = 1 << 0;
+        /// The local APIC's x2APIC mode, with its registers in MSRs.
+        const X2APIC        = 1 << 1;
+        /// The no execute bit in page table entries.
+        const NX            = 1 << 2;
+        /// Faulting on supervisor instruction fetches from user pages.
+        const SMEP          = 1 << 3;
+        /// Faulting on supervisor data accesses to user pages.
+        const SMAP          = 1 << 4;
+        const AVX           = 1 << 5;
+        /// A TSC that counts at a constant rate, whatever the CPU's clock
+        /// speed or sleep state.
+        const INVARIANT_TSC = 1 << 6;
+        /// 1 GiB pages, mapped by a single PML3 entry.
+        const PAGE_1G       = 1 << 7;
+        /// XSAVE and XRSTOR, which save and restore the state of the FPU
+        /// and its extensions.
+        const XSAVE         = 1 << 8;
+        /// RDRAND, which reads random numbers from an on-chip generator.
+        const RDRAND        = 1 << 9;
+        /// RDSEED, which reads the on-chip generator's entropy source
+        /// directly, for seeding other generators.
+        const RDSEED        = 1 << 10;
+    }
+}
+
+struct Cpu {
+    vendor: [u8; 12],
+    max_basic: u32,
+    max_extended: u32,
+    features: Features,
+}
+
+static CPU: EarlyInit<Cpu> = EarlyInit::new();
+
+// whether stac and clac can be used, which they can't without SMAP. the
+// interrupt entry code in isrs.asm reads it as a byte:
+#[export_name = "cpu_smap"]
+static SMAP: AtomicBool = AtomicBool::new(false);
+
+/// Queries CPUID on the BSP for the features in `Features`. The APs are
+/// assumed to have the same ones.
+// Safety: must not be called more than once
+pub unsafe fn init() {
+    let max_basic = __cpuid_count(CPUID_MAX_BASIC, 0);
+    let max_extended = __cpuid_count(CPUID_MAX_EXTENDED, 0).eax;
+
+    // the vendor is spelt out across ebx, edx and ecx, in that order:
+    let mut vendor = [0; 12];
+    vendor[0..4].copy_from_slice(&max_basic.ebx.to_le_bytes());
+    vendor[4..8].copy_from_slice(&max_basic.edx.to_le_bytes());
+    vendor[8..12].copy_from_slice(&max_basic.ecx.to_le_bytes());
+
+    let mut cpu = Cpu {
+        vendor,
+        max_basic: max_basic.eax,
+        max_extended,
+        features: Features::empty(),
+    };
+
+    let mut features = Features::empty();
+
+    if let Some(leaf) = cpu.cpuid(CPUID_FEATURES, 0) {
+        features.set(Features::APIC, leaf.edx & FEATURES_EDX_APIC != 0);
+        features.set(Features::X2APIC, leaf.ecx & FEATURES_ECX_X2APIC != 0);
+        features.set(Features::XSAVE, leaf.ecx & FEATURES_ECX_XSAVE != 0);
+        features.set(Features::AVX, leaf.ecx & FEATURES_ECX_AVX != 0);
+        features.set(Features::RDRAND, leaf.ecx & FEATURES_ECX_RDRAND != 0);
+    }
+
+    if let Some(leaf) = cpu.cpuid(CPUID_EXTENDED_FEATURES, 0) {
+        features.set(Features::SMEP, leaf.ebx & EXTENDED_FEATURES_EBX_SMEP != 0);
+        features.set(Features::SMAP, leaf.ebx & EXTENDED_FEATURES_EBX_SMAP != 0);
+        features.set(Features::RDSEED, leaf.ebx & EXTENDED_FEATURES_EBX_RDSEED != 0);
+    }
+
+    if let Some(leaf) = cpu.cpuid(CPUID_EXTENDED_PROCESSOR, 0) {
+        features.set(Features::NX, leaf.edx & EXTENDED_PROCESSOR_EDX_NX != 0);
+        features.set(Features::PAGE_1G, leaf.edx & EXTENDED_PROCESSOR_EDX_PAGE_1G != 0);
+    }
+
+    if let Some(leaf) = cpu.cpuid(CPUID_POWER_MANAGEMENT, 0) {
+        features.set(Features::INVARIANT_TSC, leaf.edx & POWER_MANAGEMENT_EDX_INVARIANT_TSC != 0);
+    }
+
+    cpu.features = features;
+
+    crate::println!("cpu: {} features: {:?}",
+        core::str::from_utf8(&cpu.vendor).unwrap_or("unknown"), cpu.features);
+
+    EarlyInit::set(&CPU, cpu);
+
+    SMAP.store(has(Features::SMAP), Ordering::SeqCst);
+
+    init_cpu();
+}
+
+/// Turns on NX, SMEP and SMAP for the calling CPU, whichever of them it has.
+/// From then on the kernel faults rather than executes from pages marked no
+/// execute or user pages, and rather than touches user pages outside of
+/// `user_access_begin` and `user_access_end`.
+pub unsafe fn init_cpu() {
+    if has(Features::NX) {
+        let mut efer = Msr::new(MSR_EFER);
+        let flags = efer.read();
+        efer.write(flags | EFER_NO_EXECUTE);
+    }
+
+    let mut cr4: u64;
+    asm!("movq %cr4, $0" : "=r"(cr4));
+
+    if has(Features::SMEP) {
+        cr4 |= CR4_SMEP;
+    }
+
+    if has(Features::SMAP) {
+        cr4 |= CR4_SMAP;
+    }
+
+    asm!("movq $0, %cr4" :: "r"(cr4) :: "volatile");
+}
+
+/// The EFER bits the APs need before they turn paging on: long mode, and no
+/// execute if the page tables they'll share with the BSP use it.
+pub fn efer_flags() -> u64 {
+    if has(Features::NX) {
+        EFER_LONG_MODE | EFER_NO_EXECUTE
+    } else {
+        EFER_LONG_MODE
+    }
+}
+
+/// Lets the kernel touch user pages, until `user_access_end`. Does nothing
+/// without SMAP, when it always can.
+#[inline]
+pub fn user_access_begin() {
+    if SMAP.load(Ordering::Relaxed) {
+        unsafe { asm!("stac" ::: "memory" : "volatile"); }
+    }
+}
+
+/// Stops the kernel touching user pages again.
+#[inline]
+pub fn user_access_end() {
+    if SMAP.load(Ordering::Relaxed) {
+        unsafe { asm!("clac" ::: "memory" : "volatile"); }
+    }
+}
+
+/// Whether the CPU has every feature in `features`.
+pub fn has(features: Features) -> bool {
+    CPU.features.contains(features)
+}
+
+/// Reads a random number with RDRAND, or returns None if the CPU doesn't
+/// have it or its generator keeps coming up empty.
+pub fn rdrand() -> Option<u64> {
+    if !has(Features::RDRAND) {
+        return None;
+    }
+
+    // the generator can run dry for a moment, it's worth a few more tries:
+    for _ in 0..RDRAND_RETRIES {
+        let mut value = 0;
+
+        if unsafe { _rdrand64_step(&mut value) } == 1 {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+/// Reads a seed with RDSEED, or returns None if the CPU doesn't have it or its
+/// entropy source keeps coming up empty.
+pub fn rdseed() -> Option<u64> {
+    if !has(Features::RDSEED) {
+        return None;
+    }
+
+    // the entropy source is slower than RDRAND's generator, and runs dry
+    // more often:
+    for _ in 0..RDRAND_RETRIES {
+        let mut value = 0;
+
+        if unsafe { _rdseed64_step(&mut value) } == 1 {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+/// Runs CPUID for `leaf` and `subleaf`, or returns None if the CPU doesn't
+/// have that leaf. Leaves past the highest one return junk rather than an
+/// error.
+pub fn cpuid(leaf: u32, subleaf: u32) -> Option<CpuidResult> {
+    CPU.cpuid(leaf, subleaf)
+}
+
+impl Cpu {
+    fn cpuid(&self, leaf: u32, subleaf: u32) -> Option<CpuidResult> {
+        let max = if leaf >= CPUID_MAX_EXTENDED { self.max_extended } else { self.max_basic };
+
+        if leaf > max {
+            return None;
+        }
+
+        Some(unsafe { __cpuid_count(leaf, subleaf) })
+    }
+}
+
+/// Runs `f` with user access open, and closes it again afterwards. Interrupts
+/// that come in meanwhile close it on entry, and iretq puts it back.
+pub fn with_user_access<T>(f: impl FnOnce() -> T) -> T {
+    user_access_begin();
+    let result = f();
+    user_access_end();
+
+    result
+}
@@ -0,0 +1,158 @@
+use core::fmt::{self, Debug};
+
+use crate::mem::kalloc::Box;
+use crate::mem::MemoryExhausted;
+use crate::util::EarlyInit;
+
+use super::Features;
+
+const CPUID_XSAVE: u32 = 0x0000_000d;
+
+const CR0_MONITOR_COPROCESSOR: u64 = 1 << 1;
+const CR0_EMULATION: u64 = 1 << 2;
+const CR0_TASK_SWITCHED: u64 = 1 << 3;
+const CR0_NUMERIC_ERROR: u64 = 1 << 5;
+
+const CR4_OSFXSR: u64 = 1 << 9;
+const CR4_OSXMMEXCPT: u64 = 1 << 10;
+const CR4_OSXSAVE: u64 = 1 << 18;
+
+// the state components XCR0 turns on. the kernel has no use for any others:
+const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX: u64 = 1 << 2;
+
+// x87, SSE and AVX state take 832 bytes in the standard format. the area is
+// rounded up from that:
+const AREA_SIZE: usize = 1024;
+
+// where the x87 control word and MXCSR are in the area, and what they are
+// after a reset, with every exception masked:
+const AREA_FCW: usize = 0;
+const AREA_MXCSR: usize = 24;
+const FCW_DEFAULT: u16 = 0x037f;
+const MXCSR_DEFAULT: u32 = 0x1f80;
+
+#[repr(C, align(64))]
+struct Area([u8; AREA_SIZE]);
+
+/// A task's x87, SSE and AVX registers, while it isn't running.
+pub struct FpuState {
+    area: Box<Area>,
+}
+
+// how state is saved and restored on every CPU, picked by the BSP:
+struct Mode {
+    xcr0: u64,
+}
+
+static MODE: EarlyInit<Option<Mode>> = EarlyInit::new();
+
+/// Picks how FPU state is saved, XSAVE if the CPU has it and FXSAVE if not,
+/// and turns the FPU on for the BSP.
+// Safety: must not be called more than once
+pub unsafe fn init() {
+    let mode = if super::has(Features::XSAVE) {
+        let supported = super::cpuid(CPUID_XSAVE, 0)
+            .map(|leaf| leaf.eax as u64)
+            .unwrap_or(0);
+
+        let wanted = XCR0_X87 | XCR0_SSE
+            | if super::has(Features::AVX) { XCR0_AVX } else { 0 };
+
+        Some(Mode { xcr0: wanted & supported })
+    } else {
+        None
+    };
+
+    EarlyInit::set(&MODE, mode);
+
+    init_cpu();
+
+    // the size needed for what's now on is only known once XCR0 is set:
+    if MODE.is_some() {
+        let size = super::cpuid(CPUID_XSAVE, 0).map(|leaf| leaf.ebx as usize).unwrap_or(0);
+        assert!(size <= AREA_SIZE, "fpu::init: XSAVE area of {} bytes too large", size);
+    }
+
+    crate::println!("fpu: saved with {}", if MODE.is_some() { "XSAVE" } else { "FXSAVE" });
+}
+
+/// Turns the FPU, SSE and, where the CPU has it, AVX on for the calling CPU.
+/// The kernel itself never touches them, they're only for user mode.
+pub unsafe fn init_cpu() {
+    let mut cr0: u64;
+    asm!("movq %cr0, $0" : "=r"(cr0));
+    cr0 &= !(CR0_EMULATION | CR0_TASK_SWITCHED);
+    cr0 |= CR0_MONITOR_COPROCESSOR | CR0_NUMERIC_ERROR;
+    asm!("movq $0, %cr0" :: "r"(cr0) :: "volatile");
+
+    let mut cr4: u64;
+    asm!("movq %cr4, $0" : "=r"(cr4));
+    cr4 |= CR4_OSFXSR | CR4_OSXMMEXCPT;
+
+    if MODE.is_some() {
+        cr4 |= CR4_OSXSAVE;
+    }
+
+    asm!("movq $0, %cr4" :: "r"(cr4) :: "volatile");
+
+    if let Some(ref mode) = *MODE {
+        asm!("xsetbv" :: "{ecx}"(0), "{eax}"(mode.xcr0 as u32), "{edx}"((mode.xcr0 >> 32) as u32) :: "volatile");
+    }
+
+    asm!("fninit" :::: "volatile");
+}
+
+impl FpuState {
+    /// Returns the state registers are in after a reset.
+    pub fn new() -> Result<FpuState, MemoryExhausted> {
+        let mut area = Box::new(Area([0; AREA_SIZE]))
+            .map_err(|_| MemoryExhausted)?;
+
+        // the XSAVE header after the legacy area is all zero, which makes
+        // XRSTOR load every component's initial state. FXRSTOR has to be
+        // told what that is:
+        area.0[AREA_FCW..AREA_FCW + 2].copy_from_slice(&FCW_DEFAULT.to_le_bytes());
+        area.0[AREA_MXCSR..AREA_MXCSR + 4].copy_from_slice(&MXCSR_DEFAULT.to_le_bytes());
+
+        Ok(FpuState { area })
+    }
+
+    pub fn try_clone(&self) -> Result<FpuState, MemoryExhausted> {
+        let area = Box::new(Area(self.area.0))
+            .map_err(|_| MemoryExhausted)?;
+
+        Ok(FpuState { area })
+    }
+
+    /// Saves the calling CPU's registers.
+    pub unsafe fn save(&mut self) {
+        let area = self.area.0.as_mut_ptr();
+
+        match *MODE {
+            Some(ref mode) => asm!("xsave64 ($0)"
+                :: "r"(area), "{eax}"(mode.xcr0 as u32), "{edx}"((mode.xcr0 >> 32) as u32)
+                : "memory" : "volatile"),
+            None => asm!("fxsave64 ($0)" :: "r"(area) : "memory" : "volatile"),
+        }
+    }
+
+    /// Loads the calling CPU's registers.
+    pub unsafe fn restore(&self) {
+        let area = self.area.0.as_ptr();
+
+        match *MODE {
+            Some(ref mode) => asm!("xrstor64 ($0)"
+                :: "r"(area), "{eax}"(mode.xcr0 as u32), "{edx}"((mode.xcr0 >> 32) as u32)
+                : "memory" : "volatile"),
+            None => asm!("fxrstor64 ($0)" :: "r"(area) : "memory" : "volatile"),
+        }
+    }
+}
+
+impl Debug for FpuState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FpuState").finish()
+    }
+}
@@ -0,0 +1,684 @@
+use core::convert::TryInto;
+use core::fmt::{self, Debug};
+use core::mem;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{self, AtomicU32, AtomicUsize, Ordering};
+use core::task::{Poll, Waker};
+
+use arrayvec::ArrayVec;
+use futures::future;
+use interface::{SysError, SysResult};
+
+use crate::device::block::{self, BlockDevice, BlockError, BlockFuture, RequestQueue, Sector, BLOCK_SIZE};
+use crate::device::pci::{Bar, Command, PciAddress, PciDevice, PciDriver, PciFuture};
+use crate::interrupt::{self, IrqMode};
+use crate::mem::dma::{self, Constraints, DmaBuffer};
+use crate::mem::kvirt;
+use crate::mem::page::PAGE_SIZE;
+use crate::mem::phys::RawPhys;
+use crate::mem::MemoryExhausted;
+use crate::sync::{Arc, AsyncMutex, Mutex};
+use crate::time;
+use crate::work;
+
+/// The class, subclass and programming interface of an AHCI controller.
+pub const PCI_CLASS: (u8, u8, u8) = (0x01, 0x06, 0x01);
+
+/// The most AHCI controllers that get attached to.
+pub const MAX_CONTROLLERS: usize = 2;
+
+/// The most SATA disks that get registered, across all controllers, as sda,
+/// sdb and so on.
+pub const MAX_DISKS: usize = 8;
+
+// the BAR the controller's registers are in:
+const ABAR: u8 = 5;
+
+const MAX_PORTS: usize = 32;
+
+// controller registers:
+const HBA_CAP: usize = 0x00;
+const HBA_GHC: usize = 0x04;
+const HBA_IS: usize = 0x08;
+const HBA_PI: usize = 0x0c;
+const HBA_CAP2: usize = 0x24;
+const HBA_BOHC: usize = 0x28;
+const HBA_PORTS: usize = 0x100;
+const PORT_SIZE: usize = 0x80;
+const HBA_SIZE: usize = HBA_PORTS + MAX_PORTS * PORT_SIZE;
+
+const CAP_S64A: u32 = 1 << 31;
+const CAP_SSS: u32 = 1 << 27;
+const CAP2_BOH: u32 = 1 << 0;
+const BOHC_BOS: u32 = 1 << 0;
+const BOHC_OOS: u32 = 1 << 1;
+const GHC_IE: u32 = 1 << 1;
+const GHC_AE: u32 = 1 << 31;
+
+// port registers, from the start of the port's:
+const PORT_CLB: usize = 0x00;
+const PORT_CLBU: usize = 0x04;
+const PORT_FB: usize = 0x08;
+const PORT_FBU: usize = 0x0c;
+const PORT_IS: usize = 0x10;
+const PORT_IE: usize = 0x14;
+const PORT_CMD: usize = 0x18;
+const PORT_TFD: usize = 0x20;
+const PORT_SIG: usize = 0x24;
+const PORT_SSTS: usize = 0x28;
+const PORT_SERR: usize = 0x30;
+const PORT_CI: usize = 0x38;
+
+const SSTS_DET_PRESENT: u32 = 0x3;
+const SSTS_IPM_ACTIVE: u32 = 0x1;
+const SIG_ATA: u32 = 0x0000_0101;
+
+const TFD_ERR: u32 = 0x01;
+const TFD_DRQ: u32 = 0x08;
+const TFD_BSY: u32 = 0x80;
+
+bitflags::bitflags! {
+    struct PortCommand: u32 {
+        const START                 = 1 << 0;
+        const SPIN_UP               = 1 << 1;
+        const POWER_ON              = 1 << 2;
+        const FIS_RECEIVE           = 1 << 4;
+        const FIS_RECEIVE_RUNNING   = 1 << 14;
+        const LIST_RUNNING          = 1 << 15;
+    }
+}
+
+bitflags::bitflags! {
+    struct PortInterrupt: u32 {
+        const D2H_REGISTER      = 1 << 0;
+        const PIO_SETUP         = 1 << 1;
+        const DMA_SETUP         = 1 << 2;
+        const SET_DEVICE_BITS   = 1 << 3;
+        const INTERFACE_FATAL   = 1 << 27;
+        const HOST_BUS_DATA     = 1 << 28;
+        const HOST_BUS_FATAL    = 1 << 29;
+        const TASK_FILE_ERROR   = 1 << 30;
+
+        const ERRORS = Self::INTERFACE_FATAL.bits | Self::HOST_BUS_DATA.bits
+            | Self::HOST_BUS_FATAL.bits | Self::TASK_FILE_ERROR.bits;
+    }
+}
+
+// each port has a page for the controller: its command list, where it puts
+// FISes it receives, and the table of the one command slot used:
+const COMMAND_LIST: usize = 0x000;
+const RECEIVED_FIS: usize = 0x400;
+const COMMAND_TABLE: usize = 0x500;
+const PRDT: usize = COMMAND_TABLE + 0x80;
+
+const FIS_TYPE_H2D: u8 = 0x27;
+const FIS_H2D_COMMAND: u8 = 0x80;
+const FIS_LENGTH: u32 = 5;
+const DEVICE_LBA: u8 = 1 << 6;
+
+const HEADER_WRITE: u32 = 1 << 6;
+const PRD_INTERRUPT: u32 = 1 << 31;
+
+const ATA_IDENTIFY: u8 = 0xec;
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_FLUSH_CACHE_EXT: u8 = 0xea;
+
+// the most sectors a command carries, longer transfers are split up:
+const COMMAND_SECTORS: usize = 128;
+
+// how long, in milliseconds, the controller gets to stop a port or hand
+// itself over from the firmware:
+const STOP_TIMEOUT_MS: u64 = 500;
+const HANDOFF_TIMEOUT_MS: u64 = 2000;
+const COMMAND_TIMEOUT_MS: u64 = 1000;
+
+// for stopping a port where sleeping isn't an option, in register reads:
+const STOP_SPINS: usize = 1_000_000;
+
+#[derive(Debug)]
+pub enum AhciError {
+    /// BAR 5 isn't memory mapped registers.
+    NoRegisters,
+    /// The controller didn't do what it was asked in time.
+    Timeout,
+    MemoryExhausted,
+}
+
+impl From<MemoryExhausted> for AhciError {
+    fn from(_: MemoryExhausted) -> AhciError {
+        AhciError::MemoryExhausted
+    }
+}
+
+impl From<AhciError> for SysError {
+    fn from(e: AhciError) -> SysError {
+        match e {
+            AhciError::NoRegisters => SysError::InvalidOperation,
+            AhciError::Timeout => SysError::IoError,
+            AhciError::MemoryExhausted => SysError::MemoryExhausted,
+        }
+    }
+}
+
+// a controller's or port's memory mapped registers:
+#[derive(Clone, Copy)]
+struct Registers(NonNull<u8>);
+
+impl Registers {
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { ptr::read_volatile(self.0.as_ptr().add(offset) as *const u32) }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { ptr::write_volatile(self.0.as_ptr().add(offset) as *mut u32, value) }
+    }
+
+    fn at(&self, offset: usize) -> Registers {
+        Registers(unsafe { NonNull::new_unchecked(self.0.as_ptr().add(offset)) })
+    }
+
+    fn command(&self) -> PortCommand {
+        PortCommand::from_bits_truncate(self.read(PORT_CMD))
+    }
+
+    fn set_command(&self, command: PortCommand) {
+        self.write(PORT_CMD, command.bits());
+    }
+}
+
+// polls `done` every millisecond until it's true, for up to `timeout_ms`:
+async fn wait_until(timeout_ms: u64, done: impl Fn() -> bool) -> Result<bool, MemoryExhausted> {
+    for _ in 0..timeout_ms {
+        if done() {
+            return Ok(true);
+        }
+
+        time::sleep_ns(1_000_000).await?;
+    }
+
+    Ok(done())
+}
+
+/// An AHCI controller, with the ports that have a SATA disk attached.
+pub struct Hba {
+    pci: PciAddress,
+    registers: Registers,
+    ports: ArrayVec<[Arc<AhciPort>; MAX_PORTS]>,
+}
+
+// the controllers found, for the interrupt handler to find by index:
+static CONTROLLERS: Mutex<[Option<Arc<Hba>>; MAX_CONTROLLERS]> = Mutex::new([None, None]);
+
+impl Hba {
+    async fn new(pci: PciAddress) -> Result<Hba, AhciError> {
+        let base = match pci.bar(ABAR) {
+            Some(Bar::Memory(base)) => base,
+            _ => return Err(AhciError::NoRegisters),
+        };
+
+        pci.enable(Command::MEMORY_SPACE | Command::BUS_MASTER);
+
+        let offset = base as usize % PAGE_SIZE;
+        let pages = (offset + HBA_SIZE + PAGE_SIZE - 1) / PAGE_SIZE;
+
+        let registers = unsafe {
+            let mapped = kvirt::map_mmio(RawPhys(base - offset as u64), pages)?;
+            Registers(NonNull::new_unchecked(mapped.as_ptr().add(offset)))
+        };
+
+        // take the controller from the firmware, if it's using it:
+        if registers.read(HBA_CAP2) & CAP2_BOH != 0 {
+            registers.write(HBA_BOHC, registers.read(HBA_BOHC) | BOHC_OOS);
+
+            if !wait_until(HANDOFF_TIMEOUT_MS, || registers.read(HBA_BOHC) & BOHC_BOS == 0).await? {
+                return Err(AhciError::Timeout);
+            }
+        }
+
+        registers.write(HBA_GHC, registers.read(HBA_GHC) | GHC_AE);
+
+        let capabilities = registers.read(HBA_CAP);
+        let implemented = registers.read(HBA_PI);
+
+        // without 64 bit addressing, everything the controller reads or
+        // writes has to be below 4G:
+        let constraints = match capabilities & CAP_S64A {
+            0 => Constraints::BELOW_4G,
+            _ => Constraints::ANY,
+        };
+
+        let mut ports = ArrayVec::new();
+
+        for number in 0..MAX_PORTS {
+            if implemented & (1 << number) == 0 {
+                continue;
+            }
+
+            let port_registers = registers.at(HBA_PORTS + number * PORT_SIZE);
+
+            match AhciPort::new(port_registers, number as u8, constraints, capabilities & CAP_SSS != 0).await {
+                Ok(Some(port)) => ports.push(Arc::new(port)?),
+                Ok(None) => {}
+                Err(e) => crate::println!("ahci: can't set up port {} of {:?}: {:?}", number, pci, e),
+            }
+        }
+
+        registers.write(HBA_IS, !0);
+        registers.write(HBA_GHC, registers.read(HBA_GHC) | GHC_IE);
+
+        Ok(Hba { pci, registers, ports })
+    }
+}
+
+impl Debug for Hba {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Hba({:?}, {} ports)", self.pci, self.ports.len())
+    }
+}
+
+/// A port of an AHCI controller with a SATA disk attached. Commands go
+/// through a single command slot, one at a time.
+pub struct AhciPort {
+    number: u8,
+    registers: Registers,
+    constraints: Constraints,
+    // the port's page for the controller, which whoever is using the
+    // command slot has:
+    slot: AsyncMutex<DmaBuffer>,
+    // interrupt status the interrupt handler has taken from the port, for
+    // complete() to look at:
+    pending: AtomicU32,
+    status: Mutex<PortStatus>,
+}
+
+struct PortStatus {
+    error: bool,
+    waker: Option<Waker>,
+}
+
+impl AhciPort {
+    // sets up a port for commands, or None if it has no SATA disk:
+    async fn new(registers: Registers, number: u8, constraints: Constraints, staggered_spin_up: bool)
+        -> Result<Option<AhciPort>, AhciError>
+    {
+        if staggered_spin_up {
+            registers.set_command(registers.command() | PortCommand::SPIN_UP | PortCommand::POWER_ON);
+            wait_until(10, || registers.read(PORT_SSTS) & 0xf == SSTS_DET_PRESENT).await?;
+        }
+
+        let status = registers.read(PORT_SSTS);
+
+        if status & 0xf != SSTS_DET_PRESENT || (status >> 8) & 0xf != SSTS_IPM_ACTIVE {
+            return Ok(None);
+        }
+
+        // ATAPI and port multipliers aren't disks:
+        if registers.read(PORT_SIG) != SIG_ATA {
+            return Ok(None);
+        }
+
+        // the command list and FIS addresses can only be changed while the
+        // port is idle:
+        registers.set_command(registers.command() - PortCommand::START - PortCommand::FIS_RECEIVE);
+
+        let idle = wait_until(STOP_TIMEOUT_MS, || {
+            !registers.command().intersects(PortCommand::LIST_RUNNING | PortCommand::FIS_RECEIVE_RUNNING)
+        }).await?;
+
+        if !idle {
+            return Err(AhciError::Timeout);
+        }
+
+        let memory = dma::alloc(PAGE_SIZE, constraints)?;
+        let phys = memory.phys().0;
+
+        registers.write(PORT_CLB, (phys + COMMAND_LIST as u64) as u32);
+        registers.write(PORT_CLBU, ((phys + COMMAND_LIST as u64) >> 32) as u32);
+        registers.write(PORT_FB, (phys + RECEIVED_FIS as u64) as u32);
+        registers.write(PORT_FBU, ((phys + RECEIVED_FIS as u64) >> 32) as u32);
+
+        registers.write(PORT_SERR, !0);
+        registers.write(PORT_IS, !0);
+        registers.write(PORT_IE, (PortInterrupt::D2H_REGISTER | PortInterrupt::PIO_SETUP
+            | PortInterrupt::DMA_SETUP | PortInterrupt::SET_DEVICE_BITS | PortInterrupt::ERRORS).bits());
+
+        registers.set_command(registers.command() | PortCommand::SPIN_UP | PortCommand::POWER_ON | PortCommand::FIS_RECEIVE);
+        registers.set_command(registers.command() | PortCommand::START);
+
+        Ok(Some(AhciPort {
+            number,
+            registers,
+            constraints,
+            slot: AsyncMutex::new(memory),
+            pending: AtomicU32::new(0),
+            status: Mutex::new(PortStatus { error: false, waker: None }),
+        }))
+    }
+
+    // stops and starts the port again, which abandons whatever command it
+    // had and clears any error. it's done without sleeping, so that it can
+    // be done on drop:
+    fn restart(&self) {
+        let registers = self.registers;
+
+        registers.set_command(registers.command() - PortCommand::START);
+
+        for _ in 0..STOP_SPINS {
+            if !registers.command().contains(PortCommand::LIST_RUNNING) {
+                break;
+            }
+
+            atomic::spin_loop_hint();
+        }
+
+        registers.write(PORT_SERR, !0);
+        registers.write(PORT_IS, !0);
+        registers.set_command(registers.command() | PortCommand::START);
+    }
+
+    // issues an ATA command with the given data and waits for it to
+    // complete. `count` is in sectors, and `len` in bytes of `data`:
+    async fn command(&self, command: u8, lba: u64, count: u16, data: Option<(&DmaBuffer, usize)>, write: bool)
+        -> Result<(), BlockError>
+    {
+        let mut slot = self.slot.lock().await?;
+
+        let ready = wait_until(COMMAND_TIMEOUT_MS, || self.registers.read(PORT_TFD) & (TFD_BSY | TFD_DRQ) == 0).await?;
+
+        if !ready {
+            self.restart();
+            return Err(BlockError::Io);
+        }
+
+        let table = slot.phys().0 + COMMAND_TABLE as u64;
+
+        {
+            let bytes = slot.as_mut_slice();
+            let lba = lba.to_le_bytes();
+            let count = count.to_le_bytes();
+
+            let fis = [
+                FIS_TYPE_H2D, FIS_H2D_COMMAND, command, 0,
+                lba[0], lba[1], lba[2], DEVICE_LBA,
+                lba[3], lba[4], lba[5], 0,
+                count[0], count[1], 0, 0,
+                0, 0, 0, 0,
+            ];
+
+            bytes[COMMAND_TABLE..(COMMAND_TABLE + fis.len())].copy_from_slice(&fis);
+
+            let prdt_len = match data {
+                Some((data, len)) => {
+                    bytes[PRDT..(PRDT + 8)].copy_from_slice(&data.phys().0.to_le_bytes());
+                    bytes[(PRDT + 8)..(PRDT + 12)].copy_from_slice(&0u32.to_le_bytes());
+                    bytes[(PRDT + 12)..(PRDT + 16)].copy_from_slice(&((len as u32 - 1) | PRD_INTERRUPT).to_le_bytes());
+                    1
+                }
+                None => 0,
+            };
+
+            let flags = FIS_LENGTH | if write { HEADER_WRITE } else { 0 } | prdt_len << 16;
+
+            bytes[COMMAND_LIST..(COMMAND_LIST + 4)].copy_from_slice(&flags.to_le_bytes());
+            bytes[(COMMAND_LIST + 4)..(COMMAND_LIST + 8)].copy_from_slice(&0u32.to_le_bytes());
+            bytes[(COMMAND_LIST + 8)..(COMMAND_LIST + 16)].copy_from_slice(&table.to_le_bytes());
+        }
+
+        slot.sync_for_device();
+
+        self.status.lock().error = false;
+        self.registers.write(PORT_CI, 1);
+
+        let issued = Issued(self);
+
+        let result = future::poll_fn(|cx| {
+            let mut status = self.status.lock();
+
+            if status.error {
+                return Poll::Ready(Err(BlockError::Io));
+            }
+
+            if self.registers.read(PORT_CI) & 1 == 0 {
+                return Poll::Ready(Ok(()));
+            }
+
+            status.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }).await;
+
+        mem::forget(issued);
+
+        if result.is_err() || self.registers.read(PORT_TFD) & TFD_ERR != 0 {
+            self.restart();
+            return Err(BlockError::Io);
+        }
+
+        Ok(())
+    }
+
+    async fn identify(&self) -> Result<u64, BlockError> {
+        let data = dma::alloc(BLOCK_SIZE, self.constraints)?;
+
+        self.command(ATA_IDENTIFY, 0, 0, Some((&data, BLOCK_SIZE)), false).await?;
+
+        data.sync_for_cpu();
+
+        let identify = data.as_slice();
+        let read_u32 = |offset: usize| u32::from_le_bytes(identify[offset..(offset + 4)].try_into().expect("read_u32"));
+
+        // word 83 says whether there's a 48 bit sector count in words 100
+        // to 103, otherwise there's only the 28 bit one in words 60 and 61:
+        let sectors = if identify[167] & (1 << 2) != 0 {
+            read_u32(200) as u64 | (read_u32(204) as u64) << 32
+        } else {
+            read_u32(120) as u64
+        };
+
+        Ok(sectors)
+    }
+}
+
+impl Debug for AhciPort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AhciPort({})", self.number)
+    }
+}
+
+// abandons the command in the slot if its future is dropped before it
+// completes, so the controller doesn't go on using the buffers:
+struct Issued<'a>(&'a AhciPort);
+
+impl Drop for Issued<'_> {
+    fn drop(&mut self) {
+        if self.0.registers.read(PORT_CI) & 1 != 0 {
+            self.0.restart();
+        }
+
+        self.0.status.lock().waker = None;
+    }
+}
+
+/// A SATA disk attached to an AHCI port, as a block device.
+#[derive(Debug)]
+pub struct AhciDisk {
+    port: Arc<AhciPort>,
+    sectors: u64,
+}
+
+impl BlockDevice for AhciDisk {
+    fn block_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_blocks<'a, 'b: 'a>(&'a self, lba: u64, buffs: &'a mut [&'b mut Sector]) -> BlockFuture<'a, ()> {
+        BlockFuture::new(async move {
+            for (index, chunk) in buffs.chunks_mut(COMMAND_SECTORS).enumerate() {
+                let lba = lba + (index * COMMAND_SECTORS) as u64;
+                let len = chunk.len() * BLOCK_SIZE;
+                let data = dma::alloc(len, self.port.constraints)?;
+
+                self.port.command(ATA_READ_DMA_EXT, lba, chunk.len() as u16, Some((&data, len)), false).await?;
+
+                data.sync_for_cpu();
+
+                for (sector, bytes) in chunk.iter_mut().zip(data.as_slice().chunks(BLOCK_SIZE)) {
+                    sector.copy_from_slice(bytes);
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    // the drive may have the data in its own cache when the writes complete,
+    // so it's flushed afterwards:
+    fn write_blocks<'a>(&'a self, lba: u64, buffs: &'a [&'a Sector]) -> BlockFuture<'a, ()> {
+        BlockFuture::new(async move {
+            for (index, chunk) in buffs.chunks(COMMAND_SECTORS).enumerate() {
+                let lba = lba + (index * COMMAND_SECTORS) as u64;
+                let len = chunk.len() * BLOCK_SIZE;
+                let mut data = dma::alloc(len, self.port.constraints)?;
+
+                for (sector, bytes) in chunk.iter().zip(data.as_mut_slice().chunks_mut(BLOCK_SIZE)) {
+                    bytes.copy_from_slice(&sector[..]);
+                }
+
+                data.sync_for_device();
+
+                self.port.command(ATA_WRITE_DMA_EXT, lba, chunk.len() as u16, Some((&data, len)), true).await?;
+            }
+
+            self.port.command(ATA_FLUSH_CACHE_EXT, 0, 0, None, false).await
+        })
+    }
+}
+
+fn get(index: u64) -> Option<Arc<Hba>> {
+    CONTROLLERS.lock().get(index as usize).and_then(|hba| hba.clone())
+}
+
+// runs in the interrupt handler, which may be shared with other devices:
+fn interrupt(index: u64) {
+    let hba = match get(index) {
+        Some(hba) => hba,
+        None => return,
+    };
+
+    let interrupting = hba.registers.read(HBA_IS);
+
+    if interrupting == 0 {
+        return;
+    }
+
+    // each port's status is cleared before the controller's, or it would
+    // interrupt again straight away:
+    for port in hba.ports.iter().filter(|port| interrupting & (1 << port.number) != 0) {
+        let status = port.registers.read(PORT_IS);
+        port.registers.write(PORT_IS, status);
+        port.pending.fetch_or(status, Ordering::SeqCst);
+    }
+
+    hba.registers.write(HBA_IS, interrupting);
+
+    // waking tasks takes scheduler locks, leave that until after the
+    // interrupt, unless there's no room to:
+    if work::defer(complete, index).is_err() {
+        complete(index);
+    }
+}
+
+// wakes whoever is waiting on a port the controller interrupted for:
+fn complete(index: u64) {
+    let hba = match get(index) {
+        Some(hba) => hba,
+        None => return,
+    };
+
+    for port in hba.ports.iter() {
+        let pending = PortInterrupt::from_bits_truncate(port.pending.swap(0, Ordering::SeqCst));
+
+        if pending.is_empty() {
+            continue;
+        }
+
+        let mut status = port.status.lock();
+
+        if pending.intersects(PortInterrupt::ERRORS) {
+            status.error = true;
+        }
+
+        if let Some(waker) = status.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Attaches to AHCI controllers, registering each SATA disk attached to them
+/// as a block device, sda, sdb and so on. Disks that can't be set up are
+/// skipped.
+pub struct AhciDriver;
+
+pub static DRIVER: AhciDriver = AhciDriver;
+
+// how many disks have been registered, across all controllers:
+static DISKS: AtomicUsize = AtomicUsize::new(0);
+
+impl PciDriver for AhciDriver {
+    fn name(&self) -> &'static str {
+        "ahci"
+    }
+
+    fn matches(&self, device: &PciDevice) -> bool {
+        device.class == PCI_CLASS
+    }
+
+    fn attach<'a>(&'a self, device: &'a PciDevice) -> PciFuture<'a> {
+        PciFuture::new(attach(device))
+    }
+}
+
+async fn attach(device: &PciDevice) -> SysResult<()> {
+    let irq = device.legacy_irq()
+        .ok_or(SysError::InvalidOperation)?;
+
+    let hba = Arc::new(Hba::new(device.address).await?)?;
+
+    let index = {
+        let mut controllers = CONTROLLERS.lock();
+
+        let index = controllers.iter().position(Option::is_none)
+            .ok_or(SysError::NoSpace)?;
+
+        controllers[index] = Some(hba.clone());
+        index
+    };
+
+    if interrupt::register_irq_mode(irq, IrqMode::PCI, interrupt, index as u64).is_err() {
+        CONTROLLERS.lock()[index] = None;
+        return Err(SysError::Busy);
+    }
+
+    for port in hba.ports.iter() {
+        let sectors = match port.identify().await {
+            Ok(sectors) => sectors,
+            Err(e) => {
+                crate::println!("ahci: can't identify {:?} of {:?}: {:?}", port, device.address, e);
+                continue;
+            }
+        };
+
+        let disk = DISKS.fetch_add(1, Ordering::SeqCst);
+
+        if disk >= MAX_DISKS {
+            break;
+        }
+
+        let name = [b's', b'd', b'a' + disk as u8];
+        let disk = AhciDisk { port: port.clone(), sectors };
+
+        block::register(&name, Arc::new(RequestQueue::new(Arc::new(disk)?))?).await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,599 @@
+use core::fmt::{self, Debug, Write};
+use core::future::Future;
+use core::ops::Bound;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::task::{Context, Poll, Waker};
+
+use alloc_collections::btree_map::BTreeMap;
+use arrayvec::{ArrayString, ArrayVec};
+use futures::future;
+use interface::{SysError, SysResult};
+
+use crate::config::BLOCK_NAME_MAX;
+use crate::device::{cache, partition};
+use crate::mem::kalloc::{Box, GlobalAlloc};
+use crate::mem::MemoryExhausted;
+use crate::sync::{Arc, Mutex};
+use crate::util::EarlyInit;
+
+pub const BLOCK_SIZE: usize = 512;
+
+pub type Sector = [u8; BLOCK_SIZE];
+
+// the most blocks a request queue gives a driver at once, merged from
+// neighbouring requests:
+const MERGE_MAX: usize = 64;
+
+// each request carries up to a page's worth of blocks. longer transfers are
+// split into several, which the queue merges back together:
+const REQUEST_BLOCKS: usize = 8;
+const REQUESTS_PER_MERGE: usize = MERGE_MAX / REQUEST_BLOCKS;
+
+type Name = ArrayVec<[u8; BLOCK_NAME_MAX]>;
+
+/// Identifies a registered block device to the block cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DeviceId(pub u32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The device failed the transfer.
+    Io,
+    /// The transfer would go past the end of the device.
+    OutOfRange,
+    MemoryExhausted,
+}
+
+impl From<MemoryExhausted> for BlockError {
+    fn from(_: MemoryExhausted) -> BlockError {
+        BlockError::MemoryExhausted
+    }
+}
+
+impl From<BlockError> for SysError {
+    fn from(e: BlockError) -> SysError {
+        match e {
+            BlockError::Io => SysError::IoError,
+            BlockError::OutOfRange => SysError::IllegalValue,
+            BlockError::MemoryExhausted => SysError::MemoryExhausted,
+        }
+    }
+}
+
+/// The future returned by BlockDevice's methods, boxed so that it can be
+/// used as a trait object. Fails with MemoryExhausted when polled if there's
+/// no memory to box it in.
+pub enum BlockFuture<'a, T> {
+    Boxed(Pin<alloc_collections::boxed::Box<dyn Future<Output = Result<T, BlockError>> + 'a, GlobalAlloc>>),
+    Failed,
+}
+
+impl<'a, T> BlockFuture<'a, T> {
+    pub fn new(future: impl Future<Output = Result<T, BlockError>> + 'a) -> Self {
+        match alloc_collections::boxed::Box::new(future) {
+            Ok(future) => {
+                let future = future as alloc_collections::boxed::Box<dyn Future<Output = Result<T, BlockError>> + 'a, GlobalAlloc>;
+                BlockFuture::Boxed(unsafe { Pin::new_unchecked(future) })
+            }
+            Err(_) => BlockFuture::Failed,
+        }
+    }
+}
+
+impl<'a, T> Future for BlockFuture<'a, T> {
+    type Output = Result<T, BlockError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        match self.get_mut() {
+            BlockFuture::Boxed(future) => future.as_mut().poll(cx),
+            BlockFuture::Failed => Poll::Ready(Err(BlockError::MemoryExhausted)),
+        }
+    }
+}
+
+/// Storage addressed in 512 byte blocks, such as a disk, as a driver
+/// provides it. Filesystems get at it through a registered Disk, which
+/// caches it.
+pub trait BlockDevice: Debug {
+    fn block_count(&self) -> u64;
+
+    /// Reads a block into each buffer, starting from block `lba`.
+    fn read_blocks<'a, 'b: 'a>(&'a self, lba: u64, buffs: &'a mut [&'b mut Sector]) -> BlockFuture<'a, ()>;
+
+    /// Writes each buffer to a block, starting from block `lba`. The data has
+    /// reached the storage itself by the time this completes.
+    fn write_blocks<'a>(&'a self, lba: u64, buffs: &'a [&'a Sector]) -> BlockFuture<'a, ()>;
+}
+
+// fails with OutOfRange unless `count` blocks from `lba` are all within
+// `block_count`:
+fn check_range(block_count: u64, lba: u64, count: usize) -> Result<(), BlockError> {
+    match lba.checked_add(count as u64) {
+        Some(end) if end <= block_count => Ok(()),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+/// A registered block device, under the name and id it was given: either a
+/// whole disk or a partition of one. Reads and writes go through the block
+/// cache.
+pub struct Disk {
+    name: Name,
+    id: DeviceId,
+    backing: Backing,
+}
+
+enum Backing {
+    Device(Arc<dyn BlockDevice>),
+    // a partition's blocks are cached as blocks of the whole disk, so that
+    // the two never disagree:
+    Partition { whole: Arc<Disk>, start: u64, blocks: u64 },
+}
+
+// every registered device, by name:
+static DISKS: EarlyInit<Mutex<BTreeMap<Name, Arc<Disk>, GlobalAlloc>>> = EarlyInit::new();
+
+static NEXT_ID: AtomicU32 = AtomicU32::new(0);
+
+pub fn init() {
+    EarlyInit::set(&DISKS, Mutex::new(BTreeMap::new()));
+}
+
+/// Registers a device under `name`, like "hda", returning it as a Disk for
+/// filesystems to use. Fails with AlreadyMapped if the name is taken.
+///
+/// Each partition in the device's partition table is registered too, under
+/// its number appended to the name, like "hda1". A name that already ends
+/// in a digit gets a "p" before the number, like "nvme0n1p1".
+pub async fn register(name: &[u8], device: Arc<dyn BlockDevice>) -> SysResult<Arc<Disk>> {
+    let disk = add(self::name(name)?, Backing::Device(device))?;
+
+    let partitions = match partition::scan(&disk).await {
+        Ok(partitions) => partitions,
+        Err(e) => {
+            crate::println!("block: could not read partition table of {:?}: {:?}", disk, e);
+            return Ok(disk);
+        }
+    };
+
+    for partition in partitions {
+        let backing = Backing::Partition {
+            whole: disk.clone(),
+            start: partition.start,
+            blocks: partition.blocks,
+        };
+
+        let result = partition_name(&disk.name, partition.number)
+            .and_then(|name| add(name, backing));
+
+        if let Err(e) = result {
+            crate::println!("block: could not register partition {} of {:?}: {:?}", partition.number, disk, e);
+        }
+    }
+
+    Ok(disk)
+}
+
+fn add(name: Name, backing: Backing) -> SysResult<Arc<Disk>> {
+    if name.is_empty() || name.contains(&b'/') {
+        return Err(SysError::IllegalValue);
+    }
+
+    let mut disks = DISKS.lock();
+
+    if disks.contains_key(&name) {
+        return Err(SysError::AlreadyMapped);
+    }
+
+    let disk = Arc::new(Disk {
+        name: name.clone(),
+        id: DeviceId(NEXT_ID.fetch_add(1, Ordering::SeqCst)),
+        backing,
+    })?;
+
+    disks.insert(name, disk.clone())
+        .map_err(|_| SysError::MemoryExhausted)?;
+
+    Ok(disk)
+}
+
+/// Finds a registered device by name.
+pub fn get(name: &[u8]) -> Option<Arc<Disk>> {
+    let name = self::name(name).ok()?;
+    DISKS.lock().get(&name).cloned()
+}
+
+/// The registered device whose name comes next after `name`, in byte
+/// order, for going through them all without holding the registry's lock.
+/// Passing an empty name gives the first.
+pub fn next(name: &[u8]) -> Option<Arc<Disk>> {
+    DISKS.lock().range::<[u8], _>((Bound::Excluded(name), Bound::Unbounded))
+        .next()
+        .map(|(_, disk)| disk.clone())
+}
+
+fn name(name: &[u8]) -> SysResult<Name> {
+    let mut buf = Name::new();
+
+    for byte in name {
+        buf.try_push(*byte)
+            .map_err(|_| SysError::IllegalValue)?;
+    }
+
+    Ok(buf)
+}
+
+fn partition_name(disk: &[u8], number: usize) -> SysResult<Name> {
+    let mut name = self::name(disk)?;
+
+    let mut digits = ArrayString::<[u8; 20]>::new();
+    write!(digits, "{}", number).map_err(|_| SysError::IllegalValue)?;
+
+    let separator = match disk.last() {
+        Some(byte) if byte.is_ascii_digit() => Some(b'p'),
+        _ => None,
+    };
+
+    for byte in separator.iter().chain(digits.as_bytes()) {
+        name.try_push(*byte)
+            .map_err(|_| SysError::IllegalValue)?;
+    }
+
+    Ok(name)
+}
+
+impl Disk {
+    pub fn name(&self) -> &[u8] {
+        &self.name
+    }
+
+    pub fn id(&self) -> DeviceId {
+        self.id
+    }
+
+    pub fn block_count(&self) -> u64 {
+        match &self.backing {
+            Backing::Device(device) => device.block_count(),
+            Backing::Partition { blocks, .. } => *blocks,
+        }
+    }
+
+    /// The whole disk a partition is on, or None if this is a whole disk.
+    pub fn parent(&self) -> Option<&Arc<Disk>> {
+        match &self.backing {
+            Backing::Device(_) => None,
+            Backing::Partition { whole, .. } => Some(whole),
+        }
+    }
+
+    /// The driver's device, for the block cache to read and write through.
+    /// Only whole disks have one.
+    pub fn device(&self) -> &dyn BlockDevice {
+        match &self.backing {
+            Backing::Device(device) => &**device,
+            Backing::Partition { .. } => panic!("Disk::device: partitions have no device of their own"),
+        }
+    }
+
+    // checks a transfer is within the disk, and finds where it is on the whole
+    // disk:
+    fn locate(&self, lba: u64, count: usize) -> Result<(&Disk, u64), BlockError> {
+        check_range(self.block_count(), lba, count)?;
+
+        match &self.backing {
+            Backing::Device(_) => Ok((self, lba)),
+            Backing::Partition { whole, start, .. } => Ok((whole, start + lba)),
+        }
+    }
+
+    pub async fn read_blocks(&self, lba: u64, buffs: &mut [&mut Sector]) -> Result<(), BlockError> {
+        let (whole, lba) = self.locate(lba, buffs.len())?;
+        cache::read_sectors(whole, lba as usize, buffs).await
+    }
+
+    pub async fn write_blocks(&self, lba: u64, buffs: &[&Sector]) -> Result<(), BlockError> {
+        let (whole, lba) = self.locate(lba, buffs.len())?;
+        cache::write_sectors(whole, lba as usize, buffs).await
+    }
+
+    /// Writes anything written to the disk that's still only in the block
+    /// cache back to it. For a partition, that's the whole disk it's on.
+    pub async fn writeback(&self) -> Result<(), BlockError> {
+        let (whole, _) = self.locate(0, 0)?;
+        cache::writeback(whole).await
+    }
+}
+
+impl Debug for Disk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = core::str::from_utf8(&self.name).unwrap_or("?");
+
+        match &self.backing {
+            Backing::Device(device) => write!(f, "Disk({}, {:?})", name, device),
+            Backing::Partition { start, blocks, .. } => write!(f, "Disk({}, {}+{})", name, start, blocks),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Read,
+    Write,
+}
+
+type RequestData = [Sector; REQUEST_BLOCKS];
+
+struct Request {
+    op: Op,
+    lba: u64,
+    count: usize,
+    state: Mutex<RequestState>,
+}
+
+struct RequestState {
+    // taken by whoever is transferring the request, and put back after:
+    data: Option<Box<RequestData>>,
+    result: Option<Result<(), BlockError>>,
+    waker: Option<Waker>,
+}
+
+struct QueueState {
+    // by first block, then order of arrival:
+    pending: BTreeMap<(u64, u64), Arc<Request>, GlobalAlloc>,
+    arrivals: u64,
+    // the block after the last transfer, which the elevator carries on
+    // upwards from:
+    head: u64,
+    // whether some task is giving requests to the device:
+    running: bool,
+}
+
+/// Queues transfers to a device, handing them to it in elevator order: up
+/// through the disk from where the last transfer ended, then back to the
+/// lowest block waiting. Requests next to each other are merged into one
+/// transfer. There's no task of its own doing this, whichever task finds
+/// nobody else at it gives the device requests until there are none left.
+pub struct RequestQueue {
+    device: Arc<dyn BlockDevice>,
+    state: Mutex<QueueState>,
+}
+
+impl RequestQueue {
+    pub fn new(device: Arc<dyn BlockDevice>) -> Self {
+        RequestQueue {
+            device,
+            state: Mutex::new(QueueState {
+                pending: BTreeMap::new(),
+                arrivals: 0,
+                head: 0,
+                running: false,
+            }),
+        }
+    }
+
+    fn enqueue(&self, op: Op, lba: u64, data: Box<RequestData>, count: usize) -> Result<Arc<Request>, BlockError> {
+        let request = Arc::new(Request {
+            op,
+            lba,
+            count,
+            state: Mutex::new(RequestState { data: Some(data), result: None, waker: None }),
+        })?;
+
+        let mut state = self.state.lock();
+        let key = (lba, state.arrivals);
+
+        state.pending.insert(key, request.clone())
+            .map_err(|_| BlockError::MemoryExhausted)?;
+
+        state.arrivals += 1;
+
+        Ok(request)
+    }
+
+    // waits for a request to complete, giving the device requests in the
+    // meantime if nobody else is:
+    async fn wait(&self, request: &Request) -> Result<Box<RequestData>, BlockError> {
+        loop {
+            let done = future::poll_fn(|cx| {
+                {
+                    let mut request = request.state.lock();
+
+                    if let Some(result) = request.result {
+                        return Poll::Ready(Some(result));
+                    }
+
+                    request.waker = Some(cx.waker().clone());
+                }
+
+                let mut state = self.state.lock();
+
+                if state.running {
+                    Poll::Pending
+                } else {
+                    state.running = true;
+                    Poll::Ready(None)
+                }
+            }).await;
+
+            match done {
+                Some(result) => {
+                    result?;
+
+                    return Ok(request.state.lock().data.take()
+                        .expect("RequestQueue::wait: request done without its data"));
+                }
+                None => self.run().await,
+            }
+        }
+    }
+
+    // transfers requests until there are none left:
+    async fn run(&self) {
+        let _running = Running(self);
+
+        loop {
+            let batch = match self.state.lock().next_batch() {
+                Some(batch) => batch,
+                None => return,
+            };
+
+            let mut datas = batch.iter()
+                .map(|request| request.state.lock().data.take()
+                    .expect("RequestQueue::run: queued request without its data"))
+                .collect::<ArrayVec<[Box<RequestData>; REQUESTS_PER_MERGE]>>();
+
+            let op = batch[0].op;
+            let lba = batch[0].lba;
+
+            let result = {
+                let mut buffs = batch.iter()
+                    .zip(datas.iter_mut())
+                    .flat_map(|(request, data)| data[..request.count].iter_mut())
+                    .collect::<ArrayVec<[&mut Sector; MERGE_MAX]>>();
+
+                match op {
+                    Op::Read => self.device.read_blocks(lba, &mut buffs).await,
+                    Op::Write => {
+                        let buffs = buffs.iter().map(|buff| &**buff).collect::<ArrayVec<[&Sector; MERGE_MAX]>>();
+                        self.device.write_blocks(lba, &buffs).await
+                    }
+                }
+            };
+
+            for (request, data) in batch.iter().zip(datas.drain(..)) {
+                let mut state = request.state.lock();
+                state.data = Some(data);
+                state.result = Some(result);
+
+                if let Some(waker) = state.waker.take() {
+                    waker.wake();
+                }
+            }
+        }
+    }
+
+    async fn transfer(&self, op: Op, lba: u64, count: usize, fill: impl Fn(usize, &mut Sector), mut drain: impl FnMut(usize, &Sector))
+        -> Result<(), BlockError>
+    {
+        check_range(self.device.block_count(), lba, count)?;
+
+        let mut done = 0;
+
+        // a merge's worth of requests at a time, all queued up before waiting
+        // on any so that they can go together:
+        while done < count {
+            let mut requests = ArrayVec::<[(Arc<Request>, usize); REQUESTS_PER_MERGE]>::new();
+
+            while done < count && !requests.is_full() {
+                let len = core::cmp::min(REQUEST_BLOCKS, count - done);
+                let mut data = Box::new([[0u8; BLOCK_SIZE]; REQUEST_BLOCKS])?;
+
+                for index in 0..len {
+                    fill(done + index, &mut data[index]);
+                }
+
+                requests.push((self.enqueue(op, lba + done as u64, data, len)?, done));
+                done += len;
+            }
+
+            // every request is waited for, even after one fails, as they may
+            // still be on their way to the device:
+            let mut result = Ok(());
+
+            for (request, start) in requests.iter() {
+                match self.wait(request).await {
+                    Ok(data) => data[..request.count].iter().enumerate()
+                        .for_each(|(index, sector)| drain(start + index, sector)),
+                    Err(e) => result = Err(e),
+                }
+            }
+
+            result?;
+        }
+
+        Ok(())
+    }
+}
+
+// lets another task take over giving the device requests once this one is
+// done, or if it's dropped part way through:
+struct Running<'q>(&'q RequestQueue);
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock();
+        state.running = false;
+
+        // requests still to be waited on by whoever queued them have no
+        // waker yet, they'll find the queue idle when they get to it:
+        let waker = state.pending.values()
+            .find_map(|request| request.state.lock().waker.take());
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl QueueState {
+    // takes the next request in elevator order, along with any following
+    // on from it that can be merged in:
+    fn next_batch(&mut self) -> Option<ArrayVec<[Arc<Request>; REQUESTS_PER_MERGE]>> {
+        let key = self.pending.range((self.head, 0)..).next()
+            .or_else(|| self.pending.iter().next())
+            .map(|(key, _)| *key)?;
+
+        let first = self.pending.remove(&key)
+            .expect("QueueState::next_batch: request just found");
+
+        let mut end = first.lba + first.count as u64;
+        let mut batch = ArrayVec::new();
+        batch.push(first);
+
+        while !batch.is_full() {
+            let op = batch[0].op;
+
+            let key = self.pending.range((end, 0)..=(end, u64::max_value()))
+                .find(|(_, request)| request.op == op)
+                .map(|(key, _)| *key);
+
+            let next = match key {
+                Some(key) => self.pending.remove(&key).expect("QueueState::next_batch: request just found"),
+                None => break,
+            };
+
+            end += next.count as u64;
+            batch.push(next);
+        }
+
+        self.head = end;
+
+        Some(batch)
+    }
+}
+
+impl BlockDevice for RequestQueue {
+    fn block_count(&self) -> u64 {
+        self.device.block_count()
+    }
+
+    fn read_blocks<'a, 'b: 'a>(&'a self, lba: u64, buffs: &'a mut [&'b mut Sector]) -> BlockFuture<'a, ()> {
+        BlockFuture::new(async move {
+            let count = buffs.len();
+            self.transfer(Op::Read, lba, count, |_, _| {}, |index, sector| buffs[index].copy_from_slice(sector)).await
+        })
+    }
+
+    fn write_blocks<'a>(&'a self, lba: u64, buffs: &'a [&'a Sector]) -> BlockFuture<'a, ()> {
+        BlockFuture::new(async move {
+            self.transfer(Op::Write, lba, buffs.len(), |index, sector| sector.copy_from_slice(buffs[index]), |_, _| {}).await
+        })
+    }
+}
+
+impl Debug for RequestQueue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RequestQueue({:?})", self.device)
+    }
+}
@@ -0,0 +1,254 @@
+use alloc_collections::btree_map::BTreeMap;
+use arrayvec::ArrayVec;
+
+use crate::config::WRITEBACK_INTERVAL_MS;
+use crate::device::block::{self, BlockError, DeviceId, Disk, Sector, BLOCK_SIZE};
+use crate::mem::kalloc::{Box, GlobalAlloc};
+use crate::mem::page::PAGE_SIZE;
+use crate::mem::MemoryExhausted;
+use crate::sync::Mutex;
+use crate::task::{self, TaskName};
+use crate::time;
+use crate::util::EarlyInit;
+
+const SECTORS_PER_PAGE: usize = PAGE_SIZE / BLOCK_SIZE;
+
+// the cache stops growing at this many pages, 1 MiB, and makes room by
+// evicting the least recently used clean page:
+const MAX_PAGES: usize = 256;
+
+type PageData = [Sector; SECTORS_PER_PAGE];
+
+struct CachedPage {
+    data: Box<PageData>,
+    // written to since it was read in or last written back:
+    dirty: bool,
+    // Cache::clock as of the last access:
+    last_used: u64,
+}
+
+/// Page sized blocks of block devices, kept in memory so that repeated reads
+/// of the same sectors don't go to the disk each time. Writes land in the
+/// cache and only reach the disk on writeback.
+struct Cache {
+    // by device and page number, counting pages from the start of the device:
+    pages: BTreeMap<(DeviceId, u64), CachedPage, GlobalAlloc>,
+    clock: u64,
+}
+
+static CACHE: EarlyInit<Mutex<Cache>> = EarlyInit::new();
+
+pub fn init() {
+    EarlyInit::set(&CACHE, Mutex::new(Cache {
+        pages: BTreeMap::new(),
+        clock: 0,
+    }));
+}
+
+impl Cache {
+    fn touch(&mut self, key: (DeviceId, u64)) -> Option<&mut CachedPage> {
+        self.clock += 1;
+        let clock = self.clock;
+
+        let page = self.pages.get_mut(&key)?;
+        page.last_used = clock;
+        Some(page)
+    }
+
+    fn read(&mut self, key: (DeviceId, u64), sector: usize, buff: &mut Sector) -> bool {
+        match self.touch(key) {
+            Some(page) => {
+                buff.copy_from_slice(&page.data[sector]);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn write(&mut self, key: (DeviceId, u64), sector: usize, buff: &Sector) -> bool {
+        match self.touch(key) {
+            Some(page) => {
+                page.data[sector].copy_from_slice(buff);
+                page.dirty = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // caches a page read from the device. if someone else got there first
+    // theirs is kept, as it may have been written to since:
+    fn insert(&mut self, key: (DeviceId, u64), data: Box<PageData>) {
+        if self.pages.get(&key).is_some() {
+            return;
+        }
+
+        if self.pages.len() >= MAX_PAGES && !self.evict() {
+            // everything's dirty, so it stays uncached until writeback:
+            return;
+        }
+
+        self.clock += 1;
+
+        let page = CachedPage { data, dirty: false, last_used: self.clock };
+
+        // a cache that can't grow is no great loss:
+        let _ = self.pages.insert(key, page);
+    }
+
+    fn evict(&mut self) -> bool {
+        let victim = self.pages.iter()
+            .filter(|(_, page)| !page.dirty)
+            .min_by_key(|(_, page)| page.last_used)
+            .map(|(key, _)| *key);
+
+        match victim {
+            Some(key) => {
+                self.pages.remove(&key);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // copies out a dirty page of the device for writing back, marking it clean.
+    // writes that land while it's being written dirty it again:
+    fn take_dirty(&mut self, device: DeviceId) -> Option<(u64, PageData)> {
+        let (key, page) = self.pages.iter_mut()
+            .find(|((page_device, _), page)| *page_device == device && page.dirty)?;
+
+        page.dirty = false;
+        Some((key.1, *page.data))
+    }
+
+    fn set_dirty(&mut self, key: (DeviceId, u64)) {
+        if let Some(page) = self.pages.get_mut(&key) {
+            page.dirty = true;
+        }
+    }
+}
+
+// reads a whole page from the device. returns None if there's no memory to
+// put it in:
+async fn read_page(disk: &Disk, page: u64) -> Result<Option<Box<PageData>>, BlockError> {
+    let mut data = match Box::new([[0u8; BLOCK_SIZE]; SECTORS_PER_PAGE]) {
+        Ok(data) => data,
+        Err(_) => return Ok(None),
+    };
+
+    {
+        let mut buffs = data.iter_mut().collect::<ArrayVec<[&mut Sector; SECTORS_PER_PAGE]>>();
+        disk.device().read_blocks(page * SECTORS_PER_PAGE as u64, &mut buffs).await?;
+    }
+
+    Ok(Some(data))
+}
+
+/// Reads sectors from a disk through the page cache. Pages that aren't
+/// cached yet are read in whole.
+pub async fn read_sectors(disk: &Disk, lba: usize, buffs: &mut [&mut Sector]) -> Result<(), BlockError> {
+    for (index, buff) in buffs.iter_mut().enumerate() {
+        let lba = lba + index;
+        let key = (disk.id(), (lba / SECTORS_PER_PAGE) as u64);
+        let sector = lba % SECTORS_PER_PAGE;
+
+        if CACHE.lock().read(key, sector, buff) {
+            continue;
+        }
+
+        match read_page(disk, key.1).await? {
+            Some(data) => {
+                buff.copy_from_slice(&data[sector]);
+                CACHE.lock().insert(key, data);
+            }
+            None => {
+                // out of memory, so go without the cache:
+                disk.device().read_blocks(lba as u64, &mut [&mut **buff]).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes sectors to a disk through the page cache. They only reach the disk
+/// on `writeback`, unless there's no room to cache them.
+pub async fn write_sectors(disk: &Disk, lba: usize, buffs: &[&Sector]) -> Result<(), BlockError> {
+    for (index, buff) in buffs.iter().enumerate() {
+        let lba = lba + index;
+        let key = (disk.id(), (lba / SECTORS_PER_PAGE) as u64);
+        let sector = lba % SECTORS_PER_PAGE;
+
+        if CACHE.lock().write(key, sector, buff) {
+            continue;
+        }
+
+        // the rest of the page has to come from the disk:
+        if let Some(data) = read_page(disk, key.1).await? {
+            let mut cache = CACHE.lock();
+            cache.insert(key, data);
+
+            if cache.write(key, sector, buff) {
+                continue;
+            }
+        }
+
+        // no room in the cache, write it straight through:
+        disk.device().write_blocks(lba as u64, &[*buff]).await?;
+    }
+
+    Ok(())
+}
+
+/// Writes every dirty cached page of a disk back to it.
+pub async fn writeback(disk: &Disk) -> Result<(), BlockError> {
+    loop {
+        // the lock isn't held while the page is written, so it's copied out:
+        let (page, data) = match CACHE.lock().take_dirty(disk.id()) {
+            Some(dirty) => dirty,
+            None => return Ok(()),
+        };
+
+        let buffs = data.iter().collect::<ArrayVec<[&Sector; SECTORS_PER_PAGE]>>();
+
+        if let Err(e) = disk.device().write_blocks(page * SECTORS_PER_PAGE as u64, &buffs).await {
+            CACHE.lock().set_dirty((disk.id(), page));
+            return Err(e);
+        }
+    }
+}
+
+/// Starts the writeback task, which writes dirty cached pages of every
+/// registered disk back to it every WRITEBACK_INTERVAL_MS, so that writes
+/// reach the disk before long without each one having to wait for it.
+pub fn start_writeback() -> Result<(), MemoryExhausted> {
+    task::spawn_kernel(TaskName::Static("writeback"), async {
+        loop {
+            if let Err(e) = time::sleep_ns(WRITEBACK_INTERVAL_MS * 1_000_000).await {
+                crate::println!("cache: writeback task could not sleep: {:?}", e);
+                return;
+            }
+
+            writeback_all().await;
+        }
+    })?;
+
+    Ok(())
+}
+
+/// Writes every dirty cached page of every registered disk back to it.
+/// Pages that fail to write stay dirty, and the failure is only logged.
+pub async fn writeback_all() {
+    let mut disk = block::next(b"");
+
+    while let Some(current) = disk {
+        // partitions are cached as part of their whole disk:
+        if current.parent().is_none() {
+            if let Err(e) = writeback(&current).await {
+                crate::println!("cache: could not write back {:?}: {:?}", current, e);
+            }
+        }
+
+        disk = block::next(current.name());
+    }
+}
@@ -0,0 +1,121 @@
+use core::ptr::{self, NonNull};
+
+use crate::acpi;
+use crate::mem::kvirt;
+use crate::mem::page::PAGE_SIZE;
+use crate::mem::phys::RawPhys;
+use crate::mem::MemoryExhausted;
+use crate::time::ClockSource;
+use crate::util::EarlyInit;
+
+const REG_CAPABILITIES: usize = 0x000;
+const REG_CONFIG: usize = 0x010;
+const REG_MAIN_COUNTER: usize = 0x0f0;
+
+const CAP_COUNTER_64: u64 = 1 << 13;
+// the rest of the capabilities register is the counter period:
+const CAP_PERIOD_SHIFT: u64 = 32;
+
+const CONFIG_ENABLE: u64 = 1 << 0;
+
+// the HPET table has where the registers are after the header and event
+// timer block ID:
+const TABLE_ADDRESS: usize = 40;
+
+const FS_PER_SEC: u64 = 1_000_000_000_000_000;
+// the spec promises a period no longer than 100ns:
+const MAX_PERIOD_FS: u64 = 100_000_000;
+
+#[derive(Debug)]
+pub enum HpetError {
+    NoTable,
+    BadTable,
+    BadPeriod(u64),
+    // a 32 bit counter wraps in minutes, which would need watching:
+    Counter32,
+    MemoryExhausted,
+}
+
+impl From<MemoryExhausted> for HpetError {
+    fn from(_: MemoryExhausted) -> Self {
+        HpetError::MemoryExhausted
+    }
+}
+
+/// The high precision event timer. Only its main counter is used, as a
+/// clock source.
+pub struct Hpet {
+    registers: NonNull<u8>,
+    frequency: u64,
+}
+
+unsafe impl Send for Hpet {}
+unsafe impl Sync for Hpet {}
+
+static HPET: EarlyInit<Hpet> = EarlyInit::new();
+
+/// Finds the HPET through ACPI and starts its main counter.
+// Safety: must not be called more than once
+pub unsafe fn init() -> Result<&'static Hpet, HpetError> {
+    let table = acpi::find(b"HPET").ok_or(HpetError::NoTable)?;
+
+    let base = match acpi::generic_address(table, TABLE_ADDRESS) {
+        Some(address) if address.space == acpi::ADDRESS_SPACE_MEMORY => address.address,
+        _ => return Err(HpetError::BadTable),
+    };
+
+    let offset = base as usize % PAGE_SIZE;
+    let mapped = kvirt::map_mmio(RawPhys(base - offset as u64), 1)?;
+
+    let mut hpet = Hpet {
+        registers: NonNull::new_unchecked(mapped.as_ptr().add(offset)),
+        frequency: 0,
+    };
+
+    let capabilities = hpet.read_reg(REG_CAPABILITIES);
+    let period = capabilities >> CAP_PERIOD_SHIFT;
+
+    if period == 0 || period > MAX_PERIOD_FS {
+        kvirt::free_pages(mapped, 1);
+        return Err(HpetError::BadPeriod(period));
+    }
+
+    if capabilities & CAP_COUNTER_64 == 0 {
+        kvirt::free_pages(mapped, 1);
+        return Err(HpetError::Counter32);
+    }
+
+    hpet.frequency = FS_PER_SEC / period;
+
+    // the firmware may have left it running, but it's never reset, so that
+    // only changes where the count starts from:
+    hpet.write_reg(REG_CONFIG, hpet.read_reg(REG_CONFIG) | CONFIG_ENABLE);
+
+    EarlyInit::set(&HPET, hpet);
+
+    Ok(&HPET)
+}
+
+impl Hpet {
+    fn read_reg(&self, reg: usize) -> u64 {
+        unsafe { ptr::read_volatile(self.registers.as_ptr().add(reg) as *const u64) }
+    }
+
+    fn write_reg(&self, reg: usize, value: u64) {
+        unsafe { ptr::write_volatile(self.registers.as_ptr().add(reg) as *mut u64, value) }
+    }
+}
+
+impl ClockSource for Hpet {
+    fn name(&self) -> &'static str {
+        "HPET"
+    }
+
+    fn read(&self) -> u64 {
+        self.read_reg(REG_MAIN_COUNTER)
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+}
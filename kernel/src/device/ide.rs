@@ -3,6 +3,7 @@ use core::sync::atomic::{AtomicBool, Ordering};
 use arrayvec::ArrayString;
 use x86_64::instructions::port::Port;
 
+use crate::device::block::{BlockDevice, BlockError, BlockFuture, Sector};
 use crate::sync::{Mutex, MutexGuard};
 use crate::util;
 
@@ -18,14 +19,17 @@ pub struct DriveBusy;
 
 #[derive(Debug)]
 pub struct IdeChannel {
+    #[allow(unused)]
+    number: u8,
     a: AtomicBool,
     b: AtomicBool,
     io: Mutex<IdeIo>,
 }
 
 impl IdeChannel {
-    const fn new(io: IdeIo) -> Self {
+    const fn new(number: u8, io: IdeIo) -> Self {
         IdeChannel {
+            number,
             a: AtomicBool::new(false),
             b: AtomicBool::new(false),
             io: Mutex::new(io),
@@ -48,7 +52,7 @@ impl IdeChannel {
     }
 }
 
-pub static PRIMARY: IdeChannel = IdeChannel::new(IdeIo {
+pub static PRIMARY: IdeChannel = IdeChannel::new(0, IdeIo {
     base: 0x1f0,
     control_base: 0x3f6,
 });
@@ -95,6 +99,8 @@ bitflags::bitflags! {
 #[derive(Debug, Clone, Copy)]
 pub enum AtaCommand {
     ReadPio = 0x20,
+    WritePio = 0x30,
+    CacheFlush = 0xe7,
     Identify = 0xec,
 }
 
@@ -191,11 +197,20 @@ impl IdeIo {
             buff[i * 2 + 1] = ((w >> 8) & 0xff) as u8;
         }
     }
+
+    fn write_pio_data(&self, buff: &Sector) {
+        for i in 0..256 {
+            let w = u16::from_le_bytes([buff[i * 2 + 0], buff[i * 2 + 1]]);
+            unsafe { self.data().write(w); }
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Detect {
     model: ArrayString<[u8; 40]>,
+    /// Number of sectors addressable with 28 bit LBA.
+    pub sectors: u64,
 }
 
 #[derive(Debug)]
@@ -205,7 +220,11 @@ pub enum DetectError {
     Ata(AtaError),
 }
 
-pub type Sector = [u8; 512];
+impl From<AtaError> for BlockError {
+    fn from(_: AtaError) -> BlockError {
+        BlockError::Io
+    }
+}
 
 impl IdeDrive {
     fn select(&self) -> MutexGuard<IdeIo> {
@@ -275,8 +294,16 @@ impl IdeDrive {
                 model
             };
 
+            let sectors = u32::from_le_bytes([
+                identify_data[120],
+                identify_data[121],
+                identify_data[122],
+                identify_data[123],
+            ]) as u64;
+
             Ok(Detect {
                 model,
+                sectors,
             })
         }
     }
@@ -312,4 +339,80 @@ impl IdeDrive {
 
         Ok(())
     }
+
+    pub async fn write_sectors(&self, lba: usize, buffs: &[&Sector]) -> Result<(), AtaError> {
+        if lba > 0x00fffffe {
+            panic!("cannot write lba > 0x00ffffff currently");
+        }
+
+        if buffs.len() > 255 {
+            panic!("cannot write more than 255 sectors currently");
+        }
+
+        let lba = lba.to_le_bytes();
+
+        let io = self.select();
+        io.wait_command(AtaStatus::empty())?;
+
+        unsafe {
+            io.error_features().write(0);
+            io.seccount0().write(buffs.len() as u8);
+            io.lba0().write(lba[0]);
+            io.lba1().write(lba[1]);
+            io.lba2().write(lba[2]);
+            io.wait_command(AtaStatus::DRIVE_READY)?;
+            io.command_status().write(AtaCommand::WritePio as u8);
+        }
+
+        for buff in buffs {
+            io.wait_command(AtaStatus::DATA_REQUEST_READY)?;
+            io.write_pio_data(buff);
+        }
+
+        // the data isn't safe until it's out of the drive's own cache too:
+        unsafe { io.command_status().write(AtaCommand::CacheFlush as u8); }
+        io.wait_command(AtaStatus::empty())?;
+
+        Ok(())
+    }
+}
+
+/// A detected drive, as a block device.
+#[derive(Debug)]
+pub struct IdeDisk {
+    drive: IdeDrive,
+    sectors: u64,
+}
+
+impl IdeDisk {
+    pub fn new(drive: IdeDrive, detect: &Detect) -> Self {
+        IdeDisk { drive, sectors: detect.sectors }
+    }
+}
+
+impl BlockDevice for IdeDisk {
+    fn block_count(&self) -> u64 {
+        self.sectors
+    }
+
+    // the drive takes at most 255 sectors a command:
+    fn read_blocks<'a, 'b: 'a>(&'a self, lba: u64, buffs: &'a mut [&'b mut Sector]) -> BlockFuture<'a, ()> {
+        BlockFuture::new(async move {
+            for (index, chunk) in buffs.chunks_mut(255).enumerate() {
+                self.drive.read_sectors(lba as usize + index * 255, chunk).await?;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn write_blocks<'a>(&'a self, lba: u64, buffs: &'a [&'a Sector]) -> BlockFuture<'a, ()> {
+        BlockFuture::new(async move {
+            for (index, chunk) in buffs.chunks(255).enumerate() {
+                self.drive.write_sectors(lba as usize + index * 255, chunk).await?;
+            }
+
+            Ok(())
+        })
+    }
 }
@@ -0,0 +1,185 @@
+use core::fmt::{self, Debug, Write};
+use core::mem;
+use core::ptr;
+use core::task::{Poll, Waker};
+
+use arraydeque::{ArrayDeque, Saturating};
+use arrayvec::ArrayString;
+use futures::future;
+use interface::{InputEvent, SysError, SysResult, EV_SYN, SYN_REPORT};
+
+use crate::config::DEVFS_NAME_MAX;
+use crate::fs::devfs::{self, Device, DeviceKind};
+use crate::fs::vfs::FsFuture;
+use crate::mem::MemoryExhausted;
+use crate::object::poll::Events;
+use crate::sync::{Arc, Mutex};
+use crate::time;
+use crate::util::AtomicList;
+use crate::work;
+
+/// The most input devices that get registered, as event0, event1 and so on.
+pub const MAX_DEVICES: usize = 8;
+
+// events that haven't been read yet. once it's full, whole groups are
+// dropped:
+const EVENT_BUFFER: usize = 64;
+
+const EVENT_SIZE: usize = mem::size_of::<InputEvent>();
+
+/// An event without its time, as drivers report it.
+#[derive(Debug, Clone, Copy)]
+pub struct Event {
+    pub kind: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+/// A source of input events, like a mouse, with a node in /dev its events
+/// are read from. Whatever the hardware, events come out the same way.
+pub struct InputDevice {
+    name: &'static str,
+    index: usize,
+    events: Mutex<ArrayDeque<[InputEvent; EVENT_BUFFER], Saturating>>,
+    wakers: AtomicList<Waker>,
+}
+
+// every registered device, for deferred wake ups to find by index:
+static DEVICES: Mutex<[Option<Arc<InputDevice>>; MAX_DEVICES]> = Mutex::new([None, None, None, None, None, None, None, None]);
+
+/// Registers an input device, giving it the next free node, event0 onwards.
+/// `name` is what kind of device it is, for debugging.
+pub fn register(name: &'static str) -> SysResult<Arc<InputDevice>> {
+    let index = DEVICES.lock().iter().position(Option::is_none)
+        .ok_or(SysError::NoSpace)?;
+
+    let device = Arc::new(InputDevice {
+        name,
+        index,
+        events: Mutex::new(ArrayDeque::new()),
+        wakers: AtomicList::new(),
+    })?;
+
+    {
+        let mut devices = DEVICES.lock();
+
+        if devices[index].is_some() {
+            return Err(SysError::Busy);
+        }
+
+        devices[index] = Some(device.clone());
+    }
+
+    let mut node = ArrayString::<[u8; DEVFS_NAME_MAX]>::new();
+    write!(node, "event{}", index).expect("input::register: name too long");
+
+    if let Err(e) = devfs::register(node.as_bytes(), device.clone()) {
+        DEVICES.lock()[index] = None;
+        return Err(e);
+    }
+
+    Ok(device)
+}
+
+impl InputDevice {
+    /// Queues a group of events that happened together, followed by the
+    /// SYN_REPORT that ends it. Safe to call from interrupt handlers. If
+    /// there isn't room for all of them, none are queued, so that readers
+    /// never see half a group.
+    pub fn report(&self, group: &[Event]) {
+        let time_ns = time::monotonic_ns();
+
+        {
+            let mut events = self.events.lock();
+
+            if events.capacity() - events.len() < group.len() + 1 {
+                return;
+            }
+
+            let report = Event { kind: EV_SYN, code: SYN_REPORT, value: 0 };
+
+            for event in group.iter().chain(Some(&report)) {
+                let _ = events.push_back(InputEvent {
+                    time_ns,
+                    kind: event.kind,
+                    code: event.code,
+                    value: event.value,
+                });
+            }
+        }
+
+        // waking readers takes scheduler locks, leave that until after the
+        // interrupt, unless there's no room to:
+        if work::defer(wake_readers, self.index as u64).is_err() {
+            wake_readers(self.index as u64);
+        }
+    }
+}
+
+fn wake_readers(index: u64) {
+    let device = match DEVICES.lock().get(index as usize).and_then(|device| device.clone()) {
+        Some(device) => device,
+        None => return,
+    };
+
+    for waker in device.wakers.take_iter() {
+        waker.wake();
+    }
+}
+
+impl Device for InputDevice {
+    fn kind(&self) -> DeviceKind {
+        DeviceKind::Character
+    }
+
+    fn read<'a>(&'a self, _pos: u64, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        FsFuture::new(async move {
+            if buf.len() < EVENT_SIZE {
+                return Err(SysError::IllegalValue);
+            }
+
+            let count = future::poll_fn(|ctx| {
+                // register waker before checking the queue so that we can't
+                // miss an event arriving in between:
+                if let Err(MemoryExhausted) = self.wakers.push_front(ctx.waker().clone()) {
+                    return Poll::Ready(Err(SysError::MemoryExhausted));
+                }
+
+                let mut events = self.events.lock();
+
+                if events.is_empty() {
+                    return Poll::Pending;
+                }
+
+                let mut count = 0;
+
+                for record in buf.chunks_exact_mut(EVENT_SIZE) {
+                    match events.pop_front() {
+                        Some(event) => unsafe {
+                            ptr::write_unaligned(record.as_mut_ptr() as *mut InputEvent, event);
+                        },
+                        None => break,
+                    }
+
+                    count += 1;
+                }
+
+                Poll::Ready(Ok(count))
+            }).await?;
+
+            Ok(count * EVENT_SIZE)
+        })
+    }
+
+    fn poll_ready(&self, waker: &Waker) -> Result<Events, MemoryExhausted> {
+        self.wakers.push_front(waker.clone())?;
+
+        Ok(if self.events.lock().is_empty() { Events::empty() } else { Events::IN })
+    }
+}
+
+impl Debug for InputDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "InputDevice(event{}, {})", self.index, self.name)
+    }
+}
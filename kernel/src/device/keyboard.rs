@@ -1,48 +1,396 @@
-use core::task::Poll;
+use core::cell::UnsafeCell;
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Poll, Waker};
 
 use arraydeque::{ArrayDeque, Saturating};
 use futures::future;
+use interface::{KeyEvent, SysError, KEY_MOD_ALT, KEY_MOD_CAPS_LOCK, KEY_MOD_CTRL, KEY_MOD_SHIFT};
+use interface::{KEYSYM_ALT, KEYSYM_CAPS_LOCK, KEYSYM_CTRL, KEYSYM_F1, KEYSYM_NUM_LOCK, KEYSYM_SCROLL_LOCK, KEYSYM_SHIFT};
+use interface::{KEYSYM_DELETE, KEYSYM_DOWN, KEYSYM_END, KEYSYM_HOME, KEYSYM_INSERT, KEYSYM_LEFT, KEYSYM_PAGE_DOWN};
+use interface::{KEYSYM_PAGE_UP, KEYSYM_RIGHT, KEYSYM_UP};
 use x86_64::instructions::port::Port;
 
-use crate::sync::Mutex;
+use crate::console::tty;
+use crate::critical;
+use crate::device::ps2;
+use crate::fs::devfs::{self, Device, DeviceKind};
+use crate::fs::vfs::FsFuture;
+use crate::interrupt;
+use crate::mem::MemoryExhausted;
+use crate::object::poll::Events;
+use crate::sync::{Arc, Mutex};
+use crate::util::AtomicList;
+use crate::work;
 
-pub type Scancode = u8;
+const IRQ: u8 = 1;
 
-static BUFF: Mutex<Option<ArrayDeque<[u8; 32], Saturating>>> = Mutex::new(None);
+const DATA: u16 = 0x60;
+
+const PREFIX_EXTENDED: u8 = 0xe0;
+const PREFIX_PAUSE: u8 = 0xe1;
+// set 2 only, set 1 has the top bit of the code instead:
+const PREFIX_RELEASE: u8 = 0xf0;
+
+// scancodes from the controller, before they're decoded. must be a power
+// of two:
+const RING_SIZE: usize = 64;
+
+// events for /dev/kbd that haven't been read yet. any more are dropped:
+const EVENT_BUFFER: usize = 32;
+
+const EVENT_SIZE: usize = mem::size_of::<KeyEvent>();
+
+// set 1 codes of the keys that type something, up to the space bar, without
+// and with shift:
+const NORMAL: &[u8; 0x3a] = b"\0\x1b1234567890-=\x08\tqwertyuiop[]\n\0asdfghjkl;'`\0\\zxcvbnm,./\0*\0 ";
+const SHIFTED: &[u8; 0x3a] = b"\0\x1b!@#$%^&*()_+\x08\tQWERTYUIOP{}\n\0ASDFGHJKL:\"~\0|ZXCVBNM<>?\0*\0 ";
+
+// set 1 codes 0x47 to 0x53, the keypad, which always types digits:
+const KEYPAD: &[u8; 13] = b"789-456+1230.";
+
+// set 2 codes to set 1 codes, as the controller translates them:
+const SET2_TO_SET1: [u8; 0x84] = [
+    0xff, 0x43, 0x41, 0x3f, 0x3d, 0x3b, 0x3c, 0x58, 0x64, 0x44, 0x42, 0x40, 0x3e, 0x0f, 0x29, 0x59,
+    0x65, 0x38, 0x2a, 0x70, 0x1d, 0x10, 0x02, 0x5a, 0x66, 0x71, 0x2c, 0x1f, 0x1e, 0x11, 0x03, 0x5b,
+    0x67, 0x2e, 0x2d, 0x20, 0x12, 0x05, 0x04, 0x5c, 0x68, 0x39, 0x2f, 0x21, 0x14, 0x13, 0x06, 0x5d,
+    0x69, 0x31, 0x30, 0x23, 0x22, 0x15, 0x07, 0x5e, 0x6a, 0x72, 0x32, 0x24, 0x16, 0x08, 0x09, 0x5f,
+    0x6b, 0x33, 0x25, 0x17, 0x18, 0x0b, 0x0a, 0x60, 0x6c, 0x34, 0x35, 0x26, 0x27, 0x19, 0x0c, 0x61,
+    0x6d, 0x73, 0x28, 0x74, 0x1a, 0x0d, 0x62, 0x6e, 0x3a, 0x36, 0x1c, 0x1b, 0x75, 0x2b, 0x63, 0x76,
+    0x55, 0x56, 0x77, 0x78, 0x79, 0x7a, 0x0e, 0x7b, 0x7c, 0x4f, 0x7d, 0x4b, 0x47, 0x7e, 0x7f, 0x6f,
+    0x52, 0x53, 0x50, 0x4c, 0x4d, 0x48, 0x01, 0x45, 0x57, 0x4e, 0x51, 0x4a, 0x37, 0x49, 0x46, 0x54,
+    0x80, 0x81, 0x82, 0x41,
+];
+
+// a ring of scancodes with one producer, the interrupt handler, and one
+// consumer, decode(), so neither needs a lock:
+struct ScancodeRing {
+    bytes: UnsafeCell<[u8; RING_SIZE]>,
+    // where the next scancode is read from and written to, counting up
+    // forever:
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+unsafe impl Sync for ScancodeRing {}
+
+impl ScancodeRing {
+    const fn new() -> Self {
+        ScancodeRing {
+            bytes: UnsafeCell::new([0; RING_SIZE]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    // only called by the producer. false if the ring is full:
+    fn push(&self, byte: u8) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+
+        if tail.wrapping_sub(self.head.load(Ordering::Acquire)) == RING_SIZE {
+            return false;
+        }
+
+        unsafe { ptr::write_volatile((self.bytes.get() as *mut u8).add(tail % RING_SIZE), byte); }
+
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    // only called by the consumer:
+    fn pop(&self) -> Option<u8> {
+        let head = self.head.load(Ordering::Relaxed);
+
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+
+        let byte = unsafe { ptr::read_volatile((self.bytes.get() as *const u8).add(head % RING_SIZE)) };
+
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(byte)
+    }
+}
+
+static RING: ScancodeRing = ScancodeRing::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ScancodeSet {
+    One,
+    Two,
+}
+
+// turns scancodes into key events, keeping track of the modifiers:
+struct Decoder {
+    set: ScancodeSet,
+    extended: bool,
+    released: bool,
+    // bytes of a pause sequence left to skip, pause being the one key
+    // without a release code:
+    skip: u8,
+    modifiers: u16,
+}
+
+impl Decoder {
+    fn feed(&mut self, byte: u8) -> Option<KeyEvent> {
+        if self.skip > 0 {
+            self.skip -= 1;
+            return None;
+        }
+
+        match byte {
+            PREFIX_EXTENDED => {
+                self.extended = true;
+                return None;
+            }
+            PREFIX_PAUSE => {
+                self.skip = 2;
+                return None;
+            }
+            PREFIX_RELEASE if self.set == ScancodeSet::Two => {
+                self.released = true;
+                return None;
+            }
+            _ => {}
+        }
+
+        let extended = mem::replace(&mut self.extended, false);
+
+        let (code, pressed) = match self.set {
+            ScancodeSet::One => (byte & 0x7f, byte & 0x80 == 0),
+            ScancodeSet::Two => {
+                let pressed = !mem::replace(&mut self.released, false);
+                (*SET2_TO_SET1.get(byte as usize)?, pressed)
+            }
+        };
+
+        // the keyboard pretends shift is pressed or released around some
+        // extended keys, which isn't a key of its own:
+        if extended && (code == 0x2a || code == 0x36) {
+            return None;
+        }
+
+        let keysym = keysym(code, extended, self.modifiers)?;
+
+        let modifier = match keysym {
+            KEYSYM_SHIFT => KEY_MOD_SHIFT,
+            KEYSYM_CTRL => KEY_MOD_CTRL,
+            KEYSYM_ALT => KEY_MOD_ALT,
+            _ => 0,
+        };
+
+        if pressed {
+            self.modifiers |= modifier;
+        } else {
+            self.modifiers &= !modifier;
+        }
+
+        if keysym == KEYSYM_CAPS_LOCK && pressed {
+            self.modifiers ^= KEY_MOD_CAPS_LOCK;
+        }
+
+        Some(KeyEvent { keysym, modifiers: self.modifiers, pressed: pressed as u16 })
+    }
+}
+
+// what a set 1 code types with `modifiers` held, US layout:
+fn keysym(code: u8, extended: bool, modifiers: u16) -> Option<u32> {
+    if extended {
+        let keysym = match code {
+            0x1c => '\n' as u32,
+            0x1d => KEYSYM_CTRL,
+            0x35 => '/' as u32,
+            0x38 => KEYSYM_ALT,
+            0x47 => KEYSYM_HOME,
+            0x48 => KEYSYM_UP,
+            0x49 => KEYSYM_PAGE_UP,
+            0x4b => KEYSYM_LEFT,
+            0x4d => KEYSYM_RIGHT,
+            0x4f => KEYSYM_END,
+            0x50 => KEYSYM_DOWN,
+            0x51 => KEYSYM_PAGE_DOWN,
+            0x52 => KEYSYM_INSERT,
+            0x53 => KEYSYM_DELETE,
+            _ => return None,
+        };
+
+        return Some(keysym);
+    }
+
+    let keysym = match code {
+        0x1d => KEYSYM_CTRL,
+        0x2a | 0x36 => KEYSYM_SHIFT,
+        0x38 => KEYSYM_ALT,
+        0x3a => KEYSYM_CAPS_LOCK,
+        0x3b..=0x44 => KEYSYM_F1 + (code - 0x3b) as u32,
+        0x45 => KEYSYM_NUM_LOCK,
+        0x46 => KEYSYM_SCROLL_LOCK,
+        0x47..=0x53 => KEYPAD[(code - 0x47) as usize] as u32,
+        0x57 | 0x58 => KEYSYM_F1 + 10 + (code - 0x57) as u32,
+        _ => {
+            let normal = *NORMAL.get(code as usize)?;
+
+            if normal == 0 {
+                return None;
+            }
+
+            // caps lock only shifts letters:
+            let mut shift = modifiers & KEY_MOD_SHIFT != 0;
+
+            if normal.is_ascii_alphabetic() && modifiers & KEY_MOD_CAPS_LOCK != 0 {
+                shift = !shift;
+            }
+
+            (if shift { SHIFTED[code as usize] } else { normal }) as u32
+        }
+    };
+
+    Some(keysym)
+}
+
+static DECODER: Mutex<Decoder> = Mutex::new(Decoder {
+    set: ScancodeSet::One,
+    extended: false,
+    released: false,
+    skip: 0,
+    modifiers: 0,
+});
+
+static EVENTS: Mutex<Option<ArrayDeque<[KeyEvent; EVENT_BUFFER], Saturating>>> = Mutex::new(None);
+
+static EVENT_WAKERS: AtomicList<Waker> = AtomicList::new();
 
 // Safety: must not be called more than once
 pub unsafe fn init() {
-    *BUFF.lock() = Some(ArrayDeque::new());
+    *EVENTS.lock() = Some(ArrayDeque::new());
+
+    // without translation, the keyboard speaks set 2, which is its default:
+    if let Ok(config) = ps2::read_config() {
+        if config & ps2::CONFIG_TRANSLATE == 0 {
+            DECODER.lock().set = ScancodeSet::Two;
+        }
+    }
+
+    devfs::register(b"kbd", Arc::new(Kbd).expect("keyboard::init: Arc::new"))
+        .expect("keyboard::init: register kbd");
+
+    interrupt::register_irq(IRQ, self::interrupt, 0)
+        .expect("keyboard::init: register_irq");
 }
 
-pub async fn read_scancode() -> Scancode {
-    // TODO - send task to sleep while waiting for interrupt
-    future::poll_fn(|_ctx| {
-        let mut buff = BUFF.lock();
+/// The raw key events of the keyboard, modifier keys and all, which don't
+/// go through the console.
+#[derive(Debug)]
+struct Kbd;
+
+impl Device for Kbd {
+    fn kind(&self) -> DeviceKind {
+        DeviceKind::Character
+    }
+
+    fn read<'a>(&'a self, _pos: u64, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        FsFuture::new(async move {
+            if buf.len() < EVENT_SIZE {
+                return Err(SysError::IllegalValue);
+            }
+
+            let count = future::poll_fn(|ctx| {
+                if let Err(MemoryExhausted) = EVENT_WAKERS.push_front(ctx.waker().clone()) {
+                    return Poll::Ready(Err(SysError::MemoryExhausted));
+                }
 
-        let buff = buff.as_mut()
+                let mut events = EVENTS.lock();
+
+                let events = events.as_mut()
+                    .expect("keyboard to be initialized");
+
+                if events.is_empty() {
+                    return Poll::Pending;
+                }
+
+                let mut count = 0;
+
+                for record in buf.chunks_exact_mut(EVENT_SIZE) {
+                    match events.pop_front() {
+                        Some(event) => unsafe {
+                            ptr::write_unaligned(record.as_mut_ptr() as *mut KeyEvent, event);
+                        },
+                        None => break,
+                    }
+
+                    count += 1;
+                }
+
+                Poll::Ready(Ok(count))
+            }).await?;
+
+            Ok(count * EVENT_SIZE)
+        })
+    }
+
+    fn poll_ready(&self, waker: &Waker) -> Result<Events, MemoryExhausted> {
+        EVENT_WAKERS.push_front(waker.clone())?;
+
+        let events = EVENTS.lock();
+
+        let events = events.as_ref()
             .expect("keyboard to be initialized");
 
-        match buff.pop_front() {
-            None => Poll::Pending,
-            Some(s) => Poll::Ready(s),
-        }
-    }).await
+        Ok(if events.is_empty() { Events::empty() } else { Events::IN })
+    }
 }
 
-pub unsafe fn interrupt() {
-    let mut keyboard = Port::<u8>::new(0x60);
-    let raw_scancode = keyboard.read();
+fn interrupt(_: u64) {
+    // another keyboard interrupt mustn't get in between reading the
+    // scancode and pushing it, or the ring would have two producers:
+    let pushed = critical::section(|| {
+        let raw_scancode = unsafe { Port::<u8>::new(DATA).read() };
+        RING.push(raw_scancode)
+    });
 
-    // TODO - can we do this locklessly?
-    let mut buff = BUFF.lock();
+    if !pushed {
+        crate::println!("keyboard buffer overflow!");
+    }
+
+    // decoding takes locks readers hold and waking them takes scheduler
+    // locks, leave that until after the interrupt. if the deferred work
+    // queue is full, do it now rather than lose the key:
+    if work::defer(decode, 0).is_err() {
+        decode(0);
+    }
+}
+
+// decodes whatever the interrupt handler has pushed, handing each key to the
+// console and /dev/kbd:
+fn decode(_: u64) {
+    let mut evented = false;
+
+    {
+        // held throughout, which also keeps this the ring's only consumer:
+        let mut decoder = DECODER.lock();
+
+        while let Some(byte) = RING.pop() {
+            let event = match decoder.feed(byte) {
+                Some(event) => event,
+                None => continue,
+            };
 
-    if let Some(ref mut buff) = &mut *buff {
-        match buff.push_back(raw_scancode) {
-            Ok(()) => {}
-            Err(_) => {
-                crate::println!("keyboard buffer overflow!");
+            if let Some(events) = EVENTS.lock().as_mut() {
+                evented |= events.push_back(event).is_ok();
             }
+
+            if event.pressed == 0 || !tty::filter_key(&event) {
+                continue;
+            }
+
+            tty::input(&tty::key_input(&event));
+        }
+    }
+
+    if evented {
+        for waker in EVENT_WAKERS.take_iter() {
+            waker.wake();
         }
     }
 }
@@ -1,4 +1,16 @@
+pub mod ahci;
+pub mod block;
+pub mod cache;
+pub mod hpet;
 pub mod ide;
+pub mod input;
 pub mod keyboard;
-pub mod mbr;
+pub mod mouse;
+pub mod nvme;
+pub mod partition;
+pub mod pci;
 pub mod pit;
+pub mod ps2;
+pub mod rtc;
+pub mod serial;
+pub mod virtio;
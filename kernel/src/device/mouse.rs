@@ -0,0 +1,158 @@
+use arrayvec::ArrayVec;
+use interface::{SysError, BTN_LEFT, BTN_MIDDLE, BTN_RIGHT, EV_KEY, EV_REL, REL_WHEEL, REL_X, REL_Y};
+use x86_64::instructions::port::Port;
+
+use crate::device::input::{self, Event, InputDevice};
+use crate::device::ps2::{self, Ps2Error};
+use crate::interrupt;
+use crate::sync::{Arc, Mutex};
+
+const IRQ: u8 = 12;
+
+const DATA: u16 = 0x60;
+
+const SET_DEFAULTS: u8 = 0xf6;
+const SET_SAMPLE_RATE: u8 = 0xf3;
+const GET_ID: u8 = 0xf2;
+const ENABLE_REPORTING: u8 = 0xf4;
+
+// a mouse with a scroll wheel says so with this id once it's been sent the
+// sample rates 200, 100 and 80 in a row:
+const ID_WHEEL: u8 = 3;
+const WHEEL_KNOCK: [u8; 3] = [200, 100, 80];
+
+// the first byte of a packet: the buttons, which way it moved and whether
+// the movement overflowed. it always has ALWAYS_ONE set, which is how a lost
+// byte is noticed:
+const FLAG_LEFT: u8 = 1 << 0;
+const FLAG_RIGHT: u8 = 1 << 1;
+const FLAG_MIDDLE: u8 = 1 << 2;
+const FLAG_ALWAYS_ONE: u8 = 1 << 3;
+const FLAG_X_NEGATIVE: u8 = 1 << 4;
+const FLAG_Y_NEGATIVE: u8 = 1 << 5;
+const FLAG_OVERFLOW: u8 = 0xc0;
+
+const BUTTONS: [(u8, u16); 3] = [(FLAG_LEFT, BTN_LEFT), (FLAG_RIGHT, BTN_RIGHT), (FLAG_MIDDLE, BTN_MIDDLE)];
+
+#[derive(Debug)]
+pub enum MouseError {
+    Ps2(Ps2Error),
+    Register(SysError),
+}
+
+impl From<Ps2Error> for MouseError {
+    fn from(e: Ps2Error) -> MouseError {
+        MouseError::Ps2(e)
+    }
+}
+
+impl From<SysError> for MouseError {
+    fn from(e: SysError) -> MouseError {
+        MouseError::Register(e)
+    }
+}
+
+// the packet being put together, byte by byte, as the interrupts come in:
+struct Mouse {
+    input: Arc<InputDevice>,
+    packet: [u8; 4],
+    received: usize,
+    // 4 with a scroll wheel, 3 otherwise:
+    packet_len: usize,
+    buttons: u8,
+}
+
+static MOUSE: Mutex<Option<Mouse>> = Mutex::new(None);
+
+/// Sets up the mouse on the PS/2 controller's auxiliary port, if there is
+/// one, registering it as an input device. Must be called before interrupts
+/// are enabled, since the keyboard shares the controller.
+pub unsafe fn init() -> Result<(), MouseError> {
+    ps2::enable_aux()?;
+
+    let config = ps2::read_config()?;
+    ps2::write_config((config | ps2::CONFIG_IRQ_AUX) & !ps2::CONFIG_AUX_CLOCK_DISABLED)?;
+
+    ps2::write_aux(SET_DEFAULTS)?;
+
+    for rate in WHEEL_KNOCK.iter() {
+        ps2::write_aux(SET_SAMPLE_RATE)?;
+        ps2::write_aux(*rate)?;
+    }
+
+    ps2::write_aux(GET_ID)?;
+    let packet_len = if ps2::read_data()? == ID_WHEEL { 4 } else { 3 };
+
+    let input = input::register("ps2-mouse")?;
+
+    *MOUSE.lock() = Some(Mouse { input, packet: [0; 4], received: 0, packet_len, buttons: 0 });
+
+    interrupt::register_irq(IRQ, interrupt, 0)
+        .map_err(|_| MouseError::Register(SysError::Busy))?;
+
+    ps2::write_aux(ENABLE_REPORTING)?;
+
+    Ok(())
+}
+
+fn interrupt(_: u64) {
+    // held throughout, which also keeps another mouse interrupt from
+    // getting in between reading the byte and adding it to the packet:
+    let mut mouse = MOUSE.lock();
+
+    let byte = unsafe { Port::<u8>::new(DATA).read() };
+
+    let mouse = match mouse.as_mut() {
+        Some(mouse) => mouse,
+        None => return,
+    };
+
+    if mouse.received == 0 && byte & FLAG_ALWAYS_ONE == 0 {
+        // out of step, wait for the start of the next packet:
+        return;
+    }
+
+    mouse.packet[mouse.received] = byte;
+    mouse.received += 1;
+
+    if mouse.received == mouse.packet_len {
+        mouse.received = 0;
+        mouse.report();
+    }
+}
+
+impl Mouse {
+    fn report(&mut self) {
+        let flags = self.packet[0];
+        let mut events = ArrayVec::<[Event; 8]>::new();
+
+        // movement too fast to fit in a packet is nonsense, so it's dropped:
+        if flags & FLAG_OVERFLOW == 0 {
+            let dx = self.packet[1] as i32 - if flags & FLAG_X_NEGATIVE != 0 { 0x100 } else { 0 };
+            let dy = self.packet[2] as i32 - if flags & FLAG_Y_NEGATIVE != 0 { 0x100 } else { 0 };
+
+            // the wheel moves by a 4 bit signed amount, towards the user
+            // being positive:
+            let wheel = if self.packet_len == 4 { ((self.packet[3] << 4) as i8 >> 4) as i32 } else { 0 };
+
+            // the mouse has positive y up, and events down:
+            let axes = [(REL_X, dx), (REL_Y, -dy), (REL_WHEEL, -wheel)];
+
+            for &(code, value) in axes.iter().filter(|(_, value)| *value != 0) {
+                events.push(Event { kind: EV_REL, code, value });
+            }
+        }
+
+        for &(flag, code) in BUTTONS.iter() {
+            if (flags ^ self.buttons) & flag != 0 {
+                events.push(Event { kind: EV_KEY, code, value: (flags & flag != 0) as i32 });
+            }
+        }
+
+        self.buttons = flags & (FLAG_LEFT | FLAG_RIGHT | FLAG_MIDDLE);
+
+        if !events.is_empty() {
+            self.input.report(&events);
+        }
+    }
+}
@@ -0,0 +1,899 @@
+use core::cmp;
+use core::convert::TryInto;
+use core::fmt::{self, Debug, Write};
+use core::mem;
+use core::ptr::{self, NonNull};
+use core::task::{Poll, Waker};
+
+use arrayvec::{ArrayString, ArrayVec};
+use futures::future;
+use interface::{SysError, SysResult};
+
+use crate::config::BLOCK_NAME_MAX;
+use crate::device::block::{self, BlockDevice, BlockError, BlockFuture, RequestQueue, Sector, BLOCK_SIZE};
+use crate::device::pci::{Bar, Command, Msix, PciAddress, PciDevice, PciDriver, PciFuture};
+use crate::interrupt::{self, IrqMode, MsiVector};
+use crate::mem::dma::{self, Constraints, DmaBuffer};
+use crate::mem::kvirt;
+use crate::mem::page::PAGE_SIZE;
+use crate::mem::phys::RawPhys;
+use crate::mem::MemoryExhausted;
+use crate::smp;
+use crate::sync::{Arc, Mutex};
+use crate::time;
+use crate::util::AtomicList;
+use crate::work;
+
+/// The class, subclass and programming interface of an NVMe controller.
+pub const PCI_CLASS: (u8, u8, u8) = (0x01, 0x08, 0x02);
+
+/// The most NVMe controllers that get attached to.
+pub const MAX_CONTROLLERS: usize = 2;
+
+/// The most I/O queue pairs set up on a controller. Each CPU submits to one
+/// of them, so there's less contention the closer this is to the number of
+/// CPUs.
+pub const MAX_IO_QUEUES: usize = 4;
+
+/// The most namespaces of a controller that get registered, as nvme0n1,
+/// nvme0n2 and so on.
+pub const MAX_NAMESPACES: usize = 4;
+
+// controller registers:
+const REG_CAP: usize = 0x00;
+const REG_INTMS: usize = 0x0c;
+const REG_INTMC: usize = 0x10;
+const REG_CC: usize = 0x14;
+const REG_CSTS: usize = 0x1c;
+const REG_AQA: usize = 0x24;
+const REG_ASQ: usize = 0x28;
+const REG_ACQ: usize = 0x30;
+const REG_DOORBELLS: usize = 0x1000;
+
+const CC_ENABLE: u32 = 1 << 0;
+// 64 byte submission and 16 byte completion entries:
+const CC_IOSQES: u32 = 6 << 16;
+const CC_IOCQES: u32 = 4 << 20;
+
+const CSTS_READY: u32 = 1 << 0;
+const CSTS_FATAL: u32 = 1 << 1;
+
+const SUBMISSION_SIZE: usize = 64;
+const COMPLETION_SIZE: usize = 16;
+
+// the most entries a queue has, if the controller allows that many:
+const QUEUE_ENTRIES: u16 = 64;
+
+const ADMIN_CREATE_SQ: u8 = 0x01;
+const ADMIN_CREATE_CQ: u8 = 0x05;
+const ADMIN_IDENTIFY: u8 = 0x06;
+const ADMIN_SET_FEATURES: u8 = 0x09;
+
+const IO_FLUSH: u8 = 0x00;
+const IO_WRITE: u8 = 0x01;
+const IO_READ: u8 = 0x02;
+
+const IDENTIFY_NAMESPACE: u32 = 0x00;
+const IDENTIFY_CONTROLLER: u32 = 0x01;
+
+const FEATURE_QUEUES: u32 = 0x07;
+
+const QUEUE_CONTIGUOUS: u32 = 1 << 0;
+const QUEUE_INTERRUPTS: u32 = 1 << 1;
+
+// the most sectors a command carries, longer transfers are split up. the
+// controller may allow fewer:
+const COMMAND_SECTORS: usize = 128;
+
+// how often readiness is polled while the controller comes up, and how
+// long, in milliseconds, each unit of the timeout it gives is:
+const POLL_NS: u64 = 1_000_000;
+const TIMEOUT_UNIT_MS: u64 = 500;
+
+#[derive(Debug)]
+pub enum NvmeError {
+    /// BAR 0 isn't memory mapped registers.
+    NoRegisters,
+    /// The controller didn't become ready in the time it gave itself.
+    Timeout,
+    /// The controller failed, or failed a command setting it up.
+    Failed,
+    MemoryExhausted,
+}
+
+impl From<MemoryExhausted> for NvmeError {
+    fn from(_: MemoryExhausted) -> NvmeError {
+        NvmeError::MemoryExhausted
+    }
+}
+
+impl From<NvmeError> for SysError {
+    fn from(e: NvmeError) -> SysError {
+        match e {
+            NvmeError::NoRegisters => SysError::InvalidOperation,
+            NvmeError::Timeout | NvmeError::Failed => SysError::IoError,
+            NvmeError::MemoryExhausted => SysError::MemoryExhausted,
+        }
+    }
+}
+
+impl From<BlockError> for NvmeError {
+    fn from(e: BlockError) -> NvmeError {
+        match e {
+            BlockError::MemoryExhausted => NvmeError::MemoryExhausted,
+            _ => NvmeError::Failed,
+        }
+    }
+}
+
+// the controller's memory mapped registers:
+#[derive(Clone, Copy)]
+struct Registers(NonNull<u8>);
+
+impl Registers {
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { ptr::read_volatile(self.0.as_ptr().add(offset) as *const u32) }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { ptr::write_volatile(self.0.as_ptr().add(offset) as *mut u32, value) }
+    }
+
+    fn read_u64(&self, offset: usize) -> u64 {
+        self.read(offset) as u64 | (self.read(offset + 4) as u64) << 32
+    }
+
+    fn write_u64(&self, offset: usize, value: u64) {
+        self.write(offset, value as u32);
+        self.write(offset + 4, (value >> 32) as u32);
+    }
+}
+
+/// A command to submit, less the command id, which the queue fills in.
+#[derive(Debug, Default, Clone, Copy)]
+struct Submission {
+    opcode: u8,
+    namespace: u32,
+    prp1: u64,
+    prp2: u64,
+    dwords: [u32; 6],
+}
+
+impl Submission {
+    fn write(&self, entry: &mut [u8], id: u16) {
+        for byte in entry.iter_mut() {
+            *byte = 0;
+        }
+
+        entry[0] = self.opcode;
+        entry[2..4].copy_from_slice(&id.to_le_bytes());
+        entry[4..8].copy_from_slice(&self.namespace.to_le_bytes());
+        entry[24..32].copy_from_slice(&self.prp1.to_le_bytes());
+        entry[32..40].copy_from_slice(&self.prp2.to_le_bytes());
+
+        for (index, dword) in self.dwords.iter().enumerate() {
+            let offset = 40 + index * 4;
+            entry[offset..(offset + 4)].copy_from_slice(&dword.to_le_bytes());
+        }
+    }
+}
+
+// the most commands in flight on a queue at once. there have to be fewer
+// than it has entries, or a full queue would look empty:
+const QUEUE_SLOTS: usize = 32;
+
+/// A submission queue and the completion queue it completes to. Commands
+/// are tracked by slot, which is the command id, so any number of tasks can
+/// have commands in flight on a queue pair at once.
+struct QueuePair {
+    id: u16,
+    entries: u16,
+    // the interrupt vector its completions raise, which is an entry in the
+    // MSI-X table, or 0 for every queue pair with the legacy interrupt:
+    vector: u16,
+    registers: Registers,
+    stride: usize,
+    phys: u64,
+    state: Mutex<QueueState>,
+    // tasks waiting for a slot to be freed up:
+    space_waiters: AtomicList<Waker>,
+}
+
+struct QueueState {
+    // the submission queue, then the completion queue a page on:
+    memory: DmaBuffer,
+    submission_tail: u16,
+    completion_head: u16,
+    // the phase the controller marks new completions with, which flips
+    // each time round the completion queue:
+    phase: bool,
+    slots: ArrayVec<[Slot; QUEUE_SLOTS]>,
+}
+
+enum Slot {
+    Free,
+    Waiting { buffer: Option<DmaBuffer>, waker: Option<Waker> },
+    Done { buffer: Option<DmaBuffer>, status: u16, result: u32 },
+    // the command's future was dropped before it completed, so the buffer
+    // is freed once the controller is done with it instead:
+    Abandoned(Option<DmaBuffer>),
+}
+
+impl QueuePair {
+    fn new(id: u16, entries: u16, vector: u16, registers: Registers, stride: usize)
+        -> Result<QueuePair, MemoryExhausted>
+    {
+        let memory = dma::alloc(2 * PAGE_SIZE, Constraints::ANY)?;
+
+        let mut slots = ArrayVec::new();
+
+        while slots.len() < cmp::min(QUEUE_SLOTS, entries as usize - 1) {
+            slots.push(Slot::Free);
+        }
+
+        Ok(QueuePair {
+            id,
+            entries,
+            vector,
+            registers,
+            stride,
+            phys: memory.phys().0,
+            state: Mutex::new(QueueState { memory, submission_tail: 0, completion_head: 0, phase: true, slots }),
+            space_waiters: AtomicList::new(),
+        })
+    }
+
+    fn submission_phys(&self) -> u64 {
+        self.phys
+    }
+
+    fn completion_phys(&self) -> u64 {
+        self.phys + PAGE_SIZE as u64
+    }
+
+    fn submission_doorbell(&self) -> usize {
+        REG_DOORBELLS + (2 * self.id as usize) * self.stride
+    }
+
+    fn completion_doorbell(&self) -> usize {
+        REG_DOORBELLS + (2 * self.id as usize + 1) * self.stride
+    }
+
+    // submits a command and waits for it to complete, giving back the
+    // buffer it was given along with its result:
+    async fn command(&self, submission: Submission, buffer: Option<DmaBuffer>)
+        -> Result<(Option<DmaBuffer>, u32), BlockError>
+    {
+        let mut buffer = Some(buffer);
+
+        let slot = future::poll_fn(|cx| {
+            let mut state = self.state.lock();
+
+            let slot = match state.slots.iter().position(|slot| match slot { Slot::Free => true, _ => false }) {
+                Some(slot) => slot,
+                None => {
+                    // woken by reap() once commands complete:
+                    if self.space_waiters.push_front(cx.waker().clone()).is_err() {
+                        return Poll::Ready(Err(BlockError::MemoryExhausted));
+                    }
+
+                    return Poll::Pending;
+                }
+            };
+
+            state.slots[slot] = Slot::Waiting {
+                buffer: buffer.take().expect("QueuePair::command: submitted twice"),
+                waker: None,
+            };
+
+            let tail = state.submission_tail as usize;
+            let entry = &mut state.memory.as_mut_slice()[(tail * SUBMISSION_SIZE)..((tail + 1) * SUBMISSION_SIZE)];
+            submission.write(entry, slot as u16);
+
+            // the entry has to be in memory before the controller hears of it:
+            dma::fence();
+
+            state.submission_tail = (state.submission_tail + 1) % self.entries;
+            self.registers.write(self.submission_doorbell(), state.submission_tail as u32);
+
+            Poll::Ready(Ok(slot))
+        }).await?;
+
+        let waiting = Waiting { queue: self, slot };
+
+        let (buffer, status, result) = future::poll_fn(|cx| {
+            let mut state = self.state.lock();
+
+            match &mut state.slots[slot] {
+                Slot::Waiting { waker, .. } => {
+                    *waker = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+                Slot::Done { .. } => {}
+                _ => panic!("QueuePair::command: command not in flight"),
+            }
+
+            match mem::replace(&mut state.slots[slot], Slot::Free) {
+                Slot::Done { buffer, status, result } => Poll::Ready((buffer, status, result)),
+                _ => unreachable!(),
+            }
+        }).await;
+
+        mem::forget(waiting);
+
+        for waker in self.space_waiters.take_iter() {
+            waker.wake();
+        }
+
+        if let Some(buffer) = &buffer {
+            buffer.sync_for_cpu();
+        }
+
+        // the status code and type, without the phase bit:
+        match status >> 1 {
+            0 => Ok((buffer, result)),
+            _ => Err(BlockError::Io),
+        }
+    }
+
+    // takes every completion the controller has posted, waking whoever is
+    // waiting on each:
+    fn reap(&self) {
+        let mut state = self.state.lock();
+        let mut reaped = false;
+
+        loop {
+            let head = state.completion_head as usize;
+            let offset = PAGE_SIZE + head * COMPLETION_SIZE;
+
+            let entry = &state.memory.as_slice()[offset..(offset + COMPLETION_SIZE)];
+            let status = unsafe { ptr::read_volatile(entry[14..16].as_ptr() as *const u16) };
+
+            if (status & 1 != 0) != state.phase {
+                break;
+            }
+
+            // don't read the rest of the entry before its phase says it's new:
+            dma::fence();
+
+            let result = u32::from_le_bytes(entry[0..4].try_into().expect("QueuePair::reap"));
+            let slot = u16::from_le_bytes(entry[12..14].try_into().expect("QueuePair::reap")) as usize;
+
+            state.completion_head = (state.completion_head + 1) % self.entries;
+
+            if state.completion_head == 0 {
+                state.phase = !state.phase;
+            }
+
+            reaped = true;
+
+            let waiting = match state.slots.get_mut(slot) {
+                Some(waiting) => waiting,
+                None => continue,
+            };
+
+            match mem::replace(waiting, Slot::Free) {
+                Slot::Waiting { buffer, waker } => {
+                    *waiting = Slot::Done { buffer, status, result };
+
+                    if let Some(waker) = waker {
+                        waker.wake();
+                    }
+                }
+                // dropping the buffer, now that the controller's done with it:
+                Slot::Abandoned(_) => {}
+                other => *waiting = other,
+            }
+        }
+
+        if reaped {
+            self.registers.write(self.completion_doorbell(), state.completion_head as u32);
+        }
+
+        drop(state);
+
+        if reaped {
+            for waker in self.space_waiters.take_iter() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+// leaves a command's buffer to reap() if its future is dropped while the
+// controller still has it:
+struct Waiting<'a> {
+    queue: &'a QueuePair,
+    slot: usize,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        let mut state = self.queue.state.lock();
+        let slot = &mut state.slots[self.slot];
+
+        *slot = match mem::replace(slot, Slot::Free) {
+            Slot::Waiting { buffer, .. } => Slot::Abandoned(buffer),
+            _ => Slot::Free,
+        };
+    }
+}
+
+/// An NVMe controller, with an admin queue pair and some I/O ones.
+pub struct Nvme {
+    pci: PciAddress,
+    registers: Registers,
+    admin: QueuePair,
+    io: Mutex<ArrayVec<[Arc<QueuePair>; MAX_IO_QUEUES]>>,
+    interrupts: Mutex<Interrupts>,
+}
+
+// how completions are signalled:
+enum Interrupts {
+    // through the legacy interrupt, which every queue pair shares and other
+    // devices may too:
+    Legacy,
+    // through MSI-X, with a vector for each table entry in use, by entry.
+    // the admin queue pair has entry 0, which I/O queue pairs share if
+    // there aren't enough vectors to go round:
+    Msix { table: Msix, vectors: ArrayVec<[MsiVector; MAX_IO_QUEUES + 1]> },
+}
+
+// the controllers found, for the interrupt handler to find by index:
+static CONTROLLERS: Mutex<[Option<Arc<Nvme>>; MAX_CONTROLLERS]> = Mutex::new([None, None]);
+
+// polls `done` until it's true, for up to `timeout_ms`:
+async fn wait_until(timeout_ms: u64, done: impl Fn() -> bool) -> Result<bool, MemoryExhausted> {
+    for _ in 0..timeout_ms {
+        if done() {
+            return Ok(true);
+        }
+
+        time::sleep_ns(POLL_NS).await?;
+    }
+
+    Ok(done())
+}
+
+impl Nvme {
+    // resets the controller and brings it up with just the admin queue pair:
+    async fn new(pci: PciAddress) -> Result<Nvme, NvmeError> {
+        let base = match pci.bar(0) {
+            Some(Bar::Memory(base)) => base,
+            _ => return Err(NvmeError::NoRegisters),
+        };
+
+        pci.enable(Command::MEMORY_SPACE | Command::BUS_MASTER);
+
+        // how much there is to map depends on the doorbell stride, which is
+        // in the capabilities:
+        let capabilities = unsafe {
+            let first = kvirt::map_mmio(RawPhys(base), 1)?;
+            let capabilities = Registers(first).read_u64(REG_CAP);
+            kvirt::free_pages(first, 1);
+            capabilities
+        };
+
+        let max_entries = (capabilities & 0xffff) as u16 + 1;
+        let timeout_ms = ((capabilities >> 24) & 0xff) * TIMEOUT_UNIT_MS;
+        let stride = 4 << ((capabilities >> 32) & 0xf) as usize;
+
+        // the registers and the doorbells of every queue pair used:
+        let len = REG_DOORBELLS + 2 * (MAX_IO_QUEUES + 1) * stride;
+
+        let registers = unsafe {
+            Registers(kvirt::map_mmio(RawPhys(base), (len + PAGE_SIZE - 1) / PAGE_SIZE)?)
+        };
+
+        registers.write(REG_CC, registers.read(REG_CC) & !CC_ENABLE);
+
+        if !wait_until(timeout_ms, || registers.read(REG_CSTS) & CSTS_READY == 0).await? {
+            return Err(NvmeError::Timeout);
+        }
+
+        let entries = cmp::min(QUEUE_ENTRIES, max_entries);
+        let admin = QueuePair::new(0, entries, 0, registers, stride)?;
+
+        registers.write(REG_AQA, (entries as u32 - 1) << 16 | (entries as u32 - 1));
+        registers.write_u64(REG_ASQ, admin.submission_phys());
+        registers.write_u64(REG_ACQ, admin.completion_phys());
+
+        registers.write(REG_CC, CC_ENABLE | CC_IOSQES | CC_IOCQES);
+
+        if !wait_until(timeout_ms, || registers.read(REG_CSTS) & (CSTS_READY | CSTS_FATAL) != 0).await? {
+            return Err(NvmeError::Timeout);
+        }
+
+        if registers.read(REG_CSTS) & CSTS_FATAL != 0 {
+            return Err(NvmeError::Failed);
+        }
+
+        Ok(Nvme {
+            pci,
+            registers,
+            admin,
+            io: Mutex::new(ArrayVec::new()),
+            interrupts: Mutex::new(Interrupts::Legacy),
+        })
+    }
+
+    async fn identify(&self, namespace: u32, cns: u32) -> Result<DmaBuffer, NvmeError> {
+        let data = dma::alloc(PAGE_SIZE, Constraints::ANY)?;
+
+        let submission = Submission {
+            opcode: ADMIN_IDENTIFY,
+            namespace,
+            prp1: data.phys().0,
+            dwords: [cns, 0, 0, 0, 0, 0],
+            ..Submission::default()
+        };
+
+        let (data, _) = self.admin.command(submission, Some(data)).await?;
+        Ok(data.expect("Nvme::identify: buffer not given back"))
+    }
+
+    // picks the interrupt vector I/O queue pair `id` of controller `index`
+    // completes with, giving it an MSI-X vector of its own if there's one to
+    // give:
+    fn route(&self, index: usize, id: u16) -> u16 {
+        let mut interrupts = self.interrupts.lock();
+
+        let (table, vectors) = match &mut *interrupts {
+            Interrupts::Msix { table, vectors } => (table, vectors),
+            Interrupts::Legacy => return 0,
+        };
+
+        if id as usize != vectors.len() || id >= table.len() {
+            return 0;
+        }
+
+        match interrupt::alloc_msi(queue_interrupt, (index as u64) << 16 | id as u64) {
+            Ok(vector) => {
+                table.set(id, &vector);
+                table.unmask(id);
+                vectors.push(vector);
+                id
+            }
+            Err(_) => 0,
+        }
+    }
+
+    // sets up an I/O queue pair for each CPU, as far as the controller and
+    // MAX_IO_QUEUES allow:
+    async fn create_io_queues(&self, index: usize) -> Result<(), NvmeError> {
+        let wanted = cmp::max(1, cmp::min(smp::cpu_count(), MAX_IO_QUEUES)) as u32;
+
+        let submission = Submission {
+            opcode: ADMIN_SET_FEATURES,
+            dwords: [FEATURE_QUEUES, (wanted - 1) << 16 | (wanted - 1), 0, 0, 0, 0],
+            ..Submission::default()
+        };
+
+        // the controller says how many of each it gave, from zero:
+        let (_, given) = self.admin.command(submission, None).await?;
+        let count = cmp::min(wanted, cmp::min(given & 0xffff, given >> 16) + 1);
+
+        let capabilities = self.registers.read_u64(REG_CAP);
+        let entries = cmp::min(QUEUE_ENTRIES, (capabilities & 0xffff) as u16 + 1);
+        let stride = self.admin.stride;
+
+        for id in 1..=(count as u16) {
+            let vector = self.route(index, id);
+            let queue = QueuePair::new(id, entries, vector, self.registers, stride)?;
+            let size = (entries as u32 - 1) << 16 | id as u32;
+
+            let create_cq = Submission {
+                opcode: ADMIN_CREATE_CQ,
+                prp1: queue.completion_phys(),
+                dwords: [size, (vector as u32) << 16 | QUEUE_INTERRUPTS | QUEUE_CONTIGUOUS, 0, 0, 0, 0],
+                ..Submission::default()
+            };
+
+            self.admin.command(create_cq, None).await?;
+
+            let create_sq = Submission {
+                opcode: ADMIN_CREATE_SQ,
+                prp1: queue.submission_phys(),
+                dwords: [size, (id as u32) << 16 | QUEUE_CONTIGUOUS, 0, 0, 0, 0],
+                ..Submission::default()
+            };
+
+            self.admin.command(create_sq, None).await?;
+
+            self.io.lock().push(Arc::new(queue)?);
+        }
+
+        Ok(())
+    }
+
+    // takes the completions of every queue pair that completes with
+    // `vector`:
+    fn reap(&self, vector: u16) {
+        if self.admin.vector == vector {
+            self.admin.reap();
+        }
+
+        let io = self.io.lock().clone();
+
+        for queue in io.iter().filter(|queue| queue.vector == vector) {
+            queue.reap();
+        }
+    }
+}
+
+impl Debug for Nvme {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Nvme({:?}, {} I/O queues)", self.pci, self.io.lock().len())
+    }
+}
+
+/// A namespace of an NVMe controller, as a block device. Each command goes
+/// to the I/O queue pair of the CPU submitting it.
+pub struct NvmeNamespace {
+    id: u32,
+    blocks: u64,
+    max_sectors: usize,
+    queues: ArrayVec<[Arc<QueuePair>; MAX_IO_QUEUES]>,
+}
+
+impl NvmeNamespace {
+    fn queue(&self) -> &QueuePair {
+        &self.queues[smp::cpu_index() % self.queues.len()]
+    }
+
+    // reads or writes up to max_sectors sectors. data spanning more than two
+    // pages needs a list of its pages, which goes on the page after it:
+    async fn transfer(&self, opcode: u8, lba: u64, count: usize, fill: impl FnOnce(&mut [u8]))
+        -> Result<DmaBuffer, BlockError>
+    {
+        let len = count * BLOCK_SIZE;
+        let pages = (len + PAGE_SIZE - 1) / PAGE_SIZE;
+        let mut data = dma::alloc(pages * PAGE_SIZE + PAGE_SIZE, Constraints::ANY)?;
+        let phys = data.phys().0;
+
+        let prp2 = {
+            let bytes = data.as_mut_slice();
+            fill(&mut bytes[..len]);
+
+            match pages {
+                1 => 0,
+                2 => phys + PAGE_SIZE as u64,
+                _ => {
+                    let list = pages * PAGE_SIZE;
+
+                    for page in 1..pages {
+                        let offset = list + (page - 1) * 8;
+                        bytes[offset..(offset + 8)].copy_from_slice(&(phys + (page * PAGE_SIZE) as u64).to_le_bytes());
+                    }
+
+                    phys + list as u64
+                }
+            }
+        };
+
+        data.sync_for_device();
+
+        let submission = Submission {
+            opcode,
+            namespace: self.id,
+            prp1: phys,
+            prp2,
+            dwords: [lba as u32, (lba >> 32) as u32, count as u32 - 1, 0, 0, 0],
+        };
+
+        let (data, _) = self.queue().command(submission, Some(data)).await?;
+        Ok(data.expect("NvmeNamespace::transfer: buffer not given back"))
+    }
+}
+
+impl BlockDevice for NvmeNamespace {
+    fn block_count(&self) -> u64 {
+        self.blocks
+    }
+
+    fn read_blocks<'a, 'b: 'a>(&'a self, lba: u64, buffs: &'a mut [&'b mut Sector]) -> BlockFuture<'a, ()> {
+        BlockFuture::new(async move {
+            for (index, chunk) in buffs.chunks_mut(self.max_sectors).enumerate() {
+                let lba = lba + (index * self.max_sectors) as u64;
+                let data = self.transfer(IO_READ, lba, chunk.len(), |_| {}).await?;
+
+                for (sector, bytes) in chunk.iter_mut().zip(data.as_slice().chunks(BLOCK_SIZE)) {
+                    sector.copy_from_slice(bytes);
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    // the controller may have the data in a volatile cache when the writes
+    // complete, so it's flushed afterwards:
+    fn write_blocks<'a>(&'a self, lba: u64, buffs: &'a [&'a Sector]) -> BlockFuture<'a, ()> {
+        BlockFuture::new(async move {
+            for (index, chunk) in buffs.chunks(self.max_sectors).enumerate() {
+                let lba = lba + (index * self.max_sectors) as u64;
+
+                self.transfer(IO_WRITE, lba, chunk.len(), |bytes| {
+                    for (sector, bytes) in chunk.iter().zip(bytes.chunks_mut(BLOCK_SIZE)) {
+                        bytes.copy_from_slice(&sector[..]);
+                    }
+                }).await?;
+            }
+
+            let flush = Submission { opcode: IO_FLUSH, namespace: self.id, ..Submission::default() };
+            self.queue().command(flush, None).await?;
+
+            Ok(())
+        })
+    }
+}
+
+impl Debug for NvmeNamespace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "NvmeNamespace({}, {} blocks)", self.id, self.blocks)
+    }
+}
+
+fn get(index: u64) -> Option<Arc<Nvme>> {
+    CONTROLLERS.lock().get(index as usize).and_then(|nvme| nvme.clone())
+}
+
+// runs in the handler for the legacy interrupt, which may be shared with
+// other devices. there's no telling whether the controller is the one
+// interrupting, so its interrupt is masked until its queues have been looked
+// at:
+fn interrupt(index: u64) {
+    let nvme = match get(index) {
+        Some(nvme) => nvme,
+        None => return,
+    };
+
+    nvme.registers.write(REG_INTMS, 1);
+
+    // waking tasks takes scheduler locks, leave that until after the
+    // interrupt, unless there's no room to:
+    if work::defer(complete, index).is_err() {
+        complete(index);
+    }
+}
+
+fn complete(index: u64) {
+    let nvme = match get(index) {
+        Some(nvme) => nvme,
+        None => return,
+    };
+
+    nvme.reap(0);
+    nvme.registers.write(REG_INTMC, 1);
+}
+
+// runs in the interrupt handler for an MSI-X vector, which is the
+// controller's alone, so nothing needs masking. the controller's index is
+// in the upper bits of `arg`, the table entry in the lower 16:
+fn queue_interrupt(arg: u64) {
+    if work::defer(queue_complete, arg).is_err() {
+        queue_complete(arg);
+    }
+}
+
+fn queue_complete(arg: u64) {
+    if let Some(nvme) = get(arg >> 16) {
+        nvme.reap(arg as u16);
+    }
+}
+
+// has the admin queue pair's completions interrupt through entry 0 of the
+// MSI-X table if there is one and a vector for it, or through the legacy
+// interrupt otherwise:
+fn setup_interrupts(nvme: &Nvme, device: &PciDevice, index: usize) -> SysResult<()> {
+    if let Some(table) = device.msix()? {
+        if let Ok(vector) = interrupt::alloc_msi(queue_interrupt, (index as u64) << 16) {
+            table.set(0, &vector);
+            table.unmask(0);
+            table.enable();
+
+            let mut vectors = ArrayVec::new();
+            vectors.push(vector);
+
+            *nvme.interrupts.lock() = Interrupts::Msix { table, vectors };
+            return Ok(());
+        }
+    }
+
+    let irq = device.legacy_irq()
+        .ok_or(SysError::InvalidOperation)?;
+
+    interrupt::register_irq_mode(irq, IrqMode::PCI, interrupt, index as u64)
+        .map_err(|_| SysError::Busy)
+}
+
+/// Attaches to NVMe controllers, registering their namespaces as block
+/// devices, nvme0n1 and so on.
+pub struct NvmeDriver;
+
+pub static DRIVER: NvmeDriver = NvmeDriver;
+
+impl PciDriver for NvmeDriver {
+    fn name(&self) -> &'static str {
+        "nvme"
+    }
+
+    fn matches(&self, device: &PciDevice) -> bool {
+        device.class == PCI_CLASS
+    }
+
+    fn attach<'a>(&'a self, device: &'a PciDevice) -> PciFuture<'a> {
+        PciFuture::new(attach(device))
+    }
+}
+
+// sets up a controller and registers its namespaces:
+async fn attach(device: &PciDevice) -> SysResult<()> {
+    let address = device.address;
+
+    let nvme = Arc::new(Nvme::new(address).await?)?;
+
+    let index = {
+        let mut controllers = CONTROLLERS.lock();
+
+        let index = controllers.iter().position(Option::is_none)
+            .ok_or(SysError::NoSpace)?;
+
+        controllers[index] = Some(nvme.clone());
+        index
+    };
+
+    if let Err(e) = setup_interrupts(&nvme, device, index) {
+        CONTROLLERS.lock()[index] = None;
+        return Err(e);
+    }
+
+    let controller = nvme.identify(0, IDENTIFY_CONTROLLER).await?;
+    let controller = controller.as_slice();
+
+    let namespaces = u32::from_le_bytes(controller[516..520].try_into().expect("nvme::attach"));
+
+    // the largest transfer is given in minimum sized pages, as a power of
+    // two, with 0 for no limit:
+    let max_sectors = match controller[77] {
+        0 => COMMAND_SECTORS,
+        mdts => cmp::min(COMMAND_SECTORS, (PAGE_SIZE / BLOCK_SIZE) << mdts),
+    };
+
+    nvme.create_io_queues(index).await?;
+
+    let queues = nvme.io.lock().clone();
+
+    if queues.is_empty() {
+        return Err(NvmeError::Failed.into());
+    }
+
+    for id in 1..=cmp::min(namespaces, MAX_NAMESPACES as u32) {
+        let identify = nvme.identify(id, IDENTIFY_NAMESPACE).await?;
+        let identify = identify.as_slice();
+
+        let blocks = u64::from_le_bytes(identify[0..8].try_into().expect("nvme::attach"));
+        let format = (identify[26] & 0xf) as usize;
+        let block_shift = identify[128 + format * 4 + 2];
+
+        if blocks == 0 {
+            continue;
+        }
+
+        if 1 << block_shift != BLOCK_SIZE {
+            crate::println!("nvme: namespace {} of {:?} doesn't have {} byte blocks", id, address, BLOCK_SIZE);
+            continue;
+        }
+
+        let namespace = NvmeNamespace { id, blocks, max_sectors, queues: queues.clone() };
+
+        let mut name = ArrayString::<[u8; BLOCK_NAME_MAX]>::new();
+        write!(name, "nvme{}n{}", index, id).expect("nvme::attach: name too long");
+
+        let queue = RequestQueue::new(Arc::new(namespace)?);
+
+        block::register(name.as_bytes(), Arc::new(queue)?).await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,153 @@
+use core::convert::TryInto;
+
+use arrayvec::ArrayVec;
+
+use crate::device::block::{BlockError, Disk, Sector, BLOCK_SIZE};
+
+/// The most partitions of a disk that get registered. Any more in a GPT are
+/// ignored.
+pub const MAX_PARTITIONS: usize = 16;
+
+const MBR_ENTRIES_OFFSET: usize = 0x1be;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+
+// the partition type of the single MBR entry covering a GPT disk:
+const MBR_TYPE_EMPTY: u8 = 0x00;
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xee;
+
+const GPT_HEADER_LBA: u64 = 1;
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_ENTRIES_LBA: usize = 72;
+const GPT_ENTRY_COUNT: usize = 80;
+const GPT_ENTRY_SIZE: usize = 84;
+const GPT_ENTRY_FIRST_LBA: usize = 32;
+const GPT_ENTRY_LAST_LBA: usize = 40;
+
+/// A partition found on a disk, in blocks of the disk.
+#[derive(Debug, Clone, Copy)]
+pub struct Partition {
+    /// Counting from 1, in the order of the partition table.
+    pub number: usize,
+    pub start: u64,
+    pub blocks: u64,
+}
+
+pub type Partitions = ArrayVec<[Partition; MAX_PARTITIONS]>;
+
+/// Reads a disk's partition table, GPT or MBR. A disk without either has no
+/// partitions, and partitions that go past the end of the disk are left out.
+pub async fn scan(disk: &Disk) -> Result<Partitions, BlockError> {
+    let mut mbr = [0u8; BLOCK_SIZE];
+    disk.read_blocks(0, &mut [&mut mbr]).await?;
+
+    let mut partitions = Partitions::new();
+
+    if mbr[510..512] != MBR_SIGNATURE {
+        return Ok(partitions);
+    }
+
+    let entries = (0..4).map(|index| {
+        let offset = MBR_ENTRIES_OFFSET + index * MBR_ENTRY_SIZE;
+        &mbr[offset..(offset + MBR_ENTRY_SIZE)]
+    });
+
+    for (index, entry) in entries.enumerate() {
+        let type_ = entry[4];
+
+        if type_ == MBR_TYPE_GPT_PROTECTIVE {
+            return scan_gpt(disk).await;
+        }
+
+        if type_ == MBR_TYPE_EMPTY {
+            continue;
+        }
+
+        let partition = Partition {
+            number: index + 1,
+            start: read_u32(entry, 8) as u64,
+            blocks: read_u32(entry, 12) as u64,
+        };
+
+        if fits(disk, &partition) {
+            partitions.push(partition);
+        }
+    }
+
+    Ok(partitions)
+}
+
+async fn scan_gpt(disk: &Disk) -> Result<Partitions, BlockError> {
+    let mut header = [0u8; BLOCK_SIZE];
+    disk.read_blocks(GPT_HEADER_LBA, &mut [&mut header]).await?;
+
+    let mut partitions = Partitions::new();
+
+    if &header[0..8] != GPT_SIGNATURE {
+        return Ok(partitions);
+    }
+
+    let entries_lba = read_u64(&header, GPT_ENTRIES_LBA);
+    let entry_count = read_u32(&header, GPT_ENTRY_COUNT) as usize;
+    let entry_size = read_u32(&header, GPT_ENTRY_SIZE) as usize;
+
+    // entries are at least 128 bytes, and always a power of two:
+    if entry_size < 128 || entry_size > BLOCK_SIZE || !entry_size.is_power_of_two() {
+        return Ok(partitions);
+    }
+
+    let per_block = BLOCK_SIZE / entry_size;
+    let mut block: Sector = [0u8; BLOCK_SIZE];
+
+    for index in 0..entry_count {
+        if partitions.is_full() {
+            break;
+        }
+
+        if index % per_block == 0 {
+            disk.read_blocks(entries_lba + (index / per_block) as u64, &mut [&mut block]).await?;
+        }
+
+        let offset = (index % per_block) * entry_size;
+        let entry = &block[offset..(offset + entry_size)];
+
+        // unused entries have a type GUID of all zeros:
+        if entry[0..16].iter().all(|byte| *byte == 0) {
+            continue;
+        }
+
+        let first = read_u64(entry, GPT_ENTRY_FIRST_LBA);
+        let last = read_u64(entry, GPT_ENTRY_LAST_LBA);
+
+        if last < first {
+            continue;
+        }
+
+        let partition = Partition {
+            number: index + 1,
+            start: first,
+            blocks: last - first + 1,
+        };
+
+        if fits(disk, &partition) {
+            partitions.push(partition);
+        }
+    }
+
+    Ok(partitions)
+}
+
+fn fits(disk: &Disk, partition: &Partition) -> bool {
+    partition.blocks > 0 && match partition.start.checked_add(partition.blocks) {
+        Some(end) => end <= disk.block_count(),
+        None => false,
+    }
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..(offset + 4)].try_into().expect("read_u32"))
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..(offset + 8)].try_into().expect("read_u64"))
+}
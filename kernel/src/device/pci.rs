@@ -0,0 +1,709 @@
+use core::fmt::{self, Debug};
+use core::future::Future;
+use core::ops::Bound;
+use core::pin::Pin;
+use core::ptr::{self, NonNull};
+use core::task::{Context, Poll};
+
+use alloc_collections::btree_map::BTreeMap;
+use arrayvec::ArrayVec;
+use interface::{SysError, SysResult};
+use x86_64::instructions::port::Port;
+
+use crate::interrupt::MsiVector;
+use crate::mem::kalloc::GlobalAlloc;
+use crate::mem::kvirt;
+use crate::mem::page::PAGE_SIZE;
+use crate::mem::phys::RawPhys;
+use crate::mem::MemoryExhausted;
+use crate::sync::Mutex;
+use crate::util::EarlyInit;
+
+/// The most capabilities recorded for a function. Any more are ignored.
+pub const MAX_CAPABILITIES: usize = 16;
+
+/// Capability ids.
+pub const CAPABILITY_MSI: u8 = 0x05;
+pub const CAPABILITY_MSIX: u8 = 0x11;
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+const VENDOR_ID: u16 = 0x00;
+const DEVICE_ID: u16 = 0x02;
+const COMMAND: u16 = 0x04;
+const STATUS: u16 = 0x06;
+const CLASS: u16 = 0x08;
+const HEADER_TYPE: u16 = 0x0e;
+const BAR0: u16 = 0x10;
+const SECONDARY_BUS: u16 = 0x19;
+const CAPABILITIES: u16 = 0x34;
+const INTERRUPT_LINE: u16 = 0x3c;
+const INTERRUPT_PIN: u16 = 0x3d;
+
+const VENDOR_NONE: u16 = 0xffff;
+const HEADER_MULTI_FUNCTION: u8 = 0x80;
+const HEADER_BRIDGE: u8 = 0x01;
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+const INTERRUPT_LINE_NONE: u8 = 0xff;
+
+// the MSI capability, from its start. where the data and mask registers are
+// depends on whether the address is 64 bit:
+const MSI_CONTROL: u16 = 0x02;
+const MSI_ADDRESS_LOW: u16 = 0x04;
+const MSI_ADDRESS_HIGH: u16 = 0x08;
+const MSI_CONTROL_ENABLE: u16 = 1 << 0;
+const MSI_CONTROL_MULTIPLE: u16 = 0x7 << 4;
+const MSI_CONTROL_64: u16 = 1 << 7;
+const MSI_CONTROL_MASKABLE: u16 = 1 << 8;
+
+// the MSI-X capability, from its start:
+const MSIX_CONTROL: u16 = 0x02;
+const MSIX_TABLE: u16 = 0x04;
+const MSIX_CONTROL_SIZE: u16 = 0x7ff;
+const MSIX_CONTROL_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_CONTROL_ENABLE: u16 = 1 << 15;
+
+// each entry of the MSI-X table:
+const MSIX_ENTRY_SIZE: usize = 16;
+const MSIX_ENTRY_ADDRESS_LOW: usize = 0x0;
+const MSIX_ENTRY_ADDRESS_HIGH: usize = 0x4;
+const MSIX_ENTRY_DATA: usize = 0x8;
+const MSIX_ENTRY_CONTROL: usize = 0xc;
+const MSIX_ENTRY_MASKED: u32 = 1 << 0;
+
+// each function has 4K of configuration space through ECAM, and each bus
+// 1M of it:
+const ECAM_FUNCTION_SIZE: usize = 0x1000;
+const ECAM_BUS_SIZE: usize = 0x10_0000;
+
+bitflags::bitflags! {
+    pub struct Command: u16 {
+        const IO_SPACE      = 0x0001;
+        const MEMORY_SPACE  = 0x0002;
+        const BUS_MASTER    = 0x0004;
+        const INTX_DISABLE  = 0x0400;
+    }
+}
+
+// how configuration space is reached. the I/O ports only reach the first
+// 256 bytes of each function's, ECAM reaches all 4K of it for the buses it
+// covers:
+enum Access {
+    Ports,
+    Ecam { base: NonNull<u8>, start_bus: u8, end_bus: u8 },
+}
+
+// the address and data ports are used in pairs, so one access at a time:
+static CONFIG: Mutex<Access> = Mutex::new(Access::Ports);
+
+/// Where a function is on the PCI bus, which its configuration space is
+/// addressed by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+/// Where a base address register says a function's registers are.
+#[derive(Debug, Clone, Copy)]
+pub enum Bar {
+    Io(u16),
+    Memory(u64),
+}
+
+impl PciAddress {
+    fn config_address(&self, offset: u16) -> u32 {
+        0x8000_0000
+            | (self.bus as u32) << 16
+            | (self.device as u32) << 11
+            | (self.function as u32) << 8
+            | (offset & 0xfc) as u32
+    }
+
+    // where the dword holding `offset` is through ECAM, if it's reachable
+    // that way:
+    fn ecam_ptr(&self, access: &Access, offset: u16) -> Option<*mut u32> {
+        match *access {
+            Access::Ecam { base, start_bus, end_bus } if self.bus >= start_bus && self.bus <= end_bus => {
+                let offset = (self.bus - start_bus) as usize * ECAM_BUS_SIZE
+                    + ((self.device as usize) << 3 | self.function as usize) * ECAM_FUNCTION_SIZE
+                    + (offset & !3) as usize;
+
+                Some(unsafe { base.as_ptr().add(offset) as *mut u32 })
+            }
+            _ => None,
+        }
+    }
+
+    pub fn read_u32(&self, offset: u16) -> u32 {
+        let config = CONFIG.lock();
+
+        if let Some(ptr) = self.ecam_ptr(&config, offset) {
+            return unsafe { ptr::read_volatile(ptr) };
+        }
+
+        assert!(offset < 0x100, "PciAddress::read_u32: offset only reachable through ECAM");
+
+        unsafe {
+            Port::<u32>::new(CONFIG_ADDRESS).write(self.config_address(offset));
+            Port::<u32>::new(CONFIG_DATA).read()
+        }
+    }
+
+    pub fn write_u32(&self, offset: u16, value: u32) {
+        let config = CONFIG.lock();
+
+        if let Some(ptr) = self.ecam_ptr(&config, offset) {
+            unsafe { ptr::write_volatile(ptr, value); }
+            return;
+        }
+
+        assert!(offset < 0x100, "PciAddress::write_u32: offset only reachable through ECAM");
+
+        unsafe {
+            Port::<u32>::new(CONFIG_ADDRESS).write(self.config_address(offset));
+            Port::<u32>::new(CONFIG_DATA).write(value);
+        }
+    }
+
+    pub fn read_u16(&self, offset: u16) -> u16 {
+        (self.read_u32(offset) >> ((offset & 2) * 8)) as u16
+    }
+
+    pub fn read_u8(&self, offset: u16) -> u8 {
+        (self.read_u32(offset) >> ((offset & 3) * 8)) as u8
+    }
+
+    // config space is only written a dword at a time, so the rest of the
+    // dword is written back as it was:
+    pub fn write_u16(&self, offset: u16, value: u16) {
+        let shift = (offset & 2) * 8;
+        let dword = self.read_u32(offset) & !(0xffff << shift);
+        self.write_u32(offset, dword | (value as u32) << shift);
+    }
+
+    pub fn vendor_id(&self) -> u16 {
+        self.read_u16(VENDOR_ID)
+    }
+
+    pub fn device_id(&self) -> u16 {
+        self.read_u16(DEVICE_ID)
+    }
+
+    /// The class, subclass and programming interface of the function, which
+    /// say what kind of device it is regardless of who made it.
+    pub fn class(&self) -> (u8, u8, u8) {
+        let class = self.read_u32(CLASS);
+        ((class >> 24) as u8, (class >> 16) as u8, (class >> 8) as u8)
+    }
+
+    /// The IRQ the firmware routed the function's interrupt pin to.
+    pub fn interrupt_line(&self) -> u8 {
+        self.read_u8(INTERRUPT_LINE)
+    }
+
+    /// Turns on the given kinds of access to the function, leaving the rest
+    /// of the command register alone.
+    pub fn enable(&self, command: Command) {
+        let current = self.read_u16(COMMAND);
+        self.write_u16(COMMAND, current | command.bits());
+    }
+
+    /// Turns off the given kinds of access to the function, leaving the rest
+    /// of the command register alone.
+    pub fn disable(&self, command: Command) {
+        let current = self.read_u16(COMMAND);
+        self.write_u16(COMMAND, current & !command.bits());
+    }
+
+    /// Reads base address register `index`, or None if it's unused. A 64 bit
+    /// memory BAR takes up the next register too.
+    pub fn bar(&self, index: u8) -> Option<Bar> {
+        let offset = BAR0 + index as u16 * 4;
+        let low = self.read_u32(offset);
+
+        if low & 1 != 0 {
+            return match (low & !0x3) as u16 {
+                0 => None,
+                port => Some(Bar::Io(port)),
+            };
+        }
+
+        let high = match (low >> 1) & 0x3 {
+            // 64 bit:
+            0x2 => self.read_u32(offset + 4),
+            _ => 0,
+        };
+
+        match (high as u64) << 32 | (low & !0xf) as u64 {
+            0 => None,
+            base => Some(Bar::Memory(base)),
+        }
+    }
+
+    // whether the BAR at `index` is a 64 bit memory one, taking up the next
+    // register too:
+    fn bar_is_64(&self, index: u8) -> bool {
+        let low = self.read_u32(BAR0 + index as u16 * 4);
+        low & 1 == 0 && (low >> 1) & 0x3 == 0x2
+    }
+
+    fn header_type(&self) -> u8 {
+        self.read_u8(HEADER_TYPE)
+    }
+}
+
+/// A capability in a function's capability list, by its id and where in
+/// configuration space it is.
+#[derive(Debug, Clone, Copy)]
+pub struct Capability {
+    pub id: u8,
+    pub offset: u8,
+}
+
+/// A function found on the bus, with what enumeration recorded of it.
+#[derive(Debug, Clone)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: (u8, u8, u8),
+    /// Bridges have only the first two. A 64 bit memory BAR leaves the one
+    /// after it None.
+    pub bars: [Option<Bar>; 6],
+    /// The IRQ the firmware routed the function's interrupt to, if it has
+    /// one.
+    pub irq: Option<u8>,
+    pub capabilities: ArrayVec<[Capability; MAX_CAPABILITIES]>,
+}
+
+impl PciDevice {
+    fn read(address: PciAddress) -> PciDevice {
+        let bar_count = match address.header_type() & !HEADER_MULTI_FUNCTION {
+            HEADER_BRIDGE => 2,
+            0 => 6,
+            _ => 0,
+        };
+
+        let mut bars = [None; 6];
+        let mut index = 0;
+
+        while index < bar_count {
+            bars[index as usize] = address.bar(index);
+            index += if address.bar_is_64(index) { 2 } else { 1 };
+        }
+
+        let irq = match (address.read_u8(INTERRUPT_PIN), address.interrupt_line()) {
+            (0, _) | (_, INTERRUPT_LINE_NONE) => None,
+            (_, line) => Some(line),
+        };
+
+        let mut capabilities = ArrayVec::new();
+
+        if address.read_u16(STATUS) & STATUS_CAPABILITIES != 0 {
+            let mut offset = address.read_u8(CAPABILITIES) & !0x3;
+
+            // the list is in the first 256 bytes, after the header, so it
+            // can't be longer than this without looping:
+            for _ in 0..48 {
+                if offset < 0x40 || capabilities.is_full() {
+                    break;
+                }
+
+                let header = address.read_u16(offset as u16);
+                capabilities.push(Capability { id: header as u8, offset });
+                offset = (header >> 8) as u8 & !0x3;
+            }
+        }
+
+        PciDevice {
+            address,
+            vendor_id: address.vendor_id(),
+            device_id: address.device_id(),
+            class: address.class(),
+            bars,
+            irq,
+            capabilities,
+        }
+    }
+
+    /// The IRQ the function interrupts on, if it has one the legacy PIC
+    /// delivers.
+    pub fn legacy_irq(&self) -> Option<u8> {
+        self.irq.filter(|irq| *irq < 0x10)
+    }
+
+    /// Where the first capability with the given id is.
+    pub fn capability(&self, id: u8) -> Option<u8> {
+        self.capabilities.iter()
+            .find(|capability| capability.id == id)
+            .map(|capability| capability.offset)
+    }
+
+    /// The function's MSI capability, if it has one.
+    #[allow(unused)]
+    pub fn msi(&self) -> Option<Msi> {
+        let offset = self.capability(CAPABILITY_MSI)? as u16;
+        let control = self.address.read_u16(offset + MSI_CONTROL);
+
+        Some(Msi { address: self.address, offset, control })
+    }
+
+    /// The function's MSI-X capability, if it has one, with its table
+    /// mapped. None as well if the table isn't in a memory BAR.
+    pub fn msix(&self) -> Result<Option<Msix>, MemoryExhausted> {
+        let offset = match self.capability(CAPABILITY_MSIX) {
+            Some(offset) => offset as u16,
+            None => return Ok(None),
+        };
+
+        let control = self.address.read_u16(offset + MSIX_CONTROL);
+        let table = self.address.read_u32(offset + MSIX_TABLE);
+
+        // the low bits say which BAR the table is in, the rest where in it:
+        let base = match self.bars.get((table & 0x7) as usize) {
+            Some(Some(Bar::Memory(base))) => base + (table & !0x7) as u64,
+            _ => return Ok(None),
+        };
+
+        let entries = (control & MSIX_CONTROL_SIZE) + 1;
+
+        let first_page = base & !(PAGE_SIZE as u64 - 1);
+        let end = base + entries as u64 * MSIX_ENTRY_SIZE as u64;
+        let pages = ((end - first_page) as usize + PAGE_SIZE - 1) / PAGE_SIZE;
+
+        let mapped = unsafe { kvirt::map_mmio(RawPhys(first_page), pages)? };
+        let table = unsafe { NonNull::new_unchecked(mapped.as_ptr().add((base - first_page) as usize)) };
+
+        self.address.enable(Command::MEMORY_SPACE);
+
+        Ok(Some(Msix { address: self.address, offset, entries, mapped, pages, table }))
+    }
+}
+
+/// A function's MSI capability, which raises a single vector here. Masking
+/// it is only possible if the function supports that.
+#[derive(Debug)]
+pub struct Msi {
+    address: PciAddress,
+    offset: u16,
+    control: u16,
+}
+
+#[allow(unused)]
+impl Msi {
+    fn data_register(&self) -> u16 {
+        self.offset + if self.control & MSI_CONTROL_64 != 0 { 0x0c } else { 0x08 }
+    }
+
+    fn mask_register(&self) -> u16 {
+        self.offset + if self.control & MSI_CONTROL_64 != 0 { 0x10 } else { 0x0c }
+    }
+
+    /// Has the function raise `vector` instead of its legacy interrupt.
+    pub fn enable(&self, vector: &MsiVector) {
+        let message = vector.address();
+
+        self.address.write_u32(self.offset + MSI_ADDRESS_LOW, message as u32);
+
+        if self.control & MSI_CONTROL_64 != 0 {
+            self.address.write_u32(self.offset + MSI_ADDRESS_HIGH, (message >> 32) as u32);
+        }
+
+        self.address.write_u16(self.data_register(), vector.data() as u16);
+
+        // one message only, so that it's always this vector:
+        let control = self.address.read_u16(self.offset + MSI_CONTROL) & !MSI_CONTROL_MULTIPLE;
+        self.address.write_u16(self.offset + MSI_CONTROL, control | MSI_CONTROL_ENABLE);
+
+        self.address.enable(Command::INTX_DISABLE);
+    }
+
+    /// Has the function go back to its legacy interrupt.
+    pub fn disable(&self) {
+        let control = self.address.read_u16(self.offset + MSI_CONTROL);
+        self.address.write_u16(self.offset + MSI_CONTROL, control & !MSI_CONTROL_ENABLE);
+
+        self.address.disable(Command::INTX_DISABLE);
+    }
+
+    pub fn maskable(&self) -> bool {
+        self.control & MSI_CONTROL_MASKABLE != 0
+    }
+
+    /// Holds the function's messages back until unmasked, if it's maskable.
+    /// Returns whether it is.
+    pub fn mask(&self) -> bool {
+        if self.maskable() {
+            self.address.write_u32(self.mask_register(), 1);
+        }
+
+        self.maskable()
+    }
+
+    pub fn unmask(&self) {
+        if self.maskable() {
+            self.address.write_u32(self.mask_register(), 0);
+        }
+    }
+}
+
+/// A function's MSI-X capability, with its table of messages, each of which
+/// raises a vector of its own and can be masked on its own. Every entry
+/// starts off masked.
+pub struct Msix {
+    address: PciAddress,
+    offset: u16,
+    entries: u16,
+    mapped: NonNull<u8>,
+    pages: usize,
+    table: NonNull<u8>,
+}
+
+impl Msix {
+    /// How many entries the table has.
+    pub fn len(&self) -> u16 {
+        self.entries
+    }
+
+    fn entry(&self, entry: u16, register: usize) -> *mut u32 {
+        assert!(entry < self.entries, "Msix::entry: no such entry");
+
+        unsafe { self.table.as_ptr().add(entry as usize * MSIX_ENTRY_SIZE + register) as *mut u32 }
+    }
+
+    /// Has `entry` raise `vector`. The entry should be masked while this is
+    /// done.
+    pub fn set(&self, entry: u16, vector: &MsiVector) {
+        let message = vector.address();
+
+        unsafe {
+            ptr::write_volatile(self.entry(entry, MSIX_ENTRY_ADDRESS_LOW), message as u32);
+            ptr::write_volatile(self.entry(entry, MSIX_ENTRY_ADDRESS_HIGH), (message >> 32) as u32);
+            ptr::write_volatile(self.entry(entry, MSIX_ENTRY_DATA), vector.data());
+        }
+    }
+
+    #[allow(unused)]
+    pub fn mask(&self, entry: u16) {
+        unsafe {
+            let control = ptr::read_volatile(self.entry(entry, MSIX_ENTRY_CONTROL));
+            ptr::write_volatile(self.entry(entry, MSIX_ENTRY_CONTROL), control | MSIX_ENTRY_MASKED);
+        }
+    }
+
+    pub fn unmask(&self, entry: u16) {
+        unsafe {
+            let control = ptr::read_volatile(self.entry(entry, MSIX_ENTRY_CONTROL));
+            ptr::write_volatile(self.entry(entry, MSIX_ENTRY_CONTROL), control & !MSIX_ENTRY_MASKED);
+        }
+    }
+
+    /// Has the function use the table instead of its legacy interrupt.
+    pub fn enable(&self) {
+        let control = self.address.read_u16(self.offset + MSIX_CONTROL) & !MSIX_CONTROL_FUNCTION_MASK;
+        self.address.write_u16(self.offset + MSIX_CONTROL, control | MSIX_CONTROL_ENABLE);
+
+        self.address.enable(Command::INTX_DISABLE);
+    }
+
+    /// Has the function go back to its legacy interrupt.
+    #[allow(unused)]
+    pub fn disable(&self) {
+        let control = self.address.read_u16(self.offset + MSIX_CONTROL);
+        self.address.write_u16(self.offset + MSIX_CONTROL, control & !MSIX_CONTROL_ENABLE);
+
+        self.address.disable(Command::INTX_DISABLE);
+    }
+}
+
+impl Drop for Msix {
+    fn drop(&mut self) {
+        unsafe { kvirt::free_pages(self.mapped, self.pages); }
+    }
+}
+
+impl Debug for Msix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Msix({:?}, {} entries)", self.address, self.entries)
+    }
+}
+
+/// The future returned by PciDriver::attach, boxed so that drivers can be
+/// trait objects. Fails with MemoryExhausted when polled if there's no
+/// memory to box it in.
+pub enum PciFuture<'a> {
+    Boxed(Pin<alloc_collections::boxed::Box<dyn Future<Output = SysResult<()>> + 'a, GlobalAlloc>>),
+    Failed,
+}
+
+impl<'a> PciFuture<'a> {
+    pub fn new(future: impl Future<Output = SysResult<()>> + 'a) -> Self {
+        match alloc_collections::boxed::Box::new(future) {
+            Ok(future) => {
+                let future = future as alloc_collections::boxed::Box<dyn Future<Output = SysResult<()>> + 'a, GlobalAlloc>;
+                PciFuture::Boxed(unsafe { Pin::new_unchecked(future) })
+            }
+            Err(_) => PciFuture::Failed,
+        }
+    }
+}
+
+impl<'a> Future for PciFuture<'a> {
+    type Output = SysResult<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        match self.get_mut() {
+            PciFuture::Boxed(future) => future.as_mut().poll(cx),
+            PciFuture::Failed => Poll::Ready(Err(SysError::MemoryExhausted)),
+        }
+    }
+}
+
+/// A driver for some kind of PCI device. Once registered, it's attached to
+/// every enumerated device it matches that no other driver has.
+pub trait PciDriver: Sync {
+    fn name(&self) -> &'static str;
+
+    fn matches(&self, device: &PciDevice) -> bool;
+
+    /// Sets the device up, registering whatever it provides, like block
+    /// devices. A device the driver fails to attach to is left for others.
+    fn attach<'a>(&'a self, device: &'a PciDevice) -> PciFuture<'a>;
+}
+
+struct Entry {
+    device: PciDevice,
+    // the driver attached to the device:
+    driver: Option<&'static str>,
+}
+
+// every function enumerated, by address:
+static DEVICES: EarlyInit<Mutex<BTreeMap<PciAddress, Entry, GlobalAlloc>>> = EarlyInit::new();
+
+pub fn init() {
+    EarlyInit::set(&DEVICES, Mutex::new(BTreeMap::new()));
+}
+
+/// Reaches configuration space through ECAM from here on, for the buses it
+/// covers, mapping it from `base`, where the firmware says it is.
+pub fn use_ecam(base: RawPhys, start_bus: u8, end_bus: u8) -> Result<(), MemoryExhausted> {
+    assert!(start_bus <= end_bus, "pci::use_ecam: no buses");
+
+    let len = (end_bus - start_bus) as usize * ECAM_BUS_SIZE + ECAM_BUS_SIZE;
+    let mapped = unsafe { kvirt::map_mmio(base, len / PAGE_SIZE)? };
+
+    *CONFIG.lock() = Access::Ecam { base: mapped, start_bus, end_bus };
+
+    Ok(())
+}
+
+/// Walks the bus hierarchy from the host bridges down through every PCI to
+/// PCI bridge, recording each function found.
+pub fn enumerate() -> Result<(), MemoryExhausted> {
+    let mut visited = [false; 256];
+
+    // a multi-function host bridge has a host bridge for each bus it
+    // leads to, one function each:
+    let host = PciAddress { bus: 0, device: 0, function: 0 };
+
+    if host.header_type() & HEADER_MULTI_FUNCTION == 0 {
+        return enumerate_bus(0, &mut visited);
+    }
+
+    for function in 0..8 {
+        let host = PciAddress { bus: 0, device: 0, function };
+
+        if host.vendor_id() != VENDOR_NONE {
+            enumerate_bus(function, &mut visited)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn enumerate_bus(bus: u8, visited: &mut [bool; 256]) -> Result<(), MemoryExhausted> {
+    if visited[bus as usize] {
+        return Ok(());
+    }
+
+    visited[bus as usize] = true;
+
+    for device in 0..32 {
+        let first = PciAddress { bus, device, function: 0 };
+
+        if first.vendor_id() == VENDOR_NONE {
+            continue;
+        }
+
+        let functions = match first.header_type() & HEADER_MULTI_FUNCTION {
+            0 => 1,
+            _ => 8,
+        };
+
+        for function in 0..functions {
+            let address = PciAddress { bus, device, function };
+
+            if address.vendor_id() == VENDOR_NONE {
+                continue;
+            }
+
+            record(address)?;
+
+            if address.header_type() & !HEADER_MULTI_FUNCTION == HEADER_BRIDGE {
+                enumerate_bus(address.read_u8(SECONDARY_BUS), visited)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn record(address: PciAddress) -> Result<(), MemoryExhausted> {
+    let entry = Entry { device: PciDevice::read(address), driver: None };
+
+    DEVICES.lock().insert(address, entry)
+        .map_err(|_| MemoryExhausted)?;
+
+    Ok(())
+}
+
+/// Registers a driver, attaching it to each enumerated device it matches
+/// that doesn't have one already, in order of address. Only running out of
+/// memory is an error, a device the driver can't attach to is skipped.
+pub async fn register_driver(driver: &'static dyn PciDriver) -> SysResult<()> {
+    let mut after = None;
+
+    loop {
+        let device = {
+            let devices = DEVICES.lock();
+
+            let start = match after {
+                Some(address) => Bound::Excluded(address),
+                None => Bound::Unbounded,
+            };
+
+            let found = devices.range((start, Bound::Unbounded))
+                .find(|(_, entry)| entry.driver.is_none() && driver.matches(&entry.device));
+
+            match found {
+                Some((_, entry)) => entry.device.clone(),
+                None => return Ok(()),
+            }
+        };
+
+        after = Some(device.address);
+
+        match driver.attach(&device).await {
+            Ok(()) => {
+                if let Some(entry) = DEVICES.lock().get_mut(&device.address) {
+                    entry.driver = Some(driver.name());
+                }
+            }
+            Err(SysError::MemoryExhausted) => return Err(SysError::MemoryExhausted),
+            Err(e) => crate::println!("pci: {} can't attach to {:?}: {:?}", driver.name(), device.address, e),
+        }
+    }
+}
@@ -1,26 +1,66 @@
-use core::cmp;
 use x86_64::instructions::port::Port;
 
 use crate::critical;
 
-const PIT_FREQ: usize = 1193182;
+const PIT_FREQ: u64 = 1193182;
 
-unsafe fn set_frequency(hz: usize) {
-    let divisor = cmp::min(PIT_FREQ / hz, 65535);
+const PORT_CHANNEL2: u16 = 0x42;
+const PORT_COMMAND: u16 = 0x43;
 
+// channel 2's gate and output are wired to the keyboard controller's port B,
+// along with the speaker, which is kept off:
+const PORT_B: u16 = 0x61;
+const PORT_B_GATE2: u8 = 1 << 0;
+const PORT_B_SPEAKER: u8 = 1 << 1;
+const PORT_B_OUT2: u8 = 1 << 5;
+
+// mode 0 raises the output once the count runs out, and counts nothing until
+// a count is written. both channels are set up lobyte/hibyte:
+const COMMAND_CHANNEL0_MODE0: u8 = 0b00110000;
+const COMMAND_CHANNEL2_MODE0: u8 = 0b10110000;
+
+/// The longest `measure` can wait, in nanoseconds.
+pub const MAX_MEASURE_NS: u64 = 0xffff * 1_000_000_000 / PIT_FREQ;
+
+/// Stops channel 0 from interrupting. The local APIC timer keeps time
+/// instead, and this only serves to calibrate it.
+pub unsafe fn init() {
     critical::section(|| {
-        let mut port = Port::<u8>::new(0x40);
-        port.write(((divisor >> 0) & 0xff) as u8);
-        port.write(((divisor >> 8) & 0xff) as u8);
+        Port::<u8>::new(PORT_COMMAND).write(COMMAND_CHANNEL0_MODE0);
     });
 }
 
-pub unsafe fn init() {
-    critical::section(|| {
-        // initialize pit channel 0
-        let mut port = Port::<u8>::new(0x43);
-        port.write(0b00110100);
+/// Busy waits `ns` nanoseconds on channel 2, which has no interrupt. Calls
+/// `start` as the count begins and returns what `finish` does as it runs
+/// out, so that other clocks can be measured against it.
+pub fn measure<T>(ns: u64, start: impl FnOnce(), finish: impl FnOnce() -> T) -> T {
+    assert!(ns <= MAX_MEASURE_NS, "pit::measure: too long");
 
-        set_frequency(20);
-    });
+    let count = ns * PIT_FREQ / 1_000_000_000;
+
+    critical::section(|| unsafe {
+        let mut port_b = Port::<u8>::new(PORT_B);
+        let mut channel2 = Port::<u8>::new(PORT_CHANNEL2);
+
+        // hold the gate low while the count goes in:
+        let gate = port_b.read() & !(PORT_B_GATE2 | PORT_B_SPEAKER);
+        port_b.write(gate);
+
+        Port::<u8>::new(PORT_COMMAND).write(COMMAND_CHANNEL2_MODE0);
+        channel2.write(count as u8);
+        channel2.write((count >> 8) as u8);
+
+        port_b.write(gate | PORT_B_GATE2);
+        start();
+
+        while port_b.read() & PORT_B_OUT2 == 0 {
+            core::sync::atomic::spin_loop_hint();
+        }
+
+        let result = finish();
+
+        port_b.write(gate);
+
+        result
+    })
 }
@@ -0,0 +1,102 @@
+use x86_64::instructions::port::Port;
+
+const DATA: u16 = 0x60;
+const STATUS: u16 = 0x64;
+const COMMAND: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+const COMMAND_READ_CONFIG: u8 = 0x20;
+const COMMAND_WRITE_CONFIG: u8 = 0x60;
+const COMMAND_ENABLE_AUX: u8 = 0xa8;
+const COMMAND_WRITE_AUX: u8 = 0xd4;
+// pulses the output line wired to the CPU's reset:
+const COMMAND_PULSE_RESET: u8 = 0xfe;
+
+/// Bits of the controller's configuration byte: interrupts for the
+/// auxiliary port, its clock being off, and scancode set 2 from the keyboard
+/// being translated into set 1.
+pub const CONFIG_IRQ_AUX: u8 = 1 << 1;
+pub const CONFIG_AUX_CLOCK_DISABLED: u8 = 1 << 5;
+pub const CONFIG_TRANSLATE: u8 = 1 << 6;
+
+// what a device on either port answers a command it took with:
+const ACK: u8 = 0xfa;
+
+// how many times the status register is read waiting for the controller
+// before giving up on it:
+const CONTROLLER_SPINS: usize = 100_000;
+
+#[derive(Debug)]
+pub enum Ps2Error {
+    /// The controller or device didn't answer in time, or there's none.
+    Timeout,
+    /// The device answered a command with something other than an ACK.
+    NoAck(u8),
+}
+
+// the keyboard and the mouse share the controller, and a command and its
+// answer are several accesses, so these expect to be the only ones using it.
+// they're used before interrupts are enabled:
+
+unsafe fn wait(mask: u8, set: bool) -> Result<(), Ps2Error> {
+    let mut status = Port::<u8>::new(STATUS);
+
+    if (0..CONTROLLER_SPINS).any(|_| (status.read() & mask != 0) == set) {
+        Ok(())
+    } else {
+        Err(Ps2Error::Timeout)
+    }
+}
+
+/// Reads a byte the controller or a device has for us, waiting for one.
+pub unsafe fn read_data() -> Result<u8, Ps2Error> {
+    wait(STATUS_OUTPUT_FULL, true)?;
+    Ok(Port::<u8>::new(DATA).read())
+}
+
+unsafe fn write_data(byte: u8) -> Result<(), Ps2Error> {
+    wait(STATUS_INPUT_FULL, false)?;
+    Port::<u8>::new(DATA).write(byte);
+    Ok(())
+}
+
+unsafe fn write_command(command: u8) -> Result<(), Ps2Error> {
+    wait(STATUS_INPUT_FULL, false)?;
+    Port::<u8>::new(COMMAND).write(command);
+    Ok(())
+}
+
+pub unsafe fn read_config() -> Result<u8, Ps2Error> {
+    write_command(COMMAND_READ_CONFIG)?;
+    read_data()
+}
+
+pub unsafe fn write_config(config: u8) -> Result<(), Ps2Error> {
+    write_command(COMMAND_WRITE_CONFIG)?;
+    write_data(config)
+}
+
+/// Resets the machine through the controller's reset line, the way it was
+/// done on the AT. Returns if the controller isn't there to do it.
+pub unsafe fn pulse_reset() -> Result<(), Ps2Error> {
+    write_command(COMMAND_PULSE_RESET)
+}
+
+/// Turns on the auxiliary port, which the mouse is on.
+pub unsafe fn enable_aux() -> Result<(), Ps2Error> {
+    write_command(COMMAND_ENABLE_AUX)
+}
+
+/// Sends a byte to the device on the auxiliary port and waits for it to be
+/// acknowledged.
+pub unsafe fn write_aux(byte: u8) -> Result<(), Ps2Error> {
+    write_command(COMMAND_WRITE_AUX)?;
+    write_data(byte)?;
+
+    match read_data()? {
+        ACK => Ok(()),
+        other => Err(Ps2Error::NoAck(other)),
+    }
+}
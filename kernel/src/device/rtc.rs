@@ -0,0 +1,153 @@
+use x86_64::instructions::port::Port;
+
+use crate::acpi;
+use crate::critical;
+
+// CMOS registers are selected through the index port, the top bit of which
+// also masks NMIs, so it's kept clear:
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+// where the century usually is, for when there's no FADT to say. if there's
+// no century register, or it has nonsense in it, the year is taken to be in
+// the 2000s:
+const REG_CENTURY: u8 = 0x32;
+
+const STATUS_A_UPDATING: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+
+// in 12 hour mode, the top bit of the hour is set after noon:
+const HOUR_PM: u8 = 1 << 7;
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// A date and time as the RTC keeps it, which is usually UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u32,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since the start of 1970, UTC.
+    pub fn unix_seconds(&self) -> u64 {
+        days_since_epoch(self.year, self.month as u32, self.day as u32) * SECS_PER_DAY
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64
+    }
+}
+
+fn read_register(register: u8) -> u8 {
+    critical::section(|| unsafe {
+        Port::<u8>::new(CMOS_INDEX).write(register);
+        Port::<u8>::new(CMOS_DATA).read()
+    })
+}
+
+// the FADT's century register, which is 0 if there isn't one:
+fn century_register() -> Option<u8> {
+    match acpi::fadt::fadt() {
+        Some(fadt) if fadt.century == 0 => None,
+        Some(fadt) => Some(fadt.century),
+        None => Some(REG_CENTURY),
+    }
+}
+
+// everything that changes as time passes, in register order:
+fn read_raw(century: Option<u8>) -> [u8; 7] {
+    // the registers are nonsense while the RTC updates them, once a second:
+    while read_register(REG_STATUS_A) & STATUS_A_UPDATING != 0 {}
+
+    [
+        read_register(REG_SECONDS),
+        read_register(REG_MINUTES),
+        read_register(REG_HOURS),
+        read_register(REG_DAY),
+        read_register(REG_MONTH),
+        read_register(REG_YEAR),
+        century.map(read_register).unwrap_or(0),
+    ]
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+/// Reads the date and time from the RTC.
+pub fn read() -> DateTime {
+    // an update can still start between checking for one and reading, so
+    // read until two in a row agree:
+    let century = century_register();
+    let mut raw = read_raw(century);
+
+    loop {
+        let again = read_raw(century);
+
+        if again == raw {
+            break;
+        }
+
+        raw = again;
+    }
+
+    let [second, minute, hour, day, month, year, century] = raw;
+
+    let status = read_register(REG_STATUS_B);
+    let pm = hour & HOUR_PM != 0;
+    let hour = hour & !HOUR_PM;
+
+    let decode = |value| if status & STATUS_B_BINARY != 0 { value } else { from_bcd(value) };
+
+    let mut hour = decode(hour);
+
+    if status & STATUS_B_24_HOUR == 0 {
+        // 12 AM is midnight and 12 PM is noon:
+        hour %= 12;
+
+        if pm {
+            hour += 12;
+        }
+    }
+
+    let century = match decode(century) {
+        century @ 19..=99 => century as u32,
+        _ => 20,
+    };
+
+    DateTime {
+        year: century * 100 + decode(year) as u32,
+        month: decode(month),
+        day: decode(day),
+        hour,
+        minute: decode(minute),
+        second: decode(second),
+    }
+}
+
+// days from 1970-01-01 to the date, with March first in the year so that the
+// leap day comes last:
+fn days_since_epoch(year: u32, month: u32, day: u32) -> u64 {
+    let year = (if month <= 2 { year - 1 } else { year }) as u64;
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let month_from_march = (month as u64 + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + (day as u64).saturating_sub(1);
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    // 719468 days from 0000-03-01 to 1970-01-01:
+    (era * 146097 + day_of_era).saturating_sub(719468)
+}
@@ -0,0 +1,343 @@
+use core::task::{Poll, Waker};
+
+use arraydeque::{ArrayDeque, Saturating};
+use futures::future;
+use interface::SysError;
+use x86_64::instructions::port::Port;
+
+use crate::fs::devfs::{self, Device, DeviceKind};
+use crate::fs::vfs::FsFuture;
+use crate::interrupt;
+use crate::mem::MemoryExhausted;
+use crate::object::poll::Events;
+use crate::sync::{Arc, Mutex};
+use crate::util::AtomicList;
+use crate::work;
+
+// COM1, which is the only port looked for:
+const BASE: u16 = 0x3f8;
+const IRQ: u8 = 4;
+
+// registers, from the base port. with LCR_DLAB set, the first two are the
+// baud rate divisor instead:
+const DATA: u16 = 0;
+const IER: u16 = 1;
+const IIR: u16 = 2;
+const FCR: u16 = 2;
+const LCR: u16 = 3;
+const MCR: u16 = 4;
+const LSR: u16 = 5;
+const MSR: u16 = 6;
+const DIVISOR_LOW: u16 = 0;
+const DIVISOR_HIGH: u16 = 1;
+
+const IER_RX: u8 = 1 << 0;
+const IER_THR_EMPTY: u8 = 1 << 1;
+
+// the low bit is set when nothing is interrupting, the next three say what:
+const IIR_NONE: u8 = 1 << 0;
+const IIR_ID_MASK: u8 = 0x0e;
+const IIR_MODEM: u8 = 0x00;
+const IIR_THR_EMPTY: u8 = 0x02;
+const IIR_RX: u8 = 0x04;
+const IIR_LINE: u8 = 0x06;
+const IIR_RX_TIMEOUT: u8 = 0x0c;
+
+// enable and clear both FIFOs, interrupting once 14 bytes have arrived or
+// none have for a while:
+const FCR_INIT: u8 = 0xc7;
+
+const LCR_8N1: u8 = 0x03;
+const LCR_DLAB: u8 = 0x80;
+
+// OUT2 connects the UART's interrupt to the PIC:
+const MCR_DTR: u8 = 1 << 0;
+const MCR_RTS: u8 = 1 << 1;
+const MCR_OUT2: u8 = 1 << 3;
+const MCR_LOOPBACK: u8 = 1 << 4;
+
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+
+// 115200 baud, the most the UART's clock allows:
+const DIVISOR: u16 = 1;
+
+// how much can be written at once when the transmitter is empty:
+const FIFO_SIZE: usize = 16;
+
+const RX_BUFFER: usize = 256;
+const TX_BUFFER: usize = 1024;
+
+#[derive(Debug)]
+pub enum SerialError {
+    /// Nothing answers at COM1.
+    NotPresent,
+    Register(SysError),
+}
+
+impl From<SysError> for SerialError {
+    fn from(e: SysError) -> SerialError {
+        SerialError::Register(e)
+    }
+}
+
+// bytes received and not read yet, and bytes waiting for the transmitter:
+static RX: Mutex<Option<ArrayDeque<[u8; RX_BUFFER], Saturating>>> = Mutex::new(None);
+static TX: Mutex<Option<ArrayDeque<[u8; TX_BUFFER], Saturating>>> = Mutex::new(None);
+
+static READ_WAKERS: AtomicList<Waker> = AtomicList::new();
+static WRITE_WAKERS: AtomicList<Waker> = AtomicList::new();
+
+fn port(register: u16) -> Port<u8> {
+    Port::new(BASE + register)
+}
+
+/// Sets up COM1, if there is one, registering it in devfs as ttyS0. Must be
+/// called before interrupts are enabled.
+// Safety: must not be called more than once
+pub unsafe fn init() -> Result<(), SerialError> {
+    port(IER).write(0);
+
+    port(LCR).write(LCR_DLAB);
+    port(DIVISOR_LOW).write(DIVISOR as u8);
+    port(DIVISOR_HIGH).write((DIVISOR >> 8) as u8);
+    port(LCR).write(LCR_8N1);
+
+    port(FCR).write(FCR_INIT);
+
+    // a byte sent in loopback comes straight back if there's a UART there
+    // at all:
+    port(MCR).write(MCR_LOOPBACK | MCR_RTS | MCR_DTR);
+    port(DATA).write(0xae);
+
+    if port(DATA).read() != 0xae {
+        return Err(SerialError::NotPresent);
+    }
+
+    port(MCR).write(MCR_OUT2 | MCR_RTS | MCR_DTR);
+
+    *RX.lock() = Some(ArrayDeque::new());
+    *TX.lock() = Some(ArrayDeque::new());
+
+    devfs::register(b"ttyS0", Arc::new(Serial).expect("serial::init: Arc::new"))?;
+
+    interrupt::register_irq(IRQ, self::interrupt, 0)
+        .map_err(|_| SerialError::Register(SysError::Busy))?;
+
+    port(IER).write(IER_RX);
+
+    Ok(())
+}
+
+// moves as much of `tx` to the transmitter as it'll take, then asks for an
+// interrupt when it's empty again if there's more to go. whether anything
+// was taken off `tx`:
+fn transmit(tx: &mut ArrayDeque<[u8; TX_BUFFER], Saturating>) -> bool {
+    let mut sent = false;
+
+    unsafe {
+        if port(LSR).read() & LSR_THR_EMPTY != 0 {
+            for _ in 0..FIFO_SIZE {
+                match tx.pop_front() {
+                    Some(byte) => port(DATA).write(byte),
+                    None => break,
+                }
+
+                sent = true;
+            }
+        }
+
+        port(IER).write(if tx.is_empty() { IER_RX } else { IER_RX | IER_THR_EMPTY });
+    }
+
+    sent
+}
+
+/// Writes kernel output to COM1. Never drops any: when the transmit queue is
+/// full, this waits for the UART to make room, with interrupts disabled.
+pub fn write_console(bytes: &[u8]) {
+    let mut tx = TX.lock();
+
+    let tx = match tx.as_mut() {
+        Some(tx) => tx,
+        None => return,
+    };
+
+    for &byte in bytes {
+        // terminals on the other end want both:
+        if byte == b'\n' {
+            push_polled(tx, b'\r');
+        }
+
+        push_polled(tx, byte);
+    }
+
+    transmit(tx);
+}
+
+fn push_polled(tx: &mut ArrayDeque<[u8; TX_BUFFER], Saturating>, byte: u8) {
+    while tx.push_back(byte).is_err() {
+        while unsafe { port(LSR).read() } & LSR_THR_EMPTY == 0 {}
+
+        transmit(tx);
+    }
+}
+
+/// COM1 as a character device. Bytes go out and come in as they are, with no
+/// line discipline in between.
+#[derive(Debug)]
+struct Serial;
+
+impl Device for Serial {
+    fn kind(&self) -> DeviceKind {
+        DeviceKind::Character
+    }
+
+    fn read<'a>(&'a self, _pos: u64, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        FsFuture::new(async move {
+            future::poll_fn(|ctx| {
+                // register waker before checking the buffer so that we can't
+                // miss a byte arriving in between:
+                if let Err(MemoryExhausted) = READ_WAKERS.push_front(ctx.waker().clone()) {
+                    return Poll::Ready(Err(SysError::MemoryExhausted));
+                }
+
+                let mut rx = RX.lock();
+
+                let rx = rx.as_mut()
+                    .expect("serial to be initialized");
+
+                if rx.is_empty() {
+                    return Poll::Pending;
+                }
+
+                let mut count = 0;
+
+                while count < buf.len() {
+                    match rx.pop_front() {
+                        Some(byte) => buf[count] = byte,
+                        None => break,
+                    }
+
+                    count += 1;
+                }
+
+                Poll::Ready(Ok(count))
+            }).await
+        })
+    }
+
+    fn write<'a>(&'a self, _pos: u64, buf: &'a [u8]) -> FsFuture<'a, usize> {
+        FsFuture::new(async move {
+            future::poll_fn(|ctx| {
+                if let Err(MemoryExhausted) = WRITE_WAKERS.push_front(ctx.waker().clone()) {
+                    return Poll::Ready(Err(SysError::MemoryExhausted));
+                }
+
+                let mut tx = TX.lock();
+
+                let tx = tx.as_mut()
+                    .expect("serial to be initialized");
+
+                if tx.is_full() && !buf.is_empty() {
+                    return Poll::Pending;
+                }
+
+                let mut count = 0;
+
+                for &byte in buf {
+                    if tx.push_back(byte).is_err() {
+                        break;
+                    }
+
+                    count += 1;
+                }
+
+                transmit(tx);
+
+                Poll::Ready(Ok(count))
+            }).await
+        })
+    }
+
+    fn poll_ready(&self, waker: &Waker) -> Result<Events, MemoryExhausted> {
+        READ_WAKERS.push_front(waker.clone())?;
+        WRITE_WAKERS.push_front(waker.clone())?;
+
+        let mut events = Events::empty();
+
+        if let Some(rx) = RX.lock().as_ref() {
+            if !rx.is_empty() {
+                events |= Events::IN;
+            }
+        }
+
+        if let Some(tx) = TX.lock().as_ref() {
+            if !tx.is_full() {
+                events |= Events::OUT;
+            }
+        }
+
+        Ok(events)
+    }
+}
+
+fn interrupt(_: u64) {
+    let mut received = false;
+    let mut sent = false;
+
+    // the UART may have more than one reason to interrupt, and keeps its
+    // line raised until all of them are dealt with:
+    loop {
+        let iir = unsafe { port(IIR).read() };
+
+        if iir & IIR_NONE != 0 {
+            break;
+        }
+
+        match iir & IIR_ID_MASK {
+            IIR_RX | IIR_RX_TIMEOUT => {
+                let mut rx = RX.lock();
+
+                while unsafe { port(LSR).read() } & LSR_DATA_READY != 0 {
+                    let byte = unsafe { port(DATA).read() };
+
+                    // with nobody reading, the newest bytes are dropped:
+                    if let Some(rx) = rx.as_mut() {
+                        received |= rx.push_back(byte).is_ok();
+                    }
+                }
+            }
+            IIR_THR_EMPTY => {
+                if let Some(tx) = TX.lock().as_mut() {
+                    sent |= transmit(tx);
+                }
+            }
+            // reading the status register is what acknowledges these:
+            IIR_LINE => unsafe { port(LSR).read(); },
+            IIR_MODEM => unsafe { port(MSR).read(); },
+            _ => {}
+        }
+    }
+
+    // waking readers and writers takes scheduler locks, leave that until
+    // after the interrupt:
+    if received && work::defer(wake, READ).is_err() {
+        wake(READ);
+    }
+
+    if sent && work::defer(wake, WRITE).is_err() {
+        wake(WRITE);
+    }
+}
+
+const READ: u64 = 0;
+const WRITE: u64 = 1;
+
+fn wake(which: u64) {
+    let wakers = if which == READ { &READ_WAKERS } else { &WRITE_WAKERS };
+
+    for waker in wakers.take_iter() {
+        waker.wake();
+    }
+}
@@ -0,0 +1,370 @@
+use core::fmt::{self, Debug};
+use core::mem;
+use core::ptr;
+use core::task::{Poll, Waker};
+
+use alloc_collections::btree_map::BTreeMap;
+use futures::future;
+use interface::{SysError, SysResult};
+
+use crate::device::block::{self, BlockDevice, BlockError, BlockFuture, RequestQueue, Sector, BLOCK_SIZE};
+use crate::device::pci::{PciAddress, PciDevice, PciDriver, PciFuture};
+use crate::device::virtio::{self, Segment, Virtqueue, VirtioError, VirtioPci};
+use crate::interrupt::{self, IrqMode};
+use crate::mem::dma::{self, Constraints, DmaBuffer};
+use crate::mem::kalloc::GlobalAlloc;
+use crate::sync::{Arc, Mutex};
+use crate::util::AtomicList;
+use crate::work;
+
+/// The transitional device id QEMU gives a virtio-blk device, which has the
+/// legacy interface.
+pub const PCI_DEVICE: u16 = 0x1001;
+
+/// The most virtio-blk devices that get registered, as vda, vdb and so on.
+pub const MAX_DEVICES: usize = 4;
+
+// the most sectors a request carries, longer transfers are split up:
+const REQUEST_SECTORS: usize = 128;
+
+// device configuration, from the start of the device specific registers:
+const CONFIG_CAPACITY: u16 = 0x00;
+
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+
+const STATUS_OK: u8 = 0;
+
+#[repr(C)]
+struct RequestHeader {
+    type_: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+const HEADER_SIZE: usize = mem::size_of::<RequestHeader>();
+
+/// A virtio-blk device: a disk in a virtual machine, reached through a
+/// single virtqueue of requests.
+pub struct VirtioBlk {
+    device: VirtioPci,
+    capacity: u64,
+    state: Mutex<BlkState>,
+    // tasks waiting for descriptors to be freed up:
+    space_waiters: AtomicList<Waker>,
+}
+
+struct BlkState {
+    queue: Virtqueue,
+    // requests the device has, by their first descriptor:
+    in_flight: BTreeMap<u16, InFlight, GlobalAlloc>,
+}
+
+struct InFlight {
+    done: bool,
+    // the request's future was dropped before the device was done, so it's
+    // left to complete() to free its buffer:
+    abandoned: bool,
+    buffer: Option<DmaBuffer>,
+    waker: Option<Waker>,
+}
+
+// the devices found, for the interrupt handler to find by index:
+static DEVICES: Mutex<[Option<Arc<VirtioBlk>>; MAX_DEVICES]> = Mutex::new([None, None, None, None]);
+
+impl VirtioBlk {
+    fn new(pci: PciAddress) -> Result<Self, VirtioError> {
+        let device = VirtioPci::new(pci)?;
+
+        // none of the optional features are needed:
+        device.negotiate(0);
+
+        let capacity = device.config_u64(CONFIG_CAPACITY);
+        let queue = device.setup_queue(0)?;
+
+        Ok(VirtioBlk {
+            device,
+            capacity,
+            state: Mutex::new(BlkState { queue, in_flight: BTreeMap::new() }),
+            space_waiters: AtomicList::new(),
+        })
+    }
+
+    // does one request, of up to REQUEST_SECTORS sectors. the buffer holds
+    // the data followed by the request header and then the status byte:
+    async fn request(&self, type_: u32, lba: u64, count: usize, fill: impl FnOnce(&mut [u8]))
+        -> Result<DmaBuffer, BlockError>
+    {
+        let len = count * BLOCK_SIZE;
+        let mut buffer = dma::alloc(len + HEADER_SIZE + 1, Constraints::ANY)?;
+
+        {
+            let bytes = buffer.as_mut_slice();
+            fill(&mut bytes[..len]);
+
+            let header = RequestHeader { type_, reserved: 0, sector: lba };
+            unsafe { ptr::write_unaligned(bytes[len..].as_mut_ptr() as *mut RequestHeader, header); }
+
+            // anything but OK, should the device never get to it:
+            bytes[len + HEADER_SIZE] = 0xff;
+        }
+
+        buffer.sync_for_device();
+
+        let phys = buffer.phys().0;
+
+        let segments = [
+            Segment { phys: phys + len as u64, len: HEADER_SIZE as u32, device_writes: false },
+            Segment { phys, len: len as u32, device_writes: type_ == REQUEST_IN },
+            Segment { phys: phys + (len + HEADER_SIZE) as u64, len: 1, device_writes: true },
+        ];
+
+        let mut buffer = Some(buffer);
+
+        let head = future::poll_fn(|cx| {
+            let mut state = self.state.lock();
+
+            let head = match state.queue.push(&segments) {
+                Some(head) => head,
+                None => {
+                    // woken by complete() once descriptors are freed up:
+                    if self.space_waiters.push_front(cx.waker().clone()).is_err() {
+                        return Poll::Ready(Err(BlockError::MemoryExhausted));
+                    }
+
+                    return Poll::Pending;
+                }
+            };
+
+            let in_flight = InFlight { done: false, abandoned: false, buffer: None, waker: None };
+
+            match state.in_flight.insert(head, in_flight) {
+                Ok(_) => state.in_flight.get_mut(&head)
+                    .expect("VirtioBlk::request: request just inserted")
+                    .buffer = buffer.take(),
+                Err(_) => {
+                    // the device has the request now and nothing would free
+                    // the buffer after it's done, so it's leaked rather than
+                    // freed under the device:
+                    mem::forget(buffer.take());
+                    self.device.notify(&state.queue);
+                    return Poll::Ready(Err(BlockError::MemoryExhausted));
+                }
+            }
+
+            self.device.notify(&state.queue);
+
+            Poll::Ready(Ok(head))
+        }).await?;
+
+        let waiting = Waiting { blk: self, head };
+
+        let buffer = future::poll_fn(|cx| {
+            let mut state = self.state.lock();
+
+            let in_flight = state.in_flight.get_mut(&head)
+                .expect("VirtioBlk::request: request not in flight");
+
+            if !in_flight.done {
+                in_flight.waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+
+            let in_flight = state.in_flight.remove(&head)
+                .expect("VirtioBlk::request: request just found");
+
+            Poll::Ready(in_flight.buffer.expect("VirtioBlk::request: request without its buffer"))
+        }).await;
+
+        mem::forget(waiting);
+
+        buffer.sync_for_cpu();
+
+        if buffer.as_slice()[len + HEADER_SIZE] != STATUS_OK {
+            return Err(BlockError::Io);
+        }
+
+        Ok(buffer)
+    }
+}
+
+// marks a request abandoned if its future is dropped while the device still
+// has it:
+struct Waiting<'a> {
+    blk: &'a VirtioBlk,
+    head: u16,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        let mut state = self.blk.state.lock();
+
+        let done = match state.in_flight.get_mut(&self.head) {
+            Some(in_flight) if !in_flight.done => {
+                in_flight.abandoned = true;
+                in_flight.waker = None;
+                false
+            }
+            Some(_) => true,
+            None => false,
+        };
+
+        if done {
+            state.in_flight.remove(&self.head);
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn block_count(&self) -> u64 {
+        self.capacity
+    }
+
+    fn read_blocks<'a, 'b: 'a>(&'a self, lba: u64, buffs: &'a mut [&'b mut Sector]) -> BlockFuture<'a, ()> {
+        BlockFuture::new(async move {
+            for (index, chunk) in buffs.chunks_mut(REQUEST_SECTORS).enumerate() {
+                let lba = lba + (index * REQUEST_SECTORS) as u64;
+                let buffer = self.request(REQUEST_IN, lba, chunk.len(), |_| {}).await?;
+
+                for (sector, data) in chunk.iter_mut().zip(buffer.as_slice().chunks(BLOCK_SIZE)) {
+                    sector.copy_from_slice(data);
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    // without the flush feature negotiated, the device doesn't complete a
+    // write until it's stored:
+    fn write_blocks<'a>(&'a self, lba: u64, buffs: &'a [&'a Sector]) -> BlockFuture<'a, ()> {
+        BlockFuture::new(async move {
+            for (index, chunk) in buffs.chunks(REQUEST_SECTORS).enumerate() {
+                let lba = lba + (index * REQUEST_SECTORS) as u64;
+
+                self.request(REQUEST_OUT, lba, chunk.len(), |bytes| {
+                    for (sector, data) in chunk.iter().zip(bytes.chunks_mut(BLOCK_SIZE)) {
+                        data.copy_from_slice(&sector[..]);
+                    }
+                }).await?;
+            }
+
+            Ok(())
+        })
+    }
+}
+
+impl Debug for VirtioBlk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "VirtioBlk({:?}, {} blocks)", self.device.pci(), self.capacity)
+    }
+}
+
+fn get(index: u64) -> Option<Arc<VirtioBlk>> {
+    DEVICES.lock().get(index as usize).and_then(|blk| blk.clone())
+}
+
+// runs in the interrupt handler, which may be shared with other devices:
+fn interrupt(index: u64) {
+    let blk = match get(index) {
+        Some(blk) => blk,
+        None => return,
+    };
+
+    if blk.device.ack_interrupt() == 0 {
+        return;
+    }
+
+    // waking tasks takes scheduler locks, leave that until after the
+    // interrupt, unless there's no room to:
+    if work::defer(complete, index).is_err() {
+        complete(index);
+    }
+}
+
+// takes every request the device is done with and wakes whoever is waiting
+// for it:
+fn complete(index: u64) {
+    let blk = match get(index) {
+        Some(blk) => blk,
+        None => return,
+    };
+
+    {
+        let mut state = blk.state.lock();
+
+        while let Some((head, _)) = state.queue.pop_used() {
+            let abandoned = match state.in_flight.get_mut(&head) {
+                Some(in_flight) => {
+                    in_flight.done = true;
+
+                    if let Some(waker) = in_flight.waker.take() {
+                        waker.wake();
+                    }
+
+                    in_flight.abandoned
+                }
+                None => false,
+            };
+
+            if abandoned {
+                state.in_flight.remove(&head);
+            }
+        }
+    }
+
+    for waker in blk.space_waiters.take_iter() {
+        waker.wake();
+    }
+}
+
+/// Attaches to virtio-blk devices, registering each as a block device, vda,
+/// vdb and so on.
+pub struct VirtioBlkDriver;
+
+pub static DRIVER: VirtioBlkDriver = VirtioBlkDriver;
+
+impl PciDriver for VirtioBlkDriver {
+    fn name(&self) -> &'static str {
+        "virtio-blk"
+    }
+
+    fn matches(&self, device: &PciDevice) -> bool {
+        device.vendor_id == virtio::PCI_VENDOR && device.device_id == PCI_DEVICE
+    }
+
+    fn attach<'a>(&'a self, device: &'a PciDevice) -> PciFuture<'a> {
+        PciFuture::new(attach(device))
+    }
+}
+
+async fn attach(device: &PciDevice) -> SysResult<()> {
+    let irq = device.legacy_irq()
+        .ok_or(SysError::InvalidOperation)?;
+
+    let blk = Arc::new(VirtioBlk::new(device.address)?)?;
+
+    let index = {
+        let mut devices = DEVICES.lock();
+
+        let index = devices.iter().position(Option::is_none)
+            .ok_or(SysError::NoSpace)?;
+
+        devices[index] = Some(blk.clone());
+        index
+    };
+
+    if interrupt::register_irq_mode(irq, IrqMode::PCI, interrupt, index as u64).is_err() {
+        DEVICES.lock()[index] = None;
+        return Err(SysError::Busy);
+    }
+
+    blk.device.ready();
+
+    let name = [b'v', b'd', b'a' + index as u8];
+    let queue = RequestQueue::new(blk);
+
+    block::register(&name, Arc::new(queue)?).await?;
+
+    Ok(())
+}
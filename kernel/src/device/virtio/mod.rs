@@ -0,0 +1,342 @@
+use core::mem;
+use core::ptr;
+
+use interface::SysError;
+use x86_64::instructions::port::Port;
+
+use crate::device::pci::{Bar, Command, PciAddress};
+use crate::mem::dma::{self, Constraints, DmaBuffer};
+use crate::mem::page::PAGE_SIZE;
+use crate::mem::phys::RawPhys;
+use crate::mem::MemoryExhausted;
+
+pub mod blk;
+
+/// The PCI vendor every virtio device has.
+pub const PCI_VENDOR: u16 = 0x1af4;
+
+// registers of the legacy interface, from the start of BAR 0:
+const DEVICE_FEATURES: u16 = 0x00;
+const DRIVER_FEATURES: u16 = 0x04;
+const QUEUE_ADDRESS: u16 = 0x08;
+const QUEUE_SIZE: u16 = 0x0c;
+const QUEUE_SELECT: u16 = 0x0e;
+const QUEUE_NOTIFY: u16 = 0x10;
+const DEVICE_STATUS: u16 = 0x12;
+const ISR_STATUS: u16 = 0x13;
+// where device specific configuration starts, without MSI-X:
+const DEVICE_CONFIG: u16 = 0x14;
+
+// the legacy interface only takes queues aligned like this, with the used
+// ring starting on the next boundary after the available ring:
+const QUEUE_ALIGN: usize = 4096;
+
+// the queue address register takes a 32 bit page number:
+const QUEUE_LIMIT: Constraints = Constraints { limit: RawPhys(1 << 44) };
+
+bitflags::bitflags! {
+    pub struct Status: u8 {
+        const ACKNOWLEDGE   = 0x01;
+        const DRIVER        = 0x02;
+        const DRIVER_OK     = 0x04;
+        const FEATURES_OK   = 0x08;
+        const FAILED        = 0x80;
+    }
+}
+
+const DESC_F_NEXT: u16 = 0x1;
+const DESC_F_WRITE: u16 = 0x2;
+
+#[derive(Debug)]
+pub enum VirtioError {
+    /// BAR 0 isn't the I/O ports the legacy interface needs.
+    NotLegacy,
+    /// The device has no such queue.
+    NoQueue,
+    MemoryExhausted,
+}
+
+impl From<MemoryExhausted> for VirtioError {
+    fn from(_: MemoryExhausted) -> VirtioError {
+        VirtioError::MemoryExhausted
+    }
+}
+
+impl From<VirtioError> for SysError {
+    fn from(e: VirtioError) -> SysError {
+        match e {
+            VirtioError::NotLegacy => SysError::InvalidOperation,
+            VirtioError::NoQueue => SysError::IoError,
+            VirtioError::MemoryExhausted => SysError::MemoryExhausted,
+        }
+    }
+}
+
+/// A virtio device on the PCI bus, driven through the legacy interface in its
+/// I/O BAR, which is what QEMU offers by default.
+#[derive(Debug)]
+pub struct VirtioPci {
+    pci: PciAddress,
+    io: u16,
+}
+
+impl VirtioPci {
+    /// Resets the device and acknowledges it, ready to negotiate features.
+    pub fn new(pci: PciAddress) -> Result<Self, VirtioError> {
+        let io = match pci.bar(0) {
+            Some(Bar::Io(io)) => io,
+            _ => return Err(VirtioError::NotLegacy),
+        };
+
+        pci.enable(Command::IO_SPACE | Command::BUS_MASTER);
+
+        let device = VirtioPci { pci, io };
+
+        device.set_status(Status::empty());
+        device.set_status(Status::ACKNOWLEDGE | Status::DRIVER);
+
+        Ok(device)
+    }
+
+    pub fn pci(&self) -> PciAddress {
+        self.pci
+    }
+
+    fn port<T>(&self, register: u16) -> Port<T> {
+        Port::new(self.io + register)
+    }
+
+    pub fn status(&self) -> Status {
+        Status::from_bits_truncate(unsafe { self.port::<u8>(DEVICE_STATUS).read() })
+    }
+
+    pub fn set_status(&self, status: Status) {
+        unsafe { self.port::<u8>(DEVICE_STATUS).write(status.bits()); }
+    }
+
+    /// Takes whichever of the device's features are in `wanted`, returning
+    /// the ones taken.
+    pub fn negotiate(&self, wanted: u32) -> u32 {
+        let features = unsafe { self.port::<u32>(DEVICE_FEATURES).read() } & wanted;
+        unsafe { self.port::<u32>(DRIVER_FEATURES).write(features); }
+        features
+    }
+
+    /// Tells the device the driver is ready to use it.
+    pub fn ready(&self) {
+        self.set_status(self.status() | Status::DRIVER_OK);
+    }
+
+    /// Reads the interrupt status, which also acknowledges the interrupt.
+    /// Zero if the device wasn't interrupting.
+    pub fn ack_interrupt(&self) -> u8 {
+        unsafe { self.port::<u8>(ISR_STATUS).read() }
+    }
+
+    pub fn config_u32(&self, offset: u16) -> u32 {
+        unsafe { self.port::<u32>(DEVICE_CONFIG + offset).read() }
+    }
+
+    pub fn config_u64(&self, offset: u16) -> u64 {
+        let low = self.config_u32(offset) as u64;
+        let high = self.config_u32(offset + 4) as u64;
+        high << 32 | low
+    }
+
+    /// Sets up queue `index` in memory and hands it to the device.
+    pub fn setup_queue(&self, index: u16) -> Result<Virtqueue, VirtioError> {
+        let size = unsafe {
+            self.port::<u16>(QUEUE_SELECT).write(index);
+            self.port::<u16>(QUEUE_SIZE).read()
+        };
+
+        if size == 0 {
+            return Err(VirtioError::NoQueue);
+        }
+
+        let queue = Virtqueue::new(index, size)?;
+
+        unsafe {
+            self.port::<u32>(QUEUE_ADDRESS).write((queue.ring.phys().0 / QUEUE_ALIGN as u64) as u32);
+        }
+
+        Ok(queue)
+    }
+
+    /// Tells the device there's something new in the available ring of
+    /// `queue`.
+    pub fn notify(&self, queue: &Virtqueue) {
+        unsafe { self.port::<u16>(QUEUE_NOTIFY).write(queue.index); }
+    }
+}
+
+/// One buffer of a request, which the device either reads or writes.
+#[derive(Debug, Clone, Copy)]
+pub struct Segment {
+    pub phys: u64,
+    pub len: u32,
+    pub device_writes: bool,
+}
+
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// A queue of requests to a device, in the split layout: a table of
+/// descriptors, each for a buffer, a ring of chains of them the driver makes
+/// available to the device, and a ring of chains the device is done with.
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    ring: DmaBuffer,
+    // offsets into `ring`:
+    avail: usize,
+    used: usize,
+    // unused descriptors are chained together through their next fields:
+    free_head: u16,
+    free_count: u16,
+    next_avail: u16,
+    last_used: u16,
+}
+
+impl Virtqueue {
+    fn new(index: u16, size: u16) -> Result<Virtqueue, MemoryExhausted> {
+        let descriptors = mem::size_of::<Descriptor>() * size as usize;
+        let avail = descriptors;
+        let used = align(avail + 6 + 2 * size as usize, QUEUE_ALIGN);
+        let len = used + 6 + 8 * size as usize;
+
+        let ring = dma::alloc(align(len, PAGE_SIZE), QUEUE_LIMIT)?;
+
+        let mut queue = Virtqueue {
+            index,
+            size,
+            ring,
+            avail,
+            used,
+            free_head: 0,
+            free_count: size,
+            next_avail: 0,
+            last_used: 0,
+        };
+
+        for index in 0..size {
+            queue.descriptor(index).next = index + 1;
+        }
+
+        Ok(queue)
+    }
+
+    fn descriptor(&mut self, index: u16) -> &mut Descriptor {
+        assert!(index < self.size, "Virtqueue::descriptor: index out of range");
+
+        unsafe { &mut *(self.ring.virt().as_ptr() as *mut Descriptor).add(index as usize) }
+    }
+
+    fn field<T>(&self, offset: usize) -> *mut T {
+        unsafe { self.ring.virt().as_ptr().add(offset) as *mut T }
+    }
+
+    /// Makes a chain of segments available to the device, returning the
+    /// index of its first descriptor, which `pop_used` gives back once the
+    /// device is done with it. None if there aren't enough free descriptors
+    /// for it.
+    pub fn push(&mut self, segments: &[Segment]) -> Option<u16> {
+        if segments.is_empty() || segments.len() > self.free_count as usize {
+            return None;
+        }
+
+        let head = self.free_head;
+        let mut index = head;
+
+        for (n, segment) in segments.iter().enumerate() {
+            let last = n + 1 == segments.len();
+            let descriptor = self.descriptor(index);
+
+            let next = descriptor.next;
+            descriptor.addr = segment.phys;
+            descriptor.len = segment.len;
+            descriptor.flags = if last { 0 } else { DESC_F_NEXT }
+                | if segment.device_writes { DESC_F_WRITE } else { 0 };
+
+            if last {
+                self.free_head = next;
+            } else {
+                index = next;
+            }
+        }
+
+        self.free_count -= segments.len() as u16;
+
+        // avail ring: flags, idx, then the ring itself:
+        let slot = (self.next_avail % self.size) as usize;
+
+        unsafe {
+            ptr::write_volatile(self.field::<u16>(self.avail + 4 + 2 * slot), head);
+        }
+
+        // the device mustn't see the new index before the entry it covers:
+        dma::fence();
+        self.next_avail = self.next_avail.wrapping_add(1);
+
+        unsafe {
+            ptr::write_volatile(self.field::<u16>(self.avail + 2), self.next_avail);
+        }
+
+        dma::fence();
+
+        Some(head)
+    }
+
+    /// Takes the next chain the device is done with, returning the index of
+    /// its first descriptor and how many bytes the device wrote to it. Its
+    /// descriptors are free again.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let used_idx = unsafe { ptr::read_volatile(self.field::<u16>(self.used + 2)) };
+
+        if used_idx == self.last_used {
+            return None;
+        }
+
+        // don't read the entry before the index that covers it:
+        dma::fence();
+
+        let slot = (self.last_used % self.size) as usize;
+        let entry = self.used + 4 + 8 * slot;
+
+        let (head, len) = unsafe {
+            (ptr::read_volatile(self.field::<u32>(entry)), ptr::read_volatile(self.field::<u32>(entry + 4)))
+        };
+
+        self.last_used = self.last_used.wrapping_add(1);
+
+        // put the chain back on the free list:
+        let head = head as u16;
+        let mut index = head;
+
+        loop {
+            self.free_count += 1;
+
+            let descriptor = self.descriptor(index);
+
+            if descriptor.flags & DESC_F_NEXT == 0 {
+                descriptor.next = self.free_head;
+                break;
+            }
+
+            index = descriptor.next;
+        }
+
+        self.free_head = head;
+
+        Some((head, len))
+    }
+}
+
+fn align(value: usize, align: usize) -> usize {
+    (value + align - 1) / align * align
+}
@@ -0,0 +1,600 @@
+use core::cmp;
+use core::convert::TryInto;
+use core::ptr::{self, NonNull};
+use core::slice;
+
+use alloc_collections::btree_map::BTreeMap;
+use arrayvec::ArrayVec;
+use interface::{SysError, SysResult};
+
+use crate::config::{self, EXEC_ARGS_PAGES};
+use crate::critical::{self, Critical};
+use crate::fs::vfs::File;
+use crate::interrupt::TrapFrame;
+use crate::mem::kalloc::GlobalAlloc;
+use crate::mem::kvirt;
+use crate::mem::MemoryExhausted;
+use crate::mem::page::{self, PageCtx, PageFlags, PAGE_SIZE};
+use crate::mem::phys::{self, Phys, PhysBlock};
+use crate::mem::user::{self, PageRange};
+use crate::mem::vma::{self, Backing, Vma, VmaError};
+use crate::random;
+
+/// The initial user stack occupies the pages immediately below this address,
+/// which must be in the user half. ASLR moves it down by up to
+/// ASLR_STACK_PAGES.
+pub const USER_STACK_TOP: u64 = 0x8000_0000;
+pub const USER_STACK_PAGES: u64 = 16;
+
+const ARGS_SIZE: usize = EXEC_ARGS_PAGES * PAGE_SIZE;
+
+const MAX_PROGRAM_HEADERS: usize = 16;
+
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 0x3e;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+#[derive(Debug)]
+pub enum ExecError {
+    Read(SysError),
+    BadFormat,
+    MemoryExhausted,
+    // some page would have been both writable and executable:
+    WriteExecute,
+}
+
+impl From<MemoryExhausted> for ExecError {
+    fn from(_: MemoryExhausted) -> Self {
+        ExecError::MemoryExhausted
+    }
+}
+
+impl From<ExecError> for SysError {
+    fn from(e: ExecError) -> Self {
+        match e {
+            ExecError::Read(e) => e,
+            ExecError::BadFormat => SysError::BadExecutable,
+            ExecError::MemoryExhausted => SysError::MemoryExhausted,
+            ExecError::WriteExecute => SysError::PermissionDenied,
+        }
+    }
+}
+
+/// A loaded executable, ready to be run by a task.
+#[derive(Debug)]
+pub struct Image {
+    pub page_ctx: PageCtx,
+    pub entry: u64,
+    pub stack: u64,
+    argc: u64,
+    argv: u64,
+    envp: u64,
+}
+
+impl Image {
+    /// A trap frame entering the program with argc, argv and envp in rdi,
+    /// rsi and rdx, as well as on the stack.
+    pub fn trap_frame(&self) -> TrapFrame {
+        let mut frame = TrapFrame::new(self.entry, self.stack);
+        frame.regs.rdi = self.argc;
+        frame.regs.rsi = self.argv;
+        frame.regs.rdx = self.envp;
+        frame
+    }
+}
+
+/// Where a new program's stack, heap and mappings go. With ASLR, each is
+/// moved by a random number of pages within the ranges in config.
+#[derive(Debug, Clone, Copy)]
+pub struct Layout {
+    stack_top: u64,
+    // how far past the end of the executable's segments the heap starts:
+    heap_offset: u64,
+    mmap_base: u64,
+}
+
+impl Layout {
+    /// A layout picked at random if `aslr`, and otherwise the same one every
+    /// time.
+    pub fn new(aslr: bool) -> Layout {
+        if !aslr {
+            return Layout {
+                stack_top: USER_STACK_TOP,
+                heap_offset: 0,
+                mmap_base: vma::MMAP_BASE,
+            };
+        }
+
+        Layout {
+            stack_top: USER_STACK_TOP - random_pages(config::ASLR_STACK_PAGES),
+            heap_offset: random_pages(config::ASLR_HEAP_PAGES),
+            mmap_base: vma::MMAP_BASE + random_pages(config::ASLR_MMAP_PAGES),
+        }
+    }
+
+    // where the arguments go, at the very top of the stack:
+    fn args_base(&self) -> u64 {
+        self.stack_top - ARGS_SIZE as u64
+    }
+}
+
+// a random number of pages below `pages`, in bytes:
+fn random_pages(pages: usize) -> u64 {
+    (random::next_u64() % pages as u64) * PAGE_SIZE as u64
+}
+
+/// The arguments and environment of a new program, laid out at the top of
+/// its initial stack the way the SysV ABI has them: argc at the stack
+/// pointer, followed by the NULL terminated argv and envp arrays and an empty
+/// auxiliary vector, with the strings themselves above. The stack's top, and
+/// so where they end up, is decided by `layout`.
+pub struct Args {
+    layout: Layout,
+    block: PhysBlock,
+    virt: NonNull<u8>,
+    // strings are copied in from the bottom of the block, and moved to the
+    // top once they're all in:
+    strings_len: usize,
+    argc: u64,
+    envc: u64,
+    // user addresses, set by lay_out:
+    stack: u64,
+    argv: u64,
+    envp: u64,
+}
+
+impl Args {
+    /// No arguments and an empty environment.
+    pub fn new(layout: Layout) -> Result<Args, MemoryExhausted> {
+        let mut args = Args::alloc(layout)?;
+
+        args.lay_out()
+            .expect("Args::new: no room for empty argv and envp");
+
+        Ok(args)
+    }
+
+    /// Copies `argv` and `envp` from the current address space. Each is a
+    /// NULL terminated array of pointers to NUL terminated strings, or 0 for
+    /// none. Fails with ArgumentsTooLong if they don't fit in
+    /// EXEC_ARGS_PAGES.
+    pub fn copy_from_user(argv: u64, envp: u64, layout: Layout, crit: &Critical) -> SysResult<Args> {
+        let mut args = Args::alloc(layout)?;
+
+        args.argc = args.push_strings(argv, crit)?;
+        args.envc = args.push_strings(envp, crit)?;
+        args.lay_out()?;
+
+        Ok(args)
+    }
+
+    fn alloc(layout: Layout) -> Result<Args, MemoryExhausted> {
+        let block = phys::alloc_contiguous(EXEC_ARGS_PAGES)?;
+        let virt = kvirt::map_block(&block)?;
+
+        Ok(Args {
+            layout,
+            block,
+            virt,
+            strings_len: 0,
+            argc: 0,
+            envc: 0,
+            stack: 0,
+            argv: 0,
+            envp: 0,
+        })
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.virt.as_ptr(), ARGS_SIZE) }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.virt.as_ptr(), ARGS_SIZE) }
+    }
+
+    // copies in the strings of a user array, returning how many there were:
+    fn push_strings(&mut self, array: u64, crit: &Critical) -> SysResult<u64> {
+        if array == 0 {
+            return Ok(0);
+        }
+
+        let mut count = 0;
+
+        loop {
+            let entry = array.checked_add(count * 8)
+                .ok_or(SysError::BadPointer)?;
+
+            let mut string = [0u8; 8];
+            user::copy_from_user(&mut string, entry, crit)?;
+
+            let string = u64::from_ne_bytes(string);
+
+            if string == 0 {
+                return Ok(count);
+            }
+
+            let start = self.strings_len;
+            let dst = &mut self.as_mut_slice()[start..];
+            let len = user::strncpy_from_user(dst, string, crit)?;
+
+            // the NUL has to fit too:
+            if len == dst.len() {
+                return Err(SysError::ArgumentsTooLong);
+            }
+
+            self.strings_len += len + 1;
+            count += 1;
+        }
+    }
+
+    fn lay_out(&mut self) -> SysResult<()> {
+        let strings_start = ARGS_SIZE - self.strings_len;
+
+        // argc, argv and envp with their NULLs, then AT_NULL's two words:
+        let words = 1 + (self.argc + 1) + (self.envc + 1) + 2;
+
+        let vectors_start = strings_start.checked_sub(words as usize * 8)
+            .ok_or(SysError::ArgumentsTooLong)? & !15;
+
+        unsafe {
+            let block = self.virt.as_ptr();
+            ptr::copy(block, block.add(strings_start), self.strings_len);
+            ptr::write_bytes(block, 0, strings_start);
+        }
+
+        let args_base = self.layout.args_base();
+        let mut pos = vectors_start;
+        let mut string = strings_start;
+
+        self.write_word(&mut pos, self.argc);
+        self.argv = args_base + pos as u64;
+
+        for _ in 0..self.argc {
+            self.write_word(&mut pos, args_base + string as u64);
+            string = self.skip_string(string);
+        }
+
+        self.write_word(&mut pos, 0);
+        self.envp = args_base + pos as u64;
+
+        for _ in 0..self.envc {
+            self.write_word(&mut pos, args_base + string as u64);
+            string = self.skip_string(string);
+        }
+
+        self.write_word(&mut pos, 0);
+
+        // the auxiliary vector, with nothing before AT_NULL:
+        self.write_word(&mut pos, 0);
+        self.write_word(&mut pos, 0);
+
+        self.stack = args_base + vectors_start as u64;
+
+        Ok(())
+    }
+
+    fn write_word(&mut self, pos: &mut usize, word: u64) {
+        let at = *pos;
+        self.as_mut_slice()[at..(at + 8)].copy_from_slice(&word.to_ne_bytes());
+        *pos += 8;
+    }
+
+    // returns where the string after the one at `pos` starts:
+    fn skip_string(&self, pos: usize) -> usize {
+        let len = self.as_slice()[pos..].iter()
+            .position(|byte| *byte == 0)
+            .expect("Args::skip_string: string without a NUL");
+
+        pos + len + 1
+    }
+}
+
+impl Drop for Args {
+    fn drop(&mut self) {
+        // the pages go back to the allocator once the block and any mappings
+        // in a page context go too:
+        unsafe { kvirt::free_pages(self.virt, self.block.pages()); }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    offset: u64,
+    vaddr: u64,
+    filesz: u64,
+    memsz: u64,
+    flags: PageFlags,
+}
+
+/// Reads a static ELF64 executable from `file` into a fresh page context,
+/// along with an initial user stack holding `args`. Unless `write_execute`,
+/// no page may end up both writable and executable, so code has to be in
+/// segments of its own.
+pub async fn load(file: &File, args: &Args, write_execute: bool) -> Result<Image, ExecError> {
+    let mut reader = Reader { file, pos: 0 };
+
+    let mut header = [0u8; ELF_HEADER_SIZE];
+    reader.read_exact_at(0, &mut header).await?;
+
+    if &header[0..4] != b"\x7fELF"
+        || header[4] != ELFCLASS64
+        || header[5] != ELFDATA2LSB
+        || read_u16(&header, 16) != ET_EXEC
+        || read_u16(&header, 18) != EM_X86_64
+        || read_u16(&header, 54) as usize != PROGRAM_HEADER_SIZE
+    {
+        return Err(ExecError::BadFormat);
+    }
+
+    let entry = read_u64(&header, 24);
+
+    // segments are checked as they're loaded, but nothing else stops the
+    // entry point from pointing into the kernel:
+    if !page::is_user_addr(entry) {
+        return Err(ExecError::BadFormat);
+    }
+    let phoff = read_u64(&header, 32);
+    let phnum = read_u16(&header, 56) as usize;
+
+    if phnum > MAX_PROGRAM_HEADERS {
+        return Err(ExecError::BadFormat);
+    }
+
+    let mut program_headers = [0u8; PROGRAM_HEADER_SIZE * MAX_PROGRAM_HEADERS];
+    let program_headers = &mut program_headers[0..(PROGRAM_HEADER_SIZE * phnum)];
+    reader.read_exact_at(phoff, program_headers).await?;
+
+    let mut segments = ArrayVec::<[Segment; MAX_PROGRAM_HEADERS]>::new();
+
+    for ph in program_headers.chunks(PROGRAM_HEADER_SIZE) {
+        if read_u32(ph, 0) != PT_LOAD {
+            continue;
+        }
+
+        let mut flags = PageFlags::PRESENT | PageFlags::USER;
+
+        if read_u32(ph, 4) & PF_W != 0 {
+            flags.insert(PageFlags::WRITE);
+        }
+
+        if read_u32(ph, 4) & PF_X == 0 {
+            flags.insert(PageFlags::NO_EXECUTE);
+        }
+
+        let segment = Segment {
+            offset: read_u64(ph, 8),
+            vaddr: read_u64(ph, 16),
+            filesz: read_u64(ph, 32),
+            memsz: read_u64(ph, 40),
+            flags,
+        };
+
+        if segment.filesz > segment.memsz {
+            return Err(ExecError::BadFormat);
+        }
+
+        // never fails, we can't have more segments than program headers:
+        segments.push(segment);
+    }
+
+    // files can only be read forwards, so load segments in file order:
+    segments.sort_unstable_by_key(|segment| segment.offset);
+
+    let mut pages = BTreeMap::<u64, (Phys, PageFlags), GlobalAlloc>::new();
+
+    for segment in segments.iter() {
+        load_segment(&mut reader, segment, &mut pages).await?;
+    }
+
+    // checked once the pages are all in, as segments sharing a page get the
+    // permissions of both:
+    if !write_execute && pages.values().any(|(_, flags)| is_write_execute(*flags)) {
+        return Err(ExecError::WriteExecute);
+    }
+
+    // segment contents come from the file, which can't be read from the page
+    // fault handler, but the stack is only mapped in as it's used, apart from
+    // the arguments at the top:
+    let stack_flags = PageFlags::PRESENT | PageFlags::WRITE | PageFlags::USER | PageFlags::NO_EXECUTE;
+
+    let layout = args.layout;
+
+    let stack = Vma {
+        start: layout.stack_top - USER_STACK_PAGES * PAGE_SIZE as u64,
+        end: layout.stack_top,
+        flags: stack_flags,
+        backing: Backing::Anonymous,
+    };
+
+    // the stack must not overlap any segment:
+    if pages.range(stack.start..stack.end).next().is_some() {
+        return Err(ExecError::BadFormat);
+    }
+
+    let page_ctx = PageCtx::new()?;
+
+    page_ctx.map_pages(pages.iter().map(|(virt, (phys, flags))| (*virt, phys, *flags)))
+        .map_err(|_| ExecError::MemoryExhausted)?;
+
+    page_ctx.add_vma(stack)
+        .map_err(|e| match e {
+            VmaError::Overlap => ExecError::BadFormat,
+            VmaError::MemoryExhausted => ExecError::MemoryExhausted,
+        })?;
+
+    let arg_pages = (0..EXEC_ARGS_PAGES)
+        .map(|index| args.block.page(index))
+        .collect::<ArrayVec<[Phys; EXEC_ARGS_PAGES]>>();
+
+    page_ctx.map_pages(arg_pages.iter().enumerate()
+        .map(|(index, phys)| (layout.args_base() + (index * PAGE_SIZE) as u64, phys, stack_flags)))
+        .map_err(|_| ExecError::MemoryExhausted)?;
+
+    // the heap starts after the last segment, as soon after as the layout
+    // has it without running into the stack:
+    let heap_start = pages.keys()
+        .next_back()
+        .map(|page| page + PAGE_SIZE as u64)
+        .unwrap_or(0);
+
+    let heap_start = match heap_start.checked_add(layout.heap_offset) {
+        Some(start) if start < stack.start => start,
+        _ => heap_start,
+    };
+
+    let mut vmas = page_ctx.vmas().lock();
+    vmas.set_heap_start(heap_start);
+    vmas.set_mmap_base(layout.mmap_base);
+    drop(vmas);
+
+    Ok(Image {
+        page_ctx,
+        entry,
+        stack: args.stack,
+        argc: args.argc,
+        argv: args.argv,
+        envp: args.envp,
+    })
+}
+
+async fn load_segment(
+    reader: &mut Reader<'_>,
+    segment: &Segment,
+    pages: &mut BTreeMap<u64, (Phys, PageFlags), GlobalAlloc>,
+) -> Result<(), ExecError> {
+    let range = PageRange::containing(segment.vaddr, segment.memsz)
+        .map_err(|_| ExecError::BadFormat)?;
+
+    let file_start = segment.vaddr;
+    let file_end = segment.vaddr + segment.filesz;
+
+    let mut buff = [0u8; PAGE_SIZE];
+
+    for page in range.pages() {
+        // segments may share a page at their edges, in which case the page
+        // gets the union of their permissions. no execute is the absence of
+        // one, so it's only kept if both have it:
+        let phys = match pages.get_mut(&page) {
+            Some((phys, flags)) => {
+                let no_execute = flags.contains(PageFlags::NO_EXECUTE)
+                    && segment.flags.contains(PageFlags::NO_EXECUTE);
+                flags.insert(segment.flags);
+                flags.set(PageFlags::NO_EXECUTE, no_execute);
+                phys.clone()
+            }
+            None => {
+                let phys = alloc_zeroed()?;
+
+                pages.insert(page, (phys.clone(), segment.flags))
+                    .map_err(|_| ExecError::MemoryExhausted)?;
+
+                phys
+            }
+        };
+
+        // the part of this page backed by file contents, the rest is left
+        // zeroed:
+        let start = cmp::max(page, file_start);
+        let end = cmp::min(page + PAGE_SIZE as u64, file_end);
+
+        if start >= end {
+            continue;
+        }
+
+        let len = (end - start) as usize;
+        let buff = &mut buff[0..len];
+
+        reader.read_exact_at(segment.offset + (start - segment.vaddr), buff).await?;
+
+        unsafe {
+            let crit = critical::begin();
+            let mapped = page::temp_map::<u8>(phys.raw(), &crit);
+            let dest = mapped.ptr().add((start - page) as usize);
+            ptr::copy_nonoverlapping(buff.as_ptr(), dest, len);
+        }
+    }
+
+    Ok(())
+}
+
+fn is_write_execute(flags: PageFlags) -> bool {
+    flags.contains(PageFlags::WRITE) && !flags.contains(PageFlags::NO_EXECUTE)
+}
+
+fn alloc_zeroed() -> Result<Phys, MemoryExhausted> {
+    let phys = phys::alloc()?;
+
+    unsafe {
+        let crit = critical::begin();
+        let mapped = page::temp_map::<u8>(phys.raw(), &crit);
+        ptr::write_bytes(mapped.ptr(), 0, PAGE_SIZE);
+    }
+
+    Ok(phys)
+}
+
+/// Adapts a File, which can only be read sequentially, to reads at increasing
+/// offsets.
+struct Reader<'a> {
+    file: &'a File,
+    pos: u64,
+}
+
+impl<'a> Reader<'a> {
+    async fn read_exact_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), ExecError> {
+        if offset < self.pos {
+            return Err(ExecError::BadFormat);
+        }
+
+        // skip forward to offset:
+        let mut scratch = [0u8; 512];
+
+        while self.pos < offset {
+            let len = cmp::min(scratch.len() as u64, offset - self.pos) as usize;
+            self.read_some(&mut scratch[0..len]).await?;
+        }
+
+        let mut buf = buf;
+
+        while buf.len() > 0 {
+            let read = self.read_some(buf).await?;
+            buf = &mut buf[read..];
+        }
+
+        Ok(())
+    }
+
+    async fn read_some(&mut self, buf: &mut [u8]) -> Result<usize, ExecError> {
+        let read = self.file.read(buf).await
+            .map_err(ExecError::Read)?;
+
+        if read == 0 {
+            // unexpected EOF:
+            return Err(ExecError::BadFormat);
+        }
+
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..(offset + 2)].try_into().expect("read_u16"))
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..(offset + 4)].try_into().expect("read_u32"))
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..(offset + 8)].try_into().expect("read_u64"))
+}
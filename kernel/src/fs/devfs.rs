@@ -0,0 +1,356 @@
+use core::any::Any;
+use core::fmt::{self, Debug};
+use core::task::Waker;
+
+use alloc_collections::btree_map::BTreeMap;
+use arrayvec::ArrayVec;
+use interface::{SysError, SysResult};
+
+use crate::config::DEVFS_NAME_MAX;
+use crate::console::tty;
+use crate::fs::vfs::{self, DirSink, File, FsFuture, InodeKind, Permissions, SeekFrom, Stat};
+use crate::mem::kalloc::GlobalAlloc;
+use crate::mem::MemoryExhausted;
+use crate::object::poll::Events;
+use crate::sync::{Arc, AsyncMutex, Mutex};
+use crate::util::EarlyInit;
+
+type Name = ArrayVec<[u8; DEVFS_NAME_MAX]>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceKind {
+    /// A stream of bytes, like a terminal, with no position to seek to.
+    Character,
+    /// Randomly addressable storage, like a disk.
+    #[allow(unused)]
+    Block,
+}
+
+/// A driver's device, as registered with devfs. Opening its node gives a
+/// file that reads, writes and ioctls go straight through to.
+pub trait Device: Debug {
+    fn kind(&self) -> DeviceKind;
+
+    /// The size of a block device's storage, in bytes.
+    fn size(&self) -> u64 {
+        0
+    }
+
+    /// Reads from `pos`, which character devices ignore.
+    fn read<'a>(&'a self, pos: u64, buf: &'a mut [u8]) -> FsFuture<'a, usize>;
+
+    /// Writes at `pos`, which character devices ignore.
+    fn write<'a>(&'a self, _pos: u64, _buf: &'a [u8]) -> FsFuture<'a, usize> {
+        FsFuture::new(async { Err(SysError::InvalidOperation) })
+    }
+
+    /// Performs a driver specific request.
+    fn ioctl(&self, _request: u64, _arg: u64) -> FsFuture<'_, u64> {
+        FsFuture::new(async { Err(SysError::InvalidOperation) })
+    }
+
+    /// The events the device is ready for, with `waker` woken when that may
+    /// have changed.
+    fn poll_ready(&self, _waker: &Waker) -> Result<Events, MemoryExhausted> {
+        Ok(Events::IN | Events::OUT)
+    }
+}
+
+// every registered device, by the name of its node:
+static DEVICES: EarlyInit<Mutex<BTreeMap<Name, Arc<dyn Device>, GlobalAlloc>>> = EarlyInit::new();
+
+/// Sets up the device table and registers the devices devfs provides
+/// itself: console, null and zero.
+pub fn init() {
+    EarlyInit::set(&DEVICES, Mutex::new(BTreeMap::new()));
+
+    register(b"console", Arc::new(Console).expect("devfs::init: Arc::new"))
+        .expect("devfs::init: register console");
+
+    register(b"null", Arc::new(Null).expect("devfs::init: Arc::new"))
+        .expect("devfs::init: register null");
+
+    register(b"zero", Arc::new(Zero).expect("devfs::init: Arc::new"))
+        .expect("devfs::init: register zero");
+}
+
+/// Adds a node for `device` to /dev. Fails with AlreadyMapped if the name
+/// is taken.
+pub fn register(name: &[u8], device: Arc<dyn Device>) -> SysResult<()> {
+    let name = self::name(name)?;
+
+    if name.is_empty() || name.contains(&b'/') {
+        return Err(SysError::IllegalValue);
+    }
+
+    let mut devices = DEVICES.lock();
+
+    if devices.contains_key(&name) {
+        return Err(SysError::AlreadyMapped);
+    }
+
+    devices.insert(name, device)
+        .map_err(|_| SysError::MemoryExhausted)?;
+
+    Ok(())
+}
+
+/// Removes a device's node. Files already open on it stay usable.
+#[allow(unused)]
+pub fn unregister(name: &[u8]) -> SysResult<()> {
+    let name = self::name(name).map_err(|_| SysError::NoFile)?;
+
+    DEVICES.lock().remove(&name)
+        .map(|_| ())
+        .ok_or(SysError::NoFile)
+}
+
+fn name(name: &[u8]) -> SysResult<Name> {
+    let mut buf = Name::new();
+
+    for byte in name {
+        buf.try_push(*byte)
+            .map_err(|_| SysError::IllegalValue)?;
+    }
+
+    Ok(buf)
+}
+
+/// The filesystem of device nodes, one for each registered device, in a
+/// single flat directory.
+#[derive(Debug)]
+pub struct Devfs;
+
+enum Inode {
+    Root,
+    Node(Arc<dyn Device>),
+}
+
+struct Handle {
+    device: Arc<dyn Device>,
+    pos: AsyncMutex<u64>,
+}
+
+impl vfs::Filesystem for Devfs {
+    fn root(&self) -> SysResult<Arc<dyn vfs::Inode>> {
+        Ok(Arc::new(Inode::Root)?)
+    }
+}
+
+impl vfs::Inode for Inode {
+    fn kind(&self) -> InodeKind {
+        match self {
+            Inode::Root => InodeKind::Directory,
+            Inode::Node(_) => InodeKind::File,
+        }
+    }
+
+    fn lookup<'a>(&'a self, name: &'a [u8]) -> FsFuture<'a, Option<Arc<dyn vfs::Inode>>> {
+        FsFuture::new(async move {
+            if let Inode::Node(_) = self {
+                return Err(SysError::InvalidOperation);
+            }
+
+            let device = match self::name(name).ok().and_then(|name| DEVICES.lock().get(&name).cloned()) {
+                Some(device) => device,
+                None => return Ok(None),
+            };
+
+            let inode: Arc<dyn vfs::Inode> = Arc::new(Inode::Node(device))?;
+
+            Ok(Some(inode))
+        })
+    }
+
+    fn open(&self) -> FsFuture<'_, Arc<dyn vfs::FileHandle>> {
+        FsFuture::new(async move {
+            let device = match self {
+                Inode::Node(device) => device.clone(),
+                Inode::Root => return Err(SysError::InvalidOperation),
+            };
+
+            let handle: Arc<dyn vfs::FileHandle> = Arc::new(Handle {
+                device,
+                pos: AsyncMutex::new(0),
+            })?;
+
+            Ok(handle)
+        })
+    }
+
+    // anyone may use a character device, but only root may get at a disk
+    // without going through its filesystem:
+    fn stat(&self) -> FsFuture<'_, Stat> {
+        FsFuture::new(async move {
+            Ok(match self {
+                Inode::Root => Stat {
+                    kind: InodeKind::Directory,
+                    size: 0,
+                    permissions: Permissions::root(0o755),
+                },
+                Inode::Node(device) => Stat {
+                    kind: InodeKind::File,
+                    size: device.size(),
+                    permissions: match device.kind() {
+                        DeviceKind::Character => Permissions::root(0o666),
+                        DeviceKind::Block => Permissions::root(0o600),
+                    },
+                },
+            })
+        })
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    // positions are indexes into the devices, which are kept in name order:
+    fn read_dir<'a>(&'a self, pos: u64, sink: &'a mut dyn DirSink) -> FsFuture<'a, ()> {
+        FsFuture::new(async move {
+            if let Inode::Node(_) = self {
+                return Err(SysError::InvalidOperation);
+            }
+
+            let devices = DEVICES.lock();
+
+            for (index, name) in devices.keys().enumerate().skip(pos as usize) {
+                if !sink.push(name, InodeKind::File, index as u64 + 1) {
+                    break;
+                }
+            }
+
+            Ok(())
+        })
+    }
+}
+
+impl vfs::FileHandle for Handle {
+    fn read<'a>(&'a self, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        FsFuture::new(async move {
+            let mut pos = self.pos.lock().await?;
+            let count = self.device.read(*pos, buf).await?;
+
+            if self.device.kind() == DeviceKind::Block {
+                *pos += count as u64;
+            }
+
+            Ok(count)
+        })
+    }
+
+    fn write<'a>(&'a self, buf: &'a [u8]) -> FsFuture<'a, usize> {
+        FsFuture::new(async move {
+            let mut pos = self.pos.lock().await?;
+            let count = self.device.write(*pos, buf).await?;
+
+            if self.device.kind() == DeviceKind::Block {
+                *pos += count as u64;
+            }
+
+            Ok(count)
+        })
+    }
+
+    fn seek(&self, from: SeekFrom) -> FsFuture<'_, u64> {
+        FsFuture::new(async move {
+            if self.device.kind() != DeviceKind::Block {
+                return Err(SysError::InvalidOperation);
+            }
+
+            let mut pos = self.pos.lock().await?;
+            *pos = from.resolve(*pos, self.device.size())?;
+
+            Ok(*pos)
+        })
+    }
+
+    fn ioctl(&self, request: u64, arg: u64) -> FsFuture<'_, u64> {
+        self.device.ioctl(request, arg)
+    }
+
+    fn poll_ready(&self, waker: &Waker) -> Result<Events, MemoryExhausted> {
+        self.device.poll_ready(waker)
+    }
+}
+
+/// The keyboard and screen, the same as the console handle init starts
+/// with.
+#[derive(Debug)]
+struct Console;
+
+impl Device for Console {
+    fn kind(&self) -> DeviceKind {
+        DeviceKind::Character
+    }
+
+    fn read<'a>(&'a self, _pos: u64, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        FsFuture::new(async move { File::Console.read(buf).await })
+    }
+
+    fn write<'a>(&'a self, _pos: u64, buf: &'a [u8]) -> FsFuture<'a, usize> {
+        FsFuture::new(async move { File::Console.write(buf).await })
+    }
+
+    fn ioctl(&self, request: u64, arg: u64) -> FsFuture<'_, u64> {
+        FsFuture::new(async move { File::Console.ioctl(request, arg).await })
+    }
+
+    fn poll_ready(&self, waker: &Waker) -> Result<Events, MemoryExhausted> {
+        let readable = tty::poll_readable(waker)?;
+        Ok(Events::OUT | if readable { Events::IN } else { Events::empty() })
+    }
+}
+
+/// Reads nothing, and discards whatever is written.
+#[derive(Debug)]
+struct Null;
+
+impl Device for Null {
+    fn kind(&self) -> DeviceKind {
+        DeviceKind::Character
+    }
+
+    fn read<'a>(&'a self, _pos: u64, _buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        FsFuture::new(async { Ok(0) })
+    }
+
+    fn write<'a>(&'a self, _pos: u64, buf: &'a [u8]) -> FsFuture<'a, usize> {
+        FsFuture::new(async move { Ok(buf.len()) })
+    }
+}
+
+/// Reads as zeros, and discards whatever is written.
+#[derive(Debug)]
+struct Zero;
+
+impl Device for Zero {
+    fn kind(&self) -> DeviceKind {
+        DeviceKind::Character
+    }
+
+    fn read<'a>(&'a self, _pos: u64, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        FsFuture::new(async move {
+            buf.iter_mut().for_each(|byte| *byte = 0);
+            Ok(buf.len())
+        })
+    }
+
+    fn write<'a>(&'a self, _pos: u64, buf: &'a [u8]) -> FsFuture<'a, usize> {
+        FsFuture::new(async move { Ok(buf.len()) })
+    }
+}
+
+impl Debug for Inode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Inode::Root => write!(f, "devfs::Inode(/)"),
+            Inode::Node(device) => write!(f, "devfs::Inode({:?})", device),
+        }
+    }
+}
+
+impl Debug for Handle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "devfs::Handle({:?})", self.device)
+    }
+}
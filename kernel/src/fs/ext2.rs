@@ -0,0 +1,631 @@
+use core::any::Any;
+use core::cmp;
+use core::convert::TryInto;
+use core::fmt::{self, Debug};
+use core::slice;
+
+use arrayvec::ArrayVec;
+use interface::{SysError, SysResult};
+
+use crate::device::block::{BlockError, Disk, Sector};
+use crate::fs::vfs::{self, DirSink, FsFuture, InodeKind, Permissions, SeekFrom, Stat};
+use crate::sync::{Arc, AsyncMutex};
+
+const SECTOR_SIZE: usize = 512;
+
+// the superblock is always 1024 bytes in, whatever the block size:
+const SUPERBLOCK_OFFSET: usize = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const EXT2_MAGIC: u16 = 0xef53;
+
+// blocks are read whole into a buffer of this size, so larger ones aren't
+// supported. mke2fs never picks more than 4096 by itself:
+const BLOCK_SIZE_MAX: usize = 4096;
+const SECTORS_PER_BLOCK_MAX: usize = BLOCK_SIZE_MAX / SECTOR_SIZE;
+
+const GROUP_DESCRIPTOR_SIZE: usize = 32;
+const ROOT_INODE: u32 = 2;
+
+// revision 0 filesystems have fixed size inodes:
+const GOOD_OLD_REV: u32 = 0;
+const GOOD_OLD_INODE_SIZE: usize = 128;
+
+// the only incompatible feature understood is directory entries recording
+// the type of what they point at. anything else, like ext3 journal recovery
+// or ext4 extents, changes how the filesystem has to be read:
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE;
+
+// the file type bits of an inode's mode:
+const S_IFMT: u16 = 0o170000;
+const S_IFDIR: u16 = 0o040000;
+const S_IFREG: u16 = 0o100000;
+
+// an inode's block list has 12 direct blocks, then a singly, doubly and
+// triply indirect one:
+const DIRECT_BLOCKS: u64 = 12;
+const SINGLE_INDIRECT: usize = 12;
+const DOUBLE_INDIRECT: usize = 13;
+const TRIPLE_INDIRECT: usize = 14;
+
+const DIR_ENTRY_HEADER_SIZE: usize = 8;
+
+// the type a directory entry gives with the filetype feature, for
+// directories:
+const FT_DIR: u8 = 2;
+
+/// A read-only ext2 filesystem on a partition, as made by `mke2fs -t ext2`.
+pub struct Ext2 {
+    volume: Arc<Volume>,
+    // read when opened, since Filesystem::root can't wait on the disk:
+    root: InodeData,
+}
+
+struct Volume {
+    part: Arc<Disk>,
+    geometry: Geometry,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Geometry {
+    block_size: usize,
+    block_count: u32,
+    inode_count: u32,
+    inodes_per_group: u32,
+    inode_size: usize,
+    // where the block group descriptor table starts:
+    descriptor_block: u32,
+    // whether directory entries have a file type byte in place of the high
+    // byte of the name length:
+    filetype: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct InodeData {
+    mode: u16,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    blocks: [u32; 15],
+}
+
+/// An ext2 file or directory, as handed to the VFS.
+struct Inode {
+    volume: Arc<Volume>,
+    number: u32,
+    data: InodeData,
+}
+
+/// An ext2 file opened through the VFS.
+struct Handle {
+    volume: Arc<Volume>,
+    data: InodeData,
+    pos: AsyncMutex<u64>,
+}
+
+// an entry of a directory block:
+struct DirEntry<'b> {
+    inode: u32,
+    rec_len: usize,
+    name: &'b [u8],
+    file_type: Option<u8>,
+}
+
+// a whole block, read sector by sector:
+struct Block {
+    sectors: [Sector; SECTORS_PER_BLOCK_MAX],
+    size: usize,
+}
+
+impl Ext2 {
+    /// Checks whether a partition holds an ext2 filesystem this driver can
+    /// read.
+    pub async fn probe(part: &Disk) -> Result<bool, BlockError> {
+        let superblock = match read_superblock(part).await? {
+            Some(superblock) => superblock,
+            None => return Ok(false),
+        };
+
+        Ok(Geometry::parse(&superblock, part.block_count() as usize).is_ok())
+    }
+
+    /// Opens the ext2 filesystem on a partition. Fails with IllegalValue if
+    /// it isn't one, or it uses features that aren't supported.
+    pub async fn open(part: Arc<Disk>) -> SysResult<Ext2> {
+        let superblock = read_superblock(&part).await?
+            .ok_or(SysError::IllegalValue)?;
+
+        let geometry = Geometry::parse(&superblock, part.block_count() as usize)?;
+        let volume = Arc::new(Volume { part, geometry })?;
+
+        let root = volume.inode(ROOT_INODE).await?;
+
+        if root.mode & S_IFMT != S_IFDIR {
+            return Err(SysError::IllegalValue);
+        }
+
+        Ok(Ext2 { volume, root })
+    }
+}
+
+impl vfs::Filesystem for Ext2 {
+    fn root(&self) -> SysResult<Arc<dyn vfs::Inode>> {
+        Ok(Arc::new(Inode {
+            volume: self.volume.clone(),
+            number: ROOT_INODE,
+            data: self.root,
+        })?)
+    }
+}
+
+// the superblock's two sectors, or None if the partition is too small to
+// have one:
+async fn read_superblock(part: &Disk) -> Result<Option<[Sector; 2]>, BlockError> {
+    let first = SUPERBLOCK_OFFSET / SECTOR_SIZE;
+
+    if part.block_count() as usize < first + SUPERBLOCK_SIZE / SECTOR_SIZE {
+        return Ok(None);
+    }
+
+    let mut superblock = [[0u8; SECTOR_SIZE]; 2];
+
+    {
+        let (a, b) = superblock.split_at_mut(1);
+        part.read_blocks(first as u64, &mut [&mut a[0], &mut b[0]]).await?;
+    }
+
+    Ok(Some(superblock))
+}
+
+impl Geometry {
+    // reads what's needed from the superblock, refusing anything this
+    // driver can't read or that doesn't fit in `sectors`:
+    fn parse(superblock: &[Sector; 2], sectors: usize) -> SysResult<Geometry> {
+        let sb = unsafe {
+            slice::from_raw_parts(superblock.as_ptr() as *const u8, SUPERBLOCK_SIZE)
+        };
+
+        if read_u16(sb, 56) != EXT2_MAGIC {
+            return Err(SysError::IllegalValue);
+        }
+
+        let inode_count = read_u32(sb, 0);
+        let block_count = read_u32(sb, 4);
+        let first_data_block = read_u32(sb, 20);
+        let log_block_size = read_u32(sb, 24);
+        let inodes_per_group = read_u32(sb, 40);
+        let rev_level = read_u32(sb, 76);
+
+        // blocks are 1024 << log_block_size bytes, up to BLOCK_SIZE_MAX:
+        if log_block_size > 2 || inodes_per_group == 0 {
+            return Err(SysError::IllegalValue);
+        }
+
+        let block_size = 1024 << log_block_size;
+
+        let (inode_size, incompat) = if rev_level == GOOD_OLD_REV {
+            (GOOD_OLD_INODE_SIZE, 0)
+        } else {
+            (read_u16(sb, 88) as usize, read_u32(sb, 96))
+        };
+
+        if incompat & !INCOMPAT_SUPPORTED != 0 {
+            return Err(SysError::IllegalValue);
+        }
+
+        // each inode is read from a single sector:
+        if inode_size < GOOD_OLD_INODE_SIZE || inode_size > SECTOR_SIZE || !inode_size.is_power_of_two() {
+            return Err(SysError::IllegalValue);
+        }
+
+        if block_count as usize * (block_size / SECTOR_SIZE) > sectors {
+            return Err(SysError::IllegalValue);
+        }
+
+        Ok(Geometry {
+            block_size,
+            block_count,
+            inode_count,
+            inodes_per_group,
+            inode_size,
+            descriptor_block: first_data_block + 1,
+            filetype: incompat & INCOMPAT_FILETYPE != 0,
+        })
+    }
+}
+
+impl Volume {
+    fn sectors_per_block(&self) -> usize {
+        self.geometry.block_size / SECTOR_SIZE
+    }
+
+    // reads the sector `offset` bytes into `block`. blocks past the end
+    // come from a corrupt inode or directory:
+    async fn read_sector_of(&self, block: u32, offset: usize) -> SysResult<Sector> {
+        if block >= self.geometry.block_count {
+            return Err(SysError::IoError);
+        }
+
+        let lba = block as usize * self.sectors_per_block() + offset / SECTOR_SIZE;
+
+        let mut sector = [0u8; SECTOR_SIZE];
+        self.part.read_blocks(lba as u64, &mut [&mut sector]).await?;
+
+        Ok(sector)
+    }
+
+    async fn read_block(&self, block: u32) -> SysResult<Block> {
+        if block >= self.geometry.block_count {
+            return Err(SysError::IoError);
+        }
+
+        let mut buf = Block {
+            sectors: [[0u8; SECTOR_SIZE]; SECTORS_PER_BLOCK_MAX],
+            size: self.geometry.block_size,
+        };
+
+        let mut sectors = buf.sectors.iter_mut()
+            .take(self.sectors_per_block())
+            .collect::<ArrayVec<[&mut Sector; SECTORS_PER_BLOCK_MAX]>>();
+
+        let lba = block as usize * self.sectors_per_block();
+        self.part.read_blocks(lba as u64, &mut sectors).await?;
+
+        Ok(buf)
+    }
+
+    async fn inode(&self, number: u32) -> SysResult<InodeData> {
+        if number == 0 || number > self.geometry.inode_count {
+            return Err(SysError::IoError);
+        }
+
+        let group = (number - 1) / self.geometry.inodes_per_group;
+        let index = (number - 1) % self.geometry.inodes_per_group;
+
+        // the group's descriptor has where its inode table is:
+        let descriptor = group as usize * GROUP_DESCRIPTOR_SIZE;
+        let block = self.geometry.descriptor_block + (descriptor / self.geometry.block_size) as u32;
+        let offset = descriptor % self.geometry.block_size;
+
+        let sector = self.read_sector_of(block, offset).await?;
+        let inode_table = read_u32(&sector, offset % SECTOR_SIZE + 8);
+
+        let offset = index as usize * self.geometry.inode_size;
+        let block = inode_table + (offset / self.geometry.block_size) as u32;
+        let offset = offset % self.geometry.block_size;
+
+        let sector = self.read_sector_of(block, offset).await?;
+        let raw = &sector[(offset % SECTOR_SIZE)..];
+
+        let mode = read_u16(raw, 0);
+
+        // the high half of the size is only there for regular files, and
+        // means something else for directories:
+        let size_high = if mode & S_IFMT == S_IFREG { read_u32(raw, 108) } else { 0 };
+
+        let mut blocks = [0u32; 15];
+
+        for (i, block) in blocks.iter_mut().enumerate() {
+            *block = read_u32(raw, 40 + i * 4);
+        }
+
+        // the high halves of the owner's ids are where Linux puts them, in
+        // the OS dependent part at the end:
+        let uid = (read_u16(raw, 120) as u32) << 16 | read_u16(raw, 2) as u32;
+        let gid = (read_u16(raw, 122) as u32) << 16 | read_u16(raw, 24) as u32;
+
+        Ok(InodeData {
+            mode,
+            uid,
+            gid,
+            size: (size_high as u64) << 32 | read_u32(raw, 4) as u64,
+            blocks,
+        })
+    }
+
+    // the block holding block `index` of an inode's data, or 0 for a hole:
+    async fn data_block(&self, data: &InodeData, index: u64) -> SysResult<u32> {
+        if index < DIRECT_BLOCKS {
+            return Ok(data.blocks[index as usize]);
+        }
+
+        let per_block = (self.geometry.block_size / 4) as u64;
+        let levels = [SINGLE_INDIRECT, DOUBLE_INDIRECT, TRIPLE_INDIRECT];
+
+        let mut index = index - DIRECT_BLOCKS;
+        // how many blocks each level of indirection reaches:
+        let mut span = per_block;
+
+        for (depth, top) in levels.iter().enumerate() {
+            if index < span {
+                return self.indirect_block(data.blocks[*top], index, depth as u32 + 1).await;
+            }
+
+            index -= span;
+            span *= per_block;
+        }
+
+        Err(SysError::IllegalValue)
+    }
+
+    // follows `depth` levels of indirect blocks down from `block`:
+    async fn indirect_block(&self, mut block: u32, index: u64, depth: u32) -> SysResult<u32> {
+        let per_block = (self.geometry.block_size / 4) as u64;
+
+        for level in (0..depth).rev() {
+            if block == 0 {
+                break;
+            }
+
+            let offset = (index / per_block.pow(level) % per_block) as usize * 4;
+            let sector = self.read_sector_of(block, offset).await?;
+
+            block = read_u32(&sector, offset % SECTOR_SIZE);
+        }
+
+        Ok(block)
+    }
+
+    // finds a directory's entry by name, returning its inode number:
+    async fn find(&self, dir: &InodeData, name: &[u8]) -> SysResult<Option<u32>> {
+        let block_size = self.geometry.block_size as u64;
+        let blocks = (dir.size + block_size - 1) / block_size;
+
+        for index in 0..blocks {
+            let block = match self.data_block(dir, index).await? {
+                0 => continue,
+                block => self.read_block(block).await?,
+            };
+
+            let bytes = block.as_slice();
+            let mut offset = 0;
+
+            while offset + DIR_ENTRY_HEADER_SIZE <= bytes.len() {
+                let entry = self.dir_entry(bytes, offset)?;
+
+                // deleted entries are left with an inode of 0:
+                if entry.inode != 0 && entry.name == name {
+                    return Ok(Some(entry.inode));
+                }
+
+                offset += entry.rec_len;
+            }
+        }
+
+        Ok(None)
+    }
+
+    // lists a directory's entries from `pos`, a byte offset into it that's
+    // the start of an entry:
+    async fn list(&self, dir: &InodeData, pos: u64, sink: &mut dyn DirSink) -> SysResult<()> {
+        let block_size = self.geometry.block_size as u64;
+        let blocks = (dir.size + block_size - 1) / block_size;
+
+        for index in (pos / block_size)..blocks {
+            let block = match self.data_block(dir, index).await? {
+                0 => continue,
+                block => self.read_block(block).await?,
+            };
+
+            let bytes = block.as_slice();
+            let mut offset = if index == pos / block_size { (pos % block_size) as usize } else { 0 };
+
+            while offset + DIR_ENTRY_HEADER_SIZE <= bytes.len() {
+                let entry = self.dir_entry(bytes, offset)?;
+                offset += entry.rec_len;
+
+                if entry.inode == 0 {
+                    continue;
+                }
+
+                // without the filetype feature, only the inode knows:
+                let kind = match entry.file_type {
+                    Some(FT_DIR) => InodeKind::Directory,
+                    Some(_) => InodeKind::File,
+                    None => self.inode(entry.inode).await?.kind(),
+                };
+
+                if !sink.push(entry.name, kind, index * block_size + offset as u64) {
+                    return Ok(());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // the directory entry at `offset` in a directory block:
+    fn dir_entry<'b>(&self, bytes: &'b [u8], offset: usize) -> SysResult<DirEntry<'b>> {
+        let inode = read_u32(bytes, offset);
+        let rec_len = read_u16(bytes, offset + 4) as usize;
+
+        let (name_len, file_type) = if self.geometry.filetype {
+            (bytes[offset + 6] as usize, Some(bytes[offset + 7]))
+        } else {
+            (read_u16(bytes, offset + 6) as usize, None)
+        };
+
+        if rec_len < DIR_ENTRY_HEADER_SIZE || offset + rec_len > bytes.len()
+            || DIR_ENTRY_HEADER_SIZE + name_len > rec_len
+        {
+            return Err(SysError::IoError);
+        }
+
+        let name = &bytes[(offset + DIR_ENTRY_HEADER_SIZE)..][..name_len];
+
+        Ok(DirEntry { inode, rec_len, name, file_type })
+    }
+}
+
+impl Block {
+    fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.sectors.as_ptr() as *const u8, self.size) }
+    }
+}
+
+impl InodeData {
+    // symlinks, device nodes and the like show up as files, but can't be
+    // opened:
+    fn kind(&self) -> InodeKind {
+        if self.mode & S_IFMT == S_IFDIR {
+            InodeKind::Directory
+        } else {
+            InodeKind::File
+        }
+    }
+}
+
+impl vfs::Inode for Inode {
+    fn kind(&self) -> InodeKind {
+        self.data.kind()
+    }
+
+    fn lookup<'a>(&'a self, name: &'a [u8]) -> FsFuture<'a, Option<Arc<dyn vfs::Inode>>> {
+        FsFuture::new(async move {
+            if self.data.kind() != InodeKind::Directory {
+                return Err(SysError::InvalidOperation);
+            }
+
+            let number = match self.volume.find(&self.data, name).await? {
+                Some(number) => number,
+                None => return Ok(None),
+            };
+
+            let inode: Arc<dyn vfs::Inode> = Arc::new(Inode {
+                volume: self.volume.clone(),
+                number,
+                data: self.volume.inode(number).await?,
+            })?;
+
+            Ok(Some(inode))
+        })
+    }
+
+    fn open(&self) -> FsFuture<'_, Arc<dyn vfs::FileHandle>> {
+        FsFuture::new(async move {
+            match self.data.mode & S_IFMT {
+                S_IFREG | S_IFDIR => {}
+                _ => return Err(SysError::InvalidOperation),
+            }
+
+            let handle: Arc<dyn vfs::FileHandle> = Arc::new(Handle {
+                volume: self.volume.clone(),
+                data: self.data,
+                pos: AsyncMutex::new(0),
+            })?;
+
+            Ok(handle)
+        })
+    }
+
+    fn stat(&self) -> FsFuture<'_, Stat> {
+        FsFuture::new(async move {
+            let kind = self.data.kind();
+            let size = if kind == InodeKind::Directory { 0 } else { self.data.size };
+
+            let permissions = Permissions {
+                mode: (self.data.mode & !S_IFMT) as u32,
+                uid: self.data.uid,
+                gid: self.data.gid,
+            };
+
+            Ok(Stat { kind, size, permissions })
+        })
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn read_dir<'a>(&'a self, pos: u64, sink: &'a mut dyn DirSink) -> FsFuture<'a, ()> {
+        FsFuture::new(async move {
+            if self.data.kind() != InodeKind::Directory {
+                return Err(SysError::InvalidOperation);
+            }
+
+            self.volume.list(&self.data, pos, sink).await
+        })
+    }
+}
+
+impl vfs::FileHandle for Handle {
+    fn read<'a>(&'a self, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        FsFuture::new(async move {
+            if self.data.kind() != InodeKind::File {
+                return Err(SysError::InvalidOperation);
+            }
+
+            let block_size = self.volume.geometry.block_size as u64;
+
+            let mut pos = self.pos.lock().await?;
+
+            if *pos >= self.data.size {
+                return Ok(0);
+            }
+
+            let len = cmp::min(buf.len() as u64, self.data.size - *pos) as usize;
+            let mut done = 0;
+
+            while done < len {
+                let at = *pos + done as u64;
+                let offset = (at % block_size) as usize;
+                let sector_offset = offset % SECTOR_SIZE;
+                let count = cmp::min(SECTOR_SIZE - sector_offset, len - done);
+                let dest = &mut buf[done..(done + count)];
+
+                match self.volume.data_block(&self.data, at / block_size).await? {
+                    0 => dest.iter_mut().for_each(|byte| *byte = 0),
+                    block => {
+                        let sector = self.volume.read_sector_of(block, offset).await?;
+                        dest.copy_from_slice(&sector[sector_offset..(sector_offset + count)]);
+                    }
+                }
+
+                done += count;
+            }
+
+            *pos += done as u64;
+
+            Ok(done)
+        })
+    }
+
+    fn seek(&self, from: SeekFrom) -> FsFuture<'_, u64> {
+        FsFuture::new(async move {
+            if self.data.kind() != InodeKind::File {
+                return Err(SysError::InvalidOperation);
+            }
+
+            let mut pos = self.pos.lock().await?;
+            *pos = from.resolve(*pos, self.data.size)?;
+
+            Ok(*pos)
+        })
+    }
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..(offset + 2)].try_into().expect("read_u16"))
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..(offset + 4)].try_into().expect("read_u32"))
+}
+
+impl Debug for Ext2 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Ext2({:?})", self.volume.part)
+    }
+}
+
+impl Debug for Inode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ext2::Inode({}, {:?})", self.number, self.data.kind())
+    }
+}
+
+impl Debug for Handle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ext2::Handle({:?}, {} bytes)", self.data.kind(), self.data.size)
+    }
+}
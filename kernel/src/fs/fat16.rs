@@ -1,3 +1,4 @@
+use core::any::Any;
 use core::cmp;
 use core::mem;
 
@@ -7,14 +8,18 @@ use futures::pin_mut;
 use futures::stream::{self, Stream, StreamExt, TryStream, TryStreamExt};
 use interface::{SysError, SysResult};
 
-use crate::device::ide::{AtaError, Sector};
-use crate::device::mbr::Partition;
+use crate::device::block::{BlockError, Disk, Sector};
+use crate::fs::vfs::{self, DirSink, FsFuture, InodeKind, Permissions, SeekFrom, Stat};
 use crate::mem::MemoryExhausted;
 use crate::sync::{Arc, AsyncMutex};
 
 const DIR_ENTRY_SIZE: usize = 32;
 const SECTOR_SIZE: usize = 512;
 
+// FAT keeps no owners or modes, so everything belongs to root, and anyone may
+// read it and run it:
+const FAT_PERMISSIONS: Permissions = Permissions { mode: 0o755, uid: 0, gid: 0 };
+
 #[derive(Debug)]
 pub struct Fat16 {
     fs: Arc<Filesystem>,
@@ -22,34 +27,28 @@ pub struct Fat16 {
 
 #[derive(Debug)]
 struct Filesystem {
-    part: Partition,
+    part: Arc<Disk>,
     bpb: BiosParameterBlock,
 }
 
-#[derive(Debug)]
-pub enum OpenError {
-    MemoryExhausted,
-    Ata(AtaError),
-}
-
 #[derive(Debug)]
 pub enum FatError {
     MemoryExhausted,
-    Ata(AtaError),
+    Block(BlockError),
 }
 
 impl From<FatError> for SysError {
     fn from(e: FatError) -> Self {
         match e {
             FatError::MemoryExhausted => SysError::MemoryExhausted,
-            FatError::Ata(_) => SysError::IoError,
+            FatError::Block(e) => e.into(),
         }
     }
 }
 
-impl From<AtaError> for FatError {
-    fn from(e: AtaError) -> FatError {
-        FatError::Ata(e)
+impl From<BlockError> for FatError {
+    fn from(e: BlockError) -> FatError {
+        FatError::Block(e)
     }
 }
 
@@ -63,9 +62,9 @@ impl From<MemoryExhausted> for FatError {
 struct ClusterNumber(usize);
 
 impl Fat16 {
-    pub async fn open(part: Partition) -> Result<Self, FatError> {
+    pub async fn open(part: Arc<Disk>) -> Result<Self, FatError> {
         let bpb = BiosParameterBlock::read(&part).await
-            .map_err(FatError::Ata)?;
+            .map_err(FatError::Block)?;
 
         let fs = Arc::new(Filesystem { part, bpb })
             .map_err(|_| FatError::MemoryExhausted)?;
@@ -82,7 +81,7 @@ impl Fat16 {
 }
 
 impl Filesystem {
-    async fn next_cluster(&self, cluster: ClusterNumber) -> Result<Option<ClusterNumber>, AtaError> {
+    async fn next_cluster(&self, cluster: ClusterNumber) -> Result<Option<ClusterNumber>, BlockError> {
         const FAT_ENTRY_SIZE: usize = mem::size_of::<u16>();
 
         let max_cluster = self.bpb.fat_sector_count() * SECTOR_SIZE / FAT_ENTRY_SIZE;
@@ -99,7 +98,7 @@ impl Filesystem {
         let sector_offset = fat_entry_offset % SECTOR_SIZE;
 
         let mut buff: Sector = [0u8; 512];
-        self.part.read_sectors(fat_sector, &mut [&mut buff]).await?;
+        self.part.read_blocks(fat_sector as u64, &mut [&mut buff]).await?;
 
         let next_lo = buff[sector_offset + 0];
         let next_hi = buff[sector_offset + 1];
@@ -114,7 +113,7 @@ impl Filesystem {
         }
     }
 
-    fn cluster_chain(&self, start: ClusterNumber) -> impl Stream<Item = Result<ClusterNumber, AtaError>> + '_ {
+    fn cluster_chain(&self, start: ClusterNumber) -> impl Stream<Item = Result<ClusterNumber, BlockError>> + '_ {
         stream::unfold(Some(start), move |cluster| async move {
             match cluster {
                 Some(cluster) => {
@@ -128,7 +127,7 @@ impl Filesystem {
         })
     }
 
-    fn sector_chain(&self, start: ClusterNumber) -> impl Stream<Item = Result<usize, AtaError>> + '_ {
+    fn sector_chain(&self, start: ClusterNumber) -> impl Stream<Item = Result<usize, BlockError>> + '_ {
         self.cluster_chain(start)
             .map(move |cluster| {
                 cluster.map(|cluster| stream::iter(self.bpb.cluster_sectors(cluster).map(Ok)))
@@ -150,7 +149,7 @@ pub struct Directory {
 }
 
 impl Directory {
-    fn directory_sectors(&self) -> impl TryStream<Ok = usize, Error = AtaError> + '_ {
+    fn directory_sectors(&self) -> impl TryStream<Ok = usize, Error = BlockError> + '_ {
         match &self.kind {
             DirectoryKind::Root => {
                 let first_sector = self.fs.bpb.first_root_dir_sector();
@@ -173,7 +172,7 @@ impl Directory {
             -> Result<ArrayVec<[RawDirEntry; 16]>, FatError>
         {
             let mut buff: Sector = [0u8; 512];
-            fs.part.read_sectors(sector, &mut [&mut buff]).await?;
+            fs.part.read_blocks(sector as u64, &mut [&mut buff]).await?;
 
             let entries = unsafe { mem::transmute::<&Sector, &[RawDirEntry; 16]>(&buff) };
 
@@ -183,7 +182,7 @@ impl Directory {
         let fs = &self.fs;
 
         self.directory_sectors()
-            .map_err(FatError::Ata)
+            .map_err(FatError::Block)
             .and_then(move |sector| async move {
                 let raw_entries = read_raw_entries_from_sector(fs, sector).await?;
                 Ok(stream::iter(raw_entries.into_iter().map(Ok)))
@@ -265,6 +264,10 @@ impl DirEntry {
         self.dirent().attributes().contains(Attributes::DIRECTORY)
     }
 
+    pub fn size(&self) -> u64 {
+        self.dirent().size as u64
+    }
+
     pub fn open(&self) -> Result<Open, FatError> {
         let fs = self.shared.fs.clone();
 
@@ -278,6 +281,7 @@ impl DirEntry {
                 cluster: Some(self.dirent().first_cluster()),
                 sector: 0,
                 offset: 0,
+                pos: 0,
             };
 
             Ok(Open::File(File {
@@ -300,6 +304,8 @@ struct Seek {
     cluster: Option<ClusterNumber>,
     sector: usize,
     offset: usize,
+    // from the start of the file:
+    pos: u64,
 }
 
 #[derive(Debug)]
@@ -313,8 +319,13 @@ impl File {
     pub async fn read(&self, mut buf: &mut [u8]) -> Result<usize, FatError> {
         let mut seek = self.seek.lock().await?;
         let mut total_read = 0;
+        let size = self.dirent.size();
 
         while buf.len() > 0 {
+            if seek.pos >= size {
+                return Ok(total_read);
+            }
+
             if seek.offset == SECTOR_SIZE {
                 seek.offset = 0;
                 seek.sector += 1;
@@ -341,11 +352,12 @@ impl File {
 
             let mut sector_buff: Sector = [0; SECTOR_SIZE];
             // TODO make this read multiple sectors at a time:
-            self.fs.part.read_sectors(sector, &mut [&mut sector_buff])
+            self.fs.part.read_blocks(sector as u64, &mut [&mut sector_buff])
                 .await
-                .map_err(FatError::Ata)?;
+                .map_err(FatError::Block)?;
 
             let byte_count = cmp::min(SECTOR_SIZE - seek.offset, buf.len());
+            let byte_count = cmp::min(byte_count as u64, size - seek.pos) as usize;
 
             let end = seek.offset + byte_count;
 
@@ -354,10 +366,161 @@ impl File {
             buf = &mut buf[byte_count..];
             total_read += byte_count;
             seek.offset += byte_count;
+            seek.pos += byte_count as u64;
         }
 
         Ok(total_read)
     }
+
+    pub async fn seek(&self, from: SeekFrom) -> SysResult<u64> {
+        let mut seek = self.seek.lock().await?;
+        let pos = from.resolve(seek.pos, self.dirent.size())?;
+
+        // walk the chain to the cluster the position is in:
+        let cluster_size = (self.fs.bpb.sectors_per_cluster() * SECTOR_SIZE) as u64;
+        let mut cluster = Some(self.dirent.dirent().first_cluster());
+
+        for _ in 0..(pos / cluster_size) {
+            cluster = match cluster {
+                Some(cluster) => self.fs.next_cluster(cluster).await.map_err(FatError::Block)?,
+                None => break,
+            };
+        }
+
+        let within = (pos % cluster_size) as usize;
+
+        *seek = Seek {
+            cluster,
+            sector: within / SECTOR_SIZE,
+            offset: within % SECTOR_SIZE,
+            pos,
+        };
+
+        Ok(pos)
+    }
+}
+
+impl vfs::Filesystem for Fat16 {
+    fn root(&self) -> SysResult<Arc<dyn vfs::Inode>> {
+        Ok(Arc::new(FatInode::Root(self.fs.clone()))?)
+    }
+}
+
+/// A FAT16 file or directory, as seen by the VFS. The root directory has no
+/// directory entry of its own, so it's told apart here.
+#[derive(Debug)]
+enum FatInode {
+    Root(Arc<Filesystem>),
+    Entry(DirEntry),
+}
+
+impl FatInode {
+    fn directory(&self) -> SysResult<Directory> {
+        match self {
+            FatInode::Root(fs) => Ok(Directory { fs: fs.clone(), kind: DirectoryKind::Root }),
+            FatInode::Entry(entry) => match entry.open()? {
+                Open::Dir(dir) => Ok(dir),
+                Open::File(_) => Err(SysError::InvalidOperation),
+            },
+        }
+    }
+}
+
+impl vfs::Inode for FatInode {
+    fn kind(&self) -> InodeKind {
+        match self {
+            FatInode::Root(_) => InodeKind::Directory,
+            FatInode::Entry(entry) if entry.is_dir() => InodeKind::Directory,
+            FatInode::Entry(_) => InodeKind::File,
+        }
+    }
+
+    fn lookup<'a>(&'a self, name: &'a [u8]) -> FsFuture<'a, Option<Arc<dyn vfs::Inode>>> {
+        FsFuture::new(async move {
+            let entry = match self.directory()?.entry(name).await? {
+                Some(entry) => entry,
+                None => return Ok(None),
+            };
+
+            let inode: Arc<dyn vfs::Inode> = Arc::new(FatInode::Entry(entry))?;
+            Ok(Some(inode))
+        })
+    }
+
+    fn open(&self) -> FsFuture<'_, Arc<dyn vfs::FileHandle>> {
+        FsFuture::new(async move {
+            let open = match self {
+                FatInode::Root(_) => Open::Dir(self.directory()?),
+                FatInode::Entry(entry) => entry.open()?,
+            };
+
+            let handle: Arc<dyn vfs::FileHandle> = Arc::new(open)?;
+            Ok(handle)
+        })
+    }
+
+    fn stat(&self) -> FsFuture<'_, Stat> {
+        FsFuture::new(async move {
+            let size = match self {
+                FatInode::Entry(entry) if !entry.is_dir() => entry.size(),
+                _ => 0,
+            };
+
+            Ok(Stat { kind: vfs::Inode::kind(self), size, permissions: FAT_PERMISSIONS })
+        })
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    // positions count the directory's raw entries, volume labels and long
+    // name parts included, which are skipped:
+    fn read_dir<'a>(&'a self, pos: u64, sink: &'a mut dyn DirSink) -> FsFuture<'a, ()> {
+        FsFuture::new(async move {
+            let dir = self.directory()?;
+            let entries = dir.entries();
+            pin_mut!(entries);
+
+            let mut index = 0;
+
+            while let Some(entry) = entries.try_next().await? {
+                index += 1;
+
+                if index <= pos || entry.dirent().attributes().contains(Attributes::VOLUME_ID) {
+                    continue;
+                }
+
+                let kind = if entry.is_dir() { InodeKind::Directory } else { InodeKind::File };
+
+                if !sink.push(&entry.name(), kind, index) {
+                    break;
+                }
+            }
+
+            Ok(())
+        })
+    }
+}
+
+impl vfs::FileHandle for Open {
+    fn read<'a>(&'a self, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        FsFuture::new(async move {
+            match self {
+                Open::File(file) => Ok(file.read(buf).await?),
+                Open::Dir(_) => Err(SysError::InvalidOperation),
+            }
+        })
+    }
+
+    fn seek(&self, from: SeekFrom) -> FsFuture<'_, u64> {
+        FsFuture::new(async move {
+            match self {
+                Open::File(file) => file.seek(from).await,
+                Open::Dir(_) => Err(SysError::InvalidOperation),
+            }
+        })
+    }
 }
 
 #[repr(packed)]
@@ -503,9 +666,9 @@ impl BiosParameterBlock {
 }
 
 impl BiosParameterBlock {
-    pub async fn read(part: &Partition) -> Result<BiosParameterBlock, AtaError> {
+    pub async fn read(part: &Disk) -> Result<BiosParameterBlock, BlockError> {
         let mut buff: Sector = [0; 512];
-        part.read_sectors(0, &mut [&mut buff]).await?;
+        part.read_blocks(0, &mut [&mut buff]).await?;
 
         let bpb = unsafe {
             mem::transmute::<&Sector, &BiosParameterBlock>(&buff).clone()
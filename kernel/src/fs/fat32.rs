@@ -0,0 +1,1431 @@
+use core::any::Any;
+use core::cmp;
+use core::convert::TryInto;
+use core::fmt::{self, Debug};
+use core::ptr;
+
+use alloc_collections::btree_map::BTreeMap;
+use arrayvec::ArrayVec;
+use interface::{SysError, SysResult};
+
+use crate::device::block::{BlockError, Disk, Sector};
+use crate::fs::vfs::{self, DirSink, FsFuture, InodeKind, Permissions, SeekFrom, Stat};
+use crate::mem::kalloc::GlobalAlloc;
+use crate::sync::{Arc, AsyncMutex, Mutex};
+
+const SECTOR_SIZE: usize = 512;
+const DIR_ENTRY_SIZE: usize = 32;
+const ENTRIES_PER_SECTOR: usize = SECTOR_SIZE / DIR_ENTRY_SIZE;
+
+// FAT keeps no owners or modes, so everything belongs to root, and anyone may
+// read it and run it:
+const FAT_PERMISSIONS: Permissions = Permissions { mode: 0o755, uid: 0, gid: 0 };
+
+// FAT entries are 28 bits. the top 4 are reserved, and kept as they are
+// whenever an entry is changed:
+const FAT_ENTRY_MASK: u32 = 0x0fff_ffff;
+const FAT_FREE: u32 = 0;
+const FAT_EOC: u32 = 0x0fff_ffff;
+// anything from here up marks the end of a chain:
+const FAT_EOC_MIN: u32 = 0x0fff_fff8;
+
+// with fewer clusters than this, a FAT is FAT12 or FAT16 whatever its boot
+// sector looks like:
+const FAT32_MIN_CLUSTERS: u32 = 65525;
+
+// the largest file a directory entry's 32 bit size can describe:
+const FILE_SIZE_MAX: u64 = 0xffff_ffff;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0f;
+
+// the first byte of a directory entry that's been deleted, and of the
+// first entry past the end of a directory:
+const ENTRY_FREE: u8 = 0xe5;
+const ENTRY_END: u8 = 0x00;
+
+// set in byte 12 of an entry by Windows when the base or the extension of
+// its short name is all lower case, so it doesn't need a long name:
+const NT_LOWER_BASE: u8 = 0x08;
+const NT_LOWER_EXT: u8 = 0x10;
+
+// long name entries hold 13 UCS-2 characters each, at these offsets, and a
+// name can be spread over up to 20 of them:
+const LFN_CHAR_OFFSETS: [usize; 13] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const LFN_ENTRIES_MAX: usize = 20;
+const LFN_LAST: u8 = 0x40;
+const LFN_SEQUENCE_MASK: u8 = 0x1f;
+
+const LONG_NAME_MAX: usize = 255;
+
+// 1980-01-01, the earliest date FAT can store. there's no clock to take
+// the real date from yet:
+const FAT_EPOCH_DATE: u16 = (1 << 5) | 1;
+
+const FSINFO_LEAD_SIGNATURE_OFFSET: usize = 0;
+const FSINFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIGNATURE_OFFSET: usize = 484;
+const FSINFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+const FSINFO_FREE_COUNT: usize = 488;
+const FSINFO_NEXT_FREE: usize = 492;
+const FSINFO_UNKNOWN: u32 = 0xffff_ffff;
+
+type Name = ArrayVec<[u8; LONG_NAME_MAX]>;
+type RawEntry = [u8; DIR_ENTRY_SIZE];
+
+/// A FAT32 filesystem on a partition, such as an EFI system partition.
+/// Files and directories with long names can be read, but anything created
+/// or renamed must have a name that fits in 8.3.
+pub struct Fat32 {
+    volume: Arc<Volume>,
+}
+
+struct Volume {
+    part: Arc<Disk>,
+    sectors_per_cluster: usize,
+    fat_start: usize,
+    fat_sectors: usize,
+    fat_count: usize,
+    data_start: usize,
+    // clusters are numbered from 2, so the last is cluster_count + 1:
+    cluster_count: u32,
+    root_cluster: u32,
+    fsinfo_sector: Option<usize>,
+    // held while the FAT or any directory is read or changed:
+    meta: AsyncMutex<Meta>,
+    // every file and directory with an Inode or Handle, by the slot of its
+    // directory entry. the root has no entry, so it's never here:
+    nodes: Mutex<BTreeMap<Slot, Arc<Node>, GlobalAlloc>>,
+}
+
+struct Meta {
+    // where to start looking for a free cluster:
+    next_free: u32,
+    // whether the free count in FSInfo has been marked unknown, which is
+    // done the first time a cluster is allocated or freed:
+    fsinfo_stale: bool,
+}
+
+// where a directory entry is on disk:
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Slot {
+    sector: usize,
+    index: usize,
+}
+
+// a file or directory in use, shared by everything referring to it so
+// that changes to its size or place are seen by all of them:
+struct Node {
+    state: Mutex<NodeState>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct NodeState {
+    // None for the root:
+    slot: Option<Slot>,
+    directory: bool,
+    // 0 for a file with no clusters yet:
+    first_cluster: u32,
+    size: u32,
+    refs: usize,
+}
+
+// a counted reference to a Node, which leaves the node table with the
+// last one:
+struct NodeRef {
+    volume: Arc<Volume>,
+    node: Arc<Node>,
+}
+
+/// A FAT32 file or directory, as handed to the VFS.
+struct Inode {
+    node: NodeRef,
+}
+
+/// A FAT32 file or directory opened through the VFS.
+struct Handle {
+    node: NodeRef,
+    seek: AsyncMutex<Seek>,
+}
+
+struct Seek {
+    pos: u64,
+    // the last cluster reached and its index in the file, so that reading
+    // along doesn't follow the chain from the start every time:
+    cursor: Option<(u32, u32)>,
+}
+
+// a directory entry as read, with its long name if it has one:
+struct DirEntry {
+    raw: RawEntry,
+    name: Name,
+    slot: Slot,
+    // the long name entries before it, which go along with it:
+    lfn_slots: ArrayVec<[Slot; LFN_ENTRIES_MAX]>,
+}
+
+enum Item {
+    Entry(DirEntry),
+    // a deleted entry, which can be reused:
+    Free(Slot),
+    // the first slot past the end, which can be used too:
+    End(Slot),
+}
+
+impl Fat32 {
+    /// Checks whether a partition holds a FAT32 filesystem.
+    pub async fn probe(part: &Disk) -> Result<bool, BlockError> {
+        let mut boot = [0u8; SECTOR_SIZE];
+        part.read_blocks(0, &mut [&mut boot]).await?;
+
+        Ok(Geometry::parse(&boot, part.block_count() as usize).is_ok())
+    }
+
+    /// Opens the FAT32 filesystem on a partition. Fails with IllegalValue
+    /// if it isn't one.
+    pub async fn open(part: Arc<Disk>) -> SysResult<Fat32> {
+        let mut boot = [0u8; SECTOR_SIZE];
+        part.read_blocks(0, &mut [&mut boot]).await?;
+
+        let geometry = Geometry::parse(&boot, part.block_count() as usize)?;
+
+        let mut next_free = 2;
+
+        if let Some(fsinfo) = geometry.fsinfo_sector {
+            let mut sector = [0u8; SECTOR_SIZE];
+            part.read_blocks(fsinfo as u64, &mut [&mut sector]).await?;
+
+            let hint = read_u32(&sector, FSINFO_NEXT_FREE);
+
+            if hint >= 2 && hint < geometry.cluster_count + 2 {
+                next_free = hint;
+            }
+        }
+
+        let volume = Arc::new(Volume {
+            part,
+            sectors_per_cluster: geometry.sectors_per_cluster,
+            fat_start: geometry.fat_start,
+            fat_sectors: geometry.fat_sectors,
+            fat_count: geometry.fat_count,
+            data_start: geometry.data_start,
+            cluster_count: geometry.cluster_count,
+            root_cluster: geometry.root_cluster,
+            fsinfo_sector: geometry.fsinfo_sector,
+            meta: AsyncMutex::new(Meta { next_free, fsinfo_stale: false }),
+            nodes: Mutex::new(BTreeMap::new()),
+        })?;
+
+        Ok(Fat32 { volume })
+    }
+}
+
+impl vfs::Filesystem for Fat32 {
+    fn root(&self) -> SysResult<Arc<dyn vfs::Inode>> {
+        let node = NodeRef::root(&self.volume)?;
+        Ok(Arc::new(Inode { node })?)
+    }
+
+    fn sync(&self) -> FsFuture<'_, ()> {
+        FsFuture::new(async move {
+            Ok(self.volume.part.writeback().await?)
+        })
+    }
+}
+
+struct Geometry {
+    sectors_per_cluster: usize,
+    fat_start: usize,
+    fat_sectors: usize,
+    fat_count: usize,
+    data_start: usize,
+    cluster_count: u32,
+    root_cluster: u32,
+    fsinfo_sector: Option<usize>,
+}
+
+impl Geometry {
+    // reads the BIOS parameter block from a boot sector, refusing anything
+    // that isn't FAT32 with 512 byte sectors and fits in `sectors`:
+    fn parse(boot: &Sector, sectors: usize) -> SysResult<Geometry> {
+        if boot[510..512] != [0x55, 0xaa] {
+            return Err(SysError::IllegalValue);
+        }
+
+        let bytes_per_sector = read_u16(boot, 0x0b) as usize;
+        let sectors_per_cluster = boot[0x0d] as usize;
+        let reserved_sectors = read_u16(boot, 0x0e) as usize;
+        let fat_count = boot[0x10] as usize;
+        let root_entry_count = read_u16(boot, 0x11);
+        let total_sectors_16 = read_u16(boot, 0x13) as usize;
+        let fat_sectors_16 = read_u16(boot, 0x16);
+        let total_sectors_32 = read_u32(boot, 0x20) as usize;
+        let fat_sectors = read_u32(boot, 0x24) as usize;
+        let root_cluster = read_u32(boot, 0x2c);
+        let fsinfo_sector = read_u16(boot, 0x30) as usize;
+
+        // FAT12 and FAT16 have a fixed root directory and a 16 bit FAT size:
+        if bytes_per_sector != SECTOR_SIZE
+            || !sectors_per_cluster.is_power_of_two()
+            || fat_count == 0
+            || root_entry_count != 0
+            || fat_sectors_16 != 0
+            || fat_sectors == 0
+        {
+            return Err(SysError::IllegalValue);
+        }
+
+        let total_sectors = if total_sectors_16 != 0 { total_sectors_16 } else { total_sectors_32 };
+        let data_start = reserved_sectors + fat_count * fat_sectors;
+
+        if total_sectors > sectors || data_start >= total_sectors {
+            return Err(SysError::IllegalValue);
+        }
+
+        // only as many clusters as both the data area and the FAT have
+        // room for. each FAT sector has 128 entries, the first 2 reserved:
+        let cluster_count = cmp::min(
+            (total_sectors - data_start) / sectors_per_cluster,
+            fat_sectors * (SECTOR_SIZE / 4) - 2,
+        );
+
+        if cluster_count < FAT32_MIN_CLUSTERS as usize || cluster_count > FAT_EOC_MIN as usize - 2 {
+            return Err(SysError::IllegalValue);
+        }
+
+        let cluster_count = cluster_count as u32;
+
+        if root_cluster < 2 || root_cluster >= cluster_count + 2 {
+            return Err(SysError::IllegalValue);
+        }
+
+        // 0 and 0xffff both mean there's no FSInfo sector:
+        let fsinfo_sector = Some(fsinfo_sector)
+            .filter(|sector| *sector != 0 && *sector < reserved_sectors);
+
+        Ok(Geometry {
+            sectors_per_cluster,
+            fat_start: reserved_sectors,
+            fat_sectors,
+            fat_count,
+            data_start,
+            cluster_count,
+            root_cluster,
+            fsinfo_sector,
+        })
+    }
+}
+
+impl Volume {
+    async fn read_sector(&self, lba: usize) -> SysResult<Sector> {
+        let mut sector = [0u8; SECTOR_SIZE];
+        self.part.read_blocks(lba as u64, &mut [&mut sector]).await?;
+        Ok(sector)
+    }
+
+    async fn write_sector(&self, lba: usize, sector: &Sector) -> SysResult<()> {
+        self.part.write_blocks(lba as u64, &[sector]).await.map_err(SysError::from)
+    }
+
+    fn cluster_size(&self) -> usize {
+        self.sectors_per_cluster * SECTOR_SIZE
+    }
+
+    fn valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.cluster_count + 2
+    }
+
+    // where a cluster starts. clusters out of bounds come from a corrupt
+    // FAT or directory entry:
+    fn cluster_sector(&self, cluster: u32) -> SysResult<usize> {
+        if self.valid_cluster(cluster) {
+            Ok(self.data_start + (cluster as usize - 2) * self.sectors_per_cluster)
+        } else {
+            Err(SysError::IoError)
+        }
+    }
+
+    async fn fat_entry(&self, cluster: u32) -> SysResult<u32> {
+        let offset = cluster as usize * 4;
+        let sector = self.read_sector(self.fat_start + offset / SECTOR_SIZE).await?;
+
+        Ok(read_u32(&sector, offset % SECTOR_SIZE) & FAT_ENTRY_MASK)
+    }
+
+    // changes a cluster's entry in every copy of the FAT:
+    async fn set_fat_entry(&self, cluster: u32, value: u32) -> SysResult<()> {
+        let offset = cluster as usize * 4;
+
+        for fat in 0..self.fat_count {
+            let lba = self.fat_start + fat * self.fat_sectors + offset / SECTOR_SIZE;
+            let mut sector = self.read_sector(lba).await?;
+
+            let old = read_u32(&sector, offset % SECTOR_SIZE);
+            write_u32(&mut sector, offset % SECTOR_SIZE, (old & !FAT_ENTRY_MASK) | value);
+
+            self.write_sector(lba, &sector).await?;
+        }
+
+        Ok(())
+    }
+
+    // the cluster after `cluster` in its chain, or None at the end. a chain
+    // running into a free or bad cluster is corrupt:
+    async fn next_cluster(&self, cluster: u32) -> SysResult<Option<u32>> {
+        match self.fat_entry(cluster).await? {
+            next if next >= FAT_EOC_MIN => Ok(None),
+            next if self.valid_cluster(next) => Ok(Some(next)),
+            _ => Err(SysError::IoError),
+        }
+    }
+
+    // allocates a zeroed cluster, linking it onto the end of the chain
+    // ending at `prev`, if any:
+    async fn alloc_cluster(&self, meta: &mut Meta, prev: Option<u32>) -> SysResult<u32> {
+        for i in 0..self.cluster_count {
+            let cluster = 2 + (meta.next_free - 2 + i) % self.cluster_count;
+
+            if self.fat_entry(cluster).await? != FAT_FREE {
+                continue;
+            }
+
+            self.invalidate_fsinfo(meta).await?;
+            self.zero_cluster(cluster).await?;
+            self.set_fat_entry(cluster, FAT_EOC).await?;
+
+            if let Some(prev) = prev {
+                self.set_fat_entry(prev, cluster).await?;
+            }
+
+            meta.next_free = 2 + (cluster - 1) % self.cluster_count;
+
+            return Ok(cluster);
+        }
+
+        Err(SysError::NoSpace)
+    }
+
+    async fn free_chain(&self, meta: &mut Meta, first: u32) -> SysResult<()> {
+        if !self.valid_cluster(first) {
+            return Ok(());
+        }
+
+        self.invalidate_fsinfo(meta).await?;
+
+        let mut cluster = Some(first);
+
+        while let Some(current) = cluster {
+            cluster = self.next_cluster(current).await?;
+            self.set_fat_entry(current, FAT_FREE).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn zero_cluster(&self, cluster: u32) -> SysResult<()> {
+        let zeros = [0u8; SECTOR_SIZE];
+        let first = self.cluster_sector(cluster)?;
+
+        for sector in first..(first + self.sectors_per_cluster) {
+            self.write_sector(sector, &zeros).await?;
+        }
+
+        Ok(())
+    }
+
+    // the free count in FSInfo is only a hint, which is cheaper to mark
+    // unknown than to keep up to date:
+    async fn invalidate_fsinfo(&self, meta: &mut Meta) -> SysResult<()> {
+        let lba = match self.fsinfo_sector {
+            Some(lba) if !meta.fsinfo_stale => lba,
+            _ => return Ok(()),
+        };
+
+        let mut sector = self.read_sector(lba).await?;
+
+        if read_u32(&sector, FSINFO_LEAD_SIGNATURE_OFFSET) == FSINFO_LEAD_SIGNATURE
+            && read_u32(&sector, FSINFO_STRUCT_SIGNATURE_OFFSET) == FSINFO_STRUCT_SIGNATURE
+        {
+            write_u32(&mut sector, FSINFO_FREE_COUNT, FSINFO_UNKNOWN);
+            self.write_sector(lba, &sector).await?;
+        }
+
+        meta.fsinfo_stale = true;
+
+        Ok(())
+    }
+
+    async fn read_slot(&self, slot: Slot) -> SysResult<RawEntry> {
+        let sector = self.read_sector(slot.sector).await?;
+        let offset = slot.index * DIR_ENTRY_SIZE;
+
+        Ok(sector[offset..(offset + DIR_ENTRY_SIZE)].try_into()
+            .expect("Volume::read_slot: entry size"))
+    }
+
+    async fn write_slot(&self, slot: Slot, raw: &RawEntry) -> SysResult<()> {
+        let mut sector = self.read_sector(slot.sector).await?;
+        let offset = slot.index * DIR_ENTRY_SIZE;
+
+        sector[offset..(offset + DIR_ENTRY_SIZE)].copy_from_slice(raw);
+
+        self.write_sector(slot.sector, &sector).await
+    }
+
+    async fn delete_slot(&self, slot: Slot) -> SysResult<()> {
+        let mut raw = self.read_slot(slot).await?;
+        raw[0] = ENTRY_FREE;
+        self.write_slot(slot, &raw).await
+    }
+
+    // removes an entry along with its long name:
+    async fn delete_entry(&self, entry: &DirEntry) -> SysResult<()> {
+        for slot in entry.lfn_slots.iter() {
+            self.delete_slot(*slot).await?;
+        }
+
+        self.delete_slot(entry.slot).await
+    }
+
+    // finds a named entry of a directory, skipping "." and "..":
+    async fn find(&self, dir: u32, name: &[u8]) -> SysResult<Option<DirEntry>> {
+        let mut items = Items::new(self, dir);
+
+        while let Some(item) = items.next().await? {
+            if let Item::Entry(entry) = item {
+                if !entry.is_dot() && entry.matches(name) {
+                    return Ok(Some(entry));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    async fn is_empty(&self, dir: u32) -> SysResult<bool> {
+        let mut items = Items::new(self, dir);
+
+        while let Some(item) = items.next().await? {
+            if let Item::Entry(entry) = item {
+                if !entry.is_dot() {
+                    return Ok(false);
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
+    // finds a slot for a new entry in a directory, growing it by a cluster
+    // if it's full. fails with AlreadyMapped if `name` or `short` is taken
+    // by anything other than `except`:
+    async fn free_slot(&self, meta: &mut Meta, dir: u32, name: &[u8], short: &[u8; 11], except: Option<Slot>)
+        -> SysResult<Slot>
+    {
+        let mut items = Items::new(self, dir);
+        let mut free = None;
+
+        while let Some(item) = items.next().await? {
+            match item {
+                Item::Entry(entry) => {
+                    let taken = entry.matches(name) || entry.short() == short;
+
+                    if taken && Some(entry.slot) != except {
+                        return Err(SysError::AlreadyMapped);
+                    }
+                }
+                Item::Free(slot) | Item::End(slot) => {
+                    free = free.or(Some(slot));
+                }
+            }
+        }
+
+        match free {
+            Some(slot) => Ok(slot),
+            None => {
+                let cluster = self.alloc_cluster(meta, Some(items.last_cluster)).await?;
+                Ok(Slot { sector: self.cluster_sector(cluster)?, index: 0 })
+            }
+        }
+    }
+
+    // points a directory's ".." entry at its new parent:
+    async fn set_dotdot(&self, dir: u32, parent: u32) -> SysResult<()> {
+        let slot = Slot { sector: self.cluster_sector(dir)?, index: 1 };
+        let mut raw = self.read_slot(slot).await?;
+
+        if &raw[0..11] != b"..         " {
+            return Err(SysError::IoError);
+        }
+
+        set_first_cluster(&mut raw, self.parent_cluster(parent));
+
+        self.write_slot(slot, &raw).await
+    }
+
+    // ".." entries refer to the root as cluster 0:
+    fn parent_cluster(&self, dir: u32) -> u32 {
+        if dir == self.root_cluster { 0 } else { dir }
+    }
+}
+
+// reads through a directory's entries, stopping at the end marker or the
+// end of its cluster chain:
+struct Items<'v> {
+    volume: &'v Volume,
+    cluster: Option<u32>,
+    last_cluster: u32,
+    sector: usize,
+    index: usize,
+    buf: Option<Sector>,
+    lfn: LongName,
+    // how many slots have been read, which is where a listing carries on
+    // from:
+    slots: u64,
+}
+
+// the long name entries read so far, which belong to the next short entry
+// if the sequence is complete and its checksum matches:
+struct LongName {
+    chars: [u16; LFN_ENTRIES_MAX * 13],
+    len: usize,
+    checksum: u8,
+    // the sequence number of the last entry read, counting down to 1:
+    sequence: u8,
+    slots: ArrayVec<[Slot; LFN_ENTRIES_MAX]>,
+}
+
+impl<'v> Items<'v> {
+    fn new(volume: &'v Volume, dir: u32) -> Self {
+        Items {
+            volume,
+            cluster: Some(dir),
+            last_cluster: dir,
+            sector: 0,
+            index: 0,
+            buf: None,
+            lfn: LongName::new(),
+            slots: 0,
+        }
+    }
+
+    async fn next_slot(&mut self) -> SysResult<Option<(Slot, RawEntry)>> {
+        loop {
+            let cluster = match self.cluster {
+                Some(cluster) => cluster,
+                None => return Ok(None),
+            };
+
+            if self.index == ENTRIES_PER_SECTOR {
+                self.index = 0;
+                self.sector += 1;
+                self.buf = None;
+            }
+
+            if self.sector == self.volume.sectors_per_cluster {
+                self.sector = 0;
+                self.cluster = self.volume.next_cluster(cluster).await?;
+                self.last_cluster = self.cluster.unwrap_or(cluster);
+                continue;
+            }
+
+            let lba = self.volume.cluster_sector(cluster)? + self.sector;
+
+            if self.buf.is_none() {
+                self.buf = Some(self.volume.read_sector(lba).await?);
+            }
+
+            let buf = self.buf.as_ref().expect("Items::next_slot: sector just read");
+            let offset = self.index * DIR_ENTRY_SIZE;
+
+            let raw = buf[offset..(offset + DIR_ENTRY_SIZE)].try_into()
+                .expect("Items::next_slot: entry size");
+
+            let slot = Slot { sector: lba, index: self.index };
+            self.index += 1;
+            self.slots += 1;
+
+            return Ok(Some((slot, raw)));
+        }
+    }
+
+    async fn next(&mut self) -> SysResult<Option<Item>> {
+        while let Some((slot, raw)) = self.next_slot().await? {
+            match raw[0] {
+                ENTRY_END => {
+                    self.cluster = None;
+                    return Ok(Some(Item::End(slot)));
+                }
+                ENTRY_FREE => {
+                    self.lfn.reset();
+                    return Ok(Some(Item::Free(slot)));
+                }
+                _ if raw[11] & ATTR_LONG_NAME == ATTR_LONG_NAME => {
+                    self.lfn.push(slot, &raw);
+                }
+                _ if raw[11] & ATTR_VOLUME_ID != 0 => {
+                    self.lfn.reset();
+                }
+                _ => {
+                    let mut entry = DirEntry {
+                        raw,
+                        name: Name::new(),
+                        slot,
+                        lfn_slots: ArrayVec::new(),
+                    };
+
+                    match self.lfn.take(&raw) {
+                        Some(slots) => {
+                            entry.name = self.lfn.name();
+                            entry.lfn_slots = slots;
+                        }
+                        None => entry.name = short_display_name(entry.short(), raw[12]),
+                    }
+
+                    self.lfn.reset();
+
+                    return Ok(Some(Item::Entry(entry)));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl LongName {
+    fn new() -> Self {
+        LongName {
+            chars: [0; LFN_ENTRIES_MAX * 13],
+            len: 0,
+            checksum: 0,
+            sequence: 0,
+            slots: ArrayVec::new(),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.sequence = 0;
+        self.slots.clear();
+    }
+
+    // the entries of a long name come last part first:
+    fn push(&mut self, slot: Slot, raw: &RawEntry) {
+        let sequence = raw[0] & LFN_SEQUENCE_MASK;
+
+        if raw[0] & LFN_LAST != 0 {
+            self.reset();
+            self.len = sequence as usize * 13;
+            self.checksum = raw[13];
+        } else if self.sequence == 0 || sequence + 1 != self.sequence || raw[13] != self.checksum {
+            self.reset();
+            return;
+        }
+
+        if sequence == 0 || sequence as usize > LFN_ENTRIES_MAX {
+            self.reset();
+            return;
+        }
+
+        let start = (sequence as usize - 1) * 13;
+
+        for (i, offset) in LFN_CHAR_OFFSETS.iter().enumerate() {
+            self.chars[start + i] = read_u16(raw, *offset);
+        }
+
+        self.sequence = sequence;
+        self.slots.push(slot);
+    }
+
+    // the long name's slots, if it's whole and belongs to `short`:
+    fn take(&mut self, short: &RawEntry) -> Option<ArrayVec<[Slot; LFN_ENTRIES_MAX]>> {
+        if self.sequence == 1 && self.checksum == checksum(&short[0..11]) {
+            Some(self.slots.clone())
+        } else {
+            None
+        }
+    }
+
+    // the name as ASCII, with anything else shown as '?':
+    fn name(&self) -> Name {
+        self.chars[..self.len].iter()
+            .take_while(|c| **c != 0 && **c != 0xffff)
+            .take(LONG_NAME_MAX)
+            .map(|c| if *c < 0x80 { *c as u8 } else { b'?' })
+            .collect()
+    }
+}
+
+impl DirEntry {
+    fn short(&self) -> &[u8; 11] {
+        self.raw[0..11].try_into()
+            .expect("DirEntry::short: name size")
+    }
+
+    fn is_dot(&self) -> bool {
+        self.short() == b".          " || self.short() == b"..         "
+    }
+
+    fn is_directory(&self) -> bool {
+        self.raw[11] & ATTR_DIRECTORY != 0
+    }
+
+    fn first_cluster(&self) -> u32 {
+        first_cluster(&self.raw)
+    }
+
+    fn size(&self) -> u32 {
+        read_u32(&self.raw, 28)
+    }
+
+    // names are matched regardless of case, by either the long or the
+    // short name:
+    fn matches(&self, name: &[u8]) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+            || short_display_name(self.short(), 0).eq_ignore_ascii_case(name)
+    }
+}
+
+fn short_display_name(short: &[u8; 11], nt_flags: u8) -> Name {
+    let base = trim_spaces(&short[0..8]);
+    let ext = trim_spaces(&short[8..11]);
+    let mut name = Name::new();
+
+    for byte in base {
+        name.push(if nt_flags & NT_LOWER_BASE != 0 { byte.to_ascii_lowercase() } else { *byte });
+    }
+
+    if !ext.is_empty() {
+        name.push(b'.');
+
+        for byte in ext {
+            name.push(if nt_flags & NT_LOWER_EXT != 0 { byte.to_ascii_lowercase() } else { *byte });
+        }
+    }
+
+    // 0x05 stands in for a leading 0xe5, which would mark the entry free:
+    if name.first() == Some(&0x05) {
+        name[0] = b'?';
+    }
+
+    name
+}
+
+fn trim_spaces(bytes: &[u8]) -> &[u8] {
+    let len = bytes.iter().rposition(|b| *b != b' ').map_or(0, |last| last + 1);
+    &bytes[..len]
+}
+
+// the 8.3 name `name` is stored as, along with the NT flags that keep an
+// all lower case base or extension that way. names that don't fit would
+// need long name entries, which aren't written yet:
+fn short_name(name: &[u8]) -> SysResult<([u8; 11], u8)> {
+    let (base, ext) = match name.iter().rposition(|b| *b == b'.') {
+        Some(dot) => (&name[..dot], &name[(dot + 1)..]),
+        None => (name, &b""[..]),
+    };
+
+    let has_dot = base.len() != name.len();
+
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || (has_dot && ext.is_empty()) {
+        return Err(SysError::IllegalValue);
+    }
+
+    let mut short = [b' '; 11];
+    let mut nt_flags = 0;
+
+    if fill_short(base, &mut short[0..8])? {
+        nt_flags |= NT_LOWER_BASE;
+    }
+
+    if fill_short(ext, &mut short[8..11])? {
+        nt_flags |= NT_LOWER_EXT;
+    }
+
+    Ok((short, nt_flags))
+}
+
+// upper cases `part` into `out`, returning whether it was lower case.
+// mixed case can only be kept with a long name:
+fn fill_short(part: &[u8], out: &mut [u8]) -> SysResult<bool> {
+    let valid = |b: &u8| b.is_ascii_alphanumeric() || b"!#$%&'()-@^_`{}~".contains(b);
+
+    if !part.iter().all(valid) {
+        return Err(SysError::IllegalValue);
+    }
+
+    let lower = part.iter().any(u8::is_ascii_lowercase);
+    let upper = part.iter().any(u8::is_ascii_uppercase);
+
+    if lower && upper {
+        return Err(SysError::IllegalValue);
+    }
+
+    for (out, byte) in out.iter_mut().zip(part) {
+        *out = byte.to_ascii_uppercase();
+    }
+
+    Ok(lower)
+}
+
+fn checksum(short: &[u8]) -> u8 {
+    short.iter().fold(0u8, |sum, byte| {
+        ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(*byte)
+    })
+}
+
+fn new_entry(short: &[u8; 11], nt_flags: u8, directory: bool, first: u32) -> RawEntry {
+    let mut raw = [0u8; DIR_ENTRY_SIZE];
+
+    raw[0..11].copy_from_slice(short);
+    raw[11] = if directory { ATTR_DIRECTORY } else { ATTR_ARCHIVE };
+    raw[12] = nt_flags;
+
+    // creation, access and modification dates:
+    for offset in [16, 18, 24].iter() {
+        raw[*offset..(*offset + 2)].copy_from_slice(&FAT_EPOCH_DATE.to_le_bytes());
+    }
+
+    set_first_cluster(&mut raw, first);
+
+    raw
+}
+
+fn first_cluster(raw: &RawEntry) -> u32 {
+    (read_u16(raw, 20) as u32) << 16 | read_u16(raw, 26) as u32
+}
+
+fn set_first_cluster(raw: &mut RawEntry, cluster: u32) {
+    raw[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    raw[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..(offset + 2)].try_into().expect("read_u16"))
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..(offset + 4)].try_into().expect("read_u32"))
+}
+
+fn write_u32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..(offset + 4)].copy_from_slice(&value.to_le_bytes());
+}
+
+impl NodeRef {
+    fn root(volume: &Arc<Volume>) -> SysResult<NodeRef> {
+        let node = Arc::new(Node {
+            state: Mutex::new(NodeState {
+                slot: None,
+                directory: true,
+                first_cluster: volume.root_cluster,
+                size: 0,
+                refs: 1,
+            }),
+        })?;
+
+        Ok(NodeRef { volume: volume.clone(), node })
+    }
+
+    // the node for an entry, shared with anything else referring to it:
+    fn get(volume: &Arc<Volume>, entry: &DirEntry) -> SysResult<NodeRef> {
+        let mut nodes = volume.nodes.lock();
+
+        if let Some(node) = nodes.get(&entry.slot) {
+            node.state.lock().refs += 1;
+            return Ok(NodeRef { volume: volume.clone(), node: node.clone() });
+        }
+
+        let node = Arc::new(Node {
+            state: Mutex::new(NodeState {
+                slot: Some(entry.slot),
+                directory: entry.is_directory(),
+                first_cluster: entry.first_cluster(),
+                size: entry.size(),
+                refs: 1,
+            }),
+        })?;
+
+        nodes.insert(entry.slot, node.clone())
+            .map_err(|_| SysError::MemoryExhausted)?;
+
+        Ok(NodeRef { volume: volume.clone(), node })
+    }
+
+    fn state(&self) -> NodeState {
+        *self.node.state.lock()
+    }
+
+    fn kind(&self) -> InodeKind {
+        if self.state().directory { InodeKind::Directory } else { InodeKind::File }
+    }
+
+    fn directory(&self) -> SysResult<u32> {
+        let state = self.state();
+
+        if state.directory {
+            Ok(state.first_cluster)
+        } else {
+            Err(SysError::InvalidOperation)
+        }
+    }
+}
+
+impl Clone for NodeRef {
+    fn clone(&self) -> Self {
+        self.node.state.lock().refs += 1;
+        NodeRef { volume: self.volume.clone(), node: self.node.clone() }
+    }
+}
+
+impl Drop for NodeRef {
+    fn drop(&mut self) {
+        let mut nodes = self.volume.nodes.lock();
+        let mut state = self.node.state.lock();
+
+        state.refs -= 1;
+
+        if state.refs == 0 {
+            if let Some(slot) = state.slot {
+                nodes.remove(&slot);
+            }
+        }
+    }
+}
+
+fn is_open(volume: &Volume, entry: &DirEntry) -> bool {
+    volume.nodes.lock().contains_key(&entry.slot)
+}
+
+impl vfs::Inode for Inode {
+    fn kind(&self) -> InodeKind {
+        self.node.kind()
+    }
+
+    fn lookup<'a>(&'a self, name: &'a [u8]) -> FsFuture<'a, Option<Arc<dyn vfs::Inode>>> {
+        FsFuture::new(async move {
+            let volume = &self.node.volume;
+            let dir = self.node.directory()?;
+
+            let _meta = volume.meta.lock().await?;
+
+            let entry = match volume.find(dir, name).await? {
+                Some(entry) => entry,
+                None => return Ok(None),
+            };
+
+            let node = NodeRef::get(volume, &entry)?;
+            let inode: Arc<dyn vfs::Inode> = Arc::new(Inode { node })?;
+
+            Ok(Some(inode))
+        })
+    }
+
+    fn open(&self) -> FsFuture<'_, Arc<dyn vfs::FileHandle>> {
+        FsFuture::new(async move {
+            let handle: Arc<dyn vfs::FileHandle> = Arc::new(Handle {
+                node: self.node.clone(),
+                seek: AsyncMutex::new(Seek { pos: 0, cursor: None }),
+            })?;
+
+            Ok(handle)
+        })
+    }
+
+    fn stat(&self) -> FsFuture<'_, Stat> {
+        FsFuture::new(async move {
+            let state = self.node.state();
+            let size = if state.directory { 0 } else { state.size as u64 };
+
+            Ok(Stat { kind: self.node.kind(), size, permissions: FAT_PERMISSIONS })
+        })
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    // positions count the directory's slots, so that they stay put as
+    // entries come and go:
+    fn read_dir<'a>(&'a self, pos: u64, sink: &'a mut dyn DirSink) -> FsFuture<'a, ()> {
+        FsFuture::new(async move {
+            let volume = &self.node.volume;
+            let dir = self.node.directory()?;
+
+            let _meta = volume.meta.lock().await?;
+            let mut items = Items::new(volume, dir);
+
+            while let Some(item) = items.next().await? {
+                let entry = match item {
+                    Item::Entry(entry) if items.slots > pos => entry,
+                    _ => continue,
+                };
+
+                let kind = if entry.is_directory() { InodeKind::Directory } else { InodeKind::File };
+
+                if !sink.push(&entry.name, kind, items.slots) {
+                    break;
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    fn create<'a>(&'a self, name: &'a [u8], kind: InodeKind) -> FsFuture<'a, Arc<dyn vfs::Inode>> {
+        FsFuture::new(async move {
+            let volume = &self.node.volume;
+            let dir = self.node.directory()?;
+            let (short, nt_flags) = short_name(name)?;
+
+            let mut meta = volume.meta.lock().await?;
+            let slot = volume.free_slot(&mut meta, dir, name, &short, None).await?;
+
+            let directory = kind == InodeKind::Directory;
+
+            let first = if directory {
+                let cluster = volume.alloc_cluster(&mut meta, None).await?;
+                let sector = volume.cluster_sector(cluster)?;
+
+                let dot = new_entry(b".          ", 0, true, cluster);
+                let dotdot = new_entry(b"..         ", 0, true, volume.parent_cluster(dir));
+
+                volume.write_slot(Slot { sector, index: 0 }, &dot).await?;
+                volume.write_slot(Slot { sector, index: 1 }, &dotdot).await?;
+
+                cluster
+            } else {
+                0
+            };
+
+            let raw = new_entry(&short, nt_flags, directory, first);
+            volume.write_slot(slot, &raw).await?;
+
+            let entry = DirEntry { raw, name: Name::new(), slot, lfn_slots: ArrayVec::new() };
+            let node = NodeRef::get(volume, &entry)?;
+
+            Ok(Arc::new(Inode { node })?)
+        })
+    }
+
+    // open entries can't be removed, since their clusters would be reused
+    // while still being read and written. that fails with Busy:
+    fn unlink<'a>(&'a self, name: &'a [u8]) -> FsFuture<'a, ()> {
+        FsFuture::new(async move {
+            let volume = &self.node.volume;
+            let dir = self.node.directory()?;
+
+            let mut meta = volume.meta.lock().await?;
+
+            let entry = volume.find(dir, name).await?
+                .ok_or(SysError::NoFile)?;
+
+            if is_open(volume, &entry) {
+                return Err(SysError::Busy);
+            }
+
+            if entry.is_directory() && !volume.is_empty(entry.first_cluster()).await? {
+                return Err(SysError::NotEmpty);
+            }
+
+            volume.delete_entry(&entry).await?;
+            volume.free_chain(&mut meta, entry.first_cluster()).await
+        })
+    }
+
+    fn rename<'a>(&'a self, name: &'a [u8], to: &'a dyn vfs::Inode, to_name: &'a [u8]) -> FsFuture<'a, ()> {
+        FsFuture::new(async move {
+            let volume = &self.node.volume;
+
+            let to = to.as_any().downcast_ref::<Inode>()
+                .filter(|to| ptr::eq(&*to.node.volume, &**volume))
+                .ok_or(SysError::CrossDevice)?;
+
+            let from_dir = self.node.directory()?;
+            let to_dir = to.node.directory()?;
+            let (short, nt_flags) = short_name(to_name)?;
+
+            let mut meta = volume.meta.lock().await?;
+
+            let entry = volume.find(from_dir, name).await?
+                .ok_or(SysError::NoFile)?;
+
+            // the VFS keeps directories from being moved any further inside
+            // themselves, where they'd be unreachable:
+            if entry.is_directory() && entry.first_cluster() == to_dir {
+                return Err(SysError::IllegalValue);
+            }
+
+            let replaced = volume.find(to_dir, to_name).await?
+                .filter(|replaced| replaced.slot != entry.slot);
+
+            if let Some(replaced) = &replaced {
+                if replaced.is_directory() != entry.is_directory() {
+                    return Err(SysError::InvalidOperation);
+                }
+
+                if is_open(volume, replaced) {
+                    return Err(SysError::Busy);
+                }
+
+                if replaced.is_directory() && !volume.is_empty(replaced.first_cluster()).await? {
+                    return Err(SysError::NotEmpty);
+                }
+
+                volume.delete_entry(replaced).await?;
+                volume.free_chain(&mut meta, replaced.first_cluster()).await?;
+            }
+
+            let slot = volume.free_slot(&mut meta, to_dir, to_name, &short, Some(entry.slot)).await?;
+
+            let mut raw = entry.raw;
+            raw[0..11].copy_from_slice(&short);
+            raw[12] = nt_flags;
+
+            volume.write_slot(slot, &raw).await?;
+            volume.delete_entry(&entry).await?;
+
+            if entry.is_directory() && from_dir != to_dir {
+                volume.set_dotdot(entry.first_cluster(), to_dir).await?;
+            }
+
+            // anything open on the entry follows it to its new slot:
+            let mut nodes = volume.nodes.lock();
+
+            if let Some(node) = nodes.remove(&entry.slot) {
+                node.state.lock().slot = Some(slot);
+
+                nodes.insert(slot, node)
+                    .map_err(|_| SysError::MemoryExhausted)?;
+            }
+
+            Ok(())
+        })
+    }
+}
+
+impl Handle {
+    // the cluster at `index` in the file's chain, following it from the
+    // cursor where possible. with `meta`, the chain is extended to reach
+    // it, otherwise running off the end means the file is corrupt:
+    async fn cluster_at(&self, seek: &mut Seek, first: u32, index: u32, mut meta: Option<&mut Meta>)
+        -> SysResult<u32>
+    {
+        let volume = &self.node.volume;
+
+        let (mut at, mut cluster) = match seek.cursor {
+            Some((at, cluster)) if at <= index => (at, cluster),
+            _ => (0, first),
+        };
+
+        while at < index {
+            cluster = match volume.next_cluster(cluster).await? {
+                Some(next) => next,
+                None => match &mut meta {
+                    Some(meta) => volume.alloc_cluster(meta, Some(cluster)).await?,
+                    None => return Err(SysError::IoError),
+                },
+            };
+
+            at += 1;
+        }
+
+        seek.cursor = Some((at, cluster));
+
+        Ok(cluster)
+    }
+
+    // records a new size or first cluster in the node and its entry:
+    async fn update_entry(&self, first: u32, size: u32) -> SysResult<()> {
+        let volume = &self.node.volume;
+
+        let slot = {
+            let mut state = self.node.node.state.lock();
+            state.first_cluster = first;
+            state.size = size;
+            state.slot.expect("Handle::update_entry: file without an entry")
+        };
+
+        let mut raw = volume.read_slot(slot).await?;
+        set_first_cluster(&mut raw, first);
+        write_u32(&mut raw, 28, size);
+
+        volume.write_slot(slot, &raw).await
+    }
+
+    async fn write_at(&self, seek: &mut Seek, meta: &mut Meta, buf: &[u8]) -> SysResult<usize> {
+        let volume = &self.node.volume;
+        let cluster_size = volume.cluster_size() as u64;
+        let state = self.node.state();
+
+        if seek.pos >= FILE_SIZE_MAX {
+            return Err(SysError::NoSpace);
+        }
+
+        let len = cmp::min(buf.len() as u64, FILE_SIZE_MAX - seek.pos) as usize;
+
+        let mut first = state.first_cluster;
+        let mut done = 0;
+        let mut result = Ok(());
+
+        if first == 0 && len > 0 {
+            first = volume.alloc_cluster(meta, None).await?;
+            seek.cursor = None;
+        }
+
+        while done < len {
+            let at = seek.pos + done as u64;
+            let offset = (at % cluster_size) as usize;
+
+            let cluster = match self.cluster_at(seek, first, (at / cluster_size) as u32, Some(&mut *meta)).await {
+                Ok(cluster) => cluster,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            };
+
+            let lba = match volume.cluster_sector(cluster) {
+                Ok(sector) => sector + offset / SECTOR_SIZE,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            };
+
+            let sector_offset = offset % SECTOR_SIZE;
+            let count = cmp::min(SECTOR_SIZE - sector_offset, len - done);
+
+            // only part of the sector is written over:
+            let mut sector = if count < SECTOR_SIZE {
+                match volume.read_sector(lba).await {
+                    Ok(sector) => sector,
+                    Err(e) => {
+                        result = Err(e);
+                        break;
+                    }
+                }
+            } else {
+                [0u8; SECTOR_SIZE]
+            };
+
+            sector[sector_offset..(sector_offset + count)]
+                .copy_from_slice(&buf[done..(done + count)]);
+
+            if let Err(e) = volume.write_sector(lba, &sector).await {
+                result = Err(e);
+                break;
+            }
+
+            done += count;
+        }
+
+        let size = cmp::max(state.size as u64, seek.pos + done as u64) as u32;
+
+        if first != state.first_cluster || size != state.size {
+            self.update_entry(first, size).await?;
+        }
+
+        seek.pos += done as u64;
+
+        // what was written before an error still counts:
+        match result {
+            Err(e) if done == 0 => Err(e),
+            _ => Ok(done),
+        }
+    }
+}
+
+impl vfs::FileHandle for Handle {
+    fn read<'a>(&'a self, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        FsFuture::new(async move {
+            let volume = &self.node.volume;
+            let cluster_size = volume.cluster_size() as u64;
+
+            let mut seek = self.seek.lock().await?;
+            let state = self.node.state();
+
+            if state.directory {
+                return Err(SysError::InvalidOperation);
+            }
+
+            if seek.pos >= state.size as u64 {
+                return Ok(0);
+            }
+
+            let len = cmp::min(buf.len() as u64, state.size as u64 - seek.pos) as usize;
+            let mut done = 0;
+
+            while done < len {
+                let at = seek.pos + done as u64;
+                let offset = (at % cluster_size) as usize;
+
+                let cluster = self.cluster_at(&mut seek, state.first_cluster, (at / cluster_size) as u32, None).await?;
+
+                let lba = volume.cluster_sector(cluster)? + offset / SECTOR_SIZE;
+                let sector_offset = offset % SECTOR_SIZE;
+                let count = cmp::min(SECTOR_SIZE - sector_offset, len - done);
+
+                let sector = volume.read_sector(lba).await?;
+
+                buf[done..(done + count)]
+                    .copy_from_slice(&sector[sector_offset..(sector_offset + count)]);
+
+                done += count;
+            }
+
+            seek.pos += done as u64;
+
+            Ok(done)
+        })
+    }
+
+    fn write<'a>(&'a self, buf: &'a [u8]) -> FsFuture<'a, usize> {
+        FsFuture::new(async move {
+            if self.node.state().directory {
+                return Err(SysError::InvalidOperation);
+            }
+
+            let mut seek = self.seek.lock().await?;
+            let mut meta = self.node.volume.meta.lock().await?;
+
+            self.write_at(&mut seek, &mut meta, buf).await
+        })
+    }
+
+    // the cursor can stay, cluster_at only uses it for clusters past it:
+    fn seek(&self, from: SeekFrom) -> FsFuture<'_, u64> {
+        FsFuture::new(async move {
+            let mut seek = self.seek.lock().await?;
+            let state = self.node.state();
+
+            if state.directory {
+                return Err(SysError::InvalidOperation);
+            }
+
+            seek.pos = from.resolve(seek.pos, state.size as u64)?;
+
+            Ok(seek.pos)
+        })
+    }
+}
+
+impl Debug for Fat32 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Fat32({:?})", self.volume.part)
+    }
+}
+
+impl Debug for Inode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "fat32::Inode({:?})", self.node.state())
+    }
+}
+
+impl Debug for Handle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "fat32::Handle({:?})", self.node.state())
+    }
+}
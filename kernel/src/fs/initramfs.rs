@@ -0,0 +1,352 @@
+use core::iter;
+use core::ptr::NonNull;
+use core::slice;
+use core::str;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use arrayvec::ArrayVec;
+use interface::{SysError, SysResult};
+
+use crate::fs::vfs::{InodeKind, Namespace, Permissions};
+use crate::mem::kvirt;
+use crate::mem::page::PAGE_SIZE;
+use crate::mem::phys::RawPhys;
+use crate::mem::MemoryExhausted;
+use crate::task::Credentials;
+
+// the longest path an archive entry can have once normalized:
+const PATH_MAX: usize = 256;
+
+const CPIO_HEADER_SIZE: usize = 110;
+const CPIO_TRAILER: &[u8] = b"TRAILER!!!";
+
+// the file type bits of a cpio entry's mode, and the permission bits, which
+// are all a tar entry's mode has:
+const S_IFMT: u64 = 0o170000;
+const S_IPERM: u64 = 0o7777;
+const S_IFDIR: u64 = 0o040000;
+const S_IFREG: u64 = 0o100000;
+
+const TAR_BLOCK_SIZE: usize = 512;
+
+// where the loader left the initrd, recorded by initrd_init before low memory
+// is unmapped. a length of 0 means there isn't one:
+static INITRD_BASE: AtomicU64 = AtomicU64::new(0);
+static INITRD_LEN: AtomicU64 = AtomicU64::new(0);
+
+#[no_mangle]
+pub unsafe extern "C" fn initrd_init(base: RawPhys, len: u64) {
+    INITRD_BASE.store(base.0, Ordering::SeqCst);
+    INITRD_LEN.store(len, Ordering::SeqCst);
+}
+
+/// Whether the loader found an initrd to unpack.
+pub fn present() -> bool {
+    INITRD_LEN.load(Ordering::SeqCst) != 0
+}
+
+// the initrd mapped into the kernel's address space. its memory was never
+// the physical allocator's, so it stays reserved once unmapped:
+struct Initrd {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+impl Initrd {
+    fn map() -> Result<Option<Initrd>, MemoryExhausted> {
+        let len = INITRD_LEN.load(Ordering::SeqCst) as usize;
+
+        if len == 0 {
+            return Ok(None);
+        }
+
+        let base = RawPhys(INITRD_BASE.load(Ordering::SeqCst));
+        let ptr = unsafe { kvirt::map_phys(base, pages(len))? };
+
+        Ok(Some(Initrd { ptr, len }))
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for Initrd {
+    fn drop(&mut self) {
+        unsafe { kvirt::free_pages(self.ptr, pages(self.len)); }
+    }
+}
+
+fn pages(len: usize) -> usize {
+    (len + PAGE_SIZE - 1) / PAGE_SIZE
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EntryKind {
+    File,
+    Directory,
+    // symlinks, device nodes and the like, which have nowhere to go yet:
+    Other,
+}
+
+struct Entry<'a> {
+    // the full name is prefix/name. only tar splits long names up:
+    prefix: &'a [u8],
+    name: &'a [u8],
+    kind: EntryKind,
+    // permission bits only:
+    mode: u32,
+    data: &'a [u8],
+}
+
+type Path = ArrayVec<[u8; PATH_MAX]>;
+
+impl<'a> Entry<'a> {
+    // the absolute path the entry goes to, without empty or "." segments.
+    // archives mustn't climb out of the root with "..":
+    fn path(&self) -> SysResult<Path> {
+        let mut path = Path::new();
+
+        let segments = self.prefix.split(|b| *b == b'/')
+            .chain(self.name.split(|b| *b == b'/'))
+            .filter(|segment| *segment != b"" && *segment != b".");
+
+        for segment in segments {
+            if segment == b".." {
+                return Err(SysError::IllegalValue);
+            }
+
+            for byte in iter::once(&b'/').chain(segment.iter()) {
+                path.try_push(*byte)
+                    .map_err(|_| SysError::IllegalValue)?;
+            }
+        }
+
+        Ok(path)
+    }
+}
+
+// parses the entry at the start of an archive, returning it along with the
+// rest of the archive, or None at the end of the archive:
+type NextEntry = fn(&[u8]) -> SysResult<Option<(Entry, &[u8])>>;
+
+fn format_of(archive: &[u8]) -> SysResult<NextEntry> {
+    if archive.starts_with(b"070701") || archive.starts_with(b"070702") {
+        Ok(next_cpio)
+    } else if archive.get(257..262) == Some(&b"ustar"[..]) {
+        Ok(next_tar)
+    } else {
+        Err(SysError::IllegalValue)
+    }
+}
+
+// newc cpio, as made by `cpio -H newc`. the header is all 8 digit hex
+// fields, and the name and data that follow it are each padded to 4 bytes:
+fn next_cpio(archive: &[u8]) -> SysResult<Option<(Entry, &[u8])>> {
+    let header = archive.get(..CPIO_HEADER_SIZE)
+        .ok_or(SysError::IllegalValue)?;
+
+    if &header[0..6] != b"070701" && &header[0..6] != b"070702" {
+        return Err(SysError::IllegalValue);
+    }
+
+    let field = |index: usize| parse_hex(&header[(6 + index * 8)..(14 + index * 8)]);
+
+    let mode = field(1)?;
+    let file_size = field(6)? as usize;
+    let name_size = field(11)? as usize;
+
+    let name_end = CPIO_HEADER_SIZE.checked_add(name_size)
+        .ok_or(SysError::IllegalValue)?;
+
+    let name = until_nul(archive.get(CPIO_HEADER_SIZE..name_end)
+        .ok_or(SysError::IllegalValue)?);
+
+    if name == CPIO_TRAILER {
+        return Ok(None);
+    }
+
+    let data_start = align(name_end, 4);
+    let data_end = data_start.checked_add(file_size)
+        .ok_or(SysError::IllegalValue)?;
+
+    let data = archive.get(data_start..data_end)
+        .ok_or(SysError::IllegalValue)?;
+
+    let kind = match mode & S_IFMT {
+        S_IFREG => EntryKind::File,
+        S_IFDIR => EntryKind::Directory,
+        _ => EntryKind::Other,
+    };
+
+    let entry = Entry { prefix: b"", name, kind, mode: (mode & S_IPERM) as u32, data };
+    let rest = archive.get(align(data_end, 4)..).unwrap_or(&[]);
+
+    Ok(Some((entry, rest)))
+}
+
+// ustar, as made by `tar --format=ustar`. each entry is a 512 byte header
+// block followed by its data padded to whole blocks, and the archive ends
+// with a zeroed block:
+fn next_tar(archive: &[u8]) -> SysResult<Option<(Entry, &[u8])>> {
+    let header = archive.get(..TAR_BLOCK_SIZE)
+        .ok_or(SysError::IllegalValue)?;
+
+    if header.iter().all(|b| *b == 0) {
+        return Ok(None);
+    }
+
+    if &header[257..262] != b"ustar" {
+        return Err(SysError::IllegalValue);
+    }
+
+    let mode = parse_octal(&header[100..108])?;
+    let size = parse_octal(&header[124..136])? as usize;
+
+    let data_end = TAR_BLOCK_SIZE.checked_add(size)
+        .ok_or(SysError::IllegalValue)?;
+
+    let data = archive.get(TAR_BLOCK_SIZE..data_end)
+        .ok_or(SysError::IllegalValue)?;
+
+    let kind = match header[156] {
+        b'0' | 0 => EntryKind::File,
+        b'5' => EntryKind::Directory,
+        _ => EntryKind::Other,
+    };
+
+    let entry = Entry {
+        prefix: until_nul(&header[345..500]),
+        name: until_nul(&header[0..100]),
+        kind,
+        mode: (mode & S_IPERM) as u32,
+        data,
+    };
+
+    let rest = archive.get(align(data_end, TAR_BLOCK_SIZE)..).unwrap_or(&[]);
+
+    Ok(Some((entry, rest)))
+}
+
+fn parse_hex(digits: &[u8]) -> SysResult<u64> {
+    digits.iter().try_fold(0u64, |value, digit| {
+        let digit = (*digit as char).to_digit(16)
+            .ok_or(SysError::IllegalValue)?;
+
+        Ok(value << 4 | digit as u64)
+    })
+}
+
+// tar pads its octal fields with spaces or NULs on either side:
+fn parse_octal(digits: &[u8]) -> SysResult<u64> {
+    digits.iter()
+        .filter(|digit| **digit != b' ' && **digit != 0)
+        .try_fold(0u64, |value, digit| {
+            let digit = (*digit as char).to_digit(8)
+                .ok_or(SysError::IllegalValue)?;
+
+            value.checked_mul(8)
+                .map(|value| value | digit as u64)
+                .ok_or(SysError::IllegalValue)
+        })
+}
+
+fn until_nul(bytes: &[u8]) -> &[u8] {
+    bytes.split(|b| *b == 0).next().unwrap_or(bytes)
+}
+
+fn align(offset: usize, to: usize) -> usize {
+    (offset + to - 1) & !(to - 1)
+}
+
+/// Unpacks the initrd left by the loader into `namespace`, if there is one.
+/// It can be a newc cpio or a ustar archive. Entries go to the same paths
+/// in the namespace as in the archive, with any missing directories on the
+/// way created. Everything belongs to root, with the mode it has in the
+/// archive.
+pub async fn unpack(namespace: &Namespace) -> SysResult<()> {
+    let initrd = match Initrd::map()? {
+        Some(initrd) => initrd,
+        None => return Ok(()),
+    };
+
+    let mut archive = initrd.as_slice();
+    let next = format_of(archive)?;
+    let mut files = 0;
+
+    while let Some((entry, rest)) = next(archive)? {
+        archive = rest;
+
+        let path = entry.path()?;
+
+        // the root itself:
+        if path.is_empty() {
+            continue;
+        }
+
+        match entry.kind {
+            EntryKind::Directory => {
+                create_directories(namespace, &path).await?;
+                set_mode(namespace, &path, entry.mode).await?;
+            }
+            EntryKind::File => {
+                let parent = path.iter().rposition(|b| *b == b'/').unwrap_or(0);
+                create_directories(namespace, &path[..parent]).await?;
+                write_file(namespace, &path, entry.data).await?;
+                set_mode(namespace, &path, entry.mode).await?;
+                files += 1;
+            }
+            EntryKind::Other => {
+                crate::println!("initramfs: skipping {:?}, not a file or directory",
+                    str::from_utf8(&path).unwrap_or("(non utf-8 path)"));
+            }
+        }
+    }
+
+    crate::println!("initramfs: unpacked {} files", files);
+
+    Ok(())
+}
+
+// creates the directory at `path` and each one above it, unless they're
+// there already:
+async fn create_directories(namespace: &Namespace, path: &[u8]) -> SysResult<()> {
+    let ends = (1..path.len())
+        .filter(|end| path[*end] == b'/')
+        .chain(iter::once(path.len()))
+        .filter(|end| *end > 0);
+
+    for end in ends {
+        match namespace.create(&path[..end], InodeKind::Directory, &Credentials::ROOT).await {
+            Ok(_) | Err(SysError::AlreadyMapped) => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+async fn write_file(namespace: &Namespace, path: &[u8], mut data: &[u8]) -> SysResult<()> {
+    let inode = namespace.create(path, InodeKind::File, &Credentials::ROOT).await?;
+    let handle = inode.open().await?;
+
+    while !data.is_empty() {
+        let written = handle.write(data).await?;
+
+        if written == 0 {
+            return Err(SysError::IoError);
+        }
+
+        data = &data[written..];
+    }
+
+    Ok(())
+}
+
+// the archive's owners mean nothing here, so only the mode is kept:
+async fn set_mode(namespace: &Namespace, path: &[u8], mode: u32) -> SysResult<()> {
+    match namespace.lookup(path).await?.set_permissions(Permissions::root(mode)).await {
+        Ok(()) | Err(SysError::InvalidOperation) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
@@ -1,4 +1,40 @@
+pub mod devfs;
+pub mod ext2;
 pub mod fat16;
+pub mod fat32;
+pub mod initramfs;
+pub mod pipe;
+pub mod procfs;
+pub mod tmpfs;
 pub mod vfs;
 
+use interface::{SysError, SysResult};
+
+use crate::device::block;
+use crate::sync::Arc;
+
 pub use vfs::File;
+use vfs::Filesystem;
+
+/// Sets up a filesystem of type `fstype`, as named for the Mount syscall,
+/// to be mounted. The ones kept on disk are opened from the block device
+/// registered as `source`.
+pub async fn open(fstype: &[u8], source: &[u8]) -> SysResult<Arc<dyn Filesystem>> {
+    let fs: Arc<dyn Filesystem> = match fstype {
+        b"tmpfs" => Arc::new(tmpfs::Tmpfs::new()?)?,
+        b"devfs" => Arc::new(devfs::Devfs)?,
+        b"procfs" => Arc::new(procfs::Procfs)?,
+        b"fat16" | b"fat32" | b"ext2" => {
+            let disk = block::get(source).ok_or(SysError::NoFile)?;
+
+            match fstype {
+                b"fat16" => Arc::new(fat16::Fat16::open(disk).await?)?,
+                b"fat32" => Arc::new(fat32::Fat32::open(disk).await?)?,
+                _ => Arc::new(ext2::Ext2::open(disk).await?)?,
+            }
+        }
+        _ => return Err(SysError::IllegalValue),
+    };
+
+    Ok(fs)
+}
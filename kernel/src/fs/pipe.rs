@@ -0,0 +1,224 @@
+use core::fmt::{self, Debug};
+use core::task::{Poll, Waker};
+
+use arraydeque::{ArrayDeque, Saturating};
+use futures::future;
+use interface::{SysError, SysResult};
+
+use crate::config::PIPE_BUFFER_SIZE;
+use crate::mem::MemoryExhausted;
+use crate::object::poll::Events;
+use crate::sync::{Arc, Mutex};
+use crate::util::AtomicList;
+
+struct State {
+    buff: ArrayDeque<[u8; PIPE_BUFFER_SIZE], Saturating>,
+    // each end closes once the last handle to it is released:
+    reader_open: bool,
+    writer_open: bool,
+}
+
+/// A one way stream of bytes between a reader and a writer, through a fixed
+/// size ring buffer. Reading from an empty pipe or writing to a full one
+/// suspends the task until the other end catches up.
+struct Pipe {
+    state: Mutex<State>,
+    read_wakers: AtomicList<Waker>,
+    write_wakers: AtomicList<Waker>,
+}
+
+impl Pipe {
+    fn wake_readers(&self) {
+        for waker in self.read_wakers.take_iter() {
+            waker.wake();
+        }
+    }
+
+    fn wake_writers(&self) {
+        for waker in self.write_wakers.take_iter() {
+            waker.wake();
+        }
+    }
+}
+
+/// The read end of a pipe.
+pub struct Reader(Arc<Pipe>);
+
+/// The write end of a pipe.
+pub struct Writer(Arc<Pipe>);
+
+/// Creates a pipe, returning its two ends.
+pub fn new() -> Result<(Reader, Writer), MemoryExhausted> {
+    let pipe = Arc::new(Pipe {
+        state: Mutex::new(State {
+            buff: ArrayDeque::new(),
+            reader_open: true,
+            writer_open: true,
+        }),
+        read_wakers: AtomicList::new(),
+        write_wakers: AtomicList::new(),
+    })?;
+
+    Ok((Reader(pipe.clone()), Writer(pipe)))
+}
+
+impl Reader {
+    /// Reads whatever is in the pipe, up to `buf.len()` bytes, waiting for
+    /// the writer if it's empty. Returns 0 once the pipe is empty and the
+    /// write end is closed.
+    pub async fn read(&self, buf: &mut [u8]) -> SysResult<usize> {
+        let pipe = &self.0;
+
+        let count = future::poll_fn(|ctx| {
+            // register waker before checking the buffer so that we can't miss
+            // a write in between:
+            if let Err(MemoryExhausted) = pipe.read_wakers.push_front(ctx.waker().clone()) {
+                return Poll::Ready(Err(SysError::MemoryExhausted));
+            }
+
+            let mut state = pipe.state.lock();
+
+            if buf.len() == 0 || (state.buff.is_empty() && !state.writer_open) {
+                return Poll::Ready(Ok(0));
+            }
+
+            let mut count = 0;
+
+            while count < buf.len() {
+                match state.buff.pop_front() {
+                    Some(byte) => {
+                        buf[count] = byte;
+                        count += 1;
+                    }
+                    None => break,
+                }
+            }
+
+            if count == 0 {
+                Poll::Pending
+            } else {
+                Poll::Ready(Ok(count))
+            }
+        }).await?;
+
+        // there's room for the writer now:
+        pipe.wake_writers();
+
+        Ok(count)
+    }
+
+    /// Readable with data in the pipe, HUP once the write end is closed.
+    pub fn poll_ready(&self, waker: &Waker) -> Result<Events, MemoryExhausted> {
+        self.0.read_wakers.push_front(waker.clone())?;
+
+        let state = self.0.state.lock();
+        let mut events = Events::empty();
+
+        if !state.buff.is_empty() {
+            events |= Events::IN;
+        }
+
+        if !state.writer_open {
+            events |= Events::HUP;
+        }
+
+        Ok(events)
+    }
+}
+
+impl Writer {
+    /// Writable with room in the pipe, ERR once the read end is closed.
+    pub fn poll_ready(&self, waker: &Waker) -> Result<Events, MemoryExhausted> {
+        self.0.write_wakers.push_front(waker.clone())?;
+
+        let state = self.0.state.lock();
+        let mut events = Events::empty();
+
+        if !state.buff.is_full() {
+            events |= Events::OUT;
+        }
+
+        if !state.reader_open {
+            events |= Events::ERR;
+        }
+
+        Ok(events)
+    }
+
+    /// Writes as much of `buf` as fits in the pipe, waiting for the reader if
+    /// it's full. Fails with BrokenPipe if the read end is closed.
+    pub async fn write(&self, buf: &[u8]) -> SysResult<usize> {
+        let pipe = &self.0;
+
+        let count = future::poll_fn(|ctx| {
+            // register waker before checking the buffer so that we can't miss
+            // a read in between:
+            if let Err(MemoryExhausted) = pipe.write_wakers.push_front(ctx.waker().clone()) {
+                return Poll::Ready(Err(SysError::MemoryExhausted));
+            }
+
+            let mut state = pipe.state.lock();
+
+            if !state.reader_open {
+                return Poll::Ready(Err(SysError::BrokenPipe));
+            }
+
+            if buf.len() == 0 {
+                return Poll::Ready(Ok(0));
+            }
+
+            let count = buf.iter()
+                .take_while(|byte| state.buff.push_back(**byte).is_ok())
+                .count();
+
+            if count == 0 {
+                Poll::Pending
+            } else {
+                Poll::Ready(Ok(count))
+            }
+        }).await?;
+
+        // there's something for the reader now:
+        pipe.wake_readers();
+
+        Ok(count)
+    }
+}
+
+impl Drop for Reader {
+    fn drop(&mut self) {
+        self.0.state.lock().reader_open = false;
+
+        // blocked writers fail with BrokenPipe:
+        self.0.wake_writers();
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        self.0.state.lock().writer_open = false;
+
+        // blocked readers see the end of the stream:
+        self.0.wake_readers();
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        // both ends are gone, so anything still waiting was woken already:
+        self.read_wakers.take_iter().for_each(drop);
+        self.write_wakers.take_iter().for_each(drop);
+    }
+}
+
+impl Debug for Reader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "pipe::Reader")
+    }
+}
+
+impl Debug for Writer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "pipe::Writer")
+    }
+}
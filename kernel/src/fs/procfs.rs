@@ -0,0 +1,454 @@
+use core::any::Any;
+use core::cmp;
+use core::fmt::{self, Debug, Write};
+use core::ptr::NonNull;
+use core::slice;
+use core::str;
+
+use arrayvec::ArrayString;
+use interface::{SysError, SysResult};
+
+use crate::fs::vfs::{self, DirSink, File, FsFuture, InodeKind, Permissions, SeekFrom, Stat};
+use crate::mem::kvirt;
+use crate::mem::page::{PageFlags, PAGE_SIZE};
+use crate::mem::vma::Backing;
+use crate::mem::{self, MemoryExhausted};
+use crate::object::{ObjectKind, Rights};
+use crate::sync::{Arc, AsyncMutex};
+use crate::task::{self, Credentials, SchedClass, TaskId};
+use crate::time;
+
+/// A read-only view of the kernel's state, with a directory for each task
+/// and files for system wide memory usage and uptime. Each file's contents
+/// are made up when it's first read from, so a file open for a while shows
+/// things as they were then. A task's directory belongs to whoever its
+/// process runs as, and only they and root may read it.
+#[derive(Debug)]
+pub struct Procfs;
+
+enum Inode {
+    Root,
+    Task(TaskId),
+    File(ProcFile),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ProcFile {
+    Meminfo,
+    Uptime,
+    Status(TaskId),
+    Maps(TaskId),
+    Fds(TaskId),
+}
+
+struct Handle {
+    file: ProcFile,
+    state: AsyncMutex<HandleState>,
+}
+
+struct HandleState {
+    pos: u64,
+    text: Option<Text>,
+}
+
+// the entries of the root directory before the tasks, whose positions in a
+// listing are their ids offset by the number of these:
+const ROOT_FILES: [(&[u8], InodeKind); 3] = [
+    (b"meminfo", InodeKind::File),
+    (b"uptime", InodeKind::File),
+    (b"self", InodeKind::Directory),
+];
+
+const TASK_FILES: [&[u8]; 3] = [b"status", b"maps", b"fd"];
+
+// a file's contents, in a page of its own. anything past the end of the
+// page is cut off:
+struct Text {
+    page: NonNull<u8>,
+    len: usize,
+}
+
+impl vfs::Filesystem for Procfs {
+    fn root(&self) -> SysResult<Arc<dyn vfs::Inode>> {
+        Ok(Arc::new(Inode::Root)?)
+    }
+}
+
+impl vfs::Inode for Inode {
+    fn kind(&self) -> InodeKind {
+        match self {
+            Inode::Root | Inode::Task(_) => InodeKind::Directory,
+            Inode::File(_) => InodeKind::File,
+        }
+    }
+
+    fn lookup<'a>(&'a self, name: &'a [u8]) -> FsFuture<'a, Option<Arc<dyn vfs::Inode>>> {
+        FsFuture::new(async move {
+            let inode = match (self, name) {
+                (Inode::Root, b"meminfo") => Inode::File(ProcFile::Meminfo),
+                (Inode::Root, b"uptime") => Inode::File(ProcFile::Uptime),
+                (Inode::Root, b"self") => Inode::Task(task::current()),
+                (Inode::Root, name) => match task_id(name) {
+                    Some(task_id) => Inode::Task(task_id),
+                    None => return Ok(None),
+                },
+                (Inode::Task(task_id), b"status") => Inode::File(ProcFile::Status(*task_id)),
+                (Inode::Task(task_id), b"maps") => Inode::File(ProcFile::Maps(*task_id)),
+                (Inode::Task(task_id), b"fd") => Inode::File(ProcFile::Fds(*task_id)),
+                (Inode::Task(_), _) => return Ok(None),
+                (Inode::File(_), _) => return Err(SysError::InvalidOperation),
+            };
+
+            let inode: Arc<dyn vfs::Inode> = Arc::new(inode)?;
+
+            Ok(Some(inode))
+        })
+    }
+
+    fn open(&self) -> FsFuture<'_, Arc<dyn vfs::FileHandle>> {
+        FsFuture::new(async move {
+            let file = match self {
+                Inode::File(file) => *file,
+                Inode::Root | Inode::Task(_) => return Err(SysError::InvalidOperation),
+            };
+
+            // a task's address space layout and handles are nobody else's
+            // business. the mode says as much, but the file is made up as
+            // whoever reads it, so check it here too:
+            if let Some(task_id) = file.task() {
+                let credentials = task::current_process().credentials();
+
+                if !credentials.has_authority_over(&owner(task_id)?) {
+                    return Err(SysError::PermissionDenied);
+                }
+            }
+
+            let handle: Arc<dyn vfs::FileHandle> = Arc::new(Handle {
+                file,
+                state: AsyncMutex::new(HandleState { pos: 0, text: None }),
+            })?;
+
+            Ok(handle)
+        })
+    }
+
+    // files are made up as they're read, so they have no size until then.
+    // anyone may read the system wide ones, only a task's owner its own, and
+    // nobody may write them:
+    fn stat(&self) -> FsFuture<'_, Stat> {
+        FsFuture::new(async move {
+            let kind = vfs::Inode::kind(self);
+
+            let task_id = match self {
+                Inode::Root => None,
+                Inode::Task(task_id) => Some(*task_id),
+                Inode::File(file) => file.task(),
+            };
+
+            let permissions = match (task_id, kind) {
+                (None, InodeKind::File) => Permissions::root(0o444),
+                (None, InodeKind::Directory) => Permissions::root(0o555),
+                (Some(task_id), kind) => {
+                    let owner = owner(task_id)?;
+
+                    let mode = match kind {
+                        InodeKind::File => 0o400,
+                        InodeKind::Directory => 0o500,
+                    };
+
+                    Permissions { mode, uid: owner.uid, gid: owner.gid }
+                }
+            };
+
+            Ok(Stat { kind, size: 0, permissions })
+        })
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn read_dir<'a>(&'a self, pos: u64, sink: &'a mut dyn DirSink) -> FsFuture<'a, ()> {
+        FsFuture::new(async move {
+            match self {
+                Inode::Root => list_root(pos, sink),
+                Inode::Task(_) => {
+                    for (index, name) in TASK_FILES.iter().enumerate().skip(pos as usize) {
+                        if !sink.push(name, InodeKind::File, index as u64 + 1) {
+                            break;
+                        }
+                    }
+                }
+                Inode::File(_) => return Err(SysError::InvalidOperation),
+            }
+
+            Ok(())
+        })
+    }
+}
+
+fn list_root(pos: u64, sink: &mut dyn DirSink) {
+    let fixed = ROOT_FILES.len() as u64;
+
+    for (index, (name, kind)) in ROOT_FILES.iter().enumerate().skip(pos as usize) {
+        if !sink.push(name, *kind, index as u64 + 1) {
+            return;
+        }
+    }
+
+    let mut next = TaskId(pos.saturating_sub(fixed));
+
+    while let Some(task_id) = task::next_id(next) {
+        let mut name = ArrayString::<[u8; 20]>::new();
+        let _ = write!(name, "{}", task_id.0);
+
+        if !sink.push(name.as_bytes(), InodeKind::Directory, fixed + task_id.0 + 1) {
+            return;
+        }
+
+        next = TaskId(task_id.0 + 1);
+    }
+}
+
+// whoever the process of a task runs as, who owns its directory:
+fn owner(task_id: TaskId) -> SysResult<Credentials> {
+    Ok(task::info(task_id)?.process.credentials())
+}
+
+// the task a directory name refers to, if it's the decimal id of one that
+// exists:
+fn task_id(name: &[u8]) -> Option<TaskId> {
+    if name.is_empty() || !name.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+
+    let task_id = TaskId(str::from_utf8(name).ok()?.parse().ok()?);
+
+    task::info(task_id).ok().map(|_| task_id)
+}
+
+impl vfs::FileHandle for Handle {
+    fn seek(&self, from: SeekFrom) -> FsFuture<'_, u64> {
+        FsFuture::new(async move {
+            let mut state = self.state.lock().await?;
+            let len = state.text(self.file)?.len;
+
+            state.pos = from.resolve(state.pos, len as u64)?;
+
+            Ok(state.pos)
+        })
+    }
+
+    fn read<'a>(&'a self, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        FsFuture::new(async move {
+            let mut state = self.state.lock().await?;
+            let pos = state.pos;
+            let text = state.text(self.file)?.as_slice();
+
+            // seeking can go past the end:
+            let start = cmp::min(pos, text.len() as u64) as usize;
+            let count = cmp::min(buf.len(), text.len() - start);
+
+            buf[..count].copy_from_slice(&text[start..(start + count)]);
+
+            state.pos += count as u64;
+
+            Ok(count)
+        })
+    }
+}
+
+impl HandleState {
+    // the file's contents, made up the first time they're needed:
+    fn text(&mut self, file: ProcFile) -> SysResult<&Text> {
+        if self.text.is_none() {
+            self.text = Some(file.generate()?);
+        }
+
+        Ok(self.text.as_ref().expect("procfs::HandleState::text: text just made"))
+    }
+}
+
+impl ProcFile {
+    // the task the file is about, if it's not system wide:
+    fn task(&self) -> Option<TaskId> {
+        match *self {
+            ProcFile::Meminfo | ProcFile::Uptime => None,
+            ProcFile::Status(task_id) | ProcFile::Maps(task_id) | ProcFile::Fds(task_id) => Some(task_id),
+        }
+    }
+
+    fn generate(&self) -> SysResult<Text> {
+        let mut text = Text::new()?;
+
+        match *self {
+            ProcFile::Meminfo => meminfo(&mut text),
+            ProcFile::Uptime => uptime(&mut text),
+            ProcFile::Status(task_id) => status(&mut text, task_id)?,
+            ProcFile::Maps(task_id) => maps(&mut text, task_id)?,
+            ProcFile::Fds(task_id) => fds(&mut text, task_id)?,
+        }
+
+        Ok(text)
+    }
+}
+
+// writes to a Text never fail, they're cut off instead:
+fn meminfo(text: &mut Text) {
+    let stats = mem::stats();
+    let kb = |pages: usize| pages * PAGE_SIZE / 1024;
+
+    let _ = writeln!(text, "MemTotal:       {:>8} kB", kb(stats.phys_total));
+    let _ = writeln!(text, "MemFree:        {:>8} kB", kb(stats.phys_free));
+    let _ = writeln!(text, "KernelHeap:     {:>8} kB", kb(stats.heap_pages));
+    let _ = writeln!(text, "KernelHeapUsed: {:>8} kB", stats.heap_used / 1024);
+}
+
+fn uptime(text: &mut Text) {
+    let ns = time::monotonic_ns();
+
+    let _ = writeln!(text, "{}.{:02}", ns / 1_000_000_000, ns % 1_000_000_000 / 10_000_000);
+}
+
+fn status(text: &mut Text, task_id: TaskId) -> SysResult<()> {
+    let info = task::info(task_id)?;
+
+    let _ = writeln!(text, "Name:     {}", info.name.as_str());
+    let _ = writeln!(text, "State:    {}", info.state);
+    let _ = writeln!(text, "Tid:      {}", task_id.0);
+    let _ = writeln!(text, "Pid:      {}", info.process.id().0);
+    let _ = writeln!(text, "PPid:     {}", info.process.parent().map_or(0, |parent| parent.0));
+    let _ = writeln!(text, "Pgid:     {}", info.process.group().0);
+    let _ = writeln!(text, "Parent:   {}", info.parent.map_or(0, |parent| parent.0));
+    let _ = writeln!(text, "Cpu:      {}", info.cpu);
+
+    let _ = match info.class {
+        SchedClass::Normal => writeln!(text, "Class:    normal"),
+        SchedClass::Fifo(priority) => writeln!(text, "Class:    fifo {}", priority.index()),
+        SchedClass::RoundRobin(priority) => writeln!(text, "Class:    round robin {}", priority.index()),
+    };
+
+    let _ = writeln!(text, "Priority: {}", info.priority.into_u64());
+    let _ = writeln!(text, "RunTime:  {} ns", info.stats.run_time_ns);
+    let _ = writeln!(text, "Switches: {}", info.stats.switches);
+    let _ = writeln!(text, "Syscalls: {}", info.stats.syscalls);
+
+    Ok(())
+}
+
+// one line per VMA, with its range, protection and backing, like Linux's
+// /proc/<pid>/maps:
+fn maps(text: &mut Text, task_id: TaskId) -> SysResult<()> {
+    let info = task::info(task_id)?;
+    let page_ctx = info.process.page_ctx();
+    let vmas = page_ctx.object().vmas().lock();
+
+    for vma in vmas.iter() {
+        let write = if vma.flags.contains(PageFlags::WRITE) { 'w' } else { '-' };
+        let execute = if vma.flags.contains(PageFlags::NO_EXECUTE) { '-' } else { 'x' };
+
+        let backing = match vma.backing {
+            Backing::Anonymous => "anonymous",
+            Backing::Shared => "shared",
+        };
+
+        let _ = writeln!(text, "{:016x}-{:016x} r{}{} {}", vma.start, vma.end, write, execute, backing);
+    }
+
+    if let Some(heap) = vmas.heap() {
+        let _ = writeln!(text, "{:016x}-{:016x} heap", heap.start, heap.brk);
+    }
+
+    Ok(())
+}
+
+// one line per handle, with its rights and the kind of object it's a handle
+// to:
+fn fds(text: &mut Text, task_id: TaskId) -> SysResult<()> {
+    let info = task::info(task_id)?;
+    let handles = info.process.handles().lock();
+
+    for (handle, capability) in handles.iter() {
+        let handle = handle.into_u64();
+        let rights = rights(capability.rights);
+        let rights = str::from_utf8(&rights).expect("rights are ascii");
+
+        let _ = match capability.object.kind() {
+            ObjectKind::File(File::Console) => writeln!(text, "{} {} file console", handle, rights),
+            ObjectKind::File(File::Fs(file)) => writeln!(text, "{} {} file {:?}", handle, rights, file),
+            ObjectKind::File(File::PipeReader(_)) => writeln!(text, "{} {} file pipe reader", handle, rights),
+            ObjectKind::File(File::PipeWriter(_)) => writeln!(text, "{} {} file pipe writer", handle, rights),
+            kind => writeln!(text, "{} {} {}", handle, rights, kind.name()),
+        };
+    }
+
+    Ok(())
+}
+
+// a letter for each right, like the permissions in ls -l:
+fn rights(rights: Rights) -> [u8; 5] {
+    let letters = [
+        (Rights::READ, b'r'),
+        (Rights::WRITE, b'w'),
+        (Rights::MAP, b'm'),
+        (Rights::DUP, b'd'),
+        (Rights::SIGNAL, b's'),
+    ];
+
+    let mut text = [b'-'; 5];
+
+    for (index, (right, letter)) in letters.iter().enumerate() {
+        if rights.contains(*right) {
+            text[index] = *letter;
+        }
+    }
+
+    text
+}
+
+impl Text {
+    fn new() -> Result<Text, MemoryExhausted> {
+        Ok(Text { page: kvirt::alloc_page::<u8>()?, len: 0 })
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.page.as_ptr(), self.len) }
+    }
+}
+
+impl Write for Text {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let count = cmp::min(s.len(), PAGE_SIZE - self.len);
+
+        unsafe {
+            let dest = slice::from_raw_parts_mut(self.page.as_ptr().add(self.len), count);
+            dest.copy_from_slice(&s.as_bytes()[..count]);
+        }
+
+        self.len += count;
+
+        Ok(())
+    }
+}
+
+impl Drop for Text {
+    fn drop(&mut self) {
+        unsafe { kvirt::free_page(self.page); }
+    }
+}
+
+impl Debug for Inode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Inode::Root => write!(f, "procfs::Inode(/)"),
+            Inode::Task(task_id) => write!(f, "procfs::Inode({})", task_id.0),
+            Inode::File(file) => write!(f, "procfs::Inode({:?})", file),
+        }
+    }
+}
+
+impl Debug for Handle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "procfs::Handle({:?})", self.file)
+    }
+}
@@ -0,0 +1,520 @@
+use core::any::Any;
+use core::cmp;
+use core::fmt::{self, Debug};
+use core::ptr::{self, NonNull};
+use core::slice;
+
+use alloc_collections::btree_map::BTreeMap;
+use arrayvec::ArrayVec;
+use interface::{SysError, SysResult};
+
+use crate::config::TMPFS_NAME_MAX;
+use crate::fs::vfs::{self, DirSink, FsFuture, InodeKind, Permissions, SeekFrom, Stat};
+use crate::mem::kalloc::GlobalAlloc;
+use crate::mem::kvirt;
+use crate::mem::page::PAGE_SIZE;
+use crate::mem::MemoryExhausted;
+use crate::sync::{Arc, AsyncMutex, Mutex, MutexGuard};
+
+type Name = ArrayVec<[u8; TMPFS_NAME_MAX]>;
+
+/// A read/write filesystem kept entirely in memory, with file contents in
+/// pages from the kernel's page allocator. Its contents go away along with
+/// the last reference to it.
+pub struct Tmpfs {
+    shared: Arc<Shared>,
+    root: Arc<Node>,
+}
+
+struct Shared {
+    // held by operations that lock more than one directory, so that they
+    // can't deadlock against each other. operations on a single directory
+    // only take its own lock:
+    tree_lock: Mutex<()>,
+}
+
+enum Node {
+    File(AsyncMutex<FileData>),
+    Directory(Mutex<Directory>),
+}
+
+struct Directory {
+    permissions: Permissions,
+    entries: BTreeMap<Name, Arc<Node>, GlobalAlloc>,
+    // set once the directory is removed, so nothing can be created in it
+    // through an inode looked up before:
+    unlinked: bool,
+}
+
+struct FileData {
+    permissions: Permissions,
+    len: u64,
+    // by page index. pages never written to are holes, which read as zeros:
+    pages: BTreeMap<u64, Page, GlobalAlloc>,
+}
+
+struct Page(NonNull<u8>);
+
+/// A tmpfs file or directory, as handed to the VFS.
+struct Inode {
+    shared: Arc<Shared>,
+    node: Arc<Node>,
+}
+
+/// A tmpfs file or directory opened through the VFS.
+struct Handle {
+    node: Arc<Node>,
+    pos: AsyncMutex<u64>,
+}
+
+impl Tmpfs {
+    pub fn new() -> Result<Tmpfs, MemoryExhausted> {
+        Ok(Tmpfs {
+            shared: Arc::new(Shared { tree_lock: Mutex::new(()) })?,
+            // anyone may create files in the root, like /tmp on Unix:
+            root: Node::new(InodeKind::Directory, Permissions::root(0o1777))?,
+        })
+    }
+}
+
+impl vfs::Filesystem for Tmpfs {
+    fn root(&self) -> SysResult<Arc<dyn vfs::Inode>> {
+        Ok(Inode::new(&self.shared, &self.root)?)
+    }
+}
+
+impl Node {
+    fn new(kind: InodeKind, permissions: Permissions) -> Result<Arc<Node>, MemoryExhausted> {
+        Arc::new(match kind {
+            InodeKind::File => Node::File(AsyncMutex::new(FileData {
+                permissions,
+                len: 0,
+                pages: BTreeMap::new(),
+            })),
+            InodeKind::Directory => Node::Directory(Mutex::new(Directory {
+                permissions,
+                entries: BTreeMap::new(),
+                unlinked: false,
+            })),
+        })
+    }
+
+    fn kind(&self) -> InodeKind {
+        match self {
+            Node::File(_) => InodeKind::File,
+            Node::Directory(_) => InodeKind::Directory,
+        }
+    }
+
+    fn directory(&self) -> SysResult<&Mutex<Directory>> {
+        match self {
+            Node::Directory(dir) => Ok(dir),
+            Node::File(_) => Err(SysError::InvalidOperation),
+        }
+    }
+}
+
+impl Directory {
+    fn live(&mut self) -> SysResult<&mut BTreeMap<Name, Arc<Node>, GlobalAlloc>> {
+        if self.unlinked {
+            Err(SysError::NoFile)
+        } else {
+            Ok(&mut self.entries)
+        }
+    }
+}
+
+fn name(name: &[u8]) -> SysResult<Name> {
+    let mut buf = Name::new();
+
+    for byte in name {
+        buf.try_push(*byte)
+            .map_err(|_| SysError::IllegalValue)?;
+    }
+
+    Ok(buf)
+}
+
+impl FileData {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> usize {
+        if pos >= self.len {
+            return 0;
+        }
+
+        let len = cmp::min(buf.len() as u64, self.len - pos) as usize;
+        let mut done = 0;
+
+        while done < len {
+            let at = pos + done as u64;
+            let offset = (at % PAGE_SIZE as u64) as usize;
+            let count = cmp::min(PAGE_SIZE - offset, len - done);
+            let dest = &mut buf[done..(done + count)];
+
+            match self.pages.get(&(at / PAGE_SIZE as u64)) {
+                Some(page) => dest.copy_from_slice(&page.as_slice()[offset..(offset + count)]),
+                None => dest.iter_mut().for_each(|byte| *byte = 0),
+            }
+
+            done += count;
+        }
+
+        done
+    }
+
+    // writes as much as there's memory for, only failing if that's nothing:
+    fn write_at(&mut self, pos: u64, buf: &[u8]) -> SysResult<usize> {
+        pos.checked_add(buf.len() as u64)
+            .ok_or(SysError::IllegalValue)?;
+
+        let mut done = 0;
+
+        while done < buf.len() {
+            let at = pos + done as u64;
+            let offset = (at % PAGE_SIZE as u64) as usize;
+            let count = cmp::min(PAGE_SIZE - offset, buf.len() - done);
+
+            let page = match self.page_mut(at / PAGE_SIZE as u64) {
+                Ok(page) => page,
+                Err(MemoryExhausted) if done > 0 => break,
+                Err(MemoryExhausted) => return Err(SysError::MemoryExhausted),
+            };
+
+            page.as_mut_slice()[offset..(offset + count)]
+                .copy_from_slice(&buf[done..(done + count)]);
+
+            done += count;
+        }
+
+        self.len = cmp::max(self.len, pos + done as u64);
+
+        Ok(done)
+    }
+
+    fn page_mut(&mut self, index: u64) -> Result<&mut Page, MemoryExhausted> {
+        if !self.pages.contains_key(&index) {
+            self.pages.insert(index, Page::new()?)
+                .map_err(|_| MemoryExhausted)?;
+        }
+
+        Ok(self.pages.get_mut(&index)
+            .expect("FileData::page_mut: page just inserted"))
+    }
+}
+
+impl Page {
+    fn new() -> Result<Page, MemoryExhausted> {
+        let page = kvirt::alloc_page::<u8>()?;
+        unsafe { ptr::write_bytes(page.as_ptr(), 0, PAGE_SIZE); }
+        Ok(Page(page))
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.0.as_ptr(), PAGE_SIZE) }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.0.as_ptr(), PAGE_SIZE) }
+    }
+}
+
+impl Drop for Page {
+    fn drop(&mut self) {
+        unsafe { kvirt::free_page(self.0); }
+    }
+}
+
+impl Inode {
+    fn new(shared: &Arc<Shared>, node: &Arc<Node>) -> Result<Arc<dyn vfs::Inode>, MemoryExhausted> {
+        Ok(Arc::new(Inode {
+            shared: shared.clone(),
+            node: node.clone(),
+        })?)
+    }
+}
+
+impl vfs::Inode for Inode {
+    fn kind(&self) -> InodeKind {
+        self.node.kind()
+    }
+
+    fn lookup<'a>(&'a self, name: &'a [u8]) -> FsFuture<'a, Option<Arc<dyn vfs::Inode>>> {
+        FsFuture::new(async move {
+            let dir = self.node.directory()?.lock();
+
+            // a name too long to create can't be found either:
+            let node = match self::name(name).ok().and_then(|name| dir.entries.get(&name)) {
+                Some(node) => node,
+                None => return Ok(None),
+            };
+
+            Ok(Some(Inode::new(&self.shared, node)?))
+        })
+    }
+
+    fn open(&self) -> FsFuture<'_, Arc<dyn vfs::FileHandle>> {
+        FsFuture::new(async move {
+            let handle: Arc<dyn vfs::FileHandle> = Arc::new(Handle {
+                node: self.node.clone(),
+                pos: AsyncMutex::new(0),
+            })?;
+
+            Ok(handle)
+        })
+    }
+
+    fn stat(&self) -> FsFuture<'_, Stat> {
+        FsFuture::new(async move {
+            let (size, permissions) = match &*self.node {
+                Node::File(data) => {
+                    let data = data.lock().await?;
+                    (data.len, data.permissions)
+                }
+                Node::Directory(dir) => (0, dir.lock().permissions),
+            };
+
+            Ok(Stat { kind: self.node.kind(), size, permissions })
+        })
+    }
+
+    fn set_permissions(&self, permissions: Permissions) -> FsFuture<'_, ()> {
+        FsFuture::new(async move {
+            match &*self.node {
+                Node::File(data) => data.lock().await?.permissions = permissions,
+                Node::Directory(dir) => dir.lock().permissions = permissions,
+            }
+
+            Ok(())
+        })
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    // positions are indexes into the entries, which are kept in name order:
+    fn read_dir<'a>(&'a self, pos: u64, sink: &'a mut dyn DirSink) -> FsFuture<'a, ()> {
+        FsFuture::new(async move {
+            let dir = self.node.directory()?.lock();
+
+            for (index, (name, node)) in dir.entries.iter().enumerate().skip(pos as usize) {
+                if !sink.push(name, node.kind(), index as u64 + 1) {
+                    break;
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    fn create<'a>(&'a self, name: &'a [u8], kind: InodeKind) -> FsFuture<'a, Arc<dyn vfs::Inode>> {
+        FsFuture::new(async move {
+            let name = self::name(name)?;
+
+            // Namespace::create gives the node to whoever created it:
+            let mode = match kind {
+                InodeKind::File => vfs::FILE_MODE,
+                InodeKind::Directory => vfs::DIRECTORY_MODE,
+            };
+
+            let node = Node::new(kind, Permissions::root(mode))?;
+
+            let mut dir = self.node.directory()?.lock();
+            let entries = dir.live()?;
+
+            if entries.contains_key(&name) {
+                return Err(SysError::AlreadyMapped);
+            }
+
+            entries.insert(name, node.clone())
+                .map_err(|_| SysError::MemoryExhausted)?;
+
+            Ok(Inode::new(&self.shared, &node)?)
+        })
+    }
+
+    fn unlink<'a>(&'a self, name: &'a [u8]) -> FsFuture<'a, ()> {
+        FsFuture::new(async move {
+            let name = self::name(name).map_err(|_| SysError::NoFile)?;
+
+            let _tree = self.shared.tree_lock.lock();
+            let mut dir = self.node.directory()?.lock();
+            let entries = dir.live()?;
+
+            let node = entries.get(&name).ok_or(SysError::NoFile)?.clone();
+
+            if let Node::Directory(dir) = &*node {
+                let mut dir = dir.lock();
+
+                if !dir.entries.is_empty() {
+                    return Err(SysError::NotEmpty);
+                }
+
+                dir.unlinked = true;
+            }
+
+            entries.remove(&name);
+
+            Ok(())
+        })
+    }
+
+    fn rename<'a>(&'a self, name: &'a [u8], to: &'a dyn vfs::Inode, to_name: &'a [u8]) -> FsFuture<'a, ()> {
+        FsFuture::new(async move {
+            let to = to.as_any().downcast_ref::<Inode>()
+                .filter(|to| ptr::eq(&*to.shared, &*self.shared))
+                .ok_or(SysError::CrossDevice)?;
+
+            let name = self::name(name).map_err(|_| SysError::NoFile)?;
+            let to_name = self::name(to_name)?;
+
+            let _tree = self.shared.tree_lock.lock();
+
+            if ptr::eq(&*self.node, &*to.node) {
+                let mut dir = self.node.directory()?.lock();
+                let entries = dir.live()?;
+
+                let node = entries.get(&name).ok_or(SysError::NoFile)?.clone();
+
+                if name == to_name {
+                    return Ok(());
+                }
+
+                let replaced = entries.get(&to_name).cloned();
+                let replaced_dir = lock_replaced(&node, replaced.as_ref(), &self.node)?;
+
+                entries.insert(to_name, node)
+                    .map_err(|_| SysError::MemoryExhausted)?;
+
+                entries.remove(&name);
+
+                if let Some(mut dir) = replaced_dir {
+                    dir.unlinked = true;
+                }
+            } else {
+                let mut from_dir = self.node.directory()?.lock();
+                let mut to_dir = to.node.directory()?.lock();
+                let from_entries = from_dir.live()?;
+                let to_entries = to_dir.live()?;
+
+                let node = from_entries.get(&name).ok_or(SysError::NoFile)?.clone();
+
+                // the VFS keeps directories from being moved any further
+                // inside themselves, where they'd be unreachable:
+                if ptr::eq(&*node, &*to.node) {
+                    return Err(SysError::IllegalValue);
+                }
+
+                let replaced = to_entries.get(&to_name).cloned();
+                let replaced_dir = lock_replaced(&node, replaced.as_ref(), &self.node)?;
+
+                to_entries.insert(to_name, node)
+                    .map_err(|_| SysError::MemoryExhausted)?;
+
+                from_entries.remove(&name);
+
+                if let Some(mut dir) = replaced_dir {
+                    dir.unlinked = true;
+                }
+            }
+
+            Ok(())
+        })
+    }
+}
+
+// checks that `node` may replace `target` in a rename. a directory being
+// replaced stays locked until it's gone, so nothing can be created in it in
+// the meantime:
+fn lock_replaced<'a>(node: &Node, target: Option<&'a Arc<Node>>, from: &Node)
+    -> SysResult<Option<MutexGuard<'a, Directory>>>
+{
+    let target = match target {
+        Some(target) => target,
+        None => return Ok(None),
+    };
+
+    // the source's own directory, which is already locked, can't be empty:
+    if ptr::eq(&**target, from) {
+        return Err(SysError::NotEmpty);
+    }
+
+    match (node, &**target) {
+        (Node::File(_), Node::File(_)) => Ok(None),
+        (Node::Directory(_), Node::Directory(dir)) => {
+            let dir = dir.lock();
+
+            if dir.entries.is_empty() {
+                Ok(Some(dir))
+            } else {
+                Err(SysError::NotEmpty)
+            }
+        }
+        _ => Err(SysError::InvalidOperation),
+    }
+}
+
+impl vfs::FileHandle for Handle {
+    fn read<'a>(&'a self, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        FsFuture::new(async move {
+            let data = match &*self.node {
+                Node::File(data) => data,
+                Node::Directory(_) => return Err(SysError::InvalidOperation),
+            };
+
+            let mut pos = self.pos.lock().await?;
+            let count = data.lock().await?.read_at(*pos, buf);
+
+            *pos += count as u64;
+
+            Ok(count)
+        })
+    }
+
+    fn write<'a>(&'a self, buf: &'a [u8]) -> FsFuture<'a, usize> {
+        FsFuture::new(async move {
+            let data = match &*self.node {
+                Node::File(data) => data,
+                Node::Directory(_) => return Err(SysError::InvalidOperation),
+            };
+
+            let mut pos = self.pos.lock().await?;
+            let count = data.lock().await?.write_at(*pos, buf)?;
+
+            *pos += count as u64;
+
+            Ok(count)
+        })
+    }
+
+    fn seek(&self, from: SeekFrom) -> FsFuture<'_, u64> {
+        FsFuture::new(async move {
+            let data = match &*self.node {
+                Node::File(data) => data,
+                Node::Directory(_) => return Err(SysError::InvalidOperation),
+            };
+
+            let mut pos = self.pos.lock().await?;
+            *pos = from.resolve(*pos, data.lock().await?.len)?;
+
+            Ok(*pos)
+        })
+    }
+}
+
+impl Debug for Tmpfs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Tmpfs")
+    }
+}
+
+impl Debug for Inode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "tmpfs::Inode({:?})", self.node.kind())
+    }
+}
+
+impl Debug for Handle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "tmpfs::Handle({:?})", self.node.kind())
+    }
+}
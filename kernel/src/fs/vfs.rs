@@ -1,114 +1,844 @@
-use core::fmt::Write;
+use core::any::Any;
+use core::fmt::{self, Debug, Write};
+use core::future::Future;
+use core::iter;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
 
+use alloc_collections::boxed::Box;
+use arrayvec::ArrayVec;
+use bitflags::bitflags;
 use interface::{SysError, SysResult};
+use interface::{OPEN_APPEND, OPEN_CREATE, OPEN_DIRECTORY, OPEN_EXCLUSIVE, OPEN_WRITE};
 use itertools::Itertools;
 
-use crate::fs::fat16::{self, Fat16, DirEntry, FatError};
+use crate::fs::pipe;
+use crate::mem::kalloc::GlobalAlloc;
+use crate::mem::MemoryExhausted;
+use crate::object::poll::Events;
+use crate::sync::{Arc, AsyncMutex, Mutex};
+use crate::task::Credentials;
 use crate::util;
 
-pub use fat16::Open;
+// limits of the mount table, which never allocates:
+const MAX_MOUNTS: usize = 8;
+const MOUNT_PATH_MAX: usize = 64;
 
-#[derive(Debug)]
-pub struct Filesystem {
-    root: Fat16,
+// the most segments a path can have once empty and "." segments are gone:
+const PATH_DEPTH_MAX: usize = 32;
+
+// the longest path FsContext can resolve a path to:
+const PATH_MAX: usize = 256;
+
+/// The future returned by the async methods of the filesystem traits, boxed
+/// so that the traits can be used as trait objects. If there's no memory to
+/// box it in, it fails with MemoryExhausted when polled instead.
+pub enum FsFuture<'a, T> {
+    Boxed(Pin<Box<dyn Future<Output = SysResult<T>> + 'a, GlobalAlloc>>),
+    Failed,
 }
 
-#[derive(Debug)]
-pub enum OpenError {
-    NotFound,
-    Fat(FatError),
+impl<'a, T> FsFuture<'a, T> {
+    pub fn new(future: impl Future<Output = SysResult<T>> + 'a) -> Self {
+        match Box::new(future) {
+            Ok(future) => {
+                let future = future as Box<dyn Future<Output = SysResult<T>> + 'a, GlobalAlloc>;
+
+                // TODO - why doesn't Pin::new work?
+                FsFuture::Boxed(unsafe { Pin::new_unchecked(future) })
+            }
+            Err(_) => FsFuture::Failed,
+        }
+    }
 }
 
-impl From<OpenError> for SysError {
-    fn from(e: OpenError) -> Self {
-        match e {
-            OpenError::NotFound => SysError::NoFile,
-            OpenError::Fat(e) => e.into(),
+impl<'a, T> Future for FsFuture<'a, T> {
+    type Output = SysResult<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        match self.get_mut() {
+            FsFuture::Boxed(future) => future.as_mut().poll(cx),
+            FsFuture::Failed => Poll::Ready(Err(SysError::MemoryExhausted)),
         }
     }
 }
 
-impl Filesystem {
-    pub fn new(root: Fat16) -> Self {
-        Filesystem { root }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InodeKind {
+    File,
+    Directory,
+}
+
+/// Who owns an inode, and what its owner, its group and everyone else may do
+/// with it. `mode` holds the permission bits as chmod takes them, 0o755 and
+/// the like, and nothing of the inode's kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permissions {
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Permissions {
+    /// Belonging to root, for filesystems that have no owners of their own.
+    pub fn root(mode: u32) -> Permissions {
+        Permissions { mode, uid: 0, gid: 0 }
     }
 
-    pub async fn open(&self, path: &[u8]) -> Result<File, OpenError> {
-        let mut container = self.root.root();
-        let mut segments = path.split(|b| *b == b'/');
+    /// Fails with PermissionDenied unless `credentials` allow all of
+    /// `access`. Only the first of the owner's, the group's and everyone
+    /// else's bits that apply is looked at, as on Unix.
+    pub fn check(&self, credentials: &Credentials, access: Access) -> SysResult<()> {
+        // root reads and writes anything, but only runs what's executable by
+        // somebody:
+        if credentials.is_root() {
+            if access.contains(Access::EXECUTE) && self.mode & 0o111 == 0 {
+                return Err(SysError::PermissionDenied);
+            }
 
-        // ensure path starts with /:
-        if segments.next() != Some(b"") {
-            // TODO support relative paths
-            return Err(OpenError::NotFound);
+            return Ok(());
         }
 
-        loop {
-            let segment = match segments.next() {
-                None => {
-                    return Ok(File::Fat(Open::Dir(container)));
-                }
-                Some(b"") => {
-                    // ignore empty path segments
-                    continue;
+        let bits = if credentials.uid == self.uid {
+            self.mode >> 6
+        } else if credentials.gid == self.gid {
+            self.mode >> 3
+        } else {
+            self.mode
+        };
+
+        if Access::from_bits_truncate(bits).contains(access) {
+            Ok(())
+        } else {
+            Err(SysError::PermissionDenied)
+        }
+    }
+}
+
+bitflags! {
+    /// What Permissions::check is asked about, with the same bits as each
+    /// group of three in a mode.
+    pub struct Access: u32 {
+        const READ = 0o4;
+        const WRITE = 0o2;
+        const EXECUTE = 0o1;
+    }
+}
+
+/// The mode given to files and directories as they're created, for
+/// filesystems that keep one.
+pub const FILE_MODE: u32 = 0o644;
+pub const DIRECTORY_MODE: u32 = 0o755;
+
+/// What there is to know about an inode, for the Stat syscall.
+#[derive(Debug, Clone, Copy)]
+pub struct Stat {
+    pub kind: InodeKind,
+    /// In bytes, and 0 for directories.
+    pub size: u64,
+    pub permissions: Permissions,
+}
+
+/// Where a seek goes to, relative to the start of the file, the current
+/// position or the end of the file.
+#[derive(Debug, Clone, Copy)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+impl SeekFrom {
+    /// The position a seek from `pos` goes to, in a file of `size` bytes.
+    /// Positions past the end are fine, but not ones before the start.
+    pub fn resolve(self, pos: u64, size: u64) -> SysResult<u64> {
+        let (base, offset) = match self {
+            SeekFrom::Start(offset) => return Ok(offset),
+            SeekFrom::Current(offset) => (pos, offset),
+            SeekFrom::End(offset) => (size, offset),
+        };
+
+        let pos = if offset >= 0 {
+            base.checked_add(offset as u64)
+        } else {
+            base.checked_sub((offset as u64).wrapping_neg())
+        };
+
+        pos.ok_or(SysError::IllegalValue)
+    }
+}
+
+/// Takes the entries of a directory as Inode::read_dir lists them.
+pub trait DirSink {
+    /// Takes an entry, along with the position to list from to carry on
+    /// after it. Returns false if there's no room for it, which stops the
+    /// listing.
+    fn push(&mut self, name: &[u8], kind: InodeKind, next: u64) -> bool;
+}
+
+/// A filesystem that can be mounted into a Namespace.
+pub trait Filesystem: Debug {
+    fn root(&self) -> SysResult<Arc<dyn Inode>>;
+
+    /// Writes everything changed in the filesystem so far out to its disk,
+    /// rather than leaving it to the writeback task.
+    fn sync(&self) -> FsFuture<'_, ()> {
+        FsFuture::new(async { Ok(()) })
+    }
+}
+
+/// A file or directory in a filesystem.
+pub trait Inode: Debug {
+    fn kind(&self) -> InodeKind;
+
+    /// Looks up a directory's entry by name. Returns None if there's no such
+    /// entry, and fails with InvalidOperation if this isn't a directory.
+    fn lookup<'a>(&'a self, name: &'a [u8]) -> FsFuture<'a, Option<Arc<dyn Inode>>>;
+
+    /// Opens the inode for reading and writing, with its own position.
+    fn open(&self) -> FsFuture<'_, Arc<dyn FileHandle>>;
+
+    fn stat(&self) -> FsFuture<'_, Stat>;
+
+    /// Lets a filesystem find its own inode type behind another `dyn Inode`,
+    /// such as the target directory of a rename.
+    fn as_any(&self) -> &dyn Any;
+
+    /// Creates an empty file or directory in this directory. Fails with
+    /// AlreadyMapped if the name is taken.
+    fn create<'a>(&'a self, _name: &'a [u8], _kind: InodeKind) -> FsFuture<'a, Arc<dyn Inode>> {
+        FsFuture::new(async { Err(SysError::InvalidOperation) })
+    }
+
+    /// Changes the inode's owner and mode. Filesystems that don't keep them
+    /// fail with InvalidOperation.
+    fn set_permissions(&self, _permissions: Permissions) -> FsFuture<'_, ()> {
+        FsFuture::new(async { Err(SysError::InvalidOperation) })
+    }
+
+    /// Removes an entry from this directory. Directories must be empty, and
+    /// fail with NotEmpty otherwise. Files open on the entry stay usable.
+    fn unlink<'a>(&'a self, _name: &'a [u8]) -> FsFuture<'a, ()> {
+        FsFuture::new(async { Err(SysError::InvalidOperation) })
+    }
+
+    /// Moves an entry of this directory to `to_name` in directory `to`, which
+    /// is in the same filesystem. Whatever is already there is replaced, as
+    /// long as it's of the same kind and, for directories, empty.
+    fn rename<'a>(&'a self, _name: &'a [u8], _to: &'a dyn Inode, _to_name: &'a [u8]) -> FsFuture<'a, ()> {
+        FsFuture::new(async { Err(SysError::InvalidOperation) })
+    }
+
+    /// Lists a directory's entries into `sink`, from position `pos`: 0 for
+    /// the first entry, or the position `sink` was given with the one before.
+    /// Fails with InvalidOperation if this isn't a directory.
+    fn read_dir<'a>(&'a self, _pos: u64, _sink: &'a mut dyn DirSink) -> FsFuture<'a, ()> {
+        FsFuture::new(async { Err(SysError::InvalidOperation) })
+    }
+}
+
+/// An open file or directory.
+pub trait FileHandle: Debug {
+    /// Reads from the current position, returning the number of bytes read,
+    /// which is 0 at the end of the file.
+    fn read<'a>(&'a self, buf: &'a mut [u8]) -> FsFuture<'a, usize>;
+
+    /// Writes at the current position, returning the number of bytes written.
+    fn write<'a>(&'a self, _buf: &'a [u8]) -> FsFuture<'a, usize> {
+        FsFuture::new(async { Err(SysError::InvalidOperation) })
+    }
+
+    /// Moves the current position, returning where it ends up. Files with no
+    /// position, like pipes, fail with InvalidOperation.
+    fn seek(&self, _from: SeekFrom) -> FsFuture<'_, u64> {
+        FsFuture::new(async { Err(SysError::InvalidOperation) })
+    }
+
+    /// Performs a request specific to the kind of file, which only device
+    /// nodes have any of.
+    fn ioctl(&self, _request: u64, _arg: u64) -> FsFuture<'_, u64> {
+        FsFuture::new(async { Err(SysError::InvalidOperation) })
+    }
+
+    /// The events the file is ready for, with `waker` woken when that may
+    /// have changed. Reads and writes of files on disk never wait.
+    fn poll_ready(&self, _waker: &Waker) -> Result<Events, MemoryExhausted> {
+        Ok(Events::IN | Events::OUT)
+    }
+}
+
+type Segments<'a> = ArrayVec<[&'a [u8]; PATH_DEPTH_MAX]>;
+
+// splits an absolute path into its segments, leaving out empty and "." ones:
+fn segments(path: &[u8]) -> SysResult<Segments> {
+    let mut split = path.split(|b| *b == b'/');
+
+    // ensure path starts with /:
+    if split.next() != Some(b"") {
+        // TODO support relative paths
+        return Err(SysError::NoFile);
+    }
+
+    let mut segments = Segments::new();
+
+    for segment in split.filter(|segment| *segment != b"" && *segment != b".") {
+        segments.try_push(segment)
+            .map_err(|_| SysError::IllegalValue)?;
+    }
+
+    Ok(segments)
+}
+
+#[derive(Debug)]
+struct Mount {
+    // normalized, without a trailing /:
+    path: ArrayVec<[u8; MOUNT_PATH_MAX]>,
+    fs: Arc<dyn Filesystem>,
+}
+
+impl Mount {
+    fn segments(&self) -> Segments {
+        segments(&self.path)
+            .expect("Mount::segments: mount path not normalized")
+    }
+}
+
+/// A tree of mounted filesystems, which paths are resolved against. Each path
+/// goes to the filesystem mounted at its longest matching prefix, so mount
+/// points don't have to exist in the filesystem underneath.
+#[derive(Debug)]
+pub struct Namespace {
+    mounts: Mutex<ArrayVec<[Mount; MAX_MOUNTS]>>,
+}
+
+impl Namespace {
+    pub fn new() -> Self {
+        Namespace { mounts: Mutex::new(ArrayVec::new()) }
+    }
+
+    /// Mounts `fs` at `path`. Fails with AlreadyMapped if something is
+    /// already mounted there.
+    pub fn mount(&self, path: &[u8], fs: Arc<dyn Filesystem>) -> SysResult<()> {
+        let mut normalized = ArrayVec::<[u8; MOUNT_PATH_MAX]>::new();
+
+        for segment in segments(path)?.iter() {
+            for byte in iter::once(&b'/').chain(segment.iter()) {
+                normalized.try_push(*byte)
+                    .map_err(|_| SysError::IllegalValue)?;
+            }
+        }
+
+        let mut mounts = self.mounts.lock();
+
+        if mounts.iter().any(|mount| mount.path == normalized) {
+            return Err(SysError::AlreadyMapped);
+        }
+
+        mounts.try_push(Mount { path: normalized, fs })
+            .map_err(|_| SysError::MemoryExhausted)
+    }
+
+    /// Unmounts whatever is mounted at `path`, then syncs it. Files open on
+    /// it stay usable.
+    pub async fn unmount(&self, path: &[u8]) -> SysResult<()> {
+        let segments = segments(path)?;
+
+        let mount = {
+            let mut mounts = self.mounts.lock();
+
+            let index = mounts.iter()
+                .position(|mount| mount.segments() == segments)
+                .ok_or(SysError::NoFile)?;
+
+            mounts.remove(index)
+        };
+
+        mount.fs.sync().await
+    }
+
+    // finds the filesystem a path goes to, and how many of the path's
+    // segments its mount point takes up:
+    fn mount_for(&self, segments: &Segments) -> SysResult<(Arc<dyn Filesystem>, usize)> {
+        let mounts = self.mounts.lock();
+
+        let mount = mounts.iter()
+            .filter_map(|mount| {
+                let prefix = mount.segments();
+
+                if segments.starts_with(&prefix) {
+                    Some((mount.fs.clone(), prefix.len()))
+                } else {
+                    None
                 }
-                Some(segment) => segment,
-            };
+            })
+            .max_by_key(|(_, depth)| *depth)
+            .ok_or(SysError::NoFile);
 
-            let entry = container.entry(segment)
-                .await
-                .map_err(OpenError::Fat)?
-                .ok_or(OpenError::NotFound)?;
+        mount
+    }
+
+    /// Finds the inode at an absolute path.
+    pub async fn lookup(&self, path: &[u8]) -> SysResult<Arc<dyn Inode>> {
+        let segments = segments(path)?;
+        let (fs, depth) = self.mount_for(&segments)?;
+
+        walk(fs.root()?, &segments[depth..]).await
+    }
+
+    // finds the directory the last segment of a path is an entry of, along
+    // with the filesystem it's in. mount points can't be changed through
+    // the filesystem underneath, so they're refused:
+    async fn parent<'p>(&self, path: &'p [u8]) -> SysResult<(Arc<dyn Filesystem>, Arc<dyn Inode>, &'p [u8])> {
+        let segments = segments(path)?;
+        let (fs, depth) = self.mount_for(&segments)?;
+
+        if depth == segments.len() {
+            return Err(SysError::InvalidOperation);
+        }
+
+        let (name, dir) = segments.split_last()
+            .expect("Namespace::parent: path below mount point has no segments");
+
+        let dir = walk(fs.root()?, &dir[depth..]).await?;
+
+        Ok((fs, dir, *name))
+    }
+
+    /// Creates an empty file or directory at an absolute path, owned by
+    /// whoever `credentials` are, who must be able to write to the directory
+    /// it goes in.
+    pub async fn create(&self, path: &[u8], kind: InodeKind, credentials: &Credentials)
+        -> SysResult<Arc<dyn Inode>>
+    {
+        let (_, dir, name) = self.parent(path).await?;
+        dir.stat().await?.permissions.check(credentials, Access::WRITE | Access::EXECUTE)?;
+
+        let inode = dir.create(name, kind).await?;
+
+        let mode = match kind {
+            InodeKind::File => FILE_MODE,
+            InodeKind::Directory => DIRECTORY_MODE,
+        };
+
+        let permissions = Permissions { mode, uid: credentials.uid, gid: credentials.gid };
+
+        // filesystems without owners leave everything to root:
+        match inode.set_permissions(permissions).await {
+            Ok(()) | Err(SysError::InvalidOperation) => Ok(inode),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Removes the file or empty directory at an absolute path, as whoever
+    /// `credentials` are, who must be able to write to the directory it's in.
+    pub async fn unlink(&self, path: &[u8], credentials: &Credentials) -> SysResult<()> {
+        let (_, dir, name) = self.parent(path).await?;
+        dir.stat().await?.permissions.check(credentials, Access::WRITE | Access::EXECUTE)?;
+
+        dir.unlink(name).await
+    }
+
+    /// Moves a file or directory to another path in the same filesystem,
+    /// failing with CrossDevice otherwise. `credentials` must be able to
+    /// write to both the directory it leaves and the one it goes to.
+    pub async fn rename(&self, from: &[u8], to: &[u8], credentials: &Credentials) -> SysResult<()> {
+        let from_segments = segments(from)?;
+        let to_segments = segments(to)?;
+
+        // a directory can't be moved inside itself:
+        if to_segments.len() > from_segments.len() && to_segments.starts_with(&from_segments) {
+            return Err(SysError::IllegalValue);
+        }
+
+        let (from_fs, from_dir, from_name) = self.parent(from).await?;
+        let (to_fs, to_dir, to_name) = self.parent(to).await?;
+
+        if !same_filesystem(&from_fs, &to_fs) {
+            return Err(SysError::CrossDevice);
+        }
+
+        from_dir.stat().await?.permissions.check(credentials, Access::WRITE | Access::EXECUTE)?;
+        to_dir.stat().await?.permissions.check(credentials, Access::WRITE | Access::EXECUTE)?;
+
+        from_dir.rename(from_name, &*to_dir, to_name).await
+    }
+
+    /// Opens the file or directory at an absolute path, as whoever
+    /// `credentials` are. Opening takes permission to read, and to write as
+    /// well with WRITE or APPEND, apart from a file that's only just been
+    /// created.
+    pub async fn open(&self, path: &[u8], flags: OpenFlags, credentials: &Credentials) -> SysResult<File> {
+        let create = flags.contains(OpenFlags::CREATE);
+        let exclusive = flags.contains(OpenFlags::EXCLUSIVE);
+
+        // only files are created:
+        if create && flags.contains(OpenFlags::DIRECTORY) {
+            return Err(SysError::IllegalValue);
+        }
 
-            match entry.open() {
-                Ok(Open::Dir(dir)) => {
-                    container = dir;
+        let access = if flags.intersects(OpenFlags::WRITE | OpenFlags::APPEND) {
+            Access::READ | Access::WRITE
+        } else {
+            Access::READ
+        };
+
+        let inode = match self.lookup(path).await {
+            Ok(_) if create && exclusive => return Err(SysError::AlreadyMapped),
+            Ok(inode) => {
+                inode.stat().await?.permissions.check(credentials, access)?;
+                inode
+            }
+            Err(SysError::NoFile) if create => {
+                match self.create(path, InodeKind::File, credentials).await {
+                    // someone else created it first:
+                    Err(SysError::AlreadyMapped) if !exclusive => {
+                        let inode = self.lookup(path).await?;
+                        inode.stat().await?.permissions.check(credentials, access)?;
+                        inode
+                    }
+                    inode => inode?,
                 }
-                Ok(Open::File(file)) => {
-                    match segments.next() {
-                        None => {
-                            // this was the last path segment, return file
-                            return Ok(File::Fat(Open::File(file)));
-                        }
-                        Some(_) => {
-                            // there are more path segments to go, and a file
-                            // cannot possibly contain directory entries
-                            return Err(OpenError::NotFound);
-                        }
+            }
+            Err(e) => return Err(e),
+        };
+
+        // directories are only listed, so they have no handle:
+        let handle = match inode.kind() {
+            InodeKind::File if flags.contains(OpenFlags::DIRECTORY) => {
+                return Err(SysError::InvalidOperation);
+            }
+            InodeKind::File => Some(inode.open().await?),
+            InodeKind::Directory if flags.intersects(OpenFlags::WRITE | OpenFlags::APPEND) => {
+                return Err(SysError::InvalidOperation);
+            }
+            InodeKind::Directory => None,
+        };
+
+        Ok(File::Fs(OpenFile {
+            inode,
+            handle,
+            flags,
+            dir_pos: AsyncMutex::new(0),
+        }))
+    }
+
+    /// Opens the file at an absolute path to be run by exec, which takes
+    /// permission to execute it rather than to read it.
+    pub async fn open_executable(&self, path: &[u8], credentials: &Credentials) -> SysResult<File> {
+        let inode = self.lookup(path).await?;
+
+        if inode.kind() != InodeKind::File {
+            return Err(SysError::PermissionDenied);
+        }
+
+        inode.stat().await?.permissions.check(credentials, Access::EXECUTE)?;
+
+        let handle = inode.open().await?;
+
+        Ok(File::Fs(OpenFile {
+            inode,
+            handle: Some(handle),
+            flags: OpenFlags::empty(),
+            dir_pos: AsyncMutex::new(0),
+        }))
+    }
+}
+
+/// A path resolved by FsContext, absolute and normalized.
+pub type PathBuf = ArrayVec<[u8; PATH_MAX]>;
+
+/// A process's view of a Namespace: the directory it sees as /, which it
+/// can't get above, and the directory relative paths start from. Both are
+/// kept as normalized paths in the namespace, without a trailing /, so the
+/// namespace's own root is empty.
+#[derive(Debug, Clone)]
+pub struct FsContext {
+    namespace: Arc<Namespace>,
+    root: PathBuf,
+    // always the root or below it:
+    cwd: PathBuf,
+}
+
+impl FsContext {
+    pub fn new(namespace: Arc<Namespace>) -> Self {
+        FsContext { namespace, root: PathBuf::new(), cwd: PathBuf::new() }
+    }
+
+    pub fn namespace(&self) -> &Arc<Namespace> {
+        &self.namespace
+    }
+
+    /// Turns a path, absolute or relative to the working directory, into an
+    /// absolute path in the namespace. ".." segments are followed here, and
+    /// go no higher than the root.
+    pub fn resolve(&self, path: &[u8]) -> SysResult<PathBuf> {
+        let mut resolved = match path.first() {
+            Some(b'/') => self.root.clone(),
+            Some(_) => self.cwd.clone(),
+            None => return Err(SysError::NoFile),
+        };
+
+        for segment in path.split(|b| *b == b'/') {
+            match segment {
+                b"" | b"." => {}
+                b".." => {
+                    if resolved.len() > self.root.len() {
+                        let slash = resolved.iter().rposition(|b| *b == b'/')
+                            .expect("FsContext::resolve: path below root has no /");
+
+                        resolved.truncate(slash);
                     }
                 }
-                Err(e) => {
-                    return Err(OpenError::Fat(e));
+                _ => {
+                    for byte in iter::once(&b'/').chain(segment.iter()) {
+                        resolved.try_push(*byte)
+                            .map_err(|_| SysError::IllegalValue)?;
+                    }
                 }
             }
         }
+
+        if resolved.is_empty() {
+            resolved.push(b'/');
+        }
+
+        Ok(resolved)
+    }
+
+    /// The working directory, as seen from the root.
+    pub fn cwd(&self) -> &[u8] {
+        match &self.cwd[self.root.len()..] {
+            b"" => b"/",
+            cwd => cwd,
+        }
+    }
+
+    /// The same view with the working directory moved to `path`. Fails with
+    /// NoFile unless it's a directory.
+    pub async fn chdir(&self, path: &[u8]) -> SysResult<FsContext> {
+        let cwd = self.directory(path).await?;
+        Ok(FsContext { cwd, ..self.clone() })
+    }
+
+    /// The same view with the root moved to `path`, which becomes the
+    /// working directory too. Fails with NoFile unless it's a directory.
+    pub async fn chroot(&self, path: &[u8]) -> SysResult<FsContext> {
+        let root = self.directory(path).await?;
+        Ok(FsContext { namespace: self.namespace.clone(), root: root.clone(), cwd: root })
+    }
+
+    // resolves a path that has to be a directory, without the trailing /
+    // the namespace root resolves to:
+    async fn directory(&self, path: &[u8]) -> SysResult<PathBuf> {
+        let mut path = self.resolve(path)?;
+
+        if self.namespace.lookup(&path).await?.kind() != InodeKind::Directory {
+            return Err(SysError::NoFile);
+        }
+
+        if &path[..] == b"/" {
+            path.clear();
+        }
+
+        Ok(path)
+    }
+
+    pub async fn lookup(&self, path: &[u8]) -> SysResult<Arc<dyn Inode>> {
+        self.namespace.lookup(&self.resolve(path)?).await
+    }
+
+    pub async fn open(&self, path: &[u8], flags: OpenFlags, credentials: &Credentials) -> SysResult<File> {
+        self.namespace.open(&self.resolve(path)?, flags, credentials).await
+    }
+
+    pub async fn open_executable(&self, path: &[u8], credentials: &Credentials) -> SysResult<File> {
+        self.namespace.open_executable(&self.resolve(path)?, credentials).await
+    }
+
+    pub async fn unlink(&self, path: &[u8], credentials: &Credentials) -> SysResult<()> {
+        self.namespace.unlink(&self.resolve(path)?, credentials).await
+    }
+
+    pub async fn rename(&self, from: &[u8], to: &[u8], credentials: &Credentials) -> SysResult<()> {
+        self.namespace.rename(&self.resolve(from)?, &self.resolve(to)?, credentials).await
+    }
+
+    pub fn mount(&self, path: &[u8], fs: Arc<dyn Filesystem>) -> SysResult<()> {
+        self.namespace.mount(&self.resolve(path)?, fs)
+    }
+
+    pub async fn unmount(&self, path: &[u8]) -> SysResult<()> {
+        self.namespace.unmount(&self.resolve(path)?).await
+    }
+}
+
+// follows a path's segments down from `inode`:
+async fn walk(mut inode: Arc<dyn Inode>, segments: &[&[u8]]) -> SysResult<Arc<dyn Inode>> {
+    for segment in segments.iter() {
+        if inode.kind() != InodeKind::Directory {
+            // a file can't possibly contain directory entries:
+            return Err(SysError::NoFile);
+        }
+
+        let entry = inode.lookup(segment).await?;
+        inode = entry.ok_or(SysError::NoFile)?;
+    }
+
+    Ok(inode)
+}
+
+fn same_filesystem(a: &Arc<dyn Filesystem>, b: &Arc<dyn Filesystem>) -> bool {
+    &**a as *const dyn Filesystem as *const u8 == &**b as *const dyn Filesystem as *const u8
+}
+
+bitflags! {
+    /// How Namespace::open opens a file, as in the OpenFile syscall.
+    pub struct OpenFlags: u64 {
+        const WRITE = OPEN_WRITE;
+        const CREATE = OPEN_CREATE;
+        const EXCLUSIVE = OPEN_EXCLUSIVE;
+        const DIRECTORY = OPEN_DIRECTORY;
+        const APPEND = OPEN_APPEND;
+    }
+}
+
+/// A file or directory opened from a Namespace. Files are read and written
+/// through their FileHandle, and directories are listed from a position
+/// kept here.
+pub struct OpenFile {
+    inode: Arc<dyn Inode>,
+    handle: Option<Arc<dyn FileHandle>>,
+    flags: OpenFlags,
+    dir_pos: AsyncMutex<u64>,
+}
+
+impl OpenFile {
+    fn handle(&self) -> SysResult<&Arc<dyn FileHandle>> {
+        self.handle.as_ref().ok_or(SysError::InvalidOperation)
+    }
+
+    pub async fn read(&self, buf: &mut [u8]) -> SysResult<usize> {
+        self.handle()?.read(buf).await
+    }
+
+    pub async fn write(&self, buf: &[u8]) -> SysResult<usize> {
+        let handle = self.handle()?;
+
+        if !self.flags.contains(OpenFlags::WRITE) {
+            return Err(SysError::BadHandle);
+        }
+
+        if self.flags.contains(OpenFlags::APPEND) {
+            handle.seek(SeekFrom::End(0)).await?;
+        }
+
+        handle.write(buf).await
+    }
+
+    /// Seeks a file, or for a directory, goes back to a position Getdents
+    /// gave out.
+    pub async fn seek(&self, from: SeekFrom) -> SysResult<u64> {
+        if let Some(handle) = &self.handle {
+            return handle.seek(from).await;
+        }
+
+        let mut dir_pos = self.dir_pos.lock().await?;
+
+        match from {
+            SeekFrom::Start(pos) => *dir_pos = pos,
+            SeekFrom::Current(0) => {}
+            _ => return Err(SysError::IllegalValue),
+        }
+
+        Ok(*dir_pos)
+    }
+
+    pub async fn stat(&self) -> SysResult<Stat> {
+        self.inode.stat().await
+    }
+
+    /// Lists a directory's entries into `sink`, carrying on from where the
+    /// last listing stopped. "." and ".." are left out.
+    pub async fn read_dir(&self, sink: &mut dyn DirSink) -> SysResult<()> {
+        let mut dir_pos = self.dir_pos.lock().await?;
+        let mut cursor = DirCursor { sink, pos: *dir_pos };
+
+        let result = self.inode.read_dir(*dir_pos, &mut cursor).await;
+
+        // entries taken before a failure aren't given again:
+        *dir_pos = cursor.pos;
+
+        result
+    }
+
+    pub async fn ioctl(&self, request: u64, arg: u64) -> SysResult<u64> {
+        self.handle()?.ioctl(request, arg).await
+    }
+
+    pub fn poll_ready(&self, waker: &Waker) -> Result<Events, MemoryExhausted> {
+        match &self.handle {
+            Some(handle) => handle.poll_ready(waker),
+            None => Ok(Events::IN),
+        }
+    }
+}
+
+impl Debug for OpenFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.handle {
+            Some(handle) => write!(f, "{:?}", handle),
+            None => write!(f, "{:?}", self.inode),
+        }
+    }
+}
+
+// passes entries on to another sink, keeping track of where the listing is
+// up to:
+struct DirCursor<'s> {
+    sink: &'s mut dyn DirSink,
+    pos: u64,
+}
+
+impl DirSink for DirCursor<'_> {
+    fn push(&mut self, name: &[u8], kind: InodeKind, next: u64) -> bool {
+        let dots = name == b"." || name == b"..";
+
+        if !dots && !self.sink.push(name, kind, next) {
+            return false;
+        }
+
+        self.pos = next;
+        true
     }
 }
 
 #[derive(Debug)]
 pub enum File {
     Console,
-    Fat(Open),
+    Fs(OpenFile),
+    PipeReader(pipe::Reader),
+    PipeWriter(pipe::Writer),
 }
 
 impl File {
     pub async fn read(&self, buf: &mut [u8]) -> SysResult<usize> {
         match self {
             File::Console => {
-                use crate::device::keyboard;
+                use crate::console::tty;
 
                 if buf.len() == 0 {
                     return Ok(0);
                 }
 
-                let scancode = keyboard::read_scancode().await;
-                buf[0] = scancode;
-                Ok(1)
+                Ok(tty::read(buf).await?)
+            }
+            File::Fs(file) => {
+                file.read(buf).await
             }
-            File::Fat(Open::File(file)) => {
-                Ok(file.read(buf).await?)
+            File::PipeReader(reader) => {
+                reader.read(buf).await
             }
-            File::Fat(Open::Dir(_)) => {
+            File::PipeWriter(_) => {
                 Err(SysError::InvalidOperation)
             }
         }
@@ -129,7 +859,37 @@ impl File {
 
                 Ok(buf.len())
             }
-            File::Fat(_) => { panic!() }
+            File::Fs(file) => {
+                file.write(buf).await
+            }
+            File::PipeWriter(writer) => {
+                writer.write(buf).await
+            }
+            File::PipeReader(_) => {
+                Err(SysError::InvalidOperation)
+            }
+        }
+    }
+
+    pub async fn ioctl(&self, request: u64, arg: u64) -> SysResult<u64> {
+        match self {
+            File::Console => crate::console::tty::ioctl(request, arg),
+            File::Fs(file) => file.ioctl(request, arg).await,
+            _ => Err(SysError::InvalidOperation),
+        }
+    }
+
+    pub async fn seek(&self, from: SeekFrom) -> SysResult<u64> {
+        match self {
+            File::Fs(file) => file.seek(from).await,
+            _ => Err(SysError::InvalidOperation),
+        }
+    }
+
+    pub async fn read_dir(&self, sink: &mut dyn DirSink) -> SysResult<()> {
+        match self {
+            File::Fs(file) => file.read_dir(sink).await,
+            _ => Err(SysError::InvalidOperation),
         }
     }
 }
@@ -1,18 +1,54 @@
+mod ioapic;
+
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use x86_64::instructions::port::Port;
 use x86_64::registers::control::Cr2;
+use x86_64::registers::model_specific::Msr;
 use x86_64::registers::rflags::RFlags;
 
-use crate::device::keyboard;
-use crate::task::{self, SEG_UCODE, SEG_UDATA};
+use crate::acpi::madt::{self, Entry};
+use crate::cpu;
+use crate::critical;
+use crate::mem::MemoryExhausted;
+use crate::mem::kstack::KernelStack;
+use crate::percpu;
+use crate::random;
+use crate::smp;
+use crate::sync::Mutex;
+use crate::task::{self, SEG_KCODE, SEG_KDATA, SEG_UCODE, SEG_UDATA};
+use crate::time;
+use crate::work;
 
 pub const IRQ_BASE: u8 = 0x20;
 
+/// The first of the vectors handed out for message signalled interrupts.
+/// Must match MSI_BASE in isrs.asm.
+pub const MSI_BASE: u8 = 0x50;
+
+/// How many vectors there are for message signalled interrupts. Must match
+/// MSI_VECTORS in isrs.asm.
+pub const MSI_VECTORS: usize = 32;
+
+/// The first of the vectors handed out for GSIs routed through an I/O APIC.
+/// Must match GSI_BASE in isrs.asm.
+pub const GSI_BASE: u8 = 0x90;
+
+/// How many vectors there are for GSIs. Must match GSI_VECTORS in isrs.asm.
+pub const GSI_VECTORS: usize = 32;
+
+pub use crate::acpi::madt::{Polarity, Trigger};
+
 macro_rules! interrupts {
     ($($vector:expr => $name:ident,)*) => {
         #[derive(Debug)]
         pub enum Interrupt {
             $($name,)*
             Irq(u8),
+            Msi(u8),
+            Gsi(u8),
             Other(u8),
         }
 
@@ -23,6 +59,10 @@ macro_rules! interrupts {
                     _ => {
                         if vector >= IRQ_BASE && vector < IRQ_BASE + 0x10 {
                             Interrupt::Irq(vector - IRQ_BASE)
+                        } else if vector >= MSI_BASE && ((vector - MSI_BASE) as usize) < MSI_VECTORS {
+                            Interrupt::Msi(vector - MSI_BASE)
+                        } else if vector >= GSI_BASE && ((vector - GSI_BASE) as usize) < GSI_VECTORS {
+                            Interrupt::Gsi(vector - GSI_BASE)
                         } else {
                             Interrupt::Other(vector)
                         }
@@ -36,6 +76,8 @@ macro_rules! interrupts {
                 match self {
                     $(Interrupt::$name => $vector,)*
                     Interrupt::Irq(irq) => irq + IRQ_BASE,
+                    Interrupt::Msi(index) => index + MSI_BASE,
+                    Interrupt::Gsi(index) => index + GSI_BASE,
                     Interrupt::Other(vector) => vector,
                 }
             }
@@ -64,7 +106,13 @@ interrupts! {
     0x13 => SimdException,
     0x14 => VirtualizationException,
     0x1e => SecurityException,
+    0x40 => TlbShootdown,
+    0x41 => LapicTimer,
+    0x42 => Reschedule,
     0x7f => Syscall,
+    // never delivered through the IDT. syscall_entry in isrs.asm gives the
+    // frames of syscalls made with the syscall instruction this vector:
+    0x80 => FastSyscall,
 }
 
 #[repr(C)]
@@ -123,6 +171,20 @@ impl TrapFrame {
             ss: SEG_UDATA as u64,
         }
     }
+
+    /// Creates a trap frame which returns to kernel mode code
+    pub fn new_kernel(rip: u64, rsp: u64) -> Self {
+        TrapFrame {
+            regs: Default::default(),
+            interrupt_vector: 0,
+            error_code: 0,
+            rip: rip,
+            cs: SEG_KCODE as u64,
+            rflags: RFlags::INTERRUPT_FLAG.bits(),
+            rsp: rsp,
+            ss: SEG_KDATA as u64,
+        }
+    }
 }
 
 pub enum TrapOrigin {
@@ -144,53 +206,496 @@ impl TrapFrame {
     }
 }
 
+// must match TSS_SIZE in consts.asm:
+const TSS_SIZE: usize = 0x68;
+
+extern "C" {
+    // one per CPU, see start.asm:
+    static mut tss: u8;
+    fn load_tss(cpu: u64, stack_top: u64, double_fault_stack_top: u64);
+}
+
+/// Gives the calling AP a TSS of its own and loads it. The BSP's is loaded in
+/// start.asm.
+pub unsafe fn init_ap_tss() -> Result<(), MemoryExhausted> {
+    // like the BSP, the AP reports kernel stack overflows from a stack of its
+    // own, which it never gives up:
+    let double_fault_stack = KernelStack::new()?;
+
+    // rsp0 is set by the scheduler before anything runs in user mode:
+    load_tss(smp::cpu_index() as u64, 0, double_fault_stack.top());
+    mem::forget(double_fault_stack);
+
+    Ok(())
+}
+
+/// Sets the stack the CPU switches to when a trap arrives from user mode.
+pub unsafe fn set_kernel_stack(stack_top: u64) {
+    // rsp0 is at offset 4 in the calling CPU's TSS, see start.asm:
+    let rsp0 = (&mut tss as *mut u8).add(smp::cpu_index() * TSS_SIZE + 4) as *mut u64;
+    ptr::write_unaligned(rsp0, stack_top);
+
+    // syscall_entry doesn't go through the TSS, it finds the stack in the
+    // per-CPU block instead:
+    percpu::set_kernel_stack(stack_top);
+}
+
+const MSR_EFER: u32 = 0xc000_0080;
+const MSR_STAR: u32 = 0xc000_0081;
+const MSR_LSTAR: u32 = 0xc000_0082;
+const MSR_SFMASK: u32 = 0xc000_0084;
+
+const EFER_SCE: u64 = 1 << 0;
+
+/// Lets user mode make syscalls with the syscall instruction, as well as
+/// through the int 0x7f gate. Both end up in `task::dispatch_syscall`.
+pub unsafe fn init_syscall() {
+    extern "C" {
+        fn syscall_entry();
+    }
+
+    let mut efer = Msr::new(MSR_EFER);
+    let flags = efer.read();
+    efer.write(flags | EFER_SCE);
+
+    // syscall loads cs from STAR[47:32] and ss from 8 above it. sysret loads
+    // cs from 16 above STAR[63:48] and ss from 8 above it, which is why user
+    // data comes before user code in the GDT:
+    let sysret_base = (SEG_UDATA & !3) as u64 - 8;
+    Msr::new(MSR_STAR).write((sysret_base << 48) | ((SEG_KCODE as u64) << 32));
+
+    Msr::new(MSR_LSTAR).write(syscall_entry as usize as u64);
+
+    // enter the kernel with interrupts off, as through an interrupt gate, and
+    // without any user access user mode left open:
+    let mask = RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG
+        | RFlags::ALIGNMENT_CHECK;
+    Msr::new(MSR_SFMASK).write(mask.bits());
+}
+
+// the most handlers drivers can register for IRQs, across all of them:
+const MAX_IRQ_HANDLERS: usize = 16;
+
+#[derive(Clone, Copy)]
+struct IrqHandler {
+    gsi: u32,
+    f: fn(u64),
+    arg: u64,
+}
+
+static IRQ_HANDLERS: Mutex<[Option<IrqHandler>; MAX_IRQ_HANDLERS]> = Mutex::new([None; MAX_IRQ_HANDLERS]);
+
+// set once interrupts come through the I/O APICs rather than the PICs:
+static USE_IOAPIC: AtomicBool = AtomicBool::new(false);
+
+// the GSI each vector from GSI_BASE is routed from:
+static GSI_VECTOR_MAP: Mutex<[Option<u32>; GSI_VECTORS]> = Mutex::new([None; GSI_VECTORS]);
+
+#[derive(Debug)]
+pub enum IrqError {
+    HandlersFull,
+    VectorsExhausted,
+    NoSuchGsi,
+}
+
+/// How a device signals its interrupt.
+#[derive(Debug, Clone, Copy)]
+pub struct IrqMode {
+    pub trigger: Trigger,
+    pub polarity: Polarity,
+}
+
+impl IrqMode {
+    /// ISA devices' interrupts are edge triggered and active high.
+    pub const ISA: IrqMode = IrqMode { trigger: Trigger::Edge, polarity: Polarity::High };
+    /// PCI devices' pin based interrupts are level triggered and active low.
+    pub const PCI: IrqMode = IrqMode { trigger: Trigger::Level, polarity: Polarity::Low };
+}
+
+/// Routes interrupts through the I/O APICs the MADT lists instead of the
+/// PICs, which are masked, if there are any. Must be called before any IRQ
+/// is registered.
+// Safety: must not be called more than once
+pub unsafe fn init_ioapic() {
+    match ioapic::init() {
+        Ok(0) => {
+            crate::println!("interrupt: no I/O APIC, using the PICs");
+        }
+        Ok(count) => {
+            if madt::has_pics() {
+                Port::<u8>::new(0x21).write(0xff);
+                Port::<u8>::new(0xa1).write(0xff);
+            }
+
+            USE_IOAPIC.store(true, Ordering::SeqCst);
+
+            crate::println!("interrupt: {} I/O APIC(s)", count);
+        }
+        Err(e) => {
+            crate::println!("interrupt: can't map the I/O APICs, using the PICs: {:?}", e);
+        }
+    }
+}
+
+// the GSI an ISA IRQ is wired to, and how it's triggered if the MADT says it
+// isn't the usual for `mode`:
+fn isa_route(irq: u8, mode: IrqMode) -> (u32, IrqMode) {
+    if !USE_IOAPIC.load(Ordering::SeqCst) {
+        // the PICs number GSIs the same as IRQs, and ignore the mode:
+        return (irq as u32, mode);
+    }
+
+    for entry in madt::entries() {
+        if let Entry::Override { irq: source, gsi, polarity, trigger } = entry {
+            if source == irq {
+                return (gsi, IrqMode {
+                    trigger: trigger.unwrap_or(mode.trigger),
+                    polarity: polarity.unwrap_or(mode.polarity),
+                });
+            }
+        }
+    }
+
+    (irq as u32, mode)
+}
+
+/// Has `f(arg)` called whenever ISA `irq` fires, and unmasks it. PCI devices
+/// can share an IRQ, so a handler should check that its device is the one
+/// interrupting. Handlers run in the interrupt handler, and push anything
+/// slow or lock heavy out of it with work::defer.
+pub fn register_irq(irq: u8, f: fn(u64), arg: u64) -> Result<(), IrqError> {
+    register_irq_mode(irq, IrqMode::ISA, f, arg)
+}
+
+/// Like `register_irq`, for a device whose interrupt isn't triggered the ISA
+/// way, such as a PCI device's pin based interrupt. The MADT's say about how
+/// the IRQ is triggered overrides `mode`.
+pub fn register_irq_mode(irq: u8, mode: IrqMode, f: fn(u64), arg: u64) -> Result<(), IrqError> {
+    assert!(irq < 0x10, "interrupt::register_irq: no such IRQ");
+
+    let (gsi, mode) = isa_route(irq, mode);
+
+    register_gsi(gsi, mode, f, arg)
+}
+
+/// Has `f(arg)` called whenever global system interrupt `gsi` fires, and
+/// unmasks it. Without I/O APICs, only GSIs 0 to 15, the PICs' IRQs, exist.
+/// The first handler registered for a GSI decides how it's triggered.
+pub fn register_gsi(gsi: u32, mode: IrqMode, f: fn(u64), arg: u64) -> Result<(), IrqError> {
+    let ioapic = USE_IOAPIC.load(Ordering::SeqCst);
+
+    if !ioapic && gsi >= 0x10 {
+        return Err(IrqError::NoSuchGsi);
+    }
+
+    let first = {
+        let mut handlers = IRQ_HANDLERS.lock();
+
+        let first = !handlers.iter().flatten().any(|handler| handler.gsi == gsi);
+
+        let slot = handlers.iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(IrqError::HandlersFull)?;
+
+        *slot = Some(IrqHandler { gsi, f, arg });
+
+        first
+    };
+
+    if ioapic && first {
+        if let Err(e) = route_gsi(gsi, mode) {
+            remove_handler(gsi, f, arg);
+            return Err(e);
+        }
+    }
+
+    unmask_gsi(gsi);
+
+    Ok(())
+}
+
+fn remove_handler(gsi: u32, f: fn(u64), arg: u64) {
+    let mut handlers = IRQ_HANDLERS.lock();
+
+    let slot = handlers.iter_mut()
+        .find(|slot| match slot {
+            Some(handler) => handler.gsi == gsi && handler.f as usize == f as usize && handler.arg == arg,
+            None => false,
+        });
+
+    if let Some(slot) = slot {
+        *slot = None;
+    }
+}
+
+// gives `gsi` a vector of its own and points its I/O APIC pin at the BSP:
+fn route_gsi(gsi: u32, mode: IrqMode) -> Result<(), IrqError> {
+    let mut vectors = GSI_VECTOR_MAP.lock();
+
+    let index = vectors.iter()
+        .position(|slot| slot.is_none())
+        .ok_or(IrqError::VectorsExhausted)?;
+
+    ioapic::route(gsi, GSI_BASE + index as u8, smp::bsp_apic_id(), mode.trigger, mode.polarity)
+        .map_err(|_| IrqError::NoSuchGsi)?;
+
+    vectors[index] = Some(gsi);
+
+    Ok(())
+}
+
+/// Stops `gsi` from interrupting until it's unmasked, without unregistering
+/// its handlers. For ISA IRQs, the GSI is what `register_irq` was given.
+pub fn mask_gsi(gsi: u32) {
+    set_gsi_masked(gsi, true);
+}
+
+/// Lets `gsi` interrupt again after `mask_gsi`.
+pub fn unmask_gsi(gsi: u32) {
+    set_gsi_masked(gsi, false);
+}
+
+fn set_gsi_masked(gsi: u32, masked: bool) {
+    if USE_IOAPIC.load(Ordering::SeqCst) {
+        // only GSIs with handlers have been routed, masking any other is a
+        // no-op:
+        let _ = ioapic::set_masked(gsi, masked);
+        return;
+    }
+
+    let irq = gsi as u8;
+
+    critical::section(|| unsafe {
+        let mut pic1 = Port::<u8>::new(0x21);
+        let mut pic2 = Port::<u8>::new(0xa1);
+
+        let (port, bit) = if irq < 0x08 {
+            (&mut pic1, irq)
+        } else {
+            // pic 2 is cascaded through irq 2 of pic 1, which stays unmasked:
+            if !masked {
+                let mask = pic1.read();
+                pic1.write(mask & !(1 << 2));
+            }
+
+            (&mut pic2, irq - 0x08)
+        };
+
+        let mask = port.read();
+        port.write(if masked { mask | 1 << bit } else { mask & !(1 << bit) });
+    });
+}
+
+fn run_irq_handlers(gsi: u32) {
+    // copied out so that handlers run without the lock:
+    let handlers = *IRQ_HANDLERS.lock();
+
+    for handler in handlers.iter().flatten().filter(|handler| handler.gsi == gsi) {
+        (handler.f)(handler.arg);
+    }
+}
+
+fn run_gsi_handlers(index: u8) {
+    let gsi = GSI_VECTOR_MAP.lock()[index as usize];
+
+    if let Some(gsi) = gsi {
+        run_irq_handlers(gsi);
+    }
+}
+
+// where the local APICs pick up the messages devices write, with the APIC id
+// of the destination CPU at bit 12:
+const MSI_ADDRESS: u64 = 0xfee0_0000;
+
+#[derive(Clone, Copy)]
+struct MsiHandler {
+    f: fn(u64),
+    arg: u64,
+}
+
+static MSI_HANDLERS: Mutex<[Option<MsiHandler>; MSI_VECTORS]> = Mutex::new([None; MSI_VECTORS]);
+
+#[derive(Debug)]
+pub struct VectorsExhausted;
+
+/// A vector allocated for a message signalled interrupt. A device raises it
+/// by writing `data()` to `address()`. The vector is freed when this is
+/// dropped, so the device must have stopped using it by then.
+#[derive(Debug)]
+pub struct MsiVector {
+    index: u8,
+}
+
+impl MsiVector {
+    pub fn vector(&self) -> u8 {
+        MSI_BASE + self.index
+    }
+
+    /// The address the device writes the message to. Interrupts are
+    /// delivered to the BSP, the only CPU that runs tasks.
+    pub fn address(&self) -> u64 {
+        MSI_ADDRESS | (smp::bsp_apic_id() as u64) << 12
+    }
+
+    /// The message the device writes: fixed delivery, edge triggered.
+    pub fn data(&self) -> u32 {
+        self.vector() as u32
+    }
+}
+
+impl Drop for MsiVector {
+    fn drop(&mut self) {
+        MSI_HANDLERS.lock()[self.index as usize] = None;
+    }
+}
+
+/// Allocates a vector for a message signalled interrupt and has `f(arg)`
+/// called whenever it fires. Unlike IRQs, the vector belongs to the one
+/// device it's given to, so the handler needn't check who's interrupting.
+/// Handlers run in the interrupt handler, and push anything slow or lock
+/// heavy out of it with work::defer.
+pub fn alloc_msi(f: fn(u64), arg: u64) -> Result<MsiVector, VectorsExhausted> {
+    let mut handlers = MSI_HANDLERS.lock();
+
+    let index = handlers.iter()
+        .position(|slot| slot.is_none())
+        .ok_or(VectorsExhausted)?;
+
+    handlers[index] = Some(MsiHandler { f, arg });
+
+    Ok(MsiVector { index: index as u8 })
+}
+
+fn run_msi_handler(index: u8) {
+    // copied out so that the handler runs without the lock. a vector freed
+    // while the device still had a message in flight is ignored:
+    let handler = MSI_HANDLERS.lock()[index as usize];
+
+    if let Some(handler) = handler {
+        (handler.f)(handler.arg);
+    }
+}
+
+// what's left once a device's handlers have run:
+unsafe fn finish_device_interrupt(frame: &mut TrapFrame, idle: bool) {
+    // run whatever the handlers deferred before going back to user mode. if
+    // we're idle, switch runs it instead:
+    if let TrapOrigin::User = frame.origin() {
+        work::run_pending();
+    }
+
+    if idle {
+        // this interrupt may have woken a task, so see if there's anything
+        // better to do than idling:
+        task::switch(frame);
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn interrupt(frame: &mut TrapFrame) {
-    x86_64::instructions::interrupts::enable();
+    // whoever was interrupted may have had user access open, user mode
+    // included. it's theirs, not the handler's, and iretq gives it back:
+    cpu::user_access_end();
+
+    if let Interrupt::DoubleFault = frame.interrupt() {
+        // we're on the IST stack and the state of whatever was running is
+        // unknown, so don't go near the scheduler or enable interrupts:
+        double_fault(frame);
+    }
+
+    if let Interrupt::TlbShootdown = frame.interrupt() {
+        // the initiator is spinning until we're done, so keep it short:
+        crate::mem::tlb::service();
+        unsafe { smp::eoi(); }
+        return;
+    }
+
+    // when interrupts arrive is a little unpredictable:
+    random::add_interrupt(frame.interrupt_vector);
+
+    // interrupts that arrive while the CPU is idle are handled with interrupts
+    // disabled. switching away from the idle task would otherwise abandon any
+    // handler that this one interrupted:
+    let idle = task::is_idle();
+
+    if !idle {
+        x86_64::instructions::interrupts::enable();
+    }
 
     match frame.interrupt() {
         Interrupt::Irq(irq) => {
             let mut pic1 = Port::<u8>::new(0x20);
             let mut pic2 = Port::<u8>::new(0xa0);
 
-            if irq == 0 {
-                // PIT
-
-                // only switch tasks if this interrupt arrived from user mode:
-                match frame.origin() {
-                    TrapOrigin::User => {
-                        unsafe { task::switch(frame); }
-                    }
-                    TrapOrigin::Kernel => {
-                        // do nothing
-                    }
-                }
-            }
-
-            if irq == 1 {
-                // keyboard
-                unsafe { keyboard::interrupt(); }
-            }
-
-            // acknowledge interupt:
+            // acknowledge interrupt up front. task::switch may park the CPU
+            // waiting for further interrupts, which the PIC would hold back
+            // until this one is acknowledged:
             unsafe { pic1.write(0x20); }
 
             if irq >= 0x08 {
                 // irq from pic 2, send separate ack
                 unsafe { pic2.write(0x20); }
             }
+
+            run_irq_handlers(irq as u32);
+
+            unsafe { finish_device_interrupt(frame, idle); }
+        }
+        Interrupt::LapicTimer => {
+            // acknowledged up front, as with IRQs:
+            unsafe { smp::eoi(); }
+
+            time::tick();
+
+            // only preempt tasks if this interrupt arrived from user mode:
+            match frame.origin() {
+                TrapOrigin::User => {
+                    unsafe { task::timer_tick(frame); }
+                }
+                TrapOrigin::Kernel => {
+                    // do nothing
+                }
+            }
+
+            unsafe { finish_device_interrupt(frame, idle); }
+        }
+        Interrupt::Reschedule => {
+            // acknowledged up front, as with IRQs:
+            unsafe { smp::eoi(); }
+
+            // another CPU queued a task here. there's nothing else to it, a
+            // busy CPU gets round to the task in its own time, but an idle one
+            // needs waking:
+            unsafe { finish_device_interrupt(frame, idle); }
+        }
+        Interrupt::Msi(index) => {
+            // acknowledged up front, as with IRQs:
+            unsafe { smp::eoi(); }
+
+            run_msi_handler(index);
+
+            unsafe { finish_device_interrupt(frame, idle); }
+        }
+        Interrupt::Gsi(index) => {
+            run_gsi_handlers(index);
+
+            // acknowledged once the handlers have quietened their devices. a
+            // level triggered line still asserted after that is raised again:
+            unsafe { smp::eoi(); }
+
+            unsafe { finish_device_interrupt(frame, idle); }
         }
         Interrupt::PageFault => {
             use crate::mem::fault::{fault, Flags};
 
-            let flags = Flags::from_bits(frame.error_code)
-                .expect("mem::fault::Flags::from_bits");
+            // ignore the error code bits for features we don't use:
+            let flags = Flags::from_bits_truncate(frame.error_code);
 
             let address = Cr2::read().as_ptr();
 
             fault(frame, flags, address);
         }
-        Interrupt::Syscall => {
+        Interrupt::Syscall | Interrupt::FastSyscall => {
             match frame.origin() {
                 TrapOrigin::User => {
                     unsafe { task::dispatch_syscall(frame); }
@@ -208,3 +713,24 @@ pub extern "C" fn interrupt(frame: &mut TrapFrame) {
         }
     }
 }
+
+fn double_fault(frame: &TrapFrame) -> ! {
+    use crate::mem::kstack;
+
+    // a double fault is almost always the CPU failing to push a page fault
+    // frame onto a kernel stack that has run into its guard page. cr2 still
+    // holds the address of that first fault:
+    let address = Cr2::read().as_u64();
+
+    if kstack::is_guard_page(address) || kstack::is_guard_page(frame.rsp) {
+        panic!("Kernel stack overflow! rip: {:x?}, rsp: {:x?}, address: {:x?}",
+            frame.rip,
+            frame.rsp,
+            address);
+    }
+
+    panic!("Double fault! rip: {:x?}, rsp: {:x?}, cr2: {:x?}",
+        frame.rip,
+        frame.rsp,
+        address);
+}
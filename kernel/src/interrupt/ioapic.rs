@@ -0,0 +1,154 @@
+use core::ptr::{self, NonNull};
+
+use arrayvec::ArrayVec;
+
+use crate::acpi::madt::{self, Entry, Polarity, Trigger};
+use crate::mem::kvirt;
+use crate::mem::page::PAGE_SIZE;
+use crate::mem::phys::RawPhys;
+use crate::mem::MemoryExhausted;
+use crate::sync::Mutex;
+use crate::util::EarlyInit;
+
+// registers are reached indirectly, by writing the register number to the
+// select register and then using the window:
+const REG_SELECT: usize = 0x00;
+const REG_WINDOW: usize = 0x10;
+
+const REG_VERSION: u32 = 0x01;
+// each pin's redirection entry is a pair of registers from here:
+const REG_REDIRECTION: u32 = 0x10;
+
+// the number of the last redirection entry is in the version register:
+const VERSION_MAX_ENTRY_SHIFT: u32 = 16;
+
+const MAX_IOAPICS: usize = 8;
+
+// fixed delivery to a physical APIC id, the only kind used:
+const ENTRY_ACTIVE_LOW: u64 = 1 << 13;
+const ENTRY_LEVEL: u64 = 1 << 15;
+const ENTRY_MASKED: u64 = 1 << 16;
+const ENTRY_DESTINATION_SHIFT: u64 = 56;
+
+struct IoApic {
+    registers: NonNull<u8>,
+    gsi_base: u32,
+    pins: u32,
+}
+
+unsafe impl Send for IoApic {}
+
+#[derive(Debug)]
+pub struct NoSuchGsi;
+
+static IOAPICS: EarlyInit<Mutex<ArrayVec<[IoApic; MAX_IOAPICS]>>> = EarlyInit::new();
+
+/// Maps every I/O APIC the MADT lists and masks all of their pins. Returns
+/// how many there are, which is 0 if interrupts have to go through the PICs.
+// Safety: must not be called more than once
+pub unsafe fn init() -> Result<usize, MemoryExhausted> {
+    let mut ioapics = ArrayVec::new();
+
+    for entry in madt::entries() {
+        let (address, gsi_base) = match entry {
+            Entry::IoApic { address, gsi_base, .. } => (address as u64, gsi_base),
+            _ => continue,
+        };
+
+        let offset = address as usize % PAGE_SIZE;
+        let mapped = kvirt::map_mmio(RawPhys(address - offset as u64), 1)?;
+
+        let mut ioapic = IoApic {
+            registers: NonNull::new_unchecked(mapped.as_ptr().add(offset)),
+            gsi_base,
+            pins: 0,
+        };
+
+        ioapic.pins = ((ioapic.read(REG_VERSION) >> VERSION_MAX_ENTRY_SHIFT) & 0xff) + 1;
+
+        for pin in 0..ioapic.pins {
+            ioapic.write_entry(pin, ENTRY_MASKED);
+        }
+
+        if ioapics.try_push(ioapic).is_err() {
+            crate::println!("ioapic: more than {} I/O APICs, ignoring the rest", MAX_IOAPICS);
+            break;
+        }
+    }
+
+    let count = ioapics.len();
+
+    EarlyInit::set(&IOAPICS, Mutex::new(ioapics));
+
+    Ok(count)
+}
+
+/// Routes `gsi` to `vector` on the CPU with local APIC id `apic_id`, masked
+/// until `set_masked` unmasks it.
+pub fn route(gsi: u32, vector: u8, apic_id: u8, trigger: Trigger, polarity: Polarity) -> Result<(), NoSuchGsi> {
+    let mut entry = vector as u64 | ENTRY_MASKED | (apic_id as u64) << ENTRY_DESTINATION_SHIFT;
+
+    if trigger == Trigger::Level {
+        entry |= ENTRY_LEVEL;
+    }
+
+    if polarity == Polarity::Low {
+        entry |= ENTRY_ACTIVE_LOW;
+    }
+
+    with_pin(gsi, |ioapic, pin| ioapic.write_entry(pin, entry))
+}
+
+/// Masks or unmasks `gsi`, which must have been routed.
+pub fn set_masked(gsi: u32, masked: bool) -> Result<(), NoSuchGsi> {
+    with_pin(gsi, |ioapic, pin| {
+        let entry = ioapic.read_entry(pin);
+
+        ioapic.write_entry(pin, if masked { entry | ENTRY_MASKED } else { entry & !ENTRY_MASKED });
+    })
+}
+
+// calls `f` with the I/O APIC that handles `gsi` and its pin for it:
+fn with_pin(gsi: u32, f: impl FnOnce(&IoApic, u32)) -> Result<(), NoSuchGsi> {
+    let ioapics = IOAPICS.lock();
+
+    let ioapic = ioapics.iter()
+        .find(|ioapic| gsi >= ioapic.gsi_base && gsi < ioapic.gsi_base + ioapic.pins)
+        .ok_or(NoSuchGsi)?;
+
+    f(ioapic, gsi - ioapic.gsi_base);
+
+    Ok(())
+}
+
+impl IoApic {
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            ptr::write_volatile(self.registers.as_ptr().add(REG_SELECT) as *mut u32, reg);
+            ptr::read_volatile(self.registers.as_ptr().add(REG_WINDOW) as *const u32)
+        }
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            ptr::write_volatile(self.registers.as_ptr().add(REG_SELECT) as *mut u32, reg);
+            ptr::write_volatile(self.registers.as_ptr().add(REG_WINDOW) as *mut u32, value);
+        }
+    }
+
+    fn read_entry(&self, pin: u32) -> u64 {
+        let reg = REG_REDIRECTION + pin * 2;
+
+        self.read(reg) as u64 | (self.read(reg + 1) as u64) << 32
+    }
+
+    fn write_entry(&self, pin: u32, entry: u64) {
+        let reg = REG_REDIRECTION + pin * 2;
+
+        // masked while half written. the high half, with the destination, goes
+        // in first:
+        self.write(reg, ENTRY_MASKED as u32);
+        self.write(reg + 1, (entry >> 32) as u32);
+        self.write(reg, entry as u32);
+    }
+}
@@ -0,0 +1,390 @@
+use core::fmt::{self, Debug};
+use core::task::{Poll, Waker};
+
+use alloc_collections::btree_map::BTreeMap;
+use arraydeque::{ArrayDeque, Saturating};
+use arrayvec::{ArrayString, ArrayVec};
+use futures::future;
+use interface::{SysError, SysResult, CHANNEL_HANDLES_MAX, CHANNEL_MESSAGE_MAX, CHANNEL_NAME_MAX};
+
+use crate::config::{CHANNEL_BACKLOG, CHANNEL_QUEUE_LEN};
+use crate::critical::Critical;
+use crate::mem::kalloc::{Box, GlobalAlloc};
+use crate::mem::MemoryExhausted;
+use crate::mem::user;
+use crate::object::Capability;
+use crate::object::poll::Events;
+use crate::sync::{Arc, Mutex};
+use crate::util::{self, AtomicList, EarlyInit};
+
+type Name = ArrayString<[u8; CHANNEL_NAME_MAX as usize]>;
+
+/// A message sent over a channel: some bytes, and handles to objects which
+/// the receiver gets handles of its own to, with the same rights.
+pub struct Message {
+    data: Box<[u8; CHANNEL_MESSAGE_MAX as usize]>,
+    len: usize,
+    handles: ArrayVec<[Capability; CHANNEL_HANDLES_MAX as usize]>,
+}
+
+impl Message {
+    /// Copies `len` bytes from user space at `addr` into a new message with
+    /// no handles attached.
+    pub fn copy_from_user(addr: u64, len: u64, crit: &Critical) -> SysResult<Message> {
+        if len > CHANNEL_MESSAGE_MAX {
+            return Err(SysError::IllegalValue);
+        }
+
+        let mut buff = Box::new([0u8; CHANNEL_MESSAGE_MAX as usize])
+            .map_err(|_| SysError::MemoryExhausted)?;
+
+        user::copy_from_user(&mut buff[..len as usize], addr, crit)?;
+
+        Ok(Message { data: buff, len: len as usize, handles: ArrayVec::new() })
+    }
+
+    pub fn attach(&mut self, capability: Capability) -> SysResult<()> {
+        self.handles.try_push(capability)
+            .map_err(|_| SysError::IllegalValue)
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+
+    pub fn handle_count(&self) -> usize {
+        self.handles.len()
+    }
+
+    pub fn into_handles(self) -> impl Iterator<Item = Capability> {
+        self.handles.into_iter()
+    }
+}
+
+// the messages travelling towards one end of a channel:
+struct Queue {
+    messages: ArrayDeque<[Message; CHANNEL_QUEUE_LEN], Saturating>,
+    sender_open: bool,
+    receiver_open: bool,
+}
+
+struct Shared {
+    // by the end the messages go to:
+    queues: [Mutex<Queue>; 2],
+    recv_wakers: [AtomicList<Waker>; 2],
+    send_wakers: [AtomicList<Waker>; 2],
+}
+
+/// One end of a bidirectional, message oriented connection between two
+/// processes, made by connecting to a Listener. Messages arrive whole and in
+/// the order they were sent.
+pub struct Channel {
+    shared: Arc<Shared>,
+    side: usize,
+}
+
+fn wake(wakers: &AtomicList<Waker>) {
+    for waker in wakers.take_iter() {
+        waker.wake();
+    }
+}
+
+fn pair() -> Result<(Channel, Channel), MemoryExhausted> {
+    let queue = || Mutex::new(Queue {
+        messages: ArrayDeque::new(),
+        sender_open: true,
+        receiver_open: true,
+    });
+
+    let shared = Arc::new(Shared {
+        queues: [queue(), queue()],
+        recv_wakers: [AtomicList::new(), AtomicList::new()],
+        send_wakers: [AtomicList::new(), AtomicList::new()],
+    })?;
+
+    Ok((Channel { shared: shared.clone(), side: 0 }, Channel { shared, side: 1 }))
+}
+
+impl Channel {
+    fn peer(&self) -> usize {
+        1 - self.side
+    }
+
+    /// Sends a message to the other end, waiting for room if its queue is
+    /// full. Fails with BrokenPipe once the other end is closed.
+    pub async fn send(&self, message: Message) -> SysResult<()> {
+        let peer = self.peer();
+        let mut message = Some(message);
+
+        future::poll_fn(|ctx| {
+            // register waker before checking the queue so that we can't miss
+            // a receive in between:
+            if let Err(MemoryExhausted) = self.shared.send_wakers[peer].push_front(ctx.waker().clone()) {
+                return Poll::Ready(Err(SysError::MemoryExhausted));
+            }
+
+            let mut queue = self.shared.queues[peer].lock();
+
+            if !queue.receiver_open {
+                return Poll::Ready(Err(SysError::BrokenPipe));
+            }
+
+            let pending = message.take().expect("Channel::send: polled after completion");
+
+            match queue.messages.push_back(pending) {
+                Ok(()) => Poll::Ready(Ok(())),
+                Err(full) => {
+                    message = Some(full.element);
+                    Poll::Pending
+                }
+            }
+        }).await?;
+
+        wake(&self.shared.recv_wakers[peer]);
+
+        Ok(())
+    }
+
+    /// Receives the next message, waiting for one if there are none. Fails
+    /// with IllegalValue, leaving the message queued, if it has more than
+    /// `max_len` bytes or `max_handles` handles, and with BrokenPipe once
+    /// the other end is closed and every message it sent has been received.
+    pub async fn recv(&self, max_len: usize, max_handles: usize) -> SysResult<Message> {
+        let side = self.side;
+
+        let message = future::poll_fn(|ctx| {
+            // register waker before checking the queue so that we can't miss
+            // a send in between:
+            if let Err(MemoryExhausted) = self.shared.recv_wakers[side].push_front(ctx.waker().clone()) {
+                return Poll::Ready(Err(SysError::MemoryExhausted));
+            }
+
+            let mut queue = self.shared.queues[side].lock();
+
+            let fits = match queue.messages.front() {
+                Some(message) => message.len <= max_len && message.handles.len() <= max_handles,
+                None if queue.sender_open => return Poll::Pending,
+                None => return Poll::Ready(Err(SysError::BrokenPipe)),
+            };
+
+            if !fits {
+                return Poll::Ready(Err(SysError::IllegalValue));
+            }
+
+            Poll::Ready(Ok(queue.messages.pop_front().expect("front was Some")))
+        }).await?;
+
+        wake(&self.shared.send_wakers[side]);
+
+        Ok(message)
+    }
+
+    /// Readable with a message queued, writable with room in the other end's
+    /// queue, HUP once the other end is closed.
+    pub fn poll_ready(&self, waker: &Waker) -> Result<Events, MemoryExhausted> {
+        let (side, peer) = (self.side, self.peer());
+
+        self.shared.recv_wakers[side].push_front(waker.clone())?;
+        self.shared.send_wakers[peer].push_front(waker.clone())?;
+
+        let mut events = Events::empty();
+
+        {
+            let incoming = self.shared.queues[side].lock();
+
+            if !incoming.messages.is_empty() {
+                events |= Events::IN;
+            }
+
+            if !incoming.sender_open {
+                events |= Events::HUP;
+            }
+        }
+
+        {
+            let outgoing = self.shared.queues[peer].lock();
+
+            if outgoing.receiver_open && !outgoing.messages.is_full() {
+                events |= Events::OUT;
+            }
+        }
+
+        Ok(events)
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        let peer = self.peer();
+
+        self.shared.queues[peer].lock().sender_open = false;
+
+        // nothing can receive what's queued for this end any more:
+        let unreceived = {
+            let mut queue = self.shared.queues[self.side].lock();
+            queue.receiver_open = false;
+            core::mem::replace(&mut queue.messages, ArrayDeque::new())
+        };
+
+        drop(unreceived);
+
+        // whatever the other end is blocked on won't happen now:
+        wake(&self.shared.recv_wakers[peer]);
+        wake(&self.shared.send_wakers[self.side]);
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        // both ends are gone, so anything still waiting was woken already:
+        for wakers in self.recv_wakers.iter().chain(self.send_wakers.iter()) {
+            wakers.take_iter().for_each(drop);
+        }
+    }
+}
+
+impl Debug for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Channel(side {})", self.side)
+    }
+}
+
+struct Backlog {
+    // connected channels waiting to be accepted:
+    pending: ArrayDeque<[Channel; CHANNEL_BACKLOG], Saturating>,
+    open: bool,
+}
+
+struct ListenerShared {
+    backlog: Mutex<Backlog>,
+    wakers: AtomicList<Waker>,
+}
+
+/// A name that processes can connect to, creating a channel. The server end
+/// of each channel is picked up with `accept`. The name is free again once
+/// the listener is dropped.
+pub struct Listener {
+    name: Name,
+    shared: Arc<ListenerShared>,
+}
+
+static LISTENERS: EarlyInit<Mutex<BTreeMap<Name, Arc<ListenerShared>, GlobalAlloc>>> = EarlyInit::new();
+
+pub fn init() {
+    EarlyInit::set(&LISTENERS, Mutex::new(BTreeMap::new()));
+}
+
+fn name(name: &[u8]) -> SysResult<Name> {
+    if name.len() == 0 {
+        return Err(SysError::IllegalValue);
+    }
+
+    util::array_string(name)
+        .map_err(|_| SysError::IllegalValue)
+}
+
+/// Starts listening for connections under `name`, which must not already be
+/// taken.
+pub fn listen(name_bytes: &[u8]) -> SysResult<Listener> {
+    let name = name(name_bytes)?;
+
+    let shared = Arc::new(ListenerShared {
+        backlog: Mutex::new(Backlog { pending: ArrayDeque::new(), open: true }),
+        wakers: AtomicList::new(),
+    })?;
+
+    let mut listeners = LISTENERS.lock();
+
+    if listeners.get(&name).is_some() {
+        return Err(SysError::AlreadyMapped);
+    }
+
+    listeners.insert(name, shared.clone())
+        .map_err(|_| SysError::MemoryExhausted)?;
+
+    Ok(Listener { name, shared })
+}
+
+/// Connects to the listener under `name`. Messages can be sent straight
+/// away, they're received once the connection has been accepted.
+pub fn connect(name_bytes: &[u8]) -> SysResult<Channel> {
+    let name = name(name_bytes)?;
+
+    let listener = LISTENERS.lock()
+        .get(&name)
+        .cloned()
+        .ok_or(SysError::NoFile)?;
+
+    let (client, server) = pair()?;
+
+    {
+        let mut backlog = listener.backlog.lock();
+
+        if !backlog.open {
+            return Err(SysError::NoFile);
+        }
+
+        // the server's falling behind, let the client try again later:
+        backlog.pending.push_back(server)
+            .map_err(|_| SysError::WouldBlock)?;
+    }
+
+    wake(&listener.wakers);
+
+    Ok(client)
+}
+
+impl Listener {
+    /// Waits for a connection, returning the server end of its channel.
+    pub async fn accept(&self) -> SysResult<Channel> {
+        let shared = &self.shared;
+
+        future::poll_fn(|ctx| {
+            // register waker before checking the backlog so that we can't
+            // miss a connection in between:
+            if let Err(MemoryExhausted) = shared.wakers.push_front(ctx.waker().clone()) {
+                return Poll::Ready(Err(SysError::MemoryExhausted));
+            }
+
+            match shared.backlog.lock().pending.pop_front() {
+                Some(channel) => Poll::Ready(Ok(channel)),
+                None => Poll::Pending,
+            }
+        }).await
+    }
+
+    /// Readable with a connection waiting to be accepted.
+    pub fn poll_ready(&self, waker: &Waker) -> Result<Events, MemoryExhausted> {
+        self.shared.wakers.push_front(waker.clone())?;
+
+        if self.shared.backlog.lock().pending.is_empty() {
+            Ok(Events::empty())
+        } else {
+            Ok(Events::IN)
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        LISTENERS.lock().remove(&self.name);
+
+        // the channels nobody accepted are closed, their clients see
+        // BrokenPipe:
+        let pending = {
+            let mut backlog = self.shared.backlog.lock();
+            backlog.open = false;
+            core::mem::replace(&mut backlog.pending, ArrayDeque::new())
+        };
+
+        drop(pending);
+
+        self.shared.wakers.take_iter().for_each(drop);
+    }
+}
+
+impl Debug for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Listener({:?})", self.name.as_str())
+    }
+}
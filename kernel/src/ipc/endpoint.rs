@@ -0,0 +1,205 @@
+use core::fmt::{self, Debug};
+use core::mem;
+use core::task::{Poll, Waker};
+
+use arraydeque::{ArrayDeque, Saturating};
+use futures::future;
+use interface::{SysError, SysResult};
+
+use crate::config::ENDPOINT_QUEUE_LEN;
+use crate::mem::MemoryExhausted;
+use crate::object::Capability;
+use crate::sync::{Arc, Mutex};
+use crate::util::AtomicList;
+
+/// A message passed through an endpoint: a few words, carried in registers,
+/// and optionally an object the receiver gets a handle to, with the same
+/// rights. Anything bigger goes in shared memory, passed as the object.
+pub struct Message {
+    pub words: [u64; 4],
+    pub object: Option<Capability>,
+}
+
+enum ReplyState {
+    Waiting,
+    Replied(Message),
+    // the Reply was dropped without being used, or the caller was never
+    // received:
+    Abandoned,
+    // the caller has taken the reply:
+    Taken,
+}
+
+struct ReplySlot {
+    state: Mutex<ReplyState>,
+    waker: Mutex<Option<Waker>>,
+}
+
+impl ReplySlot {
+    fn finish(&self, state: ReplyState) -> bool {
+        {
+            let mut current = self.state.lock();
+
+            if let ReplyState::Waiting = *current {
+                *current = state;
+            } else {
+                return false;
+            }
+        }
+
+        if let Some(waker) = self.waker.lock().take() {
+            waker.wake();
+        }
+
+        true
+    }
+}
+
+/// The right to reply to a call, once. The caller is blocked until it's used,
+/// and gets BrokenPipe if it's dropped unused.
+pub struct Reply {
+    slot: Arc<ReplySlot>,
+}
+
+impl Reply {
+    /// Replies to the caller, waking it. Fails with InvalidOperation if it's
+    /// already been replied to.
+    pub fn send(&self, message: Message) -> SysResult<()> {
+        if self.slot.finish(ReplyState::Replied(message)) {
+            Ok(())
+        } else {
+            Err(SysError::InvalidOperation)
+        }
+    }
+}
+
+impl Drop for Reply {
+    fn drop(&mut self) {
+        self.slot.finish(ReplyState::Abandoned);
+    }
+}
+
+impl Debug for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Reply")
+    }
+}
+
+// a call waiting to be received:
+struct Caller {
+    message: Message,
+    reply: Reply,
+}
+
+struct Shared {
+    callers: Mutex<ArrayDeque<[Caller; ENDPOINT_QUEUE_LEN], Saturating>>,
+    recv_wakers: AtomicList<Waker>,
+    call_wakers: AtomicList<Waker>,
+}
+
+/// A rendezvous point for synchronous calls, microkernel style. A caller
+/// blocks until a receiver has taken its message and replied, so that a
+/// server handles one request per client at a time and replies go straight
+/// back to whoever asked.
+pub struct Endpoint {
+    shared: Arc<Shared>,
+}
+
+impl Endpoint {
+    pub fn new() -> Result<Endpoint, MemoryExhausted> {
+        Ok(Endpoint {
+            shared: Arc::new(Shared {
+                callers: Mutex::new(ArrayDeque::new()),
+                recv_wakers: AtomicList::new(),
+                call_wakers: AtomicList::new(),
+            })?,
+        })
+    }
+
+    /// Sends a message to a receiver and waits for its reply.
+    pub async fn call(&self, message: Message) -> SysResult<Message> {
+        let slot = Arc::new(ReplySlot {
+            state: Mutex::new(ReplyState::Waiting),
+            waker: Mutex::new(None),
+        })?;
+
+        let mut caller = Some(Caller { message, reply: Reply { slot: slot.clone() } });
+
+        // wait for room in the queue:
+        future::poll_fn(|ctx| {
+            if let Err(MemoryExhausted) = self.shared.call_wakers.push_front(ctx.waker().clone()) {
+                return Poll::Ready(Err(SysError::MemoryExhausted));
+            }
+
+            let pending = caller.take().expect("Endpoint::call: polled after completion");
+
+            match self.shared.callers.lock().push_back(pending) {
+                Ok(()) => Poll::Ready(Ok(())),
+                Err(full) => {
+                    caller = Some(full.element);
+                    Poll::Pending
+                }
+            }
+        }).await?;
+
+        for waker in self.shared.recv_wakers.take_iter() {
+            waker.wake();
+        }
+
+        // wait for the reply:
+        future::poll_fn(|ctx| {
+            // register waker before checking the state so that we can't
+            // miss the reply in between:
+            *slot.waker.lock() = Some(ctx.waker().clone());
+
+            let mut state = slot.state.lock();
+
+            match mem::replace(&mut *state, ReplyState::Taken) {
+                ReplyState::Waiting => {
+                    *state = ReplyState::Waiting;
+                    Poll::Pending
+                }
+                ReplyState::Replied(message) => Poll::Ready(Ok(message)),
+                ReplyState::Abandoned => Poll::Ready(Err(SysError::BrokenPipe)),
+                ReplyState::Taken => panic!("Endpoint::call: reply taken twice"),
+            }
+        }).await
+    }
+
+    /// Waits for a call, returning its message and the right to reply to it.
+    pub async fn receive(&self) -> SysResult<(Message, Reply)> {
+        let caller = future::poll_fn(|ctx| {
+            // register waker before checking the queue so that we can't miss
+            // a call in between:
+            if let Err(MemoryExhausted) = self.shared.recv_wakers.push_front(ctx.waker().clone()) {
+                return Poll::Ready(Err(SysError::MemoryExhausted));
+            }
+
+            match self.shared.callers.lock().pop_front() {
+                Some(caller) => Poll::Ready(Ok(caller)),
+                None => Poll::Pending,
+            }
+        }).await?;
+
+        for waker in self.shared.call_wakers.take_iter() {
+            waker.wake();
+        }
+
+        Ok((caller.message, caller.reply))
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        // the queued callers' Replies go with the queue, which wakes them
+        // with BrokenPipe:
+        self.recv_wakers.take_iter().for_each(drop);
+        self.call_wakers.take_iter().for_each(drop);
+    }
+}
+
+impl Debug for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Endpoint")
+    }
+}
@@ -0,0 +1,144 @@
+use core::task::{Poll, Waker};
+
+use alloc_collections::btree_map::BTreeMap;
+use futures::future;
+use interface::{SysError, SysResult};
+
+use crate::critical;
+use crate::mem::kalloc::GlobalAlloc;
+use crate::mem::page;
+use crate::mem::phys::RawPhys;
+use crate::mem::user;
+use crate::sync::Mutex;
+use crate::util::EarlyInit;
+
+// waiters are keyed by the physical address of the futex word, so that tasks
+// in different processes sharing the memory wait on the same futex, and by
+// ticket so that they're woken in the order they started waiting:
+type Key = (RawPhys, u64);
+
+struct Waiter {
+    waker: Option<Waker>,
+    woken: bool,
+}
+
+struct Futexes {
+    waiters: BTreeMap<Key, Waiter, GlobalAlloc>,
+    next_ticket: u64,
+}
+
+static FUTEXES: EarlyInit<Mutex<Futexes>> = EarlyInit::new();
+
+pub fn init() {
+    EarlyInit::set(&FUTEXES, Mutex::new(Futexes {
+        waiters: BTreeMap::new(),
+        next_ticket: 0,
+    }));
+}
+
+// a futex word is a naturally aligned u32:
+fn futex_phys(addr: u64) -> SysResult<RawPhys> {
+    if addr % 4 != 0 {
+        return Err(SysError::IllegalValue);
+    }
+
+    let crit = critical::begin();
+    user::validate_read(addr, 4, &crit)?;
+
+    page::translate(addr, &crit)
+        .map_err(|_| SysError::BadPointer)
+}
+
+fn read_word(addr: u64) -> SysResult<u32> {
+    let mut word = [0u8; 4];
+
+    let crit = critical::begin();
+    user::copy_from_user(&mut word, addr, &crit)?;
+
+    Ok(u32::from_ne_bytes(word))
+}
+
+// takes the waiter out of the table however the wait ends, including the
+// task being killed while it waits:
+struct WaitGuard(Key);
+
+impl Drop for WaitGuard {
+    fn drop(&mut self) {
+        FUTEXES.lock().waiters.remove(&self.0);
+    }
+}
+
+/// Waits on the futex word at user address `addr` as long as it still holds
+/// `expected`, until woken by `wake`. Fails with WouldBlock straight away if
+/// it doesn't, so that a wake between the caller reading the word and
+/// waiting can't be missed.
+pub async fn wait(addr: u64, expected: u32) -> SysResult<()> {
+    let phys = futex_phys(addr)?;
+
+    let guard = {
+        let mut futexes = FUTEXES.lock();
+
+        // wakers take the lock before waking anyone, so checking the word
+        // with it held closes the race with a concurrent wake:
+        if read_word(addr)? != expected {
+            return Err(SysError::WouldBlock);
+        }
+
+        let key = (phys, futexes.next_ticket);
+        futexes.next_ticket += 1;
+
+        futexes.waiters.insert(key, Waiter { waker: None, woken: false })
+            .map_err(|_| SysError::MemoryExhausted)?;
+
+        WaitGuard(key)
+    };
+
+    future::poll_fn(|ctx| {
+        let mut futexes = FUTEXES.lock();
+
+        let waiter = futexes.waiters.get_mut(&guard.0)
+            .expect("futex::wait: waiter missing from table");
+
+        if waiter.woken {
+            Poll::Ready(())
+        } else {
+            waiter.waker = Some(ctx.waker().clone());
+            Poll::Pending
+        }
+    }).await;
+
+    Ok(())
+}
+
+/// Wakes up to `count` tasks waiting on the futex word at user address
+/// `addr`, longest waiting first. Returns how many were woken.
+pub fn wake(addr: u64, count: u64) -> SysResult<u64> {
+    let phys = futex_phys(addr)?;
+
+    let mut woken = 0;
+
+    loop {
+        let waker = {
+            let mut futexes = FUTEXES.lock();
+
+            let waiter = futexes.waiters.range_mut((phys, 0)..=(phys, u64::max_value()))
+                .map(|(_, waiter)| waiter)
+                .find(|waiter| !waiter.woken);
+
+            let waiter = match waiter {
+                Some(waiter) if woken < count => waiter,
+                _ => return Ok(woken),
+            };
+
+            waiter.woken = true;
+            waiter.waker.take()
+        };
+
+        // a waiter that hasn't been polled yet sees it's woken when it is:
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+
+        woken += 1;
+    }
+}
@@ -2,13 +2,26 @@ bits 64
 
 global isrs_init
 global interrupt_return
+global syscall_entry
 extern panic
 extern interrupt
+extern cpu_smap
 
 %include "kernel/src/consts.asm"
 
 %define IDT_SIZE 0x1000
 
+; vectors for message signalled interrupts, must match MSI_BASE and
+; MSI_VECTORS in interrupt.rs:
+%define MSI_BASE    0x50
+%define MSI_VECTORS 32
+%define MSI_STUB_SIZE 16
+
+; vectors for GSIs routed through an I/O APIC, must match GSI_BASE and
+; GSI_VECTORS in interrupt.rs. their stubs are the same size as MSI ones:
+%define GSI_BASE    0x90
+%define GSI_VECTORS 32
+
 %define PIC1 0x20
 %define PIC2 0xa0
 %define COMMAND 0
@@ -22,6 +35,7 @@ isrs_init:
     %define IDT_PRESENT 0x8000
     %define IDT_INT64   0x0e00
     %define IDT_DPL3    0x6000
+    %define IDT_IST1    0x0001
 
     ; ENTRY(vector, offset, segment, flags)
     %macro ENTRY 4
@@ -61,7 +75,7 @@ isrs_init:
     ENTRY 0x05, bound_range_exceeded,       SEG_KCODE, IDT_PRESENT | IDT_INT64
     ENTRY 0x06, invalid_opcode,             SEG_KCODE, IDT_PRESENT | IDT_INT64
     ENTRY 0x07, device_not_available,       SEG_KCODE, IDT_PRESENT | IDT_INT64
-    ENTRY 0x08, double_fault,               SEG_KCODE, IDT_PRESENT | IDT_INT64
+    ENTRY 0x08, double_fault,               SEG_KCODE, IDT_PRESENT | IDT_INT64 | IDT_IST1
     ENTRY 0x0a, invalid_tss,                SEG_KCODE, IDT_PRESENT | IDT_INT64
     ENTRY 0x0b, segment_not_present,        SEG_KCODE, IDT_PRESENT | IDT_INT64
     ENTRY 0x0c, stack_segment_fault,        SEG_KCODE, IDT_PRESENT | IDT_INT64
@@ -90,13 +104,35 @@ isrs_init:
     ENTRY 0x2e, irq14,                      SEG_KCODE, IDT_PRESENT | IDT_INT64
     ENTRY 0x2f, irq15,                      SEG_KCODE, IDT_PRESENT | IDT_INT64
 
+    ENTRY 0x40, tlb_shootdown,              SEG_KCODE, IDT_PRESENT | IDT_INT64
+    ; local APIC timer, see smp/lapic.rs:
+    ENTRY 0x41, lapic_timer,                SEG_KCODE, IDT_PRESENT | IDT_INT64
+    ; sent between CPUs when one queues a task on another, see smp.rs:
+    ENTRY 0x42, reschedule,                 SEG_KCODE, IDT_PRESENT | IDT_INT64
+
+    %assign vector MSI_BASE
+    %rep MSI_VECTORS
+        ENTRY vector, msi_stubs + (vector - MSI_BASE) * MSI_STUB_SIZE, SEG_KCODE, IDT_PRESENT | IDT_INT64
+        %assign vector vector + 1
+    %endrep
+
+    %assign vector GSI_BASE
+    %rep GSI_VECTORS
+        ENTRY vector, gsi_stubs + (vector - GSI_BASE) * MSI_STUB_SIZE, SEG_KCODE, IDT_PRESENT | IDT_INT64
+        %assign vector vector + 1
+    %endrep
+
     ENTRY 0x7f, syscall_,                   SEG_KCODE, IDT_PRESENT | IDT_INT64 | IDT_DPL3
 
+    ; local APIC spurious interrupts, see smp/lapic.rs:
+    ENTRY 0xff, lapic_spurious,             SEG_KCODE, IDT_PRESENT | IDT_INT64
+
     ; load IDT
     lidt [rel idtr]
     ret
 
 DISPATCH_0 0x06, invalid_opcode
+DISPATCH_E 0x08, double_fault
 DISPATCH_E 0x0d, general_protection_fault
 DISPATCH_E 0x0e, page_fault
 
@@ -120,9 +156,76 @@ DISPATCH_0 0x2d, irq13
 DISPATCH_0 0x2e, irq14
 ; DISPATCH_0 0x2f, irq15
 
+DISPATCH_0 0x40, tlb_shootdown
+DISPATCH_0 0x41, lapic_timer
+DISPATCH_0 0x42, reschedule
+
+; MSI dispatchers, see interrupt::alloc_msi. each is padded out to
+; MSI_STUB_SIZE so that the IDT entries can find them by vector:
+align MSI_STUB_SIZE
+msi_stubs:
+%assign vector MSI_BASE
+%rep MSI_VECTORS
+    align MSI_STUB_SIZE
+    push qword 0
+    push qword vector
+    jmp interrupt_common
+    %assign vector vector + 1
+%endrep
+
+; GSI dispatchers, see interrupt::register_gsi. laid out like the MSI ones:
+align MSI_STUB_SIZE
+gsi_stubs:
+%assign vector GSI_BASE
+%rep GSI_VECTORS
+    align MSI_STUB_SIZE
+    push qword 0
+    push qword vector
+    jmp interrupt_common
+    %assign vector vector + 1
+%endrep
+
 DISPATCH_0 0x7f, syscall_
 
+; entry point for the syscall instruction, see interrupt::init_syscall. the CPU
+; leaves the user's rip in rcx and rflags in r11, masks interrupts and doesn't
+; switch stacks, so build the same trap frame an interrupt from user mode would
+; have left on the kernel stack:
+syscall_entry:
+    swapgs
+    mov [gs:PERCPU_USER_RSP], rsp
+    mov rsp, [gs:PERCPU_KERNEL_STACK]   ; see interrupt::set_kernel_stack
+
+    push qword SEG_UDATA                ; ss
+    push qword [gs:PERCPU_USER_RSP]     ; rsp
+    push r11                            ; rflags
+    push qword SEG_UCODE                ; cs
+    push rcx                            ; rip
+    push qword 0                        ; error code
+    push qword SYSCALL_VECTOR
+
+    ; rcx is taken, so the fourth argument comes in r10 instead:
+    mov rcx, r10
+    jmp interrupt_save
+
 interrupt_common:
+    ; user mode can set AC with popfq before int 0x7f, or be interrupted with
+    ; it set, so close user access on the way in. syscall_entry has it cleared
+    ; by SFMASK instead. clac doesn't exist without SMAP:
+    cmp byte [rel cpu_smap], 0
+    je .no_smap
+    clac
+.no_smap:
+
+    ; if we came from user mode, swap the kernel's GS.base back in. the
+    ; interrupted cs sits above the vector, error code and rip:
+    test qword [rsp + 24], 3
+    jz .from_kernel
+    swapgs
+.from_kernel:
+
+interrupt_save:
+
     ; TODO - check SS and other seg regs
     ; do we need to fix up ds/es if coming from ring 3?
 
@@ -154,6 +257,20 @@ interrupt_common:
     call interrupt
 
 interrupt_return:
+    ; frames made by syscall_entry go back with sysret, which is quicker than
+    ; iretq. the general purpose registers sit below the vector:
+    cmp qword [rsp + 15 * 8], SYSCALL_VECTOR
+    jne .iret
+    cmp qword [rsp + 15 * 8 + 24], SEG_UCODE
+    jne .iret
+
+    ; sysret to a non-canonical rip faults in kernel mode on the user's stack,
+    ; so leave those to iretq:
+    mov rax, [rsp + 15 * 8 + 16]
+    shr rax, 47
+    jz syscall_return
+
+.iret:
     ; pop general purpose registers
     pop r15
     pop r14
@@ -177,9 +294,45 @@ interrupt_return:
     ; pop interrupt vector and error code
     add rsp, 16
 
+    ; swap the user's GS.base back in if we're returning to user mode
+    test qword [rsp + 8], 3
+    jz .to_kernel
+    swapgs
+.to_kernel:
+
     ; TODO figure out other return stuff
     iretq
 
+syscall_return:
+    ; nothing can be allowed to interrupt us once rsp points at the user's
+    ; stack. the user's rflags turn interrupts back on:
+    cli
+
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rdi
+    pop rsi
+    pop rbp
+    pop rbx
+    pop rdx
+    pop rcx
+    pop rax
+
+    ; sysret takes rip from rcx and rflags from r11, which the syscall
+    ; instruction clobbers anyway:
+    mov rcx, [rsp + 16]                 ; rip
+    mov r11, [rsp + 32]                 ; rflags
+    mov rsp, [rsp + 40]                 ; rsp
+
+    swapgs
+    o64 sysret
+
 pic_init:
     ; save pic masks, PIC1 in BL and PIC2 in BH
     in al, PIC2 + DATA
@@ -243,9 +396,6 @@ bound_range_exceeded:
 device_not_available:
     DISPATCH_PANIC "device not available"
 
-double_fault:
-    DISPATCH_PANIC "double fault"
-
 invalid_tss:
     DISPATCH_PANIC "invalid tss"
 
@@ -315,8 +465,13 @@ irq15:
     pop ax
     iretq
 
+; spurious interrupts from the local APIC aren't acknowledged
+lapic_spurious:
+    iretq
+
 section .data
 align 4
+global idtr
 idtr:
     dw IDT_SIZE - 1
     dq idt
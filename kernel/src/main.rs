@@ -18,26 +18,34 @@
 #[macro_use]
 extern crate kernel_derive;
 
+mod acpi;
+mod config;
 mod console;
+mod cpu;
 mod critical;
 mod device;
+mod exec;
 mod fs;
 mod interrupt;
+mod ipc;
 mod mem;
 mod object;
 mod panic;
+mod percpu;
+mod power;
+mod random;
+mod smp;
 mod sync;
 mod syscall;
 mod task;
+mod time;
 mod util;
-
-use core::slice;
+mod work;
 
 use futures::future::{Future, FutureExt, OptionFuture};
 
-use fs::vfs::Filesystem;
-use interrupt::TrapFrame;
-use mem::page::{self, PageFlags, PAGE_SIZE};
+use fs::vfs::{FsContext, Namespace};
+use mem::page;
 use mem::phys;
 use object::ObjectRef;
 use sync::Arc;
@@ -51,102 +59,248 @@ pub extern "C" fn main() -> ! {
     unsafe {
         let crit = critical::begin();
 
+        // find out what the CPU can do, and turn on what protects the
+        // kernel from itself
+        cpu::init();
+
+        // make data no execute, before anything else is mapped
+        if cpu::has(cpu::Features::NX) {
+            page::enable_no_execute();
+        }
+
+        // turn on the FPU and its extensions for user mode
+        cpu::fpu::init();
+
         // perform follow up init for phys allocator
         phys::init_ref_counts(&crit);
 
         // init kernel PML4 entries
         mem::page::init_kernel_pml4_entries(&crit);
 
-        // init object space
-        object::init();
+        // init page cache
+        device::cache::init();
+
+        // init block device registry
+        device::block::init();
+
+        // init PCI device list
+        device::pci::init();
+
+        // init channel names
+        ipc::channel::init();
+
+        // init futex wait queues
+        ipc::futex::init();
+
+        // init event queue registry
+        object::evq::init();
+
+        // init device node table
+        fs::devfs::init();
+
+        // seed the random number generator, and add its device nodes
+        random::init();
+
+        // init kernel stack allocator
+        mem::kstack::init();
+
+        // find ACPI tables, which there may not be
+        if let Err(e) = acpi::init() {
+            println!("no ACPI tables: {:?}", e);
+        }
+
+        // reach PCI configuration space through ECAM if MCFG says where. only
+        // segment 0 is reachable through the legacy ports:
+        if let Some(region) = acpi::ecam_regions().find(|region| region.segment == 0) {
+            device::pci::use_ecam(region.base, region.start_bus, region.end_bus)
+                .expect("pci::use_ecam");
+        }
 
-        // init pit
+        // quiet the pit, the local APIC timer takes over from it
         device::pit::init();
 
+        // init the BSP's local APIC, whose timer keeps time
+        smp::init_bsp()
+            .expect("smp::init_bsp");
+
+        // route IRQs through the I/O APICs, if there are any
+        interrupt::init_ioapic();
+
+        // init console line discipline
+        console::tty::init();
+
         // init keyboard
         device::keyboard::init();
+
+        // init mouse, which there may not be
+        if let Err(e) = device::mouse::init() {
+            println!("no mouse: {:?}", e);
+        }
+
+        // init serial port, which the console may move to
+        match device::serial::init() {
+            Ok(()) => console::init_serial(),
+            Err(e) => println!("no serial port: {:?}", e),
+        }
+
+        // enable the syscall instruction
+        interrupt::init_syscall();
     }
 
     task::init();
+    time::init();
+
+    // the BSP runs with interrupts enabled from here on:
+    mem::tlb::accept();
 
     unsafe {
-        let page_ctx = ObjectRef::new(page::current_ctx())
+        let page_ctx = ObjectRef::new(page::current_ctx().expect("current_ctx"))
             .expect("ObjectRef::new");
 
-        task::spawn(page_ctx, None, |task| async move {
-            use device::ide::{self, Drive};
-            use device::mbr::Mbr;
-            use fs::fat16::{Open, Fat16, DirEntry};
+        let process = task::Process::new(None, page_ctx, None)
+            .expect("Process::new");
+
+        task::spawn(process, task::TaskName::Static("init"), |task| async move {
+            use device::block::{self, RequestQueue};
+            use device::ide::{self, Drive, IdeDisk};
+            use fs::fat16::Fat16;
+            use fs::fat32::Fat32;
+            use fs::vfs::Filesystem;
+            use fs::tmpfs::Tmpfs;
+
+            smp::init().await
+                .expect("smp::init");
+
+            device::cache::start_writeback()
+                .expect("cache::start_writeback");
 
             let ide = ide::PRIMARY.open(Drive::A)
                 .expect("ide::open");
 
             println!("detecting primary master...");
-            println!("---> {:?}", ide.detect().await);
 
-            let mbr = Mbr::open(ide)
-                .expect("Mbr::open");
+            // real hardware may well have no IDE disk, only SATA ones:
+            match ide.detect().await {
+                Ok(detect) => {
+                    println!("---> {:?}", detect);
 
-            let mut partitions = mbr.partitions().await
-                .expect("mbr.partitions");
+                    let queue = RequestQueue::new(Arc::new(IdeDisk::new(ide, &detect)).expect("Arc::new"));
 
-            for part in partitions.iter() {
-                if let Some(part) = part {
-                    crate::println!("#{} - {}, {}", part.number, part.lba, part.sectors);
+                    block::register(b"hda", Arc::new(queue).expect("Arc::new"))
+                        .await
+                        .expect("block::register");
                 }
+                Err(e) => println!("---> {:?}", e),
             }
 
-            let fat = Fat16::open(partitions.remove(0).expect("partitions[0]")).await
-                .expect("Fat16::open");
+            device::pci::enumerate()
+                .expect("pci::enumerate");
 
-            // find init:
-            let entry = fat.root().entry(b"init.bin")
-                .await
-                .expect("entry")
-                .map(|entry| entry.open().expect("open"));
+            // any virtio disks QEMU was given are there to be mounted:
+            device::pci::register_driver(&device::virtio::blk::DRIVER).await
+                .expect("pci::register_driver");
 
-            let mut init = match entry {
-                Some(Open::File(init)) => init,
-                Some(Open::Dir(_)) => {
-                    panic!("/init.bin is directory");
-                }
-                None => {
-                    panic!("/init.bin does not exist");
-                }
-            };
+            device::pci::register_driver(&device::ahci::DRIVER).await
+                .expect("pci::register_driver");
 
-            let filesystem = Filesystem::new(fat);
-            task::set_filesystem(Some(Arc::new(filesystem)
-                .expect("Arc::new")));
+            device::pci::register_driver(&device::nvme::DRIVER).await
+                .expect("pci::register_driver");
 
-            // setup init task
-            let mut addr = 0x1000_0000 as *mut u8;
-            let mut task = task.setup(TrapFrame::new(addr as u64, 0x0));
+            // booting from the first partition of whichever disk has one:
+            let boot_part = [&b"hda1"[..], b"sda1", b"nvme0n1p1", b"vda1"].iter()
+                .find_map(|name| block::get(name))
+                .expect("block::get boot partition");
 
-            // read init into userspace
-            loop {
-                let phys = phys::alloc()
-                    .expect("phys::alloc");
+            let disk = boot_part.parent()
+                .expect("boot partition without a disk")
+                .clone();
 
-                page::map(phys, addr, PageFlags::PRESENT | PageFlags::WRITE | PageFlags::USER)
-                    .expect("page::map");
+            // the boot partition is FAT16 on the disk images the Makefile
+            // builds, but may as well be FAT32, like an EFI system partition:
+            let fat: Arc<dyn Filesystem> = if Fat32::probe(&boot_part).await.expect("Fat32::probe") {
+                let fat = Fat32::open(boot_part.clone()).await
+                    .expect("Fat32::open");
 
-                let read = init.read(slice::from_raw_parts_mut(addr, PAGE_SIZE))
-                    .await
-                    .expect("init.read");
+                Arc::new(fat).expect("Arc::new")
+            } else {
+                let fat = Fat16::open(boot_part.clone()).await
+                    .expect("Fat16::open");
 
-                addr = addr.add(read);
+                Arc::new(fat).expect("Arc::new")
+            };
 
-                if read < PAGE_SIZE {
-                    break;
-                }
+            let namespace = Namespace::new();
+
+            // with an initrd, the root is a tmpfs holding its contents, and
+            // the boot partition goes to /boot instead:
+            if fs::initramfs::present() {
+                let root = Tmpfs::new()
+                    .expect("Tmpfs::new");
+
+                namespace.mount(b"/", Arc::new(root).expect("Arc::new"))
+                    .expect("Namespace::mount");
+
+                namespace.mount(b"/boot", fat)
+                    .expect("Namespace::mount");
+
+                fs::initramfs::unpack(&namespace).await
+                    .expect("initramfs::unpack");
+            } else if let Some(root) = ext2_root(&disk, &boot_part).await {
+                // otherwise a Linux-built ext2 image on another partition
+                // can be the root:
+                namespace.mount(b"/", Arc::new(root).expect("Arc::new"))
+                    .expect("Namespace::mount");
+
+                namespace.mount(b"/boot", fat)
+                    .expect("Namespace::mount");
+            } else {
+                namespace.mount(b"/", fat)
+                    .expect("Namespace::mount");
             }
 
+            let tmp = Tmpfs::new()
+                .expect("Tmpfs::new");
+
+            namespace.mount(b"/tmp", Arc::new(tmp).expect("Arc::new"))
+                .expect("Namespace::mount");
+
+            namespace.mount(b"/dev", Arc::new(fs::devfs::Devfs).expect("Arc::new"))
+                .expect("Namespace::mount");
+
+            namespace.mount(b"/proc", Arc::new(fs::procfs::Procfs).expect("Arc::new"))
+                .expect("Namespace::mount");
+
+            let namespace = Arc::new(namespace)
+                .expect("Arc::new");
+
+            let fs = Arc::new(FsContext::new(namespace.clone()))
+                .expect("Arc::new");
+
+            task::set_filesystem(Some(fs));
+
+            // find init:
+            let init = namespace.open_executable(b"/init.bin", &task::Credentials::ROOT)
+                .await
+                .expect("open /init.bin");
+
+            // load init into a fresh page context and setup init task
+            let args = exec::Args::new(exec::Layout::new(true))
+                .expect("exec::Args::new");
+
+            let image = exec::load(&init, &args, false)
+                .await
+                .expect("exec::load");
+
+            let mut task = task.setup(image.trap_frame());
+
+            task::set_page_ctx(ObjectRef::new(image.page_ctx)
+                .expect("ObjectRef::new"));
+
             // set up initial console object
             let console = ObjectRef::new(crate::fs::File::Console)
                 .expect("ObjectRef::new");
 
-            object::put(task::current(), console.as_dyn()) // implicitly handle 1
+            object::put(&task::current_process(), console.as_dyn()) // implicitly handle 1
                 .expect("object::put");
 
             task.run_loop().await;
@@ -169,3 +323,27 @@ pub extern "C" fn main() -> ! {
         task::start();
     }
 }
+
+// takes the first partition of `disk`, besides the boot partition, with an
+// ext2 filesystem that can be read, if there is one:
+async fn ext2_root(disk: &device::block::Disk, boot_part: &device::block::Disk) -> Option<fs::ext2::Ext2> {
+    let mut next = device::block::next(b"");
+
+    while let Some(part) = next {
+        next = device::block::next(part.name());
+
+        let candidate = part.id() != boot_part.id()
+            && part.parent().map(|parent| parent.id()) == Some(disk.id());
+
+        if !candidate || !fs::ext2::Ext2::probe(&part).await.unwrap_or(false) {
+            continue;
+        }
+
+        match fs::ext2::Ext2::open(part).await {
+            Ok(ext2) => return Some(ext2),
+            Err(e) => println!("ext2: could not open root: {:?}", e),
+        }
+    }
+
+    None
+}
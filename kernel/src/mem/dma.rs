@@ -0,0 +1,91 @@
+use core::ptr::NonNull;
+use core::slice;
+use core::sync::atomic::{self, Ordering};
+
+use crate::mem::kvirt;
+use crate::mem::page::PAGE_SIZE;
+use crate::mem::phys::{self, PhysBlock, RawPhys};
+use crate::mem::MemoryExhausted;
+
+/// Where in physical memory a DMA buffer may go.
+#[derive(Debug, Clone, Copy)]
+pub struct Constraints {
+    /// The buffer must end at or below this physical address.
+    pub limit: RawPhys,
+}
+
+impl Constraints {
+    /// Anywhere in physical memory, for devices that can address all of it.
+    pub const ANY: Constraints = Constraints { limit: RawPhys(u64::max_value()) };
+
+    /// Below 4 GiB, for devices that only take 32 bit addresses.
+    pub const BELOW_4G: Constraints = Constraints { limit: RawPhys(1 << 32) };
+}
+
+/// A zeroed, physically contiguous buffer mapped into the kernel's address
+/// space, for handing to a device by its physical address.
+pub struct DmaBuffer {
+    virt: NonNull<u8>,
+    len: usize,
+    block: PhysBlock,
+}
+
+/// Allocates a DMA buffer of at least `len` bytes, rounded up to whole pages.
+pub fn alloc(len: usize, constraints: Constraints) -> Result<DmaBuffer, MemoryExhausted> {
+    let pages = (len.checked_add(PAGE_SIZE - 1).ok_or(MemoryExhausted)?) / PAGE_SIZE;
+
+    let block = phys::alloc_contiguous_below(pages, constraints.limit)?;
+    let virt = kvirt::map_block(&block)?;
+
+    Ok(DmaBuffer { virt, len, block })
+}
+
+impl DmaBuffer {
+    pub fn virt(&self) -> NonNull<u8> {
+        self.virt
+    }
+
+    /// The physical address of the start of the buffer, for the device.
+    pub fn phys(&self) -> RawPhys {
+        self.block.base()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.virt.as_ptr(), self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.virt.as_ptr(), self.len) }
+    }
+
+    /// Call after filling in the buffer and before telling the device to read
+    /// it. DMA is cache coherent on x86, so all that's needed is for every
+    /// write to have landed.
+    pub fn sync_for_device(&self) {
+        fence();
+    }
+
+    /// Call after the device says it's done writing to the buffer and before
+    /// reading it, so no read is done early.
+    pub fn sync_for_cpu(&self) {
+        fence();
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        // the pages go back to the allocator once the block goes too:
+        unsafe { kvirt::free_pages(self.virt, self.block.pages()); }
+    }
+}
+
+/// Orders every memory access before it, from the compiler and the CPU's
+/// point of view, before every one after it.
+pub fn fence() {
+    atomic::compiler_fence(Ordering::SeqCst);
+    unsafe { asm!("mfence" ::: "memory" : "volatile"); }
+}
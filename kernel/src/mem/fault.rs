@@ -1,23 +1,161 @@
-use crate::interrupt::TrapFrame;
+use interface::{EXIT_FAULT, SIGSEGV};
+
+use crate::critical::{self, Critical};
+use crate::interrupt::{TrapFrame, TrapOrigin};
+use crate::mem::page::{self, PageCtx, PageFlags, PAGE_SIZE};
+use crate::mem::user;
+use crate::mem::vma;
+use crate::mem::{self, MemoryExhausted};
+use crate::task;
+use crate::task::signal::{self, Signal};
 
 use bitflags::bitflags;
 
 bitflags! {
+    // the page fault error code pushed by the CPU:
     pub struct Flags: u64 {
-        const PRESENT   = 0x001;
-        const WRITE     = 0x002;
-        const USER      = 0x004;
+        const PRESENT       = 0x001;
+        const WRITE         = 0x002;
+        const USER          = 0x004;
+        const RESERVED      = 0x008;
+        const INSTRUCTION   = 0x010;
     }
 }
 
-extern {
-    static _bss: u8;
-    static _bss_end: u8;
+/// What a page fault was caused by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// An untouched page of a VMA, mapped in on first access.
+    Demand,
+    /// A write to a page shared copy on write by fork.
+    CopyOnWrite,
+    /// An access to an address with nothing mapped at it.
+    NotPresent,
+    /// An access the flags of a mapped page don't allow, such as a write to a
+    /// read-only page or a user mode access to a kernel page.
+    Protection,
+    /// A page table entry has reserved bits set. The page tables are corrupt.
+    Reserved,
 }
 
-pub fn fault(frame: &TrapFrame, flags: Flags, address: *const u8) {
-    panic!("Page fault! rip: {:x?}, address: {:?}, flags: {:?}",
-        frame.rip,
+impl Kind {
+    fn classify(flags: Flags, address: u64, page_ctx: Option<&PageCtx>, crit: &Critical) -> Kind {
+        if flags.contains(Flags::RESERVED) {
+            return Kind::Reserved;
+        }
+
+        if !flags.contains(Flags::PRESENT) {
+            let in_vma = page::is_user_addr(address) && page_ctx
+                .map(|page_ctx| page_ctx.vmas().lock().find(address).is_some())
+                .unwrap_or(false);
+
+            return if in_vma { Kind::Demand } else { Kind::NotPresent };
+        }
+
+        let page = (address & !(PAGE_SIZE as u64 - 1)) as *mut u8;
+
+        let cow = flags.contains(Flags::WRITE) && page::entry(page, crit)
+            .map(|entry| entry.flags().contains(PageFlags::COW))
+            .unwrap_or(false);
+
+        if cow {
+            Kind::CopyOnWrite
+        } else {
+            Kind::Protection
+        }
+    }
+}
+
+pub fn fault(frame: &mut TrapFrame, flags: Flags, address: *const u8) {
+    let crit = critical::begin();
+
+    // there's no page context to look in if there's no current task:
+    let page_ctx = task::try_get_page_ctx();
+    let page_ctx = page_ctx.as_ref().map(|page_ctx| page_ctx.object());
+
+    // other threads of the process may be faulting on or unmapping the same
+    // page on other CPUs. the page tables have to stay as they were when the
+    // fault was classified until it's resolved. faults on kernel addresses
+    // are left alone, they're never resolved and may well come from code
+    // holding the lock:
+    let tables = page_ctx
+        .filter(|_| page::is_user_addr(address as u64))
+        .map(|page_ctx| page_ctx.lock_tables());
+
+    let kind = Kind::classify(flags, address as u64, page_ctx, &crit);
+
+    let resolved = match kind {
+        Kind::Demand => {
+            page_ctx
+                .map(|page_ctx| vma::fault_in(page_ctx, address as u64, &crit))
+                .unwrap_or(Ok(false))
+        }
+        Kind::CopyOnWrite => {
+            unsafe { page::copy_on_write(address as *mut u8, &crit) }
+        }
+        Kind::NotPresent | Kind::Protection | Kind::Reserved => Ok(false),
+    };
+
+    drop(tables);
+
+    let reason = match resolved {
+        Ok(true) => {
+            // the page is mapped in or writable now, retry the access:
+            return;
+        }
+        Ok(false) => "bad access",
+        Err(MemoryExhausted) => "out of memory",
+    };
+
+    // a user task can only hurt itself, so it's killed and everyone else
+    // carries on. corrupt page tables can't be blamed on it though:
+    let from_user = match frame.origin() {
+        TrapOrigin::User => true,
+        TrapOrigin::Kernel => false,
+    };
+
+    if from_user && kind != Kind::Reserved {
+        drop(crit);
+
+        let task_id = task::current();
+
+        // a task that handles SIGSEGV gets to deal with it itself:
+        let segv = Signal::new(SIGSEGV).expect("mem::fault: SIGSEGV out of range");
+
+        if signal::is_handled(segv) && signal::send(task_id, segv).is_ok() {
+            if !signal::deliver(task_id, frame) {
+                unsafe { task::switch(frame); }
+            }
+
+            return;
+        }
+
+        crate::println!("mem::fault: {} ({:?}) at {:?} in {:?}, rip: {:x?}, flags: {:?}, killing it",
+            reason, kind, address, task_id, frame.rip, flags);
+
+        if let Err(MemoryExhausted) = resolved {
+            crate::println!("mem::fault: {}", mem::stats());
+        }
+
+        let _ = task::kill(task_id, task::ExitStatus(EXIT_FAULT));
+        unsafe { task::switch(frame); }
+        return;
+    }
+
+    // the user copy routines can't always validate everything up front, and
+    // bail out on a bad user pointer instead:
+    if kind != Kind::Reserved && page::is_user_addr(address as u64) && user::recover_fault(frame) {
+        return;
+    }
+
+    // anything else is a kernel bug. the kernel validates user pointers before
+    // touching user memory, so faults on user addresses are included:
+    panic!("Page fault in kernel! {} ({:?}) at {:?}, rip: {:x?}, rsp: {:x?}, flags: {:?}, user address: {}",
+        reason,
+        kind,
         address,
-        flags);
+        frame.rip,
+        frame.rsp,
+        flags,
+        page::is_user_addr(address as u64));
 }
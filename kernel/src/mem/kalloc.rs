@@ -1,7 +1,9 @@
 use core::alloc::{AllocErr, Layout};
-use core::mem;
+use core::{cmp, mem, slice};
 use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
+use crate::config::HEAP_CHECKS;
 use crate::mem::{kvirt, MemoryExhausted};
 use crate::mem::page::PAGE_SIZE;
 use crate::sync::Mutex;
@@ -10,6 +12,28 @@ pub type Box<T> = alloc_collections::boxed::Box<T, GlobalAlloc>;
 
 static ALLOCATOR: Mutex<Allocator> = Mutex::new(Allocator::new());
 
+// with HEAP_CHECKS, the bytes either side of every allocation and the bytes
+// of every free object:
+const REDZONE: usize = 16;
+const REDZONE_BYTE: u8 = 0xfa;
+const POISON_BYTE: u8 = 0x6b;
+
+// kept outside of the allocator so memory stats can be taken without its lock,
+// which may be held by whoever ran out of memory:
+static HEAP_PAGES: AtomicUsize = AtomicUsize::new(0);
+static HEAP_USED: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the number of pages the kernel heap has taken for itself.
+pub fn heap_pages() -> usize {
+    HEAP_PAGES.load(Ordering::SeqCst)
+}
+
+/// Returns the number of bytes of the kernel heap allocated, including what's
+/// lost to rounding up to a size class.
+pub fn heap_used() -> usize {
+    HEAP_USED.load(Ordering::SeqCst)
+}
+
 pub fn alloc<T>(value: T) -> Result<NonNull<T>, MemoryExhausted> {
     ALLOCATOR.lock().alloc(value)
 }
@@ -47,6 +71,11 @@ impl SizeClass {
         }
 
         let new_page = kvirt::alloc_page::<u8>()?.as_ptr();
+        HEAP_PAGES.fetch_add(1, Ordering::SeqCst);
+
+        if HEAP_CHECKS {
+            unsafe { ptr::write_bytes(new_page, POISON_BYTE, PAGE_SIZE); }
+        }
 
         for offset in (0..PAGE_SIZE).step_by(self.size) {
             let ptr = unsafe { NonNull::new_unchecked(new_page.add(offset)) };
@@ -59,6 +88,17 @@ impl SizeClass {
 
     pub fn alloc(&mut self) -> Result<NonNull<u8>, MemoryExhausted> {
         let ptr = self.alloc_uninitialized()?;
+
+        // everything but the free list link should still be poisoned:
+        if HEAP_CHECKS {
+            let link = mem::size_of::<FreeObject>();
+
+            if !unsafe { is_filled(ptr.as_ptr().add(link), self.size - link, POISON_BYTE) } {
+                panic!("kalloc::SizeClass::alloc: {:?} ({} bytes) was written to after being freed",
+                    ptr, self.size);
+            }
+        }
+
         unsafe { ptr::write_bytes(ptr.as_ptr(), 0, self.size); }
         Ok(ptr)
     }
@@ -74,6 +114,10 @@ impl SizeClass {
     }
 
     pub unsafe fn free(&mut self, ptr: NonNull<u8>) {
+        if HEAP_CHECKS {
+            ptr::write_bytes(ptr.as_ptr(), POISON_BYTE, self.size);
+        }
+
         self.add_free(ptr);
     }
 
@@ -105,18 +149,78 @@ impl Allocator {
         }
     }
 
-    fn class(&mut self, layout: Layout) -> &mut SizeClass {
-        let class = self.classes.iter_mut()
-            .find(|class| class.fits(layout));
+    fn class(&mut self, layout: Layout) -> Option<&mut SizeClass> {
+        self.classes.iter_mut()
+            .find(|class| class.fits(layout))
+    }
+
+    pub fn alloc_layout(&mut self, layout: Layout) -> Result<NonNull<u8>, MemoryExhausted> {
+        if !HEAP_CHECKS {
+            return self.alloc_slot(layout);
+        }
+
+        let (padded, front) = padded(layout)?;
+        let slot = self.alloc_slot(padded)?.as_ptr();
+        let back = self.slot_size(padded) - front - layout.size();
+
+        unsafe {
+            ptr::write_bytes(slot, REDZONE_BYTE, front);
+            ptr::write_bytes(slot.add(front + layout.size()), REDZONE_BYTE, back);
 
-        match class {
-            Some(class) => class,
-            None => panic!("no size class for layout = {:?}", layout)
+            Ok(NonNull::new_unchecked(slot.add(front)))
         }
     }
 
-    pub fn alloc_layout(&mut self, layout: Layout) -> Result<NonNull<u8>, MemoryExhausted> {
-        self.class(layout).alloc()
+    pub unsafe fn free_layout(&mut self, layout: Layout, ptr: NonNull<u8>) {
+        if !HEAP_CHECKS {
+            return self.free_slot(layout, ptr);
+        }
+
+        let (padded, front) = padded(layout)
+            .expect("kalloc::free_layout: layout too big to have been allocated");
+
+        let slot = ptr.as_ptr().sub(front);
+        let back = self.slot_size(padded) - front - layout.size();
+
+        let intact = is_filled(slot, front, REDZONE_BYTE)
+            && is_filled(ptr.as_ptr().add(layout.size()), back, REDZONE_BYTE);
+
+        if !intact {
+            panic!("kalloc::free_layout: redzone around {:?} ({} bytes) overwritten",
+                ptr, layout.size());
+        }
+
+        self.free_slot(padded, NonNull::new_unchecked(slot));
+    }
+
+    // the number of bytes actually set aside for an allocation of the layout:
+    fn slot_size(&mut self, layout: Layout) -> usize {
+        match self.class(layout) {
+            Some(class) => class.size,
+            None => page_count(layout) * PAGE_SIZE,
+        }
+    }
+
+    fn alloc_slot(&mut self, layout: Layout) -> Result<NonNull<u8>, MemoryExhausted> {
+        if let Some(class) = self.class(layout) {
+            let ptr = class.alloc()?;
+            HEAP_USED.fetch_add(class.size, Ordering::SeqCst);
+            return Ok(ptr);
+        }
+
+        // too big for any size class, so it gets pages of its own. nothing
+        // is ever aligned to more than a page:
+        if layout.align() > PAGE_SIZE {
+            return Err(MemoryExhausted);
+        }
+
+        let pages = page_count(layout);
+        let ptr = kvirt::alloc_pages(pages)?;
+
+        HEAP_PAGES.fetch_add(pages, Ordering::SeqCst);
+        HEAP_USED.fetch_add(pages * PAGE_SIZE, Ordering::SeqCst);
+
+        Ok(ptr)
     }
 
     pub fn alloc<T>(&mut self, value: T) -> Result<NonNull<T>, MemoryExhausted> {
@@ -125,11 +229,45 @@ impl Allocator {
         Ok(ptr)
     }
 
-    pub unsafe fn free_layout(&mut self, layout: Layout, ptr: NonNull<u8>) {
-        self.class(layout).free(ptr)
+    unsafe fn free_slot(&mut self, layout: Layout, ptr: NonNull<u8>) {
+        match self.class(layout) {
+            Some(class) => {
+                class.free(ptr);
+                HEAP_USED.fetch_sub(class.size, Ordering::SeqCst);
+            }
+            None => {
+                let pages = page_count(layout);
+                kvirt::free_pages(ptr, pages);
+
+                HEAP_PAGES.fetch_sub(pages, Ordering::SeqCst);
+                HEAP_USED.fetch_sub(pages * PAGE_SIZE, Ordering::SeqCst);
+            }
+        }
     }
 }
 
+fn page_count(layout: Layout) -> usize {
+    (layout.size() + PAGE_SIZE - 1) / PAGE_SIZE
+}
+
+// the layout of an allocation along with its redzones, and the offset of the
+// allocation itself. the front redzone keeps the allocation aligned:
+fn padded(layout: Layout) -> Result<(Layout, usize), MemoryExhausted> {
+    let front = cmp::max(REDZONE, layout.align());
+
+    let size = layout.size().checked_add(front + REDZONE)
+        .ok_or(MemoryExhausted)?;
+
+    let padded = Layout::from_size_align(size, layout.align())
+        .map_err(|_| MemoryExhausted)?;
+
+    Ok((padded, front))
+}
+
+unsafe fn is_filled(ptr: *const u8, len: usize, byte: u8) -> bool {
+    slice::from_raw_parts(ptr, len).iter().all(|b| *b == byte)
+}
+
 pub struct GlobalAlloc;
 
 unsafe impl alloc_collections::glue::GlobalAlloc for GlobalAlloc {
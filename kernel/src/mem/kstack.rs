@@ -0,0 +1,163 @@
+use arrayvec::ArrayVec;
+
+use crate::config::KERNEL_STACK_PAGES;
+use crate::mem::MemoryExhausted;
+use crate::mem::page::{self, PageFlags, PAGE_SIZE};
+use crate::mem::phys;
+use crate::mem::tlb;
+use crate::sync::Mutex;
+use crate::util::EarlyInit;
+
+// kernel stacks live in PML4 entry 510, just below the recursive map. each
+// stack gets a slot made up of an unmapped guard page followed by the stack
+// itself, so that overflowing a stack faults rather than corrupting the stack
+// below it:
+const KSTACK_REGION: u64 = 0xffffff0000000000;
+const KSTACK_REGION_END: u64 = 0xffffff8000000000;
+const STACK_SIZE: u64 = (KERNEL_STACK_PAGES * PAGE_SIZE) as u64;
+const SLOT_SIZE: u64 = STACK_SIZE + PAGE_SIZE as u64;
+
+// freed stacks are kept mapped and reused, up to this many:
+const FREE_STACKS: usize = 32;
+
+struct Allocator {
+    next_slot: u64,
+    free: ArrayVec<[u64; FREE_STACKS]>,
+}
+
+static ALLOCATOR: EarlyInit<Mutex<Allocator>> = EarlyInit::new();
+
+pub fn init() {
+    EarlyInit::set(&ALLOCATOR, Mutex::new(Allocator {
+        next_slot: KSTACK_REGION,
+        free: ArrayVec::new(),
+    }));
+}
+
+#[derive(Debug)]
+pub struct KernelStack {
+    // lowest address of the stack, the guard page sits immediately below:
+    base: u64,
+}
+
+impl KernelStack {
+    pub fn new() -> Result<Self, MemoryExhausted> {
+        let slot = {
+            let mut allocator = ALLOCATOR.lock();
+
+            if let Some(base) = allocator.free.pop() {
+                return Ok(KernelStack { base });
+            }
+
+            let slot = allocator.next_slot;
+            allocator.next_slot += SLOT_SIZE;
+            slot
+        };
+
+        let base = slot + PAGE_SIZE as u64;
+
+        for index in 0..KERNEL_STACK_PAGES {
+            let virt = (base + (index * PAGE_SIZE) as u64) as *mut u8;
+
+            let result = phys::alloc().and_then(|phys| unsafe {
+                page::map(phys, virt, PageFlags::PRESENT | PageFlags::WRITE | PageFlags::NO_EXECUTE)
+                    .map_err(|_| MemoryExhausted)
+            });
+
+            if let Err(e) = result {
+                // give up on this slot entirely, there's plenty of address
+                // space to go around:
+                unsafe { unmap_pages(base, index); }
+                return Err(e);
+            }
+        }
+
+        Ok(KernelStack { base })
+    }
+
+    /// Returns the initial stack pointer for this stack.
+    pub fn top(&self) -> u64 {
+        self.base + STACK_SIZE
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let overflow = ALLOCATOR.lock().free.try_push(self.base);
+
+        if overflow.is_err() {
+            unsafe { unmap_pages(self.base, KERNEL_STACK_PAGES); }
+        }
+    }
+}
+
+unsafe fn unmap_pages(base: u64, count: usize) {
+    let mut batch = tlb::Batch::new();
+
+    for index in 0..count {
+        let virt = (base + (index * PAGE_SIZE) as u64) as *mut u8;
+
+        page::unmap(virt, &mut batch)
+            .expect("kernel stack page not mapped");
+    }
+}
+
+extern "C" {
+    static stackguard: u8;
+    static dfstackguard: u8;
+}
+
+/// Returns true if the address lies in the guard page below some kernel stack,
+/// meaning an access to it was most likely a stack overflow.
+pub fn is_guard_page(address: u64) -> bool {
+    let boot_guards = unsafe {
+        [&stackguard as *const u8 as u64, &dfstackguard as *const u8 as u64]
+    };
+
+    if boot_guards.iter().any(|guard| address >= *guard && address < guard + PAGE_SIZE as u64) {
+        return true;
+    }
+
+    // don't take the allocator lock to check against next_slot, we may be
+    // handling a double fault with it held:
+    address >= KSTACK_REGION
+        && address < KSTACK_REGION_END
+        && (address - KSTACK_REGION) % SLOT_SIZE < PAGE_SIZE as u64
+}
+
+/// Returns true if the CPU is currently running on the kernel stack with the
+/// given top.
+pub fn is_current(stack_top: u64) -> bool {
+    let rsp: u64;
+    unsafe { asm!("movq %rsp, $0" : "=r"(rsp)); }
+
+    rsp <= stack_top && rsp > stack_top - STACK_SIZE
+}
+
+extern "C" {
+    fn call_on_stack(arg: *mut u8, f: extern "C" fn(*mut u8), stack_top: u64);
+}
+
+/// Calls `f` on the kernel stack with the given top, unless we're already
+/// running on it. The stack must not be in use by anything else.
+pub unsafe fn call_on<R>(stack_top: u64, f: impl FnOnce() -> R) -> R {
+    if is_current(stack_top) {
+        return f();
+    }
+
+    extern "C" fn trampoline<F: FnOnce()>(arg: *mut u8) {
+        let f = unsafe { &mut *(arg as *mut Option<F>) };
+        (f.take().expect("call_on trampoline called twice"))();
+    }
+
+    fn get_trampoline<F: FnOnce()>(_: &Option<F>) -> extern "C" fn(*mut u8) {
+        trampoline::<F>
+    }
+
+    let mut result = None;
+    let mut call = Some(|| { result = Some(f()); });
+
+    call_on_stack(&mut call as *mut _ as *mut u8, get_trampoline(&call), stack_top);
+
+    result.expect("call_on_stack did not call f")
+}
@@ -2,7 +2,8 @@ use core::ops::{Deref, DerefMut};
 
 use crate::mem::MemoryExhausted;
 use crate::mem::page::{self, PageFlags, MapError, PAGE_SIZE};
-use crate::mem::phys;
+use crate::mem::phys::{self, Phys, PhysBlock, RawPhys};
+use crate::mem::tlb;
 use crate::sync::Mutex;
 
 use core::ptr::{self, NonNull};
@@ -19,11 +20,51 @@ pub fn alloc_page<T: PageSized>() -> Result<NonNull<T>, MemoryExhausted> {
     ALLOCATOR.alloc().map(NonNull::cast)
 }
 
-#[allow(unused)]
 pub unsafe fn free_page<T: PageSized>(page: NonNull<T>) {
     ALLOCATOR.free(page.cast())
 }
 
+/// Maps `count` fresh zeroed pages at consecutive virtual addresses in the
+/// kernel heap region.
+pub fn alloc_pages(count: usize) -> Result<NonNull<u8>, MemoryExhausted> {
+    ALLOCATOR.alloc_run(count)
+}
+
+/// Unmaps pages returned by `alloc_pages`, freeing the physical pages behind
+/// them. The virtual address range is not reused.
+pub unsafe fn free_pages(ptr: NonNull<u8>, count: usize) {
+    unmap_run(ptr.as_ptr(), count);
+}
+
+/// Maps every page of a block at consecutive virtual addresses in the kernel
+/// heap region. Unmap it with `free_pages`, which drops the references the
+/// mapping holds but not the block's own.
+pub fn map_block(block: &PhysBlock) -> Result<NonNull<u8>, MemoryExhausted> {
+    ALLOCATOR.map_run(block.pages(), PageFlags::empty(), |index| Ok(block.page(index)))
+}
+
+/// Maps `count` pages of physical memory from `base` at consecutive virtual
+/// addresses in the kernel heap region. For memory the physical allocator
+/// doesn't manage, like what the loader leaves behind, which stays put when
+/// unmapped with `free_pages`.
+pub unsafe fn map_phys(base: RawPhys, count: usize) -> Result<NonNull<u8>, MemoryExhausted> {
+    ALLOCATOR.map_run(count, PageFlags::empty(), |index| Ok(Phys::new(RawPhys(base.0 + (index * PAGE_SIZE) as u64))))
+}
+
+/// Like `map_phys`, but uncached, for a device's memory mapped registers.
+pub unsafe fn map_mmio(base: RawPhys, count: usize) -> Result<NonNull<u8>, MemoryExhausted> {
+    ALLOCATOR.map_run(count, PageFlags::CACHE_DISABLED, |index| Ok(Phys::new(RawPhys(base.0 + (index * PAGE_SIZE) as u64))))
+}
+
+unsafe fn unmap_run(ptr: *mut u8, count: usize) {
+    let mut batch = tlb::Batch::new();
+
+    for index in 0..count {
+        page::unmap(ptr.add(index * PAGE_SIZE), &mut batch)
+            .expect("page::unmap in kvirt::unmap_run");
+    }
+}
+
 struct PageAllocator {
     inner: Mutex<PageAllocatorInner>,
 }
@@ -73,16 +114,54 @@ impl PageAllocator {
                 ptr
             };
 
-            match page::map(phys, ptr, PageFlags::PRESENT | PageFlags::WRITE) {
+            match page::map(phys, ptr, PageFlags::PRESENT | PageFlags::WRITE | PageFlags::NO_EXECUTE) {
                 Ok(()) => {}
                 Err(MapError::CannotAllocatePageTable) => return Err(MemoryExhausted),
                 Err(MapError::AlreadyMapped) => panic!("MapError::AlreadyMapped in PageAllocator::allocate"),
+                Err(MapError::WrongHalf) => panic!("MapError::WrongHalf in PageAllocator::allocate"),
             }
 
             Ok(NonNull::new_unchecked(ptr))
         }
     }
 
+    pub fn alloc_run(&self, count: usize) -> Result<NonNull<u8>, MemoryExhausted> {
+        self.map_run(count, PageFlags::empty(), |_| phys::alloc())
+    }
+
+    // maps the pages returned by f at a fresh range of address space, with
+    // any flags beyond the usual ones:
+    fn map_run(&self, count: usize, flags: PageFlags, mut f: impl FnMut(usize) -> Result<phys::Phys, MemoryExhausted>)
+        -> Result<NonNull<u8>, MemoryExhausted>
+    {
+        let ptr = {
+            let mut inner = self.inner.lock();
+            let ptr = inner.ptr;
+            inner.ptr = unsafe { inner.ptr.add(count * PAGE_SIZE) };
+            ptr
+        };
+
+        for index in 0..count {
+            let result = f(index).and_then(|phys| unsafe {
+                page::map(phys, ptr.add(index * PAGE_SIZE), PageFlags::PRESENT | PageFlags::WRITE | PageFlags::NO_EXECUTE | flags)
+                    .map_err(|e| match e {
+                        MapError::CannotAllocatePageTable => MemoryExhausted,
+                        MapError::AlreadyMapped => panic!("MapError::AlreadyMapped in PageAllocator::alloc_run"),
+                        MapError::WrongHalf => panic!("MapError::WrongHalf in PageAllocator::alloc_run"),
+                    })
+            });
+
+            if let Err(e) = result {
+                // give up on this range of address space entirely, there's
+                // plenty to go around:
+                unsafe { unmap_run(ptr, index); }
+                return Err(e);
+            }
+        }
+
+        Ok(unsafe { NonNull::new_unchecked(ptr) })
+    }
+
     pub unsafe fn free(&self, page: NonNull<u8>) {
         let mut inner = self.inner.lock();
 
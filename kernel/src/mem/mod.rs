@@ -1,23 +1,75 @@
+use core::fmt::{self, Display};
 use core::ptr;
 
 use interface::SysError;
 
+use crate::critical;
+
+pub mod dma;
 pub mod fault;
 pub mod kalloc;
+pub mod kstack;
 pub mod kvirt;
 pub mod page;
 pub mod phys;
+pub mod shm;
+pub mod tlb;
 pub mod user;
+pub mod vma;
 
 #[derive(Debug)]
 pub struct MemoryExhausted;
 
 impl From<MemoryExhausted> for SysError {
     fn from(_: MemoryExhausted) -> SysError {
+        // this is where running out of memory stops being the kernel's
+        // problem, so make a note of what it was up against:
+        crate::println!("mem: out of memory, {}", stats());
+
         SysError::MemoryExhausted
     }
 }
 
+/// A snapshot of memory usage, see `stats`.
+#[derive(Debug, Clone, Copy)]
+pub struct MemStats {
+    /// Physical pages managed by the page allocator.
+    pub phys_total: usize,
+    /// Physical pages free for allocation.
+    pub phys_free: usize,
+    /// Pages taken by the kernel heap.
+    pub heap_pages: usize,
+    /// Bytes of the kernel heap allocated.
+    pub heap_used: usize,
+    /// Pages mapped in the user half of the current page context.
+    pub resident: usize,
+}
+
+impl Display for MemStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "phys: {}/{} pages free, heap: {} bytes used of {} pages, resident: {} pages",
+            self.phys_free,
+            self.phys_total,
+            self.heap_used,
+            self.heap_pages,
+            self.resident)
+    }
+}
+
+/// Takes a snapshot of memory usage. No locks are taken, so it's safe to call
+/// from wherever memory ran out.
+pub fn stats() -> MemStats {
+    let crit = critical::begin();
+
+    MemStats {
+        phys_total: phys::total_pages(),
+        phys_free: phys::free_pages(),
+        heap_pages: kalloc::heap_pages(),
+        heap_used: kalloc::heap_used(),
+        resident: page::user_resident_pages(&crit),
+    }
+}
+
 pub unsafe fn zero(ptr: *mut u8, bytes: usize) {
     ptr::write_bytes(ptr, 0, bytes);
 }
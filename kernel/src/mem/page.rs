@@ -1,20 +1,60 @@
+use core::mem;
+use core::ops::Range;
 use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
+use alloc_collections::btree_map::BTreeMap;
 use bitflags::bitflags;
 use x86_64::registers::control::Cr3;
 
+use crate::cpu;
 use crate::critical::{self, Critical};
+use crate::mem::kalloc::GlobalAlloc;
 use crate::mem::MemoryExhausted;
-use crate::mem::phys::{self, Phys, RawPhys};
+use crate::mem::phys::{self, Phys, PhysBlock, RawPhys};
+use crate::mem::tlb;
+use crate::mem::vma::{Vma, VmaError, VmaList};
+use crate::sync::{Arc, Mutex, MutexGuard};
 
 pub const PAGE_SIZE: usize = 0x1000;
 
+/// Size of a huge page, mapped by a single PML2 entry.
+pub const HUGE_PAGE_SIZE: usize = 0x200000;
+
+/// Number of pages making up a huge page.
+pub const HUGE_PAGE_PAGES: usize = HUGE_PAGE_SIZE / PAGE_SIZE;
+
+/// User mappings live in the lower half of the address space, below this
+/// address.
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+
+/// The kernel lives in the higher half of the address space, at and above this
+/// address. Must match KERNEL_BASE in consts.asm.
+pub const KERNEL_BASE: u64 = 0xffff_8000_0000_0000;
+
+/// Returns true if the address lies in the user half of the address space.
+pub fn is_user_addr(addr: u64) -> bool {
+    addr < USER_END
+}
+
+/// Returns true if the address lies in the kernel half of the address space.
+pub fn is_kernel_addr(addr: u64) -> bool {
+    addr >= KERNEL_BASE
+}
+
+// the bits of a page table entry that hold the physical address. the rest are
+// flags, including the no execute bit at the very top:
+const ENTRY_ADDRESS: u64 = 0x000f_ffff_ffff_f000;
+
+// whether the CPU has the no execute bit turned on, see `enable_no_execute`:
+static NO_EXECUTE: AtomicBool = AtomicBool::new(false);
+
 #[repr(transparent)]
 pub struct PmlEntry(pub u64);
 
 impl PmlEntry {
     fn raw_phys(&self) -> Option<RawPhys> {
-        let raw = self.0 & !0xfff;
+        let raw = self.0 & ENTRY_ADDRESS;
 
         if raw != 0 {
             Some(RawPhys(raw))
@@ -24,11 +64,11 @@ impl PmlEntry {
     }
 
     pub fn flags(&self) -> PageFlags {
-        PageFlags::from_bits(self.0 & 0xfff).expect("PageFlags::from_bits in PmlEntry::flags")
+        PageFlags::from_bits(self.0 & !ENTRY_ADDRESS).expect("PageFlags::from_bits in PmlEntry::flags")
     }
 
     pub fn set_flags(&mut self, flags: PageFlags) {
-        let new_entry = (self.0 & !0xfff) | flags.bits();
+        let new_entry = (self.0 & ENTRY_ADDRESS) | entry_bits(flags);
         self.0 = new_entry;
     }
 }
@@ -43,12 +83,98 @@ bitflags! {
         const ACCESSED          = 0x020;
         const DIRTY             = 0x040;
         const GLOBAL            = 0x080;
+        // the same bit in a PML2 entry maps a huge page rather than pointing
+        // to a PML1 table:
+        const HUGE              = 0x080;
+        // available to software. marks a page shared by fork that must be
+        // copied before it can be written to:
+        const COW               = 0x200;
+        // available to software. marks a page of a shared memory object,
+        // which stays shared and writable across fork:
+        const SHARED            = 0x400;
+        // the page can't be executed from. left out of entries on CPUs
+        // without NX, where the bit is reserved:
+        const NO_EXECUTE        = 1 << 63;
+    }
+}
+
+// the bits of an entry with the given flags:
+fn entry_bits(flags: PageFlags) -> u64 {
+    if NO_EXECUTE.load(Ordering::Relaxed) {
+        flags.bits()
+    } else {
+        (flags - PageFlags::NO_EXECUTE).bits()
+    }
+}
+
+/// Lets entries have the no execute bit, once EFER.NXE is on. Everything
+/// but the kernel's code in the kernel image, which start.asm maps before
+/// the CPU's features are known, is made no execute.
+// Safety: EFER.NXE must be on, on every CPU using the kernel's page tables
+pub unsafe fn enable_no_execute() {
+    extern "C" {
+        static _text_end: u8;
+        static _bss_end: u8;
+    }
+
+    NO_EXECUTE.store(true, Ordering::SeqCst);
+
+    // the code's last page is shared with nothing else, .rodata starts on
+    // the next one:
+    let start = (&_text_end as *const u8 as u64 + PAGE_SIZE as u64 - 1) & !(PAGE_SIZE as u64 - 1);
+    let end = &_bss_end as *const u8 as u64;
+
+    for virt in (start..end).step_by(PAGE_SIZE) {
+        let entry = pml1_entry(CURRENT_PML, virt);
+
+        // the stack guard pages aren't mapped:
+        if (*entry).0 != 0 {
+            let flags = (*entry).flags();
+            (*entry).set_flags(flags | PageFlags::NO_EXECUTE);
+            invlpg(virt as *mut u8);
+        }
+    }
+}
+
+// a reference to the PML4 of an address space, counted like any other
+// physical page. whoever drops the last one, be it a page context or cr3,
+// takes the user half of the address space down with it:
+#[derive(Debug)]
+struct Pml4(RawPhys);
+
+impl Pml4 {
+    unsafe fn new(raw_phys: RawPhys) -> Self {
+        Pml4(Phys::new(raw_phys).into_raw())
+    }
+
+    fn into_raw(self) -> RawPhys {
+        let raw_phys = self.0;
+        mem::forget(self);
+        raw_phys
+    }
+}
+
+impl Clone for Pml4 {
+    fn clone(&self) -> Self {
+        unsafe { Pml4::new(self.0) }
+    }
+}
+
+impl Drop for Pml4 {
+    fn drop(&mut self) {
+        unsafe {
+            Phys::from_raw(self.0).release_with(|pml4| release_user_half(pml4));
+        }
     }
 }
 
 #[derive(Clone, Debug)]
 pub struct PageCtx {
-    pml4: Phys,
+    pml4: Pml4,
+    // shared between clones, they all refer to the same address space:
+    vmas: Arc<Mutex<VmaList>>,
+    // likewise, see lock_tables:
+    tables: Arc<Mutex<()>>,
 }
 
 impl PageCtx {
@@ -66,11 +192,178 @@ impl PageCtx {
             pml4[511] = PmlEntry(pml4_raw.0 | (PageFlags::PRESENT /*| PageFlags::WRITE*/).bits());
         }
 
-        let pml4 = unsafe { Phys::from_raw(pml4_raw) };
-        Ok(PageCtx { pml4 })
+        let pml4 = Pml4(pml4_raw);
+        let vmas = Arc::new(Mutex::new(VmaList::new()))?;
+        let tables = Arc::new(Mutex::new(()))?;
+
+        Ok(PageCtx { pml4, vmas, tables })
+    }
+
+    /// Returns the physical address of the PML4, as loaded into cr3.
+    pub fn pml4_phys(&self) -> RawPhys {
+        self.pml4.0
+    }
+
+    pub fn vmas(&self) -> &Mutex<VmaList> {
+        &self.vmas
+    }
+
+    /// Locks the user half of this page context's page tables against the
+    /// other threads of the process, which may be running on other CPUs.
+    /// Anything that looks at user page table entries and changes them based
+    /// on what it saw, like the page fault handler, holds this throughout.
+    /// Must not be called with it held already.
+    pub fn lock_tables(&self) -> MutexGuard<()> {
+        self.tables.lock()
+    }
+
+    /// Registers a VMA, whose pages are mapped in on first access.
+    pub fn add_vma(&self, vma: Vma) -> Result<(), VmaError> {
+        self.vmas.lock().insert(vma)
+    }
+
+    /// Maps each `(virt, phys, flags)` page into this page context, which
+    /// need not be the current one.
+    pub fn map_pages<'a>(&self, pages: impl Iterator<Item = (u64, &'a Phys, PageFlags)>)
+        -> Result<(), MapError>
+    {
+        critical::section(|| unsafe {
+            let current = swap_pml4(self.pml4.clone());
+
+            let result = pages
+                .map(|(virt, phys, flags)| map(phys.clone(), virt as *mut u8, flags))
+                .collect::<Result<(), MapError>>();
+
+            swap_pml4(current);
+
+            result
+        })
+    }
+
+    /// Identity maps a page of low physical memory for the kernel, for code
+    /// that has to run before paging is fully set up, like the AP trampoline.
+    /// This is the only way to get a kernel page into the user half, so a page
+    /// context mapping one must never run user code.
+    pub fn map_low_identity(&self, phys: &Phys) -> Result<(), MapError> {
+        let virt = phys.raw().0;
+
+        critical::section(|| unsafe {
+            let current = swap_pml4(self.pml4.clone());
+            let result = map_unchecked(phys.clone(), virt as *mut u8, PageFlags::PRESENT | PageFlags::WRITE);
+            swap_pml4(current);
+
+            result
+        })
+    }
+
+    /// Creates a new page context sharing every user page of this one. Pages
+    /// that were writable become read-only and copy on write in both page
+    /// contexts, and whichever writes to such a page first gets its own copy
+    /// of it in the page fault handler. The child gets a copy of the VMAs, so
+    /// untouched pages are faulted in separately by each. Kernel mappings are
+    /// shared as usual.
+    pub fn clone_cow(&self) -> Result<PageCtx, MemoryExhausted> {
+        let child = PageCtx::new()?;
+        *child.vmas.lock() = self.vmas.lock().try_clone()?;
+
+        // collect every user page first, then map them all into the child's
+        // page context in one go:
+        let mut shared = BTreeMap::<u64, (Phys, PageFlags), GlobalAlloc>::new();
+
+        // the child isn't running anywhere yet, but this page context may be:
+        let _tables = self.lock_tables();
+        let crit = critical::begin();
+
+        unsafe {
+            let current = swap_pml4(self.pml4.clone());
+
+            let result = try_each_user_page(&crit, |virt, entry| {
+                let raw_phys = entry.raw_phys()
+                    .expect("raw_phys of mapped page");
+
+                // memory mapped hardware isn't reference counted, and is
+                // always shared as is, like shared memory:
+                let private = phys::refs(raw_phys).is_some()
+                    && !entry.flags().contains(PageFlags::SHARED);
+
+                if entry.flags().contains(PageFlags::WRITE) && private {
+                    entry.set_flags((entry.flags() - PageFlags::WRITE) | PageFlags::COW);
+                }
+
+                let flags = entry.flags() - (PageFlags::ACCESSED | PageFlags::DIRTY);
+
+                shared.insert(virt, (Phys::new(raw_phys), flags))
+                    .map_err(|_| MemoryExhausted)?;
+
+                Ok(())
+            });
+
+            swap_pml4(current);
+
+            // other CPUs running this address space may still have the
+            // writable entries cached. if we failed part way, the pages
+            // already marked copy on write are simply made writable again on
+            // their next write:
+            drop(tlb::Batch::full());
+
+            result?;
+        }
+
+        child.map_pages(shared.iter().map(|(virt, (phys, flags))| (*virt, phys, *flags)))
+            .map_err(|_| MemoryExhausted)?;
+
+        Ok(child)
     }
 }
 
+/// Gives the current page context a private, writable copy of the copy on
+/// write page containing `virt`. Returns whether the page was copy on write.
+/// The caller holds the page context's tables, see `PageCtx::lock_tables`.
+pub unsafe fn copy_on_write(virt: *mut u8, crit: &Critical) -> Result<bool, MemoryExhausted> {
+    let virt = (virt as u64 & !(PAGE_SIZE as u64 - 1)) as *mut u8;
+
+    let pml1_ent = match checked_pml1_entry(CURRENT_PML, virt, crit) {
+        Ok(pml1_ent) => pml1_ent,
+        Err(NotMapped) => return Ok(false),
+    };
+
+    let flags = (*pml1_ent).flags();
+
+    if !flags.contains(PageFlags::COW) {
+        return Ok(false);
+    }
+
+    let raw_phys = (*pml1_ent).raw_phys()
+        .expect("raw_phys of copy on write page");
+
+    let flags = (flags - PageFlags::COW) | PageFlags::WRITE;
+
+    // other CPUs may have the read-only entry cached, and must let go of it
+    // before the shared page can be:
+    let mut batch = tlb::Batch::new();
+    batch.add(virt);
+
+    if phys::refs(raw_phys) == Some(1) {
+        // everyone else sharing the page already has their own copy:
+        (*pml1_ent).set_flags(flags);
+    } else {
+        let copy = phys::alloc()?;
+
+        {
+            let mapped = temp_map::<u8>(copy.raw(), crit);
+            // the page is a user one, read through its user address:
+            cpu::with_user_access(|| ptr::copy_nonoverlapping(virt as *const u8, mapped.ptr(), PAGE_SIZE));
+        }
+
+        *pml1_ent = PmlEntry(copy.into_raw().0 | entry_bits(flags));
+
+        // drop our reference to the shared page:
+        batch.release(Phys::from_raw(raw_phys));
+    }
+
+    Ok(true)
+}
+
 pub unsafe fn init_kernel_pml4_entries(_crit: &Critical) {
     let kernel_start = 0xfffffffffffff800 as *mut PmlEntry;
 
@@ -85,23 +378,78 @@ pub unsafe fn init_kernel_pml4_entries(_crit: &Critical) {
     }
 }
 
-pub fn current_ctx() -> PageCtx {
+/// Returns a page context for the page tables currently loaded, with no VMAs
+/// of its own.
+pub fn current_ctx() -> Result<PageCtx, MemoryExhausted> {
     let cr3;
     unsafe { asm!("movq %cr3, $0" : "=r"(cr3)); }
 
-    let pml4 = unsafe { Phys::new(cr3) };
-    PageCtx { pml4 }
+    let pml4 = unsafe { Pml4::new(cr3) };
+    let vmas = Arc::new(Mutex::new(VmaList::new()))?;
+    let tables = Arc::new(Mutex::new(()))?;
+
+    Ok(PageCtx { pml4, vmas, tables })
 }
 
 pub unsafe fn set_ctx(ctx: PageCtx) {
+    // ensure we decrement the ref count of the old previous cr3
+    drop(swap_pml4(ctx.pml4));
+}
+
+// loads the given page tables into cr3, returning the previous ones:
+unsafe fn swap_pml4(pml4: Pml4) -> Pml4 {
     let old_cr3;
     asm!("movq %cr3, $0" : "=r"(old_cr3));
 
-    let new_cr3 = ctx.pml4.into_raw();
+    let new_cr3 = pml4.into_raw();
     asm!("movq $0, %cr3" :: "r"(new_cr3));
 
-    // ensure we decrement the ref count of the old previous cr3
-    Phys::from_raw(old_cr3);
+    Pml4(old_cr3)
+}
+
+// releases every page mapped in the user half of an address space that is no
+// longer loaded anywhere, along with the page tables mapping them. the PML4
+// itself is left to the caller:
+unsafe fn release_user_half(pml4: RawPhys) {
+    let crit = critical::begin();
+    release_table(pml4, 4, 0..256, &crit);
+}
+
+// the address space isn't loaded, so its tables can't be reached through the
+// recursive map. the temp page can only map one table at a time, so each entry
+// is read out before descending into the table it points to:
+unsafe fn release_table(table: RawPhys, level: usize, entries: Range<usize>, crit: &Critical) {
+    for index in entries {
+        let entry = {
+            let mapped = temp_map::<[PmlEntry; 512]>(table, crit);
+            PmlEntry((*mapped.ptr())[index].0)
+        };
+
+        let raw_phys = match entry.raw_phys() {
+            Some(raw_phys) => raw_phys,
+            None => continue,
+        };
+
+        if level == 2 && entry.flags().contains(PageFlags::HUGE) {
+            // a huge page holds a reference on each of its pages:
+            release_huge(raw_phys);
+            continue;
+        }
+
+        if level > 1 {
+            release_table(raw_phys, level - 1, 0..512, crit);
+        }
+
+        // drop the reference the entry held. a page shared copy on write is
+        // just left to the other sharers:
+        Phys::from_raw(raw_phys);
+    }
+}
+
+unsafe fn release_huge(base: RawPhys) {
+    for index in 0..HUGE_PAGE_PAGES {
+        Phys::from_raw(RawPhys(base.0 + (index * PAGE_SIZE) as u64));
+    }
 }
 
 const CURRENT_PML: u64 = 0xffffff8000000000;
@@ -126,6 +474,53 @@ unsafe fn pml1_entry(base: u64, virt: u64) -> *mut PmlEntry {
     base.add(((virt >> 12) & 0xfffffffff) as usize)
 }
 
+/// Returns the number of pages mapped in the user half of the current page
+/// context, counting each page of a huge page.
+pub fn user_resident_pages(_crit: &Critical) -> usize {
+    let mut count = 0;
+
+    unsafe {
+        for pml4_idx in 0..256 {
+            let base = 0xfffffffffffff000 as *mut PmlEntry;
+
+            if (*base.add(pml4_idx)).raw_phys().is_none() {
+                continue;
+            }
+
+            for pml3_idx in 0..512 {
+                let base = 0xffffffffffe00000 as *mut PmlEntry;
+
+                if (*base.add((pml4_idx << 9) | pml3_idx)).raw_phys().is_none() {
+                    continue;
+                }
+
+                for pml2_idx in 0..512 {
+                    let base = 0xffffffffc0000000 as *mut PmlEntry;
+                    let entry = &*base.add((pml4_idx << 18) | (pml3_idx << 9) | pml2_idx);
+
+                    if entry.raw_phys().is_none() {
+                        continue;
+                    }
+
+                    if entry.flags().contains(PageFlags::HUGE) {
+                        count += HUGE_PAGE_PAGES;
+                        continue;
+                    }
+
+                    let base = 0xffffff8000000000 as *mut PmlEntry;
+                    let first = (pml4_idx << 27) | (pml3_idx << 18) | (pml2_idx << 9);
+
+                    count += (0..512)
+                        .filter(|pml1_idx| (*base.add(first | pml1_idx)).raw_phys().is_some())
+                        .count();
+                }
+            }
+        }
+    }
+
+    count
+}
+
 /// recursively iterates PML4 yielding raw physical pages
 pub unsafe fn each_phys(mut f: impl FnMut(RawPhys)) {
     f(RawPhys(Cr3::read().0.start_address().as_u64()));
@@ -150,6 +545,14 @@ pub unsafe fn each_phys(mut f: impl FnMut(RawPhys)) {
                         let entry = &*base.add((pml4_idx << 18) | (pml3_idx << 9) | pml2_idx);
 
                         if let Some(phys) = entry.raw_phys() {
+                            if entry.flags().contains(PageFlags::HUGE) {
+                                for index in 0..HUGE_PAGE_PAGES {
+                                    f(RawPhys(phys.0 + (index * PAGE_SIZE) as u64));
+                                }
+
+                                continue;
+                            }
+
                             f(phys);
 
                             for pml1_idx in 0..512 {
@@ -168,6 +571,62 @@ pub unsafe fn each_phys(mut f: impl FnMut(RawPhys)) {
     }
 }
 
+/// Calls `f` with the virtual address and page table entry of every page
+/// mapped in the user half of the current page context, stopping at the first
+/// error. Huge pages are split up along the way, so `f` only ever sees 4 KiB
+/// pages.
+pub unsafe fn try_each_user_page<E: From<MemoryExhausted>>(
+    crit: &Critical,
+    mut f: impl FnMut(u64, &mut PmlEntry) -> Result<(), E>,
+) -> Result<(), E> {
+    for pml4_idx in 0..256 {
+        let base = 0xfffffffffffff000 as *mut PmlEntry;
+        let entry = &*base.add(pml4_idx);
+
+        if entry.raw_phys().is_none() {
+            continue;
+        }
+
+        for pml3_idx in 0..512 {
+            let base = 0xffffffffffe00000 as *mut PmlEntry;
+            let entry = &*base.add((pml4_idx << 9) | pml3_idx);
+
+            if entry.raw_phys().is_none() {
+                continue;
+            }
+
+            for pml2_idx in 0..512 {
+                let base = 0xffffffffc0000000 as *mut PmlEntry;
+                let entry = base.add((pml4_idx << 18) | (pml3_idx << 9) | pml2_idx);
+
+                if (*entry).raw_phys().is_none() {
+                    continue;
+                }
+
+                if (*entry).flags().contains(PageFlags::HUGE) {
+                    let virt = ((pml4_idx << 39) | (pml3_idx << 30) | (pml2_idx << 21)) as u64;
+                    split_huge_entry(entry, virt, crit)?;
+                }
+
+                for pml1_idx in 0..512 {
+                    let base = 0xffffff8000000000 as *mut PmlEntry;
+                    let entry = &mut *base.add((pml4_idx << 27) | (pml3_idx << 18) | (pml2_idx << 9) | pml1_idx);
+
+                    if entry.raw_phys().is_none() {
+                        continue;
+                    }
+
+                    let virt = ((pml4_idx << 39) | (pml3_idx << 30) | (pml2_idx << 21) | (pml1_idx << 12)) as u64;
+
+                    f(virt, entry)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
 extern "C" {
     static mut temp_page: u8;
 }
@@ -209,7 +668,7 @@ pub unsafe fn temp_map<'a, T>(phys: RawPhys, critical: &'a Critical) -> TempMap<
         panic!("temp page already mapped");
     }
 
-    *entry = PmlEntry(phys.0 | (PageFlags::PRESENT | PageFlags::WRITE).bits());
+    *entry = PmlEntry(phys.0 | entry_bits(PageFlags::PRESENT | PageFlags::WRITE | PageFlags::NO_EXECUTE));
     invlpg(virt);
 
     TempMap {
@@ -222,6 +681,8 @@ pub unsafe fn temp_map<'a, T>(phys: RawPhys, critical: &'a Critical) -> TempMap<
 pub enum MapError {
     AlreadyMapped,
     CannotAllocatePageTable,
+    // a user page in the kernel half or a kernel page in the user half:
+    WrongHalf,
 }
 
 pub fn is_mapped(virt: *const u8) -> bool {
@@ -248,54 +709,209 @@ pub fn is_mapped(virt: *const u8) -> bool {
                 return false;
             }
 
+            if (*pml2_ent).flags().contains(PageFlags::HUGE) {
+                return true;
+            }
+
             (*pml1_ent).0 != 0
         }
     })
 }
 
+fn check_half(virt: *mut u8, flags: PageFlags) -> Result<(), MapError> {
+    let right_half = if flags.contains(PageFlags::USER) {
+        is_user_addr(virt as u64)
+    } else {
+        is_kernel_addr(virt as u64)
+    };
+
+    if right_half {
+        Ok(())
+    } else {
+        Err(MapError::WrongHalf)
+    }
+}
+
 pub unsafe fn map(phys: Phys, virt: *mut u8, flags: PageFlags) -> Result<(), MapError> {
+    check_half(virt, flags)?;
+    map_unchecked(phys, virt, flags)
+}
+
+// points an empty page table entry at a fresh table. next_ent is any entry in
+// that table, whose stale translation needs flushing:
+unsafe fn alloc_table(ent: *mut PmlEntry, next_ent: *mut PmlEntry) -> Result<(), MapError> {
+    // the kernel half is shared by every CPU, and nothing stops two of them
+    // mapping pages that need the same new table. whoever installs theirs
+    // first wins, and the other's is freed again:
+    let ent = &*(ent as *const AtomicU64);
+
+    if ent.load(Ordering::SeqCst) == 0 {
+        let tab = phys::alloc().map_err(|_: MemoryExhausted|
+            MapError::CannotAllocatePageTable)?;
+
+        let new = tab.raw().0 | (PageFlags::PRESENT | PageFlags::WRITE | PageFlags::USER).bits();
+
+        if ent.compare_and_swap(0, new, Ordering::SeqCst) == 0 {
+            tab.into_raw();
+        }
+
+        invlpg(next_ent as *mut u8);
+    }
+
+    Ok(())
+}
+
+/// Maps a huge page made up of the 2 MiB aligned block of pages `block` at the
+/// 2 MiB aligned address `virt`. Each page in the block gains a reference, as
+/// if it was mapped on its own.
+pub unsafe fn map_huge(block: &PhysBlock, virt: *mut u8, flags: PageFlags) -> Result<(), MapError> {
+    assert!(block.pages() == HUGE_PAGE_PAGES, "page::map_huge called with a block that isn't huge page sized");
+    assert!(block.base().0 % HUGE_PAGE_SIZE as u64 == 0, "page::map_huge called with a misaligned block");
+    assert!(virt as u64 % HUGE_PAGE_SIZE as u64 == 0, "page::map_huge called with a misaligned address");
+
+    check_half(virt, flags)?;
+
     critical::section(|| {
         let virt = virt as u64;
 
         let pml4_ent = pml4_entry(CURRENT_PML, virt);
         let pml3_ent = pml3_entry(CURRENT_PML, virt);
         let pml2_ent = pml2_entry(CURRENT_PML, virt);
-        let pml1_ent = pml1_entry(CURRENT_PML, virt);
 
-        // ensure all pml tables exist:
+        alloc_table(pml4_ent, pml3_ent)?;
+        alloc_table(pml3_ent, pml2_ent)?;
 
-        if (*pml4_ent).0 == 0 {
-            // need to allocate new page table for entry:
-            let pml3_tab = phys::alloc().map_err(|_: MemoryExhausted|
-                MapError::CannotAllocatePageTable)?;
+        // even an empty PML1 table gets in the way:
+        if (*pml2_ent).0 != 0 {
+            return Err(MapError::AlreadyMapped);
+        }
 
-            *pml4_ent = PmlEntry(pml3_tab.into_raw().0 | (PageFlags::PRESENT | PageFlags::WRITE | PageFlags::USER).bits());
-            invlpg(pml3_ent as *mut u8);
+        for index in 0..HUGE_PAGE_PAGES {
+            block.page(index).into_raw();
         }
 
-        if (*pml3_ent).0 == 0 {
-            // need to allocate new page table for entry:
-            let pml2_tab = phys::alloc().map_err(|_: MemoryExhausted|
-                MapError::CannotAllocatePageTable)?;
+        *pml2_ent = PmlEntry(block.base().0 | entry_bits(flags | PageFlags::HUGE));
+        invlpg(virt as *mut u8);
+
+        Ok(())
+    })
+}
+
+/// Returns true if nothing is mapped in the 2 MiB aligned range at `virt`, not
+/// even an empty PML1 table, so a huge page could be mapped there.
+pub fn can_map_huge(virt: *const u8, _crit: &Critical) -> bool {
+    let virt = virt as u64;
+
+    unsafe {
+        (*pml4_entry(CURRENT_PML, virt)).0 == 0
+            || (*pml3_entry(CURRENT_PML, virt)).0 == 0
+            || (*pml2_entry(CURRENT_PML, virt)).0 == 0
+    }
+}
+
+/// Returns true if the address is mapped by a huge page.
+pub fn is_huge(virt: *const u8, _crit: &Critical) -> bool {
+    let virt = virt as u64;
 
-            *pml3_ent = PmlEntry(pml2_tab.into_raw().0 | (PageFlags::PRESENT | PageFlags::WRITE | PageFlags::USER).bits());
-            invlpg(pml2_ent as *mut u8);
+    unsafe {
+        (*pml4_entry(CURRENT_PML, virt)).0 != 0
+            && (*pml3_entry(CURRENT_PML, virt)).0 != 0
+            && (*pml2_entry(CURRENT_PML, virt)).flags().contains(PageFlags::HUGE)
+    }
+}
+
+/// Splits the huge page containing `virt`, if there is one, into 4 KiB pages
+/// with the same flags. Pages inside a huge page can't be unmapped or have
+/// their flags changed individually until it's been split.
+pub fn split_huge(virt: *const u8, crit: &Critical) -> Result<(), MemoryExhausted> {
+    if !is_huge(virt, crit) {
+        return Ok(());
+    }
+
+    unsafe { split_huge_entry(pml2_entry(CURRENT_PML, virt as u64), virt as u64, crit) }
+}
+
+// replaces the huge page mapped by a PML2 entry with a PML1 table mapping the
+// same pages. the references the huge page held pass to the new entries:
+unsafe fn split_huge_entry(pml2_ent: *mut PmlEntry, virt: u64, crit: &Critical)
+    -> Result<(), MemoryExhausted>
+{
+    let base = (*pml2_ent).raw_phys()
+        .expect("raw_phys of huge page");
+
+    let flags = (*pml2_ent).flags() - PageFlags::HUGE;
+
+    let table = phys::alloc()?;
+
+    {
+        // the table isn't reachable through the recursive map until it's
+        // installed, so fill it in through the temp page:
+        let mapped = temp_map::<[PmlEntry; 512]>(table.raw(), crit);
+
+        for (index, entry) in (*mapped.ptr()).iter_mut().enumerate() {
+            *entry = PmlEntry((base.0 + (index * PAGE_SIZE) as u64) | entry_bits(flags));
         }
+    }
 
-        if (*pml2_ent).0 == 0 {
-            // need to allocate new page table for entry:
-            let pml1_tab = phys::alloc().map_err(|_: MemoryExhausted|
-                MapError::CannotAllocatePageTable)?;
+    *pml2_ent = PmlEntry(table.into_raw().0 | (PageFlags::PRESENT | PageFlags::WRITE | PageFlags::USER).bits());
 
-            *pml2_ent = PmlEntry(pml1_tab.into_raw().0 | (PageFlags::PRESENT | PageFlags::WRITE | PageFlags::USER).bits());
-            invlpg(pml1_ent as *mut u8);
+    // the huge page and its replacement translate every address the same, so
+    // it doesn't matter if other CPUs hang on to the huge one until its pages
+    // next change:
+    let virt = virt & !(HUGE_PAGE_SIZE as u64 - 1);
+    invlpg(virt as *mut u8);
+    invlpg(pml1_entry(CURRENT_PML, virt) as *mut u8);
+
+    Ok(())
+}
+
+/// Unmaps the whole huge page at the 2 MiB aligned address `virt`, dropping
+/// its references to each of its pages. The batch is flushed before they're
+/// dropped.
+pub unsafe fn unmap_huge(virt: *mut u8, batch: &mut tlb::Batch) -> Result<(), NotMapped> {
+    let crit = critical::begin();
+
+    if !is_huge(virt, &crit) {
+        return Err(NotMapped);
+    }
+
+    let pml2_ent = pml2_entry(CURRENT_PML, virt as u64);
+    let base = (*pml2_ent).raw_phys().expect("raw_phys of huge page");
+
+    *pml2_ent = PmlEntry(0);
+
+    // too many pages to hand to the batch one by one:
+    batch.add(virt);
+    batch.flush();
+    release_huge(base);
+
+    Ok(())
+}
+
+unsafe fn map_unchecked(phys: Phys, virt: *mut u8, flags: PageFlags) -> Result<(), MapError> {
+    critical::section(|| {
+        let virt = virt as u64;
+
+        let pml4_ent = pml4_entry(CURRENT_PML, virt);
+        let pml3_ent = pml3_entry(CURRENT_PML, virt);
+        let pml2_ent = pml2_entry(CURRENT_PML, virt);
+        let pml1_ent = pml1_entry(CURRENT_PML, virt);
+
+        // ensure all pml tables exist:
+
+        alloc_table(pml4_ent, pml3_ent)?;
+        alloc_table(pml3_ent, pml2_ent)?;
+        alloc_table(pml2_ent, pml1_ent)?;
+
+        if (*pml2_ent).flags().contains(PageFlags::HUGE) {
+            return Err(MapError::AlreadyMapped);
         }
 
         if (*pml1_ent).0 != 0 {
             return Err(MapError::AlreadyMapped);
         }
 
-        *pml1_ent = PmlEntry(phys.into_raw().0 | flags.bits());
+        *pml1_ent = PmlEntry(phys.into_raw().0 | entry_bits(flags));
         invlpg(virt as *mut u8);
 
         Ok(())
@@ -327,6 +943,11 @@ fn checked_pml1_entry(pml_base: u64, virt: *mut u8, _crit: &Critical) -> Result<
                 return Err(NotMapped);
             }
 
+            // huge pages have no PML1 entry, see split_huge:
+            if (*pml2_ent).flags().contains(PageFlags::HUGE) {
+                return Err(NotMapped);
+            }
+
             if (*pml1_ent).0 == 0 {
                 return Err(NotMapped);
             }
@@ -336,8 +957,14 @@ fn checked_pml1_entry(pml_base: u64, virt: *mut u8, _crit: &Critical) -> Result<
     })
 }
 
+/// Returns the page table entry mapping `virt`, which is the PML2 entry for an
+/// address in a huge page.
 pub fn entry(virt: *mut u8, _crit: &Critical) -> Result<&PmlEntry, NotMapped> {
-    let entry = checked_pml1_entry(CURRENT_PML, virt, _crit)?;
+    let entry = if is_huge(virt, _crit) {
+        unsafe { pml2_entry(CURRENT_PML, virt as u64) }
+    } else {
+        checked_pml1_entry(CURRENT_PML, virt, _crit)?
+    };
 
     // Safety(UNSAFE): ref lifetime is tied to critical section lifetime.
     // This could result in bad memory access if the page tables are mutated
@@ -346,17 +973,31 @@ pub fn entry(virt: *mut u8, _crit: &Critical) -> Result<&PmlEntry, NotMapped> {
     unsafe { Ok(&*entry) }
 }
 
-pub unsafe fn unmap(virt: *mut u8) -> Result<(), NotMapped> {
+/// Returns the physical address the byte at `virt` is mapped to.
+pub fn translate(virt: u64, crit: &Critical) -> Result<RawPhys, NotMapped> {
+    let page_size = if is_huge(virt as *const u8, crit) { HUGE_PAGE_SIZE } else { PAGE_SIZE };
+    let offset_mask = page_size as u64 - 1;
+
+    let base = entry(virt as *mut u8, crit)?.raw_phys()
+        .ok_or(NotMapped)?;
+
+    Ok(RawPhys((base.0 & !offset_mask) + (virt & offset_mask)))
+}
+
+/// Unmaps the page at `virt`. Its translation is invalidated and its
+/// reference dropped when the batch is flushed.
+pub unsafe fn unmap(virt: *mut u8, batch: &mut tlb::Batch) -> Result<(), NotMapped> {
     let crit = critical::begin();
 
     let pml1_ent = checked_pml1_entry(CURRENT_PML, virt, &crit)?;
 
     match (*pml1_ent).raw_phys() {
         Some(raw_phys) => {
-            // ensure we decrement the ref count of the physical page:
-            Phys::from_raw(raw_phys);
             *pml1_ent = PmlEntry(0);
-            invlpg(virt as *mut u8);
+            batch.add(virt);
+
+            // ensure we decrement the ref count of the physical page:
+            batch.release(Phys::from_raw(raw_phys));
             Ok(())
         }
         None => {
@@ -365,11 +1006,51 @@ pub unsafe fn unmap(virt: *mut u8) -> Result<(), NotMapped> {
     }
 }
 
-pub unsafe fn modify(virt: *mut u8, flags: PageFlags) -> Result<(), NotMapped> {
+/// Changes the flags of the page at `virt`. They take effect everywhere when
+/// the batch is flushed.
+pub unsafe fn modify(virt: *mut u8, flags: PageFlags, batch: &mut tlb::Batch) -> Result<(), NotMapped> {
     let crit = critical::begin();
 
     let pml1_ent = checked_pml1_entry(CURRENT_PML, virt, &crit)?;
     (*pml1_ent).set_flags(flags);
+    batch.add(virt);
+
+    Ok(())
+}
+
+/// Makes the page at `virt`, or the whole huge page containing it, writable or
+/// read-only, and executable or not. A private page still shared with another
+/// page context since fork is made copy on write instead of writable. The
+/// change takes effect everywhere when the batch is flushed.
+pub unsafe fn protect(virt: *mut u8, writable: bool, executable: bool, batch: &mut tlb::Batch)
+    -> Result<(), NotMapped>
+{
+    let crit = critical::begin();
+
+    let ent = if is_huge(virt, &crit) {
+        pml2_entry(CURRENT_PML, virt as u64)
+    } else {
+        checked_pml1_entry(CURRENT_PML, virt, &crit)?
+    };
+
+    let raw_phys = (*ent).raw_phys().ok_or(NotMapped)?;
+    let mut flags = (*ent).flags() - (PageFlags::WRITE | PageFlags::COW);
+    flags.set(PageFlags::NO_EXECUTE, !executable);
+
+    // fork splits huge pages, so only 4 KiB pages are ever shared like this:
+    let shared_by_fork = !flags.contains(PageFlags::SHARED)
+        && phys::refs(raw_phys).map(|refs| refs > 1).unwrap_or(false);
+
+    let flags = if !writable {
+        flags
+    } else if shared_by_fork {
+        flags | PageFlags::COW
+    } else {
+        flags | PageFlags::WRITE
+    };
+
+    (*ent).set_flags(flags);
+    batch.add(virt);
 
     Ok(())
 }
@@ -379,6 +1060,14 @@ pub fn virt_to_phys(virt: *mut u8) -> Result<Phys, NotMapped> {
     let crit = critical::begin();
 
     unsafe {
+        if is_huge(virt, &crit) {
+            let base = (*pml2_entry(CURRENT_PML, virt as u64)).raw_phys()
+                .expect("raw_phys of huge page");
+
+            let offset = virt as u64 & (HUGE_PAGE_SIZE as u64 - 1) & !(PAGE_SIZE as u64 - 1);
+            return Ok(Phys::new(RawPhys(base.0 + offset)));
+        }
+
         let pml1_ent = checked_pml1_entry(CURRENT_PML, virt, &crit)?;
 
         (*pml1_ent).raw_phys()
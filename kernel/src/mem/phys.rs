@@ -1,26 +1,31 @@
 use core::fmt::{self, Debug};
-use core::mem;
-use core::ptr;
+use core::{cmp, iter, mem};
 use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 use arrayvec::ArrayVec;
 
+use crate::config::MAX_NODES;
 use crate::critical::{self, Critical};
 use crate::mem::page::{self, PAGE_SIZE, PageFlags};
 use crate::mem::{zero, MemoryExhausted};
+use crate::smp;
 use crate::sync::Mutex;
 use crate::util::EarlyInit;
 
 extern "C" {
     static _phys_rc: AtomicUsize;
     static _phys_rc_end: AtomicUsize;
+    static _phys_frames: Frame;
 }
 
 static PHYS_REGIONS: EarlyInit<ArrayVec<[PhysRegion; 8]>> = EarlyInit::new();
 static PHYS_BUMP_ALLOC: EarlyInit<Mutex<ArrayVec<[RawPhys; 8]>>> = EarlyInit::new();
 
 static REF_COUNT_ENABLED: AtomicBool = AtomicBool::new(false);
-static NEXT_FREE_PHYS: Mutex<Option<RawPhys>> = Mutex::new(None);
+
+// pages on the buddy allocator's free lists, kept outside of it so memory
+// stats can be taken without its lock:
+static FREE_PAGES: AtomicUsize = AtomicUsize::new(0);
 
 const REGION_KIND_USABLE: u32 = 1;
 const MAX_PHYS_PAGE: u64 = 1 << 48;
@@ -48,6 +53,12 @@ impl Phys {
         RawPhys(phys)
     }
 
+    /// Returns the raw address of the physical page without consuming the
+    /// Phys. The reference count of the page is unaffected.
+    pub fn raw(&self) -> RawPhys {
+        RawPhys(self.0)
+    }
+
     /// Constructs a Phys from a raw address returned by `into_raw`. This
     /// function is the dual of into_raw. This function does not affect the
     /// reference count of the underlying physical page, so care must be taken
@@ -55,6 +66,13 @@ impl Phys {
     pub unsafe fn from_raw(raw_phys: RawPhys) -> Phys {
         Phys(raw_phys.0)
     }
+
+    /// Drops this reference like dropping the Phys would, except that if it
+    /// was the last one, `f` is called with the page before it is freed. Page
+    /// tables use this to release the pages they map.
+    pub fn release_with(self, f: impl FnOnce(RawPhys)) {
+        release_then(self.into_raw(), f);
+    }
 }
 
 impl Clone for Phys {
@@ -69,10 +87,35 @@ impl Debug for Phys {
     }
 }
 
+/// A NUMA node, a set of CPUs and the physical memory closest to them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeId(pub usize);
+
 #[derive(Debug)]
 struct PhysRegion {
     begin: RawPhys,
     end: RawPhys,
+    node: NodeId,
+}
+
+// returns the node a range of physical memory belongs to. there's no SRAT
+// parsing yet to say otherwise, so it's all on node 0 for now:
+fn node_of_range(_begin: RawPhys, _end: RawPhys) -> NodeId {
+    NodeId(0)
+}
+
+fn node_of(raw: RawPhys) -> Option<NodeId> {
+    PHYS_REGIONS.iter()
+        .find(|reg| reg.begin <= raw && raw < reg.end)
+        .map(|reg| reg.node)
+}
+
+// the preferred node first, then every other node in order:
+fn nodes_from(preferred: NodeId) -> impl Iterator<Item = NodeId> {
+    let preferred = NodeId(cmp::min(preferred.0, MAX_NODES - 1));
+
+    iter::once(preferred)
+        .chain((0..MAX_NODES).map(NodeId).filter(move |node| *node != preferred))
 }
 
 #[repr(C)]
@@ -83,27 +126,9 @@ pub struct BiosMemoryRegion {
     acpi_ex_attrs: u32,
 }
 
-fn alloc_freelist() -> Option<Phys> {
-    let mut next_free = NEXT_FREE_PHYS.lock();
-
-    next_free.take().map(|phys| {
-        unsafe {
-            let crit = critical::begin();
-            let phys = Phys::new(phys);
-
-            let mapped = page::temp_map::<Option<RawPhys>>(RawPhys(phys.0), &crit);
-
-            // pull linked next free phys out:
-            *next_free = (*mapped.ptr()).take();
-
-            // zero page before returning:
-            zero(mapped.ptr() as *mut u8, PAGE_SIZE);
-
-            phys
-        }
-    })
-}
-
+// allocates from the bump allocators, which hand out each region's pages in
+// order. only used while setting up the buddy allocator, which takes over all
+// remaining pages once it's ready:
 fn alloc_new(regions: &[PhysRegion], bump_alloc: &mut [RawPhys]) -> Result<Phys, MemoryExhausted> {
     for (region, alloc) in regions.iter().zip(bump_alloc.iter_mut()) {
         if *alloc >= region.end {
@@ -114,13 +139,7 @@ fn alloc_new(regions: &[PhysRegion], bump_alloc: &mut [RawPhys]) -> Result<Phys,
         alloc.0 += PAGE_SIZE as u64;
 
         let phys = unsafe { Phys::new(raw_phys) };
-
-        unsafe {
-            let crit = critical::begin();
-
-            let mapped = page::temp_map::<u64>(raw_phys, &crit);
-            zero(mapped.ptr() as *mut u8, PAGE_SIZE);
-        }
+        zero_page(raw_phys);
 
         return Ok(phys);
     }
@@ -128,38 +147,355 @@ fn alloc_new(regions: &[PhysRegion], bump_alloc: &mut [RawPhys]) -> Result<Phys,
     Err(MemoryExhausted)
 }
 
+fn zero_page(raw_phys: RawPhys) {
+    unsafe {
+        let crit = critical::begin();
+
+        let mapped = page::temp_map::<u64>(raw_phys, &crit);
+        zero(mapped.ptr() as *mut u8, PAGE_SIZE);
+    }
+}
+
+/// Allocates a single zeroed physical page, from the calling CPU's node if
+/// it has any to spare.
 pub fn alloc() -> Result<Phys, MemoryExhausted> {
-    if let Some(page) = alloc_freelist() {
-        return Ok(page);
+    alloc_on(smp::cpu_node())
+}
+
+/// Allocates a single zeroed physical page, preferably from `node` but from
+/// any other node rather than fail.
+pub fn alloc_on(node: NodeId) -> Result<Phys, MemoryExhausted> {
+    let page_number = nodes_from(node)
+        .filter_map(|node| BUDDIES[node.0].lock().alloc(0))
+        .next();
+
+    if let Some(page_number) = page_number {
+        let raw_phys = RawPhys(page_number * PAGE_SIZE as u64);
+        zero_page(raw_phys);
+
+        return Ok(unsafe { Phys::new(raw_phys) });
     }
 
-    // Safety: need unsafe to access static mut. We only ever take a mut ref to
-    // PHYS_REGIONS during phys_regions_init
     let regions = &PHYS_REGIONS;
     let mut bump_alloc = PHYS_BUMP_ALLOC.lock();
 
     alloc_new(regions, &mut *bump_alloc)
 }
 
+/// A run of physically contiguous pages, for DMA buffers and the like. Each
+/// page is reference counted individually, and goes back to the allocator once
+/// the block and every Phys taken from it have been dropped.
+pub struct PhysBlock {
+    base: RawPhys,
+    pages: usize,
+}
+
+impl PhysBlock {
+    pub fn base(&self) -> RawPhys {
+        self.base
+    }
+
+    pub fn pages(&self) -> usize {
+        self.pages
+    }
+
+    /// Returns a reference to one of the pages in the block.
+    pub fn page(&self, index: usize) -> Phys {
+        assert!(index < self.pages, "PhysBlock::page index out of range");
+        unsafe { Phys::new(RawPhys(self.base.0 + (index * PAGE_SIZE) as u64)) }
+    }
+}
+
+impl Debug for PhysBlock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PhysBlock(0x{:08x}, {} pages)", self.base.0, self.pages)
+    }
+}
+
+impl Drop for PhysBlock {
+    fn drop(&mut self) {
+        for index in 0..self.pages {
+            release(RawPhys(self.base.0 + (index * PAGE_SIZE) as u64));
+        }
+    }
+}
+
+/// Allocates `pages` zeroed, physically contiguous pages, aligned to the
+/// next power of two at least `pages` in size, from the calling CPU's node if
+/// it has a block to spare.
+pub fn alloc_contiguous(pages: usize) -> Result<PhysBlock, MemoryExhausted> {
+    alloc_contiguous_on(pages, smp::cpu_node())
+}
+
+/// Like `alloc_contiguous`, but preferably from `node`. The block always comes
+/// from a single node.
+pub fn alloc_contiguous_on(pages: usize, node: NodeId) -> Result<PhysBlock, MemoryExhausted> {
+    alloc_block(pages, node, RawPhys(u64::max_value()))
+}
+
+/// Like `alloc_contiguous`, but the whole block lies below `limit`, for devices
+/// that can't address all of physical memory.
+pub fn alloc_contiguous_below(pages: usize, limit: RawPhys) -> Result<PhysBlock, MemoryExhausted> {
+    alloc_block(pages, smp::cpu_node(), limit)
+}
+
+fn alloc_block(pages: usize, node: NodeId, limit: RawPhys) -> Result<PhysBlock, MemoryExhausted> {
+    let order = (0..ORDER_COUNT)
+        .find(|order| (1 << order) >= pages)
+        .ok_or(MemoryExhausted)?;
+
+    let limit_page = limit.0 / PAGE_SIZE as u64;
+
+    let (mut buddy, first) = nodes_from(node)
+        .filter_map(|node| {
+            let mut buddy = BUDDIES[node.0].lock();
+            buddy.alloc_below(order, limit_page).map(|first| (buddy, first))
+        })
+        .next()
+        .ok_or(MemoryExhausted)?;
+
+    // hand back the pages rounding up to a whole block left unused:
+    for page_number in (first + pages as u64)..(first + (1 << order)) {
+        unsafe { buddy.free(page_number, 0); }
+    }
+
+    drop(buddy);
+
+    for index in 0..pages {
+        let raw_phys = RawPhys((first + index as u64) * PAGE_SIZE as u64);
+        zero_page(raw_phys);
+        inc_ref(raw_phys);
+    }
+
+    Ok(PhysBlock { base: RawPhys(first * PAGE_SIZE as u64), pages })
+}
+
+/// Returns the number of physical pages the allocator manages, free or not.
+pub fn total_pages() -> usize {
+    PHYS_REGIONS.iter()
+        .map(|reg| ((reg.end.0 - reg.begin.0) / PAGE_SIZE as u64) as usize)
+        .sum()
+}
+
+/// Returns the number of physical pages free for allocation.
+pub fn free_pages() -> usize {
+    FREE_PAGES.load(Ordering::SeqCst)
+}
+
+// drops a reference to a page, freeing it if it was the last:
+fn release(raw_phys: RawPhys) {
+    release_then(raw_phys, |_| {});
+}
+
+// like release, but calls f with the page before freeing it:
+fn release_then(raw_phys: RawPhys, f: impl FnOnce(RawPhys)) {
+    match dec_ref(raw_phys) {
+        PhysStatus::InUse => {}
+        PhysStatus::ShouldFree => {
+            f(raw_phys);
+            let node = node_of(raw_phys).expect("freed page outside of PHYS_REGIONS");
+            unsafe { BUDDIES[node.0].lock().free(raw_phys.0 / PAGE_SIZE as u64, 0); }
+        }
+    }
+}
+
 impl Drop for Phys {
     fn drop(&mut self) {
-        match dec_ref(RawPhys(self.0)) {
-            PhysStatus::InUse => {}
-            PhysStatus::ShouldFree => {
-                let mut next_free = NEXT_FREE_PHYS.lock();
-
-                unsafe {
-                    let crit = critical::begin();
-                    let link = page::temp_map::<Option<RawPhys>>(RawPhys(self.0), &crit);
-                    ptr::write(link.ptr(), next_free.take());
-                }
-
-                *next_free = Some(RawPhys(self.0));
+        release(RawPhys(self.0));
+    }
+}
+
+// blocks of up to 2^(ORDER_COUNT - 1) pages, 4 MiB:
+const ORDER_COUNT: usize = 11;
+
+// marks the first page of a free block in Frame::state, along with its order:
+const FRAME_FREE: u64 = 1 << 63;
+
+// no page, for free list links:
+const NO_PAGE: u64 = u64::max_value();
+
+// allocator state for each physical page, in an array indexed by page number
+// like the reference counts. only the first page of a free block uses it:
+#[repr(C)]
+struct Frame {
+    next: u64,
+    prev: u64,
+    state: u64,
+    // pads Frame to a size that divides the page size:
+    _reserved: u64,
+}
+
+// one allocator per node, each only ever holding that node's pages:
+static BUDDIES: [Mutex<Buddy>; MAX_NODES] = [
+    Mutex::new(Buddy::new(NodeId(0))),
+    Mutex::new(Buddy::new(NodeId(1))),
+    Mutex::new(Buddy::new(NodeId(2))),
+    Mutex::new(Buddy::new(NodeId(3))),
+];
+
+/// A binary buddy allocator. Free blocks of 2^order pages are kept on one
+/// doubly linked list per order, threaded through the Frame array rather than
+/// the free pages themselves so that unlinking a block doesn't need it mapped.
+/// Allocating splits larger blocks in half as needed, freeing merges a block
+/// with its buddy for as long as the buddy is free too.
+struct Buddy {
+    node: NodeId,
+    // first page number of the first free block of each order:
+    free: [u64; ORDER_COUNT],
+}
+
+impl Buddy {
+    const fn new(node: NodeId) -> Self {
+        Buddy { node, free: [NO_PAGE; ORDER_COUNT] }
+    }
+
+    fn alloc(&mut self, order: usize) -> Option<u64> {
+        self.alloc_below(order, NO_PAGE)
+    }
+
+    // like alloc, but the block must end at or below the page `limit`. only
+    // blocks that don't fit have to be walked past, so with no limit to speak
+    // of this is as quick as taking the first free block:
+    fn alloc_below(&mut self, order: usize, limit: u64) -> Option<u64> {
+        let (page_number, found) = (order..ORDER_COUNT)
+            .filter_map(|found| {
+                self.free_blocks(found)
+                    .find(|page_number| page_number + (1 << order) <= limit)
+                    .map(|page_number| (page_number, found))
+            })
+            .next()?;
+
+        unsafe {
+            self.unlink(page_number, found);
+
+            // split the block, giving back the upper half each time:
+            for split_order in (order..found).rev() {
+                self.push(page_number + (1 << split_order), split_order);
+            }
+        }
+
+        FREE_PAGES.fetch_sub(1 << order, Ordering::SeqCst);
+
+        Some(page_number)
+    }
+
+    // the Frame of the page must be mapped, which is the case for every page
+    // in PHYS_REGIONS:
+    unsafe fn free(&mut self, mut page_number: u64, mut order: usize) {
+        FREE_PAGES.fetch_add(1 << order, Ordering::SeqCst);
+
+        while order + 1 < ORDER_COUNT {
+            let buddy = page_number ^ (1 << order);
+
+            if !self.is_free(buddy, order) {
+                break;
+            }
+
+            self.unlink(buddy, order);
+            page_number &= !(1 << order);
+            order += 1;
+        }
+
+        self.push(page_number, order);
+    }
+
+    unsafe fn is_free(&self, page_number: u64, order: usize) -> bool {
+        // a free buddy on another node is on another allocator's lists:
+        if node_of(RawPhys(page_number * PAGE_SIZE as u64)) != Some(self.node) {
+            return false;
+        }
+
+        match frame(page_number) {
+            Some(frame) => (*frame).state == FRAME_FREE | order as u64,
+            None => false,
+        }
+    }
+
+    fn free_blocks<'a>(&'a self, order: usize) -> impl Iterator<Item = u64> + 'a {
+        let mut next = self.free[order];
+
+        iter::from_fn(move || {
+            let page_number = next;
+
+            if page_number == NO_PAGE {
+                return None;
+            }
+
+            next = unsafe { (*frame_of_free(page_number)).next };
+            Some(page_number)
+        })
+    }
+
+    unsafe fn push(&mut self, page_number: u64, order: usize) {
+        let frame = frame(page_number).expect("Buddy::push frame");
+        let next = self.free[order];
+
+        (*frame).state = FRAME_FREE | order as u64;
+        (*frame).prev = NO_PAGE;
+        (*frame).next = next;
+
+        if next != NO_PAGE {
+            (*frame_of_free(next)).prev = page_number;
+        }
+
+        self.free[order] = page_number;
+    }
+
+    unsafe fn unlink(&mut self, page_number: u64, order: usize) {
+        let frame = frame_of_free(page_number);
+        let (prev, next) = ((*frame).prev, (*frame).next);
+
+        if prev == NO_PAGE {
+            self.free[order] = next;
+        } else {
+            (*frame_of_free(prev)).next = next;
+        }
+
+        if next != NO_PAGE {
+            (*frame_of_free(next)).prev = prev;
+        }
+
+        (*frame).state = 0;
+    }
+
+    // hands a range of pages to the allocator, as the largest aligned blocks
+    // that fit:
+    unsafe fn add_range(&mut self, begin: RawPhys, end: RawPhys) {
+        let mut page_number = (begin.0 + PAGE_SIZE as u64 - 1) / PAGE_SIZE as u64;
+        let end_page_number = end.0 / PAGE_SIZE as u64;
+
+        while page_number < end_page_number {
+            let mut order = 0;
+
+            while order + 1 < ORDER_COUNT
+                && page_number % (1 << (order + 1)) == 0
+                && page_number + (1 << (order + 1)) <= end_page_number
+            {
+                order += 1;
             }
+
+            self.free(page_number, order);
+            page_number += 1 << order;
         }
     }
 }
 
+// returns the Frame for a page, if it's one the allocator manages:
+fn frame(page_number: u64) -> Option<*mut Frame> {
+    let raw = RawPhys(page_number.checked_mul(PAGE_SIZE as u64)?);
+
+    if !PHYS_REGIONS.iter().any(|reg| reg.begin <= raw && raw < reg.end) {
+        return None;
+    }
+
+    let base = unsafe { &_phys_frames as *const Frame as *mut Frame };
+    Some(unsafe { base.add(page_number as usize) })
+}
+
+fn frame_of_free(page_number: u64) -> *mut Frame {
+    frame(page_number).expect("free page without a Frame")
+}
+
 pub unsafe fn init_ref_counts(_critical: &Critical) {
     // inc ref for all currently mapped pages
     page::each_phys(|raw_phys| {
@@ -199,6 +535,12 @@ fn ref_count(raw: RawPhys) -> Option<&'static AtomicUsize> {
     Some(unsafe { &*rc })
 }
 
+/// Returns the number of references to a physical page, or None if the page
+/// isn't reference counted, such as memory mapped hardware.
+pub fn refs(raw: RawPhys) -> Option<usize> {
+    ref_count(raw).map(|rc| rc.load(Ordering::SeqCst))
+}
+
 fn inc_ref(raw: RawPhys) {
     if REF_COUNT_ENABLED.load(Ordering::SeqCst) {
         if let Some(rc) = ref_count(raw) {
@@ -248,7 +590,21 @@ unsafe fn ensure_rc_page(phys: RawPhys) {
             let phys = alloc()
                 .expect("phys::alloc in phys_init");
 
-            page::map(phys, ref_count_page, PageFlags::PRESENT | PageFlags::WRITE)
+            page::map(phys, ref_count_page, PageFlags::PRESENT | PageFlags::WRITE | PageFlags::NO_EXECUTE)
+                .expect("page::map in phys_init");
+        }
+    }
+}
+
+unsafe fn ensure_frame_page(phys: RawPhys) {
+    if let Some(frame) = frame(phys.0 / PAGE_SIZE as u64) {
+        let frame_page = (frame as usize & !(PAGE_SIZE - 1)) as *mut u8;
+
+        if !page::is_mapped(frame_page) {
+            let phys = alloc()
+                .expect("phys::alloc in phys_init");
+
+            page::map(phys, frame_page, PageFlags::PRESENT | PageFlags::WRITE | PageFlags::NO_EXECUTE)
                 .expect("page::map in phys_init");
         }
     }
@@ -295,6 +651,7 @@ pub unsafe extern "C" fn phys_init(
         phys_regions.push(PhysRegion {
             begin: region_begin,
             end: region_end,
+            node: node_of_range(region_begin, region_end),
         });
 
         match phys_bump_alloc.try_push(region_begin) {
@@ -331,5 +688,26 @@ pub unsafe extern "C" fn phys_init(
         ensure_rc_page(raw_phys);
     }
 
+    // map allocator state for all phys regions:
+    for region in PHYS_REGIONS.iter() {
+        let mut phys = region.begin;
+
+        while phys < region.end {
+            ensure_frame_page(phys);
+            phys.0 += PAGE_SIZE as u64;
+        }
+    }
+
+    // then hand every page the bump allocators haven't already given out over
+    // to the buddy allocator:
+    {
+        let mut bump_alloc = PHYS_BUMP_ALLOC.lock();
+
+        for (region, alloc) in PHYS_REGIONS.iter().zip(bump_alloc.iter_mut()) {
+            BUDDIES[region.node.0].lock().add_range(*alloc, region.end);
+            *alloc = region.end;
+        }
+    }
+
     crate::println!();
 }
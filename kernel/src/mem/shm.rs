@@ -0,0 +1,91 @@
+use core::fmt::{self, Debug};
+
+use alloc_collections::btree_map::BTreeMap;
+use interface::{SysError, SysResult};
+
+use crate::critical::Critical;
+use crate::mem::kalloc::GlobalAlloc;
+use crate::mem::MemoryExhausted;
+use crate::mem::page::{self, MapError, PageCtx, PageFlags, PAGE_SIZE};
+use crate::mem::phys::{self, Phys};
+use crate::mem::user::PageRange;
+use crate::mem::vma::{self, Backing, Vma};
+
+/// A set of physical pages that any number of page contexts can map at once,
+/// for sharing memory between processes. Each page goes back to the allocator
+/// once the object and every mapping of it are gone.
+pub struct SharedMemory {
+    // by page index. there's nowhere else in the kernel to keep an arbitrary
+    // number of pages:
+    pages: BTreeMap<u64, Phys, GlobalAlloc>,
+    page_count: u64,
+}
+
+impl SharedMemory {
+    /// Allocates `page_count` zeroed pages.
+    pub fn new(page_count: u64) -> Result<SharedMemory, MemoryExhausted> {
+        let mut pages = BTreeMap::new();
+
+        for index in 0..page_count {
+            pages.insert(index, phys::alloc()?)
+                .map_err(|_| MemoryExhausted)?;
+        }
+
+        Ok(SharedMemory { pages, page_count })
+    }
+
+    pub fn page_count(&self) -> u64 {
+        self.page_count
+    }
+
+    pub fn byte_len(&self) -> u64 {
+        self.page_count * PAGE_SIZE as u64
+    }
+
+    /// Maps every page into the current page context at `range`, which must
+    /// be the same size as the object and have nothing mapped in it. The range
+    /// gets a VMA of its own, so it can be unmapped with munmap and is kept
+    /// shared across fork rather than made copy on write.
+    pub fn map(&self, page_ctx: &PageCtx, range: &PageRange, flags: PageFlags, crit: &Critical)
+        -> SysResult<()>
+    {
+        assert!(range.end() - range.start() == self.byte_len(),
+            "SharedMemory::map called with a range of the wrong size");
+
+        let flags = flags | PageFlags::SHARED;
+
+        page_ctx.add_vma(Vma {
+            start: range.start(),
+            end: range.end(),
+            flags,
+            backing: Backing::Shared,
+        })?;
+
+        let tables = page_ctx.lock_tables();
+
+        for (addr, phys) in range.pages().zip(self.pages.values()) {
+            let result = unsafe { page::map(phys.clone(), addr as *mut u8, flags) };
+
+            if let Err(e) = result {
+                drop(tables);
+
+                // take back what's been mapped so far, along with the VMA:
+                let _ = vma::unmap_range(page_ctx, range, crit);
+
+                return Err(match e {
+                    MapError::AlreadyMapped => SysError::AlreadyMapped,
+                    MapError::CannotAllocatePageTable => SysError::MemoryExhausted,
+                    MapError::WrongHalf => panic!("SharedMemory::map: range outside of the user half"),
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Debug for SharedMemory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SharedMemory({} pages)", self.page_count)
+    }
+}
@@ -0,0 +1,185 @@
+use core::cell::UnsafeCell;
+use core::sync::atomic::{self, AtomicBool, AtomicUsize, Ordering};
+
+use arrayvec::ArrayVec;
+
+use crate::critical;
+use crate::mem::page;
+use crate::mem::phys::Phys;
+use crate::smp;
+
+// past this many pages, a batch flushes whole TLBs instead:
+const BATCH_PAGES: usize = 32;
+
+/// The vector remote CPUs are interrupted with to flush their TLBs. Must match
+/// the IDT entry in isrs.asm.
+pub const SHOOTDOWN_VECTOR: u8 = 0x40;
+
+crate::percpu! {
+    // whether this CPU answers shootdowns. CPUs that never enable interrupts
+    // can't, and are left out of them:
+    static ACCEPTING: AtomicBool = AtomicBool::new(false);
+
+    // set by the initiator of a shootdown on every CPU it's waiting for:
+    static PENDING: AtomicBool = AtomicBool::new(false);
+}
+
+#[derive(Clone, Copy)]
+struct Pages {
+    pages: [u64; BATCH_PAGES],
+    len: usize,
+    // too many pages to name, flush everything:
+    full: bool,
+}
+
+impl Pages {
+    const fn new() -> Self {
+        Pages { pages: [0; BATCH_PAGES], len: 0, full: false }
+    }
+
+    fn invalidate(&self) {
+        if self.full {
+            unsafe { reload_cr3(); }
+        } else {
+            for virt in &self.pages[0..self.len] {
+                page::invlpg(*virt as *mut u8);
+            }
+        }
+    }
+}
+
+// only one shootdown is in flight at a time. its initiator holds IN_PROGRESS
+// from before it writes the request until every target has acknowledged it by
+// decrementing ACKS, so targets can read the request without a lock:
+struct Request(UnsafeCell<Pages>);
+
+unsafe impl Sync for Request {}
+
+static IN_PROGRESS: AtomicBool = AtomicBool::new(false);
+static REQUEST: Request = Request(UnsafeCell::new(Pages::new()));
+static ACKS: AtomicUsize = AtomicUsize::new(0);
+
+/// A set of pages whose translations have changed, to be invalidated on every
+/// CPU in one go. The batch is flushed when it's dropped.
+///
+/// Physical pages that were unmapped must not be freed while another CPU may
+/// still reach them through its TLB, so they're handed to the batch with
+/// `release` and only dropped once it has been flushed.
+pub struct Batch {
+    pages: Pages,
+    released: ArrayVec<[Phys; BATCH_PAGES]>,
+}
+
+impl Batch {
+    pub fn new() -> Self {
+        Batch { pages: Pages::new(), released: ArrayVec::new() }
+    }
+
+    /// Creates a batch that flushes the whole TLB, for when the changes are
+    /// too widespread to track page by page.
+    pub fn full() -> Self {
+        let mut batch = Batch::new();
+        batch.pages.full = true;
+        batch
+    }
+
+    pub fn add(&mut self, virt: *mut u8) {
+        if self.pages.full {
+            return;
+        }
+
+        if self.pages.len == BATCH_PAGES {
+            self.pages.full = true;
+            return;
+        }
+
+        self.pages.pages[self.pages.len] = virt as u64;
+        self.pages.len += 1;
+    }
+
+    /// Drops `phys` once the batch has been flushed.
+    pub fn release(&mut self, phys: Phys) {
+        if let Err(overflow) = self.released.try_push(phys) {
+            self.flush();
+            self.released.push(overflow.element());
+        }
+    }
+
+    /// Flushes the batch now, leaving it empty.
+    pub fn flush(&mut self) {
+        if self.pages.full || self.pages.len > 0 {
+            shootdown(&self.pages);
+        }
+
+        self.pages = Pages::new();
+
+        // only now is nobody using the released pages:
+        self.released.clear();
+    }
+}
+
+impl Drop for Batch {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// Makes the calling CPU answer shootdowns. It must be able to take the
+/// shootdown interrupt from then on, and never sit with interrupts disabled
+/// for long outside of Mutex::lock, which answers them while it spins.
+pub fn accept() {
+    ACCEPTING.get().store(true, Ordering::SeqCst);
+}
+
+/// Carries out the shootdown pending on the calling CPU, if there is one.
+/// Called from the shootdown interrupt, and by anything spinning with
+/// interrupts disabled that the initiator may be waiting on.
+pub fn service() {
+    if !PENDING.get().swap(false, Ordering::SeqCst) {
+        return;
+    }
+
+    unsafe { (*REQUEST.0.get()).invalidate(); }
+
+    ACKS.fetch_sub(1, Ordering::SeqCst);
+}
+
+fn shootdown(pages: &Pages) {
+    let _crit = critical::begin();
+
+    pages.invalidate();
+
+    let this_cpu = smp::cpu_index();
+    let is_target = |cpu: &usize| *cpu != this_cpu && ACCEPTING.for_cpu(*cpu).load(Ordering::SeqCst);
+
+    // nobody else to tell on a single CPU, or before the APs are up:
+    if !(0..smp::cpu_count()).any(|cpu| is_target(&cpu)) {
+        return;
+    }
+
+    // another CPU may be waiting on us to finish its own shootdown:
+    while IN_PROGRESS.compare_and_swap(false, true, Ordering::SeqCst) {
+        service();
+        atomic::spin_loop_hint();
+    }
+
+    unsafe { *REQUEST.0.get() = *pages; }
+
+    // CPUs only ever start accepting, so there's at least one target still:
+    for cpu in (0..smp::cpu_count()).filter(is_target) {
+        ACKS.fetch_add(1, Ordering::SeqCst);
+        PENDING.for_cpu(cpu).store(true, Ordering::SeqCst);
+    }
+
+    unsafe { smp::send_ipi_all(SHOOTDOWN_VECTOR); }
+
+    while ACKS.load(Ordering::SeqCst) != 0 {
+        atomic::spin_loop_hint();
+    }
+
+    IN_PROGRESS.store(false, Ordering::SeqCst);
+}
+
+unsafe fn reload_cr3() {
+    asm!("movq %cr3, %rax; movq %rax, %cr3" ::: "rax", "memory" : "volatile");
+}
@@ -1,11 +1,15 @@
-use core::{mem, slice};
+use core::{cmp, iter};
 
-use crate::mem::page::{self, PAGE_SIZE, PageFlags};
+use arrayvec::{Array, ArrayVec};
+
+use crate::cpu::with_user_access;
+use crate::interrupt::TrapFrame;
+use crate::mem::page::{self, PAGE_SIZE, PageFlags, USER_END};
+use crate::mem::vma;
 use crate::critical::Critical;
+use crate::task;
 use interface::{SysResult, SysError};
 
-const MAX_USER_ADDR: u64 = 0x0000800000000000; // exclusive max
-
 pub fn validate_page_align(addr: u64) -> SysResult<()> {
     if (addr & (PAGE_SIZE as u64 - 1)) != 0 {
         return Err(SysError::BadPointer);
@@ -29,11 +33,11 @@ impl PageRange {
 
         validate_page_align(base_page)?;
 
-        if base_page > MAX_USER_ADDR {
+        if !page::is_user_addr(base_page) {
             return Err(SysError::BadPointer);
         }
 
-        if base_page + byte_len > MAX_USER_ADDR {
+        if base_page.checked_add(byte_len).map_or(true, |end| end > USER_END) {
             return Err(SysError::BadPointer);
         }
 
@@ -72,6 +76,15 @@ impl PageRange {
         PageRange::new(base_page, page_count)
     }
 
+    pub fn start(&self) -> u64 {
+        self.base_page
+    }
+
+    // exclusive:
+    pub fn end(&self) -> u64 {
+        self.base_page + (self.page_count * PAGE_SIZE) as u64
+    }
+
     pub fn pages(&self) -> impl Iterator<Item = u64> {
         let base_page = self.base_page;
 
@@ -110,10 +123,24 @@ pub fn validate_available(page_range: &PageRange, crit: &Critical) -> SysResult<
     Ok(())
 }
 
+// maps in any untouched VMA pages in the range, which otherwise don't pass
+// validation as they have no page table entries yet:
+fn fault_in(page_range: &PageRange, crit: &Critical) -> SysResult<()> {
+    let page_ctx = task::get_page_ctx();
+    let _tables = page_ctx.object().lock_tables();
+
+    for addr in page_range.pages() {
+        vma::fault_in(page_ctx.object(), addr, crit)?;
+    }
+
+    Ok(())
+}
+
 pub fn validate_read(addr: u64, len: u64, crit: &Critical) -> SysResult<()> {
     // explicitly not checking for PRESENT flag, as this prevent us from
     // faulting in pages
     let page_range = PageRange::containing(addr, len)?;
+    fault_in(&page_range, crit)?;
     validate_map(&page_range, PageFlags::empty(), crit)
 }
 
@@ -121,42 +148,105 @@ pub fn validate_write(addr: u64, len: u64, crit: &Critical) -> SysResult<()> {
     // explicitly not checking for PRESENT flag, as this prevent us from
     // faulting in pages
     let page_range = PageRange::containing(addr, len)?;
+    fault_in(&page_range, crit)?;
+
+    // the kernel can write to read-only pages without faulting, so copy on
+    // write pages must be copied before it's let loose on them:
+    let page_ctx = task::get_page_ctx();
+    let tables = page_ctx.object().lock_tables();
+
+    for addr in page_range.pages() {
+        unsafe { page::copy_on_write(addr as *mut u8, crit)?; }
+    }
+
+    drop(tables);
+
     validate_map(&page_range, PageFlags::WRITE, crit)
 }
 
-/// Borrows a slice from user space. `len` is the number of elements, not the
-/// number of bytes.
-pub fn borrow_slice<T>(addr: u64, len: u64, crit: &Critical) -> SysResult<&[T]> {
-    let byte_len = len.checked_mul(mem::size_of::<T>() as u64)
-        .ok_or(SysError::BadPointer)?;
+/// Copies `len` bytes from user space at `addr` into a new ArrayVec, for
+/// paths, names and the like that the kernel holds onto while it works.
+/// Fails with IllegalValue if they don't fit.
+pub fn copy_array_from_user<A: Array<Item = u8>>(addr: u64, len: u64, crit: &Critical)
+    -> SysResult<ArrayVec<A>>
+{
+    let mut array = ArrayVec::new();
+
+    if len > array.capacity() as u64 {
+        return Err(SysError::IllegalValue);
+    }
 
-    validate_read(addr, byte_len, crit)?;
+    array.extend(iter::repeat(0).take(len as usize));
+    copy_from_user(&mut array, addr, crit)?;
+
+    Ok(array)
+}
 
-    // Safety(UNSAFE): ref lifetime is tied to critical section lifetime.
-    // This could result in bad memory access if the page tables are mutated
-    // by the kernel while this critical section is held, but importantly at
-    // least protects us from concurrent modification by other processes.
-    Ok(unsafe { slice::from_raw_parts(addr as *const T, len as usize) })
+extern "C" {
+    fn user_copy(dst: *mut u8, src: *const u8, len: usize) -> u64;
+    fn user_strncpy(dst: *mut u8, src: *const u8, len: usize) -> u64;
+    static user_copy_begin: u8;
+    static user_copy_end: u8;
+    fn user_access_fault();
 }
 
-/// Borrows a mut slice from user space. `len` is the number of elements, not
-/// the number of bytes.
-pub fn borrow_slice_mut<T>(addr: u64, len: u64, crit: &Critical) -> SysResult<&mut [T]> {
-    let byte_len = len.checked_mul(mem::size_of::<T>() as u64)
-        .ok_or(SysError::BadPointer)?;
+// returned by user_copy and user_strncpy when they fault, see aux.asm:
+const USER_ACCESS_FAULTED: u64 = u64::max_value();
 
-    validate_write(addr, byte_len, crit)?;
+/// Copies `dst.len()` bytes from user space at `addr` into `dst`. Fails with
+/// BadPointer, rather than faulting, if any of them aren't readable.
+pub fn copy_from_user(dst: &mut [u8], addr: u64, crit: &Critical) -> SysResult<()> {
+    validate_read(addr, dst.len() as u64, crit)?;
 
-    // Safety(UNSAFE): ref lifetime is tied to critical section lifetime.
-    // This could result in bad memory access if the page tables are mutated
-    // by the kernel while this critical section is held, but importantly at
-    // least protects us from concurrent modification by other processes.
-    Ok(unsafe { slice::from_raw_parts_mut(addr as *mut T, len as usize) })
+    match with_user_access(|| unsafe { user_copy(dst.as_mut_ptr(), addr as *const u8, dst.len()) }) {
+        USER_ACCESS_FAULTED => Err(SysError::BadPointer),
+        _ => Ok(()),
+    }
+}
+
+/// Copies `src` to user space at `addr`. Fails with BadPointer, rather than
+/// faulting, if any of the destination isn't writable.
+pub fn copy_to_user(addr: u64, src: &[u8], crit: &Critical) -> SysResult<()> {
+    validate_write(addr, src.len() as u64, crit)?;
+
+    match with_user_access(|| unsafe { user_copy(addr as *mut u8, src.as_ptr(), src.len()) }) {
+        USER_ACCESS_FAULTED => Err(SysError::BadPointer),
+        _ => Ok(()),
+    }
 }
 
-#[allow(unused)]
-pub fn borrow<T>(addr: u64, crit: &Critical) -> SysResult<&T> {
-    let slice = borrow_slice(addr, mem::size_of::<T>() as u64, crit)?;
+/// Copies a NUL terminated string from user space at `addr` into `dst`,
+/// including the NUL. Returns the length of the string, or `dst.len()` if it
+/// didn't fit. Fails with BadPointer if the string runs into memory that
+/// isn't readable before it ends.
+pub fn strncpy_from_user(dst: &mut [u8], addr: u64, _crit: &Critical) -> SysResult<usize> {
+    if !page::is_user_addr(addr) {
+        return Err(SysError::BadPointer);
+    }
+
+    // how long the string is isn't known up front, so it can't be validated
+    // like other ranges. untouched pages are faulted in as it's copied, and
+    // the copy bails out on anything else. it mustn't run off the end of the
+    // user half though:
+    let len = cmp::min(dst.len() as u64, USER_END - addr) as usize;
+
+    match with_user_access(|| unsafe { user_strncpy(dst.as_mut_ptr(), addr as *const u8, len) }) {
+        USER_ACCESS_FAULTED => Err(SysError::BadPointer),
+        copied if copied as usize == len && len < dst.len() => Err(SysError::BadPointer),
+        copied => Ok(copied as usize),
+    }
+}
+
+/// Resumes a page fault in one of the user copy routines at their error path.
+/// Returns false if the fault happened anywhere else.
+pub fn recover_fault(frame: &mut TrapFrame) -> bool {
+    let begin = unsafe { &user_copy_begin as *const u8 as u64 };
+    let end = unsafe { &user_copy_end as *const u8 as u64 };
+
+    if frame.rip < begin || frame.rip >= end {
+        return false;
+    }
 
-    Ok(&slice[0])
+    frame.rip = user_access_fault as usize as u64;
+    true
 }
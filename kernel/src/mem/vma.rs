@@ -0,0 +1,483 @@
+use core::ptr;
+
+use alloc_collections::btree_map::BTreeMap;
+use interface::{SysError, SysResult};
+
+use crate::critical::Critical;
+use crate::mem::kalloc::GlobalAlloc;
+use crate::mem::MemoryExhausted;
+use crate::mem::page::{self, MapError, PageCtx, PageFlags, HUGE_PAGE_PAGES, HUGE_PAGE_SIZE, PAGE_SIZE};
+use crate::mem::phys::{self, Phys};
+use crate::mem::tlb;
+use crate::mem::user::{self, PageRange};
+
+/// Mappings placed by the kernel go at or above this address, or above
+/// wherever exec moved the page context's mmap base to.
+pub const MMAP_BASE: u64 = 0x0000_0001_0000_0000;
+
+/// What a VMA's pages are filled with when they're first touched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    Anonymous,
+    /// A shared memory object, whose pages are all mapped up front.
+    Shared,
+}
+
+/// A virtual memory area, a page aligned range of user address space whose
+/// pages are only mapped in by the page fault handler on first access.
+#[derive(Debug, Clone, Copy)]
+pub struct Vma {
+    pub start: u64,
+    // exclusive:
+    pub end: u64,
+    pub flags: PageFlags,
+    pub backing: Backing,
+}
+
+impl Vma {
+    pub fn contains(&self, addr: u64) -> bool {
+        self.start <= addr && addr < self.end
+    }
+}
+
+#[derive(Debug)]
+pub enum VmaError {
+    Overlap,
+    // part of the range isn't covered by any VMA:
+    Unmapped,
+    MemoryExhausted,
+}
+
+impl From<MemoryExhausted> for VmaError {
+    fn from(_: MemoryExhausted) -> Self {
+        VmaError::MemoryExhausted
+    }
+}
+
+impl From<VmaError> for SysError {
+    fn from(e: VmaError) -> Self {
+        match e {
+            VmaError::Overlap => SysError::AlreadyMapped,
+            VmaError::Unmapped => SysError::BadPointer,
+            VmaError::MemoryExhausted => SysError::MemoryExhausted,
+        }
+    }
+}
+
+/// The heap of a program, which starts right after its executable's
+/// segments and ends at the program break. Only the pages up to the break are
+/// covered by VMAs.
+#[derive(Debug, Clone, Copy)]
+pub struct Heap {
+    pub start: u64,
+    pub brk: u64,
+}
+
+/// The VMAs of a page context, keyed by start address. VMAs never overlap.
+#[derive(Debug)]
+pub struct VmaList {
+    vmas: BTreeMap<u64, Vma, GlobalAlloc>,
+    // only page contexts set up by exec have a heap:
+    heap: Option<Heap>,
+    // where mappings the kernel places start looking for room:
+    mmap_base: u64,
+}
+
+impl VmaList {
+    pub fn new() -> Self {
+        VmaList { vmas: BTreeMap::new(), heap: None, mmap_base: MMAP_BASE }
+    }
+
+    pub fn try_clone(&self) -> Result<Self, MemoryExhausted> {
+        let vmas = self.vmas.clone()
+            .map_err(|_| MemoryExhausted)?;
+
+        Ok(VmaList { vmas, heap: self.heap, mmap_base: self.mmap_base })
+    }
+
+    pub fn heap(&self) -> Option<Heap> {
+        self.heap
+    }
+
+    /// Every VMA, lowest address first.
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.vmas.values()
+    }
+
+    /// Sets up an empty heap starting at the given page aligned address.
+    pub fn set_heap_start(&mut self, start: u64) {
+        self.heap = Some(Heap { start, brk: start });
+    }
+
+    /// The lowest address the kernel places mappings at.
+    pub fn mmap_base(&self) -> u64 {
+        self.mmap_base
+    }
+
+    /// Moves the mmap base, which must be page aligned and at or above
+    /// MMAP_BASE.
+    pub fn set_mmap_base(&mut self, base: u64) {
+        self.mmap_base = base;
+    }
+
+    /// Whether no VMA overlaps the given range.
+    pub fn is_free(&self, start: u64, end: u64) -> bool {
+        !self.vmas.values().any(|vma| vma.start < end && start < vma.end)
+    }
+
+    pub fn insert(&mut self, vma: Vma) -> Result<(), VmaError> {
+        if !self.is_free(vma.start, vma.end) {
+            return Err(VmaError::Overlap);
+        }
+
+        // extend the VMA right before this one instead, if it's just the same.
+        // this keeps heaps grown a little at a time down to a single VMA:
+        let previous = self.vmas.range(..vma.start)
+            .next_back()
+            .map(|(_, previous)| *previous)
+            .filter(|previous| previous.end == vma.start
+                && previous.flags == vma.flags
+                && previous.backing == vma.backing);
+
+        if let Some(previous) = previous {
+            self.vmas.get_mut(&previous.start)
+                .expect("previous VMA")
+                .end = vma.end;
+
+            return Ok(());
+        }
+
+        self.vmas.insert(vma.start, vma)
+            .map_err(|_| VmaError::MemoryExhausted)?;
+
+        Ok(())
+    }
+
+    /// Removes the given range from any VMAs overlapping it, splitting VMAs
+    /// that extend past it on both sides.
+    pub fn remove_range(&mut self, start: u64, end: u64) -> Result<(), MemoryExhausted> {
+        loop {
+            let vma = match self.vmas.values().find(|vma| vma.start < end && start < vma.end) {
+                Some(vma) => *vma,
+                None => return Ok(()),
+            };
+
+            // the part after the range is the only one needing a new entry, so
+            // add it first. if that fails the VMA is left untouched:
+            if vma.end > end {
+                self.vmas.insert(end, Vma { start: end, ..vma })
+                    .map_err(|_| MemoryExhausted)?;
+            }
+
+            if vma.start < start {
+                self.vmas.get_mut(&vma.start)
+                    .expect("VMA being trimmed")
+                    .end = start;
+            } else {
+                self.vmas.remove(&vma.start);
+            }
+        }
+    }
+
+    /// Whether every page in the range is covered by some VMA.
+    pub fn is_covered(&self, start: u64, end: u64) -> bool {
+        let mut covered_to = start;
+
+        for vma in self.vmas.range(..end).map(|(_, vma)| vma) {
+            if vma.end <= covered_to {
+                continue;
+            }
+
+            if vma.start > covered_to {
+                return false;
+            }
+
+            covered_to = vma.end;
+        }
+
+        covered_to >= end
+    }
+
+    // splits the VMA containing `addr` in two at `addr`, unless it already
+    // starts there:
+    fn split_at(&mut self, addr: u64) -> Result<(), MemoryExhausted> {
+        let vma = match self.find(addr) {
+            Some(vma) if vma.start != addr => *vma,
+            _ => return Ok(()),
+        };
+
+        self.vmas.insert(addr, Vma { start: addr, ..vma })
+            .map_err(|_| MemoryExhausted)?;
+
+        self.vmas.get_mut(&vma.start)
+            .expect("VMA being split")
+            .end = addr;
+
+        Ok(())
+    }
+
+    /// Gives the range, which must be covered by VMAs, new protection flags,
+    /// splitting VMAs that extend past it. VMAs of shared memory stay shared.
+    pub fn protect_range(&mut self, start: u64, end: u64, flags: PageFlags) -> Result<(), VmaError> {
+        if !self.is_covered(start, end) {
+            return Err(VmaError::Unmapped);
+        }
+
+        // if the second split fails the first is left in place, which is
+        // harmless as both halves are the same:
+        self.split_at(start)?;
+        self.split_at(end)?;
+
+        for (_, vma) in self.vmas.range_mut(start..end) {
+            vma.flags = flags | (vma.flags & PageFlags::SHARED);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the lowest address between `base` and `top` with `len` bytes
+    /// free of VMAs after it.
+    pub fn find_free(&self, len: u64, base: u64, top: u64) -> Option<u64> {
+        let mut candidate = base;
+
+        for vma in self.vmas.values() {
+            if vma.end <= candidate {
+                continue;
+            }
+
+            if vma.start >= candidate.checked_add(len)? {
+                break;
+            }
+
+            candidate = vma.end;
+        }
+
+        if candidate.checked_add(len)? <= top {
+            Some(candidate)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the VMA containing the given address.
+    pub fn find(&self, addr: u64) -> Option<&Vma> {
+        self.vmas.range(..=addr)
+            .next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| vma.contains(addr))
+    }
+}
+
+/// Maps in the page containing `addr` if it belongs to one of the current page
+/// context's VMAs and hasn't been touched yet. Returns whether the page is
+/// mapped now, which it may already have been by another thread of the
+/// process. The caller holds the page context's tables, see
+/// `PageCtx::lock_tables`.
+pub fn fault_in(page_ctx: &PageCtx, addr: u64, crit: &Critical) -> Result<bool, MemoryExhausted> {
+    let page = addr & !(PAGE_SIZE as u64 - 1);
+
+    if page::is_mapped(page as *const u8) {
+        return Ok(true);
+    }
+
+    let vma = match page_ctx.vmas().lock().find(page) {
+        Some(vma) => *vma,
+        None => return Ok(false),
+    };
+
+    if fault_in_huge(&vma, page, crit) {
+        return Ok(true);
+    }
+
+    let phys = match vma.backing {
+        Backing::Anonymous => alloc_zeroed(crit)?,
+        // anything missing was unmapped since:
+        Backing::Shared => return Ok(false),
+    };
+
+    let result = unsafe { page::map(phys, page as *mut u8, vma.flags) };
+
+    match result {
+        // whoever mapped it first, the page is there now:
+        Ok(()) | Err(MapError::AlreadyMapped) => Ok(true),
+        Err(MapError::CannotAllocatePageTable) => Err(MemoryExhausted),
+        Err(MapError::WrongHalf) => panic!("vma::fault_in: VMA outside of the user half"),
+    }
+}
+
+// anonymous VMAs get a whole huge page at a time wherever they cover one, as
+// long as there's physical memory to spare for it. returns whether it mapped
+// one, if not the page is faulted in on its own:
+fn fault_in_huge(vma: &Vma, page: u64, crit: &Critical) -> bool {
+    let huge_page = page & !(HUGE_PAGE_SIZE as u64 - 1);
+
+    let covered = vma.backing == Backing::Anonymous
+        && vma.start <= huge_page
+        && huge_page + HUGE_PAGE_SIZE as u64 <= vma.end;
+
+    // no good if part of it has been mapped in already:
+    if !covered || !page::can_map_huge(huge_page as *const u8, crit) {
+        return false;
+    }
+
+    let block = match phys::alloc_contiguous(HUGE_PAGE_PAGES) {
+        Ok(block) => block,
+        Err(MemoryExhausted) => return false,
+    };
+
+    unsafe { page::map_huge(&block, huge_page as *mut u8, vma.flags).is_ok() }
+}
+
+fn alloc_zeroed(crit: &Critical) -> Result<Phys, MemoryExhausted> {
+    let phys = phys::alloc()?;
+
+    unsafe {
+        let mapped = page::temp_map::<u8>(phys.raw(), crit);
+        ptr::write_bytes(mapped.ptr(), 0, PAGE_SIZE);
+    }
+
+    Ok(phys)
+}
+
+/// Removes a range of the current page context's address space: both its VMAs
+/// and any pages mapped in it, whether faulted in or mapped directly.
+pub fn unmap_range(page_ctx: &PageCtx, range: &PageRange, crit: &Critical)
+    -> Result<(), MemoryExhausted>
+{
+    let _tables = page_ctx.lock_tables();
+
+    split_partial_huge(range, crit)?;
+    page_ctx.vmas().lock().remove_range(range.start(), range.end())?;
+    unmap_pages(range, crit);
+
+    Ok(())
+}
+
+// huge pages can only hang over the ends of a range. those are split up before
+// anything else is changed, so that running out of memory leaves the range as
+// it was:
+fn split_partial_huge(range: &PageRange, crit: &Critical) -> Result<(), MemoryExhausted> {
+    if range.start() == range.end() {
+        return Ok(());
+    }
+
+    for addr in [range.start(), range.end() - 1].iter() {
+        let huge_page = addr & !(HUGE_PAGE_SIZE as u64 - 1);
+
+        if huge_page < range.start() || huge_page + HUGE_PAGE_SIZE as u64 > range.end() {
+            page::split_huge(*addr as *const u8, crit)?;
+        }
+    }
+
+    Ok(())
+}
+
+// the range must have been through split_partial_huge:
+fn unmap_pages(range: &PageRange, crit: &Critical) {
+    let mut batch = tlb::Batch::new();
+    let mut addr = range.start();
+
+    while addr < range.end() {
+        if page::is_huge(addr as *const u8, crit) {
+            unsafe { page::unmap_huge(addr as *mut u8, &mut batch) }
+                .expect("page::unmap_huge in vma::unmap_pages");
+
+            addr += HUGE_PAGE_SIZE as u64;
+        } else {
+            // untouched pages aren't mapped, which is fine:
+            let _ = unsafe { page::unmap(addr as *mut u8, &mut batch) };
+
+            addr += PAGE_SIZE as u64;
+        }
+    }
+}
+
+/// Changes the protection of a range of the current page context's address
+/// space, which must be covered by VMAs. Pages already mapped in are changed
+/// as well as the VMAs, whose flags apply to pages faulted in later.
+pub fn protect_range(page_ctx: &PageCtx, range: &PageRange, flags: PageFlags, crit: &Critical)
+    -> SysResult<()>
+{
+    let _tables = page_ctx.lock_tables();
+
+    if !page_ctx.vmas().lock().is_covered(range.start(), range.end()) {
+        return Err(SysError::BadPointer);
+    }
+
+    split_partial_huge(range, crit)?;
+    page_ctx.vmas().lock().protect_range(range.start(), range.end(), flags)?;
+
+    let writable = flags.contains(PageFlags::WRITE);
+    let executable = !flags.contains(PageFlags::NO_EXECUTE);
+    let mut batch = tlb::Batch::new();
+    let mut addr = range.start();
+
+    while addr < range.end() {
+        let step = if page::is_huge(addr as *const u8, crit) {
+            HUGE_PAGE_SIZE as u64
+        } else {
+            PAGE_SIZE as u64
+        };
+
+        // untouched pages aren't mapped, and get the VMA's new flags when
+        // they're faulted in:
+        let _ = unsafe { page::protect(addr as *mut u8, writable, executable, &mut batch) };
+
+        addr += step;
+    }
+
+    Ok(())
+}
+
+fn page_align_up(addr: u64) -> Option<u64> {
+    addr.checked_add(PAGE_SIZE as u64 - 1)
+        .map(|addr| addr & !(PAGE_SIZE as u64 - 1))
+}
+
+/// Moves the program break of the current page context, growing or shrinking
+/// the heap to match, and returns the new break.
+pub fn set_brk(page_ctx: &PageCtx, brk: u64, crit: &Critical) -> SysResult<u64> {
+    let _tables = page_ctx.lock_tables();
+    let mut vmas = page_ctx.vmas().lock();
+
+    let heap = vmas.heap.ok_or(SysError::InvalidOperation)?;
+
+    if brk < heap.start || brk > page::USER_END {
+        return Err(SysError::IllegalValue);
+    }
+
+    let old_end = page_align_up(heap.brk).ok_or(SysError::IllegalValue)?;
+    let new_end = page_align_up(brk).ok_or(SysError::IllegalValue)?;
+
+    let released = if new_end > old_end {
+        let range = PageRange::new(old_end, (new_end - old_end) / PAGE_SIZE as u64)?;
+
+        // pages mapped by AllocPage aren't covered by any VMA:
+        user::validate_available(&range, crit)?;
+
+        vmas.insert(Vma {
+            start: old_end,
+            end: new_end,
+            flags: PageFlags::PRESENT | PageFlags::WRITE | PageFlags::USER | PageFlags::NO_EXECUTE,
+            backing: Backing::Anonymous,
+        })?;
+
+        None
+    } else if new_end < old_end {
+        let range = PageRange::new(new_end, (old_end - new_end) / PAGE_SIZE as u64)?;
+        split_partial_huge(&range, crit)?;
+        vmas.remove_range(range.start(), range.end())?;
+        Some(range)
+    } else {
+        None
+    };
+
+    vmas.heap = Some(Heap { start: heap.start, brk });
+    drop(vmas);
+
+    if let Some(range) = released {
+        unmap_pages(&range, crit);
+    }
+
+    Ok(brk)
+}
@@ -1,23 +1,54 @@
+pub mod evq;
 pub mod file;
+pub mod poll;
 
 use core::num::NonZeroU64;
 use core::marker::PhantomData;
 
 use alloc_collections::btree_map::BTreeMap;
-use interface::{SysResult, SysError};
+use bitflags::bitflags;
+use interface::{SysResult, SysError, RIGHT_DUP, RIGHT_MAP, RIGHT_READ, RIGHT_SIGNAL, RIGHT_WRITE};
 
 use crate::fs::vfs;
+use crate::ipc::channel::{Channel, Listener};
+use crate::ipc::endpoint::{Endpoint, Reply};
+use crate::time::timer::Timer;
 use crate::mem::kalloc::GlobalAlloc;
 use crate::mem::MemoryExhausted;
 use crate::mem::page::PageCtx;
-use crate::sync::{Arc, Mutex};
-use crate::task::{TaskId, TaskMap};
-use crate::util::EarlyInit;
+use crate::mem::shm::SharedMemory;
+use crate::object::evq::EventQueue;
+use crate::sync::Arc;
+use crate::task::Process;
 
 #[derive(Debug)]
 pub enum ObjectKind {
     PageCtx(PageCtx),
     File(vfs::File),
+    SharedMemory(SharedMemory),
+    Channel(Channel),
+    Listener(Listener),
+    EventQueue(EventQueue),
+    Endpoint(Endpoint),
+    Reply(Reply),
+    Timer(Timer),
+}
+
+impl ObjectKind {
+    /// What kind of object this is, for debugging.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ObjectKind::PageCtx(_) => "page context",
+            ObjectKind::File(_) => "file",
+            ObjectKind::SharedMemory(_) => "shared memory",
+            ObjectKind::Channel(_) => "channel",
+            ObjectKind::Listener(_) => "listener",
+            ObjectKind::EventQueue(_) => "event queue",
+            ObjectKind::Endpoint(_) => "endpoint",
+            ObjectKind::Reply(_) => "reply",
+            ObjectKind::Timer(_) => "timer",
+        }
+    }
 }
 
 pub trait ObjectKindT {
@@ -53,6 +84,104 @@ impl ObjectKindT for vfs::File {
     }
 }
 
+impl ObjectKindT for SharedMemory {
+    fn wrap(self) -> ObjectKind {
+        ObjectKind::SharedMemory(self)
+    }
+
+    fn as_ref(kind: &ObjectKind) -> SysResult<&Self> {
+        if let ObjectKind::SharedMemory(ref a) = kind {
+            Ok(a)
+        } else {
+            Err(SysError::WrongObjectKind)
+        }
+    }
+}
+
+impl ObjectKindT for Channel {
+    fn wrap(self) -> ObjectKind {
+        ObjectKind::Channel(self)
+    }
+
+    fn as_ref(kind: &ObjectKind) -> SysResult<&Self> {
+        if let ObjectKind::Channel(ref a) = kind {
+            Ok(a)
+        } else {
+            Err(SysError::WrongObjectKind)
+        }
+    }
+}
+
+impl ObjectKindT for Listener {
+    fn wrap(self) -> ObjectKind {
+        ObjectKind::Listener(self)
+    }
+
+    fn as_ref(kind: &ObjectKind) -> SysResult<&Self> {
+        if let ObjectKind::Listener(ref a) = kind {
+            Ok(a)
+        } else {
+            Err(SysError::WrongObjectKind)
+        }
+    }
+}
+
+impl ObjectKindT for EventQueue {
+    fn wrap(self) -> ObjectKind {
+        ObjectKind::EventQueue(self)
+    }
+
+    fn as_ref(kind: &ObjectKind) -> SysResult<&Self> {
+        if let ObjectKind::EventQueue(ref a) = kind {
+            Ok(a)
+        } else {
+            Err(SysError::WrongObjectKind)
+        }
+    }
+}
+
+impl ObjectKindT for Endpoint {
+    fn wrap(self) -> ObjectKind {
+        ObjectKind::Endpoint(self)
+    }
+
+    fn as_ref(kind: &ObjectKind) -> SysResult<&Self> {
+        if let ObjectKind::Endpoint(ref a) = kind {
+            Ok(a)
+        } else {
+            Err(SysError::WrongObjectKind)
+        }
+    }
+}
+
+impl ObjectKindT for Reply {
+    fn wrap(self) -> ObjectKind {
+        ObjectKind::Reply(self)
+    }
+
+    fn as_ref(kind: &ObjectKind) -> SysResult<&Self> {
+        if let ObjectKind::Reply(ref a) = kind {
+            Ok(a)
+        } else {
+            Err(SysError::WrongObjectKind)
+        }
+    }
+}
+
+impl ObjectKindT for Timer {
+    fn wrap(self) -> ObjectKind {
+        ObjectKind::Timer(self)
+    }
+
+    fn as_ref(kind: &ObjectKind) -> SysResult<&Self> {
+        if let ObjectKind::Timer(ref a) = kind {
+            Ok(a)
+        } else {
+            Err(SysError::WrongObjectKind)
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Object {
     kind: ObjectKind,
@@ -65,6 +194,10 @@ impl Object {
         Arc::new(Object { kind })
     }
 
+    pub fn kind(&self) -> &ObjectKind {
+        &self.kind
+    }
+
     pub fn downcast<T: ObjectKindT>(self: DynObjectRef) -> SysResult<ObjectRef<T>> {
         ObjectRef::from_dyn(self)
     }
@@ -117,49 +250,170 @@ impl Handle {
     }
 }
 
-static TASK_HANDLES: TaskMap<BTreeMap<Handle, DynObjectRef, GlobalAlloc>> = TaskMap::new();
+bitflags! {
+    /// What a handle lets its holder do with the object behind it. Rights can
+    /// be dropped from a handle but never added, and a handle made from
+    /// another, by duplicating it or sending it to another process, has no
+    /// more rights than the original.
+    pub struct Rights: u64 {
+        /// Reading, receiving and waiting for something to arrive.
+        const READ = RIGHT_READ;
+        /// Writing, sending and changing the object's settings.
+        const WRITE = RIGHT_WRITE;
+        /// Mapping the object into an address space, or running in it.
+        const MAP = RIGHT_MAP;
+        /// Making more handles to the object, including by sending it.
+        const DUP = RIGHT_DUP;
+        /// Waiting on the object's events with Poll or an event queue.
+        const SIGNAL = RIGHT_SIGNAL;
+    }
+}
+
+/// An object along with the rights a handle to it carries.
+#[derive(Debug, Clone)]
+pub struct Capability {
+    pub object: DynObjectRef,
+    pub rights: Rights,
+}
+
+type HandleMap = BTreeMap<Handle, Capability, GlobalAlloc>;
 
-pub fn init() {
-    EarlyInit::set(&TASK_HANDLES, Mutex::new(BTreeMap::new()));
+/// The objects a process holds handles to, by handle number. Files, shared
+/// memory and page contexts all share the one namespace, and like file
+/// descriptors a new handle gets the lowest free number. Each handle carries
+/// its own rights, which every syscall that takes it checks.
+#[derive(Debug)]
+pub struct FdTable {
+    handles: HandleMap,
 }
 
-pub fn put(task_id: TaskId, object: DynObjectRef) -> SysResult<Handle> {
-    let mut task_handles = TASK_HANDLES.lock();
+impl FdTable {
+    pub fn new() -> Self {
+        FdTable { handles: BTreeMap::new() }
+    }
 
-    let handles = match task_handles.get_mut(&task_id) {
-        Some(handles) => handles,
-        None => {
-            let handles = BTreeMap::new();
+    fn lowest_free(&self) -> Handle {
+        let mut next = 1;
 
-            task_handles.insert(task_id, handles)
-                .map_err(|_| SysError::MemoryExhausted)?;
+        for handle in self.handles.keys() {
+            if handle.0.get() != next {
+                break;
+            }
 
-            task_handles.get_mut(&task_id).expect("should never fail")
+            next = next.checked_add(1).expect("FdTable::lowest_free: handle wrap around");
         }
-    };
 
-    let new_id = handles.keys().rev().nth(0)
-        .map(|h| Handle(
-            NonZeroU64::new(h.0.get() + 1)
-                .expect("handle wrap around")))
-        .unwrap_or(Handle(NonZeroU64::new(1).expect("impossible")));
+        Handle(NonZeroU64::new(next).expect("impossible"))
+    }
+
+    pub fn put(&mut self, capability: Capability) -> SysResult<Handle> {
+        let handle = self.lowest_free();
+
+        self.handles.insert(handle.clone(), capability)
+            .map_err(|_| SysError::MemoryExhausted)?;
+
+        Ok(handle)
+    }
 
-    handles.insert(new_id.clone(), object)
-        .map_err(|_| SysError::MemoryExhausted)?;
+    /// The object behind `handle`, which must carry at least `rights`.
+    pub fn get(&self, handle: &Handle, rights: Rights) -> SysResult<Capability> {
+        let capability = self.handles.get(handle).ok_or(SysError::BadHandle)?;
 
-    Ok(new_id)
+        if !capability.rights.contains(rights) {
+            return Err(SysError::PermissionDenied);
+        }
+
+        Ok(capability.clone())
+    }
+
+    pub fn release(&mut self, handle: &Handle) -> Option<Capability> {
+        self.handles.remove(handle)
+    }
+
+    /// Drops every right `handle` carries that isn't in `rights`, returning
+    /// the rights it's left with.
+    pub fn restrict(&mut self, handle: &Handle, rights: Rights) -> SysResult<Rights> {
+        let capability = self.handles.get_mut(handle).ok_or(SysError::BadHandle)?;
+        capability.rights &= rights;
+
+        Ok(capability.rights)
+    }
+
+    /// Every handle in the table with its object and rights, lowest handle
+    /// first.
+    pub fn iter(&self) -> impl Iterator<Item = (&Handle, &Capability)> {
+        self.handles.iter()
+    }
+
+    /// Makes `new` a handle to the same object as `old`, with the same rights,
+    /// releasing whatever `new` was a handle to before, which is returned so
+    /// that it can be dropped outside of the lock. `old` must carry DUP.
+    pub fn dup2(&mut self, old: &Handle, new: Handle) -> SysResult<Option<Capability>> {
+        let capability = self.get(old, Rights::DUP)?;
+
+        if *old == new {
+            return Ok(None);
+        }
+
+        let previous = self.handles.remove(&new);
+
+        self.handles.insert(new, capability)
+            .map_err(|_| SysError::MemoryExhausted)?;
+
+        Ok(previous)
+    }
+
+    /// A table with a handle to every object this one has a handle to, under
+    /// the same handle numbers and with the same rights.
+    pub fn try_clone(&self) -> Result<FdTable, MemoryExhausted> {
+        let mut handles = BTreeMap::new();
+
+        for (handle, capability) in self.handles.iter() {
+            handles.insert(handle.clone(), capability.clone())
+                .map_err(|_| MemoryExhausted)?;
+        }
+
+        Ok(FdTable { handles })
+    }
+}
+
+/// Gives the process a handle to `object` carrying every right.
+pub fn put(process: &Process, object: DynObjectRef) -> SysResult<Handle> {
+    put_capability(process, Capability { object, rights: Rights::all() })
 }
 
-pub fn get(task_id: TaskId, handle: Handle) -> Option<DynObjectRef> {
-    TASK_HANDLES.lock().get(&task_id)?.get(&handle).cloned()
+pub fn put_capability(process: &Process, capability: Capability) -> SysResult<Handle> {
+    process.handles().lock().put(capability)
 }
 
-pub fn release(task_id: TaskId, handle: Handle) -> Result<DynObjectRef, ()> {
-    TASK_HANDLES.lock().get_mut(&task_id)
-        .and_then(|map| map.remove(&handle))
+/// The object behind `handle` in the process, failing with PermissionDenied
+/// if the handle doesn't carry all of `rights`.
+pub fn get(process: &Process, handle: Handle, rights: Rights) -> SysResult<DynObjectRef> {
+    process.handles().lock().get(&handle, rights)
+        .map(|capability| capability.object)
+}
+
+/// The object behind `handle` along with its rights, to be handed to another
+/// process. The handle must carry DUP.
+pub fn share(process: &Process, handle: Handle) -> SysResult<Capability> {
+    process.handles().lock().get(&handle, Rights::DUP)
+}
+
+pub fn release(process: &Process, handle: Handle) -> Result<DynObjectRef, ()> {
+    process.handles().lock().release(&handle)
+        .map(|capability| capability.object)
         .ok_or(())
 }
 
-pub fn drop_all_for_task(task_id: TaskId) {
-    TASK_HANDLES.lock().remove(&task_id);
+/// Drops rights from `handle` in the process, see FdTable::restrict.
+pub fn restrict(process: &Process, handle: Handle, rights: Rights) -> SysResult<Rights> {
+    process.handles().lock().restrict(&handle, rights)
+}
+
+/// Makes `new` a handle to the same object as `old` in the process.
+pub fn dup2(process: &Process, old: Handle, new: Handle) -> SysResult<Handle> {
+    let previous = process.handles().lock().dup2(&old, new.clone())?;
+    drop(previous);
+
+    Ok(new)
 }
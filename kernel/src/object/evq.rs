@@ -0,0 +1,325 @@
+use core::fmt::{self, Debug};
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Poll, RawWaker, RawWakerVTable, Waker};
+
+use alloc_collections::btree_map::BTreeMap;
+use arrayvec::ArrayVec;
+use futures::future;
+use interface::{SysError, SysResult, EVQ_MAX_EVENTS};
+
+use crate::mem::kalloc::GlobalAlloc;
+use crate::mem::MemoryExhausted;
+use crate::object::poll::{self, Events};
+use crate::object::{DynObjectRef, Handle};
+use crate::sync::{Arc, Mutex};
+use crate::time;
+use crate::util::{AtomicList, EarlyInit};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct QueueId(u32);
+
+struct Registration {
+    object: DynObjectRef,
+    events: Events,
+    edge: bool,
+    data: u64,
+    // what was last reported, for edge triggered registrations:
+    last: Events,
+}
+
+struct State {
+    registrations: BTreeMap<Handle, Registration, GlobalAlloc>,
+    // registrations whose object may have become ready since they were last
+    // looked at. only these are polled by `wait`:
+    ready: BTreeMap<Handle, (), GlobalAlloc>,
+    // a registration couldn't be put on `ready`, so every one of them has to
+    // be polled next time:
+    rescan: bool,
+}
+
+struct Shared {
+    id: QueueId,
+    state: Mutex<State>,
+    wakers: AtomicList<Waker>,
+}
+
+/// A set of objects that a task can wait on all at once, like epoll. Objects
+/// wake the queue when they may have become ready, so waiting costs in
+/// proportion to the number of objects that are ready rather than the number
+/// registered.
+pub struct EventQueue {
+    shared: Arc<Shared>,
+}
+
+/// An event returned by `EventQueue::wait`: the data the object was
+/// registered with, and the events it's ready for.
+#[derive(Debug, Clone, Copy)]
+pub struct Event {
+    pub data: u64,
+    pub events: Events,
+}
+
+pub type EventBatch = ArrayVec<[Event; EVQ_MAX_EVENTS as usize]>;
+
+// registration wakers find their queue through here, so that they only need
+// to carry the queue's id and the handle:
+static QUEUES: EarlyInit<Mutex<BTreeMap<QueueId, Arc<Shared>, GlobalAlloc>>> = EarlyInit::new();
+
+pub fn init() {
+    EarlyInit::set(&QUEUES, Mutex::new(BTreeMap::new()));
+}
+
+fn alloc_queue_id() -> QueueId {
+    static NEXT_QUEUE_ID: AtomicU64 = AtomicU64::new(0);
+    QueueId(NEXT_QUEUE_ID.fetch_add(1, Ordering::SeqCst) as u32)
+}
+
+impl EventQueue {
+    pub fn new() -> Result<EventQueue, MemoryExhausted> {
+        let shared = Arc::new(Shared {
+            id: alloc_queue_id(),
+            state: Mutex::new(State {
+                registrations: BTreeMap::new(),
+                ready: BTreeMap::new(),
+                rescan: false,
+            }),
+            wakers: AtomicList::new(),
+        })?;
+
+        QUEUES.lock().insert(shared.id, shared.clone())
+            .map_err(|_| MemoryExhausted)?;
+
+        Ok(EventQueue { shared })
+    }
+
+    /// Registers interest in `events` on `object`, under `handle`, replacing
+    /// any registration already under it. Edge triggered registrations report
+    /// events only as they happen rather than for as long as they last.
+    pub fn add(&self, handle: Handle, object: DynObjectRef, events: Events, edge: bool, data: u64)
+        -> SysResult<()>
+    {
+        // the handle has to fit in a registration's waker:
+        if handle.into_u64() > u32::max_value() as u64 {
+            return Err(SysError::IllegalValue);
+        }
+
+        let registration = Registration {
+            object,
+            events,
+            edge,
+            data,
+            last: Events::empty(),
+        };
+
+        let previous = {
+            let mut state = self.shared.state.lock();
+
+            let previous = state.registrations.remove(&handle);
+
+            state.registrations.insert(handle.clone(), registration)
+                .map_err(|_| SysError::MemoryExhausted)?;
+
+            // it's polled on the next wait, which registers its waker:
+            if state.ready.insert(handle, ()).is_err() {
+                state.rescan = true;
+            }
+
+            previous
+        };
+
+        drop(previous);
+        self.wake_waiters();
+
+        Ok(())
+    }
+
+    pub fn remove(&self, handle: &Handle) -> SysResult<()> {
+        let registration = {
+            let mut state = self.shared.state.lock();
+            state.ready.remove(handle);
+            state.registrations.remove(handle)
+        };
+
+        registration.map(drop).ok_or(SysError::BadHandle)
+    }
+
+    fn wake_waiters(&self) {
+        for waker in self.shared.wakers.take_iter() {
+            waker.wake();
+        }
+    }
+
+    // polls the registrations that may be ready, adding up to `max` events to
+    // `batch`. those that turn out not to be are taken off `ready` until
+    // their object wakes them again:
+    fn collect(&self, batch: &mut EventBatch, max: usize) -> Result<(), MemoryExhausted> {
+        let candidates = {
+            let mut state = self.shared.state.lock();
+
+            let mut candidates = BTreeMap::<Handle, DynObjectRef, GlobalAlloc>::new();
+
+            let rescan = state.rescan;
+            state.rescan = false;
+
+            for (handle, registration) in state.registrations.iter() {
+                if rescan || state.ready.get(handle).is_some() {
+                    candidates.insert(handle.clone(), registration.object.clone())
+                        .map_err(|_| MemoryExhausted)?;
+                }
+            }
+
+            candidates
+        };
+
+        // objects are polled without the state lock, as they may wake the
+        // queue while they're at it:
+        for (handle, object) in candidates.iter() {
+            if batch.len() >= max {
+                // the rest stay on `ready` for the next wait:
+                break;
+            }
+
+            let waker = registration_waker(self.shared.id, handle);
+            let current = poll::poll_ready(object, &waker)?;
+
+            let mut state = self.shared.state.lock();
+
+            let registration = match state.registrations.get_mut(handle) {
+                Some(registration) => registration,
+                // removed while it was polled:
+                None => continue,
+            };
+
+            let current = current & (registration.events | Events::ERR | Events::HUP);
+
+            let reported = if registration.edge {
+                current & !registration.last
+            } else {
+                current
+            };
+
+            registration.last = current;
+            let data = registration.data;
+
+            // level triggered registrations stay ready for as long as they
+            // are, edge triggered ones until their object wakes them again:
+            if current.is_empty() || registration.edge {
+                state.ready.remove(handle);
+            }
+
+            if !reported.is_empty() {
+                batch.push(Event { data, events: reported });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Waits until at least one registered object is ready, or until
+    /// `timeout_ns` has passed if given, returning up to `max` events, which
+    /// must be no more than EVQ_MAX_EVENTS.
+    pub async fn wait(&self, max: usize, timeout_ns: Option<u64>) -> SysResult<EventBatch> {
+        let mut timeout = timeout_ns.map(time::sleep_ns);
+
+        future::poll_fn(|ctx| {
+            // register waker before collecting so that we can't miss an
+            // object becoming ready in between:
+            if let Err(MemoryExhausted) = self.shared.wakers.push_front(ctx.waker().clone()) {
+                return Poll::Ready(Err(SysError::MemoryExhausted));
+            }
+
+            let mut batch = EventBatch::new();
+
+            if let Err(MemoryExhausted) = self.collect(&mut batch, max) {
+                return Poll::Ready(Err(SysError::MemoryExhausted));
+            }
+
+            if !batch.is_empty() {
+                return Poll::Ready(Ok(batch));
+            }
+
+            match timeout {
+                Some(ref mut sleep) => match Pin::new(sleep).poll(ctx) {
+                    Poll::Ready(Ok(())) => Poll::Ready(Ok(batch)),
+                    Poll::Ready(Err(MemoryExhausted)) => Poll::Ready(Err(SysError::MemoryExhausted)),
+                    Poll::Pending => Poll::Pending,
+                },
+                None => Poll::Pending,
+            }
+        }).await
+    }
+}
+
+impl Drop for EventQueue {
+    fn drop(&mut self) {
+        let shared = QUEUES.lock().remove(&self.shared.id);
+        drop(shared);
+
+        self.shared.wakers.take_iter().for_each(drop);
+    }
+}
+
+impl Debug for EventQueue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EventQueue({})", self.shared.id.0)
+    }
+}
+
+// marks a registration as possibly ready and wakes anyone waiting on its
+// queue. the queue may have gone since, in which case there's nothing to do:
+fn notify(queue_id: QueueId, handle: Handle) {
+    let shared = match QUEUES.lock().get(&queue_id) {
+        Some(shared) => shared.clone(),
+        None => return,
+    };
+
+    {
+        let mut state = shared.state.lock();
+
+        if state.registrations.get(&handle).is_none() {
+            return;
+        }
+
+        if state.ready.insert(handle, ()).is_err() {
+            state.rescan = true;
+        }
+    }
+
+    for waker in shared.wakers.take_iter() {
+        waker.wake();
+    }
+}
+
+static REGISTRATION_WAKER_VTABLE: RawWakerVTable = RawWakerVTable::new(
+    registration_waker_clone,
+    registration_waker_wake,
+    registration_waker_wake_by_ref,
+    registration_waker_drop,
+);
+
+// the queue id goes in the top half of the data, the handle in the bottom:
+fn registration_waker(queue_id: QueueId, handle: &Handle) -> Waker {
+    let data = (queue_id.0 as u64) << 32 | handle.into_u64();
+    unsafe { Waker::from_raw(RawWaker::new(data as *const (), &REGISTRATION_WAKER_VTABLE)) }
+}
+
+unsafe fn registration_waker_clone(data: *const ()) -> RawWaker {
+    RawWaker::new(data, &REGISTRATION_WAKER_VTABLE)
+}
+
+unsafe fn registration_waker_wake(data: *const ()) {
+    let data = data as u64;
+    let queue_id = QueueId((data >> 32) as u32);
+
+    if let Some(handle) = Handle::from_u64(data & 0xffff_ffff) {
+        notify(queue_id, handle);
+    }
+}
+
+unsafe fn registration_waker_wake_by_ref(data: *const ()) {
+    registration_waker_wake(data);
+}
+
+unsafe fn registration_waker_drop(_data: *const ()) {}
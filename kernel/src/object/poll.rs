@@ -0,0 +1,113 @@
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Poll, Waker};
+
+use arrayvec::ArrayVec;
+use bitflags::bitflags;
+use futures::future;
+use interface::{SysError, SysResult, POLLERR, POLLHUP, POLLIN, POLLNVAL, POLLOUT, POLL_MAX};
+
+use crate::console::tty;
+use crate::fs::vfs::File;
+use crate::mem::MemoryExhausted;
+use crate::object::{DynObjectRef, ObjectKind};
+use crate::time;
+
+bitflags! {
+    pub struct Events: u64 {
+        const IN    = POLLIN;
+        const OUT   = POLLOUT;
+        const ERR   = POLLERR;
+        const HUP   = POLLHUP;
+        const NVAL  = POLLNVAL;
+    }
+}
+
+impl Events {
+    // reported whether they're asked for or not:
+    fn always() -> Events {
+        Events::ERR | Events::HUP | Events::NVAL
+    }
+}
+
+/// What a `poll` entry waits on.
+pub enum Target {
+    Object(DynObjectRef),
+    /// A handle that isn't open, which is always reported as NVAL.
+    BadHandle,
+    /// Nothing, the entry is never ready.
+    Skip,
+}
+
+/// One object for `poll` to wait on.
+pub struct PollEntry {
+    pub target: Target,
+    pub events: Events,
+    pub revents: Events,
+}
+
+pub type PollEntries = ArrayVec<[PollEntry; POLL_MAX as usize]>;
+
+/// Returns the events an object is ready for, and has `waker` woken when
+/// that may have changed. Objects that can't be waited on are never ready.
+pub fn poll_ready(object: &DynObjectRef, waker: &Waker) -> Result<Events, MemoryExhausted> {
+    match object.kind() {
+        ObjectKind::File(File::Console) => {
+            let readable = tty::poll_readable(waker)?;
+            Ok(Events::OUT | if readable { Events::IN } else { Events::empty() })
+        }
+        ObjectKind::File(File::Fs(file)) => file.poll_ready(waker),
+        ObjectKind::File(File::PipeReader(reader)) => reader.poll_ready(waker),
+        ObjectKind::File(File::PipeWriter(writer)) => writer.poll_ready(waker),
+        ObjectKind::Channel(channel) => channel.poll_ready(waker),
+        ObjectKind::Listener(listener) => listener.poll_ready(waker),
+        ObjectKind::Timer(timer) => timer.poll_ready(waker),
+        ObjectKind::PageCtx(_)
+            | ObjectKind::SharedMemory(_)
+            | ObjectKind::EventQueue(_)
+            | ObjectKind::Endpoint(_)
+            | ObjectKind::Reply(_) => Ok(Events::empty()),
+    }
+}
+
+/// Waits until at least one of the entries is ready for one of the events it
+/// asks for, or until `timeout_ns` has passed if given, filling in
+/// `revents`. Returns the number of entries that are ready, which is 0 on
+/// timeout.
+pub async fn poll(entries: &mut PollEntries, timeout_ns: Option<u64>) -> SysResult<usize> {
+    let mut timeout = timeout_ns.map(time::sleep_ns);
+
+    future::poll_fn(|ctx| {
+        let mut ready = 0;
+
+        // every object is asked with our waker before any is found to be
+        // ready, so that whichever becomes ready first wakes us:
+        for entry in entries.iter_mut() {
+            let events = match entry.target {
+                Target::Object(ref object) => poll_ready(object, ctx.waker())
+                    .map_err(|_| SysError::MemoryExhausted)?,
+                Target::BadHandle => Events::NVAL,
+                Target::Skip => Events::empty(),
+            };
+
+            entry.revents = events & (entry.events | Events::always());
+
+            if !entry.revents.is_empty() {
+                ready += 1;
+            }
+        }
+
+        if ready > 0 {
+            return Poll::Ready(Ok(ready));
+        }
+
+        match timeout {
+            Some(ref mut sleep) => match Pin::new(sleep).poll(ctx) {
+                Poll::Ready(Ok(())) => Poll::Ready(Ok(0)),
+                Poll::Ready(Err(MemoryExhausted)) => Poll::Ready(Err(SysError::MemoryExhausted)),
+                Poll::Pending => Poll::Pending,
+            },
+            None => Poll::Pending,
+        }
+    }).await
+}
@@ -0,0 +1,153 @@
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::config::MAX_CPUS;
+use crate::mem::MemoryExhausted;
+use crate::mem::page::{self, PageFlags, PAGE_SIZE};
+use crate::mem::phys;
+
+// every per-CPU static lives in the .percpu section, which is never used
+// directly. instead each CPU gets its own copy of the whole section, called its
+// block, and GS.base points at the block while the CPU runs kernel code. the
+// BSP's block is reserved by the linker in .percpu_bsp and set up in start.asm,
+// the APs' blocks are allocated here before they are started:
+extern "C" {
+    static _percpu: u8;
+    static _percpu_end: u8;
+    static _percpu_bsp: u8;
+}
+
+// the CPUs whose blocks have been set up, one bit per CPU index. the BSP's
+// is set up before any Rust code runs:
+static ALLOCATED: AtomicU64 = AtomicU64::new(1);
+
+// the AP blocks live in PML4 entry 508, one fixed size slot per CPU:
+const PERCPU_REGION: u64 = 0xfffffe0000000000;
+const SLOT_SIZE: u64 = 0x100000;
+
+// the linker reserves room for this at the start of .percpu, see linker.ld.
+// must match the BSP setup in start.asm:
+#[repr(C)]
+struct Header {
+    // address of the block itself, so that it can be found through GS:
+    base: u64,
+    index: u64,
+    // scratch space for syscall_entry in isrs.asm, which has nowhere else to
+    // keep the user's rsp while it switches stacks:
+    user_rsp: u64,
+    // the stack syscall_entry switches to, the same one the TSS has for
+    // interrupts. see interrupt::set_kernel_stack:
+    kernel_stack: u64,
+}
+
+/// Declares per-CPU statics. Each CPU sees its own copy of the value through
+/// `PerCpu::get`:
+///
+///     crate::percpu! {
+///         static COUNT: AtomicU64 = AtomicU64::new(0);
+///     }
+#[macro_export]
+macro_rules! percpu {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)+) => {
+        $(
+            $(#[$attr])*
+            #[link_section = ".percpu"]
+            $vis static $name: $crate::percpu::PerCpu<$ty> = $crate::percpu::PerCpu::new($init);
+        )+
+    }
+}
+
+/// A per-CPU static, declared with `percpu!`.
+pub struct PerCpu<T> {
+    // the template every block is copied from, never accessed in place:
+    template: T,
+}
+
+unsafe impl<T: Sync> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    pub const fn new(value: T) -> Self {
+        PerCpu { template: value }
+    }
+
+    fn offset(&self) -> u64 {
+        &self.template as *const T as u64 - template_start()
+    }
+
+    /// Returns the calling CPU's copy of the value.
+    pub fn get(&'static self) -> &'static T {
+        unsafe { &*((current_block() + self.offset()) as *const T) }
+    }
+
+    /// Returns the given CPU's copy of the value. The CPU's block must have
+    /// been allocated, which happens before it is even started.
+    pub fn for_cpu(&'static self, cpu: usize) -> &'static T {
+        // the APs come online in no particular order, so a CPU's index may
+        // well be past the number of them online so far:
+        assert!(ALLOCATED.load(Ordering::SeqCst) & (1 << cpu) != 0, "PerCpu::for_cpu called for CPU with no block");
+
+        unsafe { &*((block(cpu) + self.offset()) as *const T) }
+    }
+}
+
+fn template_start() -> u64 {
+    unsafe { &_percpu as *const u8 as u64 }
+}
+
+fn template_size() -> u64 {
+    unsafe { &_percpu_end as *const u8 as u64 - template_start() }
+}
+
+fn current_block() -> u64 {
+    let base: u64;
+    unsafe { asm!("movq %gs:0, $0" : "=r"(base) ::: "volatile"); }
+    base
+}
+
+fn block(cpu: usize) -> u64 {
+    if cpu == 0 {
+        unsafe { &_percpu_bsp as *const u8 as u64 }
+    } else {
+        PERCPU_REGION + cpu as u64 * SLOT_SIZE
+    }
+}
+
+/// Returns the index of the calling CPU, between 0 and MAX_CPUS.
+pub fn index() -> usize {
+    let index: u64;
+    unsafe { asm!("movq %gs:8, $0" : "=r"(index) ::: "volatile"); }
+    index as usize
+}
+
+/// Sets the stack the calling CPU's syscall_entry switches to.
+pub fn set_kernel_stack(stack_top: u64) {
+    unsafe { asm!("movq $0, %gs:24" :: "r"(stack_top) :: "volatile"); }
+}
+
+/// Allocates and initialises the block for an AP, returning its address for
+/// the AP to load into GS.base.
+pub fn alloc(cpu: usize) -> Result<u64, MemoryExhausted> {
+    assert!(cpu > 0 && cpu < MAX_CPUS, "percpu::alloc called with bad CPU index");
+    assert!(template_size() <= SLOT_SIZE, ".percpu larger than a slot");
+
+    let base = block(cpu);
+    let size = template_size();
+
+    for offset in (0..size).step_by(PAGE_SIZE) {
+        let phys = phys::alloc()?;
+
+        unsafe {
+            page::map(phys, (base + offset) as *mut u8, PageFlags::PRESENT | PageFlags::WRITE | PageFlags::NO_EXECUTE)
+                .map_err(|_| MemoryExhausted)?;
+        }
+    }
+
+    unsafe {
+        ptr::copy_nonoverlapping(template_start() as *const u8, base as *mut u8, size as usize);
+        ptr::write(base as *mut Header, Header { base, index: cpu as u64, user_rsp: 0, kernel_stack: 0 });
+    }
+
+    ALLOCATED.fetch_or(1 << cpu, Ordering::SeqCst);
+
+    Ok(base)
+}
@@ -0,0 +1,173 @@
+use core::ptr;
+
+use interface::SysError;
+use x86_64::instructions::port::Port;
+use x86_64::instructions::tables::{lidt, DescriptorTablePointer};
+
+use crate::acpi::{self, fadt, ADDRESS_SPACE_IO, ADDRESS_SPACE_MEMORY};
+use crate::critical;
+use crate::device::ps2;
+use crate::mem::kvirt;
+use crate::mem::page::PAGE_SIZE;
+use crate::mem::phys::RawPhys;
+use crate::time;
+
+// bits of the PM1 control registers:
+const PM1_SCI_ENABLE: u16 = 1 << 0;
+const PM1_SLEEP_TYPE_SHIFT: u16 = 10;
+const PM1_SLEEP_ENABLE: u16 = 1 << 13;
+
+// the soft off state:
+const SLEEP_STATE_S5: u8 = 5;
+
+// how long the firmware gets to hand ACPI over, and each way of resetting to
+// take effect before the next is tried:
+const ACPI_ENABLE_TIMEOUT_MS: u64 = 1000;
+const RESET_TIMEOUT_MS: u64 = 100;
+
+// the reset control register chipsets have had since the PIIX. setting the
+// reset bit with the hard reset bit set resets the whole machine:
+const PORT_RESET_CONTROL: u16 = 0xcf9;
+const RESET_CONTROL_HARD: u8 = 1 << 1;
+const RESET_CONTROL_RESET: u8 = 1 << 2;
+
+#[derive(Debug)]
+pub enum PowerError {
+    /// There's no FADT, or it has no PM1 control block.
+    NoFadt,
+    /// The DSDT has no \_S5 object, or one that couldn't be read.
+    NoSleepState,
+    /// The firmware didn't hand ACPI over to the OS.
+    AcpiEnableTimeout,
+    /// The machine was told to turn off, and didn't.
+    StillOn,
+}
+
+impl From<PowerError> for SysError {
+    fn from(e: PowerError) -> Self {
+        match e {
+            PowerError::NoFadt | PowerError::NoSleepState => SysError::InvalidOperation,
+            PowerError::AcpiEnableTimeout | PowerError::StillOn => SysError::IoError,
+        }
+    }
+}
+
+/// Turns the machine off by putting it into ACPI sleep state S5. Only returns
+/// if that couldn't be done, saying why. Nothing is written back to disk
+/// first, that's the caller's job.
+pub fn shutdown() -> Result<!, PowerError> {
+    let fadt = fadt::fadt()
+        .filter(|fadt| fadt.pm1a_control != 0)
+        .ok_or(PowerError::NoFadt)?;
+
+    let (sleep_type_a, sleep_type_b) = acpi::sleep_types(SLEEP_STATE_S5)
+        .ok_or(PowerError::NoSleepState)?;
+
+    crate::println!("power: shutting down");
+
+    let _crit = critical::begin();
+
+    unsafe {
+        enable_acpi(&fadt)?;
+
+        let mut pm1a = Port::<u16>::new(fadt.pm1a_control as u16);
+        let value = pm1a.read() & PM1_SCI_ENABLE;
+        pm1a.write(value | (sleep_type_a as u16) << PM1_SLEEP_TYPE_SHIFT | PM1_SLEEP_ENABLE);
+
+        if fadt.pm1b_control != 0 {
+            let mut pm1b = Port::<u16>::new(fadt.pm1b_control as u16);
+            let value = pm1b.read() & PM1_SCI_ENABLE;
+            pm1b.write(value | (sleep_type_b as u16) << PM1_SLEEP_TYPE_SHIFT | PM1_SLEEP_ENABLE);
+        }
+    }
+
+    spin_ms(RESET_TIMEOUT_MS);
+
+    Err(PowerError::StillOn)
+}
+
+/// Resets the machine. Tries the FADT's reset register, then the reset
+/// control register at 0xcf9, then the keyboard controller, and if none of
+/// those work, triple faults. Nothing is written back to disk first, that's
+/// the caller's job.
+pub fn reboot() -> ! {
+    crate::println!("power: rebooting");
+
+    let _crit = critical::begin();
+
+    unsafe {
+        if let Some(fadt) = fadt::fadt() {
+            if fadt.flags & fadt::FLAG_RESET_REGISTER != 0 {
+                reset_register(&fadt);
+                spin_ms(RESET_TIMEOUT_MS);
+            }
+        }
+
+        let mut reset_control = Port::<u8>::new(PORT_RESET_CONTROL);
+        reset_control.write(RESET_CONTROL_HARD);
+        reset_control.write(RESET_CONTROL_HARD | RESET_CONTROL_RESET);
+        spin_ms(RESET_TIMEOUT_MS);
+
+        if ps2::pulse_reset().is_ok() {
+            spin_ms(RESET_TIMEOUT_MS);
+        }
+
+        // with no IDT, the breakpoint can't be delivered, nor the double
+        // fault that follows, and the CPU resets:
+        lidt(&DescriptorTablePointer { limit: 0, base: 0 });
+        asm!("int3" :::: "volatile");
+    }
+
+    loop {}
+}
+
+// hands ACPI over from SMM to the OS, if the firmware hasn't already, so that
+// the PM1 registers can be used:
+unsafe fn enable_acpi(fadt: &fadt::Fadt) -> Result<(), PowerError> {
+    let mut pm1a = Port::<u16>::new(fadt.pm1a_control as u16);
+
+    if pm1a.read() & PM1_SCI_ENABLE != 0 || fadt.smi_command == 0 || fadt.acpi_enable == 0 {
+        return Ok(());
+    }
+
+    Port::<u8>::new(fadt.smi_command as u16).write(fadt.acpi_enable);
+
+    let deadline = time::monotonic_ns() + ACPI_ENABLE_TIMEOUT_MS * 1_000_000;
+
+    while pm1a.read() & PM1_SCI_ENABLE == 0 {
+        if time::monotonic_ns() >= deadline {
+            return Err(PowerError::AcpiEnableTimeout);
+        }
+
+        core::sync::atomic::spin_loop_hint();
+    }
+
+    Ok(())
+}
+
+unsafe fn reset_register(fadt: &fadt::Fadt) {
+    let register = match fadt.reset_register {
+        Some(register) => register,
+        None => return,
+    };
+
+    match register.space {
+        ADDRESS_SPACE_IO => Port::<u8>::new(register.address as u16).write(fadt.reset_value),
+        ADDRESS_SPACE_MEMORY => {
+            let offset = register.address as usize % PAGE_SIZE;
+
+            if let Ok(mapped) = kvirt::map_mmio(RawPhys(register.address - offset as u64), 1) {
+                ptr::write_volatile(mapped.as_ptr().add(offset), fadt.reset_value);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn spin_ms(ms: u64) {
+    let deadline = time::monotonic_ns() + ms * 1_000_000;
+
+    while time::monotonic_ns() < deadline {
+        core::sync::atomic::spin_loop_hint();
+    }
+}
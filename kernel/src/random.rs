@@ -0,0 +1,276 @@
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::cpu;
+use crate::fs::devfs::{self, Device, DeviceKind};
+use crate::fs::vfs::FsFuture;
+use crate::sync::{Arc, Mutex};
+use crate::util::EarlyInit;
+
+// how many times the TSC is sampled for jitter at boot:
+const JITTER_ROUNDS: usize = 256;
+
+// "expand 32-byte k", which starts every ChaCha20 block:
+const CHACHA_CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+const CHACHA_ROUNDS: usize = 20;
+const BLOCK_WORDS: usize = 16;
+const BLOCK_SIZE: usize = BLOCK_WORDS * 4;
+
+// how many rounds of the ChaCha permutation stir each sample into the pool:
+const MIX_ROUNDS: usize = 8;
+
+// how many samples the pool takes in before the generator is rekeyed from it:
+const RESEED_SAMPLES: usize = 64;
+
+// how many interrupts each CPU folds together before adding them to the pool
+// as a single sample:
+const INTERRUPT_BATCH: u64 = 64;
+
+// how much is generated under the lock at once. the key is replaced after each
+// chunk:
+const CHUNK_SIZE: usize = 1024;
+
+struct Rng {
+    // samples are stirred into the pool with the ChaCha permutation, which
+    // never loses any of what's already in there:
+    pool: [u32; BLOCK_WORDS],
+    samples: usize,
+    // the generator is ChaCha20 with this key. it's replaced after every
+    // request, so that what came out before can't be worked out from it:
+    key: [u32; 8],
+    counter: u64,
+}
+
+static RNG: EarlyInit<Mutex<Rng>> = EarlyInit::new();
+
+crate::percpu! {
+    // interrupt timings are folded together here without taking the lock:
+    static INTERRUPT_POOL: AtomicU64 = AtomicU64::new(0);
+    static INTERRUPT_COUNT: AtomicU64 = AtomicU64::new(0);
+}
+
+/// Seeds the pool from RDSEED and RDRAND, where the CPU has them, and TSC
+/// jitter, and adds /dev/random and /dev/urandom. Must come after devfs::init.
+pub fn init() {
+    let mut rng = Rng {
+        pool: [0; BLOCK_WORDS],
+        samples: 0,
+        key: [0; 8],
+        counter: 0,
+    };
+
+    for _ in 0..4 {
+        if let Some(seed) = cpu::rdseed() {
+            rng.mix(seed);
+        }
+
+        if let Some(value) = cpu::rdrand() {
+            rng.mix(value);
+        }
+    }
+
+    rng.mix(jitter());
+    rng.reseed();
+
+    EarlyInit::set(&RNG, Mutex::new(rng));
+
+    crate::println!("random: seeded from{}{} TSC jitter",
+        if cpu::has(cpu::Features::RDSEED) { " RDSEED," } else { "" },
+        if cpu::has(cpu::Features::RDRAND) { " RDRAND," } else { "" });
+
+    // there's no telling how much entropy there is, so both are the same:
+    devfs::register(b"random", Arc::new(Random).expect("random::init: Arc::new"))
+        .expect("random::init: register random");
+
+    devfs::register(b"urandom", Arc::new(Random).expect("random::init: Arc::new"))
+        .expect("random::init: register urandom");
+}
+
+// the jitter in how long the TSC says the same work takes, which is there
+// whether or not the CPU has a hardware generator:
+fn jitter() -> u64 {
+    let mut seed = unsafe { _rdtsc() };
+
+    for _ in 0..JITTER_ROUNDS {
+        let start = unsafe { _rdtsc() };
+        seed = splitmix(seed ^ start);
+        let elapsed = unsafe { _rdtsc() }.wrapping_sub(start);
+        seed = splitmix(seed.rotate_left(17) ^ elapsed);
+    }
+
+    seed
+}
+
+// the splitmix64 finaliser, which spreads every bit of its input across its
+// output:
+fn splitmix(x: u64) -> u64 {
+    let x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    let x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// Fills `buf` with bytes from the CSPRNG.
+pub fn fill(buf: &mut [u8]) {
+    for chunk in buf.chunks_mut(CHUNK_SIZE) {
+        let mut rng = RNG.lock();
+
+        if rng.samples >= RESEED_SAMPLES {
+            if let Some(value) = cpu::rdrand() {
+                rng.mix(value);
+            }
+
+            rng.reseed();
+        }
+
+        rng.generate(chunk);
+    }
+}
+
+/// A random number from the CSPRNG.
+pub fn next_u64() -> u64 {
+    let mut bytes = [0; 8];
+    fill(&mut bytes);
+    u64::from_ne_bytes(bytes)
+}
+
+/// Stirs `sample` into the pool. It's never trusted to be random, only hoped
+/// to be a little unpredictable.
+pub fn add_sample(sample: u64) {
+    if let Some(rng) = EarlyInit::try_get(&RNG) {
+        rng.lock().mix(sample);
+    }
+}
+
+/// Records when an interrupt arrived. Called for every interrupt, so it only
+/// takes the pool's lock once every INTERRUPT_BATCH of them.
+pub fn add_interrupt(vector: u64) {
+    let sample = unsafe { _rdtsc() } ^ vector.rotate_right(8);
+
+    let pool = INTERRUPT_POOL.get();
+    pool.store(pool.load(Ordering::Relaxed).rotate_left(7) ^ sample, Ordering::Relaxed);
+
+    if INTERRUPT_COUNT.get().fetch_add(1, Ordering::Relaxed) % INTERRUPT_BATCH == INTERRUPT_BATCH - 1 {
+        add_sample(pool.swap(0, Ordering::Relaxed));
+    }
+}
+
+impl Rng {
+    fn mix(&mut self, sample: u64) {
+        self.pool[0] ^= sample as u32;
+        self.pool[1] ^= (sample >> 32) as u32;
+        permute(&mut self.pool, MIX_ROUNDS);
+        self.samples += 1;
+    }
+
+    // derives a new key from the old one and the pool together, through the
+    // ChaCha20 block function, which can't be run backwards:
+    fn reseed(&mut self) {
+        let mut input = self.pool;
+
+        for (word, key) in input.iter_mut().zip(self.key.iter()) {
+            *word ^= key;
+        }
+
+        let output = block(&input);
+        self.key.copy_from_slice(&output[0..8]);
+
+        // nothing the key was made from is left as it was:
+        permute(&mut self.pool, CHACHA_ROUNDS);
+        self.samples = 0;
+    }
+
+    fn generate(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(BLOCK_SIZE) {
+            let output = self.keystream();
+
+            for (bytes, word) in chunk.chunks_mut(4).zip(output.iter()) {
+                bytes.copy_from_slice(&word.to_le_bytes()[0..bytes.len()]);
+            }
+        }
+
+        let output = self.keystream();
+        self.key.copy_from_slice(&output[0..8]);
+    }
+
+    fn keystream(&mut self) -> [u32; BLOCK_WORDS] {
+        let mut state = [0; BLOCK_WORDS];
+        state[0..4].copy_from_slice(&CHACHA_CONSTANTS);
+        state[4..12].copy_from_slice(&self.key);
+        state[12] = self.counter as u32;
+        state[13] = (self.counter >> 32) as u32;
+
+        self.counter = self.counter.wrapping_add(1);
+
+        block(&state)
+    }
+}
+
+// the ChaCha20 block function, the permutation with its input added back on:
+fn block(input: &[u32; BLOCK_WORDS]) -> [u32; BLOCK_WORDS] {
+    let mut state = *input;
+    permute(&mut state, CHACHA_ROUNDS);
+
+    for (word, input) in state.iter_mut().zip(input.iter()) {
+        *word = word.wrapping_add(*input);
+    }
+
+    state
+}
+
+// `rounds` rounds of ChaCha, alternating between columns and diagonals:
+fn permute(state: &mut [u32; BLOCK_WORDS], rounds: usize) {
+    for round in 0..rounds {
+        if round % 2 == 0 {
+            quarter_round(state, 0, 4, 8, 12);
+            quarter_round(state, 1, 5, 9, 13);
+            quarter_round(state, 2, 6, 10, 14);
+            quarter_round(state, 3, 7, 11, 15);
+        } else {
+            quarter_round(state, 0, 5, 10, 15);
+            quarter_round(state, 1, 6, 11, 12);
+            quarter_round(state, 2, 7, 8, 13);
+            quarter_round(state, 3, 4, 9, 14);
+        }
+    }
+}
+
+fn quarter_round(state: &mut [u32; BLOCK_WORDS], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+// /dev/random and /dev/urandom. writes are stirred into the pool:
+#[derive(Debug)]
+struct Random;
+
+impl Device for Random {
+    fn kind(&self) -> DeviceKind {
+        DeviceKind::Character
+    }
+
+    fn read<'a>(&'a self, _pos: u64, buf: &'a mut [u8]) -> FsFuture<'a, usize> {
+        FsFuture::new(async move {
+            fill(buf);
+            Ok(buf.len())
+        })
+    }
+
+    fn write<'a>(&'a self, _pos: u64, buf: &'a [u8]) -> FsFuture<'a, usize> {
+        FsFuture::new(async move {
+            for chunk in buf.chunks(8) {
+                let mut bytes = [0; 8];
+                bytes[0..chunk.len()].copy_from_slice(chunk);
+                add_sample(u64::from_ne_bytes(bytes));
+            }
+
+            Ok(buf.len())
+        })
+    }
+}
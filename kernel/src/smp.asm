@@ -0,0 +1,158 @@
+%include "kernel/src/consts.asm"
+
+extern ap_main
+extern gdtr
+extern idtr
+extern tcb
+
+; the trampoline is assembled into the kernel image and copied down to
+; AP_TRAMPOLINE_BASE before application processors are started. it must only
+; refer to its own labels through AP_ADDR:
+%define AP_ADDR(label) (AP_TRAMPOLINE_BASE + (label) - ap_trampoline)
+
+%define AP_SEG_CODE32   0x08
+%define AP_SEG_DATA     0x10
+%define AP_SEG_CODE64   0x18
+
+section .rodata
+
+bits 16
+global ap_trampoline
+ap_trampoline:
+    ; the startup IPI starts us in real mode at AP_TRAMPOLINE_BASE
+    cli
+    cld
+    xor ax, ax
+    mov ds, ax
+
+    o32 lgdt [AP_ADDR(ap_gdtr)]
+
+    ; enable protected mode
+    mov eax, cr0
+    or eax, 1
+    mov cr0, eax
+
+    jmp dword AP_SEG_CODE32:AP_ADDR(ap_protected_mode)
+
+bits 32
+ap_protected_mode:
+    mov ax, AP_SEG_DATA
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    ; enable the same cr4 extensions as the BSP
+    %define CR4_PAGE_SIZE_EXT (1 << 4)
+    %define CR4_PHYS_ADDR_EXT (1 << 5)
+    mov eax, cr4
+    or eax, CR4_PAGE_SIZE_EXT | CR4_PHYS_ADDR_EXT
+    mov cr4, eax
+
+    ; load the page context prepared for us, which identity maps this page
+    mov eax, [AP_ADDR(ap_params.cr3)]
+    mov cr3, eax
+
+    ; enable long mode, and no execute if the BSP's page tables use it
+    mov ecx, 0xc0000080
+    rdmsr
+    or eax, [AP_ADDR(ap_params.efer)]
+    wrmsr
+
+    ; enable paging
+    mov eax, cr0
+    or eax, 1 << 31
+    mov cr0, eax
+
+    jmp AP_SEG_CODE64:AP_ADDR(ap_long_mode)
+
+bits 64
+ap_long_mode:
+    ; every AP starts at once, so claim a cpu index and the stack that goes
+    ; with it
+    mov eax, 1
+    lock xadd [AP_ADDR(ap_params.next_cpu)], eax
+
+    cmp eax, MAX_CPUS
+    jae .park
+
+    mov edi, eax
+    mov rsp, [AP_ADDR(ap_params.stacks) + rdi * 8]
+    mov rsi, [AP_ADDR(ap_params.percpu) + rdi * 8]
+    mov rax, [AP_ADDR(ap_params.entry)]
+    jmp rax
+
+.park:
+    ; more CPUs than we have room for, leave this one halted forever
+    cli
+    hlt
+    jmp .park
+
+align 8
+ap_gdt:
+    dq 0
+    dq 0x00cf9a000000ffff   ; 32 bit code
+    dq 0x00cf92000000ffff   ; data
+    dq 0x00af9a000000ffff   ; 64 bit code
+.end:
+
+ap_gdtr:
+    dw (ap_gdt.end - ap_gdt) - 1
+    dd AP_ADDR(ap_gdt)
+
+; filled in by smp::init, layout must match ApParams in smp.rs
+align 8
+global ap_params
+ap_params:
+    .cr3        dq 0
+    .entry      dq 0
+    .next_cpu   dd 0
+    .efer       dd 0
+    .stacks     times MAX_CPUS dq 0
+    .percpu     times MAX_CPUS dq 0
+
+global ap_trampoline_end
+ap_trampoline_end:
+
+section .text
+
+; the trampoline jumps here in the higher half, with the cpu index in rdi, the
+; address of its per-CPU block in rsi and the stack set up
+global ap_entry
+ap_entry:
+    ; switch from the trampoline GDT to the kernel GDT
+    lgdt [rel gdtr]
+
+    push SEG_KCODE
+    lea rax, [rel .reload_cs]
+    push rax
+    retfq
+
+.reload_cs:
+    mov ax, SEG_KDATA
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    lidt [rel idtr]
+
+    ; set up TLS, same as the BSP
+    mov ecx, 0xc0000100 ; MSR_FS_BASE
+    mov rax, tcb
+    mov rdx, rax
+    shr rdx, 32
+    wrmsr
+
+    ; point GS.base at our per-CPU block, and start user GS.base at zero, same
+    ; as the BSP
+    mov ecx, 0xc0000101 ; MSR_GS_BASE
+    mov rax, rsi
+    mov rdx, rax
+    shr rdx, 32
+    wrmsr
+
+    mov ecx, 0xc0000102 ; MSR_KERNEL_GS_BASE
+    xor eax, eax
+    xor edx, edx
+    wrmsr
+
+    call ap_main
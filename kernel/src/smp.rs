@@ -0,0 +1,226 @@
+mod lapic;
+
+use core::cmp;
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+use crate::acpi;
+use crate::config::MAX_CPUS;
+use crate::cpu::{self, Features};
+use crate::critical;
+use crate::interrupt;
+use crate::mem::MemoryExhausted;
+use crate::mem::kstack::KernelStack;
+use crate::mem::page::{self, PageCtx, PAGE_SIZE};
+use crate::mem::phys::{NodeId, Phys, RawPhys};
+use crate::mem::tlb;
+use crate::percpu;
+use crate::task;
+use crate::time;
+
+// must match AP_TRAMPOLINE_BASE in consts.asm:
+const AP_TRAMPOLINE_BASE: u64 = 0x7000;
+
+// how long the APs get to check in, in milliseconds:
+const AP_CHECK_IN_MS: usize = 100;
+
+// must match ap_params in smp.asm:
+#[repr(C)]
+struct ApParams {
+    cr3: u64,
+    entry: u64,
+    next_cpu: u32,
+    efer: u32,
+    stacks: [u64; MAX_CPUS],
+    percpu: [u64; MAX_CPUS],
+}
+
+extern "C" {
+    static ap_trampoline: u8;
+    static ap_trampoline_end: u8;
+    static ap_params: ApParams;
+    fn ap_entry();
+}
+
+// number of CPUs that have come online, including the BSP:
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+static BSP_APIC_ID: AtomicU8 = AtomicU8::new(0);
+
+crate::percpu! {
+    // for sending the CPU IPIs:
+    static APIC_ID: AtomicU8 = AtomicU8::new(0);
+}
+
+/// Returns the index of the calling CPU, between 0 and MAX_CPUS. The BSP is
+/// always index 0.
+pub fn cpu_index() -> usize {
+    percpu::index()
+}
+
+pub fn cpu_count() -> usize {
+    ONLINE.load(Ordering::SeqCst)
+}
+
+/// Returns the local APIC ID of the BSP, which interrupts are sent to.
+pub fn bsp_apic_id() -> u8 {
+    BSP_APIC_ID.load(Ordering::SeqCst)
+}
+
+/// Returns the NUMA node the calling CPU is on, for allocating memory close
+/// to it. Without the ACPI tables to say otherwise every CPU is on node 0.
+pub fn cpu_node() -> NodeId {
+    NodeId(0)
+}
+
+/// Interrupts every CPU but the calling one with the given vector.
+pub unsafe fn send_ipi_all(vector: u8) {
+    lapic::send_fixed_all(vector);
+}
+
+/// Interrupts the given CPU, which must be online, to have it look at its run
+/// queue.
+pub fn reschedule(cpu: usize) {
+    let apic_id = APIC_ID.for_cpu(cpu).load(Ordering::SeqCst);
+
+    // an interrupt handler sending an IPI of its own mustn't get in between
+    // the two ICR writes:
+    critical::section(|| unsafe {
+        lapic::send_fixed(apic_id, lapic::RESCHEDULE_VECTOR);
+    });
+}
+
+/// Acknowledges an interrupt delivered by the local APIC, as opposed to the
+/// PIC.
+pub unsafe fn eoi() {
+    lapic::eoi();
+}
+
+/// Starts the local APIC timer of the calling CPU counting down from `count`,
+/// interrupting once when it gets to zero. A count of zero stops it.
+pub unsafe fn timer_oneshot(count: u32) {
+    lapic::timer_oneshot(count);
+}
+
+/// Returns what the local APIC timer of the calling CPU has left to count
+/// down.
+pub fn timer_current() -> u32 {
+    lapic::timer_current()
+}
+
+/// Enables the local APIC of the BSP, whose timer the clock runs on.
+// Safety: must not be called more than once
+pub unsafe fn init_bsp() -> Result<(), MemoryExhausted> {
+    // there's nothing else to keep time with:
+    assert!(cpu::has(Features::APIC), "smp::init_bsp: no local APIC");
+
+    lapic::init()?;
+    lapic::enable();
+
+    BSP_APIC_ID.store(lapic::id(), Ordering::SeqCst);
+    APIC_ID.get().store(lapic::id(), Ordering::SeqCst);
+
+    Ok(())
+}
+
+/// Starts every application processor in the system.
+pub async fn init() -> Result<(), MemoryExhausted> {
+    // the trampoline switches on paging while running from low memory, so it
+    // needs a page context with the trampoline identity mapped. the APs keep
+    // using it afterwards, so it lives forever:
+    let page_ctx = PageCtx::new()?;
+
+    let trampoline_phys = unsafe { Phys::new(RawPhys(AP_TRAMPOLINE_BASE)) };
+
+    page_ctx.map_low_identity(&trampoline_phys)
+        .map_err(|_| MemoryExhausted)?;
+
+    let mut params = ApParams {
+        cr3: page_ctx.pml4_phys().0,
+        entry: ap_entry as usize as u64,
+        next_cpu: 1,
+        efer: cpu::efer_flags() as u32,
+        stacks: [0; MAX_CPUS],
+        percpu: [0; MAX_CPUS],
+    };
+
+    mem::forget(page_ctx);
+
+    // likewise, the APs never give up their boot stacks:
+    for stack in params.stacks.iter_mut().skip(1) {
+        let kernel_stack = KernelStack::new()?;
+        *stack = kernel_stack.top();
+        mem::forget(kernel_stack);
+    }
+
+    for cpu in 1..MAX_CPUS {
+        params.percpu[cpu] = percpu::alloc(cpu)?;
+    }
+
+    unsafe {
+        let start = &ap_trampoline as *const u8;
+        let len = (&ap_trampoline_end as *const u8).offset_from(start) as usize;
+        let params_offset = (&ap_params as *const ApParams as *const u8).offset_from(start) as usize;
+
+        assert!(len <= PAGE_SIZE, "AP trampoline larger than a page");
+
+        let crit = critical::begin();
+        let mapped = page::temp_map::<u8>(RawPhys(AP_TRAMPOLINE_BASE), &crit);
+        ptr::copy_nonoverlapping(start, mapped.ptr(), len);
+        ptr::write(mapped.ptr().add(params_offset) as *mut ApParams, params);
+    }
+
+    // INIT-SIPI-SIPI. the APs are started all at once, by broadcast, and
+    // count themselves in:
+    unsafe { lapic::send_init_all(); }
+    time::sleep_ns(10_000_000).await?;
+
+    for _ in 0..2 {
+        unsafe { lapic::send_startup_all((AP_TRAMPOLINE_BASE >> 12) as u8); }
+        time::sleep_ns(1_000_000).await?;
+    }
+
+    // give the APs a moment to check in. if the MADT says how many there are,
+    // there's no need to wait once they all have:
+    let expected = acpi::madt::usable_cpus()
+        .map(|cpus| cmp::min(cpus, MAX_CPUS))
+        .unwrap_or(MAX_CPUS);
+
+    for _ in 0..AP_CHECK_IN_MS {
+        if cpu_count() >= expected {
+            break;
+        }
+
+        time::sleep_ns(1_000_000).await?;
+    }
+
+    crate::println!("smp: {} CPUs online", cpu_count());
+
+    Ok(())
+}
+
+#[no_mangle]
+pub extern "C" fn ap_main() -> ! {
+    unsafe {
+        lapic::enable();
+        cpu::init_cpu();
+        cpu::fpu::init_cpu();
+        interrupt::init_ap_tss().expect("interrupt::init_ap_tss");
+        interrupt::init_syscall();
+    }
+
+    APIC_ID.get().store(lapic::id(), Ordering::SeqCst);
+
+    task::init_cpu();
+
+    tlb::accept();
+
+    ONLINE.fetch_add(1, Ordering::SeqCst);
+
+    // the timer goes off every tick from here on, to preempt whatever the
+    // scheduler runs. it's stopped again whenever the AP idles:
+    time::rearm();
+
+    unsafe { task::start() }
+}
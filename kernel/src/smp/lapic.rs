@@ -0,0 +1,130 @@
+use core::ptr;
+
+use x86_64::registers::model_specific::Msr;
+
+use crate::mem::MemoryExhausted;
+use crate::mem::page::{self, PageFlags};
+use crate::mem::phys::{Phys, RawPhys};
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// the local APIC registers are mapped at this address in PML4 entry 509. every
+// CPU's local APIC lives at the same physical address, so one mapping does for
+// all of them:
+const LAPIC_VIRT: u64 = 0xfffffe8000000000;
+
+const REG_ID: usize = 0x020;
+const REG_EOI: usize = 0x0b0;
+const REG_SPURIOUS: usize = 0x0f0;
+const REG_ICR_LOW: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+const REG_LVT_TIMER: usize = 0x320;
+const REG_TIMER_INITIAL: usize = 0x380;
+const REG_TIMER_CURRENT: usize = 0x390;
+const REG_TIMER_DIVIDE: usize = 0x3e0;
+
+const SPURIOUS_ENABLE: u32 = 1 << 8;
+const SPURIOUS_VECTOR: u32 = 0xff;
+
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+// the timer counts down at the bus clock divided by this:
+const TIMER_DIVIDE_16: u32 = 0b0011;
+
+/// Vector of the local APIC timer interrupt. Must match isrs.asm.
+pub const TIMER_VECTOR: u8 = 0x41;
+
+/// Vector of the IPI telling a CPU it has a task queued. Must match isrs.asm.
+pub const RESCHEDULE_VECTOR: u8 = 0x42;
+
+/// Maps the local APIC registers. Must be called on the BSP before any other
+/// function in this module.
+pub unsafe fn init() -> Result<(), MemoryExhausted> {
+    let base = Msr::new(IA32_APIC_BASE).read() & 0x000f_ffff_ffff_f000;
+    let phys = Phys::new(RawPhys(base));
+
+    page::map(phys, LAPIC_VIRT as *mut u8,
+            PageFlags::PRESENT | PageFlags::WRITE | PageFlags::NO_EXECUTE | PageFlags::CACHE_DISABLED)
+        .map_err(|_| MemoryExhausted)
+}
+
+/// Software enables the local APIC of the calling CPU.
+pub unsafe fn enable() {
+    let mut apic_base = Msr::new(IA32_APIC_BASE);
+    let value = apic_base.read();
+    apic_base.write(value | APIC_BASE_ENABLE);
+
+    write(REG_SPURIOUS, SPURIOUS_ENABLE | SPURIOUS_VECTOR);
+}
+
+/// Returns the local APIC ID of the calling CPU.
+pub fn id() -> u8 {
+    unsafe { (read(REG_ID) >> 24) as u8 }
+}
+
+pub unsafe fn eoi() {
+    write(REG_EOI, 0);
+}
+
+/// Sends an INIT IPI to every CPU but the calling one.
+pub unsafe fn send_init_all() {
+    send_ipi_all(ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+}
+
+/// Sends a startup IPI to every CPU but the calling one, starting them in real
+/// mode at the given page.
+pub unsafe fn send_startup_all(page: u8) {
+    send_ipi_all(ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT | page as u32);
+}
+
+/// Sends a fixed interrupt with the given vector to every CPU but the calling
+/// one.
+pub unsafe fn send_fixed_all(vector: u8) {
+    send_ipi_all(ICR_LEVEL_ASSERT | vector as u32);
+}
+
+/// Sends a fixed interrupt with the given vector to the CPU with the given
+/// local APIC ID.
+pub unsafe fn send_fixed(apic_id: u8, vector: u8) {
+    send_ipi((apic_id as u32) << 24, ICR_LEVEL_ASSERT | vector as u32);
+}
+
+/// Starts the timer of the calling CPU counting down from `count`, raising
+/// TIMER_VECTOR once when it gets to zero. A count of zero stops it.
+pub unsafe fn timer_oneshot(count: u32) {
+    write(REG_TIMER_DIVIDE, TIMER_DIVIDE_16);
+    // one-shot is mode 0, so the vector is all there is to the LVT entry:
+    write(REG_LVT_TIMER, TIMER_VECTOR as u32);
+    write(REG_TIMER_INITIAL, count);
+}
+
+/// Returns what the timer of the calling CPU has left to count down.
+pub fn timer_current() -> u32 {
+    unsafe { read(REG_TIMER_CURRENT) }
+}
+
+unsafe fn send_ipi_all(icr: u32) {
+    send_ipi(0, ICR_ALL_EXCLUDING_SELF | icr);
+}
+
+unsafe fn send_ipi(icr_high: u32, icr_low: u32) {
+    write(REG_ICR_HIGH, icr_high);
+    write(REG_ICR_LOW, icr_low);
+
+    while read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+        core::sync::atomic::spin_loop_hint();
+    }
+}
+
+unsafe fn read(reg: usize) -> u32 {
+    ptr::read_volatile((LAPIC_VIRT as usize + reg) as *const u32)
+}
+
+unsafe fn write(reg: usize, value: u32) {
+    ptr::write_volatile((LAPIC_VIRT as usize + reg) as *mut u32, value)
+}
@@ -7,8 +7,12 @@ extern _bss
 extern _rodata_end
 extern _bss_end
 extern _tls_end
+extern _percpu
+extern _percpu_end
+extern _percpu_bsp
 extern main
 extern phys_init
+extern initrd_init
 extern isrs_init
 extern console_init
 
@@ -25,6 +29,20 @@ start:
 
 bits 32
 protected_mode:
+    ; the loader put the initrd straight after the kernel image, where bss
+    ; goes. move it to the first page boundary past bss, copying backwards as
+    ; the two may overlap
+    mov ecx, [EARLY_INITRD_LEN]
+    mov esi, [EARLY_INITRD_BASE]
+    mov edi, EARLY_PHYS(_bss_end) + PAGE_SIZE - 1
+    and edi, ~(PAGE_SIZE - 1)
+    mov [EARLY_INITRD_BASE], edi
+    lea esi, [esi + ecx - 1]
+    lea edi, [edi + ecx - 1]
+    std
+    rep movsb
+    cld
+
     ; zero bss pages
     xor eax, eax
     mov edi, EARLY_PHYS(_bss)
@@ -107,10 +125,13 @@ long_mode:
     mov r8, _rodata_end
     mov r9, _bss_end
     mov r10, stackguard
+    mov r11, dfstackguard
 map_kernel:
-    ; don't map stack guard:
+    ; don't map stack guards:
     cmp rsi, r10
     je .commit
+    cmp rsi, r11
+    je .commit
     ; build pt k entry:
     mov rax, rsi
     mov rbx, KERNEL_BASE - KERNEL_PHYS_BASE
@@ -146,10 +167,39 @@ higher_half:
     shr rdx, 32
     wrmsr
 
-    ; init phys allocator
+    ; set up the BSP's per-CPU block from the template, see percpu.rs
+    mov rsi, _percpu
+    mov rdi, _percpu_bsp
+    mov rcx, _percpu_end
+    sub rcx, rsi
+    rep movsb
+
+    mov rax, _percpu_bsp
+    mov [rax + 0], rax          ; header base
+    mov qword [rax + 8], 0      ; header index
+    mov qword [rax + 16], 0     ; header user rsp
+    mov rbx, stackend
+    mov [rax + 24], rbx         ; header kernel stack
+
+    mov ecx, 0xc0000101 ; MSR_GS_BASE
+    mov rdx, rax
+    shr rdx, 32
+    wrmsr
+
+    ; user code starts out with a zero GS.base, swapped in on return to user
+    ; mode
+    mov ecx, 0xc0000102 ; MSR_KERNEL_GS_BASE
+    xor eax, eax
+    xor edx, edx
+    wrmsr
+
+    ; init phys allocator, keeping it clear of the kernel and initrd
+    mov edx, [EARLY_INITRD_BASE]
+    add edx, [EARLY_INITRD_LEN]
+    add rdx, PAGE_SIZE - 1
+    and rdx, ~(PAGE_SIZE - 1)
     mov rdi, EARLY_MEMORY_MAP
     mov rsi, [EARLY_MEMORY_MAP_LEN]
-    mov rdx, EARLY_PHYS(_bss_end)
     call phys_init
 
     ; init console
@@ -157,6 +207,11 @@ higher_half:
     mov rsi, EARLY_BIOS_FONT
     call console_init
 
+    ; record where the initrd is before low memory goes
+    mov edi, [EARLY_INITRD_BASE]
+    mov esi, [EARLY_INITRD_LEN]
+    call initrd_init
+
     ; unmap low memory
     xor rax, rax
     mov rbx, EARLY_PHYS(pml4)
@@ -166,25 +221,16 @@ higher_half:
     mov rax, cr3
     mov cr3, rax
 
-    ; point tss gdt entry to tss
-    mov [rel gdt.tss_size_0_15], word tss.end - tss
-    mov rax, tss
-    mov [rel gdt.tss_base_0_15], ax
-    shr rax, 16
-    mov [rel gdt.tss_base_16_23], al
-    shr rax, 8
-    mov [rel gdt.tss_base_24_31], al
-    shr rax, 8
-    mov [rel gdt.tss_base_32_63], eax
-
     ; reload GDT in high memory
     mov rax, qword gdt
     mov [rel gdtr.offset], rax
     lgdt [rel gdtr]
 
-    ; load tss
-    mov ax, SEG_TSS
-    ltr ax
+    ; load the BSP's tss
+    xor edi, edi
+    mov rsi, stackend
+    mov rdx, dfstackend
+    call load_tss
 
     ; initialize interrupts
     call isrs_init
@@ -195,7 +241,39 @@ higher_half:
     push 0
     jmp main
 
+; points the gdt's tss entry for the cpu index in rdi at that cpu's tss and
+; loads it, with rsi as rsp0 and rdx as the double fault stack. each cpu needs
+; an entry of its own, as ltr marks the entry busy:
+global load_tss
+load_tss:
+    imul rax, rdi, TSS_SIZE
+    lea rcx, [rel tss]
+    add rax, rcx
+    mov [rax + 4], rsi          ; rsp0
+    mov [rax + 36], rdx         ; ist1, double faults only, see isrs.asm
+
+    mov rcx, rdi
+    shl rcx, 4
+    lea r8, [rel gdt.tss]
+    add r8, rcx
+    mov word [r8], TSS_SIZE     ; size
+    mov [r8 + 2], ax            ; base 0..15
+    shr rax, 16
+    mov [r8 + 4], al            ; base 16..23
+    mov byte [r8 + 5], 0x89     ; access
+    mov byte [r8 + 6], 1 << 4   ; flags and limit
+    shr rax, 8
+    mov [r8 + 7], al            ; base 24..31
+    shr rax, 8
+    mov [r8 + 8], eax           ; base 32..63
+    mov dword [r8 + 12], 0      ; reserved
+
+    lea eax, [rcx + SEG_TSS]
+    ltr ax
+    ret
+
 section .data
+global gdtr
 gdtr:
     .size   dw (gdt.end - gdt) - 1 ; size
     .offset dq EARLY_PHYS(gdt)     ; offset
@@ -207,29 +285,26 @@ gdt:
     dq GDT64_DESCRIPTOR | GDT64_PRESENT | GDT64_READWRITE | GDT64_EXECUTABLE | GDT64_64BIT
     ; kernel data entry
     dq GDT64_DESCRIPTOR | GDT64_PRESENT | GDT64_READWRITE
-    ; user code entry
-    dq GDT64_DESCRIPTOR | GDT64_PRESENT | GDT64_READWRITE | GDT64_EXECUTABLE | GDT64_64BIT | GDT64_USER
     ; user data entry
     dq GDT64_DESCRIPTOR | GDT64_PRESENT | GDT64_READWRITE | GDT64_USER
-    ; tss entry
-    .tss_size_0_15  dw 0
-    .tss_base_0_15  dw 0
-    .tss_base_16_23 db 0
-    .tss_access     db 0x89
-    .tss_flags_lim  db (1 << 4)
-    .tss_base_24_31 db 0
-    .tss_base_32_63 dd 0
-    .tss_reserved   dd 0
+    ; user code entry
+    dq GDT64_DESCRIPTOR | GDT64_PRESENT | GDT64_READWRITE | GDT64_EXECUTABLE | GDT64_64BIT | GDT64_USER
+    ; tss entries, one per cpu from SEG_TSS on. see load_tss:
+.tss:
+    times MAX_CPUS * 2 dq 0
 .end:
 
+global tcb
 tcb:
     ; tcb+0 points to end of TLS block
     dq _tls_end
 
+; one tss per cpu, indexed by cpu index. the stacks are filled in by load_tss:
 global tss
 tss:
+%rep MAX_CPUS
     dd 0                ; reserved
-    dq stackend         ; rsp0
+    dq 0                ; rsp0
     dq 0                ; rsp1
     dq 0                ; rsp2
     dq 0                ; reserved
@@ -242,7 +317,8 @@ tss:
     dq 0                ; ist7
     dq 0                ; reserved
     dw 0                ; reserved
-    dw (tss.end - tss)  ; iopb offset
+    dw TSS_SIZE         ; iopb offset
+%endrep
 .end:
 
 section .bss
@@ -269,3 +345,10 @@ section .stack
     stack times 16 * PAGE_SIZE db 0
     global stackend
     stackend equ $
+    ; double faults switch to their own stack through the IST, so that a
+    ; kernel stack overflow can still be reported:
+    global dfstackguard
+    dfstackguard times PAGE_SIZE db 0
+    dfstack times 4 * PAGE_SIZE db 0
+    global dfstackend
+    dfstackend equ $
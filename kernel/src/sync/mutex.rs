@@ -1,10 +1,19 @@
 use core::ops::{Drop, Deref, DerefMut};
 use core::cell::UnsafeCell;
 use core::fmt::{self, Debug};
+use core::sync::atomic::{self, AtomicUsize, Ordering};
 
 use crate::critical::{self, Critical};
+use crate::smp;
 
+// Mutex::owner value when the mutex is unlocked:
+const UNLOCKED: usize = usize::max_value();
+
+/// A mutex is both a critical section, keeping interrupt handlers on this CPU
+/// out, and a spin lock, keeping other CPUs out.
 pub struct Mutex<T> {
+    // index of the CPU holding the lock, or UNLOCKED:
+    owner: AtomicUsize,
     inner: UnsafeCell<MutexInner<T>>,
 }
 
@@ -18,46 +27,46 @@ unsafe impl<T> Sync for Mutex<T> {}
 
 struct MutexInner<T> {
     value: T,
-    locked: bool,
 }
 
 impl<T> Mutex<T> {
     pub const fn new(value: T) -> Self {
         Mutex {
+            owner: AtomicUsize::new(UNLOCKED),
             inner: UnsafeCell::new(MutexInner {
                 value: value,
-                locked: false,
             })
         }
     }
 
     pub fn locked(&self, _critical: &Critical) -> bool {
-        let inner = unsafe {
-            // ref to Critical proves we're in a critical section, so this is
-            // safe:
-            &*self.inner.get()
-        };
-
-        inner.locked
+        self.owner.load(Ordering::SeqCst) != UNLOCKED
     }
 
     pub fn lock<'a>(&'a self) -> MutexGuard<'a, T> {
         let critical = critical::begin();
+        let cpu = smp::cpu_index();
 
-        if self.locked(&critical) {
+        if self.owner.load(Ordering::SeqCst) == cpu {
+            // spinning would never end, we're the ones holding it:
             panic!("recursive mutex lock!");
         }
 
+        while self.owner.compare_and_swap(UNLOCKED, cpu, Ordering::SeqCst) != UNLOCKED {
+            // the holder may be waiting on us to flush our TLB, and with
+            // interrupts disabled we won't hear about it otherwise:
+            crate::mem::tlb::service();
+            atomic::spin_loop_hint();
+        }
+
         let inner = unsafe {
-            // we are in critical section and have checked that the mutex is not
-            // already locked, so this is safe:
+            // we are in critical section and hold the lock, so this is safe:
             &mut *self.inner.get()
         };
 
-        inner.locked = true;
-
         MutexGuard {
             _critical: critical,
+            owner: &self.owner,
             inner: inner,
         }
     }
@@ -65,12 +74,13 @@ impl<T> Mutex<T> {
 
 pub struct MutexGuard<'a, T> {
     _critical: Critical,
+    owner: &'a AtomicUsize,
     inner: &'a mut MutexInner<T>,
 }
 
 impl<'a, T> Drop for MutexGuard<'a, T> {
     fn drop(&mut self) {
-        self.inner.locked = false;
+        self.owner.store(UNLOCKED, Ordering::SeqCst);
     }
 }
 
@@ -1,22 +1,57 @@
-use core::convert::TryInto;
+use core::cmp;
+use core::convert::{TryFrom, TryInto};
+use core::mem;
+use core::ptr;
+use core::str;
 
+use arrayvec::ArrayVec;
 use bitflags::bitflags;
-use interface::{OK, Syscall, SysError, SysResult};
+use interface::{OK, EXIT_KILLED, Syscall, SysError, SysResult};
+use interface::{MAP_ANONYMOUS, MAP_FIXED, PROT_EXEC, PROT_READ, PROT_WRITE};
+use interface::{ChannelMessage, CHANNEL_HANDLES_MAX, CHANNEL_NAME_MAX};
+use interface::{PollFd, POLL_INFINITE, POLL_MAX};
+use interface::{EvqEvent, EVQ_EDGE, EVQ_MAX_EVENTS};
+use interface::{Utsname, UTSNAME_LEN};
+use interface::{Timespec, CLOCK_MONOTONIC, CLOCK_REALTIME};
+use interface::{REBOOT_POWER_OFF, REBOOT_RESTART};
+use interface::{SCHED_FIFO, SCHED_NORMAL, SCHED_RR};
+use interface::{FILTER_ALLOW, FILTER_DENY, SYSCALL_FILTER_WORDS};
+use interface::{Dirent, MountRequest, Stat, SEEK_CUR, SEEK_END, SEEK_SET};
+use interface::{FILE_KIND_CONSOLE, FILE_KIND_DIRECTORY, FILE_KIND_FILE, FILE_KIND_PIPE};
 
 use crate::interrupt::{TrapFrame, Registers};
 use crate::mem::page::{self, PageFlags, MapError, PageCtx, PAGE_SIZE};
 use crate::mem::phys::{self, Phys, RawPhys};
+use crate::mem::shm::SharedMemory;
+use crate::mem::tlb;
 use crate::mem::user::{self, PageRange};
-use crate::object::{self, Handle, Object, ObjectKind, ObjectRef};
-use crate::fs::vfs::File;
+use crate::mem::kalloc::Box;
+use crate::mem::vma::{self, Backing, Vma};
+use crate::object::{self, Handle, Object, ObjectKind, ObjectRef, Rights};
+use crate::object::evq::EventQueue;
+use crate::object::poll::{Events, PollEntries, PollEntry, Target};
+use crate::fs::pipe;
+use crate::fs::vfs::{DirSink, File, InodeKind, OpenFlags, PathBuf, Permissions, SeekFrom};
+use crate::device::cache;
+use crate::exec;
+use crate::ipc::channel::{self, Channel, Listener, Message};
+use crate::ipc::endpoint::{self, Endpoint, Reply};
+use crate::ipc::futex;
+use crate::power;
+use crate::random;
+use crate::sync::Arc;
 use crate::task;
+use crate::task::signal::{self, Handler, Signal};
+use crate::time;
+use crate::time::timer::Timer;
+use crate::console::tty;
 use crate::{critical, println};
 
 mod args;
-use args::UserArg;
+use args::SyscallArgs;
 
 pub async fn dispatch(frame: &mut TrapFrame) {
-    let result = dispatch0(&mut frame.regs).await;
+    let result = dispatch0(frame).await;
 
     frame.regs.rax = match result {
         Ok(u) => u,
@@ -24,27 +59,111 @@ pub async fn dispatch(frame: &mut TrapFrame) {
     };
 }
 
-async fn dispatch0(regs: &mut Registers) -> SyscallReturn {
+async fn dispatch0(frame: &mut TrapFrame) -> SyscallReturn {
+    let regs = frame.regs.clone();
+
     let syscall = regs.rax
         .try_into()
         .map_err(|()| SysError::BadSyscall)?;
 
+    let args = SyscallArgs::new(&regs);
+
     match syscall {
-        Syscall::AllocPage => alloc_page(regs.rdi, regs.rsi, regs.rdx),
-        Syscall::ReleasePage => release_page(regs.rdi, regs.rsi),
-        Syscall::ModifyPage => modify_page(regs.rdi, regs.rsi, regs.rdx),
-        Syscall::CloneHandle => clone_handle(UserArg::from_reg(regs.rdi)?),
-        Syscall::ReleaseHandle => release_handle(UserArg::from_reg(regs.rdi)?),
+        Syscall::AllocPage => alloc_page(args.get(0)?, args.get(1)?, args.get(2)?),
+        Syscall::ReleasePage => release_page(args.get(0)?, args.get(1)?),
+        Syscall::ModifyPage => modify_page(args.get(0)?, args.get(1)?, args.get(2)?),
+        Syscall::CloneHandle => clone_handle(args.get(0)?),
+        Syscall::ReleaseHandle => release_handle(args.get(0)?),
         Syscall::CreatePageContext => create_page_context(),
-        Syscall::Debug => debug(regs),
-        Syscall::SetPageContext => set_page_context(UserArg::from_reg(regs.rdi)?),
+        Syscall::Debug => debug(&regs),
+        Syscall::SetPageContext => set_page_context(args.get(0)?),
         Syscall::GetPageContext => get_page_context(),
-        Syscall::CreateTask => create_task(UserArg::from_reg(regs.rdi)?, regs.rsi, regs.rdx),
-        Syscall::Exit => exit(UserArg::from_reg(regs.rdi)?),
-        Syscall::MapPhysicalMemory => map_physical_memory(regs.rdi, regs.rsi, regs.rdx, regs.rcx),
-        Syscall::ReadStream => read_stream(UserArg::from_reg(regs.rdi)?, regs.rsi, regs.rdx).await,
-        Syscall::WriteStream => write_stream(UserArg::from_reg(regs.rdi)?, regs.rsi, regs.rdx).await,
-        Syscall::OpenFile => open_file(regs.rdi, regs.rsi, regs.rdx).await,
+        Syscall::CreateTask => create_task(args.get(0)?, args.get(1)?, args.get(2)?),
+        Syscall::Exit => exit(args.get(0)?),
+        Syscall::MapPhysicalMemory => map_physical_memory(args.get(0)?, args.get(1)?, args.get(2)?, args.get(3)?),
+        Syscall::ReadStream => read_stream(args.get(0)?, args.get(1)?, args.get(2)?).await,
+        Syscall::WriteStream => write_stream(args.get(0)?, args.get(1)?, args.get(2)?).await,
+        Syscall::OpenFile => open_file(args.get(0)?, args.get(1)?, args.get(2)?).await,
+        Syscall::SetPriority => set_priority(args.get(0)?),
+        Syscall::GetPriority => get_priority(),
+        Syscall::SetSchedClass => set_sched_class(args.get(0)?, args.get(1)?),
+        Syscall::SetAffinity => set_affinity(args.get(0)?),
+        Syscall::GetAffinity => get_affinity(),
+        // handled by task::dispatch_syscall without involving the task's
+        // kernel future, so it never gets here:
+        Syscall::Yield => Ok(OK),
+        Syscall::Kill => kill(args.get(0)?),
+        Syscall::Sleep => sleep(args.get(0)?).await,
+        Syscall::Fork => fork(frame),
+        Syscall::Exec => exec(frame, args.get(0)?, args.get(1)?, args.get(2)?, args.get(3)?).await,
+        Syscall::Wait => wait(args.get(0)?).await,
+        Syscall::CreateThread => create_thread(args.get(0)?, args.get(1)?),
+        Syscall::Mmap => mmap(args.get(0)?, args.get(1)?, args.get(2)?, args.get(3)?),
+        Syscall::Munmap => munmap(args.get(0)?, args.get(1)?),
+        Syscall::Mprotect => mprotect(args.get(0)?, args.get(1)?, args.get(2)?),
+        Syscall::Brk => brk(args.get(0)?),
+        Syscall::ShmCreate => shm_create(args.get(0)?),
+        Syscall::ShmMap => shm_map(args.get(0)?, args.get(1)?, args.get(2)?),
+        Syscall::Sigaction => sigaction(args.get(0)?, args.get(1)?, args.get(2)?),
+        Syscall::SendSignal => send_signal(args.get(0)?, args.get(1)?),
+        Syscall::Sigreturn => sigreturn(frame, args.get(0)?),
+        // handles are file descriptors, these are the same syscalls under
+        // their usual names:
+        Syscall::Close => release_handle(args.get(0)?),
+        Syscall::Dup => clone_handle(args.get(0)?),
+        Syscall::Dup2 => dup2(args.get(0)?, args.get(1)?),
+        Syscall::Pipe => pipe(args.get(0)?),
+        Syscall::ChannelListen => channel_listen(args.get(0)?, args.get(1)?),
+        Syscall::ChannelConnect => channel_connect(args.get(0)?, args.get(1)?),
+        Syscall::ChannelAccept => channel_accept(args.get(0)?).await,
+        Syscall::ChannelSend => channel_send(args.get(0)?, args.get(1)?).await,
+        Syscall::ChannelRecv => channel_recv(args.get(0)?, args.get(1)?).await,
+        Syscall::FutexWait => futex_wait(args.get(0)?, args.get(1)?).await,
+        Syscall::FutexWake => futex::wake(args.get(0)?, args.get(1)?),
+        Syscall::Poll => poll(args.get(0)?, args.get(1)?, args.get(2)?).await,
+        Syscall::EvqCreate => evq_create(),
+        Syscall::EvqAdd => evq_add(args.get(0)?, args.get(1)?, args.get(2)?, args.get(3)?),
+        Syscall::EvqRemove => evq_remove(args.get(0)?, args.get(1)?),
+        Syscall::EvqWait => evq_wait(args.get(0)?, args.get(1)?, args.get(2)?, args.get(3)?).await,
+        Syscall::EndpointCreate => endpoint_create(),
+        Syscall::Call => call(frame, args.get(0)?).await,
+        Syscall::Receive => receive(frame, args.get(0)?).await,
+        Syscall::Reply => reply(&regs, args.get(0)?),
+        Syscall::SetProcessGroup => set_process_group(args.get(0)?, args.get(1)?),
+        Syscall::GetProcessGroup => get_process_group(args.get(0)?),
+        Syscall::SendGroupSignal => send_group_signal(args.get(0)?, args.get(1)?),
+        Syscall::SetForeground => set_foreground(args.get(0)?, args.get(1)?),
+        Syscall::GetForeground => get_foreground(args.get(0)?),
+        Syscall::TimerCreate => timer_create(args.get(0)?),
+        Syscall::TimerSet => timer_set(args.get(0)?, args.get(1)?, args.get(2)?),
+        Syscall::TimerRead => timer_read(args.get(0)?).await,
+        Syscall::GetProcessId => get_process_id(),
+        Syscall::GetParentProcessId => get_parent_process_id(),
+        Syscall::GetTaskId => get_task_id(),
+        Syscall::Uname => uname(args.get(0)?),
+        Syscall::Ioctl => ioctl(args.get(0)?, args.get(1)?, args.get(2)?).await,
+        Syscall::Seek => seek(args.get(0)?, args.get(1)?, args.get(2)?).await,
+        Syscall::Stat => stat(args.get(0)?, args.get(1)?, args.get(2)?).await,
+        Syscall::Fstat => fstat(args.get(0)?, args.get(1)?).await,
+        Syscall::Getdents => getdents(args.get(0)?, args.get(1)?, args.get(2)?).await,
+        Syscall::Mount => mount(args.get(0)?).await,
+        Syscall::Umount => umount(args.get(0)?, args.get(1)?).await,
+        Syscall::Chdir => chdir(args.get(0)?, args.get(1)?).await,
+        Syscall::Getcwd => getcwd(args.get(0)?, args.get(1)?),
+        Syscall::Chroot => chroot(args.get(0)?, args.get(1)?).await,
+        Syscall::ClockGettime => clock_gettime(args.get(0)?, args.get(1)?),
+        Syscall::Reboot => reboot(args.get(0)?).await,
+        Syscall::SetAslr => set_aslr(args.get(0)?),
+        Syscall::GetRandom => getrandom(args.get(0)?, args.get(1)?),
+        Syscall::SetWriteExecute => set_write_execute(args.get(0)?),
+        Syscall::HandleRestrict => handle_restrict(args.get(0)?, args.get(1)?),
+        Syscall::SyscallFilter => syscall_filter(args.get(0)?, args.get(1)?),
+        Syscall::SetUid => set_uid(args.get(0)?),
+        Syscall::SetGid => set_gid(args.get(0)?),
+        Syscall::GetUid => get_uid(),
+        Syscall::GetGid => get_gid(),
+        Syscall::Unlink => unlink(args.get(0)?, args.get(1)?).await,
+        Syscall::Rename => rename(args.get(0)?, args.get(1)?, args.get(2)?, args.get(3)?).await,
     }
 }
 
@@ -56,8 +175,9 @@ bitflags! {
 
 impl From<UserPageFlags> for PageFlags {
     fn from(user_flags: UserPageFlags) -> PageFlags {
-        // UserPageFlags implies PRESENT and USER:
-        let mut flags = PageFlags::PRESENT | PageFlags::USER;
+        // UserPageFlags implies PRESENT and USER, and pages allocated this
+        // way are for data:
+        let mut flags = PageFlags::PRESENT | PageFlags::USER | PageFlags::NO_EXECUTE;
 
         if user_flags.contains(UserPageFlags::WRITE) {
             flags.insert(PageFlags::WRITE);
@@ -73,6 +193,10 @@ fn alloc_page(virtual_addr: u64, page_count: u64, flags: u64) -> SyscallReturn {
     println!("SYSCALL alloc_page(virt = {:x?}, count = {:x?}, flags = {:x?})",
         virtual_addr,  page_count, flags);
 
+    // other threads of the process mustn't map anything in the range between
+    // checking it's free and mapping it:
+    let page_ctx = task::get_page_ctx();
+    let _tables = page_ctx.object().lock_tables();
     let crit = critical::begin();
 
     let page_range = PageRange::new(virtual_addr, page_count)?;
@@ -103,6 +227,10 @@ fn alloc_page(virtual_addr: u64, page_count: u64, flags: u64) -> SyscallReturn {
                     MapError::CannotAllocatePageTable => {
                         SysError::MemoryExhausted
                     }
+                    MapError::WrongHalf => {
+                        // PageRange only covers the user half
+                        panic!("alloc_page: WrongHalf error should never happen")
+                    }
                 })?;
         }
     }
@@ -113,11 +241,15 @@ fn alloc_page(virtual_addr: u64, page_count: u64, flags: u64) -> SyscallReturn {
 fn release_page(virtual_addr: u64, page_count: u64) -> SyscallReturn {
     println!("SYSCALL release_page");
 
+    let page_ctx = task::get_page_ctx();
+    let _tables = page_ctx.object().lock_tables();
     let crit = critical::begin();
 
     let page_range = PageRange::new(virtual_addr, page_count)?;
     user::validate_map(&page_range, PageFlags::empty(), &crit)?;
 
+    let mut batch = tlb::Batch::new();
+
     for addr in page_range.pages() {
         let addr = addr as *mut u8;
 
@@ -126,7 +258,10 @@ fn release_page(virtual_addr: u64, page_count: u64) -> SyscallReturn {
         unsafe {
             println!("releasing {:?}", addr);
 
-            page::unmap(addr)
+            // pages faulted in as part of a huge page can't be unmapped alone:
+            page::split_huge(addr, &crit)?;
+
+            page::unmap(addr, &mut batch)
                 .expect("release_page: NotMapped error should never happen");
         }
     }
@@ -137,6 +272,8 @@ fn release_page(virtual_addr: u64, page_count: u64) -> SyscallReturn {
 fn modify_page(virtual_addr: u64, page_count: u64, flags: u64) -> SyscallReturn {
     println!("SYSCALL release_page");
 
+    let page_ctx = task::get_page_ctx();
+    let _tables = page_ctx.object().lock_tables();
     let crit = critical::begin();
 
     let page_range = PageRange::new(virtual_addr, page_count)?;
@@ -147,6 +284,8 @@ fn modify_page(virtual_addr: u64, page_count: u64, flags: u64) -> SyscallReturn
 
     let flags = PageFlags::from(flags);
 
+    let mut batch = tlb::Batch::new();
+
     for addr in page_range.pages() {
         let addr = addr as *mut u8;
 
@@ -155,7 +294,12 @@ fn modify_page(virtual_addr: u64, page_count: u64, flags: u64) -> SyscallReturn
         unsafe {
             println!("releasing {:?}", addr);
 
-            page::modify(addr, flags)
+            // give the page a private copy first, or changing its flags could
+            // make a page shared by fork writable:
+            page::copy_on_write(addr, &crit)?;
+            page::split_huge(addr, &crit)?;
+
+            page::modify(addr, flags, &mut batch)
                 .expect("modify_page: NotMapped error should never happen");
         }
     }
@@ -169,8 +313,11 @@ fn map_physical_memory(virtual_addr: u64, physical_addr: u64, page_count: u64, f
     println!("SYSCALL map_physical_memory(virt = {:x?}, phys = {:x?}, count = {:x?}, flags = {:x?})",
         virtual_addr, physical_addr, page_count, flags);
 
-    // TODO - insert capabilities check here. calling process must have DRIVER caps
+    // only drivers map physical memory, and they run as root:
+    require_root()?;
 
+    let page_ctx = task::get_page_ctx();
+    let _tables = page_ctx.object().lock_tables();
     let crit = critical::begin();
 
     let page_range = PageRange::new(virtual_addr, page_count)?;
@@ -199,119 +346,1502 @@ fn map_physical_memory(virtual_addr: u64, physical_addr: u64, page_count: u64, f
     Ok(OK)
 }
 
-fn clone_handle(handle: Handle) -> SyscallReturn  {
-    let object_ref = object::get(task::current(), handle)
-        .ok_or(SysError::BadHandle)?;
+bitflags! {
+    pub struct MmapProt: u64 {
+        const READ = PROT_READ;
+        const WRITE = PROT_WRITE;
+        const EXEC = PROT_EXEC;
+    }
+}
 
-    Ok(object::put(task::current(), object_ref)?.into_u64())
+impl From<MmapProt> for PageFlags {
+    fn from(prot: MmapProt) -> PageFlags {
+        // mapped memory is always readable:
+        let mut flags = PageFlags::PRESENT | PageFlags::USER;
+
+        if prot.contains(MmapProt::WRITE) {
+            flags.insert(PageFlags::WRITE);
+        }
+
+        if !prot.contains(MmapProt::EXEC) {
+            flags.insert(PageFlags::NO_EXECUTE);
+        }
+
+        flags
+    }
 }
 
+// memory that's writable and executable at once lets anything that can write
+// to it run code of its choosing, so it's only allowed for processes that have
+// asked for it:
+fn check_write_execute(prot: MmapProt) -> SysResult<()> {
+    if prot.contains(MmapProt::WRITE | MmapProt::EXEC) && !task::current_process().write_execute() {
+        return Err(SysError::PermissionDenied);
+    }
 
-fn release_handle(handle: Handle) -> SyscallReturn  {
-    object::release(task::current(), handle)
-        .map_err(|_| SysError::BadHandle)?;
+    Ok(())
+}
 
-    Ok(OK)
+bitflags! {
+    pub struct MmapFlags: u64 {
+        const FIXED = MAP_FIXED;
+        const ANONYMOUS = MAP_ANONYMOUS;
+    }
 }
 
-fn create_page_context() -> SyscallReturn {
-    let page_ctx = PageCtx::new()
-        .map_err(|_| SysError::MemoryExhausted)?;
+// the number of pages needed to hold len bytes:
+fn page_count(len: u64) -> SysResult<u64> {
+    let len = len.checked_add(PAGE_SIZE as u64 - 1)
+        .ok_or(SysError::IllegalValue)?;
 
-    let obj = Object::new(ObjectKind::PageCtx(page_ctx))
-        .map_err(|_| SysError::MemoryExhausted)?;
+    Ok(len / PAGE_SIZE as u64)
+}
+
+fn mmap(addr: u64, len: u64, prot: u64, flags: u64) -> SyscallReturn {
+    let prot = MmapProt::from_bits(prot)
+        .ok_or(SysError::IllegalValue)?;
+
+    let flags = MmapFlags::from_bits(flags)
+        .ok_or(SysError::IllegalValue)?;
+
+    if !flags.contains(MmapFlags::ANONYMOUS) {
+        // TODO - file backed mappings
+        return Err(SysError::InvalidOperation);
+    }
+
+    // there's no such thing as an unreadable present page:
+    if !prot.contains(MmapProt::READ) || len == 0 {
+        return Err(SysError::IllegalValue);
+    }
+
+    check_write_execute(prot)?;
 
-    Ok(object::put(task::current(), obj)?.into_u64())
+    let page_count = page_count(len)?;
+    let byte_len = page_count * PAGE_SIZE as u64;
+
+    let page_ctx = task::get_page_ctx();
+    let page_ctx = page_ctx.object();
+
+    let crit = critical::begin();
+
+    let start = if flags.contains(MmapFlags::FIXED) {
+        let page_range = PageRange::new(addr, page_count)?;
+        vma::unmap_range(page_ctx, &page_range, &crit)?;
+        addr
+    } else {
+        // use the address as a hint if it's free, otherwise find some room:
+        let hint_free = user::validate_page_align(addr).is_ok()
+            && addr != 0
+            && PageRange::new(addr, page_count).is_ok()
+            && page_ctx.vmas().lock().is_free(addr, addr + byte_len);
+
+        if hint_free {
+            addr
+        } else {
+            let vmas = page_ctx.vmas().lock();
+            vmas.find_free(byte_len, vmas.mmap_base(), page::USER_END)
+                .ok_or(SysError::MemoryExhausted)?
+        }
+    };
+
+    let page_range = PageRange::new(start, page_count)?;
+
+    // pages mapped by AllocPage or exec aren't covered by any VMA:
+    user::validate_available(&page_range, &crit)?;
+
+    page_ctx.add_vma(Vma {
+        start: page_range.start(),
+        end: page_range.end(),
+        flags: PageFlags::from(prot),
+        backing: Backing::Anonymous,
+    })?;
+
+    Ok(start)
 }
 
-fn debug(regs: &mut Registers) -> SyscallReturn {
-    println!("{:#x?}", regs);
+fn munmap(addr: u64, len: u64) -> SyscallReturn {
+    let page_range = PageRange::new(addr, page_count(len)?)?;
+
+    let crit = critical::begin();
+    vma::unmap_range(task::get_page_ctx().object(), &page_range, &crit)?;
+
     Ok(OK)
 }
 
-fn set_page_context(page_ctx: Handle) -> SyscallReturn {
-    let page_ctx = object::get(task::current(), page_ctx)
-        .ok_or(SysError::BadHandle)?
-        .downcast::<PageCtx>()?
-        .object()
-        .clone();
+fn mprotect(addr: u64, len: u64, prot: u64) -> SyscallReturn {
+    let prot = MmapProt::from_bits(prot)
+        .ok_or(SysError::IllegalValue)?;
 
-    // TODO we need to set task's page ctx too?
-    unsafe { page::set_ctx(page_ctx); }
+    if !prot.contains(MmapProt::READ) {
+        return Err(SysError::IllegalValue);
+    }
+
+    check_write_execute(prot)?;
+
+    let page_range = PageRange::new(addr, page_count(len)?)?;
+
+    let crit = critical::begin();
+    vma::protect_range(task::get_page_ctx().object(), &page_range, PageFlags::from(prot), &crit)?;
 
     Ok(OK)
 }
 
-fn get_page_context() -> SyscallReturn {
+fn brk(addr: u64) -> SyscallReturn {
     let page_ctx = task::get_page_ctx();
 
-    Ok(object::put(task::current(), page_ctx.as_dyn())?.into_u64())
+    if addr == 0 {
+        // just asking where the break is:
+        return page_ctx.object().vmas().lock()
+            .heap()
+            .map(|heap| heap.brk)
+            .ok_or(SysError::InvalidOperation);
+    }
+
+    let crit = critical::begin();
+    vma::set_brk(page_ctx.object(), addr, &crit)
 }
 
-fn create_task(page_ctx: Handle, rip: u64, rsp: u64) -> SyscallReturn {
-    let page_ctx = object::get(task::current(), page_ctx)
-        .ok_or(SysError::BadHandle)?
-        .downcast::<PageCtx>()?
-        .clone();
+fn shm_create(len: u64) -> SyscallReturn {
+    if len == 0 {
+        return Err(SysError::IllegalValue);
+    }
 
-    let filesystem = task::get_filesystem();
+    let shm = ObjectRef::new(SharedMemory::new(page_count(len)?)?)?;
 
-    task::spawn(page_ctx, filesystem, |task| async move {
-        task.setup(TrapFrame::new(rip, rsp)).run_loop().await
-    })?;
+    Ok(object::put(&task::current_process(), shm.as_dyn())?.into_u64())
+}
 
-    Ok(OK)
+fn shm_map(shm: Handle, addr: u64, prot: u64) -> SyscallReturn {
+    let prot = MmapProt::from_bits(prot)
+        .ok_or(SysError::IllegalValue)?;
+
+    if !prot.contains(MmapProt::READ) {
+        return Err(SysError::IllegalValue);
+    }
+
+    check_write_execute(prot)?;
+
+    // a writable mapping is as good as writing:
+    let rights = if prot.contains(MmapProt::WRITE) {
+        Rights::MAP | Rights::READ | Rights::WRITE
+    } else {
+        Rights::MAP | Rights::READ
+    };
+
+    let shm = object::get(&task::current_process(), shm, rights)?
+        .downcast::<SharedMemory>()?;
+
+    let shm = shm.object();
+
+    let page_ctx = task::get_page_ctx();
+    let page_ctx = page_ctx.object();
+
+    let crit = critical::begin();
+
+    // unlike mmap, a non-null address is where the mapping must go:
+    let start = if addr != 0 {
+        addr
+    } else {
+        let vmas = page_ctx.vmas().lock();
+        vmas.find_free(shm.byte_len(), vmas.mmap_base(), page::USER_END)
+            .ok_or(SysError::MemoryExhausted)?
+    };
+
+    let page_range = PageRange::new(start, shm.page_count())?;
+
+    // pages mapped by AllocPage or exec aren't covered by any VMA:
+    user::validate_available(&page_range, &crit)?;
+
+    shm.map(page_ctx, &page_range, PageFlags::from(prot), &crit)?;
+
+    Ok(start)
 }
 
-fn exit(_status: u64) -> SyscallReturn {
-    // TODO implement
-    panic!("process exited!")
+// the new handle has the same rights as the old one:
+fn clone_handle(handle: Handle) -> SyscallReturn  {
+    let capability = object::share(&task::current_process(), handle)?;
+
+    Ok(object::put_capability(&task::current_process(), capability)?.into_u64())
 }
 
-async fn read_stream(file: Handle, buf: u64, nbyte: u64) -> SyscallReturn {
-    let file = object::get(task::current(), file)
-        .ok_or(SysError::BadHandle)?
-        .downcast::<File>()?;
+fn dup2(old: Handle, new: Handle) -> SyscallReturn {
+    Ok(object::dup2(&task::current_process(), old, new)?.into_u64())
+}
+
+// keeps only the rights in `rights`, returning those the handle is left
+// with. RIGHTS_ALL leaves it as it is, and so asks what it has:
+fn handle_restrict(handle: Handle, rights: u64) -> SyscallReturn {
+    let rights = Rights::from_bits(rights)
+        .ok_or(SysError::IllegalValue)?;
+
+    Ok(object::restrict(&task::current_process(), handle, rights)?.bits())
+}
+
+fn pipe(handles_ptr: u64) -> SyscallReturn {
+    // check the pointer up front so that we don't create handles only to
+    // lose them:
+    {
+        let crit = critical::begin();
+        user::validate_write(handles_ptr, 16, &crit)?;
+    }
+
+    let (reader, writer) = pipe::new()?;
+    let reader = ObjectRef::new(File::PipeReader(reader))?;
+    let writer = ObjectRef::new(File::PipeWriter(writer))?;
+
+    let process = task::current_process();
+    let reader = object::put(&process, reader.as_dyn())?;
+
+    let writer = match object::put(&process, writer.as_dyn()) {
+        Ok(writer) => writer,
+        Err(e) => {
+            let _ = object::release(&process, reader);
+            return Err(e);
+        }
+    };
+
+    let mut handles = [0u8; 16];
+    handles[..8].copy_from_slice(&reader.into_u64().to_ne_bytes());
+    handles[8..].copy_from_slice(&writer.into_u64().to_ne_bytes());
 
     let crit = critical::begin();
-    let buf = user::borrow_slice_mut::<u8>(buf, nbyte, &crit)?;
 
-    file.object()
-        .read(buf)
-        .await
-        .map(|sz| sz as u64)
+    if let Err(e) = user::copy_to_user(handles_ptr, &handles, &crit) {
+        drop(crit);
+        let _ = object::release(&process, reader);
+        let _ = object::release(&process, writer);
+        return Err(e);
+    }
+
+    Ok(OK)
 }
 
-async fn write_stream(file: Handle, buf: u64, nbyte: u64) -> SyscallReturn {
-    let file = object::get(task::current(), file)
-        .ok_or(SysError::BadHandle)?
-        .downcast::<File>()?;
+type ChannelName = ArrayVec<[u8; CHANNEL_NAME_MAX as usize]>;
+
+fn channel_listen(name: u64, name_len: u64) -> SyscallReturn {
+    let listener = {
+        let crit = critical::begin();
+        channel::listen(&user::copy_array_from_user::<ChannelName>(name, name_len, &crit)?)?
+    };
+
+    let listener = ObjectRef::new(listener)?;
+
+    Ok(object::put(&task::current_process(), listener.as_dyn())?.into_u64())
+}
+
+fn channel_connect(name: u64, name_len: u64) -> SyscallReturn {
+    let channel = {
+        let crit = critical::begin();
+        channel::connect(&user::copy_array_from_user::<ChannelName>(name, name_len, &crit)?)?
+    };
+
+    let channel = ObjectRef::new(channel)?;
+
+    Ok(object::put(&task::current_process(), channel.as_dyn())?.into_u64())
+}
+
+async fn channel_accept(listener: Handle) -> SyscallReturn {
+    let listener = object::get(&task::current_process(), listener, Rights::READ)?
+        .downcast::<Listener>()?;
+
+    let channel = ObjectRef::new(listener.object().accept().await?)?;
+
+    Ok(object::put(&task::current_process(), channel.as_dyn())?.into_u64())
+}
+
+fn read_message_desc(addr: u64) -> SysResult<ChannelMessage> {
+    let mut bytes = [0u8; mem::size_of::<ChannelMessage>()];
 
     let crit = critical::begin();
-    let buf = user::borrow_slice::<u8>(buf, nbyte, &crit)?;
+    user::copy_from_user(&mut bytes, addr, &crit)?;
 
-    file.object()
-        .write(buf)
-        .await
-        .map(|sz| sz as u64)
+    Ok(unsafe { ptr::read_unaligned(bytes.as_ptr() as *const ChannelMessage) })
 }
 
-bitflags! {
-    pub struct OpenPathFlags: u64 {
-        const WRITE = 0x01;
+async fn channel_send(channel: Handle, message_ptr: u64) -> SyscallReturn {
+    let process = task::current_process();
+
+    let channel = object::get(&process, channel, Rights::WRITE)?
+        .downcast::<Channel>()?;
+
+    let desc = read_message_desc(message_ptr)?;
+
+    if desc.handles_len > CHANNEL_HANDLES_MAX {
+        return Err(SysError::IllegalValue);
+    }
+
+    let message = {
+        let crit = critical::begin();
+
+        let mut message = Message::copy_from_user(desc.data, desc.data_len, &crit)?;
+
+        // the sender keeps its handles, the receiver gets handles of its own
+        // to the same objects, so sending a handle takes DUP:
+        let mut handles = [0u8; 8 * CHANNEL_HANDLES_MAX as usize];
+        let handles = &mut handles[..desc.handles_len as usize * 8];
+        user::copy_from_user(handles, desc.handles, &crit)?;
+
+        for raw in handles.chunks(8) {
+            let raw = u64::from_ne_bytes(raw.try_into().expect("chunk of 8 bytes"));
+
+            let handle = Handle::from_u64(raw)
+                .ok_or(SysError::BadHandle)?;
+
+            message.attach(object::share(&process, handle)?)?;
+        }
+
+        message
+    };
+
+    channel.object().send(message).await?;
+
+    Ok(OK)
+}
+
+async fn channel_recv(channel: Handle, message_ptr: u64) -> SyscallReturn {
+    let process = task::current_process();
+
+    let channel = object::get(&process, channel, Rights::READ)?
+        .downcast::<Channel>()?;
+
+    let desc = read_message_desc(message_ptr)?;
+    let max_handles = desc.handles_len.min(CHANNEL_HANDLES_MAX) as usize;
+
+    let message = channel.object().recv(desc.data_len as usize, max_handles).await?;
+
+    let data_len = message.data().len() as u64;
+    let handles_len = message.handle_count() as u64;
+
+    let crit = critical::begin();
+
+    user::copy_to_user(desc.data, message.data(), &crit)?;
+
+    let mut handles = [0u8; 8 * CHANNEL_HANDLES_MAX as usize];
+
+    for (index, capability) in message.into_handles().enumerate() {
+        let handle = object::put_capability(&process, capability)?;
+        handles[index * 8..][..8].copy_from_slice(&handle.into_u64().to_ne_bytes());
+    }
+
+    if handles_len > 0 {
+        user::copy_to_user(desc.handles, &handles[..handles_len as usize * 8], &crit)?;
     }
+
+    // the lengths in the ChannelMessage become those of the message:
+    user::copy_to_user(message_ptr + 8, &data_len.to_ne_bytes(), &crit)?;
+    user::copy_to_user(message_ptr + 24, &handles_len.to_ne_bytes(), &crit)?;
+
+    Ok(data_len)
 }
 
-async fn open_file(path: u64, path_len: u64, flags: u64) -> SyscallReturn {
-    crate::println!("open_path: {:x?}, {:x?}, {:x?}", path, path_len, flags);
+async fn futex_wait(addr: u64, expected: u32) -> SyscallReturn {
+    futex::wait(addr, expected).await?;
+    Ok(OK)
+}
+
+async fn poll(fds: u64, count: u64, timeout_ns: u64) -> SyscallReturn {
+    if count > POLL_MAX {
+        return Err(SysError::IllegalValue);
+    }
+
+    let fds_len = count * mem::size_of::<PollFd>() as u64;
+    let mut bytes = [0u8; POLL_MAX as usize * mem::size_of::<PollFd>()];
+    let bytes = &mut bytes[..fds_len as usize];
+
+    {
+        let crit = critical::begin();
+        user::validate_write(fds, fds_len, &crit)?;
+        user::copy_from_user(bytes, fds, &crit)?;
+    }
+
+    let process = task::current_process();
+    let mut entries = PollEntries::new();
+
+    for raw in bytes.chunks(mem::size_of::<PollFd>()) {
+        let fd = unsafe { ptr::read_unaligned(raw.as_ptr() as *const PollFd) };
+
+        // handle 0 is skipped, and reported as never ready. a handle without
+        // SIGNAL can't be waited on, and is as good as a bad one:
+        let target = match Handle::from_u64(fd.handle) {
+            Some(handle) => object::get(&process, handle, Rights::SIGNAL)
+                .map(Target::Object)
+                .unwrap_or(Target::BadHandle),
+            None => Target::Skip,
+        };
+
+        entries.push(PollEntry {
+            target,
+            events: Events::from_bits_truncate(fd.events),
+            revents: Events::empty(),
+        });
+    }
+
+    let timeout_ns = if timeout_ns == POLL_INFINITE { None } else { Some(timeout_ns) };
+    let ready = object::poll::poll(&mut entries, timeout_ns).await?;
+
     let crit = critical::begin();
-    let path = user::borrow_slice::<u8>(path, path_len, &crit)?;
 
-    let flags = OpenPathFlags::from_bits(flags)
+    for (index, entry) in entries.iter().enumerate() {
+        // revents is the last field of PollFd:
+        let offset = (index * mem::size_of::<PollFd>() + 16) as u64;
+        user::copy_to_user(fds + offset, &entry.revents.bits().to_ne_bytes(), &crit)?;
+    }
+
+    Ok(ready as u64)
+}
+
+fn evq_create() -> SyscallReturn {
+    let evq = ObjectRef::new(EventQueue::new()?)?;
+
+    Ok(object::put(&task::current_process(), evq.as_dyn())?.into_u64())
+}
+
+fn evq_add(evq: Handle, handle: Handle, events: u64, data: u64) -> SyscallReturn {
+    let process = task::current_process();
+
+    let evq = object::get(&process, evq, Rights::WRITE)?
+        .downcast::<EventQueue>()?;
+
+    let object = object::get(&process, handle.clone(), Rights::SIGNAL)?;
+
+    // a queue on a queue would never be ready, and could keep itself alive:
+    if let ObjectKind::EventQueue(_) = object.kind() {
+        return Err(SysError::IllegalValue);
+    }
+
+    let edge = events & EVQ_EDGE != 0;
+    let events = Events::from_bits(events & !EVQ_EDGE)
         .ok_or(SysError::IllegalValue)?;
 
-    let fs = task::get_filesystem().ok_or(SysError::NoFile)?;
-    let file = ObjectRef::new(fs.open(path).await?)?;
+    evq.object().add(handle, object, events, edge, data)?;
+
+    Ok(OK)
+}
+
+fn evq_remove(evq: Handle, handle: Handle) -> SyscallReturn {
+    let evq = object::get(&task::current_process(), evq, Rights::WRITE)?
+        .downcast::<EventQueue>()?;
+
+    evq.object().remove(&handle)?;
+
+    Ok(OK)
+}
+
+async fn evq_wait(evq: Handle, events_ptr: u64, max: u64, timeout_ns: u64) -> SyscallReturn {
+    if max == 0 || max > EVQ_MAX_EVENTS {
+        return Err(SysError::IllegalValue);
+    }
+
+    let events_len = max * mem::size_of::<EvqEvent>() as u64;
+
+    // check the buffer up front so that we don't take events only to lose
+    // them:
+    {
+        let crit = critical::begin();
+        user::validate_write(events_ptr, events_len, &crit)?;
+    }
+
+    let evq = object::get(&task::current_process(), evq, Rights::READ)?
+        .downcast::<EventQueue>()?;
+
+    let timeout_ns = if timeout_ns == POLL_INFINITE { None } else { Some(timeout_ns) };
+    let batch = evq.object().wait(max as usize, timeout_ns).await?;
+
+    let crit = critical::begin();
+
+    for (index, event) in batch.iter().enumerate() {
+        let mut bytes = [0u8; mem::size_of::<EvqEvent>()];
+        bytes[..8].copy_from_slice(&event.data.to_ne_bytes());
+        bytes[8..].copy_from_slice(&event.events.bits().to_ne_bytes());
+
+        let offset = (index * mem::size_of::<EvqEvent>()) as u64;
+        user::copy_to_user(events_ptr + offset, &bytes, &crit)?;
+    }
+
+    Ok(batch.len() as u64)
+}
+
+fn endpoint_create() -> SyscallReturn {
+    let endpoint = ObjectRef::new(Endpoint::new()?)?;
+
+    Ok(object::put(&task::current_process(), endpoint.as_dyn())?.into_u64())
+}
+
+// endpoint messages travel in rsi, rdx, r8 and r9, which the syscall
+// instruction leaves alone, along with a handle to pass on in r10, or 0. as
+// with channels, passing a handle on takes DUP:
+fn read_message(regs: &Registers, process: &task::Process) -> SysResult<endpoint::Message> {
+    let object = match Handle::from_u64(regs.r10) {
+        Some(handle) => Some(object::share(process, handle)?),
+        None => None,
+    };
+
+    Ok(endpoint::Message {
+        words: [regs.rsi, regs.rdx, regs.r8, regs.r9],
+        object,
+    })
+}
+
+fn write_message(regs: &mut Registers, message: endpoint::Message, process: &task::Process) -> SysResult<()> {
+    let handle = match message.object {
+        Some(capability) => object::put_capability(process, capability)?.into_u64(),
+        None => 0,
+    };
+
+    regs.rsi = message.words[0];
+    regs.rdx = message.words[1];
+    regs.r8 = message.words[2];
+    regs.r9 = message.words[3];
+    regs.r10 = handle;
+
+    Ok(())
+}
+
+async fn call(frame: &mut TrapFrame, endpoint: Handle) -> SyscallReturn {
+    let process = task::current_process();
+
+    let endpoint = object::get(&process, endpoint, Rights::WRITE)?
+        .downcast::<Endpoint>()?;
+
+    let message = read_message(&frame.regs, &process)?;
+    let reply = endpoint.object().call(message).await?;
+    write_message(&mut frame.regs, reply, &process)?;
+
+    Ok(OK)
+}
+
+// returns a handle to the Reply, which the Reply syscall consumes:
+async fn receive(frame: &mut TrapFrame, endpoint: Handle) -> SyscallReturn {
+    let process = task::current_process();
+
+    let endpoint = object::get(&process, endpoint, Rights::READ)?
+        .downcast::<Endpoint>()?;
+
+    let (message, reply) = endpoint.object().receive().await?;
+
+    let reply = ObjectRef::new(reply)?;
+    let reply = object::put(&process, reply.as_dyn())?;
+
+    write_message(&mut frame.regs, message, &process)?;
+
+    Ok(reply.into_u64())
+}
+
+fn reply(regs: &Registers, reply: Handle) -> SyscallReturn {
+    let process = task::current_process();
+
+    let reply_ref = object::get(&process, reply.clone(), Rights::WRITE)?
+        .downcast::<Reply>()?;
 
-    Ok(object::put(task::current(), file.as_dyn())?.into_u64())
+    let message = read_message(regs, &process)?;
+    reply_ref.object().send(message)?;
+
+    // a reply can only be used once:
+    let _ = object::release(&process, reply);
+
+    Ok(OK)
+}
+
+fn release_handle(handle: Handle) -> SyscallReturn  {
+    object::release(&task::current_process(), handle)
+        .map_err(|_| SysError::BadHandle)?;
+
+    Ok(OK)
+}
+
+fn create_page_context() -> SyscallReturn {
+    let page_ctx = PageCtx::new()
+        .map_err(|_| SysError::MemoryExhausted)?;
+
+    let obj = Object::new(ObjectKind::PageCtx(page_ctx))
+        .map_err(|_| SysError::MemoryExhausted)?;
+
+    Ok(object::put(&task::current_process(), obj)?.into_u64())
+}
+
+fn debug(regs: &Registers) -> SyscallReturn {
+    println!("{:#x?}", regs);
+    Ok(OK)
+}
+
+fn set_page_context(page_ctx: Handle) -> SyscallReturn {
+    let page_ctx = object::get(&task::current_process(), page_ctx, Rights::MAP)?
+        .downcast::<PageCtx>()?
+        .object()
+        .clone();
+
+    // TODO we need to set task's page ctx too?
+    unsafe { page::set_ctx(page_ctx); }
+
+    Ok(OK)
+}
+
+fn get_page_context() -> SyscallReturn {
+    let page_ctx = task::get_page_ctx();
+
+    Ok(object::put(&task::current_process(), page_ctx.as_dyn())?.into_u64())
+}
+
+fn create_task(page_ctx: Handle, rip: u64, rsp: u64) -> SyscallReturn {
+    let page_ctx = object::get(&task::current_process(), page_ctx, Rights::MAP)?
+        .downcast::<PageCtx>()?
+        .clone();
+
+    let process = task::Process::new(Some(task::current_process().id()), page_ctx, task::get_filesystem())?;
+
+    // the new process runs as the same user, or it'd start out as root:
+    process.set_credentials(task::current_process().credentials());
+
+    let task_id = task::spawn(process, task::TaskName::Static("user"), |task| async move {
+        task.setup(TrapFrame::new(rip, rsp)).run_loop().await
+    })?;
+
+    Ok(task_id.0)
+}
+
+fn create_thread(rip: u64, rsp: u64) -> SyscallReturn {
+    let task_id = task::spawn(task::current_process(), task::get_name(), |task| async move {
+        task.setup(TrapFrame::new(rip, rsp)).run_loop().await
+    })?;
+
+    Ok(task_id.0)
+}
+
+fn get_process_id() -> SyscallReturn {
+    Ok(task::current_process().id().0)
+}
+
+// processes started by the kernel have no parent, and get 0:
+fn get_parent_process_id() -> SyscallReturn {
+    Ok(task::current_process().parent().map(|parent| parent.0).unwrap_or(0))
+}
+
+fn get_task_id() -> SyscallReturn {
+    Ok(task::current().0)
+}
+
+fn uname(buf: u64) -> SyscallReturn {
+    // in Utsname's field order. there's no network, so no host name to go by
+    // other than the system's:
+    let fields = [
+        "crabos",
+        "crabos",
+        env!("CARGO_PKG_VERSION"),
+        concat!("crabos ", env!("CARGO_PKG_VERSION")),
+        "x86_64",
+    ];
+
+    let mut bytes = [0u8; mem::size_of::<Utsname>()];
+
+    for (field, value) in bytes.chunks_mut(UTSNAME_LEN).zip(fields.iter()) {
+        // leave room for at least one NUL:
+        let len = value.len().min(UTSNAME_LEN - 1);
+        field[..len].copy_from_slice(&value.as_bytes()[..len]);
+    }
+
+    let crit = critical::begin();
+    user::copy_to_user(buf, &bytes, &crit)?;
+
+    Ok(OK)
+}
+
+fn exit(status: u64) -> SyscallReturn {
+    task::exit(task::ExitStatus(status));
+    Ok(OK)
+}
+
+// a task can only kill, signal or move processes of its own user, unless it
+// runs as root:
+fn check_authority(process: &task::Process) -> SysResult<()> {
+    if task::current_process().credentials().has_authority_over(&process.credentials()) {
+        Ok(())
+    } else {
+        Err(SysError::PermissionDenied)
+    }
+}
+
+// for what would let one user get at everyone else's files or the machine
+// itself:
+fn require_root() -> SysResult<()> {
+    if task::current_process().credentials().is_root() {
+        Ok(())
+    } else {
+        Err(SysError::PermissionDenied)
+    }
+}
+
+fn kill(task_id: task::TaskId) -> SyscallReturn {
+    check_authority(&task::process_of(task_id)?)?;
+    task::kill(task_id, task::ExitStatus(EXIT_KILLED))?;
+
+    Ok(OK)
+}
+
+async fn wait(status_ptr: u64) -> SyscallReturn {
+    // check the status pointer up front so that we don't collect a child's
+    // exit status only to lose it:
+    if status_ptr != 0 {
+        let crit = critical::begin();
+        user::validate_write(status_ptr, 8, &crit)?;
+    }
+
+    let (task_id, status) = task::wait().await?
+        .ok_or(SysError::NoTask)?;
+
+    if status_ptr != 0 {
+        let crit = critical::begin();
+        user::copy_to_user(status_ptr, &status.0.to_ne_bytes(), &crit)?;
+    }
+
+    Ok(task_id.0)
+}
+
+fn fork(frame: &TrapFrame) -> SyscallReturn {
+    let task_id = task::fork(frame)?;
+    Ok(task_id.0)
+}
+
+async fn exec(frame: &mut TrapFrame, path: u64, path_len: u64, argv: u64, envp: u64) -> SyscallReturn {
+    let (path, args) = {
+        let crit = critical::begin();
+        let path = user::copy_array_from_user::<PathBuf>(path, path_len, &crit)?;
+
+        // the arguments have to be copied out before the old program goes.
+        // they're laid out for wherever the new program's stack goes:
+        let layout = exec::Layout::new(task::current_process().aslr());
+        let args = exec::Args::copy_from_user(argv, envp, layout, &crit)?;
+
+        (path, args)
+    };
+
+    // the task takes the name of the program it's running:
+    let name = task::TaskName::new(str::from_utf8(&path).unwrap_or("?"));
+
+    let fs = task::get_filesystem().ok_or(SysError::NoFile)?;
+    let credentials = task::current_process().credentials();
+    let file = fs.open_executable(&path, &credentials).await?;
+
+    let image = exec::load(&file, &args, task::current_process().write_execute()).await?;
+    let trap_frame = image.trap_frame();
+    let page_ctx = ObjectRef::new(image.page_ctx)?;
+
+    // the new program starts with the FPU as it is after a reset. the old one
+    // has no use for its state by now either:
+    task::reset_fpu()?;
+
+    // nothing of the old program may keep running in the new one's address
+    // space, so the process is down to this thread from here on:
+    task::kill_other_threads().await?;
+
+    // point of no return, the old program is gone once we switch page
+    // contexts:
+    task::set_page_ctx(page_ctx);
+    task::set_name(name);
+    task::current_process().signal_actions().lock().reset_for_exec();
+    *frame = trap_frame;
+
+    Ok(OK)
+}
+
+fn sigaction(signal: u64, handler: u64, restorer: u64) -> SyscallReturn {
+    let signal = Signal::new(signal)
+        .ok_or(SysError::IllegalValue)?;
+
+    let old = signal::set_handler(signal, Handler::from_user(handler, restorer))?;
+
+    Ok(old.into_u64())
+}
+
+fn send_signal(task_id: task::TaskId, signal: u64) -> SyscallReturn {
+    let signal = Signal::new(signal)
+        .ok_or(SysError::IllegalValue)?;
+
+    check_authority(&task::process_of(task_id)?)?;
+    signal::send(task_id, signal)?;
+
+    Ok(OK)
+}
+
+// task 0 stands for the current task:
+fn process_of(task_id: u64) -> SysResult<Arc<task::Process>> {
+    match task_id {
+        0 => Ok(task::current_process()),
+        task_id => Ok(task::process_of(task::TaskId(task_id))?),
+    }
+}
+
+// group 0 stands for the current process's group:
+fn group_or_own(group: u64) -> task::ProcessGroupId {
+    match group {
+        0 => task::current_process().group(),
+        group => task::ProcessGroupId(group),
+    }
+}
+
+fn set_process_group(task_id: u64, group: u64) -> SyscallReturn {
+    let process = process_of(task_id)?;
+    check_authority(&process)?;
+
+    // group 0 starts a group named after the process:
+    let group = match group {
+        0 => task::ProcessGroupId(process.id().0),
+        group => task::ProcessGroupId(group),
+    };
+
+    // a process can only start a group of its own or join one that exists:
+    if group.0 != process.id().0 && !task::group_exists(group) {
+        return Err(SysError::NoTask);
+    }
+
+    process.set_group(group);
+
+    Ok(OK)
+}
+
+fn get_process_group(task_id: u64) -> SyscallReturn {
+    Ok(process_of(task_id)?.group().0)
+}
+
+fn send_group_signal(group: u64, signal: u64) -> SyscallReturn {
+    let signal = Signal::new(signal)
+        .ok_or(SysError::IllegalValue)?;
+
+    let credentials = task::current_process().credentials();
+    signal::send_group_as(group_or_own(group), signal, &credentials)?;
+
+    Ok(OK)
+}
+
+// the console is the only terminal there is:
+fn console(handle: Handle) -> SysResult<()> {
+    let file = object::get(&task::current_process(), handle, Rights::WRITE)?
+        .downcast::<File>()?;
+
+    match file.object() {
+        File::Console => Ok(()),
+        _ => Err(SysError::WrongObjectKind),
+    }
+}
+
+fn set_foreground(terminal: Handle, group: u64) -> SyscallReturn {
+    console(terminal)?;
+
+    let group = group_or_own(group);
+
+    if !task::group_exists(group) {
+        return Err(SysError::NoTask);
+    }
+
+    // the foreground group gets the terminal's input and its signals, so it
+    // takes authority over every process in it:
+    let credentials = task::current_process().credentials();
+
+    if !task::group_all(group, |process| credentials.has_authority_over(&process.credentials())) {
+        return Err(SysError::PermissionDenied);
+    }
+
+    tty::set_foreground(group);
+
+    Ok(OK)
+}
+
+fn get_foreground(terminal: Handle) -> SyscallReturn {
+    console(terminal)?;
+
+    tty::foreground()
+        .map(|group| group.0)
+        .ok_or(SysError::NoTask)
+}
+
+fn sigreturn(frame: &mut TrapFrame, frame_addr: u64) -> SyscallReturn {
+    // rax is overwritten with the return value, so it's returned rather than
+    // restored with the rest:
+    signal::restore(frame, frame_addr)
+}
+
+fn clock_gettime(clock: u64, buf: u64) -> SyscallReturn {
+    let ns = match clock {
+        CLOCK_REALTIME => time::realtime_ns(),
+        CLOCK_MONOTONIC => time::monotonic_ns(),
+        _ => return Err(SysError::IllegalValue),
+    };
+
+    // in Timespec's field order:
+    let mut bytes = [0u8; mem::size_of::<Timespec>()];
+    bytes[0..8].copy_from_slice(&(ns / 1_000_000_000).to_ne_bytes());
+    bytes[8..16].copy_from_slice(&(ns % 1_000_000_000).to_ne_bytes());
+
+    let crit = critical::begin();
+    user::copy_to_user(buf, &bytes, &crit)?;
+
+    Ok(OK)
+}
+
+// only returns if the machine couldn't be turned off:
+async fn reboot(action: u64) -> SyscallReturn {
+    require_root()?;
+
+    if action != REBOOT_POWER_OFF && action != REBOOT_RESTART {
+        return Err(SysError::IllegalValue);
+    }
+
+    cache::writeback_all().await;
+
+    match action {
+        REBOOT_RESTART => power::reboot(),
+        _ => match power::shutdown() {
+            Ok(never) => never,
+            Err(e) => Err(e.into()),
+        },
+    }
+}
+
+// 0 turns ASLR off for whatever the process execs next, 1 turns it back on.
+// returns which it was:
+fn set_aslr(enabled: u64) -> SyscallReturn {
+    let enabled = match enabled {
+        0 => false,
+        1 => true,
+        _ => return Err(SysError::IllegalValue),
+    };
+
+    Ok(task::current_process().set_aslr(enabled) as u64)
+}
+
+// 1 allows memory to be writable and executable at once, 0 forbids it again.
+// returns which it was:
+fn set_write_execute(allowed: u64) -> SyscallReturn {
+    let allowed = match allowed {
+        0 => false,
+        1 => true,
+        _ => return Err(SysError::IllegalValue),
+    };
+
+    Ok(task::current_process().set_write_execute(allowed) as u64)
+}
+
+// fills the buffer from the kernel's CSPRNG, which is seeded before any user
+// code runs, so this never blocks:
+fn getrandom(buf: u64, len: u64) -> SyscallReturn {
+    let mut chunk = [0u8; 256];
+    let mut done = 0;
+
+    while done < len {
+        let count = cmp::min(len - done, chunk.len() as u64) as usize;
+        random::fill(&mut chunk[0..count]);
+
+        let addr = buf.checked_add(done).ok_or(SysError::BadPointer)?;
+
+        let crit = critical::begin();
+        user::copy_to_user(addr, &chunk[0..count], &crit)?;
+        done += count as u64;
+    }
+
+    Ok(len)
+}
+
+async fn sleep(ns: u64) -> SyscallReturn {
+    time::sleep_ns(ns).await?;
+    Ok(OK)
+}
+
+// signal 0 makes a timer that's only waited on, not signalled:
+fn timer_create(signal: u64) -> SyscallReturn {
+    let signal = match signal {
+        0 => None,
+        signal => Some((task::current(), Signal::new(signal).ok_or(SysError::IllegalValue)?)),
+    };
+
+    let timer = ObjectRef::new(Timer::new(signal)?)?;
+
+    Ok(object::put(&task::current_process(), timer.as_dyn())?.into_u64())
+}
+
+fn timer_set(timer: Handle, initial_ns: u64, interval_ns: u64) -> SyscallReturn {
+    let timer = object::get(&task::current_process(), timer, Rights::WRITE)?
+        .downcast::<Timer>()?;
+
+    timer.object().set(initial_ns, interval_ns)?;
+
+    Ok(OK)
+}
+
+async fn timer_read(timer: Handle) -> SyscallReturn {
+    let timer = object::get(&task::current_process(), timer, Rights::READ)?
+        .downcast::<Timer>()?;
+
+    timer.object().read().await
+}
+
+fn set_priority(priority: u64) -> SyscallReturn {
+    let priority = task::Priority::new(priority)
+        .ok_or(SysError::IllegalValue)?;
+
+    task::set_priority(priority);
+
+    Ok(OK)
+}
+
+// filters only ever narrow, so a sandboxed task can't undo its sandbox, and
+// nor can anything it starts:
+fn syscall_filter(mode: u64, bitmap_ptr: u64) -> SyscallReturn {
+    let mut bytes = [0u8; SYSCALL_FILTER_WORDS as usize * 8];
+
+    {
+        let crit = critical::begin();
+        user::copy_from_user(&mut bytes, bitmap_ptr, &crit)?;
+    }
+
+    let mut bitmap = [0u64; SYSCALL_FILTER_WORDS as usize];
+
+    for (word, raw) in bitmap.iter_mut().zip(bytes.chunks(8)) {
+        *word = u64::from_ne_bytes(raw.try_into().expect("chunk of 8 bytes"));
+    }
+
+    let filter = match mode {
+        FILTER_ALLOW => task::SyscallFilter::new(bitmap),
+        FILTER_DENY => task::SyscallFilter::new(bitmap).invert(),
+        _ => return Err(SysError::IllegalValue),
+    };
+
+    task::restrict_syscalls(filter);
+
+    Ok(OK)
+}
+
+// root can become anyone, but everyone else can only "set" the ID they
+// already have. there's no way back to root once it's been given up:
+fn set_uid(uid: u64) -> SyscallReturn {
+    let uid = u32::try_from(uid).map_err(|_| SysError::IllegalValue)?;
+    let process = task::current_process();
+    let mut credentials = process.credentials();
+
+    if !credentials.is_root() && credentials.uid != uid {
+        return Err(SysError::PermissionDenied);
+    }
+
+    credentials.uid = uid;
+    process.set_credentials(credentials);
+
+    Ok(OK)
+}
+
+fn set_gid(gid: u64) -> SyscallReturn {
+    let gid = u32::try_from(gid).map_err(|_| SysError::IllegalValue)?;
+    let process = task::current_process();
+    let mut credentials = process.credentials();
+
+    if !credentials.is_root() && credentials.gid != gid {
+        return Err(SysError::PermissionDenied);
+    }
+
+    credentials.gid = gid;
+    process.set_credentials(credentials);
+
+    Ok(OK)
+}
+
+fn get_uid() -> SyscallReturn {
+    Ok(task::current_process().credentials().uid.into())
+}
+
+fn get_gid() -> SyscallReturn {
+    Ok(task::current_process().credentials().gid.into())
+}
+
+fn get_priority() -> SyscallReturn {
+    Ok(task::get_priority().into_u64())
+}
+
+// real-time tasks starve everything else for as long as they like, so only
+// root gets to make them:
+fn set_sched_class(class: u64, rt_priority: u64) -> SyscallReturn {
+    require_root()?;
+
+    let class = match class {
+        SCHED_NORMAL => task::SchedClass::Normal,
+        SCHED_FIFO | SCHED_RR => {
+            let rt_priority = task::RtPriority::new(rt_priority)
+                .ok_or(SysError::IllegalValue)?;
+
+            if class == SCHED_FIFO {
+                task::SchedClass::Fifo(rt_priority)
+            } else {
+                task::SchedClass::RoundRobin(rt_priority)
+            }
+        }
+        _ => return Err(SysError::IllegalValue),
+    };
+
+    task::set_sched_class(class);
+
+    Ok(OK)
+}
+
+fn set_affinity(mask: u64) -> SyscallReturn {
+    let affinity = task::Affinity::new(mask)
+        .ok_or(SysError::IllegalValue)?;
+
+    task::set_affinity(affinity)?;
+
+    Ok(OK)
+}
+
+fn get_affinity() -> SyscallReturn {
+    Ok(task::get_affinity().into_u64())
+}
+
+// reads and writes go through a kernel buffer a chunk at a time, so that user
+// memory is only ever open for the copy, and never while a driver or
+// filesystem has the data:
+const IO_CHUNK: usize = PAGE_SIZE;
+
+fn io_buffer() -> SysResult<Box<[u8; IO_CHUNK]>> {
+    Box::new([0u8; IO_CHUNK]).map_err(|_| SysError::MemoryExhausted)
+}
+
+// a short chunk ends the read. only files in a filesystem go on to another
+// chunk after a full one, as pipes and the console might block on it with
+// data already in hand:
+async fn read_stream(file: Handle, buf: u64, nbyte: u64) -> SyscallReturn {
+    let file = object::get(&task::current_process(), file, Rights::READ)?
+        .downcast::<File>()?;
+
+    let more = match file.object() {
+        File::Fs(_) => true,
+        _ => false,
+    };
+
+    let mut chunk = io_buffer()?;
+    let mut done = 0;
+
+    while done < nbyte {
+        let len = cmp::min(nbyte - done, IO_CHUNK as u64) as usize;
+
+        let read = match file.object().read(&mut chunk[..len]).await {
+            Ok(read) => read,
+            // what was read already is still returned:
+            Err(_) if done > 0 => break,
+            Err(e) => return Err(e),
+        };
+
+        {
+            let crit = critical::begin();
+            user::copy_to_user(buf + done, &chunk[..read], &crit)?;
+        }
+
+        done += read as u64;
+
+        if read < len || !more {
+            break;
+        }
+    }
+
+    Ok(done)
+}
+
+async fn write_stream(file: Handle, buf: u64, nbyte: u64) -> SyscallReturn {
+    let file = object::get(&task::current_process(), file, Rights::WRITE)?
+        .downcast::<File>()?;
+
+    let mut chunk = io_buffer()?;
+    let mut done = 0;
+
+    while done < nbyte {
+        let len = cmp::min(nbyte - done, IO_CHUNK as u64) as usize;
+
+        {
+            let crit = critical::begin();
+            user::copy_from_user(&mut chunk[..len], buf + done, &crit)?;
+        }
+
+        let written = match file.object().write(&chunk[..len]).await {
+            Ok(written) => written,
+            Err(_) if done > 0 => break,
+            Err(e) => return Err(e),
+        };
+
+        done += written as u64;
+
+        if written < len {
+            break;
+        }
+    }
+
+    Ok(done)
+}
+
+// what `request` means, and whether `arg` is a pointer, is up to the
+// driver behind the file:
+async fn ioctl(file: Handle, request: u64, arg: u64) -> SyscallReturn {
+    let file = object::get(&task::current_process(), file, Rights::READ | Rights::WRITE)?
+        .downcast::<File>()?;
+
+    file.object()
+        .ioctl(request, arg)
+        .await
+}
+
+async fn open_file(path: u64, path_len: u64, flags: u64) -> SyscallReturn {
+    crate::println!("open_path: {:x?}, {:x?}, {:x?}", path, path_len, flags);
+    let path = {
+        let crit = critical::begin();
+        user::copy_array_from_user::<PathBuf>(path, path_len, &crit)?
+    };
+
+    let flags = OpenFlags::from_bits(flags)
+        .ok_or(SysError::IllegalValue)?;
+
+    let fs = task::get_filesystem().ok_or(SysError::NoFile)?;
+    let credentials = task::current_process().credentials();
+    let file = ObjectRef::new(fs.open(&path, flags, &credentials).await?)?;
+
+    Ok(object::put(&task::current_process(), file.as_dyn())?.into_u64())
+}
+
+// the offset is signed for SEEK_CUR and SEEK_END:
+async fn seek(file: Handle, offset: u64, whence: u64) -> SyscallReturn {
+    let from = match whence {
+        SEEK_SET => SeekFrom::Start(offset),
+        SEEK_CUR => SeekFrom::Current(offset as i64),
+        SEEK_END => SeekFrom::End(offset as i64),
+        _ => return Err(SysError::IllegalValue),
+    };
+
+    // moving the position neither reads nor writes, so takes no rights:
+    let file = object::get(&task::current_process(), file, Rights::empty())?
+        .downcast::<File>()?;
+
+    file.object()
+        .seek(from)
+        .await
+}
+
+async fn stat(path: u64, path_len: u64, buf: u64) -> SyscallReturn {
+    let path = {
+        let crit = critical::begin();
+        user::copy_array_from_user::<PathBuf>(path, path_len, &crit)?
+    };
+
+    let fs = task::get_filesystem().ok_or(SysError::NoFile)?;
+    let stat = fs.lookup(&path).await?.stat().await?;
+
+    copy_stat_to_user(buf, file_kind(stat.kind), stat.size, &stat.permissions)
+}
+
+async fn fstat(file: Handle, buf: u64) -> SyscallReturn {
+    let file = object::get(&task::current_process(), file, Rights::READ)?
+        .downcast::<File>()?;
+
+    // the console and pipes aren't in any filesystem, so they're reported as
+    // belonging to whoever asks:
+    let credentials = task::current_process().credentials();
+    let unowned = Permissions { mode: 0o600, uid: credentials.uid, gid: credentials.gid };
+
+    let (kind, size, permissions) = match file.object() {
+        File::Fs(file) => {
+            let stat = file.stat().await?;
+            (file_kind(stat.kind), stat.size, stat.permissions)
+        }
+        File::Console => (FILE_KIND_CONSOLE, 0, unowned),
+        File::PipeReader(_) | File::PipeWriter(_) => (FILE_KIND_PIPE, 0, unowned),
+    };
+
+    copy_stat_to_user(buf, kind, size, &permissions)
+}
+
+fn file_kind(kind: InodeKind) -> u32 {
+    match kind {
+        InodeKind::File => FILE_KIND_FILE,
+        InodeKind::Directory => FILE_KIND_DIRECTORY,
+    }
+}
+
+// in Stat's field order, leaving the reserved fields zeroed:
+fn copy_stat_to_user(buf: u64, kind: u32, size: u64, permissions: &Permissions) -> SyscallReturn {
+    let mut bytes = [0u8; mem::size_of::<Stat>()];
+    bytes[0..4].copy_from_slice(&kind.to_ne_bytes());
+    bytes[4..8].copy_from_slice(&permissions.mode.to_ne_bytes());
+    bytes[8..16].copy_from_slice(&size.to_ne_bytes());
+    bytes[16..20].copy_from_slice(&permissions.uid.to_ne_bytes());
+    bytes[20..24].copy_from_slice(&permissions.gid.to_ne_bytes());
+
+    let crit = critical::begin();
+    user::copy_to_user(buf, &bytes, &crit)?;
+
+    Ok(OK)
+}
+
+// returns the number of bytes of Dirent records filled in, which is 0 once
+// the whole directory has been listed:
+async fn getdents(file: Handle, buf: u64, len: u64) -> SyscallReturn {
+    let file = object::get(&task::current_process(), file, Rights::READ)?
+        .downcast::<File>()?;
+
+    // a buffer bigger than IO_CHUNK is only filled up to IO_CHUNK, which
+    // still lists at least one entry:
+    let mut chunk = io_buffer()?;
+    let chunk_len = cmp::min(len, IO_CHUNK as u64) as usize;
+
+    let mut records = DirentRecords { buf: &mut chunk[..chunk_len], len: 0, full: false };
+    file.object().read_dir(&mut records).await?;
+
+    // the buffer can't even fit the next entry:
+    if records.len == 0 && records.full {
+        return Err(SysError::IllegalValue);
+    }
+
+    let filled = records.len;
+
+    let crit = critical::begin();
+    user::copy_to_user(buf, &chunk[..filled], &crit)?;
+
+    Ok(filled as u64)
+}
+
+// fills a Getdents buffer with a record for each entry, for as long as there
+// is room:
+struct DirentRecords<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    full: bool,
+}
+
+impl DirSink for DirentRecords<'_> {
+    fn push(&mut self, name: &[u8], kind: InodeKind, next: u64) -> bool {
+        const HEADER_SIZE: usize = mem::size_of::<Dirent>();
+
+        // the name is followed by at least one NUL:
+        let record_len = (HEADER_SIZE + name.len() + 8) & !7;
+
+        if record_len > self.buf.len() - self.len || record_len > u16::max_value() as usize {
+            self.full = true;
+            return false;
+        }
+
+        let record = &mut self.buf[self.len..(self.len + record_len)];
+        record.iter_mut().for_each(|byte| *byte = 0);
+
+        record[0..8].copy_from_slice(&next.to_ne_bytes());
+        record[8..10].copy_from_slice(&(record_len as u16).to_ne_bytes());
+        record[10..12].copy_from_slice(&(name.len() as u16).to_ne_bytes());
+        record[12..16].copy_from_slice(&file_kind(kind).to_ne_bytes());
+        record[HEADER_SIZE..(HEADER_SIZE + name.len())].copy_from_slice(name);
+
+        self.len += record_len;
+        true
+    }
+}
+
+async fn mount(request: u64) -> SyscallReturn {
+    require_root()?;
+
+    let request = {
+        let mut bytes = [0u8; mem::size_of::<MountRequest>()];
+
+        let crit = critical::begin();
+        user::copy_from_user(&mut bytes, request, &crit)?;
+
+        unsafe { ptr::read_unaligned(bytes.as_ptr() as *const MountRequest) }
+    };
+
+    let (fstype, source, target) = {
+        let crit = critical::begin();
+
+        (user::copy_array_from_user::<PathBuf>(request.fstype, request.fstype_len, &crit)?,
+            user::copy_array_from_user::<PathBuf>(request.source, request.source_len, &crit)?,
+            user::copy_array_from_user::<PathBuf>(request.target, request.target_len, &crit)?)
+    };
+
+    let fs = task::get_filesystem().ok_or(SysError::NoFile)?;
+    fs.mount(&target, crate::fs::open(&fstype, &source).await?)?;
+
+    Ok(OK)
+}
+
+async fn umount(path: u64, path_len: u64) -> SyscallReturn {
+    require_root()?;
+
+    let path = {
+        let crit = critical::begin();
+        user::copy_array_from_user::<PathBuf>(path, path_len, &crit)?
+    };
+
+    let fs = task::get_filesystem().ok_or(SysError::NoFile)?;
+    fs.unmount(&path).await?;
+
+    Ok(OK)
+}
+
+async fn chdir(path: u64, path_len: u64) -> SyscallReturn {
+    let path = {
+        let crit = critical::begin();
+        user::copy_array_from_user::<PathBuf>(path, path_len, &crit)?
+    };
+
+    let fs = task::get_filesystem().ok_or(SysError::NoFile)?;
+    let fs = fs.chdir(&path).await?;
+
+    task::set_filesystem(Some(Arc::new(fs)?));
+
+    Ok(OK)
+}
+
+// returns the length of the path, which isn't NUL terminated:
+fn getcwd(buf: u64, len: u64) -> SyscallReturn {
+    let fs = task::get_filesystem().ok_or(SysError::NoFile)?;
+    let cwd = fs.cwd();
+
+    if cwd.len() as u64 > len {
+        return Err(SysError::IllegalValue);
+    }
+
+    let crit = critical::begin();
+    user::copy_to_user(buf, cwd, &crit)?;
+
+    Ok(cwd.len() as u64)
+}
+
+async fn chroot(path: u64, path_len: u64) -> SyscallReturn {
+    require_root()?;
+
+    let path = {
+        let crit = critical::begin();
+        user::copy_array_from_user::<PathBuf>(path, path_len, &crit)?
+    };
+
+    let fs = task::get_filesystem().ok_or(SysError::NoFile)?;
+    let fs = fs.chroot(&path).await?;
+
+    task::set_filesystem(Some(Arc::new(fs)?));
+
+    Ok(OK)
+}
+
+async fn unlink(path: u64, path_len: u64) -> SyscallReturn {
+    let path = {
+        let crit = critical::begin();
+        user::copy_array_from_user::<PathBuf>(path, path_len, &crit)?
+    };
+
+    let fs = task::get_filesystem().ok_or(SysError::NoFile)?;
+    let credentials = task::current_process().credentials();
+    fs.unlink(&path, &credentials).await?;
+
+    Ok(OK)
+}
+
+async fn rename(from: u64, from_len: u64, to: u64, to_len: u64) -> SyscallReturn {
+    let (from, to) = {
+        let crit = critical::begin();
+
+        (user::copy_array_from_user::<PathBuf>(from, from_len, &crit)?,
+            user::copy_array_from_user::<PathBuf>(to, to_len, &crit)?)
+    };
+
+    let fs = task::get_filesystem().ok_or(SysError::NoFile)?;
+    let credentials = task::current_process().credentials();
+    fs.rename(&from, &to, &credentials).await?;
+
+    Ok(OK)
 }
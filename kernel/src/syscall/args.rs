@@ -1,6 +1,8 @@
 use interface::{SysResult, SysError};
 
+use crate::interrupt::Registers;
 use crate::object::Handle;
+use crate::task::TaskId;
 
 pub trait UserArg: Sized {
     fn from_reg(reg: u64) -> SysResult<Self>;
@@ -12,6 +14,16 @@ impl UserArg for u64 {
     }
 }
 
+impl UserArg for u32 {
+    fn from_reg(reg: u64) -> SysResult<u32> {
+        if reg > u32::max_value() as u64 {
+            return Err(SysError::IllegalValue);
+        }
+
+        Ok(reg as u32)
+    }
+}
+
 impl UserArg for Handle {
     fn from_reg(reg: u64) -> SysResult<Handle> {
         Handle::from_u64(reg).ok_or(SysError::BadHandle)
@@ -24,33 +36,25 @@ impl UserArg for Option<Handle> {
     }
 }
 
-// pub trait CallRegs {
-//     type Ret;
-//     fn call_regs(&self, regs: &mut Registers) -> Self::Ret;
-//     // fn call_regs(&self, regs: &mut Registers) -> Self::Ret;
-// }
-
-// macro_rules! impl_call_regs {
-//     ($($tys:tt => ($($args:ident ,)*) ;)*) => {
-//         $(
-//             impl<F, Ret> CallRegs for F
-//                 where F: Fn$tys -> Ret,
-//             {
-//                 type Ret = Ret;
-
-//                 fn call_regs(&self, regs: &mut Registers) -> Ret {
-//                     self($( regs.$args ,)*)
-//                 }
-//             }
-//         )*
-//     }
-// }
-
-// impl_call_regs! {
-//     (u64)                => (rdi,);
-//     (u64, u64)           => (rdi, rsi,);
-//     (u64, u64, u64)      => (rdi, rsi, rdx,);
-//     (u64, u64, u64, u64) => (rdi, rsi, rdx, rcx,);
-// }
-
-// pub fn wrap_syscall(F: )
+impl UserArg for TaskId {
+    fn from_reg(reg: u64) -> SysResult<TaskId> {
+        Ok(TaskId(reg))
+    }
+}
+
+/// The arguments of a syscall, in the order they're passed: rdi, rsi, rdx,
+/// then rcx. The syscall number goes in rax, and so does the result. The
+/// syscall instruction takes the fourth argument in r10, which syscall_entry
+/// in isrs.asm moves to rcx.
+pub struct SyscallArgs([u64; 4]);
+
+impl SyscallArgs {
+    pub fn new(regs: &Registers) -> Self {
+        SyscallArgs([regs.rdi, regs.rsi, regs.rdx, regs.rcx])
+    }
+
+    /// Decodes argument `index` as a `T`.
+    pub fn get<T: UserArg>(&self, index: usize) -> SysResult<T> {
+        T::from_reg(self.0[index])
+    }
+}
@@ -1,41 +1,322 @@
 use core::future::Future;
 use core::pin::Pin;
-use core::sync::atomic::{AtomicU64, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use core::task::{Poll, Context, Waker, RawWaker, RawWakerVTable};
 
 use alloc_collections::boxed::Box;
 use alloc_collections::btree_map::BTreeMap;
-
-use crate::fs::vfs::Filesystem;
-use crate::interrupt::TrapFrame;
+use arrayvec::ArrayString;
+use futures::future;
+use interface::{OK, EXIT_KILLED, Syscall, SysError, SYSCALL_FILTER_WORDS};
+
+use crate::config;
+use crate::cpu::{self, fpu::FpuState};
+use crate::fs::vfs::FsContext;
+use crate::interrupt::{self, TrapFrame};
 use crate::mem::kalloc::GlobalAlloc;
+use crate::mem::kstack::{self, KernelStack};
 use crate::mem::MemoryExhausted;
 use crate::object::ObjectRef;
 use crate::page::{self, PageCtx};
+use crate::smp;
 use crate::sync::{Arc, Mutex};
 use crate::syscall;
-use crate::util::EarlyInit;
+use crate::time;
+use crate::util::{AtomicList, EarlyInit};
+use crate::work;
+
+mod process;
+mod queue;
+pub mod signal;
 
-pub const SEG_UCODE: u16 = 0x1b;
-pub const SEG_UDATA: u16 = 0x23;
+pub use process::{Credentials, Process, ProcessGroupId, ProcessId};
+use queue::RunQueue;
+
+pub const SEG_KCODE: u16 = 0x08;
+pub const SEG_KDATA: u16 = 0x10;
+pub const SEG_UDATA: u16 = 0x1b;
+pub const SEG_UCODE: u16 = 0x23;
 
 pub type TaskMap<V> = EarlyInit<Mutex<BTreeMap<TaskId, V, GlobalAlloc>>>;
 
-static TASKS: TaskMap<Task> = TaskMap::new();
+static TASKS: TaskMap<Thread> = TaskMap::new();
 static TASK_STATES: TaskMap<TaskState> = TaskMap::new();
 static TASK_FUTURES: TaskMap<TaskFuture> = TaskMap::new();
+static TASK_EXITS: TaskMap<Exited> = TaskMap::new();
+
+// the process every kernel task belongs to. it has the kernel's page context
+// and no handles:
+static KERNEL_PROCESS: EarlyInit<Arc<Process>> = EarlyInit::new();
+
+// scheduler state private to each CPU. every task belongs to the run queue of
+// exactly one CPU:
+crate::percpu! {
+    static CURRENT: Mutex<Option<Current>> = Mutex::new(None);
+    // set while the CPU is running the idle task, see `idle`:
+    static IDLE: AtomicBool = AtomicBool::new(false);
+    // when the current task's time slice runs out, in monotonic_ns:
+    static SLICE_END: AtomicU64 = AtomicU64::new(0);
+    // when the current task last started running, see `charge_run_time`:
+    static RUN_START: AtomicU64 = AtomicU64::new(0);
+    // set while the FPU registers hold the current task's state rather than
+    // its FpuState. the kernel never touches them, so they only change hands
+    // on the way back to user mode:
+    static FPU_LOADED: AtomicBool = AtomicBool::new(false);
+    // the idle task never has any state worth keeping, so it starts afresh at
+    // the top of this stack every time the scheduler switches to it.
+    // interrupts that arrive while idle are handled on this stack too:
+    static IDLE_STACK: EarlyInit<KernelStack> = EarlyInit::new();
+    static RUN_QUEUE: EarlyInit<Mutex<RunQueue>> = EarlyInit::new();
+    // the kernel stack of a task reaped by the scheduler while it was running
+    // on that stack. it is released on the next switch, which necessarily
+    // starts on some other stack:
+    static RETIRED_STACK: Mutex<Option<KernelStack>> = Mutex::new(None);
+}
+
+// the task running on a CPU. the process is kept here too so that looking it
+// up doesn't need the TASKS lock:
+struct Current {
+    task: TaskId,
+    process: Arc<Process>,
+    class: SchedClass,
+}
+
+// the CPUs that have a run queue and are about to start taking tasks from it,
+// or already are, one bit per CPU index. tasks are only ever given to these:
+static SCHEDULING: AtomicU64 = AtomicU64::new(0);
+
+// wakers of futures waiting on any task to exit. we wake all of them whenever
+// any task exits, spurious wake ups are harmless:
+static EXIT_WAKERS: AtomicList<Waker> = AtomicList::new();
 
 pub fn init() {
     EarlyInit::set(&TASKS, Mutex::new(BTreeMap::new()));
     EarlyInit::set(&TASK_STATES, Mutex::new(BTreeMap::new()));
     EarlyInit::set(&TASK_FUTURES, Mutex::new(BTreeMap::new()));
+    EarlyInit::set(&TASK_EXITS, Mutex::new(BTreeMap::new()));
+    queue::init();
+
+    let page_ctx = ObjectRef::new(page::current_ctx().expect("current_ctx for kernel page context"))
+        .expect("ObjectRef::new for kernel page context");
+
+    EarlyInit::set(&KERNEL_PROCESS, Process::new(None, page_ctx, None)
+        .expect("Process::new for kernel process"));
+
+    init_cpu();
 }
 
-static CURRENT_TASK: Mutex<Option<TaskId>> = Mutex::new(None);
+/// Sets up the scheduler state of the calling CPU, which must go on to call
+/// `start`. Tasks can be given to it from here on. Called once on each CPU.
+pub fn init_cpu() {
+    EarlyInit::set(IDLE_STACK.get(), KernelStack::new().expect("KernelStack::new for idle task"));
+    EarlyInit::set(RUN_QUEUE.get(), Mutex::new(RunQueue::new(smp::cpu_index())));
+
+    SCHEDULING.fetch_or(1 << smp::cpu_index(), Ordering::SeqCst);
+}
 
 #[derive(Debug, Clone, Copy, PartialOrd, PartialEq, Eq, Ord)]
 pub struct TaskId(pub u64);
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExitStatus(pub u64);
+
+// a task that has been reaped, but whose exit status has not yet been
+// collected by join or wait:
+#[derive(Debug)]
+struct Exited {
+    parent: Option<TaskId>,
+    status: ExitStatus,
+}
+
+pub const PRIORITY_COUNT: usize = 8;
+
+/// Scheduling priority of a task. Runnable tasks of a higher priority always
+/// run before those of a lower priority, tasks of equal priority are run
+/// round-robin.
+#[derive(Debug, Clone, Copy, PartialOrd, PartialEq, Eq, Ord)]
+pub struct Priority(u8);
+
+impl Priority {
+    pub const LOWEST: Priority = Priority(0);
+    pub const DEFAULT: Priority = Priority(PRIORITY_COUNT as u8 / 2);
+    pub const HIGHEST: Priority = Priority(PRIORITY_COUNT as u8 - 1);
+
+    pub fn new(priority: u64) -> Option<Priority> {
+        if priority < PRIORITY_COUNT as u64 {
+            Some(Priority(priority as u8))
+        } else {
+            None
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        self.0 as usize
+    }
+
+    pub fn into_u64(&self) -> u64 {
+        self.0 as u64
+    }
+
+    /// Share of the CPU a task of this priority gets under the fair policy,
+    /// relative to other tasks. Each priority level is worth 25% more than
+    /// the one below it.
+    pub fn weight(&self) -> u64 {
+        const WEIGHTS: [u64; PRIORITY_COUNT] = [419, 524, 655, 819, 1024, 1280, 1600, 2000];
+        WEIGHTS[self.index()]
+    }
+}
+
+pub const RT_PRIORITY_COUNT: usize = 8;
+
+/// Priority of a real-time task. Only compared against other real-time tasks,
+/// every real-time task outranks every normal task.
+#[derive(Debug, Clone, Copy, PartialOrd, PartialEq, Eq, Ord)]
+pub struct RtPriority(u8);
+
+impl RtPriority {
+    pub fn new(priority: u64) -> Option<RtPriority> {
+        if priority < RT_PRIORITY_COUNT as u64 {
+            Some(RtPriority(priority as u8))
+        } else {
+            None
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// Scheduling class of a task. Runnable real-time tasks always run before
+/// normal tasks, and preempt them on the next timer tick.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedClass {
+    /// Scheduled by priority according to `config::SCHED_POLICY`.
+    Normal,
+    /// Runs until it blocks, yields or is preempted by a higher real-time
+    /// priority. Never loses the CPU to tasks of its own priority.
+    Fifo(RtPriority),
+    /// Like Fifo, but takes turns with tasks of the same real-time priority
+    /// every time slice.
+    RoundRobin(RtPriority),
+}
+
+impl SchedClass {
+    pub fn rt_priority(&self) -> Option<RtPriority> {
+        match *self {
+            SchedClass::Normal => None,
+            SchedClass::Fifo(priority) | SchedClass::RoundRobin(priority) => Some(priority),
+        }
+    }
+
+    /// Returns true if a runnable task of this class must run before a task
+    /// of the other class.
+    pub fn outranks(&self, other: &SchedClass) -> bool {
+        match (self.rt_priority(), other.rt_priority()) {
+            (Some(ours), Some(theirs)) => ours > theirs,
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+}
+
+/// Scheduling policies, see `config::SCHED_POLICY`.
+#[allow(unused)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedPolicy {
+    RoundRobin,
+    Fair,
+}
+
+/// The set of CPUs a task is allowed to run on, one bit per CPU index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Affinity(u64);
+
+impl Affinity {
+    pub const ALL: Affinity = Affinity((1 << config::MAX_CPUS) - 1);
+
+    /// Returns None if the mask is empty or names CPUs we can never have.
+    pub fn new(mask: u64) -> Option<Affinity> {
+        if mask != 0 && mask & !Affinity::ALL.0 == 0 {
+            Some(Affinity(mask))
+        } else {
+            None
+        }
+    }
+
+    pub fn contains(&self, cpu: usize) -> bool {
+        self.0 & (1 << cpu) != 0
+    }
+
+    /// Picks the CPU a task with this affinity should run on: `preferred` if
+    /// allowed, otherwise the first allowed CPU that runs tasks. CPUs that
+    /// are online but don't run tasks are never picked.
+    fn pick(&self, preferred: usize) -> Option<usize> {
+        let scheduling = SCHEDULING.load(Ordering::SeqCst);
+        let allowed = |cpu: usize| self.contains(cpu) && scheduling & (1 << cpu) != 0;
+
+        if allowed(preferred) {
+            return Some(preferred);
+        }
+
+        (0..config::MAX_CPUS).find(|cpu| allowed(*cpu))
+    }
+
+    pub fn into_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+/// The syscalls a task may make, one bit per syscall number. A task starts
+/// with its parent's filter, keeps it across exec, and can only narrow it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyscallFilter([u64; SYSCALL_FILTER_WORDS as usize]);
+
+impl SyscallFilter {
+    pub const ALL: SyscallFilter = SyscallFilter([!0; SYSCALL_FILTER_WORDS as usize]);
+
+    /// A filter allowing the syscalls set in `bitmap`.
+    pub fn new(bitmap: [u64; SYSCALL_FILTER_WORDS as usize]) -> SyscallFilter {
+        SyscallFilter(bitmap)
+    }
+
+    /// A filter allowing every syscall but those this one allows.
+    pub fn invert(&self) -> SyscallFilter {
+        let mut bitmap = self.0;
+
+        for word in bitmap.iter_mut() {
+            *word = !*word;
+        }
+
+        SyscallFilter(bitmap)
+    }
+
+    /// A filter allowing only what both this one and `other` allow.
+    pub fn intersect(&self, other: &SyscallFilter) -> SyscallFilter {
+        let mut bitmap = self.0;
+
+        for (word, other) in bitmap.iter_mut().zip(other.0.iter()) {
+            *word &= other;
+        }
+
+        SyscallFilter(bitmap)
+    }
+
+    /// Exit is always allowed, so that no filter keeps a task from finishing.
+    /// Numbers past the bitmap aren't syscalls, and are left for dispatch to
+    /// turn away.
+    pub fn allows(&self, number: u64) -> bool {
+        if number == Syscall::Exit as u64 {
+            return true;
+        }
+
+        match self.0.get((number / 64) as usize) {
+            Some(word) => word & (1 << (number % 64)) != 0,
+            None => true,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum TaskState {
     SyscallEntry(TrapFrame),
@@ -44,13 +325,174 @@ pub enum TaskState {
     User(TrapFrame),
 }
 
+/// A state change that the scheduler's invariants don't allow, such as a task
+/// being entered from user mode twice. These are always kernel bugs.
+#[derive(Debug)]
+pub struct IllegalTransition {
+    pub from: &'static str,
+    pub to: &'static str,
+}
+
+impl TaskState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TaskState::SyscallEntry(_) => "syscall",
+            TaskState::Wake => "wake",
+            TaskState::Sleep => "sleep",
+            TaskState::User(_) => "user",
+        }
+    }
+
+    /// Whether the scheduler has anything to do for a task in this state.
+    pub fn is_runnable(&self) -> bool {
+        match self {
+            TaskState::Sleep => false,
+            TaskState::Wake | TaskState::SyscallEntry(_) | TaskState::User(_) => true,
+        }
+    }
+
+    fn transition(&mut self, to: TaskState, legal: bool) -> Result<(), IllegalTransition> {
+        if !legal {
+            return Err(IllegalTransition { from: self.name(), to: to.name() });
+        }
+
+        *self = to;
+        Ok(())
+    }
+
+    /// A task in user mode trapped into the kernel with a syscall.
+    pub fn to_entry(&mut self, frame: TrapFrame) -> Result<(), IllegalTransition> {
+        let legal = match self {
+            TaskState::User(_) => true,
+            _ => false,
+        };
+
+        self.transition(TaskState::SyscallEntry(frame), legal)
+    }
+
+    /// The task's kernel future is returning to user mode. Only the future
+    /// itself can do this, so the task must be mid-poll: asleep, or woken
+    /// during the poll.
+    pub fn to_user(&mut self, frame: TrapFrame) -> Result<(), IllegalTransition> {
+        let legal = match self {
+            TaskState::Sleep | TaskState::Wake => true,
+            _ => false,
+        };
+
+        self.transition(TaskState::User(frame), legal)
+    }
+
+    /// Something the task's kernel future was waiting on happened.
+    pub fn to_wake(&mut self) -> Result<(), IllegalTransition> {
+        let legal = match self {
+            TaskState::Sleep => true,
+            _ => false,
+        };
+
+        self.transition(TaskState::Wake, legal)
+    }
+
+    /// The task's kernel future is about to be polled in response to a wake
+    /// up, consuming it.
+    pub fn to_sleep(&mut self) -> Result<(), IllegalTransition> {
+        let legal = match self {
+            TaskState::Wake => true,
+            _ => false,
+        };
+
+        self.transition(TaskState::Sleep, legal)
+    }
+
+    /// Takes the pending syscall entry, if any, leaving the task running
+    /// kernel code until it either returns to user mode or blocks.
+    pub fn take_entry(&mut self) -> Option<TrapFrame> {
+        let frame = match self {
+            TaskState::SyscallEntry(frame) => frame.clone(),
+            _ => return None,
+        };
+
+        *self = TaskState::Sleep;
+        Some(frame)
+    }
+}
+
+pub const TASK_NAME_LEN: usize = 32;
+
+/// A human readable name for a task, for debugging. Names built at runtime are
+/// copied into the task, truncated to TASK_NAME_LEN bytes.
+#[derive(Debug, Clone)]
+pub enum TaskName {
+    Static(&'static str),
+    Owned(ArrayString<[u8; TASK_NAME_LEN]>),
+}
+
+impl TaskName {
+    pub fn new(name: &str) -> TaskName {
+        let mut owned = ArrayString::new();
+
+        for c in name.chars() {
+            if owned.try_push(c).is_err() {
+                break;
+            }
+        }
+
+        TaskName::Owned(owned)
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            TaskName::Static(name) => name,
+            TaskName::Owned(name) => name.as_str(),
+        }
+    }
+}
+
+/// Scheduler statistics for a single task.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TaskStats {
+    /// Time spent running, in nanoseconds at the resolution of the clock
+    /// source, normally the TSC.
+    pub run_time_ns: u64,
+    /// Number of times the scheduler has switched to the task.
+    pub switches: u64,
+    /// Number of syscalls the task has made.
+    pub syscalls: u64,
+}
+
 type TaskFuture = Arc<Mutex<Pin<Box<dyn Future<Output = ()>, GlobalAlloc>>>>;
 
+/// A thread of execution, identified by its TaskId. Every thread belongs to a
+/// process, which it shares its address space and handles with.
 #[derive(Debug)]
-pub struct Task {
+pub struct Thread {
     id: TaskId,
-    page_ctx: ObjectRef<PageCtx>,
-    filesystem: Option<Arc<Filesystem>>,
+    name: TaskName,
+    // the task that spawned this one, if it is still around to wait for it:
+    parent: Option<TaskId>,
+    process: Arc<Process>,
+    // index of the CPU whose run queue the task is on, always one allowed by
+    // its affinity:
+    cpu: usize,
+    affinity: Affinity,
+    // checked on every syscall before it's dispatched:
+    syscall_filter: SyscallFilter,
+    // the task's kernel future is polled on this stack, and traps from the
+    // task's user mode code arrive on it:
+    kernel_stack: KernelStack,
+    // the task's user mode FPU state while it isn't in the registers. kernel
+    // tasks never run any user code, and have none:
+    fpu: Option<FpuState>,
+    class: SchedClass,
+    priority: Priority,
+    // run time weighted by priority, in nanoseconds. the fair policy runs the
+    // task with the lowest virtual runtime first:
+    vruntime: u64,
+    stats: TaskStats,
+    // one bit per signal waiting for its handler to run, see signal::deliver:
+    pending_signals: u64,
+    // set once the task has asked to terminate. the scheduler reaps the task
+    // instead of running it again:
+    exit_status: Option<ExitStatus>,
 }
 
 fn alloc_task_id() -> TaskId {
@@ -58,7 +500,26 @@ fn alloc_task_id() -> TaskId {
     TaskId(NEXT_TASK_ID.fetch_add(1, Ordering::SeqCst))
 }
 
-pub fn spawn<F, Fut>(page_ctx: ObjectRef<PageCtx>, filesystem: Option<Arc<Filesystem>>, f: F) -> Result<TaskId, MemoryExhausted>
+/// Spawns a task in the given process as a child of the current task.
+pub fn spawn<F, Fut>(process: Arc<Process>, name: TaskName, f: F) -> Result<TaskId, MemoryExhausted>
+    where F: FnOnce(TaskEmbryo) -> Fut, Fut: Future<Output = ()> + 'static
+{
+    let parent = CURRENT.get().lock().as_ref().map(|current| current.task);
+    spawn_with_parent(process, parent, name, Some(FpuState::new()?), f)
+}
+
+/// Spawns a pure kernel task, which runs `future` to completion and never
+/// enters user mode. Kernel tasks share the kernel's page context, and have no
+/// parent to wait for them. This is where drivers run deferred work.
+pub fn spawn_kernel<Fut>(name: TaskName, future: Fut) -> Result<TaskId, MemoryExhausted>
+    where Fut: Future<Output = ()> + 'static
+{
+    spawn_with_parent(KERNEL_PROCESS.clone(), None, name, None, |_| future)
+}
+
+fn spawn_with_parent<F, Fut>(process: Arc<Process>, parent: Option<TaskId>, name: TaskName,
+        fpu: Option<FpuState>, f: F)
+    -> Result<TaskId, MemoryExhausted>
     where F: FnOnce(TaskEmbryo) -> Fut, Fut: Future<Output = ()> + 'static
 {
     let id = alloc_task_id();
@@ -75,7 +536,38 @@ pub fn spawn<F, Fut>(page_ctx: ObjectRef<PageCtx>, filesystem: Option<Arc<Filesy
         unsafe { Pin::new_unchecked(future_obj) }
     };
 
-    let task = Task { id, page_ctx, filesystem };
+    // new tasks inherit their parent's affinity and syscall filter:
+    let (affinity, syscall_filter) = match parent {
+        Some(parent) => TASKS.lock().get(&parent).map(|task| (task.affinity, task.syscall_filter)),
+        None => None,
+    }.unwrap_or((Affinity::ALL, SyscallFilter::ALL));
+
+    // set_affinity made sure the parent's affinity allows a CPU that runs
+    // tasks, and CPUs never stop running them:
+    let cpu = affinity.pick(smp::cpu_index())
+        .expect("task affinity allows no online CPU");
+
+    // start new tasks level with everyone else, rather than letting them run
+    // until they catch up:
+    let vruntime = RUN_QUEUE.for_cpu(cpu).lock().min_vruntime();
+
+    let task = Thread {
+        id,
+        name,
+        parent,
+        process,
+        cpu,
+        affinity,
+        syscall_filter,
+        kernel_stack: KernelStack::new()?,
+        fpu,
+        class: SchedClass::Normal,
+        priority: Priority::DEFAULT,
+        vruntime,
+        stats: TaskStats::default(),
+        pending_signals: 0,
+        exit_status: None,
+    };
 
     // try inserting all task related data:
     let result: Result<_, MemoryExhausted> = (|| {
@@ -88,6 +580,10 @@ pub fn spawn<F, Fut>(page_ctx: ObjectRef<PageCtx>, filesystem: Option<Arc<Filesy
         TASKS.lock().insert(id, task)
             .map_err(|_| MemoryExhausted)?;
 
+        queue::reserve(id)?;
+
+        RUN_QUEUE.for_cpu(cpu).lock().push(id, SchedClass::Normal, Priority::DEFAULT, vruntime);
+
         Ok(())
     })();
 
@@ -103,32 +599,551 @@ pub fn spawn<F, Fut>(page_ctx: ObjectRef<PageCtx>, filesystem: Option<Arc<Filesy
     }
 }
 
+/// Creates a copy of the current task in a new process, with a private copy of
+/// its user memory and the same object handles. The child resumes in user mode
+/// from `trap_frame` with 0 in rax, while the parent gets the child's task ID.
+pub fn fork(trap_frame: &TrapFrame) -> Result<TaskId, MemoryExhausted> {
+    let parent = current_process();
+    let page_ctx = ObjectRef::new(parent.page_ctx().object().clone_cow()?)?;
+    let child = Process::new(Some(parent.id()), page_ctx, parent.filesystem())?;
+    *child.signal_actions().lock() = *parent.signal_actions().lock();
+    child.set_group(parent.group());
+    child.set_aslr(parent.aslr());
+    child.set_write_execute(parent.write_execute());
+    child.set_credentials(parent.credentials());
+
+    *child.handles().lock() = parent.handles().lock().try_clone()?;
+
+    let mut child_frame = trap_frame.clone();
+    child_frame.regs.rax = 0;
+
+    let fpu = current_fpu()?;
+
+    spawn_with_parent(child, Some(current()), get_name(), Some(fpu), |task| async move {
+        task.setup(child_frame).run_loop().await
+    })
+}
+
+// returns a copy of the current task's FPU state as it is now, which may only
+// be in the registers:
+fn current_fpu() -> Result<FpuState, MemoryExhausted> {
+    let mut tasks = TASKS.lock();
+
+    let fpu = tasks.get_mut(&current())
+        .and_then(|task| task.fpu.as_mut())
+        .expect("task::current_fpu called with no current user task");
+
+    if FPU_LOADED.get().load(Ordering::SeqCst) {
+        unsafe { fpu.save(); }
+    }
+
+    fpu.try_clone()
+}
+
+/// Puts the current task's FPU back in the state it starts in, for a new
+/// program.
+pub fn reset_fpu() -> Result<(), MemoryExhausted> {
+    let fresh = FpuState::new()?;
+
+    let mut tasks = TASKS.lock();
+
+    let task = tasks.get_mut(&current())
+        .expect("task::reset_fpu called with no current task");
+
+    if FPU_LOADED.get().load(Ordering::SeqCst) {
+        unsafe { fresh.restore(); }
+    }
+
+    // drop the old state outside of the lock:
+    let old = task.fpu.replace(fresh);
+    drop(tasks);
+    drop(old);
+
+    Ok(())
+}
+
 pub fn current() -> TaskId {
-    CURRENT_TASK.lock()
+    CURRENT.get().lock()
+        .as_ref()
         .expect("task::current called with no current task")
+        .task
+}
+
+/// Returns the process the current task belongs to.
+pub fn current_process() -> Arc<Process> {
+    CURRENT.get().lock()
+        .as_ref()
+        .expect("task::current_process called with no current task")
+        .process
+        .clone()
+}
+
+// takes the current task off this CPU. the process reference is dropped
+// outside of the lock, it may be the last one:
+fn clear_current() {
+    let current = CURRENT.get().lock().take();
+    drop(current);
 }
 
 pub fn get_page_ctx() -> ObjectRef<PageCtx> {
+    current_process().page_ctx()
+}
+
+/// Returns the page context of the current process, or None if there is no
+/// current task.
+pub fn try_get_page_ctx() -> Option<ObjectRef<PageCtx>> {
+    let process = CURRENT.get().lock()
+        .as_ref()
+        .map(|current| current.process.clone())?;
+
+    Some(process.page_ctx())
+}
+
+/// Replaces the page context of the current process and switches to it.
+pub fn set_page_ctx(page_ctx: ObjectRef<PageCtx>) {
+    unsafe { page::set_ctx(page_ctx.object().clone()); }
+
+    // drop the old page context outside of the process lock, it may be the
+    // last reference:
+    let old_page_ctx = current_process().replace_page_ctx(page_ctx);
+    drop(old_page_ctx);
+}
+
+pub fn get_filesystem() -> Option<Arc<FsContext>> {
+    current_process().filesystem()
+}
+
+pub fn get_priority() -> Priority {
     TASKS.lock()
         .get(&current())
-        .expect("task::get_page_ctx called with no current task")
-        .page_ctx
-        .clone()
+        .expect("task::get_priority called with no current task")
+        .priority
+}
+
+pub fn set_priority(priority: Priority) {
+    let task_id = current();
+
+    let (cpu, class, vruntime) = {
+        let mut tasks = TASKS.lock();
+
+        let task = tasks.get_mut(&task_id)
+            .expect("task::set_priority called with no current task");
+
+        task.priority = priority;
+        (task.cpu, task.class, task.vruntime)
+    };
+
+    // move the task to its new queue if it's waiting to run:
+    let mut run_queue = RUN_QUEUE.for_cpu(cpu).lock();
+
+    if run_queue.remove(task_id) {
+        run_queue.push(task_id, class, priority, vruntime);
+    }
+}
+
+/// Changes the scheduling class of the current task. Meant for tasks with
+/// latency requirements, such as drivers.
+pub fn set_sched_class(class: SchedClass) {
+    let task_id = current();
+
+    let (cpu, priority, vruntime) = {
+        let mut tasks = TASKS.lock();
+
+        let task = tasks.get_mut(&task_id)
+            .expect("task::set_sched_class called with no current task");
+
+        task.class = class;
+        (task.cpu, task.priority, task.vruntime)
+    };
+
+    if let Some(current) = CURRENT.get().lock().as_mut() {
+        if current.task == task_id {
+            current.class = class;
+        }
+    }
+
+    // move the task to its new queue if it's waiting to run:
+    let mut run_queue = RUN_QUEUE.for_cpu(cpu).lock();
+
+    if run_queue.remove(task_id) {
+        run_queue.push(task_id, class, priority, vruntime);
+    }
+}
+
+/// Returns the process the given task belongs to.
+pub fn process_of(task_id: TaskId) -> Result<Arc<Process>, NoSuchTask> {
+    TASKS.lock()
+        .get(&task_id)
+        .map(|task| task.process.clone())
+        .ok_or(NoSuchTask)
+}
+
+/// Whether any task belongs to a process in the given group.
+pub fn group_exists(group: ProcessGroupId) -> bool {
+    TASKS.lock()
+        .values()
+        .any(|task| task.process.group() == group)
+}
+
+/// Whether `f` holds for the process of every task in the given group.
+pub fn group_all(group: ProcessGroupId, f: impl Fn(&Process) -> bool) -> bool {
+    TASKS.lock()
+        .values()
+        .filter(|task| task.process.group() == group)
+        .all(|task| f(&task.process))
 }
 
-pub fn get_filesystem() -> Option<Arc<Filesystem>> {
+/// Narrows the current task's syscall filter to what both it and `filter`
+/// allow, returning the result.
+pub fn restrict_syscalls(filter: SyscallFilter) -> SyscallFilter {
+    let mut tasks = TASKS.lock();
+
+    let task = tasks.get_mut(&current())
+        .expect("task::restrict_syscalls called with no current task");
+
+    task.syscall_filter = task.syscall_filter.intersect(&filter);
+    task.syscall_filter
+}
+
+pub fn get_name() -> TaskName {
     TASKS.lock()
         .get(&current())
-        .expect("task::get_filesystem called with no current task")
-        .filesystem
+        .expect("task::get_name called with no current task")
+        .name
         .clone()
 }
 
-pub fn set_filesystem(fs: Option<Arc<Filesystem>>) {
+pub fn set_name(name: TaskName) {
+    TASKS.lock()
+        .get_mut(&current())
+        .expect("task::set_name called with no current task")
+        .name = name;
+}
+
+/// A snapshot of what a task is and what it's doing, as shown in procfs.
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+    pub name: TaskName,
+    pub state: &'static str,
+    pub parent: Option<TaskId>,
+    pub process: Arc<Process>,
+    pub cpu: usize,
+    pub class: SchedClass,
+    pub priority: Priority,
+    pub stats: TaskStats,
+}
+
+/// Returns a snapshot of the given task.
+pub fn info(task_id: TaskId) -> Result<TaskInfo, NoSuchTask> {
+    let tasks = TASKS.lock();
+    let task_states = TASK_STATES.lock();
+
+    let task = tasks.get(&task_id).ok_or(NoSuchTask)?;
+
+    Ok(TaskInfo {
+        name: task.name.clone(),
+        state: state_name(task, task_states.get(&task_id)),
+        parent: task.parent,
+        process: task.process.clone(),
+        cpu: task.cpu,
+        class: task.class,
+        priority: task.priority,
+        stats: task.stats,
+    })
+}
+
+/// The task with the lowest id of at least `from`, for going through every
+/// task in order without holding on to the task table.
+pub fn next_id(from: TaskId) -> Option<TaskId> {
+    TASKS.lock()
+        .range(from..)
+        .next()
+        .map(|(task_id, _)| *task_id)
+}
+
+// what a task is doing, for debugging. tasks that have asked to terminate
+// are exiting whatever their state:
+fn state_name(task: &Thread, state: Option<&TaskState>) -> &'static str {
+    match (task.exit_status, state) {
+        (Some(_), _) => "exiting",
+        (None, Some(state)) => state.name(),
+        (None, None) => "unknown",
+    }
+}
+
+/// Prints every task's id, name, state and user mode rip to the kernel log.
+/// Useful for working out what everything is waiting on when the system hangs.
+#[allow(unused)]
+pub fn dump() {
+    let tasks = TASKS.lock();
+    let task_states = TASK_STATES.lock();
+
+    crate::println!("{} tasks:", tasks.len());
+
+    for (id, task) in tasks.iter() {
+        let state = task_states.get(id);
+
+        let rip = match state {
+            Some(TaskState::SyscallEntry(frame)) | Some(TaskState::User(frame)) => Some(frame.rip),
+            Some(TaskState::Wake) | Some(TaskState::Sleep) | None => None,
+        };
+
+        let state = state_name(task, state);
+
+        match rip {
+            Some(rip) => crate::println!("  {:>4} {:<32} {:<8} rip={:#x}", id.0, task.name.as_str(), state, rip),
+            None => crate::println!("  {:>4} {:<32} {:<8}", id.0, task.name.as_str(), state),
+        }
+    }
+}
+
+pub fn get_affinity() -> Affinity {
+    TASKS.lock()
+        .get(&current())
+        .expect("task::get_affinity called with no current task")
+        .affinity
+}
+
+#[derive(Debug)]
+pub struct NoOnlineCpu;
+
+impl From<NoOnlineCpu> for SysError {
+    fn from(_: NoOnlineCpu) -> SysError {
+        SysError::IllegalValue
+    }
+}
+
+/// Restricts the current task to the given CPUs, at least one of which must
+/// run tasks. If the CPU it is on is not among them, the task moves to one
+/// that is the next time the scheduler takes it off its run queue.
+pub fn set_affinity(affinity: Affinity) -> Result<(), NoOnlineCpu> {
+    affinity.pick(smp::cpu_index())
+        .ok_or(NoOnlineCpu)?;
+
+    // the task is running, so it stays on its CPU for now. moving it under
+    // its feet would let another CPU run it at the same time:
+    TASKS.lock()
+        .get_mut(&current())
+        .expect("task::set_affinity called with no current task")
+        .affinity = affinity;
+
+    Ok(())
+}
+
+pub fn set_filesystem(fs: Option<Arc<FsContext>>) {
+    current_process().set_filesystem(fs);
+}
+
+/// Requests termination of the current task. The task is never scheduled
+/// again, and is reaped with the given status the next time the scheduler
+/// runs.
+pub fn exit(status: ExitStatus) {
     TASKS.lock()
         .get_mut(&current())
-        .expect("task::get_filesystem called with no current task")
-        .filesystem = fs;
+        .expect("task::exit called with no current task")
+        .exit_status = Some(status);
+}
+
+#[derive(Debug)]
+pub struct NoSuchTask;
+
+impl From<NoSuchTask> for SysError {
+    fn from(_: NoSuchTask) -> SysError {
+        SysError::NoTask
+    }
+}
+
+/// Marks the given task for termination. The task is never scheduled again:
+/// the next time the scheduler runs it drops the task's kernel future and
+/// page context, regardless of whether it was asleep, runnable or in user mode.
+pub fn kill(task_id: TaskId, status: ExitStatus) -> Result<(), NoSuchTask> {
+    mark_exiting(task_id, status)?;
+
+    // make sure the scheduler gets around to reaping it even if it's asleep:
+    requeue(task_id);
+
+    Ok(())
+}
+
+// sets the exit status of a task, which the scheduler then reaps instead of
+// running. a task that is already exiting keeps its original status:
+fn mark_exiting(task_id: TaskId, status: ExitStatus) -> Result<(), NoSuchTask> {
+    let mut tasks = TASKS.lock();
+
+    let task = tasks.get_mut(&task_id)
+        .ok_or(NoSuchTask)?;
+
+    if task.exit_status.is_none() {
+        task.exit_status = Some(status);
+    }
+
+    Ok(())
+}
+
+/// Kills every other thread of the current process, and waits until they've
+/// all been reaped, as exec has to before it replaces the program under them.
+/// Threads running on other CPUs are reaped the next time they enter the
+/// kernel.
+pub async fn kill_other_threads() -> Result<(), MemoryExhausted> {
+    let current = current();
+    let process = current_process().id();
+
+    let next_sibling = |next: TaskId| TASKS.lock()
+        .range(next..)
+        .find(|(task_id, task)| **task_id != current && task.process.id() == process)
+        .map(|(task_id, _)| *task_id);
+
+    // kill takes the TASKS lock, so the threads are looked up one at a time:
+    let mut next = TaskId(0);
+
+    while let Some(task_id) = next_sibling(next) {
+        // it may have exited since:
+        let _ = kill(task_id, ExitStatus(EXIT_KILLED));
+        next = TaskId(task_id.0 + 1);
+    }
+
+    future::poll_fn(|ctx| {
+        // register waker before checking for threads, as in join:
+        match EXIT_WAKERS.push_front(ctx.waker().clone()) {
+            Ok(()) => {}
+            Err(e) => { return Poll::Ready(Err(e)); }
+        }
+
+        match next_sibling(TaskId(0)) {
+            Some(_) => Poll::Pending,
+            None => Poll::Ready(Ok(())),
+        }
+    }).await
+}
+
+/// Waits for the given task to exit, returning its exit status. Returns None
+/// if there is no such task, or its exit status has already been collected.
+pub async fn join(task_id: TaskId) -> Result<Option<ExitStatus>, MemoryExhausted> {
+    future::poll_fn(|ctx| {
+        // register waker before checking whether the task is alive so that we
+        // can't miss it exiting in between:
+        match EXIT_WAKERS.push_front(ctx.waker().clone()) {
+            Ok(()) => {}
+            Err(e) => { return Poll::Ready(Err(e)); }
+        }
+
+        if let Some(exited) = TASK_EXITS.lock().remove(&task_id) {
+            return Poll::Ready(Ok(Some(exited.status)));
+        }
+
+        if TASKS.lock().contains_key(&task_id) {
+            return Poll::Pending;
+        }
+
+        Poll::Ready(Ok(None))
+    }).await
+}
+
+/// Waits for any child of the current task to exit, returning its ID and exit
+/// status. Returns None if the current task has no children left to wait for.
+pub async fn wait() -> Result<Option<(TaskId, ExitStatus)>, MemoryExhausted> {
+    let parent = current();
+
+    future::poll_fn(|ctx| {
+        // register waker before checking for children, as in join:
+        match EXIT_WAKERS.push_front(ctx.waker().clone()) {
+            Ok(()) => {}
+            Err(e) => { return Poll::Ready(Err(e)); }
+        }
+
+        {
+            let mut task_exits = TASK_EXITS.lock();
+
+            let child = task_exits.iter()
+                .find(|(_, exited)| exited.parent == Some(parent))
+                .map(|(child, _)| *child);
+
+            if let Some(child) = child {
+                let exited = task_exits.remove(&child)
+                    .expect("child not in TASK_EXITS");
+
+                return Poll::Ready(Ok(Some((child, exited.status))));
+            }
+        }
+
+        if TASKS.lock().values().any(|task| task.parent == Some(parent)) {
+            return Poll::Pending;
+        }
+
+        Poll::Ready(Ok(None))
+    }).await
+}
+
+/// Removes all trace of a task from the scheduler, dropping its kernel future,
+/// handles and page context, and records its exit status for `join`.
+fn reap(task_id: TaskId, status: ExitStatus) {
+    let task = TASKS.lock().remove(&task_id);
+    TASK_STATES.lock().remove(&task_id);
+    let future = TASK_FUTURES.lock().remove(&task_id);
+
+    // take the task off whichever run queue it's still in, and give up its
+    // place there. whoever decided to queue it before it was removed above may
+    // have done so meanwhile, in which case take it off again:
+    loop {
+        match queue::queued_on(task_id) {
+            Some(cpu) => {
+                RUN_QUEUE.for_cpu(cpu).lock().remove(task_id);
+            }
+            None => {
+                if queue::release(task_id) {
+                    break;
+                }
+            }
+        }
+    }
+
+    let parent = task.as_ref().and_then(|task| task.parent);
+
+    // drop the future and task outside of any locks, their destructors may
+    // need to take them:
+    drop(future);
+
+    if let Some(task) = task {
+        let Thread { kernel_stack, .. } = task;
+
+        // if the task trapped into the kernel to exit, we may still be running
+        // on its kernel stack. hang on to it until the next switch:
+        if kstack::is_current(kernel_stack.top()) {
+            *RETIRED_STACK.get().lock() = Some(kernel_stack);
+        }
+    }
+
+    // nobody is left to wait for this task's children, so orphan the live ones
+    // and discard the exit statuses of the dead ones:
+    for child in TASKS.lock().values_mut() {
+        if child.parent == Some(task_id) {
+            child.parent = None;
+        }
+    }
+
+    {
+        let mut task_exits = TASK_EXITS.lock();
+
+        while let Some(child) = task_exits.iter()
+            .find(|(_, exited)| exited.parent == Some(task_id))
+            .map(|(child, _)| *child)
+        {
+            task_exits.remove(&child);
+        }
+    }
+
+    match TASK_EXITS.lock().insert(task_id, Exited { parent, status }) {
+        Ok(_) => {}
+        Err(_) => {
+            // the only consequence of failing to record the exit status is
+            // that joiners see the task as unknown:
+            crate::println!("task::reap: could not record exit status for {:?}", task_id);
+        }
+    }
+
+    for waker in EXIT_WAKERS.take_iter() {
+        waker.wake();
+    }
 }
 
 pub unsafe fn start() -> ! {
@@ -142,41 +1157,110 @@ pub unsafe fn start() -> ! {
     unreachable!()
 }
 
+/// Returns true if the CPU is currently parked in the idle task.
+pub fn is_idle() -> bool {
+    IDLE.get().load(Ordering::SeqCst)
+}
+
+/// The idle task runs in kernel mode whenever no other task is runnable. It
+/// just halts until the next interrupt, which switches away from it if that
+/// interrupt woke anything up.
+extern "C" fn idle() -> ! {
+    loop {
+        unsafe { asm!("sti; hlt" :::: "volatile"); }
+    }
+}
+
+fn idle_frame() -> TrapFrame {
+    let stack_top = IDLE_STACK.get().top();
+    TrapFrame::new_kernel(idle as usize as u64, stack_top)
+}
+
 pub unsafe fn switch(frame: &mut TrapFrame) {
-    fn save_current_task(frame: &mut TrapFrame) -> Option<TaskId> {
-        let current = (*CURRENT_TASK.lock())?;
+    // if we were idle, we aren't any more. there's nothing to save either,
+    // but the timer may be set for a long way off:
+    if IDLE.get().swap(false, Ordering::SeqCst) {
+        time::rearm();
+    }
 
-        let mut task_states = TASK_STATES.lock();
+    // we can't be running on a reaped task's stack any more:
+    drop(RETIRED_STACK.get().lock().take());
 
-        let state = task_states
-            .get_mut(&current)
-            .expect("task id not in TASK_STATES");
+    fn save_current_task(frame: &mut TrapFrame) {
+        let current = match *CURRENT.get().lock() {
+            Some(ref current) => current.task,
+            None => return,
+        };
 
-        match *state {
-            TaskState::User(ref mut task_frame) => {
-                *task_frame = frame.clone();
+        // the registers are left as they are, they only need to change if
+        // another task returns to user mode before this one does:
+        if FPU_LOADED.get().swap(false, Ordering::SeqCst) {
+            if let Some(fpu) = TASKS.lock().get_mut(&current).and_then(|task| task.fpu.as_mut()) {
+                unsafe { fpu.save(); }
             }
-            _ => {}
         }
 
-        Some(current)
+        {
+            let mut task_states = TASK_STATES.lock();
+
+            let state = task_states
+                .get_mut(&current)
+                .expect("task id not in TASK_STATES");
+
+            match *state {
+                TaskState::User(ref mut task_frame) => {
+                    *task_frame = frame.clone();
+                }
+                _ => {}
+            }
+        }
+
+        charge_run_time(current);
+        requeue(current);
     }
 
     enum WorkItem {
         Kernel(TaskFuture),
         User(TrapFrame),
+        Exit(ExitStatus),
     }
 
-    fn find_next_work_item(previous_task_id: Option<TaskId>) -> (TaskId, WorkItem) {
-        let tasks = TASKS.lock();
+    fn find_next_work_item() -> Option<(TaskId, WorkItem)> {
+        loop {
+            let id = RUN_QUEUE.get().lock().pop()?;
+
+            let mut tasks = TASKS.lock();
+
+            let task = match tasks.get_mut(&id) {
+                Some(task) => task,
+                None => {
+                    // task was reaped while it was queued
+                    continue;
+                }
+            };
+
+            if let Some(status) = task.exit_status {
+                return Some((id, WorkItem::Exit(status)));
+            }
 
-        let previous_task_id = previous_task_id.unwrap_or(TaskId(0));
+            let cpu = smp::cpu_index();
 
-        let next_tasks = tasks.range(previous_task_id..)
-            .filter(|(id, _)| **id != previous_task_id)
-            .chain(tasks.range(..=previous_task_id));
+            // never run a task on a CPU its affinity doesn't allow. a task
+            // only ever runs on its own CPU, so if that's this one it isn't
+            // running anywhere and can safely move:
+            if task.cpu == cpu && !task.affinity.contains(cpu) {
+                task.cpu = task.affinity.pick(cpu)
+                    .expect("task affinity allows no online CPU");
+            }
+
+            // it was queued here before moving, so send it on to its own
+            // queue:
+            if task.cpu != cpu {
+                drop(tasks);
+                requeue(id);
+                continue;
+            }
 
-        for (id, _) in next_tasks {
             let mut task_states = TASK_STATES.lock();
 
             let state = task_states.get_mut(&id)
@@ -186,89 +1270,350 @@ pub unsafe fn switch(frame: &mut TrapFrame) {
                 TaskState::Sleep => {
                     continue;
                 }
-                TaskState::SyscallEntry(_) | TaskState::Wake => {
-                    let future = TASK_FUTURES.lock()
-                        .get(&id)
-                        .cloned()
-                        .expect("id not in TASK_FUTURES");
-
-                    WorkItem::Kernel(future)
+                TaskState::Wake => {
+                    // the task is about to be polled, so consume its wake up.
+                    // if anything wakes it again during the poll, the waker
+                    // will flip the state back to Wake:
+                    state.to_sleep()
+                        .expect("Wake to Sleep");
+
+                    WorkItem::Kernel(task_future(id))
+                }
+                TaskState::SyscallEntry(_) => {
+                    WorkItem::Kernel(task_future(id))
                 }
                 TaskState::User(ref task_frame) => {
                     WorkItem::User(task_frame.clone())
                 }
             };
 
-            return (*id, work_item);
+            return Some((id, work_item));
         }
-
-        panic!("there should always be a task ready to run!");
     }
 
-    let mut previous_task_id = save_current_task(frame);
+    save_current_task(frame);
 
     loop {
-        let (task_id, work_item) = find_next_work_item(previous_task_id);
+        // deferred work often wakes tasks, so run it before picking one:
+        work::run_pending();
+
+        let (task_id, work_item) = match find_next_work_item() {
+            Some(next) => next,
+            None => {
+                // every task is asleep waiting on some event. park the CPU in
+                // the idle task until an interrupt wakes one of them:
+                clear_current();
+
+                // idle on the kernel's own page tables, so that an exited
+                // task's address space isn't kept alive by cr3 until the next
+                // task runs:
+                page::set_ctx(KERNEL_PROCESS.page_ctx().object().clone());
+
+                // with nothing to preempt, the timer only needs to go off for
+                // the next sleeper:
+                IDLE.get().store(true, Ordering::SeqCst);
+                time::rearm();
+
+                *frame = idle_frame();
+                return;
+            }
+        };
 
-        *CURRENT_TASK.lock() = Some(task_id);
+        if let WorkItem::Exit(status) = work_item {
+            clear_current();
+            reap(task_id, status);
+            continue;
+        }
 
-        let page_ctx = TASKS.lock()
-            .get(&task_id)
-            .expect("current task in TASKS")
-            .page_ctx
-            .clone();
+        let (process, class, stack_top) = {
+            let mut tasks = TASKS.lock();
+
+            let task = tasks.get_mut(&task_id)
+                .expect("current task in TASKS");
+
+            task.stats.switches += 1;
+
+            (task.process.clone(), task.class, task.kernel_stack.top())
+        };
+
+        let page_ctx = process.page_ctx();
+
+        let previous = CURRENT.get().lock().replace(Current { task: task_id, process, class });
+        drop(previous);
+
+        // every time a task is picked from the run queue it gets a fresh time
+        // slice:
+        let now = time::monotonic_ns();
+        SLICE_END.get().store(now + config::TIME_SLICE_TICKS * time::NS_PER_TICK, Ordering::SeqCst);
+        RUN_START.get().store(now, Ordering::SeqCst);
 
         page::set_ctx(page_ctx.object().clone());
 
         match work_item {
             WorkItem::Kernel(future) => {
-                let waker = Waker::from_raw(task_waker_new(task_id));
-                let mut cx = Context::from_waker(&waker);
-                let mut fut = future.lock();
-
-                match fut.as_mut().poll(&mut cx) {
-                    Poll::Ready(()) => panic!("task finished!"),
+                match poll_task(task_id, &future, stack_top) {
+                    Poll::Ready(()) => {
+                        // the kernel future finished without the task asking
+                        // to exit, treat that as a successful exit:
+                        let status = TASKS.lock()
+                            .get(&task_id)
+                            .and_then(|task| task.exit_status)
+                            .unwrap_or(ExitStatus(0));
+
+                        clear_current();
+                        drop(future);
+                        reap(task_id, status);
+                    }
                     Poll::Pending => {
-                        // TODO set task state to sleep
+                        // the task state is Sleep unless the future was woken
+                        // or returned to user mode while it was being polled,
+                        // in which case it goes to the back of its queue:
+                        requeue(task_id);
                     }
                 }
-
-                previous_task_id = Some(task_id);
             }
             WorkItem::User(task_frame) => {
                 *frame = task_frame;
+
+                if !signal::deliver(task_id, frame) {
+                    // a signal killed it, which requeued it to be reaped:
+                    continue;
+                }
+
+                if let Some(fpu) = TASKS.lock().get(&task_id).and_then(|task| task.fpu.as_ref()) {
+                    fpu.restore();
+                    FPU_LOADED.get().store(true, Ordering::SeqCst);
+                }
+
+                interrupt::set_kernel_stack(stack_top);
                 return;
             }
+            WorkItem::Exit(_) => unreachable!(),
+        }
+    }
+}
+
+fn task_future(id: TaskId) -> TaskFuture {
+    TASK_FUTURES.lock()
+        .get(&id)
+        .cloned()
+        .expect("id not in TASK_FUTURES")
+}
+
+// polls a task's kernel future on the task's own kernel stack, charging the
+// time it took to the task:
+unsafe fn poll_task(task_id: TaskId, future: &TaskFuture, stack_top: u64) -> Poll<()> {
+    let waker = waker(task_id);
+    let mut cx = Context::from_waker(&waker);
+
+    // user access is only ever open around a copy, never across a poll:
+    cpu::user_access_end();
+
+    let poll = kstack::call_on(stack_top, || {
+        future.lock().as_mut().poll(&mut cx)
+    });
+
+    charge_run_time(task_id);
+
+    poll
+}
+
+/// Called on every timer tick that interrupts user code. Preempts the current
+/// task once it has used up its time slice.
+pub unsafe fn timer_tick(frame: &mut TrapFrame) {
+    let class = match *CURRENT.get().lock() {
+        Some(ref current) => current.class,
+        None => return,
+    };
+
+    // a real-time task that became runnable since the last tick takes over
+    // straight away if it outranks the current task:
+    let preempted = RUN_QUEUE.get().lock()
+        .highest_rt_priority()
+        .map(|queued| SchedClass::Fifo(queued).outranks(&class))
+        .unwrap_or(false);
+
+    // fifo tasks have no time slice to use up:
+    let expired = match class {
+        SchedClass::Fifo(_) => false,
+        // measured by the clock rather than counting ticks, which don't all
+        // come at the same interval:
+        SchedClass::Normal | SchedClass::RoundRobin(_) => {
+            time::monotonic_ns() >= SLICE_END.get().load(Ordering::SeqCst)
+        }
+    };
+
+    if preempted || expired {
+        switch(frame);
+    }
+}
+
+// adds the time since the current task started running on this CPU to its run
+// time, and restarts the clock:
+fn charge_run_time(task_id: TaskId) {
+    let now = time::monotonic_ns();
+    let start = RUN_START.get().swap(now, Ordering::SeqCst);
+
+    if let Some(task) = TASKS.lock().get_mut(&task_id) {
+        let run_time = now.saturating_sub(start);
+
+        task.stats.run_time_ns += run_time;
+        task.vruntime += run_time * Priority::DEFAULT.weight() / task.priority.weight();
+    }
+}
+
+/// Puts a task back on the run queue if it has anything to do.
+fn requeue(task_id: TaskId) {
+    let (cpu, class, priority, vruntime) = {
+        let mut tasks = TASKS.lock();
+
+        let task = match tasks.get_mut(&task_id) {
+            Some(task) => task,
+            None => return,
+        };
+
+        let runnable = task.exit_status.is_some() || TASK_STATES.lock()
+            .get(&task_id)
+            .map(TaskState::is_runnable)
+            .unwrap_or(false);
+
+        if !runnable {
+            return;
         }
+
+        // a task that slept for a while mustn't make up for all the time it
+        // wasn't runnable:
+        let min_vruntime = RUN_QUEUE.for_cpu(task.cpu).lock().min_vruntime();
+
+        if task.vruntime < min_vruntime {
+            task.vruntime = min_vruntime;
+        }
+
+        (task.cpu, task.class, task.priority, task.vruntime)
+    };
+
+    RUN_QUEUE.for_cpu(cpu).lock().push(task_id, class, priority, vruntime);
+
+    // the other CPU may be idle, with its timer stopped. it's told whether
+    // it is or not, as it may only be on its way to idling and have already
+    // found its queue empty:
+    if cpu != smp::cpu_index() {
+        smp::reschedule(cpu);
     }
 }
 
 pub unsafe fn dispatch_syscall(frame: &mut TrapFrame) {
-    {
-        let current_task = CURRENT_TASK.lock()
-            .expect("no current task for syscall entry");
+    let allowed = match TASKS.lock().get_mut(&current()) {
+        Some(task) => {
+            task.stats.syscalls += 1;
+            task.syscall_filter.allows(frame.regs.rax)
+        }
+        None => true,
+    };
+
+    // a filtered syscall never gets as far as its handler, Yield included:
+    if !allowed {
+        frame.regs.rax = SysError::PermissionDenied as u64;
+        return;
+    }
 
-        let mut task_states = TASK_STATES.lock();
+    if frame.regs.rax == Syscall::Yield as u64 {
+        yield_current(frame);
+        return;
+    }
 
-        let task_state = task_states.get_mut(&current_task)
-            .expect("current task in TASK_STATES");
+    let current_task = current();
+
+    let entry = TASK_STATES.lock()
+        .get_mut(&current_task)
+        .expect("current task in TASK_STATES")
+        .to_entry(frame.clone());
+
+    if let Err(e) = entry {
+        // the scheduler resumed a task in user mode that it didn't think was
+        // there. whatever the task was doing in the kernel can't be trusted,
+        // but everyone else can carry on:
+        crate::println!("task::dispatch_syscall: {:?} in {:?}, killing it", e, current_task);
+        let _ = kill(current_task, ExitStatus(EXIT_KILLED));
+        switch(frame);
+        return;
+    }
 
-        match task_state {
-            TaskState::User(_) => {
-                // ok
-            }
-            _ => {
-                panic!("syscall arrived from kernel context! task state: {:?}", task_state);
-            }
+    // run the syscall on the current task straight away. most syscalls finish
+    // without blocking, and then there's no need for the scheduler at all:
+    let future = task_future(current_task);
+
+    let stack_top = TASKS.lock()
+        .get(&current_task)
+        .expect("current task in TASKS")
+        .kernel_stack
+        .top();
+
+    match poll_task(current_task, &future, stack_top) {
+        Poll::Ready(()) => {
+            // as in switch, a finished kernel future means a successful exit.
+            // the scheduler reaps the task once we switch away from it:
+            let _ = mark_exiting(current_task, ExitStatus(0));
         }
+        Poll::Pending => {
+            let exiting = TASKS.lock()
+                .get(&current_task)
+                .map(|task| task.exit_status.is_some())
+                .unwrap_or(true);
+
+            let resume = match TASK_STATES.lock().get(&current_task) {
+                Some(TaskState::User(task_frame)) => Some(task_frame.clone()),
+                _ => None,
+            };
+
+            if let (Some(task_frame), false) = (resume, exiting) {
+                // the syscall completed and the task is back in user mode:
+                *frame = task_frame;
 
-        *task_state = TaskState::SyscallEntry(frame.clone());
+                if signal::deliver(current_task, frame) {
+                    work::run_pending();
+                    return;
+                }
+            }
+        }
     }
 
-    // TODO don't switch immediately but process syscall on this task first:
+    // the syscall blocked or the task is exiting, let the scheduler sort it
+    // out:
+    drop(future);
+    switch(frame)
+}
+
+/// Handles the Yield syscall. The task stays in user mode as far as the
+/// scheduler is concerned, so it is still runnable and just goes to the back
+/// of its queue. Its kernel future is never involved.
+unsafe fn yield_current(frame: &mut TrapFrame) {
+    frame.regs.rax = OK;
+
+    // switch saves the frame as the task's user state before requeueing it:
     switch(frame)
 }
 
+/// Returns a Waker which wakes the given task when woken. The waker only
+/// carries the task's id, so it can be freely cloned and sent to interrupt
+/// handlers. Waking a task that has since exited is a no-op.
+pub fn waker(task_id: TaskId) -> Waker {
+    unsafe { Waker::from_raw(task_waker_new(task_id)) }
+}
+
+/// Wakes the given task if it is asleep. Tasks that are running user code or
+/// already awaiting a poll are left untouched.
+pub fn wake(task_id: TaskId) {
+    if let Some(state) = TASK_STATES.lock().get_mut(&task_id) {
+        if state.to_wake().is_err() {
+            // not asleep, so there's nothing to wake:
+            return;
+        }
+    }
+
+    requeue(task_id);
+}
+
 static TASK_WAKER_VTABLE: RawWakerVTable = RawWakerVTable::new(
     task_waker_clone,
     task_waker_wake,
@@ -285,11 +1630,7 @@ unsafe fn task_waker_clone(data: *const ()) -> RawWaker {
 }
 
 unsafe fn task_waker_wake(data: *const ()) {
-    let task_id = TaskId(data as u64);
-
-    if let Some(state) = TASK_STATES.lock().get_mut(&task_id) {
-        *state = TaskState::Wake;
-    }
+    wake(TaskId(data as u64));
 }
 
 unsafe fn task_waker_wake_by_ref(data: *const ()) {
@@ -318,12 +1659,18 @@ pub struct TaskRun {
 
 impl TaskRun {
     pub fn run(&mut self) -> TaskResume {
-        let mut task_states = TASK_STATES.lock();
-
-        let task_state = task_states.get_mut(&self.task_id)
-            .expect("id not in TASK_STATES");
-
-        *task_state = TaskState::User(self.trap_frame.clone());
+        let result = TASK_STATES.lock()
+            .get_mut(&self.task_id)
+            .expect("id not in TASK_STATES")
+            .to_user(self.trap_frame.clone());
+
+        if let Err(e) = result {
+            // the task would otherwise end up in user mode twice over. the
+            // returned TaskResume never completes, and the task is reaped the
+            // next time the scheduler runs:
+            crate::println!("TaskRun::run: {:?} in {:?}, killing it", e, self.task_id);
+            let _ = kill(self.task_id, ExitStatus(EXIT_KILLED));
+        }
 
         TaskResume { task_run: self }
     }
@@ -355,19 +1702,17 @@ impl<'a> Future for TaskResume<'a> {
     type Output = Trap;
 
     fn poll(mut self: Pin<&mut Self>, _cx: &mut core::task::Context) -> Poll<Self::Output> {
-        let mut task_states = TASK_STATES.lock();
-
-        let task_state = task_states.get_mut(&self.task_run.task_id)
-            .expect("id not in TASK_STATES");
-
-        let (trap, frame) = match *task_state {
-            TaskState::SyscallEntry(ref frame) => (Trap::Syscall, frame.clone()),
-            TaskState::Wake => return Poll::Pending,
-            TaskState::User(_) => return Poll::Pending,
-            TaskState::Sleep => panic!("task state should not be Sleep"),
-        };
-
-        self.task_run.trap_frame = frame;
-        Poll::Ready(trap)
+        let entry = TASK_STATES.lock()
+            .get_mut(&self.task_run.task_id)
+            .expect("id not in TASK_STATES")
+            .take_entry();
+
+        match entry {
+            Some(frame) => {
+                self.task_run.trap_frame = frame;
+                Poll::Ready(Trap::Syscall)
+            }
+            None => Poll::Pending,
+        }
     }
 }
@@ -0,0 +1,163 @@
+use core::mem;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::fs::vfs::FsContext;
+use crate::mem::MemoryExhausted;
+use crate::mem::page::PageCtx;
+use crate::object::{FdTable, ObjectRef};
+use crate::sync::{Arc, Mutex};
+
+use super::signal::SignalActions;
+
+#[derive(Debug, Clone, Copy, PartialOrd, PartialEq, Eq, Ord)]
+pub struct ProcessId(pub u64);
+
+/// Identifies a process group, which signals can be sent to as a whole, such
+/// as a shell pipeline. A group takes the id of the process that started it.
+#[derive(Debug, Clone, Copy, PartialOrd, PartialEq, Eq, Ord)]
+pub struct ProcessGroupId(pub u64);
+
+/// Who a process acts as, which decides what it may do with files. User 0 is
+/// root, which may read and write any file and become any user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Credentials {
+    pub const ROOT: Credentials = Credentials { uid: 0, gid: 0 };
+
+    pub fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+
+    /// Whether a process acting as `self` may kill, signal or move a process
+    /// acting as `other`: its own user's processes, or anyone's for root.
+    pub fn has_authority_over(&self, other: &Credentials) -> bool {
+        self.is_root() || self.uid == other.uid
+    }
+}
+
+/// A process owns the resources shared by all of its threads: the address
+/// space, the object handle table, the filesystem and the signal handlers.
+/// Every process also belongs to a process group, and acts as some user.
+#[derive(Debug)]
+pub struct Process {
+    id: ProcessId,
+    // the process that forked or created this one, if any:
+    parent: Option<ProcessId>,
+    page_ctx: Mutex<ObjectRef<PageCtx>>,
+    filesystem: Mutex<Option<Arc<FsContext>>>,
+    handles: Mutex<FdTable>,
+    signal_actions: Mutex<SignalActions>,
+    group: Mutex<ProcessGroupId>,
+    // whether exec places the stack, heap and mmap region at random, see
+    // exec::Layout. kept across exec and inherited across fork:
+    aslr: AtomicBool,
+    // whether memory may be mapped writable and executable at once. kept
+    // across exec and inherited across fork:
+    write_execute: AtomicBool,
+    // processes the kernel starts run as root. kept across exec and inherited
+    // by children:
+    credentials: Mutex<Credentials>,
+}
+
+fn alloc_process_id() -> ProcessId {
+    static NEXT_PROCESS_ID: AtomicU64 = AtomicU64::new(1);
+    ProcessId(NEXT_PROCESS_ID.fetch_add(1, Ordering::SeqCst))
+}
+
+impl Process {
+    pub fn new(parent: Option<ProcessId>, page_ctx: ObjectRef<PageCtx>, filesystem: Option<Arc<FsContext>>)
+        -> Result<Arc<Process>, MemoryExhausted>
+    {
+        let id = alloc_process_id();
+
+        // until it's moved, a new process starts a group of its own:
+        Arc::new(Process {
+            id,
+            parent,
+            page_ctx: Mutex::new(page_ctx),
+            filesystem: Mutex::new(filesystem),
+            handles: Mutex::new(FdTable::new()),
+            signal_actions: Mutex::new(SignalActions::new()),
+            group: Mutex::new(ProcessGroupId(id.0)),
+            aslr: AtomicBool::new(true),
+            write_execute: AtomicBool::new(false),
+            credentials: Mutex::new(Credentials::ROOT),
+        })
+    }
+
+    pub fn id(&self) -> ProcessId {
+        self.id
+    }
+
+    pub fn parent(&self) -> Option<ProcessId> {
+        self.parent
+    }
+
+    pub fn page_ctx(&self) -> ObjectRef<PageCtx> {
+        self.page_ctx.lock().clone()
+    }
+
+    /// Replaces the page context of the process, returning the old one. Each
+    /// thread picks up the new page context the next time it is scheduled.
+    pub fn replace_page_ctx(&self, page_ctx: ObjectRef<PageCtx>) -> ObjectRef<PageCtx> {
+        mem::replace(&mut *self.page_ctx.lock(), page_ctx)
+    }
+
+    pub fn filesystem(&self) -> Option<Arc<FsContext>> {
+        self.filesystem.lock().clone()
+    }
+
+    pub fn set_filesystem(&self, filesystem: Option<Arc<FsContext>>) {
+        *self.filesystem.lock() = filesystem;
+    }
+
+    pub fn handles(&self) -> &Mutex<FdTable> {
+        &self.handles
+    }
+
+    pub fn signal_actions(&self) -> &Mutex<SignalActions> {
+        &self.signal_actions
+    }
+
+    pub fn group(&self) -> ProcessGroupId {
+        *self.group.lock()
+    }
+
+    pub fn set_group(&self, group: ProcessGroupId) {
+        *self.group.lock() = group;
+    }
+
+    pub fn aslr(&self) -> bool {
+        self.aslr.load(Ordering::SeqCst)
+    }
+
+    /// Turns address space layout randomization on or off for programs the
+    /// process execs from now on, returning whether it was on.
+    pub fn set_aslr(&self, aslr: bool) -> bool {
+        self.aslr.swap(aslr, Ordering::SeqCst)
+    }
+
+    pub fn write_execute(&self) -> bool {
+        self.write_execute.load(Ordering::SeqCst)
+    }
+
+    /// Allows or forbids mappings that are both writable and executable, both
+    /// from mmap and friends and in programs the process execs, returning
+    /// whether they were allowed. Mappings that already exist are left as
+    /// they are.
+    pub fn set_write_execute(&self, allowed: bool) -> bool {
+        self.write_execute.swap(allowed, Ordering::SeqCst)
+    }
+
+    pub fn credentials(&self) -> Credentials {
+        *self.credentials.lock()
+    }
+
+    pub fn set_credentials(&self, credentials: Credentials) {
+        *self.credentials.lock() = credentials;
+    }
+}
@@ -0,0 +1,272 @@
+use alloc_collections::btree_map::BTreeMap;
+
+use crate::config;
+use crate::mem::kalloc::GlobalAlloc;
+use crate::mem::MemoryExhausted;
+use crate::sync::Mutex;
+use crate::task::{Priority, RtPriority, SchedClass, SchedPolicy, TaskId, TaskMap};
+use crate::task::{PRIORITY_COUNT, RT_PRIORITY_COUNT};
+use crate::util::EarlyInit;
+
+// every task's place in the run queues, from when it is spawned until it is
+// reaped. tasks are linked into the queues through these, so queueing a task
+// never needs to allocate. always locked after any run queue:
+static SLOTS: TaskMap<Slot> = TaskMap::new();
+
+type Slots = BTreeMap<TaskId, Slot, GlobalAlloc>;
+
+#[derive(Debug, Default)]
+struct Slot {
+    // which CPU's run queue the task is in, and which queue there:
+    queued: Option<(usize, Position)>,
+    prev: Option<TaskId>,
+    next: Option<TaskId>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Position {
+    Realtime(RtPriority),
+    RoundRobin(Priority),
+    Fair(u64),
+}
+
+fn slot(slots: &mut Slots, task_id: TaskId) -> &mut Slot {
+    slots.get_mut(&task_id)
+        .expect("queued task has no slot")
+}
+
+// a queue of tasks linked through their slots:
+#[derive(Debug, Clone, Copy)]
+struct Queue {
+    head: Option<TaskId>,
+    tail: Option<TaskId>,
+}
+
+impl Queue {
+    const EMPTY: Queue = Queue { head: None, tail: None };
+
+    // links a task in after another, or at the front if none:
+    fn insert_after(&mut self, slots: &mut Slots, task_id: TaskId, after: Option<TaskId>) {
+        let next = match after {
+            Some(after) => slot(slots, after).next,
+            None => self.head,
+        };
+
+        {
+            let slot = slot(slots, task_id);
+            slot.prev = after;
+            slot.next = next;
+        }
+
+        match after {
+            Some(after) => slot(slots, after).next = Some(task_id),
+            None => self.head = Some(task_id),
+        }
+
+        match next {
+            Some(next) => slot(slots, next).prev = Some(task_id),
+            None => self.tail = Some(task_id),
+        }
+    }
+
+    fn push_back(&mut self, slots: &mut Slots, task_id: TaskId) {
+        let tail = self.tail;
+        self.insert_after(slots, task_id, tail);
+    }
+
+    fn unlink(&mut self, slots: &mut Slots, task_id: TaskId) {
+        let (prev, next) = {
+            let slot = slot(slots, task_id);
+            (slot.prev.take(), slot.next.take())
+        };
+
+        match prev {
+            Some(prev) => slot(slots, prev).next = next,
+            None => self.head = next,
+        }
+
+        match next {
+            Some(next) => slot(slots, next).prev = prev,
+            None => self.tail = prev,
+        }
+    }
+}
+
+pub fn init() {
+    EarlyInit::set(&SLOTS, Mutex::new(BTreeMap::new()));
+}
+
+/// Gives a new task its place in the run queues. Must be called before the
+/// task is first pushed, after which pushing it can't fail.
+pub fn reserve(task_id: TaskId) -> Result<(), MemoryExhausted> {
+    SLOTS.lock().insert(task_id, Slot::default())
+        .map(|_| ())
+        .map_err(|_| MemoryExhausted)
+}
+
+/// Returns the CPU whose run queue a task is in, if it is queued.
+pub fn queued_on(task_id: TaskId) -> Option<usize> {
+    SLOTS.lock()
+        .get(&task_id)
+        .and_then(|slot| slot.queued)
+        .map(|(cpu, _)| cpu)
+}
+
+/// Gives up a reaped task's place in the run queues, after which pushing it
+/// does nothing. Fails if the task is still queued, it must be removed first.
+pub fn release(task_id: TaskId) -> bool {
+    let mut slots = SLOTS.lock();
+
+    if slots.get(&task_id).and_then(|slot| slot.queued).is_some() {
+        return false;
+    }
+
+    slots.remove(&task_id);
+    true
+}
+
+/// Ready queues of runnable tasks. Real-time tasks have one FIFO per real-time
+/// priority, and always come first. For normal tasks, the round robin policy
+/// has one FIFO per priority level, the fair policy a single queue ordered by
+/// virtual runtime.
+pub struct RunQueue {
+    cpu: usize,
+    realtime: [Queue; RT_PRIORITY_COUNT],
+    queues: [Queue; PRIORITY_COUNT],
+    // ordered by virtual runtime, then by the order queued:
+    fair: Queue,
+    // the virtual runtime of the task most recently taken off the fair queue.
+    // it only ever increases:
+    min_vruntime: u64,
+}
+
+impl RunQueue {
+    pub fn new(cpu: usize) -> Self {
+        RunQueue {
+            cpu,
+            realtime: [Queue::EMPTY; RT_PRIORITY_COUNT],
+            queues: [Queue::EMPTY; PRIORITY_COUNT],
+            fair: Queue::EMPTY,
+            min_vruntime: 0,
+        }
+    }
+
+    /// The virtual runtime a task should have at least when it is queued, so
+    /// that new and long sleeping tasks don't get to monopolise the CPU while
+    /// they catch up with everyone else.
+    pub fn min_vruntime(&self) -> u64 {
+        self.min_vruntime
+    }
+
+    fn queue(&mut self, position: Position) -> &mut Queue {
+        match position {
+            Position::Realtime(rt_priority) => &mut self.realtime[rt_priority.index()],
+            Position::RoundRobin(priority) => &mut self.queues[priority.index()],
+            Position::Fair(_) => &mut self.fair,
+        }
+    }
+
+    /// Appends a task to the back of the queue for its real-time priority or
+    /// priority, or under the fair policy, queues a normal task by its virtual
+    /// runtime. Does nothing if the task is already queued, or has been
+    /// released.
+    pub fn push(&mut self, task_id: TaskId, class: SchedClass, priority: Priority, vruntime: u64) {
+        let mut slots = SLOTS.lock();
+        let slots = &mut *slots;
+
+        match slots.get(&task_id) {
+            Some(slot) if slot.queued.is_none() => {}
+            _ => return,
+        }
+
+        let position = match (class.rt_priority(), config::SCHED_POLICY) {
+            (Some(rt_priority), _) => Position::Realtime(rt_priority),
+            (None, SchedPolicy::RoundRobin) => Position::RoundRobin(priority),
+            (None, SchedPolicy::Fair) => Position::Fair(vruntime),
+        };
+
+        match position {
+            Position::Fair(_) => {
+                // tasks with equal virtual runtime run in the order queued.
+                // most tasks go at or near the back, so look from there:
+                let mut after = self.fair.tail;
+
+                while let Some(prev) = after {
+                    let slot = slot(slots, prev);
+
+                    match slot.queued {
+                        Some((_, Position::Fair(prev_vruntime))) if prev_vruntime > vruntime => {
+                            after = slot.prev;
+                        }
+                        _ => break,
+                    }
+                }
+
+                self.fair.insert_after(slots, task_id, after);
+            }
+            _ => self.queue(position).push_back(slots, task_id),
+        }
+
+        slot(slots, task_id).queued = Some((self.cpu, position));
+    }
+
+    /// Returns the highest real-time priority of any queued task.
+    pub fn highest_rt_priority(&self) -> Option<RtPriority> {
+        (0..RT_PRIORITY_COUNT).rev()
+            .find(|index| self.realtime[*index].head.is_some())
+            .map(|index| RtPriority(index as u8))
+    }
+
+    /// Takes the task at the front of the highest priority non-empty
+    /// real-time queue. Failing that, takes the task at the front of the
+    /// highest priority non-empty queue, or under the fair policy, the task
+    /// with the lowest virtual runtime.
+    pub fn pop(&mut self) -> Option<TaskId> {
+        let mut slots = SLOTS.lock();
+        let slots = &mut *slots;
+
+        let realtime = self.realtime.iter()
+            .rev()
+            .find_map(|queue| queue.head);
+
+        let task_id = match (realtime, config::SCHED_POLICY) {
+            (Some(task_id), _) => task_id,
+            (None, SchedPolicy::RoundRobin) => {
+                self.queues.iter()
+                    .rev()
+                    .find_map(|queue| queue.head)?
+            }
+            (None, SchedPolicy::Fair) => self.fair.head?,
+        };
+
+        let (_, position) = slot(slots, task_id).queued
+            .expect("task at front of run queue not queued");
+
+        self.queue(position).unlink(slots, task_id);
+        slot(slots, task_id).queued = None;
+
+        if let Position::Fair(vruntime) = position {
+            if vruntime > self.min_vruntime {
+                self.min_vruntime = vruntime;
+            }
+        }
+
+        Some(task_id)
+    }
+
+    /// Removes a task from whichever queue it is in, if any. Returns whether
+    /// the task was queued.
+    pub fn remove(&mut self, task_id: TaskId) -> bool {
+        let mut slots = SLOTS.lock();
+        let slots = &mut *slots;
+
+        let position = match slots.get(&task_id).and_then(|slot| slot.queued) {
+            Some((cpu, position)) if cpu == self.cpu => position,
+            _ => return false,
+        };
+
+        self.queue(position).unlink(slots, task_id);
+        slot(slots, task_id).queued = None;
+        true
+    }
+}
@@ -0,0 +1,350 @@
+use core::mem;
+use core::slice;
+
+use interface::{EXIT_FAULT, EXIT_KILLED, NSIG, SIGCHLD, SIGKILL, SIG_DFL, SIG_IGN, SysError, SysResult};
+use x86_64::registers::rflags::RFlags;
+
+use crate::critical;
+use crate::interrupt::{Registers, TrapFrame, TrapOrigin};
+use crate::mem::user;
+
+use super::{current_process, kill, process_of, Credentials, ExitStatus, NoSuchTask, Process, ProcessGroupId, TaskId, TASKS};
+
+// the part of the user stack below rsp that leaf functions may use without
+// moving rsp, which the signal frame has to stay clear of:
+const RED_ZONE: u64 = 128;
+
+// the rflags bits user code may change through a signal frame:
+fn user_rflags() -> u64 {
+    (RFlags::CARRY_FLAG
+        | RFlags::PARITY_FLAG
+        | RFlags::AUXILIARY_CARRY_FLAG
+        | RFlags::ZERO_FLAG
+        | RFlags::SIGN_FLAG
+        | RFlags::TRAP_FLAG
+        | RFlags::DIRECTION_FLAG
+        | RFlags::OVERFLOW_FLAG).bits()
+}
+
+/// A signal number, between 1 and NSIG.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Signal(u64);
+
+impl Signal {
+    pub const KILL: Signal = Signal(SIGKILL);
+
+    pub fn new(signal: u64) -> Option<Signal> {
+        if signal > 0 && signal < NSIG {
+            Some(Signal(signal))
+        } else {
+            None
+        }
+    }
+
+    pub fn into_u64(self) -> u64 {
+        self.0
+    }
+
+    fn bit(self) -> u64 {
+        1 << self.0
+    }
+
+    // what SIG_DFL does with the signal:
+    fn ignored_by_default(self) -> bool {
+        self.0 == SIGCHLD
+    }
+}
+
+/// What a process does when one of its tasks receives a signal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handler {
+    Default,
+    Ignore,
+    /// Runs `entry` on the task's user stack, which returns to `restorer`.
+    /// The restorer is expected to make the Sigreturn syscall.
+    User { entry: u64, restorer: u64 },
+}
+
+impl Handler {
+    /// Decodes the handler passed to the Sigaction syscall.
+    pub fn from_user(handler: u64, restorer: u64) -> Handler {
+        match handler {
+            SIG_DFL => Handler::Default,
+            SIG_IGN => Handler::Ignore,
+            entry => Handler::User { entry, restorer },
+        }
+    }
+
+    pub fn into_u64(self) -> u64 {
+        match self {
+            Handler::Default => SIG_DFL,
+            Handler::Ignore => SIG_IGN,
+            Handler::User { entry, .. } => entry,
+        }
+    }
+}
+
+/// The handlers of a process, one per signal.
+#[derive(Debug, Clone, Copy)]
+pub struct SignalActions([Handler; NSIG as usize]);
+
+impl SignalActions {
+    pub const fn new() -> Self {
+        SignalActions([Handler::Default; NSIG as usize])
+    }
+
+    pub fn get(&self, signal: Signal) -> Handler {
+        self.0[signal.0 as usize]
+    }
+
+    /// A new program can't have handlers in the old one's code, so they go
+    /// back to their defaults. Ignored signals stay ignored.
+    pub fn reset_for_exec(&mut self) {
+        for handler in self.0.iter_mut() {
+            if let Handler::User { .. } = handler {
+                *handler = Handler::Default;
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct IllegalSignal;
+
+impl From<IllegalSignal> for SysError {
+    fn from(_: IllegalSignal) -> SysError {
+        SysError::IllegalValue
+    }
+}
+
+/// Sets the current process's handler for a signal, returning the old one.
+/// SIGKILL always terminates, so its handler can't be changed.
+pub fn set_handler(signal: Signal, handler: Handler) -> Result<Handler, IllegalSignal> {
+    if signal == Signal::KILL {
+        return Err(IllegalSignal);
+    }
+
+    let process = current_process();
+    let mut actions = process.signal_actions().lock();
+
+    let old = actions.get(signal);
+    actions.0[signal.0 as usize] = handler;
+
+    Ok(old)
+}
+
+/// Sends a signal to a task. Signals with a handler are left pending until the
+/// task next returns to user mode, others take effect straight away.
+pub fn send(task_id: TaskId, signal: Signal) -> Result<(), NoSuchTask> {
+    let process = process_of(task_id)?;
+
+    let handler = process.signal_actions().lock().get(signal);
+
+    match handler {
+        Handler::User { .. } if signal != Signal::KILL => {
+            TASKS.lock()
+                .get_mut(&task_id)
+                .ok_or(NoSuchTask)?
+                .pending_signals |= signal.bit();
+
+            Ok(())
+        }
+        Handler::Ignore if signal != Signal::KILL => Ok(()),
+        _ if signal.ignored_by_default() => Ok(()),
+        _ => kill(task_id, ExitStatus(EXIT_KILLED)),
+    }
+}
+
+/// Sends a signal to every task of every process in a group. Fails with
+/// NoSuchTask if the group is empty.
+pub fn send_group(group: ProcessGroupId, signal: Signal) -> Result<(), NoSuchTask> {
+    match send_group_where(group, signal, |_| true) {
+        (_, true) => Ok(()),
+        (_, false) => Err(NoSuchTask),
+    }
+}
+
+/// Sends a signal on behalf of a process acting as `credentials`, to the
+/// tasks in a group whose processes it has authority over. Fails with
+/// PermissionDenied if the group has tasks but none of those.
+pub fn send_group_as(group: ProcessGroupId, signal: Signal, credentials: &Credentials) -> SysResult<()> {
+    let allowed = |process: &Process| credentials.has_authority_over(&process.credentials());
+
+    match send_group_where(group, signal, allowed) {
+        (_, true) => Ok(()),
+        (true, false) => Err(SysError::PermissionDenied),
+        (false, false) => Err(SysError::NoTask),
+    }
+}
+
+// returns whether the group had any tasks, and whether any of them were
+// allowed and sent the signal:
+fn send_group_where(group: ProcessGroupId, signal: Signal, allowed: impl Fn(&Process) -> bool)
+    -> (bool, bool)
+{
+    let mut next = TaskId(0);
+    let mut found = false;
+    let mut sent = false;
+
+    // send takes the TASKS lock, so the tasks are looked up one at a time:
+    loop {
+        let task = TASKS.lock()
+            .range(next..)
+            .find(|(_, task)| task.process.group() == group)
+            .map(|(task_id, task)| (*task_id, allowed(&task.process)));
+
+        let (task_id, permitted) = match task {
+            Some(task) => task,
+            None => break,
+        };
+
+        found = true;
+
+        // the task may have exited since:
+        if permitted && send(task_id, signal).is_ok() {
+            sent = true;
+        }
+
+        next = TaskId(task_id.0 + 1);
+    }
+
+    (found, sent)
+}
+
+/// Whether the current process has a handler for the signal, for signals
+/// raised by the kernel that would otherwise kill the task.
+pub fn is_handled(signal: Signal) -> bool {
+    match current_process().signal_actions().lock().get(signal) {
+        Handler::User { .. } => true,
+        Handler::Default | Handler::Ignore => false,
+    }
+}
+
+// what's pushed onto the user stack for a handler, and restored by Sigreturn:
+#[repr(C)]
+#[derive(Clone)]
+struct SignalFrame {
+    regs: Registers,
+    rip: u64,
+    rflags: u64,
+    rsp: u64,
+    signal: u64,
+}
+
+/// Runs the handler of the lowest numbered pending signal of a task that's
+/// about to return to user mode through `frame`, by rewriting the frame to
+/// enter the handler instead. Returns false if the task was terminated
+/// instead, in which case it mustn't return to user mode.
+pub fn deliver(task_id: TaskId, frame: &mut TrapFrame) -> bool {
+    if let TrapOrigin::Kernel = frame.origin() {
+        return true;
+    }
+
+    loop {
+        let (signal, process) = {
+            let mut tasks = TASKS.lock();
+
+            let task = match tasks.get_mut(&task_id) {
+                Some(task) => task,
+                None => return true,
+            };
+
+            if task.pending_signals == 0 {
+                return true;
+            }
+
+            let signal = Signal(task.pending_signals.trailing_zeros() as u64);
+            task.pending_signals &= !signal.bit();
+
+            (signal, task.process.clone())
+        };
+
+        // the handler may have changed since the signal was sent:
+        let handler = process.signal_actions().lock().get(signal);
+
+        match handler {
+            Handler::User { entry, restorer } => {
+                if push_frame(frame, signal, entry, restorer).is_err() {
+                    // nowhere to run the handler:
+                    let _ = kill(task_id, ExitStatus(EXIT_FAULT));
+                    return false;
+                }
+
+                return true;
+            }
+            Handler::Ignore => {}
+            Handler::Default if signal.ignored_by_default() => {}
+            Handler::Default => {
+                let _ = kill(task_id, ExitStatus(EXIT_KILLED));
+                return false;
+            }
+        }
+    }
+}
+
+fn push_frame(frame: &mut TrapFrame, signal: Signal, entry: u64, restorer: u64) -> SysResult<()> {
+    let saved = SignalFrame {
+        regs: frame.regs.clone(),
+        rip: frame.rip,
+        rflags: frame.rflags,
+        rsp: frame.rsp,
+        signal: signal.0,
+    };
+
+    let frame_size = mem::size_of::<SignalFrame>() as u64;
+
+    // the handler is entered as if called, with the restorer as its return
+    // address just below the 16 byte aligned signal frame:
+    let frame_addr = frame.rsp.checked_sub(RED_ZONE + frame_size)
+        .ok_or(SysError::BadPointer)? & !15;
+
+    let return_addr = frame_addr - 8;
+
+    let crit = critical::begin();
+    user::copy_to_user(frame_addr, as_bytes(&saved), &crit)?;
+    user::copy_to_user(return_addr, &restorer.to_ne_bytes(), &crit)?;
+
+    frame.rip = entry;
+    frame.rsp = return_addr;
+    frame.rflags &= !RFlags::DIRECTION_FLAG.bits();
+    frame.regs.rdi = signal.0;
+    frame.regs.rsi = frame_addr;
+
+    Ok(())
+}
+
+/// Handles the Sigreturn syscall, restoring the state saved in the signal
+/// frame at `frame_addr` to `frame`. Returns the saved rax, which the syscall
+/// returns in rax.
+pub fn restore(frame: &mut TrapFrame, frame_addr: u64) -> SysResult<u64> {
+    let mut saved = SignalFrame {
+        regs: Registers::default(),
+        rip: 0,
+        rflags: 0,
+        rsp: 0,
+        signal: 0,
+    };
+
+    {
+        let crit = critical::begin();
+        user::copy_from_user(as_bytes_mut(&mut saved), frame_addr, &crit)?;
+    }
+
+    frame.regs = saved.regs;
+    frame.rip = saved.rip;
+    frame.rsp = saved.rsp;
+
+    // the frame lives in user memory, so only let it change flags user code
+    // could have changed itself:
+    frame.rflags = (frame.rflags & !user_rflags()) | (saved.rflags & user_rflags());
+
+    Ok(frame.regs.rax)
+}
+
+fn as_bytes(frame: &SignalFrame) -> &[u8] {
+    unsafe { slice::from_raw_parts(frame as *const SignalFrame as *const u8, mem::size_of::<SignalFrame>()) }
+}
+
+fn as_bytes_mut(frame: &mut SignalFrame) -> &mut [u8] {
+    unsafe { slice::from_raw_parts_mut(frame as *mut SignalFrame as *mut u8, mem::size_of::<SignalFrame>()) }
+}
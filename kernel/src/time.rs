@@ -0,0 +1,337 @@
+use core::cmp;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+
+use crate::config;
+use crate::cpu::{self, Features};
+use crate::critical;
+use crate::device::{hpet, pit, rtc};
+use crate::mem::MemoryExhausted;
+use crate::smp;
+use crate::sync::Mutex;
+use crate::task;
+use crate::util::EarlyInit;
+
+pub mod timer;
+mod tsc;
+mod wheel;
+use wheel::{TimerId, Wheel};
+
+const NS_PER_SEC: u64 = 1_000_000_000;
+
+pub const NS_PER_TICK: u64 = NS_PER_SEC / config::TIMER_HZ as u64;
+
+// how long the TSC and local APIC timer are measured for:
+const CALIBRATION_NS: u64 = 10_000_000;
+
+// the wall clock time at boot, in nanoseconds since the start of 1970:
+static REALTIME_OFFSET: AtomicU64 = AtomicU64::new(0);
+static WHEEL: EarlyInit<Mutex<Wheel>> = EarlyInit::new();
+static SOURCE: EarlyInit<Source> = EarlyInit::new();
+static CLOCK: Mutex<Clock> = Mutex::new(Clock { lapic_hz: 0, lapic_base: 0, armed: 0 });
+
+/// A counter to read the time from.
+pub trait ClockSource: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Reads the counter, which never goes backwards.
+    fn read(&self) -> u64;
+
+    /// How many times a second the counter goes up.
+    fn frequency(&self) -> u64;
+}
+
+// time is read from a clock source when there is one. it's read without
+// taking any locks, so reading the time is as quick as reading the source:
+struct Source {
+    source: &'static dyn ClockSource,
+    // what it read at boot:
+    start: u64,
+}
+
+impl Source {
+    fn ns(&self) -> u64 {
+        counts_to_ns(self.source.read() - self.start, self.source.frequency())
+    }
+}
+
+// the BSP's local APIC timer runs one-shot. it's armed for the next tick
+// while there's anything to preempt, and for the next timer deadline while the
+// CPU idles. without a clock source, time is kept by adding up its counts:
+struct Clock {
+    // counts per second, 0 until it's been calibrated:
+    lapic_hz: u64,
+    // counts since boot as of when the timer was last armed:
+    lapic_base: u64,
+    // what the timer was last armed with:
+    armed: u32,
+}
+
+impl Clock {
+    fn lapic_counts(&self) -> u64 {
+        // only the BSP's timer keeps time, the APs' counts mean nothing:
+        if smp::cpu_index() != 0 {
+            return self.lapic_base;
+        }
+
+        self.lapic_base + (self.armed - smp::timer_current()) as u64
+    }
+
+    fn ns(&self) -> u64 {
+        match EarlyInit::try_get(&SOURCE) {
+            Some(source) => source.ns(),
+            None => counts_to_ns(self.lapic_counts(), self.lapic_hz),
+        }
+    }
+
+    // arms the timer to go off on the given tick, or as soon as it can if
+    // that's passed:
+    fn arm(&mut self, deadline: u64) {
+        let until_ns = deadline.saturating_mul(NS_PER_TICK).saturating_sub(self.ns());
+
+        // rounded up, so that the deadline has passed by the time it goes off:
+        let until = (until_ns / NS_PER_SEC).saturating_mul(self.lapic_hz)
+            .saturating_add((until_ns % NS_PER_SEC * self.lapic_hz + NS_PER_SEC - 1) / NS_PER_SEC);
+
+        let count = cmp::max(1, cmp::min(until, u32::max_value() as u64)) as u32;
+
+        self.lapic_base = self.lapic_counts();
+        self.armed = count;
+
+        unsafe { smp::timer_oneshot(count); }
+    }
+
+    // how many counts there are to a tick, which is all the APs' timers are
+    // ever armed for:
+    fn tick_counts(&self) -> u32 {
+        cmp::min(self.lapic_hz * NS_PER_TICK / NS_PER_SEC, u32::max_value() as u64) as u32
+    }
+}
+
+fn counts_to_ns(counts: u64, hz: u64) -> u64 {
+    match hz {
+        0 => 0,
+        hz => counts / hz * NS_PER_SEC + counts % hz * NS_PER_SEC / hz,
+    }
+}
+
+// returns how far `counter` goes in CALIBRATION_NS, timed by `reference` if
+// there is one, or else the PIT:
+fn measure(reference: Option<&dyn ClockSource>, counter: impl Fn() -> u64) -> u64 {
+    match reference {
+        Some(reference) => critical::section(|| {
+            let end = reference.read() + reference.frequency() * CALIBRATION_NS / NS_PER_SEC;
+            let start = counter();
+
+            while reference.read() < end {
+                core::sync::atomic::spin_loop_hint();
+            }
+
+            counter() - start
+        }),
+        None => {
+            let mut start = 0;
+            let end = pit::measure(CALIBRATION_NS, || start = counter(), &counter);
+            end - start
+        }
+    }
+}
+
+// picks the clock source time is read from, best first. an invariant TSC is
+// the quickest to read by far:
+fn find_source() -> Option<&'static dyn ClockSource> {
+    let hpet = match unsafe { hpet::init() } {
+        Ok(hpet) => Some(hpet as &dyn ClockSource),
+        Err(e) => {
+            crate::println!("time: no HPET: {:?}", e);
+            None
+        }
+    };
+
+    // the TSC only makes a clock if it counts at the same rate whatever the
+    // CPU's clock speed or sleep state:
+    if !cpu::has(Features::INVARIANT_TSC) {
+        return hpet;
+    }
+
+    let frequency = tsc::cpuid_frequency()
+        .unwrap_or_else(|| measure(hpet, tsc::read) * NS_PER_SEC / CALIBRATION_NS);
+
+    Some(tsc::init(frequency))
+}
+
+/// Picks a clock source, calibrates the local APIC timer, which
+/// smp::init_bsp must have enabled, and starts the clock.
+pub fn init() {
+    let source = find_source();
+
+    // the timer counts down, so it's measured by how far it has to go:
+    unsafe { smp::timer_oneshot(u32::max_value()); }
+    let lapic_hz = measure(source, || (u32::max_value() - smp::timer_current()) as u64)
+        * NS_PER_SEC / CALIBRATION_NS;
+
+    {
+        let mut clock = CLOCK.lock();
+
+        // the clock starts from when calibration ended:
+        if let Some(source) = source {
+            EarlyInit::set(&SOURCE, Source { source, start: source.read() });
+        }
+
+        *clock = Clock {
+            lapic_hz,
+            lapic_base: 0,
+            armed: smp::timer_current(),
+        };
+
+        clock.arm(1);
+    }
+
+    match source {
+        Some(source) => crate::println!("time: clock source {} at {} kHz", source.name(),
+            source.frequency() / 1000),
+        None => crate::println!("time: no clock source, counting local APIC timer ticks"),
+    }
+
+    crate::println!("time: local APIC timer at {} kHz", lapic_hz / 1000);
+
+    EarlyInit::set(&WHEEL, Mutex::new(Wheel::new(ticks())));
+    timer::init();
+
+    let now = rtc::read();
+    set_realtime_ns(now.unix_seconds() * NS_PER_SEC);
+
+    crate::println!("time: {:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", now.year, now.month, now.day,
+        now.hour, now.minute, now.second);
+}
+
+/// Number of timer ticks since boot. The timer doesn't interrupt on every
+/// one while idle, but they're counted all the same.
+pub fn ticks() -> u64 {
+    monotonic_ns() / NS_PER_TICK
+}
+
+/// Nanoseconds since boot, at the clock source's resolution.
+pub fn monotonic_ns() -> u64 {
+    match EarlyInit::try_get(&SOURCE) {
+        Some(source) => source.ns(),
+        None => CLOCK.lock().ns(),
+    }
+}
+
+/// Nanoseconds since the start of 1970, UTC. Kept as an offset from the
+/// monotonic clock, so it never goes backwards unless it's set.
+pub fn realtime_ns() -> u64 {
+    REALTIME_OFFSET.load(Ordering::SeqCst) + monotonic_ns()
+}
+
+/// Sets the wall clock to `ns` since the start of 1970.
+pub fn set_realtime_ns(ns: u64) {
+    REALTIME_OFFSET.store(ns.saturating_sub(monotonic_ns()), Ordering::SeqCst);
+}
+
+/// Converts a duration in nanoseconds to a number of ticks, rounding up so
+/// that sleeps never end early.
+pub fn ns_to_ticks(ns: u64) -> u64 {
+    ns / NS_PER_TICK + if ns % NS_PER_TICK == 0 { 0 } else { 1 }
+}
+
+/// Called from the timer interrupt. Wakes any sleepers whose deadline has
+/// arrived and arms the timer again.
+pub fn tick() {
+    let now = ticks();
+
+    // wake outside of the wheel lock, waking a task takes scheduler locks:
+    loop {
+        let waker = WHEEL.lock().pop_expired(now);
+
+        match waker {
+            Some(waker) => waker.wake(),
+            None => break,
+        }
+    }
+
+    rearm();
+}
+
+/// Arms the timer for the next tick, or while the CPU is idle, for the next
+/// sleeper's deadline, so that an idle CPU isn't woken up for nothing. Called
+/// by the scheduler whenever the CPU goes idle or stops being idle.
+pub fn rearm() {
+    if smp::cpu_index() != 0 {
+        // the APs' timers keep no time and wake no sleepers, that's the BSP's
+        // job. they only go off to preempt, so an idle AP stops its timer and
+        // waits for another CPU to send it a task:
+        let count = if task::is_idle() { 0 } else { CLOCK.lock().tick_counts() };
+        unsafe { smp::timer_oneshot(count); }
+        return;
+    }
+
+    let next_deadline = if task::is_idle() {
+        WHEEL.lock().next_deadline()
+    } else {
+        None
+    };
+
+    let mut clock = CLOCK.lock();
+
+    if clock.lapic_hz == 0 {
+        return;
+    }
+
+    let deadline = match next_deadline {
+        Some(deadline) => deadline,
+        None if task::is_idle() => u64::max_value(),
+        None => clock.ns() / NS_PER_TICK + 1,
+    };
+
+    clock.arm(deadline);
+}
+
+/// Returns a future which completes once the given tick has been reached.
+pub fn sleep_until(deadline: u64) -> Sleep {
+    Sleep { deadline, timer: None }
+}
+
+/// Returns a future which completes after at least `ns` nanoseconds.
+pub fn sleep_ns(ns: u64) -> Sleep {
+    sleep_until(ticks().saturating_add(ns_to_ticks(ns)))
+}
+
+pub struct Sleep {
+    deadline: u64,
+    timer: Option<TimerId>,
+}
+
+impl Future for Sleep {
+    type Output = Result<(), MemoryExhausted>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut wheel = WHEEL.lock();
+
+        // re-register on every poll in case we're being polled with a
+        // different waker than last time:
+        if let Some(timer) = self.timer.take() {
+            wheel.cancel(timer);
+        }
+
+        match wheel.insert(self.deadline, cx.waker().clone()) {
+            Ok(None) => Poll::Ready(Ok(())),
+            Ok(Some(timer)) => {
+                self.timer = Some(timer);
+                Poll::Pending
+            }
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(timer) = self.timer.take() {
+            WHEEL.lock().cancel(timer);
+        }
+    }
+}
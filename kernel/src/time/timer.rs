@@ -0,0 +1,279 @@
+use core::fmt::{self, Debug};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Poll, RawWaker, RawWakerVTable, Waker};
+
+use alloc_collections::btree_map::BTreeMap;
+use futures::future;
+use interface::{SysError, SysResult};
+
+use crate::mem::kalloc::GlobalAlloc;
+use crate::mem::MemoryExhausted;
+use crate::object::poll::Events;
+use crate::sync::{Arc, Mutex};
+use crate::task::TaskId;
+use crate::task::signal::{self, Signal};
+use crate::util::{AtomicList, EarlyInit};
+
+use super::wheel::TimerId;
+use super::{ns_to_ticks, ticks, WHEEL};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Key(u32);
+
+struct State {
+    // the next expiry in ticks, or None while disarmed:
+    deadline: Option<u64>,
+    // ticks between expiries, or 0 for a one-shot timer:
+    interval: u64,
+    // bumped each time the timer is set, so that an expiry from before
+    // can't count:
+    generation: u32,
+    timer: Option<TimerId>,
+    // expiries since the last read:
+    expirations: u64,
+}
+
+struct Shared {
+    key: Key,
+    // where expiries go as well as to readers and pollers, if anywhere:
+    signal: Option<(TaskId, Signal)>,
+    state: Mutex<State>,
+    wakers: AtomicList<Waker>,
+}
+
+/// A timer on the timer wheel for user mode, in one-shot or periodic mode.
+/// Each expiry makes it readable, so that it can be waited on with poll or an
+/// event queue, and optionally sends a signal to the task that created it.
+pub struct Timer {
+    shared: Arc<Shared>,
+}
+
+// expiry wakers find their timer through here, so that they only need to
+// carry its key and generation:
+static TIMERS: EarlyInit<Mutex<BTreeMap<Key, Arc<Shared>, GlobalAlloc>>> = EarlyInit::new();
+
+pub fn init() {
+    EarlyInit::set(&TIMERS, Mutex::new(BTreeMap::new()));
+}
+
+fn alloc_key() -> Key {
+    static NEXT_KEY: AtomicU64 = AtomicU64::new(0);
+    Key(NEXT_KEY.fetch_add(1, Ordering::SeqCst) as u32)
+}
+
+impl Timer {
+    /// Creates a disarmed timer, which sends `signal` to `task` on expiry if
+    /// given.
+    pub fn new(signal: Option<(TaskId, Signal)>) -> Result<Timer, MemoryExhausted> {
+        let shared = Arc::new(Shared {
+            key: alloc_key(),
+            signal,
+            state: Mutex::new(State {
+                deadline: None,
+                interval: 0,
+                generation: 0,
+                timer: None,
+                expirations: 0,
+            }),
+            wakers: AtomicList::new(),
+        })?;
+
+        TIMERS.lock().insert(shared.key, shared.clone())
+            .map_err(|_| MemoryExhausted)?;
+
+        Ok(Timer { shared })
+    }
+
+    /// Arms the timer to expire `initial_ns` from now, then every
+    /// `interval_ns` after that unless it's 0. An `initial_ns` of 0 disarms
+    /// it. Expiries not yet read are forgotten either way.
+    pub fn set(&self, initial_ns: u64, interval_ns: u64) -> SysResult<()> {
+        let expired = {
+            let mut state = self.shared.state.lock();
+
+            if let Some(timer) = state.timer.take() {
+                WHEEL.lock().cancel(timer);
+            }
+
+            state.generation = state.generation.wrapping_add(1);
+            state.expirations = 0;
+
+            if initial_ns == 0 {
+                state.deadline = None;
+                state.interval = 0;
+                return Ok(());
+            }
+
+            // a periodic timer expires at most once per tick:
+            state.interval = match interval_ns {
+                0 => 0,
+                ns => ns_to_ticks(ns),
+            };
+
+            state.deadline = Some(ticks().saturating_add(ns_to_ticks(initial_ns)));
+
+            schedule(&self.shared, &mut state)?
+        };
+
+        if expired {
+            self.shared.notify();
+        }
+
+        Ok(())
+    }
+
+    /// Waits for the timer to expire if it hasn't since the last read, and
+    /// returns the number of times it has.
+    pub async fn read(&self) -> SysResult<u64> {
+        future::poll_fn(|ctx| {
+            // register waker before checking for expiries so that we can't
+            // miss one in between:
+            if let Err(MemoryExhausted) = self.shared.wakers.push_front(ctx.waker().clone()) {
+                return Poll::Ready(Err(SysError::MemoryExhausted));
+            }
+
+            let mut state = self.shared.state.lock();
+
+            match state.expirations {
+                0 => Poll::Pending,
+                expirations => {
+                    state.expirations = 0;
+                    Poll::Ready(Ok(expirations))
+                }
+            }
+        }).await
+    }
+
+    /// Readable once the timer has expired since the last read.
+    pub fn poll_ready(&self, waker: &Waker) -> Result<Events, MemoryExhausted> {
+        self.shared.wakers.push_front(waker.clone())?;
+
+        if self.shared.state.lock().expirations > 0 {
+            Ok(Events::IN)
+        } else {
+            Ok(Events::empty())
+        }
+    }
+}
+
+impl Shared {
+    fn notify(&self) {
+        for waker in self.wakers.take_iter() {
+            waker.wake();
+        }
+
+        if let Some((task_id, signal)) = self.signal {
+            // the task may have exited since it created the timer:
+            let _ = signal::send(task_id, signal);
+        }
+    }
+}
+
+// registers the deadline with the wheel, counting expiries that have already
+// passed on the way. returns whether there were any:
+fn schedule(shared: &Shared, state: &mut State) -> Result<bool, MemoryExhausted> {
+    let mut expired = false;
+
+    while let Some(deadline) = state.deadline {
+        let waker = expiry_waker(shared.key, state.generation);
+
+        if let Some(timer) = WHEEL.lock().insert(deadline, waker)? {
+            state.timer = Some(timer);
+            break;
+        }
+
+        expired = true;
+        state.expirations += 1;
+        state.deadline = next_deadline(deadline, state.interval);
+    }
+
+    Ok(expired)
+}
+
+fn next_deadline(deadline: u64, interval: u64) -> Option<u64> {
+    match interval {
+        0 => None,
+        interval => Some(deadline.saturating_add(interval)),
+    }
+}
+
+// called when the wheel fires one of our wakers. the timer may have been set
+// again or dropped since it was registered, in which case there's nothing to
+// do:
+fn expire(key: Key, generation: u32) {
+    let shared = match TIMERS.lock().get(&key) {
+        Some(shared) => shared.clone(),
+        None => return,
+    };
+
+    {
+        let mut state = shared.state.lock();
+
+        if state.generation != generation {
+            return;
+        }
+
+        let deadline = match state.deadline {
+            Some(deadline) => deadline,
+            None => return,
+        };
+
+        state.timer = None;
+        state.expirations += 1;
+        state.deadline = next_deadline(deadline, state.interval);
+
+        if let Err(MemoryExhausted) = schedule(&shared, &mut state) {
+            // nowhere to keep the next expiry, so it stops:
+            state.deadline = None;
+        }
+    }
+
+    shared.notify();
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        let shared = TIMERS.lock().remove(&self.shared.key);
+        drop(shared);
+
+        if let Some(timer) = self.shared.state.lock().timer.take() {
+            WHEEL.lock().cancel(timer);
+        }
+
+        self.shared.wakers.take_iter().for_each(drop);
+    }
+}
+
+impl Debug for Timer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Timer({})", self.shared.key.0)
+    }
+}
+
+static EXPIRY_WAKER_VTABLE: RawWakerVTable = RawWakerVTable::new(
+    expiry_waker_clone,
+    expiry_waker_wake,
+    expiry_waker_wake_by_ref,
+    expiry_waker_drop,
+);
+
+// the key goes in the top half of the data, the generation in the bottom:
+fn expiry_waker(key: Key, generation: u32) -> Waker {
+    let data = (key.0 as u64) << 32 | generation as u64;
+    unsafe { Waker::from_raw(RawWaker::new(data as *const (), &EXPIRY_WAKER_VTABLE)) }
+}
+
+unsafe fn expiry_waker_clone(data: *const ()) -> RawWaker {
+    RawWaker::new(data, &EXPIRY_WAKER_VTABLE)
+}
+
+unsafe fn expiry_waker_wake(data: *const ()) {
+    let data = data as u64;
+    expire(Key((data >> 32) as u32), data as u32);
+}
+
+unsafe fn expiry_waker_wake_by_ref(data: *const ()) {
+    expiry_waker_wake(data);
+}
+
+unsafe fn expiry_waker_drop(_data: *const ()) {}
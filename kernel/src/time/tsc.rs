@@ -0,0 +1,53 @@
+use core::arch::x86_64::_rdtsc;
+
+use crate::cpu;
+use crate::util::EarlyInit;
+
+use super::ClockSource;
+
+const CPUID_TSC_CRYSTAL: u32 = 0x0000_0015;
+
+/// The CPU's time stamp counter, used as a clock source when it's invariant.
+pub struct Tsc {
+    frequency: u64,
+}
+
+static TSC: EarlyInit<Tsc> = EarlyInit::new();
+
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// The TSC's frequency in Hz, if the CPU says what it is. Most only say
+/// what their crystal's is and how the TSC's relates to it, if that.
+pub fn cpuid_frequency() -> Option<u64> {
+    // eax and ebx are the denominator and numerator of the ratio of the TSC's
+    // frequency to the crystal's, which is in ecx:
+    let leaf = cpu::cpuid(CPUID_TSC_CRYSTAL, 0)?;
+
+    if leaf.eax == 0 || leaf.ebx == 0 || leaf.ecx == 0 {
+        return None;
+    }
+
+    Some(leaf.ecx as u64 * leaf.ebx as u64 / leaf.eax as u64)
+}
+
+/// Makes the TSC, counting at `frequency`, a clock source.
+pub fn init(frequency: u64) -> &'static Tsc {
+    EarlyInit::set(&TSC, Tsc { frequency });
+    &TSC
+}
+
+impl ClockSource for Tsc {
+    fn name(&self) -> &'static str {
+        "TSC"
+    }
+
+    fn read(&self) -> u64 {
+        read()
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+}
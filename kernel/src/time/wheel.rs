@@ -0,0 +1,106 @@
+use core::task::Waker;
+
+use alloc_collections::btree_map::BTreeMap;
+use arrayvec::ArrayVec;
+
+use crate::mem::kalloc::GlobalAlloc;
+use crate::mem::MemoryExhausted;
+
+const WHEEL_SLOTS: usize = 64;
+
+/// Identifies a timer registered with a Wheel, for cancellation.
+#[derive(Debug, Clone, Copy, PartialOrd, PartialEq, Eq, Ord)]
+pub struct TimerId {
+    deadline: u64,
+    seq: u64,
+}
+
+/// A hashed timer wheel. Timers are placed in the slot corresponding to their
+/// deadline tick modulo the number of slots, so each tick only has to look at
+/// one slot. Timers more than one revolution away just stay in their slot
+/// until their deadline comes around.
+pub struct Wheel {
+    // last tick that has been fully expired:
+    current: u64,
+    next_seq: u64,
+    slots: ArrayVec<[Slot; WHEEL_SLOTS]>,
+}
+
+type Slot = BTreeMap<TimerId, Waker, GlobalAlloc>;
+
+impl Wheel {
+    pub fn new(now: u64) -> Self {
+        let mut slots = ArrayVec::new();
+
+        while !slots.is_full() {
+            slots.push(BTreeMap::new());
+        }
+
+        Wheel {
+            current: now,
+            next_seq: 0,
+            slots,
+        }
+    }
+
+    fn slot(&mut self, tick: u64) -> &mut Slot {
+        &mut self.slots[(tick % WHEEL_SLOTS as u64) as usize]
+    }
+
+    /// Registers a waker to be woken at the given deadline tick. Returns None
+    /// if the deadline has already passed, in which case nothing is
+    /// registered.
+    pub fn insert(&mut self, deadline: u64, waker: Waker) -> Result<Option<TimerId>, MemoryExhausted> {
+        if deadline <= self.current {
+            return Ok(None);
+        }
+
+        let id = TimerId { deadline, seq: self.next_seq };
+
+        self.slot(deadline).insert(id, waker)
+            .map_err(|_| MemoryExhausted)?;
+
+        self.next_seq += 1;
+
+        Ok(Some(id))
+    }
+
+    /// Cancels a timer. Returns false if the timer has already fired.
+    pub fn cancel(&mut self, id: TimerId) -> bool {
+        self.slot(id.deadline).remove(&id).is_some()
+    }
+
+    /// Returns the earliest deadline of any timer still registered.
+    pub fn next_deadline(&self) -> Option<u64> {
+        // each slot is ordered by deadline, so only its first timer counts:
+        self.slots.iter()
+            .filter_map(|slot| slot.keys().next())
+            .map(|id| id.deadline)
+            .min()
+    }
+
+    /// Removes and returns the next expired timer's waker, advancing the wheel
+    /// one tick at a time up to `now`. Returns None once every timer due by
+    /// `now` has been returned.
+    pub fn pop_expired(&mut self, now: u64) -> Option<Waker> {
+        loop {
+            let current = self.current;
+            let slot = self.slot(current);
+
+            let expired = slot.keys()
+                .next()
+                .cloned()
+                .filter(|id| id.deadline <= current);
+
+            if let Some(id) = expired {
+                return slot.remove(&id);
+            }
+
+            if current >= now {
+                return None;
+            }
+
+            self.current += 1;
+        }
+    }
+}
@@ -35,6 +35,15 @@ impl<T> EarlyInit<T> {
 
         early_init.init.store(SAFE, Ordering::SeqCst);
     }
+
+    /// Returns the value, or None if it hasn't been set yet.
+    pub fn try_get(early_init: &Self) -> Option<&T> {
+        if early_init.init.load(Ordering::Acquire) != SAFE {
+            return None;
+        }
+
+        Some(unsafe { &*(*early_init.value.get()).as_ptr() })
+    }
 }
 
 impl<T> Deref for EarlyInit<T> {
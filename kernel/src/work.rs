@@ -0,0 +1,72 @@
+use crate::sync::Mutex;
+
+// number of work items each CPU can have queued:
+const QUEUE_LEN: usize = 32;
+
+#[derive(Clone, Copy)]
+struct Work {
+    f: fn(u64),
+    arg: u64,
+}
+
+// a fixed size ring, so that queueing work never allocates:
+struct Queue {
+    items: [Option<Work>; QUEUE_LEN],
+    head: usize,
+    len: usize,
+}
+
+impl Queue {
+    const fn new() -> Self {
+        Queue { items: [None; QUEUE_LEN], head: 0, len: 0 }
+    }
+
+    fn push(&mut self, work: Work) -> Result<(), QueueFull> {
+        if self.len == QUEUE_LEN {
+            return Err(QueueFull);
+        }
+
+        self.items[(self.head + self.len) % QUEUE_LEN] = Some(work);
+        self.len += 1;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Option<Work> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let work = self.items[self.head].take();
+        self.head = (self.head + 1) % QUEUE_LEN;
+        self.len -= 1;
+        work
+    }
+}
+
+crate::percpu! {
+    static QUEUE: Mutex<Queue> = Mutex::new(Queue::new());
+}
+
+#[derive(Debug)]
+pub struct QueueFull;
+
+/// Queues `f(arg)` to run on this CPU the next time it leaves an interrupt
+/// for user mode or runs the scheduler. Never allocates, so interrupt handlers
+/// can use it to push anything slow or lock heavy out of the handler itself.
+pub fn defer(f: fn(u64), arg: u64) -> Result<(), QueueFull> {
+    QUEUE.get().lock().push(Work { f, arg })
+}
+
+/// Runs all work queued on this CPU, including anything queued by the work
+/// itself.
+pub fn run_pending() {
+    loop {
+        // take one item at a time, the work runs outside of the queue lock:
+        let work = QUEUE.get().lock().pop();
+
+        match work {
+            Some(work) => (work.f)(work.arg),
+            None => break,
+        }
+    }
+}
@@ -1,18 +1,146 @@
+use core::convert::TryInto;
+use core::mem;
+
 use crate::Handle;
 use crate::io::{Result, Read, Write};
 use crate::syscall;
 
+pub use interface::{Dirent, MountRequest, Stat};
+pub use interface::{FILE_KIND_CONSOLE, FILE_KIND_DIRECTORY, FILE_KIND_FILE, FILE_KIND_PIPE};
+pub use interface::{OPEN_APPEND, OPEN_CREATE, OPEN_DIRECTORY, OPEN_EXCLUSIVE, OPEN_WRITE};
+pub use interface::{SEEK_CUR, SEEK_END, SEEK_SET};
+
 #[derive(Clone)]
 pub struct File(Handle);
 
 impl File {
     pub fn open(path: &[u8]) -> Result<File> {
+        File::open_with(path, 0)
+    }
+
+    /// Opens a file with the given OPEN_* flags.
+    pub fn open_with(path: &[u8], flags: u64) -> Result<File> {
         let ret = unsafe {
-            syscall::open_file(path.as_ptr(), path.len() as u64, 0)
+            syscall::open_file(path.as_ptr(), path.len() as u64, flags)
         };
 
         Result::from(ret).map(File)
     }
+
+    /// Moves the position reads and writes happen at, with `whence` one of
+    /// the SEEK_* constants. Returns the new position.
+    pub fn seek(&self, offset: i64, whence: u64) -> Result<u64> {
+        unsafe { syscall::seek(self.0.as_raw(), offset, whence) }.into()
+    }
+
+    pub fn stat(&self) -> Result<Stat> {
+        let mut stat = Stat::default();
+        let result: Result<u64> = unsafe { syscall::fstat(self.0.as_raw(), &mut stat) }.into();
+        result.map(|_| stat)
+    }
+
+    /// Fills `buf` with Dirent records for the next entries of a directory,
+    /// returning how many bytes of them there are, which is 0 once every
+    /// entry has been listed. DirEntries goes through them.
+    pub fn read_dir(&self, buf: &mut [u8]) -> Result<usize> {
+        let result: Result<u64> = unsafe {
+            syscall::getdents(self.0.as_raw(), buf.as_mut_ptr(), buf.len() as u64)
+        }.into();
+
+        result.map(|len| len as usize)
+    }
+}
+
+/// Describes the file at `path`.
+pub fn stat(path: &[u8]) -> Result<Stat> {
+    let mut stat = Stat::default();
+    let result: Result<u64> = unsafe { syscall::stat(path.as_ptr(), path.len() as u64, &mut stat) }.into();
+    result.map(|_| stat)
+}
+
+/// Mounts a filesystem of type `fstype` at `target`, on the block device
+/// `source` for the types kept on disk. See MountRequest for the types.
+pub fn mount(fstype: &[u8], source: &[u8], target: &[u8]) -> Result<()> {
+    let request = MountRequest {
+        fstype: fstype.as_ptr() as u64,
+        fstype_len: fstype.len() as u64,
+        source: source.as_ptr() as u64,
+        source_len: source.len() as u64,
+        target: target.as_ptr() as u64,
+        target_len: target.len() as u64,
+    };
+
+    let result: Result<u64> = unsafe { syscall::mount(&request) }.into();
+    result.map(|_| ())
+}
+
+pub fn umount(path: &[u8]) -> Result<()> {
+    let result: Result<u64> = unsafe { syscall::umount(path.as_ptr(), path.len() as u64) }.into();
+    result.map(|_| ())
+}
+
+/// Changes the directory relative paths start from.
+pub fn chdir(path: &[u8]) -> Result<()> {
+    let result: Result<u64> = unsafe { syscall::chdir(path.as_ptr(), path.len() as u64) }.into();
+    result.map(|_| ())
+}
+
+/// Copies the working directory into `buf`, returning its length.
+pub fn getcwd(buf: &mut [u8]) -> Result<usize> {
+    let result: Result<u64> = unsafe { syscall::getcwd(buf.as_mut_ptr(), buf.len() as u64) }.into();
+    result.map(|len| len as usize)
+}
+
+/// Makes `path` the root of every path this process resolves from now on,
+/// and its working directory.
+pub fn chroot(path: &[u8]) -> Result<()> {
+    let result: Result<u64> = unsafe { syscall::chroot(path.as_ptr(), path.len() as u64) }.into();
+    result.map(|_| ())
+}
+
+/// Removes the file or empty directory at `path`.
+pub fn unlink(path: &[u8]) -> Result<()> {
+    let result: Result<u64> = unsafe { syscall::unlink(path.as_ptr(), path.len() as u64) }.into();
+    result.map(|_| ())
+}
+
+/// Moves the file or directory at `from` to `to`, which must be in the same
+/// filesystem.
+pub fn rename(from: &[u8], to: &[u8]) -> Result<()> {
+    let result: Result<u64> = unsafe {
+        syscall::rename(from.as_ptr(), from.len() as u64, to.as_ptr(), to.len() as u64)
+    }.into();
+
+    result.map(|_| ())
+}
+
+/// The names and FILE_KIND_* kinds of the entries File::read_dir filled a
+/// buffer with.
+pub struct DirEntries<'a>(&'a [u8]);
+
+impl<'a> DirEntries<'a> {
+    pub fn new(records: &'a [u8]) -> Self {
+        DirEntries(records)
+    }
+}
+
+impl<'a> Iterator for DirEntries<'a> {
+    type Item = (&'a [u8], u32);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        const HEADER_SIZE: usize = mem::size_of::<Dirent>();
+
+        let header = self.0.get(..HEADER_SIZE)?;
+
+        let record_len = u16::from_ne_bytes(header[8..10].try_into().ok()?) as usize;
+        let name_len = u16::from_ne_bytes(header[10..12].try_into().ok()?) as usize;
+        let kind = u32::from_ne_bytes(header[12..16].try_into().ok()?);
+
+        let name = self.0.get(HEADER_SIZE..(HEADER_SIZE + name_len))?;
+        self.0 = self.0.get(record_len..).unwrap_or(&[]);
+
+        Some((name, kind))
+    }
 }
 
 impl Read for File {
@@ -33,6 +33,30 @@ pub fn console() -> Console {
 #[derive(Clone)]
 pub struct Console(ManuallyDrop<Handle>);
 
+impl Console {
+    /// Makes a process group the console's foreground group, which gets
+    /// SIGINT when Ctrl-C is typed. Group 0 is the current process's group.
+    pub fn set_foreground(&self, group: u64) -> Result<()> {
+        let result: Result<u64> = unsafe { syscall::set_foreground(self.0.as_raw(), group) }.into();
+        result.map(|_| ())
+    }
+
+    pub fn foreground(&self) -> Result<u64> {
+        unsafe { syscall::get_foreground(self.0.as_raw()) }.into()
+    }
+
+    /// The console's TTY_* modes.
+    pub fn mode(&self) -> Result<u64> {
+        unsafe { syscall::ioctl(self.0.as_raw(), interface::TTY_GET_MODE, 0) }.into()
+    }
+
+    /// Sets the console's TTY_* modes, none of them for raw input.
+    pub fn set_mode(&self, mode: u64) -> Result<()> {
+        let result: Result<u64> = unsafe { syscall::ioctl(self.0.as_raw(), interface::TTY_SET_MODE, mode) }.into();
+        result.map(|_| ())
+    }
+}
+
 impl Read for Console {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
         let result = unsafe {
@@ -57,3 +81,99 @@ impl Write for Console {
         Ok(())
     }
 }
+
+/// Creates a pipe, returning its read and write ends. Reads wait for data and
+/// return 0 once every write end is closed.
+pub fn pipe() -> Result<(PipeReader, PipeWriter)> {
+    let mut handles = [0u64; 2];
+    let result: Result<u64> = unsafe { syscall::pipe(&mut handles) }.into();
+    result?;
+
+    unsafe {
+        Ok((PipeReader(Handle::from_raw(handles[0])), PipeWriter(Handle::from_raw(handles[1]))))
+    }
+}
+
+#[derive(Clone)]
+pub struct PipeReader(Handle);
+
+#[derive(Clone)]
+pub struct PipeWriter(Handle);
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let result = unsafe {
+            syscall::read_stream(self.0.as_raw(), buf.as_mut_ptr(), buf.len() as u64)
+        };
+
+        result.into()
+    }
+}
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let result = unsafe {
+            syscall::write_stream(self.0.as_raw(), buf.as_ptr(), buf.len() as u64)
+        };
+
+        result.into()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        // writes go straight into the pipe
+        Ok(())
+    }
+}
+
+pub use interface::{PollFd, POLLIN, POLLOUT, POLLERR, POLLHUP, POLLNVAL, POLL_MAX};
+
+/// Waits until one of the handles in `fds` is ready for one of its events, or
+/// `timeout_ns` has passed if given. Returns how many are ready, with their
+/// `revents` set, or 0 on timeout.
+pub fn poll(fds: &mut [PollFd], timeout_ns: Option<u64>) -> Result<usize> {
+    let timeout_ns = timeout_ns.unwrap_or(interface::POLL_INFINITE);
+    unsafe { syscall::poll(fds.as_mut_ptr(), fds.len() as u64, timeout_ns) }.into()
+}
+
+pub use interface::{EvqEvent, EVQ_EDGE, EVQ_MAX_EVENTS};
+
+/// A set of handles to wait on at once, which only costs as much as the
+/// number that are ready.
+pub struct EventQueue(Handle);
+
+impl EventQueue {
+    pub fn new() -> Result<EventQueue> {
+        Result::from(unsafe { syscall::evq_create() }).map(EventQueue)
+    }
+
+    /// Waits for `events`, POLL* events optionally along with EVQ_EDGE, on
+    /// `handle`. They're reported along with `data`.
+    pub fn add(&self, handle: &Handle, events: u64, data: u64) -> Result<()> {
+        let result: Result<u64> = unsafe {
+            syscall::evq_add(self.0.as_raw(), handle.as_raw(), events, data)
+        }.into();
+
+        result.map(|_| ())
+    }
+
+    pub fn remove(&self, handle: &Handle) -> Result<()> {
+        let result: Result<u64> = unsafe { syscall::evq_remove(self.0.as_raw(), handle.as_raw()) }.into();
+        result.map(|_| ())
+    }
+
+    /// Waits until one of the handles is ready, or `timeout_ns` has passed if
+    /// given, filling in `events`. Returns how many were filled in.
+    pub fn wait(&self, events: &mut [EvqEvent], timeout_ns: Option<u64>) -> Result<usize> {
+        let timeout_ns = timeout_ns.unwrap_or(interface::POLL_INFINITE);
+        let max = events.len().min(EVQ_MAX_EVENTS as usize) as u64;
+
+        unsafe { syscall::evq_wait(self.0.as_raw(), events.as_mut_ptr(), max, timeout_ns) }.into()
+    }
+}
+
+/// Fills `buf` with random bytes from the kernel's CSPRNG, suitable for keys.
+/// Never blocks.
+pub fn getrandom(buf: &mut [u8]) -> Result<()> {
+    let result: Result<u64> = unsafe { syscall::getrandom(buf.as_mut_ptr(), buf.len() as u64) }.into();
+    result.map(|_| ())
+}
@@ -0,0 +1,163 @@
+use interface::ChannelMessage;
+
+use crate::Handle;
+use crate::io::Result;
+use crate::syscall::{self, RegisterMessage};
+
+pub use interface::{CHANNEL_HANDLES_MAX, CHANNEL_MESSAGE_MAX, CHANNEL_NAME_MAX};
+
+/// A name clients can connect to. Each connection is a new Channel.
+pub struct Listener(Handle);
+
+impl Listener {
+    pub fn bind(name: &[u8]) -> Result<Listener> {
+        let ret = unsafe { syscall::channel_listen(name.as_ptr(), name.len() as u64) };
+        Result::from(ret).map(Listener)
+    }
+
+    /// Waits for a client to connect.
+    pub fn accept(&self) -> Result<Channel> {
+        let ret = unsafe { syscall::channel_accept(self.0.as_raw()) };
+        Result::from(ret).map(Channel)
+    }
+}
+
+/// One end of a connection between two processes, which carries messages of
+/// bytes and handles.
+pub struct Channel(Handle);
+
+impl Channel {
+    pub fn connect(name: &[u8]) -> Result<Channel> {
+        let ret = unsafe { syscall::channel_connect(name.as_ptr(), name.len() as u64) };
+        Result::from(ret).map(Channel)
+    }
+
+    /// Sends `data` along with handles to the objects behind `handles`. This
+    /// process keeps its own handles.
+    pub fn send(&self, data: &[u8], handles: &[&Handle]) -> Result<()> {
+        let mut raw = [0u64; CHANNEL_HANDLES_MAX as usize];
+
+        for (raw, handle) in raw.iter_mut().zip(handles) {
+            *raw = handle.as_raw();
+        }
+
+        let message = ChannelMessage {
+            data: data.as_ptr() as u64,
+            data_len: data.len() as u64,
+            handles: raw.as_ptr() as u64,
+            handles_len: handles.len() as u64,
+        };
+
+        let result: Result<u64> = unsafe { syscall::channel_send(self.0.as_raw(), &message) }.into();
+        result.map(|_| ())
+    }
+
+    /// Waits for a message, returning the number of bytes put in `data` and
+    /// of handles put in `handles`.
+    pub fn recv(&self, data: &mut [u8], handles: &mut [Option<Handle>]) -> Result<(usize, usize)> {
+        let mut raw = [0u64; CHANNEL_HANDLES_MAX as usize];
+
+        let mut message = ChannelMessage {
+            data: data.as_mut_ptr() as u64,
+            data_len: data.len() as u64,
+            handles: raw.as_mut_ptr() as u64,
+            handles_len: handles.len().min(raw.len()) as u64,
+        };
+
+        let result: Result<u64> = unsafe { syscall::channel_recv(self.0.as_raw(), &mut message) }.into();
+        result?;
+
+        for (slot, raw) in handles.iter_mut().zip(&raw[..message.handles_len as usize]) {
+            *slot = Some(unsafe { Handle::from_raw(*raw) });
+        }
+
+        Ok((message.data_len as usize, message.handles_len as usize))
+    }
+}
+
+/// A message passed through an Endpoint: four words and optionally a handle.
+pub struct Message {
+    pub words: [u64; 4],
+    pub handle: Option<Handle>,
+}
+
+impl Message {
+    pub fn new(words: [u64; 4]) -> Message {
+        Message { words, handle: None }
+    }
+
+    // the handle passed in is only borrowed, the receiver gets its own:
+    fn to_raw(&self) -> RegisterMessage {
+        RegisterMessage {
+            words: self.words,
+            handle: self.handle.as_ref().map(Handle::as_raw).unwrap_or(0),
+        }
+    }
+
+    fn from_raw(raw: RegisterMessage) -> Message {
+        let handle = match raw.handle {
+            0 => None,
+            handle => Some(unsafe { Handle::from_raw(handle) }),
+        };
+
+        Message { words: raw.words, handle }
+    }
+}
+
+/// A synchronous rendezvous between clients, which call and wait for a reply,
+/// and servers, which receive calls and reply to them.
+pub struct Endpoint(Handle);
+
+impl Endpoint {
+    pub fn new() -> Result<Endpoint> {
+        let ret = unsafe { syscall::endpoint_create() };
+        Result::from(ret).map(Endpoint)
+    }
+
+    pub fn from_handle(handle: Handle) -> Endpoint {
+        Endpoint(handle)
+    }
+
+    pub fn handle(&self) -> &Handle {
+        &self.0
+    }
+
+    /// Sends a message and waits for the server's reply. Fails with
+    /// BrokenPipe if the server drops the call without replying.
+    pub fn call(&self, message: &Message) -> Result<Message> {
+        let mut raw = message.to_raw();
+
+        let result: Result<u64> = unsafe { syscall::call(self.0.as_raw(), &mut raw) }.into();
+        result?;
+
+        Ok(Message::from_raw(raw))
+    }
+
+    /// Waits for a call, returning its message and the Reply to answer it with.
+    pub fn receive(&self) -> Result<(Message, Reply)> {
+        let mut raw = RegisterMessage { words: [0; 4], handle: 0 };
+
+        let ret = unsafe { syscall::receive(self.0.as_raw(), &mut raw) };
+        let reply = Result::from(ret).map(Reply)?;
+
+        Ok((Message::from_raw(raw), reply))
+    }
+}
+
+/// The right to answer one call. Dropping it unused fails the call.
+pub struct Reply(Handle);
+
+impl Reply {
+    pub fn send(self, message: &Message) -> Result<()> {
+        let mut raw = message.to_raw();
+
+        let result: Result<u64> = unsafe { syscall::reply(self.0.as_raw(), &mut raw) }.into();
+
+        // the kernel has released the handle once the reply's gone:
+        if result.is_ok() {
+            core::mem::forget(self);
+        }
+
+        result.map(|_| ())
+    }
+}
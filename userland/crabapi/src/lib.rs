@@ -1,16 +1,23 @@
 #![no_std]
 #![feature(asm)]
 #![feature(core_panic)]
+#![feature(global_asm)]
 #![feature(panic_info_message)]
 #![feature(start)]
 
 pub mod fs;
 pub mod io;
+pub mod ipc;
+pub mod mem;
+pub mod signal;
+pub mod sync;
 pub mod syscall;
 pub mod task;
 
 mod panic;
 
+pub use interface::{RIGHT_DUP, RIGHT_MAP, RIGHT_READ, RIGHT_SIGNAL, RIGHT_WRITE, RIGHTS_ALL};
+
 #[repr(transparent)]
 pub struct Handle(u64);
 
@@ -22,6 +29,19 @@ impl Handle {
     pub fn as_raw(&self) -> u64 {
         self.0
     }
+
+    /// Drops every right this handle carries that isn't in `rights`, a mask
+    /// of the RIGHT_ constants, for handing to code that should be able to do
+    /// less with it. Rights can't be given back, and without RIGHT_DUP the
+    /// handle can't be cloned or sent. Returns the rights left.
+    pub fn restrict(&self, rights: u64) -> io::Result<u64> {
+        unsafe { syscall::handle_restrict(self.0, rights) }.into()
+    }
+
+    /// The rights this handle carries.
+    pub fn rights(&self) -> io::Result<u64> {
+        self.restrict(RIGHTS_ALL)
+    }
 }
 
 impl Clone for Handle {
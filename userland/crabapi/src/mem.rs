@@ -0,0 +1,61 @@
+use crate::io::Result;
+use crate::syscall;
+use crate::Handle;
+
+pub use interface::{MAP_ANONYMOUS, MAP_FIXED, PROT_EXEC, PROT_READ, PROT_WRITE};
+
+/// Maps `len` bytes of zeroed memory, rounded up to whole pages, and returns
+/// its address. Pages are only allocated when first touched. Without
+/// MAP_FIXED, `addr` is just a hint and may be null.
+pub unsafe fn mmap(addr: *mut u8, len: usize, prot: u64, flags: u64) -> Result<*mut u8> {
+    let result: Result<usize> = syscall::mmap(addr, len as u64, prot, flags).into();
+    result.map(|addr| addr as *mut u8)
+}
+
+/// Unmaps every page in the given range, whether or not it was mapped by
+/// `mmap`.
+pub unsafe fn munmap(addr: *mut u8, len: usize) -> Result<()> {
+    let result: Result<u64> = syscall::munmap(addr, len as u64).into();
+    result.map(|_| ())
+}
+
+/// Changes the protection of every page in the given range, which must all
+/// have been mapped by `mmap`, `shm_map` or `brk`.
+pub unsafe fn mprotect(addr: *mut u8, len: usize, prot: u64) -> Result<()> {
+    let result: Result<u64> = syscall::mprotect(addr, len as u64, prot).into();
+    result.map(|_| ())
+}
+
+/// Creates a shared memory object of `len` bytes of zeroed memory, rounded up
+/// to whole pages. Any process holding a handle to it can map it with
+/// `shm_map`.
+pub fn shm_create(len: usize) -> Result<Handle> {
+    unsafe { syscall::shm_create(len as u64).into() }
+}
+
+/// Maps the whole of a shared memory object and returns its address. Writes
+/// through one mapping are seen by every other. Unlike `mmap`, a non-null
+/// `addr` is where the mapping must go. Unmap it with `munmap`.
+pub unsafe fn shm_map(shm: &Handle, addr: *mut u8, prot: u64) -> Result<*mut u8> {
+    let result: Result<usize> = syscall::shm_map(shm.as_raw(), addr, prot).into();
+    result.map(|addr| addr as *mut u8)
+}
+
+/// Moves the program break, the end of the heap, to `addr` and returns it.
+/// Passing null returns the current break without moving it.
+pub unsafe fn brk(addr: *mut u8) -> Result<*mut u8> {
+    let result: Result<usize> = syscall::brk(addr).into();
+    result.map(|addr| addr as *mut u8)
+}
+
+/// Moves the program break by `increment` bytes and returns the old break,
+/// which is the start of the newly allocated memory when growing the heap.
+pub unsafe fn sbrk(increment: isize) -> Result<*mut u8> {
+    let old = brk(core::ptr::null_mut())?;
+
+    if increment != 0 {
+        brk(old.offset(increment))?;
+    }
+
+    Ok(old)
+}
@@ -0,0 +1,58 @@
+use interface::{SIG_DFL, SIG_IGN};
+
+use crate::io::Result;
+use crate::syscall;
+
+pub use interface::{NSIG, SIGINT, SIGKILL, SIGSEGV, SIGALRM, SIGTERM, SIGCHLD};
+
+/// A signal handler. It's passed the signal number and the address of the
+/// state the kernel saved, which is restored once it returns.
+pub type Handler = extern "C" fn(signal: u64, frame: *mut u8);
+
+// handlers return here, with rsp pointing at the signal frame. 35 is
+// Syscall::Sigreturn:
+global_asm!("
+    .intel_syntax noprefix
+    .global crabapi_signal_restorer
+crabapi_signal_restorer:
+    mov rdi, rsp
+    mov eax, 35
+    syscall
+    ud2
+    .att_syntax
+");
+
+extern "C" {
+    fn crabapi_signal_restorer();
+}
+
+/// Runs `handler` whenever the current process receives `signal`. Returns
+/// the previous handler's address, or SIG_DFL or SIG_IGN.
+pub fn set_handler(signal: u64, handler: Handler) -> Result<u64> {
+    let restorer = crabapi_signal_restorer as usize as u64;
+    unsafe { syscall::sigaction(signal, handler as usize as u64, restorer) }.into()
+}
+
+/// Has the current process ignore `signal`.
+pub fn ignore(signal: u64) -> Result<u64> {
+    unsafe { syscall::sigaction(signal, SIG_IGN, 0) }.into()
+}
+
+/// Restores what the kernel does with `signal` by default, which is to
+/// terminate the task for everything but SIGCHLD.
+pub fn set_default(signal: u64) -> Result<u64> {
+    unsafe { syscall::sigaction(signal, SIG_DFL, 0) }.into()
+}
+
+/// Sends `signal` to a task.
+pub fn send(task_id: u64, signal: u64) -> Result<()> {
+    let result: Result<u64> = unsafe { syscall::send_signal(task_id, signal) }.into();
+    result.map(|_| ())
+}
+
+/// Sends `signal` to every task in a process group, or in the current
+/// process's group if `group` is 0.
+pub fn send_group(group: u64, signal: u64) -> Result<()> {
+    let result: Result<u64> = unsafe { syscall::send_group_signal(group, signal) }.into();
+    result.map(|_| ())
+}
@@ -0,0 +1,86 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use interface::SysError;
+
+use crate::io::Result;
+use crate::syscall;
+
+/// Blocks while the futex word `word` holds `expected`, until another task
+/// calls `wake` on it. Returns straight away if it doesn't hold `expected`.
+pub fn wait(word: &AtomicU32, expected: u32) -> Result<()> {
+    let result: Result<u64> = unsafe { syscall::futex_wait(word as *const AtomicU32 as *const u32, expected) }.into();
+
+    match result {
+        Ok(_) | Err(SysError::WouldBlock) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Wakes up to `count` tasks blocked in `wait` on `word`, returning how many
+/// were woken.
+pub fn wake(word: &AtomicU32, count: u64) -> Result<u64> {
+    unsafe { syscall::futex_wake(word as *const AtomicU32 as *const u32, count) }.into()
+}
+
+const UNLOCKED: u32 = 0;
+const LOCKED: u32 = 1;
+// locked, and somebody may be blocked waiting for it:
+const CONTENDED: u32 = 2;
+
+/// A mutual exclusion lock which blocks in the kernel while contended,
+/// rather than spinning.
+pub struct Mutex<T> {
+    state: AtomicU32,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            state: AtomicU32::new(UNLOCKED),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<T> {
+        if self.state.compare_and_swap(UNLOCKED, LOCKED, Ordering::Acquire) != UNLOCKED {
+            // whoever unlocks it next has to wake someone, which may be us:
+            while self.state.swap(CONTENDED, Ordering::Acquire) != UNLOCKED {
+                let _ = wait(&self.state, CONTENDED);
+            }
+        }
+
+        MutexGuard { mutex: self }
+    }
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        if self.mutex.state.swap(UNLOCKED, Ordering::Release) == CONTENDED {
+            let _ = wake(&self.mutex.state, 1);
+        }
+    }
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
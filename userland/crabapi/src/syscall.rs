@@ -1,6 +1,6 @@
 use core::convert::TryInto;
 
-use interface::{SysResult, SysError, Syscall};
+use interface::{ChannelMessage, EvqEvent, MountRequest, PollFd, Stat, SysResult, SysError, Syscall, Timespec, Utsname};
 use interface::ERR_FLAG;
 
 use crate::Handle;
@@ -44,11 +44,11 @@ impl<T> From<SyscallResult> for Result<T, SysError> where T: FromSysOk {
 unsafe fn syscall0(vector: Syscall) -> SyscallResult {
     let ret: SyscallResult;
 
-    asm!("int 0x7f" :
+    asm!("syscall" :
         "={rax}"(ret)
     :
         "{rax}"(vector as u64)
-    :: "intel");
+    : "rcx", "r11" : "intel");
 
     ret
 }
@@ -56,12 +56,12 @@ unsafe fn syscall0(vector: Syscall) -> SyscallResult {
 unsafe fn syscall1(vector: Syscall, a: u64) -> SyscallResult {
     let ret: SyscallResult;
 
-    asm!("int 0x7f" :
+    asm!("syscall" :
         "={rax}"(ret)
     :
         "{rax}"(vector as u64),
         "{rdi}"(a)
-    :: "intel");
+    : "rcx", "r11" : "intel");
 
     ret
 }
@@ -69,13 +69,13 @@ unsafe fn syscall1(vector: Syscall, a: u64) -> SyscallResult {
 unsafe fn syscall2(vector: Syscall, a: u64, b: u64) -> SyscallResult {
     let ret: SyscallResult;
 
-    asm!("int 0x7f" :
+    asm!("syscall" :
         "={rax}"(ret)
     :
         "{rax}"(vector as u64),
         "{rdi}"(a),
         "{rsi}"(b)
-    :: "intel");
+    : "rcx", "r11" : "intel");
 
     ret
 }
@@ -83,14 +83,14 @@ unsafe fn syscall2(vector: Syscall, a: u64, b: u64) -> SyscallResult {
 unsafe fn syscall3(vector: Syscall, a: u64, b: u64, c: u64) -> SyscallResult {
     let ret: SyscallResult;
 
-    asm!("int 0x7f" :
+    asm!("syscall" :
         "={rax}"(ret)
     :
         "{rax}"(vector as u64),
         "{rdi}"(a),
         "{rsi}"(b),
         "{rdx}"(c)
-    :: "intel");
+    : "rcx", "r11" : "intel");
 
     ret
 }
@@ -98,15 +98,41 @@ unsafe fn syscall3(vector: Syscall, a: u64, b: u64, c: u64) -> SyscallResult {
 unsafe fn syscall4(vector: Syscall, a: u64, b: u64, c: u64, d: u64) -> SyscallResult {
     let ret: SyscallResult;
 
-    asm!("int 0x7f" :
+    asm!("syscall" :
         "={rax}"(ret)
     :
         "{rax}"(vector as u64),
         "{rdi}"(a),
         "{rsi}"(b),
         "{rdx}"(c),
-        "{rcx}"(d)
-    :: "intel");
+        "{r10}"(d)
+    : "rcx", "r11" : "intel");
+
+    ret
+}
+
+/// A message for the endpoint syscalls, which pass it in rsi, rdx, r8 and r9
+/// with a handle, or 0, in r10, rather than in memory.
+#[repr(C)]
+pub struct RegisterMessage {
+    pub words: [u64; 4],
+    pub handle: u64,
+}
+
+unsafe fn syscall_message(vector: Syscall, a: u64, message: &mut RegisterMessage) -> SyscallResult {
+    let ret: SyscallResult;
+
+    asm!("syscall" :
+        "={rax}"(ret),
+        "+{rsi}"(message.words[0]),
+        "+{rdx}"(message.words[1]),
+        "+{r8}"(message.words[2]),
+        "+{r9}"(message.words[3]),
+        "+{r10}"(message.handle)
+    :
+        "{rax}"(vector as u64),
+        "{rdi}"(a)
+    : "rcx", "r11" : "intel");
 
     ret
 }
@@ -126,6 +152,36 @@ pub unsafe extern "C" fn modify_page(base_addr: *mut u8, page_count: u64, flags:
     syscall3(Syscall::ModifyPage, base_addr as u64, page_count, flags)
 }
 
+#[export_name = "syscall_mmap"]
+pub unsafe extern "C" fn mmap(addr: *mut u8, len: u64, prot: u64, flags: u64) -> SyscallResult {
+    syscall4(Syscall::Mmap, addr as u64, len, prot, flags)
+}
+
+#[export_name = "syscall_munmap"]
+pub unsafe extern "C" fn munmap(addr: *mut u8, len: u64) -> SyscallResult {
+    syscall2(Syscall::Munmap, addr as u64, len)
+}
+
+#[export_name = "syscall_mprotect"]
+pub unsafe extern "C" fn mprotect(addr: *mut u8, len: u64, prot: u64) -> SyscallResult {
+    syscall3(Syscall::Mprotect, addr as u64, len, prot)
+}
+
+#[export_name = "syscall_brk"]
+pub unsafe extern "C" fn brk(addr: *mut u8) -> SyscallResult {
+    syscall1(Syscall::Brk, addr as u64)
+}
+
+#[export_name = "syscall_shm_create"]
+pub unsafe extern "C" fn shm_create(len: u64) -> SyscallResult {
+    syscall1(Syscall::ShmCreate, len)
+}
+
+#[export_name = "syscall_shm_map"]
+pub unsafe extern "C" fn shm_map(shm: u64, addr: *mut u8, prot: u64) -> SyscallResult {
+    syscall3(Syscall::ShmMap, shm, addr as u64, prot)
+}
+
 #[export_name = "syscall_map_physical_memory"]
 pub unsafe extern "C" fn map_physical_memory(base_addr: *mut u8, physical_addr: u64, page_count: u64, flags: u64) -> SyscallResult {
     syscall4(Syscall::MapPhysicalMemory, base_addr as u64, physical_addr, page_count, flags)
@@ -141,6 +197,16 @@ pub unsafe extern "C" fn release_handle(handle: u64) -> SyscallResult  {
     syscall1(Syscall::ReleaseHandle, handle)
 }
 
+#[export_name = "syscall_handle_restrict"]
+pub unsafe extern "C" fn handle_restrict(handle: u64, rights: u64) -> SyscallResult {
+    syscall2(Syscall::HandleRestrict, handle, rights)
+}
+
+#[export_name = "syscall_syscall_filter"]
+pub unsafe extern "C" fn syscall_filter(mode: u64, bitmap: *const u64) -> SyscallResult {
+    syscall2(Syscall::SyscallFilter, mode, bitmap as u64)
+}
+
 #[export_name = "syscall_create_page_context"]
 pub unsafe extern "C" fn create_page_context() -> SyscallResult {
     syscall0(Syscall::CreatePageContext)
@@ -166,6 +232,11 @@ pub unsafe extern "C" fn create_task(page_ctx: u64, rip: u64, rsp: u64) -> Sysca
     syscall3(Syscall::CreateTask, page_ctx, rip, rsp)
 }
 
+#[export_name = "syscall_create_thread"]
+pub unsafe extern "C" fn create_thread(rip: u64, rsp: u64) -> SyscallResult {
+    syscall2(Syscall::CreateThread, rip, rsp)
+}
+
 #[export_name = "syscall_exit"]
 pub unsafe extern "C" fn exit(status: u64) -> SyscallResult {
     syscall1(Syscall::Exit, status)
@@ -181,7 +252,337 @@ pub unsafe extern "C" fn write_stream(file: u64, buff: *const u8, buff_len: u64)
     syscall3(Syscall::WriteStream, file, buff as u64, buff_len)
 }
 
+#[export_name = "syscall_set_priority"]
+pub unsafe extern "C" fn set_priority(priority: u64) -> SyscallResult {
+    syscall1(Syscall::SetPriority, priority)
+}
+
+#[export_name = "syscall_get_priority"]
+pub unsafe extern "C" fn get_priority() -> SyscallResult {
+    syscall0(Syscall::GetPriority)
+}
+
+#[export_name = "syscall_set_sched_class"]
+pub unsafe extern "C" fn set_sched_class(class: u64, rt_priority: u64) -> SyscallResult {
+    syscall2(Syscall::SetSchedClass, class, rt_priority)
+}
+
+#[export_name = "syscall_set_affinity"]
+pub unsafe extern "C" fn set_affinity(mask: u64) -> SyscallResult {
+    syscall1(Syscall::SetAffinity, mask)
+}
+
+#[export_name = "syscall_get_affinity"]
+pub unsafe extern "C" fn get_affinity() -> SyscallResult {
+    syscall0(Syscall::GetAffinity)
+}
+
+#[export_name = "syscall_yield"]
+pub unsafe extern "C" fn yield_now() -> SyscallResult {
+    syscall0(Syscall::Yield)
+}
+
+#[export_name = "syscall_kill"]
+pub unsafe extern "C" fn kill(task_id: u64) -> SyscallResult {
+    syscall1(Syscall::Kill, task_id)
+}
+
+#[export_name = "syscall_sleep"]
+pub unsafe extern "C" fn sleep(ns: u64) -> SyscallResult {
+    syscall1(Syscall::Sleep, ns)
+}
+
+#[export_name = "syscall_fork"]
+pub unsafe extern "C" fn fork() -> SyscallResult {
+    syscall0(Syscall::Fork)
+}
+
+#[export_name = "syscall_exec"]
+pub unsafe extern "C" fn exec(path: *const u8, path_len: u64, argv: *const *const u8, envp: *const *const u8) -> SyscallResult {
+    syscall4(Syscall::Exec, path as u64, path_len, argv as u64, envp as u64)
+}
+
+#[export_name = "syscall_wait"]
+pub unsafe extern "C" fn wait(status: *mut u64) -> SyscallResult {
+    syscall1(Syscall::Wait, status as u64)
+}
+
 #[export_name = "syscall_open_file"]
 pub unsafe extern "C" fn open_file(path: *const u8, path_len: u64, flags: u64) -> SyscallResult {
     syscall3(Syscall::OpenFile, path as u64, path_len, flags)
 }
+
+#[export_name = "syscall_sigaction"]
+pub unsafe extern "C" fn sigaction(signal: u64, handler: u64, restorer: u64) -> SyscallResult {
+    syscall3(Syscall::Sigaction, signal, handler, restorer)
+}
+
+#[export_name = "syscall_send_signal"]
+pub unsafe extern "C" fn send_signal(task_id: u64, signal: u64) -> SyscallResult {
+    syscall2(Syscall::SendSignal, task_id, signal)
+}
+
+#[export_name = "syscall_close"]
+pub unsafe extern "C" fn close(handle: u64) -> SyscallResult {
+    syscall1(Syscall::Close, handle)
+}
+
+#[export_name = "syscall_dup"]
+pub unsafe extern "C" fn dup(handle: u64) -> SyscallResult {
+    syscall1(Syscall::Dup, handle)
+}
+
+#[export_name = "syscall_dup2"]
+pub unsafe extern "C" fn dup2(old: u64, new: u64) -> SyscallResult {
+    syscall2(Syscall::Dup2, old, new)
+}
+
+#[export_name = "syscall_pipe"]
+pub unsafe extern "C" fn pipe(handles: *mut [u64; 2]) -> SyscallResult {
+    syscall1(Syscall::Pipe, handles as u64)
+}
+
+#[export_name = "syscall_channel_listen"]
+pub unsafe extern "C" fn channel_listen(name: *const u8, name_len: u64) -> SyscallResult {
+    syscall2(Syscall::ChannelListen, name as u64, name_len)
+}
+
+#[export_name = "syscall_channel_connect"]
+pub unsafe extern "C" fn channel_connect(name: *const u8, name_len: u64) -> SyscallResult {
+    syscall2(Syscall::ChannelConnect, name as u64, name_len)
+}
+
+#[export_name = "syscall_channel_accept"]
+pub unsafe extern "C" fn channel_accept(listener: u64) -> SyscallResult {
+    syscall1(Syscall::ChannelAccept, listener)
+}
+
+#[export_name = "syscall_channel_send"]
+pub unsafe extern "C" fn channel_send(channel: u64, message: *const ChannelMessage) -> SyscallResult {
+    syscall2(Syscall::ChannelSend, channel, message as u64)
+}
+
+#[export_name = "syscall_channel_recv"]
+pub unsafe extern "C" fn channel_recv(channel: u64, message: *mut ChannelMessage) -> SyscallResult {
+    syscall2(Syscall::ChannelRecv, channel, message as u64)
+}
+
+#[export_name = "syscall_futex_wait"]
+pub unsafe extern "C" fn futex_wait(addr: *const u32, expected: u32) -> SyscallResult {
+    syscall2(Syscall::FutexWait, addr as u64, expected as u64)
+}
+
+#[export_name = "syscall_futex_wake"]
+pub unsafe extern "C" fn futex_wake(addr: *const u32, count: u64) -> SyscallResult {
+    syscall2(Syscall::FutexWake, addr as u64, count)
+}
+
+#[export_name = "syscall_poll"]
+pub unsafe extern "C" fn poll(fds: *mut PollFd, count: u64, timeout_ns: u64) -> SyscallResult {
+    syscall3(Syscall::Poll, fds as u64, count, timeout_ns)
+}
+
+#[export_name = "syscall_evq_create"]
+pub unsafe extern "C" fn evq_create() -> SyscallResult {
+    syscall0(Syscall::EvqCreate)
+}
+
+#[export_name = "syscall_evq_add"]
+pub unsafe extern "C" fn evq_add(evq: u64, handle: u64, events: u64, data: u64) -> SyscallResult {
+    syscall4(Syscall::EvqAdd, evq, handle, events, data)
+}
+
+#[export_name = "syscall_evq_remove"]
+pub unsafe extern "C" fn evq_remove(evq: u64, handle: u64) -> SyscallResult {
+    syscall2(Syscall::EvqRemove, evq, handle)
+}
+
+#[export_name = "syscall_evq_wait"]
+pub unsafe extern "C" fn evq_wait(evq: u64, events: *mut EvqEvent, max: u64, timeout_ns: u64) -> SyscallResult {
+    syscall4(Syscall::EvqWait, evq, events as u64, max, timeout_ns)
+}
+
+#[export_name = "syscall_endpoint_create"]
+pub unsafe extern "C" fn endpoint_create() -> SyscallResult {
+    syscall0(Syscall::EndpointCreate)
+}
+
+#[export_name = "syscall_call"]
+pub unsafe extern "C" fn call(endpoint: u64, message: *mut RegisterMessage) -> SyscallResult {
+    syscall_message(Syscall::Call, endpoint, &mut *message)
+}
+
+#[export_name = "syscall_receive"]
+pub unsafe extern "C" fn receive(endpoint: u64, message: *mut RegisterMessage) -> SyscallResult {
+    syscall_message(Syscall::Receive, endpoint, &mut *message)
+}
+
+#[export_name = "syscall_reply"]
+pub unsafe extern "C" fn reply(reply: u64, message: *mut RegisterMessage) -> SyscallResult {
+    syscall_message(Syscall::Reply, reply, &mut *message)
+}
+
+#[export_name = "syscall_set_process_group"]
+pub unsafe extern "C" fn set_process_group(task_id: u64, group: u64) -> SyscallResult {
+    syscall2(Syscall::SetProcessGroup, task_id, group)
+}
+
+#[export_name = "syscall_get_process_group"]
+pub unsafe extern "C" fn get_process_group(task_id: u64) -> SyscallResult {
+    syscall1(Syscall::GetProcessGroup, task_id)
+}
+
+#[export_name = "syscall_send_group_signal"]
+pub unsafe extern "C" fn send_group_signal(group: u64, signal: u64) -> SyscallResult {
+    syscall2(Syscall::SendGroupSignal, group, signal)
+}
+
+#[export_name = "syscall_set_foreground"]
+pub unsafe extern "C" fn set_foreground(terminal: u64, group: u64) -> SyscallResult {
+    syscall2(Syscall::SetForeground, terminal, group)
+}
+
+#[export_name = "syscall_get_foreground"]
+pub unsafe extern "C" fn get_foreground(terminal: u64) -> SyscallResult {
+    syscall1(Syscall::GetForeground, terminal)
+}
+
+#[export_name = "syscall_timer_create"]
+pub unsafe extern "C" fn timer_create(signal: u64) -> SyscallResult {
+    syscall1(Syscall::TimerCreate, signal)
+}
+
+#[export_name = "syscall_timer_set"]
+pub unsafe extern "C" fn timer_set(timer: u64, initial_ns: u64, interval_ns: u64) -> SyscallResult {
+    syscall3(Syscall::TimerSet, timer, initial_ns, interval_ns)
+}
+
+#[export_name = "syscall_timer_read"]
+pub unsafe extern "C" fn timer_read(timer: u64) -> SyscallResult {
+    syscall1(Syscall::TimerRead, timer)
+}
+
+#[export_name = "syscall_get_process_id"]
+pub unsafe extern "C" fn get_process_id() -> SyscallResult {
+    syscall0(Syscall::GetProcessId)
+}
+
+#[export_name = "syscall_get_parent_process_id"]
+pub unsafe extern "C" fn get_parent_process_id() -> SyscallResult {
+    syscall0(Syscall::GetParentProcessId)
+}
+
+#[export_name = "syscall_get_task_id"]
+pub unsafe extern "C" fn get_task_id() -> SyscallResult {
+    syscall0(Syscall::GetTaskId)
+}
+
+#[export_name = "syscall_uname"]
+pub unsafe extern "C" fn uname(buf: *mut Utsname) -> SyscallResult {
+    syscall1(Syscall::Uname, buf as u64)
+}
+
+#[export_name = "syscall_ioctl"]
+pub unsafe extern "C" fn ioctl(fd: u64, request: u64, arg: u64) -> SyscallResult {
+    syscall3(Syscall::Ioctl, fd, request, arg)
+}
+
+#[export_name = "syscall_seek"]
+pub unsafe extern "C" fn seek(fd: u64, offset: i64, whence: u64) -> SyscallResult {
+    syscall3(Syscall::Seek, fd, offset as u64, whence)
+}
+
+#[export_name = "syscall_stat"]
+pub unsafe extern "C" fn stat(path: *const u8, path_len: u64, buf: *mut Stat) -> SyscallResult {
+    syscall3(Syscall::Stat, path as u64, path_len, buf as u64)
+}
+
+#[export_name = "syscall_fstat"]
+pub unsafe extern "C" fn fstat(fd: u64, buf: *mut Stat) -> SyscallResult {
+    syscall2(Syscall::Fstat, fd, buf as u64)
+}
+
+#[export_name = "syscall_getdents"]
+pub unsafe extern "C" fn getdents(fd: u64, buf: *mut u8, len: u64) -> SyscallResult {
+    syscall3(Syscall::Getdents, fd, buf as u64, len)
+}
+
+#[export_name = "syscall_mount"]
+pub unsafe extern "C" fn mount(request: *const MountRequest) -> SyscallResult {
+    syscall1(Syscall::Mount, request as u64)
+}
+
+#[export_name = "syscall_umount"]
+pub unsafe extern "C" fn umount(path: *const u8, path_len: u64) -> SyscallResult {
+    syscall2(Syscall::Umount, path as u64, path_len)
+}
+
+#[export_name = "syscall_chdir"]
+pub unsafe extern "C" fn chdir(path: *const u8, path_len: u64) -> SyscallResult {
+    syscall2(Syscall::Chdir, path as u64, path_len)
+}
+
+#[export_name = "syscall_getcwd"]
+pub unsafe extern "C" fn getcwd(buf: *mut u8, len: u64) -> SyscallResult {
+    syscall2(Syscall::Getcwd, buf as u64, len)
+}
+
+#[export_name = "syscall_chroot"]
+pub unsafe extern "C" fn chroot(path: *const u8, path_len: u64) -> SyscallResult {
+    syscall2(Syscall::Chroot, path as u64, path_len)
+}
+
+#[export_name = "syscall_clock_gettime"]
+pub unsafe extern "C" fn clock_gettime(clock: u64, buf: *mut Timespec) -> SyscallResult {
+    syscall2(Syscall::ClockGettime, clock, buf as u64)
+}
+
+#[export_name = "syscall_reboot"]
+pub unsafe extern "C" fn reboot(action: u64) -> SyscallResult {
+    syscall1(Syscall::Reboot, action)
+}
+
+#[export_name = "syscall_set_aslr"]
+pub unsafe extern "C" fn set_aslr(enabled: u64) -> SyscallResult {
+    syscall1(Syscall::SetAslr, enabled)
+}
+
+#[export_name = "syscall_getrandom"]
+pub unsafe extern "C" fn getrandom(buf: *mut u8, len: u64) -> SyscallResult {
+    syscall2(Syscall::GetRandom, buf as u64, len)
+}
+
+#[export_name = "syscall_set_write_execute"]
+pub unsafe extern "C" fn set_write_execute(allowed: u64) -> SyscallResult {
+    syscall1(Syscall::SetWriteExecute, allowed)
+}
+
+#[export_name = "syscall_set_uid"]
+pub unsafe extern "C" fn set_uid(uid: u64) -> SyscallResult {
+    syscall1(Syscall::SetUid, uid)
+}
+
+#[export_name = "syscall_set_gid"]
+pub unsafe extern "C" fn set_gid(gid: u64) -> SyscallResult {
+    syscall1(Syscall::SetGid, gid)
+}
+
+#[export_name = "syscall_get_uid"]
+pub unsafe extern "C" fn get_uid() -> SyscallResult {
+    syscall0(Syscall::GetUid)
+}
+
+#[export_name = "syscall_get_gid"]
+pub unsafe extern "C" fn get_gid() -> SyscallResult {
+    syscall0(Syscall::GetGid)
+}
+
+#[export_name = "syscall_unlink"]
+pub unsafe extern "C" fn unlink(path: *const u8, path_len: u64) -> SyscallResult {
+    syscall2(Syscall::Unlink, path as u64, path_len)
+}
+
+#[export_name = "syscall_rename"]
+pub unsafe extern "C" fn rename(from: *const u8, from_len: u64, to: *const u8, to_len: u64) -> SyscallResult {
+    syscall4(Syscall::Rename, from as u64, from_len, to as u64, to_len)
+}
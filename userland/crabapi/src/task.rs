@@ -1,6 +1,266 @@
+use core::ptr;
+
+use interface::SysError;
+
+pub use interface::{FILTER_ALLOW, FILTER_DENY, SYSCALL_FILTER_WORDS};
+pub use interface::{SCHED_FIFO, SCHED_NORMAL, SCHED_RR};
+
+use crate::Handle;
+use crate::io::Result;
 use crate::syscall;
 
 pub fn exit(status: u64) -> ! {
     unsafe { syscall::exit(status); }
     unreachable!()
 }
+
+/// Sets the scheduling priority of the current task. Higher priorities run
+/// first; valid priorities are 0 to 7 inclusive.
+pub fn set_priority(priority: u64) -> Result<()> {
+    let result: Result<u64> = unsafe { syscall::set_priority(priority) }.into();
+    result.map(|_| ())
+}
+
+pub fn priority() -> Result<u64> {
+    unsafe { syscall::get_priority() }.into()
+}
+
+/// Sets the scheduling class of the current task: SCHED_NORMAL, or SCHED_FIFO
+/// or SCHED_RR with a real-time priority from 0 to 7 inclusive. Only root may
+/// do this.
+pub fn set_sched_class(class: u64, rt_priority: u64) -> Result<()> {
+    let result: Result<u64> = unsafe { syscall::set_sched_class(class, rt_priority) }.into();
+    result.map(|_| ())
+}
+
+/// Restricts the current task to the CPUs set in `mask`, where bit N stands
+/// for CPU N. At least one of them must be online.
+pub fn set_affinity(mask: u64) -> Result<()> {
+    let result: Result<u64> = unsafe { syscall::set_affinity(mask) }.into();
+    result.map(|_| ())
+}
+
+pub fn affinity() -> Result<u64> {
+    unsafe { syscall::get_affinity() }.into()
+}
+
+/// Gives up the CPU to any other runnable task of the same or higher priority.
+pub fn yield_now() {
+    let _: Result<u64> = unsafe { syscall::yield_now() }.into();
+}
+
+/// Blocks the current task for at least `ns` nanoseconds.
+pub fn sleep(ns: u64) -> Result<()> {
+    let result: Result<u64> = unsafe { syscall::sleep(ns) }.into();
+    result.map(|_| ())
+}
+
+/// Returns the id of the current task's process.
+pub fn process_id() -> u64 {
+    Result::from(unsafe { syscall::get_process_id() })
+        .expect("syscall::get_process_id")
+}
+
+/// Returns the id of the process that created the current one, or 0 if the
+/// kernel did.
+pub fn parent_process_id() -> u64 {
+    Result::from(unsafe { syscall::get_parent_process_id() })
+        .expect("syscall::get_parent_process_id")
+}
+
+/// Returns the user the current process acts as. User 0 is root.
+pub fn uid() -> u32 {
+    Result::from(unsafe { syscall::get_uid() })
+        .expect("syscall::get_uid") as u32
+}
+
+/// Returns the group the current process acts as.
+pub fn gid() -> u32 {
+    Result::from(unsafe { syscall::get_gid() })
+        .expect("syscall::get_gid") as u32
+}
+
+/// Makes the current process act as another user, as do the tasks it starts
+/// from then on. Only root can change its user, and can't change it back.
+pub fn set_uid(uid: u32) -> Result<()> {
+    let result: Result<u64> = unsafe { syscall::set_uid(uid.into()) }.into();
+    result.map(|_| ())
+}
+
+/// Makes the current process act as part of another group. Only root can
+/// change its group.
+pub fn set_gid(gid: u32) -> Result<()> {
+    let result: Result<u64> = unsafe { syscall::set_gid(gid.into()) }.into();
+    result.map(|_| ())
+}
+
+/// Returns the id of the current task, the one that fork returns.
+pub fn id() -> u64 {
+    Result::from(unsafe { syscall::get_task_id() })
+        .expect("syscall::get_task_id")
+}
+
+pub use interface::{Utsname, UTSNAME_LEN};
+
+/// Returns the kernel's name, version and machine.
+pub fn uname() -> Result<Utsname> {
+    let mut utsname = Utsname::default();
+    let result: Result<u64> = unsafe { syscall::uname(&mut utsname) }.into();
+    result.map(|_| utsname)
+}
+
+pub use interface::{Timespec, CLOCK_MONOTONIC, CLOCK_REALTIME};
+
+/// Reads a clock, CLOCK_REALTIME for the date and time or CLOCK_MONOTONIC for
+/// time since boot.
+pub fn clock_gettime(clock: u64) -> Result<Timespec> {
+    let mut timespec = Timespec::default();
+    let result: Result<u64> = unsafe { syscall::clock_gettime(clock, &mut timespec) }.into();
+    result.map(|_| timespec)
+}
+
+pub use interface::{REBOOT_POWER_OFF, REBOOT_RESTART};
+
+/// Writes everything back to disk and then turns the machine off, with
+/// REBOOT_POWER_OFF, or restarts it, with REBOOT_RESTART. Only returns on
+/// failure.
+pub fn reboot(action: u64) -> Result<()> {
+    let result: Result<u64> = unsafe { syscall::reboot(action) }.into();
+    result.map(|_| ())
+}
+
+/// Turns address space layout randomization on or off for programs this
+/// process execs from now on, and their children. Off is for debugging, when
+/// addresses should be the same from one run to the next. Returns whether it
+/// was on.
+pub fn set_aslr(enabled: bool) -> Result<bool> {
+    let result: Result<u64> = unsafe { syscall::set_aslr(enabled as u64) }.into();
+    result.map(|old| old != 0)
+}
+
+/// Narrows the syscalls this task may make. `bitmap` has a bit for each
+/// syscall number, and `mode` says whether those are the only ones allowed,
+/// FILTER_ALLOW, or the ones denied, FILTER_DENY. Syscalls the task was
+/// already denied stay denied, and tasks it starts, and programs it execs,
+/// get the same filter. Denied syscalls fail with PermissionDenied, apart
+/// from Exit, which is always allowed.
+pub fn filter_syscalls(mode: u64, bitmap: &[u64; SYSCALL_FILTER_WORDS as usize]) -> Result<()> {
+    let result: Result<u64> = unsafe { syscall::syscall_filter(mode, bitmap.as_ptr()) }.into();
+    result.map(|_| ())
+}
+
+/// Allows or forbids memory that's writable and executable at once, for this
+/// process, the programs it execs and their children. It's forbidden by
+/// default, which is in the way of JIT compilers and little else. Returns
+/// whether it was allowed.
+pub fn set_write_execute(allowed: bool) -> Result<bool> {
+    let result: Result<u64> = unsafe { syscall::set_write_execute(allowed as u64) }.into();
+    result.map(|old| old != 0)
+}
+
+/// Creates a copy of the current task. Returns the new task's id in the
+/// parent, and 0 in the child.
+pub fn fork() -> Result<u64> {
+    unsafe { syscall::fork() }.into()
+}
+
+/// Replaces the program running in the current task with the static ELF
+/// executable at `path`, passing it `argv` and `envp`. Each string must end
+/// in a NUL. Only returns on failure.
+pub fn exec(path: &[u8], argv: &[&[u8]], envp: &[&[u8]]) -> Result<()> {
+    let mut argv_ptrs = [ptr::null(); MAX_EXEC_ARGS + 1];
+    let mut envp_ptrs = [ptr::null(); MAX_EXEC_ARGS + 1];
+
+    c_strings(argv, &mut argv_ptrs)?;
+    c_strings(envp, &mut envp_ptrs)?;
+
+    let result: Result<u64> = unsafe {
+        syscall::exec(path.as_ptr(), path.len() as u64, argv_ptrs.as_ptr(), envp_ptrs.as_ptr())
+    }.into();
+
+    result.map(|_| ())
+}
+
+// the most strings exec takes in each of argv and envp:
+const MAX_EXEC_ARGS: usize = 64;
+
+// fills in a NULL terminated array of pointers to the strings:
+fn c_strings(strings: &[&[u8]], ptrs: &mut [*const u8; MAX_EXEC_ARGS + 1]) -> Result<()> {
+    if strings.len() > MAX_EXEC_ARGS {
+        return Err(SysError::ArgumentsTooLong);
+    }
+
+    for (ptr, string) in ptrs.iter_mut().zip(strings) {
+        if string.last() != Some(&0) {
+            return Err(SysError::IllegalValue);
+        }
+
+        *ptr = string.as_ptr();
+    }
+
+    Ok(())
+}
+
+/// Moves the process of a task, or of the current task if `task_id` is 0,
+/// into a process group. Group 0 starts a new group named after the process.
+pub fn set_process_group(task_id: u64, group: u64) -> Result<()> {
+    let result: Result<u64> = unsafe { syscall::set_process_group(task_id, group) }.into();
+    result.map(|_| ())
+}
+
+/// Returns the process group of a task, or of the current task if `task_id`
+/// is 0.
+pub fn process_group(task_id: u64) -> Result<u64> {
+    unsafe { syscall::get_process_group(task_id) }.into()
+}
+
+/// Waits for any child of the current task to exit. Returns the child's id
+/// and exit status.
+pub fn wait() -> Result<(u64, u64)> {
+    let mut status = 0;
+    let result: Result<u64> = unsafe { syscall::wait(&mut status) }.into();
+    result.map(|task_id| (task_id, status))
+}
+
+/// Starts a new thread in the current process, sharing its address space and
+/// handles. The thread begins executing at `entry` with its stack pointer set
+/// to `stack`. Returns the new thread's task id.
+pub unsafe fn create_thread(entry: u64, stack: u64) -> Result<u64> {
+    syscall::create_thread(entry, stack).into()
+}
+
+/// Terminates the task with the given id. Its exit status will be
+/// `interface::EXIT_KILLED`.
+pub fn kill(task_id: u64) -> Result<()> {
+    let result: Result<u64> = unsafe { syscall::kill(task_id) }.into();
+    result.map(|_| ())
+}
+
+/// A one-shot or periodic timer. It becomes readable when it expires, so it
+/// can be waited on with `io::poll` or an `io::EventQueue`, and can also send
+/// a signal to the task that created it.
+pub struct Timer(Handle);
+
+impl Timer {
+    /// Creates a disarmed timer, which sends `signal` on expiry unless it's 0.
+    pub fn new(signal: u64) -> Result<Timer> {
+        Result::from(unsafe { syscall::timer_create(signal) }).map(Timer)
+    }
+
+    pub fn handle(&self) -> &Handle {
+        &self.0
+    }
+
+    /// Arms the timer to expire after `initial_ns`, then every `interval_ns`
+    /// unless it's 0. An `initial_ns` of 0 disarms it.
+    pub fn set(&self, initial_ns: u64, interval_ns: u64) -> Result<()> {
+        let result: Result<u64> = unsafe { syscall::timer_set(self.0.as_raw(), initial_ns, interval_ns) }.into();
+        result.map(|_| ())
+    }
+
+    /// Waits for the timer to expire, returning how many times it has since
+    /// the last read.
+    pub fn read(&self) -> Result<u64> {
+        unsafe { syscall::timer_read(self.0.as_raw()) }.into()
+    }
+}
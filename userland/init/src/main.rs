@@ -28,5 +28,12 @@ pub extern "C" fn main() {
     con.write_all(&buf)
         .expect("Console::write_all");
 
+    // there's nothing left to run, so turn the machine off rather than leave
+    // it idling:
+    if task::reboot(task::REBOOT_POWER_OFF).is_err() {
+        con.write_all(b"could not power off\n")
+            .expect("Console::write_all");
+    }
+
     task::exit(0);
 }